@@ -0,0 +1,32 @@
+use std::process::Command;
+
+/// Embeds the git commit and build date as `RUKU_GIT_SHA` and `RUKU_BUILD_DATE`. Both can be given in the
+/// environment instead, e.g. by a packager building from a tarball, and are `unknown` when neither works.
+fn main() {
+    let git_sha = std::env::var("RUKU_GIT_SHA")
+        .ok()
+        .or_else(|| output("git", &["rev-parse", "--short=12", "HEAD"]))
+        .unwrap_or("unknown".to_string());
+    // SOURCE_DATE_EPOCH keeps reproducible builds reproducible
+    let build_date = std::env::var("RUKU_BUILD_DATE")
+        .ok()
+        .or_else(|| {
+            let epoch = std::env::var("SOURCE_DATE_EPOCH").ok()?;
+            output("date", &["-u", "-d", &format!("@{}", epoch), "+%Y-%m-%d"])
+        })
+        .or_else(|| output("date", &["-u", "+%Y-%m-%d"]))
+        .unwrap_or("unknown".to_string());
+    println!("cargo:rustc-env=RUKU_GIT_SHA={}", git_sha);
+    println!("cargo:rustc-env=RUKU_BUILD_DATE={}", build_date);
+    println!("cargo:rerun-if-env-changed=RUKU_GIT_SHA");
+    println!("cargo:rerun-if-env-changed=RUKU_BUILD_DATE");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+}
+
+fn output(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    let stdout = String::from_utf8(output.stdout).ok()?;
+    (output.status.success() && !stdout.trim().is_empty()).then(|| stdout.trim().to_string())
+}
@@ -0,0 +1,539 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::Instant;
+
+use chrono::Utc;
+use colored::Colorize;
+use serde::Serialize;
+use serde_json::Value;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+
+use crate::debug_bundle::Redactor;
+use crate::logger::Logger;
+use crate::templates::SECRET_MASK;
+
+/// Environment variable that records the daemon API calls like `--debug-api`.
+pub const TRACE_ENV: &str = "RUKU_TRACE_DOCKER";
+/// The trace in the state root, one JSON line per call.
+pub const TRACE_FILE: &str = "docker-api.trace.jsonl";
+/// What the trace is moved to when it reaches its size cap, replacing the one before.
+pub const ROTATED_FILE: &str = "docker-api.trace.1.jsonl";
+/// Size past which the trace is rotated, so a long follow doesn't fill the disk.
+const MAX_TRACE_SIZE: u64 = 8 * 1024 * 1024;
+/// Bytes of a request or error body kept in the trace, larger bodies are only counted.
+const MAX_BODY: usize = 64 * 1024;
+/// Longest request or response head accepted.
+const MAX_HEAD: usize = 64 * 1024;
+/// Headers carrying registry credentials.
+const SECRET_HEADERS: [&str; 4] = [
+    "authorization",
+    "proxy-authorization",
+    "x-registry-auth",
+    "x-registry-config",
+];
+/// Query parameters whose values can hold secrets, build args in particular.
+const SECRET_QUERY: [&str; 2] = ["buildargs", "auth"];
+/// Prefix of the directories holding the sockets the daemon is reached through while tracing.
+const SOCKET_DIR_PREFIX: &str = "ruku-api-trace-";
+
+static TRACER: OnceLock<Tracer> = OnceLock::new();
+
+/// Whether `--debug-api` or `RUKU_TRACE_DOCKER` asks for the daemon API calls to be recorded.
+pub fn is_requested() -> bool {
+    std::env::var(TRACE_ENV).is_ok_and(|value| !matches!(value.trim(), "" | "0" | "false"))
+}
+
+/// The trace files in `state_root`, the current one first.
+pub fn trace_files(state_root: &Path) -> [(&'static str, PathBuf); 2] {
+    [
+        (TRACE_FILE, state_root.join(TRACE_FILE)),
+        (ROTATED_FILE, state_root.join(ROTATED_FILE)),
+    ]
+}
+
+/// Record every daemon API call from here on to the trace in `state_root`, and point to it when the
+/// command fails.
+pub fn enable(log: &Logger, state_root: &Path) {
+    let path = state_root.join(TRACE_FILE);
+    let tracer = Tracer {
+        path: path.clone(),
+        written: Mutex::new(()),
+        calls: AtomicU64::new(0),
+        proxies: Mutex::new(HashMap::new()),
+    };
+    if TRACER.set(tracer).is_err() {
+        return;
+    }
+    remove_stale_sockets(SOCKET_DIR_PREFIX);
+    log.debug(&format!("Recording the daemon API calls to {}", path.display()));
+    log.on_error(move |_| eprintln!("=> {}", format!("API trace written to {}", path.display()).yellow()));
+}
+
+/// The address to reach the daemon at `host` through: a local socket recording each call when tracing
+/// is enabled, else `host` itself.
+pub fn route(host: &str) -> String {
+    let Some(tracer) = TRACER.get() else {
+        return host.to_string();
+    };
+    // The recording runs on the runtime of the command, there is none outside of one
+    if tokio::runtime::Handle::try_current().is_err() {
+        return host.to_string();
+    }
+    let mut proxies = tracer.proxies.lock().unwrap();
+    if let Some(address) = proxies.get(host) {
+        return address.clone();
+    }
+    match tracer.listen(host) {
+        Ok(address) => {
+            proxies.insert(host.to_string(), address.clone());
+            address
+        }
+        Err(e) => {
+            Logger::new().warn(&format!("Could not record the daemon API calls: {}", e));
+            host.to_string()
+        }
+    }
+}
+
+/// One daemon API call in the trace, secrets masked.
+#[derive(Debug, Serialize)]
+struct Call {
+    time: String,
+    id: u64,
+    method: String,
+    path: String,
+    headers: BTreeMap<String, String>,
+    /// The JSON body of the request, none for other bodies like a build context.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request: Option<Value>,
+    request_bytes: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<u16>,
+    /// Until the response headers arrived, a streamed body like a log follow goes on after.
+    ms: u64,
+    /// The body of an error response, or why the call got no response.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+struct Tracer {
+    path: PathBuf,
+    written: Mutex<()>,
+    calls: AtomicU64,
+    /// The address of the recording socket by daemon address.
+    proxies: Mutex<HashMap<String, String>>,
+}
+
+impl Tracer {
+    /// Listen on a socket only this user can reach and relay what arrives to the daemon at `host`.
+    #[cfg(unix)]
+    fn listen(&'static self, host: &str) -> io::Result<String> {
+        let upstream = Upstream::parse(host)?;
+        // Named after the process so a later run can tell it is left over
+        let dir = tempfile::Builder::new()
+            .prefix(&format!("{}{}-", SOCKET_DIR_PREFIX, std::process::id()))
+            .tempdir()?
+            .keep();
+        let socket = dir.join("docker.sock");
+        let listener = tokio::net::UnixListener::bind(&socket)?;
+        tokio::spawn(async move {
+            while let Ok((client, _)) = listener.accept().await {
+                let upstream = upstream.clone();
+                tokio::spawn(async move { self.relay(client, upstream).await });
+            }
+        });
+        Ok(format!("unix://{}", socket.display()))
+    }
+
+    #[cfg(not(unix))]
+    fn listen(&'static self, _host: &str) -> io::Result<String> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "only supported on unix"))
+    }
+
+    /// Relay the requests of one client connection to the daemon and the responses back, recording each.
+    async fn relay(&self, client: impl AsyncRead + AsyncWrite + Unpin, upstream: Upstream) {
+        let mut client = BufReader::new(client);
+        let mut daemon = match upstream.connect().await {
+            Ok(daemon) => BufReader::new(daemon),
+            Err(e) => {
+                // The client sees the connection close, the trace tells why
+                if let Ok(Some(head)) = read_head(&mut client).await {
+                    let mut call = self.call(&Head::parse(&head), &Capture::new(0));
+                    call.error = Some(format!("could not reach the daemon: {}", e));
+                    self.write(&call);
+                }
+                return;
+            }
+        };
+        while let Ok(Some(head)) = read_head(&mut client).await {
+            let started = Instant::now();
+            let request = Head::parse(&head);
+            let mut body = Capture::new(MAX_BODY);
+            let sent = async {
+                daemon.write_all(&head).await?;
+                copy_body(&mut client, &mut daemon, request.framing(false), &mut body).await?;
+                daemon.flush().await?;
+                read_head(&mut daemon).await
+            };
+            let response = match sent.await {
+                Ok(Some(response)) => response,
+                result => {
+                    let mut call = self.call(&request, &body);
+                    call.ms = started.elapsed().as_millis() as u64;
+                    call.error = Some(match result {
+                        Err(e) => e.to_string(),
+                        _ => "the daemon closed the connection".to_string(),
+                    });
+                    self.write(&call);
+                    return;
+                }
+            };
+            let ms = started.elapsed().as_millis() as u64;
+            if client.write_all(&response).await.is_err() {
+                return;
+            }
+            let response = Head::parse(&response);
+            let status = response.status();
+            let mut call = self.call(&request, &body);
+            call.status = status;
+            call.ms = ms;
+
+            // Attach and exec take the connection over for their streams
+            if status == Some(101) {
+                self.write(&call);
+                let _ = tokio::io::copy_bidirectional(&mut client, &mut daemon).await;
+                return;
+            }
+            let framing = match status {
+                Some(204 | 304) | Some(100..=199) => Framing::Length(0),
+                _ if request.method() == "HEAD" => Framing::Length(0),
+                _ => response.framing(true),
+            };
+            let failed = status.is_some_and(|status| status >= 400);
+            if !failed {
+                self.write(&call);
+            }
+            let mut error = Capture::new(if failed { MAX_BODY } else { 0 });
+            let relayed = copy_body(&mut daemon, &mut client, framing, &mut error).await;
+            if failed {
+                call.error = Some(String::from_utf8_lossy(&error.bytes).trim().to_string());
+                self.write(&call);
+            }
+            if relayed.is_err() || framing == Framing::UntilClose || client.flush().await.is_err() {
+                return;
+            }
+        }
+    }
+
+    /// The call of `request` with `body`, secrets masked.
+    fn call(&self, request: &Head, body: &Capture) -> Call {
+        let headers = request
+            .headers
+            .iter()
+            // The host of a socket connection is its path, hex encoded
+            .filter(|(name, _)| !name.eq_ignore_ascii_case("host"))
+            .map(
+                |(name, value)| match SECRET_HEADERS.contains(&name.to_ascii_lowercase().as_str()) {
+                    true => (name.clone(), SECRET_MASK.to_string()),
+                    false => (name.clone(), value.clone()),
+                },
+            )
+            .collect();
+        let json = body.total <= MAX_BODY as u64 && matches!(body.bytes.first(), Some(b'{' | b'['));
+        let request_json = json
+            .then(|| serde_json::from_slice::<Value>(&body.bytes).ok())
+            .flatten()
+            .map(|mut value| {
+                Redactor::new().redact_json(&mut value);
+                value
+            });
+        Call {
+            time: Utc::now().to_rfc3339(),
+            id: self.calls.fetch_add(1, Ordering::Relaxed) + 1,
+            method: request.method().to_string(),
+            path: redact_query(request.target()),
+            headers,
+            request: request_json,
+            request_bytes: body.total,
+            status: None,
+            ms: 0,
+            error: None,
+        }
+    }
+
+    /// Append `call` to the trace, rotating it first when it would go past its cap.
+    fn write(&self, call: &Call) {
+        let Ok(mut line) = serde_json::to_string(call) else {
+            return;
+        };
+        line.push('\n');
+        let _written = self.written.lock().unwrap();
+        let size = fs::metadata(&self.path).map(|metadata| metadata.len()).unwrap_or(0);
+        if size > 0 && size + line.len() as u64 > MAX_TRACE_SIZE {
+            let _ = fs::rename(&self.path, self.path.with_file_name(ROTATED_FILE));
+        }
+        if let Some(dir) = self.path.parent() {
+            let _ = fs::create_dir_all(dir);
+        }
+        // A trace that can't be written must not fail the command it traces
+        let _ = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut file| file.write_all(line.as_bytes()));
+    }
+}
+
+/// Remove the socket directories named `prefix` and a pid of earlier runs that are no longer running,
+/// where `/proc` tells.
+pub fn remove_stale_sockets(prefix: &str) {
+    if !Path::new("/proc/self").exists() {
+        return;
+    }
+    let Ok(entries) = fs::read_dir(std::env::temp_dir()) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().to_string();
+        let Some(pid) = name.strip_prefix(prefix).and_then(|rest| rest.split('-').next()) else {
+            continue;
+        };
+        if pid != std::process::id().to_string() && !Path::new("/proc").join(pid).exists() {
+            let _ = fs::remove_dir_all(entry.path());
+        }
+    }
+}
+
+/// `target` with the values of secret query parameters masked.
+fn redact_query(target: &str) -> String {
+    let Some((path, query)) = target.split_once('?') else {
+        return target.to_string();
+    };
+    let params: Vec<String> = query
+        .split('&')
+        .map(|param| match param.split_once('=') {
+            Some((key, _)) if SECRET_QUERY.contains(&key.to_ascii_lowercase().as_str()) => {
+                format!("{}={}", key, SECRET_MASK)
+            }
+            _ => param.to_string(),
+        })
+        .collect();
+    format!("{}?{}", path, params.join("&"))
+}
+
+/// Where the daemon is reached, by the address the command would connect to.
+#[derive(Debug, Clone)]
+enum Upstream {
+    #[cfg(unix)]
+    Unix(PathBuf),
+    Tcp(String),
+}
+
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+impl Upstream {
+    fn parse(host: &str) -> io::Result<Upstream> {
+        #[cfg(unix)]
+        if let Some(path) = host.strip_prefix("unix://") {
+            return Ok(Upstream::Unix(PathBuf::from(path)));
+        }
+        match host.strip_prefix("tcp://").or(host.strip_prefix("http://")) {
+            Some(address) => Ok(Upstream::Tcp(address.trim_end_matches('/').to_string())),
+            None => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("{} can't be traced", host),
+            )),
+        }
+    }
+
+    async fn connect(&self) -> io::Result<Box<dyn Stream>> {
+        match self {
+            #[cfg(unix)]
+            Upstream::Unix(path) => Ok(Box::new(tokio::net::UnixStream::connect(path).await?)),
+            Upstream::Tcp(address) => Ok(Box::new(tokio::net::TcpStream::connect(address).await?)),
+        }
+    }
+}
+
+/// How the end of a body is found.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Framing {
+    Length(u64),
+    Chunked,
+    /// A response body that ends with the connection.
+    UntilClose,
+}
+
+/// The start line and headers of a request or response.
+struct Head {
+    start: String,
+    headers: Vec<(String, String)>,
+}
+
+impl Head {
+    fn parse(head: &[u8]) -> Head {
+        let text = String::from_utf8_lossy(head);
+        let mut lines = text.split("\r\n");
+        let start = lines.next().unwrap_or_default().to_string();
+        let headers = lines
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+            .collect();
+        Head { start, headers }
+    }
+
+    fn method(&self) -> &str {
+        self.start.split(' ').next().unwrap_or_default()
+    }
+
+    fn target(&self) -> &str {
+        self.start.split(' ').nth(1).unwrap_or_default()
+    }
+
+    fn status(&self) -> Option<u16> {
+        self.start.split(' ').nth(1).and_then(|status| status.parse().ok())
+    }
+
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn framing(&self, response: bool) -> Framing {
+        if self
+            .header("Transfer-Encoding")
+            .is_some_and(|encoding| encoding.to_ascii_lowercase().contains("chunked"))
+        {
+            return Framing::Chunked;
+        }
+        match self.header("Content-Length").and_then(|length| length.parse().ok()) {
+            Some(length) => Framing::Length(length),
+            None if response => Framing::UntilClose,
+            None => Framing::Length(0),
+        }
+    }
+}
+
+/// The first bytes of a body, with how many it had in all.
+struct Capture {
+    bytes: Vec<u8>,
+    limit: usize,
+    total: u64,
+}
+
+impl Capture {
+    fn new(limit: usize) -> Capture {
+        Capture {
+            bytes: vec![],
+            limit,
+            total: 0,
+        }
+    }
+
+    fn push(&mut self, bytes: &[u8]) {
+        let room = self.limit.saturating_sub(self.bytes.len());
+        self.bytes.extend_from_slice(&bytes[..bytes.len().min(room)]);
+        self.total += bytes.len() as u64;
+    }
+}
+
+/// The head of the next message on `reader`, none when the connection closed before one.
+async fn read_head(reader: &mut (impl AsyncBufReadExt + Unpin)) -> io::Result<Option<Vec<u8>>> {
+    let mut head = vec![];
+    loop {
+        let read = reader.read_until(b'\n', &mut head).await?;
+        if read == 0 {
+            return match head.is_empty() {
+                true => Ok(None),
+                false => Err(io::ErrorKind::UnexpectedEof.into()),
+            };
+        }
+        if head.len() > MAX_HEAD {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "the message head is too long",
+            ));
+        }
+        if head.ends_with(b"\r\n\r\n") || head == b"\r\n" {
+            return Ok(Some(head));
+        }
+    }
+}
+
+/// Relay a body framed by `framing` from `reader` to `writer`, keeping its start in `capture`.
+async fn copy_body(
+    reader: &mut (impl AsyncBufReadExt + Unpin),
+    writer: &mut (impl AsyncWrite + Unpin),
+    framing: Framing,
+    capture: &mut Capture,
+) -> io::Result<()> {
+    match framing {
+        Framing::Length(length) => copy_exact(reader, writer, length, capture).await,
+        Framing::Chunked => loop {
+            let mut line = vec![];
+            reader.read_until(b'\n', &mut line).await?;
+            writer.write_all(&line).await?;
+            let size = String::from_utf8_lossy(&line);
+            let size = size.split(';').next().unwrap_or_default().trim();
+            let size = u64::from_str_radix(size, 16)
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "bad chunk size"))?;
+            if size == 0 {
+                // Trailers up to the empty line
+                loop {
+                    let mut line = vec![];
+                    if reader.read_until(b'\n', &mut line).await? == 0 {
+                        return Err(io::ErrorKind::UnexpectedEof.into());
+                    }
+                    writer.write_all(&line).await?;
+                    if line == b"\r\n" {
+                        return Ok(());
+                    }
+                }
+            }
+            copy_exact(reader, writer, size, capture).await?;
+            let mut end = vec![];
+            reader.read_until(b'\n', &mut end).await?;
+            writer.write_all(&end).await?;
+        },
+        Framing::UntilClose => {
+            let mut buffer = vec![0; 16 * 1024];
+            loop {
+                let read = reader.read(&mut buffer).await?;
+                if read == 0 {
+                    return Ok(());
+                }
+                writer.write_all(&buffer[..read]).await?;
+                capture.push(&buffer[..read]);
+            }
+        }
+    }
+}
+
+async fn copy_exact(
+    reader: &mut (impl AsyncRead + Unpin),
+    writer: &mut (impl AsyncWrite + Unpin),
+    length: u64,
+    capture: &mut Capture,
+) -> io::Result<()> {
+    let mut buffer = vec![0; 16 * 1024];
+    let mut remaining = length;
+    while remaining > 0 {
+        let wanted = remaining.min(buffer.len() as u64) as usize;
+        let read = reader.read(&mut buffer[..wanted]).await?;
+        if read == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        writer.write_all(&buffer[..read]).await?;
+        capture.push(&buffer[..read]);
+        remaining -= read as u64;
+    }
+    Ok(())
+}
@@ -0,0 +1,67 @@
+use std::fmt;
+
+use crate::model::RukuConfig;
+
+/// A Docker API version, `1.41` is `ApiVersion(1, 41)`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ApiVersion(pub u32, pub u32);
+
+impl ApiVersion {
+    /// The `major.minor` version the daemon reports, none when it is not one.
+    pub fn parse(version: &str) -> Option<ApiVersion> {
+        let (major, minor) = version.trim().split_once('.')?;
+        Some(ApiVersion(major.parse().ok()?, minor.parse().ok()?))
+    }
+}
+
+impl fmt::Display for ApiVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.0, self.1)
+    }
+}
+
+/// The oldest API ruku is tested against, Docker 20.10. Older daemons still run configs that use none of
+/// the features below beyond what they support, with a warning.
+pub const MIN_API_VERSION: ApiVersion = ApiVersion(1, 41);
+
+/// A setting that makes ruku use a part of the API older daemons don't have.
+pub struct ApiFeature {
+    pub name: &'static str,
+    /// The first API version that has it.
+    pub version: ApiVersion,
+    /// Whether `config` uses it.
+    pub used: fn(&RukuConfig) -> bool,
+}
+
+/// Every setting gated on the API version, add one here when a setting needs a newer daemon.
+pub const API_FEATURES: &[ApiFeature] = &[
+    ApiFeature {
+        name: "multi-platform builds",
+        // BuildKit through buildx came with Docker 19.03
+        version: ApiVersion(1, 40),
+        used: |config| config.build.as_ref().is_some_and(|build| build.is_multi_platform()),
+    },
+    ApiFeature {
+        name: "health-gated deploy strategies",
+        version: ApiVersion(1, 24),
+        used: |config| config.strategy.gates_health(),
+    },
+    ApiFeature {
+        name: "resources.pids_limit",
+        version: ApiVersion(1, 23),
+        used: |config| config.resources.as_ref().is_some_and(|r| r.pids_limit.is_some()),
+    },
+    ApiFeature {
+        name: "resources.oom_score_adj",
+        version: ApiVersion(1, 22),
+        used: |config| config.resources.as_ref().is_some_and(|r| r.oom_score_adj.is_some()),
+    },
+];
+
+/// The features `config` uses that need a newer API than `api`.
+pub fn unsupported_features(config: &RukuConfig, api: ApiVersion) -> Vec<&'static ApiFeature> {
+    API_FEATURES
+        .iter()
+        .filter(|feature| feature.version > api && (feature.used)(config))
+        .collect()
+}
@@ -0,0 +1,133 @@
+use std::path::{Path, PathBuf};
+
+/// Env var naming the app of a command that is given none, after `--app`.
+pub const APP_ENV: &str = "RUKU_APP";
+const CONFIG_FILE: &str = "ruku.yml";
+
+/// Where the app a command acts on was named.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AppSource {
+    /// The app argument of the command or the global `--app` flag.
+    Flag,
+    /// The `RUKU_APP` env var.
+    Env,
+    /// The directory of the nearest ruku.yml.
+    Discovered(PathBuf),
+}
+
+/// The app a command acts on: its app argument or `--app`, then `app_env`, then the name of the directory
+/// holding the nearest ruku.yml from `cwd` up. The error lists every path that was searched.
+pub fn resolve_app(
+    argument: Option<&str>,
+    flag: Option<&str>,
+    app_env: Option<&str>,
+    cwd: &Path,
+) -> Result<(String, AppSource), String> {
+    match (argument, flag) {
+        (Some(argument), Some(flag)) if argument != flag => {
+            return Err(format!(
+                "The app is given as {} and as --app {}, pass only one",
+                argument, flag
+            ));
+        }
+        (Some(app), _) | (None, Some(app)) => return Ok((app.to_string(), AppSource::Flag)),
+        (None, None) => {}
+    }
+    if let Some(app) = app_env.filter(|app| !app.is_empty()) {
+        return Ok((app.to_string(), AppSource::Env));
+    }
+
+    let config = find_config(cwd).map_err(|searched| {
+        let searched: Vec<String> = searched.iter().map(|path| path.display().to_string()).collect();
+        format!(
+            "No app given and no ruku.yml found, searched {}. Pass the app name, --app or set {}",
+            searched.join(", "),
+            APP_ENV
+        )
+    })?;
+    let app = config
+        .parent()
+        .and_then(Path::file_name)
+        .map(|name| name.to_string_lossy().to_string())
+        .ok_or_else(|| format!("Can't take an app name from the directory of {}", config.display()))?;
+    Ok((app, AppSource::Discovered(config)))
+}
+
+/// The nearest ruku.yml in `dir` or one of its parents, like git finds its repository, or every path
+/// that was searched when there is none.
+pub fn find_config(dir: &Path) -> Result<PathBuf, Vec<PathBuf>> {
+    let mut searched = vec![];
+    for dir in dir.ancestors() {
+        let path = dir.join(CONFIG_FILE);
+        if path.is_file() {
+            return Ok(path);
+        }
+        searched.push(path);
+    }
+    Err(searched)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn flags_come_before_the_env_and_the_config() {
+        let cwd = tempfile::tempdir().unwrap();
+        fs::write(cwd.path().join(CONFIG_FILE), "").unwrap();
+        let resolve = |argument, flag, env| resolve_app(argument, flag, env, cwd.path());
+
+        assert_eq!(
+            resolve(Some("shop"), None, Some("blog")),
+            Ok(("shop".to_string(), AppSource::Flag))
+        );
+        assert_eq!(
+            resolve(None, Some("shop"), None),
+            Ok(("shop".to_string(), AppSource::Flag))
+        );
+        assert_eq!(
+            resolve(Some("shop"), Some("shop"), None),
+            Ok(("shop".to_string(), AppSource::Flag))
+        );
+        assert!(resolve(Some("shop"), Some("blog"), None).is_err());
+        assert_eq!(
+            resolve(None, None, Some("blog")),
+            Ok(("blog".to_string(), AppSource::Env))
+        );
+        // An empty env var is unset
+        assert!(matches!(
+            resolve(None, None, Some("")),
+            Ok((_, AppSource::Discovered(_)))
+        ));
+    }
+
+    #[test]
+    fn the_nearest_config_names_the_app() {
+        let root = tempfile::tempdir().unwrap();
+        let deep = root.path().join("shop").join("src").join("handlers");
+        fs::create_dir_all(&deep).unwrap();
+        let config = root.path().join("shop").join(CONFIG_FILE);
+        fs::write(&config, "").unwrap();
+        // A ruku.yml directory is not a config
+        fs::create_dir(deep.join(CONFIG_FILE)).unwrap();
+
+        assert_eq!(
+            resolve_app(None, None, None, &deep),
+            Ok(("shop".to_string(), AppSource::Discovered(config)))
+        );
+    }
+
+    #[test]
+    fn every_searched_path_is_listed() {
+        let root = tempfile::tempdir().unwrap();
+        let searched = find_config(root.path()).unwrap_err();
+        assert_eq!(searched[0], root.path().join(CONFIG_FILE));
+        assert_eq!(searched.len(), root.path().ancestors().count());
+
+        let error = resolve_app(None, None, None, root.path()).unwrap_err();
+        assert!(error.contains(&root.path().join(CONFIG_FILE).display().to_string()));
+        assert!(error.ends_with("Pass the app name, --app or set RUKU_APP"));
+    }
+}
@@ -0,0 +1,247 @@
+use std::env;
+use std::net::IpAddr;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use bollard::models::{ContainerSummary, PortTypeEnum};
+
+use crate::container::PORT_LABEL;
+use crate::proxy::{has_certificate, DOMAINS_LABEL};
+
+/// `scheme://host[:port]path`. An IPv6 host goes in brackets, the port is left out when it is the default
+/// of the scheme and the path always starts with a `/`.
+pub fn format_url(scheme: &str, host: &str, port: Option<u16>, path: &str) -> String {
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let host = if host.contains(':') {
+        format!("[{}]", host)
+    } else {
+        host.to_string()
+    };
+    let default_port = match scheme {
+        "https" => Some(443),
+        "http" => Some(80),
+        _ => None,
+    };
+    let port = match port {
+        Some(port) if Some(port) != default_port => format!(":{}", port),
+        _ => String::new(),
+    };
+    let path = if path.starts_with('/') {
+        path.to_string()
+    } else {
+        format!("/{}", path)
+    };
+    format!("{}://{}{}{}", scheme, host, port, path)
+}
+
+/// The host a port published on `bound_ip` by the daemon at `docker_host` is reached on from here. A port
+/// published on every interface of a remote daemon is reached through the address of the daemon.
+pub fn published_host(docker_host: &str, bound_ip: Option<&str>) -> String {
+    let bound = bound_ip.and_then(|ip| ip.parse::<IpAddr>().ok());
+    let remote = docker_host
+        .strip_prefix("tcp://")
+        .or_else(|| docker_host.strip_prefix("http://"))
+        .map(|address| match address.rsplit_once(':') {
+            Some((host, port)) if port.chars().all(|c| c.is_ascii_digit()) => host,
+            _ => address,
+        });
+    match (bound, remote) {
+        (Some(ip), _) if !ip.is_unspecified() && !ip.is_loopback() => ip.to_string(),
+        (_, Some(remote)) => remote.to_string(),
+        (Some(ip), None) if ip.is_loopback() => ip.to_string(),
+        _ => "localhost".to_string(),
+    }
+}
+
+/// The URL the app in `summary` answers on. With `domains` that is the first one that is not a wildcard,
+/// through the proxy, over HTTPS when the proxy has a certificate for the app in `state_root`. Otherwise
+/// it is the host port ruku published for it or else the first TCP port it publishes, over plain HTTP.
+/// None when it has no domain and publishes no TCP port.
+pub fn app_url(summary: &ContainerSummary, docker_host: &str, state_root: &Path) -> Option<String> {
+    let domains: Vec<&str> = summary
+        .labels
+        .as_ref()
+        .and_then(|labels| labels.get(DOMAINS_LABEL))
+        .map(|domains| domains.split(',').filter(|domain| !domain.is_empty()).collect())
+        .unwrap_or_default();
+    if let Some(domain) = domains.iter().find(|domain| !domain.starts_with('*')) {
+        let scheme = match has_certificate(state_root, domains[0]) {
+            true => "https",
+            false => "http",
+        };
+        return Some(format_url(scheme, domain, None, "/"));
+    }
+    let ports: Vec<_> = summary
+        .ports
+        .iter()
+        .flatten()
+        .filter(|port| port.public_port.is_some() && port.typ != Some(PortTypeEnum::UDP))
+        .collect();
+    let labeled: Option<u16> = summary
+        .labels
+        .as_ref()
+        .and_then(|labels| labels.get(PORT_LABEL))
+        .and_then(|port| port.parse().ok());
+    let port = ports
+        .iter()
+        .find(|port| port.public_port == labeled)
+        .or(ports.first())?;
+    let host = published_host(docker_host, port.ip.as_deref());
+    Some(format_url("http", &host, port.public_port, "/"))
+}
+
+/// Open `url` in the browser of the desktop session this runs in. False when there is none, e.g. over ssh.
+pub fn open_in_browser(url: &str) -> bool {
+    let opener = if cfg!(target_os = "macos") {
+        "open"
+    } else if env::var_os("DISPLAY").is_some() || env::var_os("WAYLAND_DISPLAY").is_some() {
+        "xdg-open"
+    } else {
+        return false;
+    };
+    Command::new(opener)
+        .arg(url)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok_and(|status| status.success())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::fs;
+
+    use bollard::models::Port;
+
+    use super::*;
+
+    fn summary(ports: &[(Option<&str>, u16, PortTypeEnum)], labels: &[(&str, &str)]) -> ContainerSummary {
+        ContainerSummary {
+            ports: Some(
+                ports
+                    .iter()
+                    .map(|(ip, public_port, typ)| Port {
+                        ip: ip.map(str::to_string),
+                        private_port: 3000,
+                        public_port: Some(*public_port),
+                        typ: Some(*typ),
+                    })
+                    .collect(),
+            ),
+            labels: Some(HashMap::from_iter(
+                labels.iter().map(|(key, value)| (key.to_string(), value.to_string())),
+            )),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn ipv6_hosts_go_in_brackets_and_default_ports_are_left_out() {
+        assert_eq!(format_url("http", "::1", Some(8080), "/"), "http://[::1]:8080/");
+        assert_eq!(
+            format_url("http", "[fe80::1]", Some(80), "health"),
+            "http://[fe80::1]/health"
+        );
+        assert_eq!(
+            format_url("https", "example.com", Some(443), ""),
+            "https://example.com/"
+        );
+        assert_eq!(
+            format_url("https", "example.com", Some(80), "/a"),
+            "https://example.com:80/a"
+        );
+        assert_eq!(format_url("tcp", "10.0.0.2", None, "/"), "tcp://10.0.0.2/");
+    }
+
+    #[test]
+    fn the_host_depends_on_where_the_daemon_is() {
+        // A local daemon, through its unix socket or a named pipe
+        assert_eq!(
+            published_host("unix:///var/run/docker.sock", Some("0.0.0.0")),
+            "localhost"
+        );
+        assert_eq!(published_host("npipe:////./pipe/docker_engine", None), "localhost");
+        assert_eq!(
+            published_host("unix:///var/run/docker.sock", Some("127.0.0.1")),
+            "127.0.0.1"
+        );
+        // A remote daemon, reached by its address unless the port is bound to another one
+        assert_eq!(published_host("tcp://10.0.0.2:2375", Some("0.0.0.0")), "10.0.0.2");
+        assert_eq!(published_host("tcp://10.0.0.2:2375", Some("::")), "10.0.0.2");
+        assert_eq!(published_host("http://docker.internal", None), "docker.internal");
+        assert_eq!(
+            published_host("tcp://10.0.0.2:2375", Some("192.168.1.5")),
+            "192.168.1.5"
+        );
+        assert_eq!(
+            published_host("unix:///var/run/docker.sock", Some("fd00::5")),
+            "fd00::5"
+        );
+    }
+
+    #[test]
+    fn the_url_takes_the_labeled_port_and_skips_udp() {
+        let socket = "unix:///var/run/docker.sock";
+        let none = Path::new("/nonexistent");
+        let summary_of = |ports: &[(Option<&str>, u16, PortTypeEnum)], labels: &[(&str, &str)]| {
+            app_url(&summary(ports, labels), socket, none)
+        };
+        let ports = [
+            (Some("0.0.0.0"), 9000, PortTypeEnum::TCP),
+            (Some("0.0.0.0"), 8080, PortTypeEnum::TCP),
+        ];
+        assert_eq!(
+            summary_of(&ports, &[(PORT_LABEL, "8080")]).as_deref(),
+            Some("http://localhost:8080/")
+        );
+        assert_eq!(summary_of(&ports, &[]).as_deref(), Some("http://localhost:9000/"));
+        assert_eq!(
+            summary_of(&[(Some("::1"), 8080, PortTypeEnum::TCP)], &[]).as_deref(),
+            Some("http://[::1]:8080/")
+        );
+        assert_eq!(summary_of(&[(None, 5353, PortTypeEnum::UDP)], &[]), None);
+        assert_eq!(summary_of(&[], &[]), None);
+
+        // A port of the container that is not published has no URL
+        let mut unpublished = summary(&[(None, 8080, PortTypeEnum::TCP)], &[]);
+        unpublished.ports.as_mut().unwrap()[0].public_port = None;
+        assert_eq!(app_url(&unpublished, socket, none), None);
+    }
+
+    #[test]
+    fn a_domain_wins_over_the_published_port() {
+        let state_root = tempfile::tempdir().unwrap();
+        let summary = summary(
+            &[(Some("0.0.0.0"), 8080, PortTypeEnum::TCP)],
+            &[(DOMAINS_LABEL, "*.shop.example.com,shop.example.com")],
+        );
+        assert_eq!(
+            app_url(&summary, "unix:///var/run/docker.sock", state_root.path()).as_deref(),
+            Some("http://shop.example.com/")
+        );
+
+        // The certificate is named after the first domain, a leading `*` written as `_`
+        let certs = state_root.path().join("proxy/certs");
+        fs::create_dir_all(&certs).unwrap();
+        fs::write(certs.join("_.shop.example.com.crt"), "").unwrap();
+        fs::write(certs.join("_.shop.example.com.key"), "").unwrap();
+        assert_eq!(
+            app_url(&summary, "unix:///var/run/docker.sock", state_root.path()).as_deref(),
+            Some("https://shop.example.com/")
+        );
+    }
+
+    #[test]
+    fn only_wildcard_domains_fall_back_to_the_port() {
+        let summary = summary(
+            &[(Some("0.0.0.0"), 8080, PortTypeEnum::TCP)],
+            &[(DOMAINS_LABEL, "*.example.com")],
+        );
+        assert_eq!(
+            app_url(&summary, "unix:///var/run/docker.sock", Path::new("/nonexistent")).as_deref(),
+            Some("http://localhost:8080/")
+        );
+    }
+}
@@ -0,0 +1,388 @@
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use bollard::Docker;
+use chrono::{DateTime, Utc};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use tempfile::TempDir;
+use validator::Validate;
+
+use crate::container::Container;
+use crate::history::{Deployment, History};
+use crate::image::Image;
+use crate::inflight::{InFlight, Turn};
+use crate::logger::Logger;
+use crate::misc::get_image_name_with_version;
+use crate::model::RukuConfig;
+use crate::server_config::ServerConfig;
+use crate::slots::DeploySlots;
+use crate::volume::{get_volume_name, Volumes};
+
+/// Version of the archive layout. Bump it whenever the layout changes in an incompatible way.
+pub const ARCHIVE_FORMAT_VERSION: u32 = 1;
+
+const MANIFEST_FILE: &str = "manifest.json";
+const CONFIG_FILE: &str = "ruku.yml";
+const IMAGE_FILE: &str = "image.tar";
+const DATA_DIR: &str = "data";
+/// Holds a `<key>.tar` of each named volume.
+const VOLUMES_DIR: &str = "volumes";
+
+/// Describes the contents of an app archive.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Manifest {
+    pub format_version: u32,
+    pub ruku_version: String,
+    pub app: String,
+    pub version: Option<String>,
+    pub image: Option<String>,
+    pub data: bool,
+    /// Keys of the named volumes in the archive, an older ruku left them out.
+    #[serde(default)]
+    pub volumes: Vec<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Packs an app definition into a single `.tar.gz` archive.
+pub struct Export<'a> {
+    log: &'a Logger,
+    name: &'a str,
+    server_config: &'a ServerConfig,
+    config: &'a RukuConfig,
+}
+
+impl<'a> Export<'a> {
+    pub fn new(log: &'a Logger, name: &'a str, server_config: &'a ServerConfig, config: &'a RukuConfig) -> Export<'a> {
+        Export {
+            log,
+            name,
+            server_config,
+            config,
+        }
+    }
+
+    /// Write the archive to `output`. The image and the named volumes are only included when a Docker
+    /// connection is given, `include_data` covers the data directory and the volumes.
+    pub async fn run(
+        &self,
+        output: &Path,
+        docker: Option<&Docker>,
+        include_image: bool,
+        include_data: bool,
+    ) -> Result<(), String> {
+        let config_path = self.server_config.apps_root.join(self.name).join(CONFIG_FILE);
+        let history = History::new(self.log, &self.server_config.state_root.join(self.name));
+        let data_path = self.server_config.data_root.join(self.name);
+        let has_data = include_data && data_path.exists();
+
+        let image_name_with_version = get_image_name_with_version(self.name, &self.config.version);
+        let image_file = match docker.filter(|_| include_image) {
+            Some(docker) => Some(self.save_image(docker, &image_name_with_version).await?),
+            None => None,
+        };
+        let mut volume_files = vec![];
+        if let Some(docker) = docker.filter(|_| include_data) {
+            let volumes = Volumes::new(self.log, self.name, docker);
+            for key in volumes.existing_keys(&self.config.all_volume_specs()).await? {
+                volume_files.push((key.clone(), self.save_volume(&volumes, &key).await?));
+            }
+        }
+
+        let manifest = Manifest {
+            format_version: ARCHIVE_FORMAT_VERSION,
+            ruku_version: env!("CARGO_PKG_VERSION").to_string(),
+            app: self.name.to_string(),
+            version: self.config.version.clone(),
+            image: image_file.as_ref().map(|_| image_name_with_version.clone()),
+            data: has_data,
+            volumes: volume_files.iter().map(|(key, _)| key.clone()).collect(),
+            created_at: Utc::now(),
+        };
+
+        let file = File::create(output).map_err(|e| format!("Error creating archive {}: {}", output.display(), e))?;
+        let mut builder = tar::Builder::new(GzEncoder::new(file, Compression::default()));
+
+        let manifest_content = serde_json::to_vec_pretty(&manifest).unwrap();
+        let mut header = tar::Header::new_gnu();
+        header.set_size(manifest_content.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime(manifest.created_at.timestamp() as u64);
+        header.set_cksum();
+        let mut result = builder.append_data(&mut header, MANIFEST_FILE, manifest_content.as_slice());
+
+        result = result.and_then(|_| builder.append_path_with_name(&config_path, CONFIG_FILE));
+        if history.path().exists() {
+            self.log.step("Adding deployment history");
+            result = result.and_then(|_| builder.append_path_with_name(history.path(), History::FILE_NAME));
+        }
+        if let Some(image_file) = &image_file {
+            self.log.step(&format!("Adding image {}", image_name_with_version));
+            result = result.and_then(|_| builder.append_path_with_name(image_file.path(), IMAGE_FILE));
+        }
+        if has_data {
+            self.log.step(&format!("Adding data from {}", data_path.display()));
+            result = result.and_then(|_| builder.append_dir_all(DATA_DIR, &data_path));
+        }
+        for (key, file) in &volume_files {
+            self.log
+                .step(&format!("Adding volume {}", get_volume_name(self.name, key)));
+            let name = Path::new(VOLUMES_DIR).join(format!("{}.tar", key));
+            result = result.and_then(|_| builder.append_path_with_name(file.path(), name));
+        }
+
+        result
+            .and_then(|_| builder.into_inner())
+            .and_then(|encoder| encoder.finish())
+            .map_err(|e| format!("Error writing archive {}: {}", output.display(), e))?;
+
+        self.log
+            .step(&format!("Exported {} to {}", self.name, output.display()));
+        Ok(())
+    }
+
+    /// Copy the content of the named volume `key` into a temporary file.
+    async fn save_volume(&self, volumes: &Volumes<'_>, key: &str) -> Result<tempfile::NamedTempFile, String> {
+        let volume_name = get_volume_name(self.name, key);
+        self.log.step(&format!("Saving volume {}", volume_name));
+        let mut file = tempfile::NamedTempFile::new().map_err(|e| format!("Error creating temporary file: {}", e))?;
+        volumes
+            .export(key, &mut file)
+            .await
+            .map_err(|e| format!("Error saving volume {}: {}", volume_name, e))?;
+        Ok(file)
+    }
+
+    /// Stream `docker save` output into a temporary file.
+    async fn save_image(&self, docker: &Docker, image_name: &str) -> Result<tempfile::NamedTempFile, String> {
+        self.log.step(&format!("Saving image {}", image_name));
+
+        let mut file = tempfile::NamedTempFile::new().map_err(|e| format!("Error creating temporary file: {}", e))?;
+
+        let mut stream = docker.export_image(image_name);
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk.map_err(|e| format!("Error saving image {}: {}", image_name, e))?;
+            file.write_all(&chunk)
+                .map_err(|e| format!("Error writing image to disk: {}", e))?;
+        }
+
+        Ok(file)
+    }
+}
+
+/// An archive that has been unpacked and checked for compatibility.
+pub struct UnpackedArchive {
+    dir: TempDir,
+    pub manifest: Manifest,
+    pub config: RukuConfig,
+}
+
+impl UnpackedArchive {
+    fn path(&self, name: &str) -> PathBuf {
+        self.dir.path().join(name)
+    }
+}
+
+/// Recreates an app from an archive produced by [`Export`].
+pub struct Import<'a> {
+    log: &'a Logger,
+    server_config: &'a ServerConfig,
+}
+
+impl<'a> Import<'a> {
+    pub fn new(log: &'a Logger, server_config: &'a ServerConfig) -> Import<'a> {
+        Import { log, server_config }
+    }
+
+    /// Unpack the archive and validate it. Nothing on the host is touched yet.
+    pub fn open(&self, file: &Path) -> Result<UnpackedArchive, String> {
+        let archive = File::open(file).map_err(|e| format!("Error opening archive {}: {}", file.display(), e))?;
+        let dir = TempDir::new().map_err(|e| format!("Error creating temporary directory: {}", e))?;
+        tar::Archive::new(GzDecoder::new(archive))
+            .unpack(dir.path())
+            .map_err(|e| format!("Error unpacking archive {}: {}", file.display(), e))?;
+
+        let manifest_content =
+            fs::read_to_string(dir.path().join(MANIFEST_FILE)).map_err(|_| "Archive is missing its manifest")?;
+        let manifest: Manifest =
+            serde_json::from_str(&manifest_content).map_err(|e| format!("Error parsing archive manifest: {}", e))?;
+        if manifest.format_version == 0 || manifest.format_version > ARCHIVE_FORMAT_VERSION {
+            return Err(format!(
+                "Archive format version {} is not supported, this ruku supports up to {}",
+                manifest.format_version, ARCHIVE_FORMAT_VERSION
+            ));
+        }
+
+        let config_content =
+            fs::read_to_string(dir.path().join(CONFIG_FILE)).map_err(|_| "Archive is missing ruku.yml")?;
+        let mut config: RukuConfig =
+            serde_yaml::from_str(&config_content).map_err(|e| format!("Error parsing ruku.yml file: {}", e))?;
+        config.resolve_paths(&self.server_config.apps_root.join(&manifest.app));
+        if let Err(e) = config.validate() {
+            return Err(format!("Error validating ruku.yml file: {}", e));
+        }
+
+        if self.server_config.apps_root.join(&manifest.app).exists() {
+            return Err(format!("App {} already exists on this host", manifest.app));
+        }
+
+        Ok(UnpackedArchive { dir, manifest, config })
+    }
+
+    /// What restoring the archive writes on the host, for the confirmation.
+    pub fn affected(&self, archive: &UnpackedArchive) -> Vec<String> {
+        let app = &archive.manifest.app;
+        let mut affected = vec![format!(
+            "create {}",
+            self.server_config.apps_root.join(app).join(CONFIG_FILE).display()
+        )];
+        if archive.path(History::FILE_NAME).exists() {
+            let history = self.server_config.state_root.join(app).join(History::FILE_NAME);
+            affected.push(format!("write {}", history.display()));
+        }
+        if archive.manifest.data {
+            let data_path = self.server_config.data_root.join(app);
+            // Files of the archive take the place of the ones by the same name
+            let verb = if data_path.exists() {
+                "overwrite files in"
+            } else {
+                "create"
+            };
+            affected.push(format!("{} {}", verb, data_path.display()));
+        }
+        for key in &archive.manifest.volumes {
+            affected.push(format!("fill volume {}", get_volume_name(app, key)));
+        }
+        affected.push(format!(
+            "start {} from {}",
+            app,
+            get_image_name_with_version(app, &archive.config.version)
+        ));
+        affected
+    }
+
+    /// Restore the config, deployment history and data of the app.
+    pub fn restore(&self, archive: &UnpackedArchive) -> Result<(), String> {
+        let app = &archive.manifest.app;
+        let app_path = self.server_config.apps_root.join(app);
+        let state_path = self.server_config.state_root.join(app);
+        let data_path = self.server_config.data_root.join(app);
+
+        self.log.step(&format!("Restoring config to {}", app_path.display()));
+        self.create_dir(&app_path)?;
+        self.copy(&archive.path(CONFIG_FILE), &app_path.join(CONFIG_FILE))?;
+
+        let history_file = archive.path(History::FILE_NAME);
+        if history_file.exists() {
+            self.log.step("Restoring deployment history");
+            self.create_dir(&state_path)?;
+            self.copy(&history_file, &state_path.join(History::FILE_NAME))?;
+        }
+
+        if archive.manifest.data {
+            self.log.step(&format!("Restoring data to {}", data_path.display()));
+            self.copy_dir(&archive.path(DATA_DIR), &data_path)?;
+        }
+        Ok(())
+    }
+
+    /// Load (or pull) the image and start the app container.
+    pub async fn deploy(&self, archive: &UnpackedArchive, docker: &Docker) -> Result<(), String> {
+        let app = &archive.manifest.app;
+        let image_name_with_version = get_image_name_with_version(app, &archive.config.version);
+        let started_at = Utc::now();
+
+        if archive.manifest.image.is_some() {
+            let _slot = DeploySlots::new(self.log, self.server_config).acquire().await?;
+            self.log.step("Loading image from archive");
+            Image::new(self.log, docker).load(&archive.path(IMAGE_FILE)).await?;
+        } else {
+            let image = Image::new(self.log, docker);
+            match InFlight::new(self.log, self.server_config)
+                .turn(&image_name_with_version, "pull")
+                .await
+            {
+                Turn::Reuse(pid) if image.exists(&image_name_with_version).await => self.log.step(&format!(
+                    "Using image {} pulled by pid {}",
+                    image_name_with_version, pid
+                )),
+                turn => {
+                    let _slot = DeploySlots::new(self.log, self.server_config).acquire().await?;
+                    image.pull(&image_name_with_version).await?;
+                    if let Turn::Run(lead) = turn {
+                        lead.finish();
+                    }
+                }
+            }
+        }
+
+        // Filled before the container first mounts them
+        if !archive.manifest.volumes.is_empty() {
+            let volumes = Volumes::new(self.log, app, docker);
+            volumes.ensure(&archive.config.all_volume_specs()).await?;
+            for key in &archive.manifest.volumes {
+                let volume_name = get_volume_name(app, key);
+                self.log.step(&format!("Restoring volume {}", volume_name));
+                let file = archive.path(VOLUMES_DIR).join(format!("{}.tar", key));
+                volumes
+                    .import(key, &file)
+                    .await
+                    .map_err(|e| format!("Error restoring volume {}: {}", volume_name, e))?;
+            }
+        }
+
+        let container = Container::new(self.log, app, docker, &archive.config);
+        container.run().await?;
+
+        History::new(self.log, &self.server_config.state_root.join(app)).record(Deployment::new(
+            &archive.config.version,
+            &image_name_with_version,
+            started_at,
+        ))?;
+        self.log.step(&format!("Imported {}", app));
+        Ok(())
+    }
+
+    fn create_dir(&self, path: &Path) -> Result<(), String> {
+        fs::create_dir_all(path).map_err(|e| format!("Error creating directory: {}", e))
+    }
+
+    fn copy(&self, from: &Path, to: &Path) -> Result<(), String> {
+        fs::copy(from, to).map_err(|e| format!("Error copying {}: {}", from.display(), e))?;
+        Ok(())
+    }
+
+    fn copy_dir(&self, from: &Path, to: &Path) -> Result<(), String> {
+        self.create_dir(to)?;
+        let entries = fs::read_dir(from).map_err(|e| format!("Error reading directory {}: {}", from.display(), e))?;
+        for entry in entries.flatten() {
+            let target = to.join(entry.file_name());
+            if entry.path().is_dir() {
+                self.copy_dir(&entry.path(), &target)?;
+            } else {
+                self.copy(&entry.path(), &target)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn manifests_of_archives_without_volumes_still_open() {
+        let manifest: Manifest = serde_json::from_str(
+            r#"{"format_version": 1, "ruku_version": "0.1.0", "app": "shop", "version": "1.0",
+                "image": null, "data": true, "created_at": "2026-01-02T03:04:05Z"}"#,
+        )
+        .unwrap();
+        assert!(manifest.volumes.is_empty());
+        assert!(manifest.data);
+    }
+}
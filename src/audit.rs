@@ -0,0 +1,432 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::error::RukuError;
+use crate::freeze;
+use crate::logger::Logger;
+use crate::read_only::guard;
+use crate::sha256::sha256_hex;
+use crate::store;
+use crate::version;
+
+/// The hash the first record chains to.
+const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// One state-changing command, as it is chained into the audit log.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditRecord {
+    /// Position in the log, starting at 0, so a log missing its first lines is noticed.
+    pub seq: u64,
+    pub timestamp: DateTime<Utc>,
+    /// Who ran the command, the user behind sudo when there is one.
+    pub operator: String,
+    pub command: String,
+    pub app: Option<String>,
+    /// What else the command acted on, e.g. the key `config:set` changed.
+    pub detail: Option<String>,
+    pub old_version: Option<String>,
+    pub new_version: Option<String>,
+    /// `succeeded`, or `failed: ` followed by the error.
+    pub outcome: String,
+    /// The ruku build that ran the command, none in records from before it was recorded. Left out when
+    /// none so those records keep their hash.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ruku_version: Option<String>,
+    pub prev_hash: String,
+    /// SHA-256 of the record without this field, which covers the previous hash.
+    pub hash: String,
+}
+
+impl AuditRecord {
+    fn compute_hash(&self) -> String {
+        let mut unsigned = self.clone();
+        unsigned.hash = String::new();
+        sha256_hex(serde_json::to_string(&unsigned).unwrap().as_bytes())
+    }
+}
+
+/// The last record written, kept next to the log so a log cut short at the end is noticed too.
+#[derive(Debug, Serialize, Deserialize)]
+struct AuditHead {
+    seq: u64,
+    hash: String,
+}
+
+/// A command being audited, recorded as failed when an error is logged before it finishes.
+pub struct PendingAudit {
+    log_path: PathBuf,
+    record: Arc<Mutex<Option<AuditRecord>>>,
+}
+
+impl PendingAudit {
+    /// The version the app ran before the command.
+    pub fn old_version(&self, version: Option<String>) {
+        if let Some(record) = self.record.lock().unwrap().as_mut() {
+            record.old_version = version;
+        }
+    }
+
+    /// The version the app runs after the command.
+    pub fn new_version(&self, version: Option<String>) {
+        if let Some(record) = self.record.lock().unwrap().as_mut() {
+            record.new_version = version;
+        }
+    }
+
+    /// Add to what else the command acted on, after the detail it was started with.
+    pub fn detail(&self, detail: &str) {
+        if let Some(record) = self.record.lock().unwrap().as_mut() {
+            record.detail = Some(match record.detail.take() {
+                Some(before) => format!("{}; {}", before, detail),
+                None => detail.to_string(),
+            });
+        }
+    }
+
+    /// Record the command as succeeded, unless it already failed.
+    pub fn succeeded(self, log: &Logger) {
+        let record = self.record.lock().unwrap().take();
+        if let Some(record) = record {
+            if let Err(e) = AuditLog::at(self.log_path).append(record) {
+                log.warn(&format!("Error writing the audit log: {}", e));
+            }
+        }
+    }
+}
+
+/// Every state-changing command on the host, one JSON record per line, each carrying the hash of the one
+/// before it.
+pub struct AuditLog {
+    path: PathBuf,
+}
+
+impl AuditLog {
+    pub const FILE_NAME: &'static str = "audit.log";
+    const HEAD_FILE_NAME: &'static str = "audit.head";
+
+    pub fn new(state_root: &Path) -> AuditLog {
+        AuditLog::at(state_root.join(Self::FILE_NAME))
+    }
+
+    fn at(path: PathBuf) -> AuditLog {
+        AuditLog { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Start auditing `command`, which is recorded as failed if `log` reports an error before
+    /// [`PendingAudit::succeeded`]. Fails in read-only mode.
+    pub fn begin(
+        &self,
+        log: &Logger,
+        command: &str,
+        app: Option<&str>,
+        detail: Option<&str>,
+    ) -> Result<PendingAudit, RukuError> {
+        // Every state-changing command is audited, so none gets past here in read-only mode
+        guard(&format!("run {}", command))?;
+        let detail = match (detail, freeze::overridden()) {
+            (Some(detail), Some(overridden)) => Some(format!("{}; {}", detail, overridden)),
+            (detail, overridden) => detail.map(str::to_string).or(overridden),
+        };
+        let record = Arc::new(Mutex::new(Some(AuditRecord {
+            seq: 0,
+            timestamp: Utc::now(),
+            operator: operator(),
+            command: command.to_string(),
+            app: app.map(str::to_string),
+            detail,
+            old_version: None,
+            new_version: None,
+            outcome: "succeeded".to_string(),
+            ruku_version: Some(version::LONG_VERSION.to_string()),
+            prev_hash: String::new(),
+            hash: String::new(),
+        })));
+        let pending = Arc::clone(&record);
+        let path = self.path.clone();
+        log.on_error(move |message| {
+            if let Some(mut record) = pending.lock().unwrap().take() {
+                record.outcome = format!("failed: {}", message);
+                // The error is what the operator sees, a failing audit write must not hide it
+                let _ = AuditLog::at(path).append(record);
+            }
+        });
+        Ok(PendingAudit {
+            log_path: self.path.clone(),
+            record,
+        })
+    }
+
+    /// Chain `record` onto the log. The log is locked while the last record is read and the new one is
+    /// written, so concurrent commands queue up instead of chaining onto the same record. A last line a
+    /// crash cut short is dropped first, with a record of how much of it was lost.
+    fn append(&self, record: AuditRecord) -> Result<(), String> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+        }
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&self.path)
+            .map_err(|e| e.to_string())?;
+        file.lock().map_err(|e| e.to_string())?;
+
+        let mut content = vec![];
+        file.read_to_end(&mut content).map_err(|e| e.to_string())?;
+        // Every record is written with its newline, what follows the last one is a torn write
+        let complete = content.iter().rposition(|&b| b == b'\n').map_or(0, |i| i + 1);
+        let tail = &content[complete..];
+        let mut torn = None;
+        if !tail.iter().all(u8::is_ascii_whitespace) {
+            match serde_json::from_slice::<AuditRecord>(tail) {
+                // Only the newline is missing
+                Ok(_) => file.write_all(b"\n").map_err(|e| e.to_string())?,
+                Err(_) => {
+                    file.set_len(complete as u64).map_err(|e| e.to_string())?;
+                    torn = Some(tail.len());
+                    content.truncate(complete);
+                }
+            }
+        }
+        let content = String::from_utf8_lossy(&content);
+        let mut last = content
+            .lines()
+            .rev()
+            .find(|line| !line.trim().is_empty())
+            .map(|line| serde_json::from_str::<AuditRecord>(line).map_err(|e| format!("unreadable last record: {}", e)))
+            .transpose()?;
+
+        if let Some(bytes) = torn {
+            let repair = AuditRecord {
+                command: "audit:repair".to_string(),
+                app: None,
+                detail: Some(format!(
+                    "dropped {} bytes of a record cut short at the end of the log, ruku was interrupted writing it",
+                    bytes
+                )),
+                old_version: None,
+                new_version: None,
+                outcome: "succeeded".to_string(),
+                ..record.clone()
+            };
+            last = Some(Self::chain(&mut file, last.as_ref(), repair)?);
+        }
+        let record = Self::chain(&mut file, last.as_ref(), record)?;
+        self.write_head(&AuditHead {
+            seq: record.seq,
+            hash: record.hash,
+        })
+    }
+
+    /// Write `record` after `last` with its place in the chain filled in.
+    fn chain(file: &mut File, last: Option<&AuditRecord>, mut record: AuditRecord) -> Result<AuditRecord, String> {
+        record.seq = last.map_or(0, |last| last.seq + 1);
+        record.prev_hash = last.map_or(GENESIS_HASH.to_string(), |last| last.hash.clone());
+        record.timestamp = Utc::now();
+        record.hash = record.compute_hash();
+
+        // One write of a whole line, appended, so a crash leaves at most a partial last line
+        let line = format!("{}\n", serde_json::to_string(&record).unwrap());
+        file.write_all(line.as_bytes()).map_err(|e| e.to_string())?;
+        file.sync_data().map_err(|e| e.to_string())?;
+        Ok(record)
+    }
+
+    fn write_head(&self, head: &AuditHead) -> Result<(), String> {
+        let path = self.path.with_file_name(Self::HEAD_FILE_NAME);
+        store::write_atomic(&path, serde_json::to_string(head).unwrap().as_bytes()).map_err(|e| e.to_string())
+    }
+
+    /// Every record after checking the chain, or the line number and what is wrong with it.
+    pub fn verify(&self) -> Result<Vec<AuditRecord>, (usize, String)> {
+        let content = match File::open(&self.path) {
+            Ok(mut file) => {
+                let mut content = vec![];
+                file.read_to_end(&mut content).map_err(|e| (0, e.to_string()))?;
+                // A write cut short may end within a character
+                String::from_utf8_lossy(&content).into_owned()
+            }
+            Err(_) => String::new(),
+        };
+
+        let mut records: Vec<AuditRecord> = vec![];
+        let lines = content.lines().count();
+        for (i, line) in content.lines().enumerate().filter(|(_, line)| !line.trim().is_empty()) {
+            let number = i + 1;
+            let record: AuditRecord =
+                serde_json::from_str(line).map_err(|e| match number == lines && !content.ends_with('\n') {
+                    true => (
+                        number,
+                        "the last record is incomplete, ruku was interrupted writing it, the next command drops it"
+                            .to_string(),
+                    ),
+                    false => (number, format!("unreadable record: {}", e)),
+                })?;
+            let (expected_seq, expected_prev) = match records.last() {
+                Some(previous) => (previous.seq + 1, previous.hash.as_str()),
+                None => (0, GENESIS_HASH),
+            };
+            if record.seq != expected_seq {
+                return Err((
+                    number,
+                    format!(
+                        "record {} follows record {}, records are missing",
+                        record.seq, expected_seq
+                    ),
+                ));
+            }
+            if record.prev_hash != expected_prev {
+                return Err((number, "the previous record was changed or removed".to_string()));
+            }
+            if record.hash != record.compute_hash() {
+                return Err((number, "the record was changed".to_string()));
+            }
+            records.push(record);
+        }
+
+        let head_path = self.path.with_file_name(Self::HEAD_FILE_NAME);
+        if let Some(head) = fs::read_to_string(head_path)
+            .ok()
+            .and_then(|content| serde_json::from_str::<AuditHead>(&content).ok())
+        {
+            let last = records.last();
+            if last.map(|last| (last.seq, last.hash.as_str())) != Some((head.seq, head.hash.as_str())) {
+                return Err((
+                    content.lines().count(),
+                    format!("the log ends before record {}, it was cut short", head.seq),
+                ));
+            }
+        }
+        Ok(records)
+    }
+}
+
+/// The user running ruku, the one who invoked sudo rather than root.
+fn operator() -> String {
+    std::env::var("SUDO_USER")
+        .or_else(|_| std::env::var("USER"))
+        .unwrap_or("unknown".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(command: &str) -> AuditRecord {
+        AuditRecord {
+            seq: 0,
+            timestamp: Utc::now(),
+            operator: "alice".to_string(),
+            command: command.to_string(),
+            app: Some("blog".to_string()),
+            detail: None,
+            old_version: None,
+            new_version: Some("1.0".to_string()),
+            outcome: "succeeded".to_string(),
+            ruku_version: None,
+            prev_hash: String::new(),
+            hash: String::new(),
+        }
+    }
+
+    fn commands(records: &[AuditRecord]) -> Vec<&str> {
+        records.iter().map(|record| record.command.as_str()).collect()
+    }
+
+    #[test]
+    fn appended_records_are_chained_and_verify() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = AuditLog::new(dir.path());
+        assert!(log.verify().unwrap().is_empty());
+        for command in ["deploy", "config:set", "stop"] {
+            log.append(record(command)).unwrap();
+        }
+
+        let records = log.verify().unwrap();
+        assert_eq!(commands(&records), ["deploy", "config:set", "stop"]);
+        assert_eq!(records.iter().map(|record| record.seq).collect::<Vec<_>>(), [0, 1, 2]);
+        assert_eq!(records[0].prev_hash, GENESIS_HASH);
+        assert_eq!(records[2].prev_hash, records[1].hash);
+    }
+
+    #[test]
+    fn changed_removed_and_cut_records_fail_verification() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = AuditLog::new(dir.path());
+        for command in ["deploy", "config:set", "stop"] {
+            log.append(record(command)).unwrap();
+        }
+        let content = fs::read_to_string(log.path()).unwrap();
+        let lines: Vec<&str> = content.lines().collect();
+
+        fs::write(log.path(), content.replace("config:set", "config:get")).unwrap();
+        assert_eq!(log.verify().unwrap_err(), (2, "the record was changed".to_string()));
+
+        fs::write(log.path(), format!("{}\n{}\n", lines[0], lines[2])).unwrap();
+        assert_eq!(
+            log.verify().unwrap_err(),
+            (2, "record 2 follows record 1, records are missing".to_string())
+        );
+
+        fs::write(log.path(), format!("{}\n{}\n", lines[0], lines[1])).unwrap();
+        assert_eq!(
+            log.verify().unwrap_err(),
+            (2, "the log ends before record 2, it was cut short".to_string())
+        );
+    }
+
+    #[test]
+    fn a_record_torn_by_a_crash_is_dropped_by_the_next_append() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = AuditLog::new(dir.path());
+        log.append(record("deploy")).unwrap();
+        let intact = fs::read(log.path()).unwrap();
+        // The line of a second record cut short, as a crash writing it leaves it
+        log.append(record("stop")).unwrap();
+        let written = fs::read(log.path()).unwrap();
+        fs::write(log.path(), &written[..intact.len() + 40]).unwrap();
+        // The head is written after the record, so it still names the first
+        let head = serde_json::from_slice::<AuditRecord>(intact.trim_ascii_end()).unwrap();
+        log.write_head(&AuditHead {
+            seq: 0,
+            hash: head.hash,
+        })
+        .unwrap();
+        assert_eq!(
+            log.verify().unwrap_err(),
+            (
+                2,
+                "the last record is incomplete, ruku was interrupted writing it, the next command drops it".to_string()
+            )
+        );
+
+        log.append(record("start")).unwrap();
+        let records = log.verify().unwrap();
+        assert_eq!(commands(&records), ["deploy", "audit:repair", "start"]);
+        assert_eq!(
+            records[1].detail.as_deref(),
+            Some("dropped 40 bytes of a record cut short at the end of the log, ruku was interrupted writing it")
+        );
+    }
+
+    #[test]
+    fn a_record_missing_only_its_newline_is_kept() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = AuditLog::new(dir.path());
+        log.append(record("deploy")).unwrap();
+        let content = fs::read_to_string(log.path()).unwrap();
+        fs::write(log.path(), content.trim_end()).unwrap();
+
+        log.append(record("stop")).unwrap();
+        assert_eq!(commands(&log.verify().unwrap()), ["deploy", "stop"]);
+    }
+}
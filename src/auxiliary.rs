@@ -0,0 +1,152 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use bollard::container::{ListContainersOptions, RemoveContainerOptions};
+use bollard::errors::Error;
+use bollard::models::ContainerSummary;
+use bollard::Docker;
+use chrono::Utc;
+
+use crate::container::{get_container_name, APP_LABEL};
+
+/// Label with the kind of a one-off container ruku runs next to an app, e.g. `ruku.aux=probe`.
+pub const AUX_LABEL: &str = "ruku.aux";
+/// Label with the id of the deploy a one-off container was run for.
+pub const DEPLOYMENT_LABEL: &str = "ruku.deployment";
+/// Seconds a one-off container may exist before `repair` and the next deploy take it for a leftover.
+pub const DEFAULT_AUX_MAX_AGE: u64 = 3600;
+
+/// The deploy this process runs, its one-off containers are labeled with it.
+static DEPLOYMENT: Mutex<Option<String>> = Mutex::new(None);
+
+/// Label the one-off containers created from now on with the deploy `id`.
+pub fn set_deployment(id: &str) {
+    *DEPLOYMENT.lock().unwrap() = Some(id.to_string());
+}
+
+/// What a one-off container is for.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AuxKind {
+    /// Runs the `pre_start` command before the app starts.
+    PreStart,
+    /// Checks the app from its network for the health gate or smoke tests.
+    Probe,
+    /// Never started, gives access to what the image ships at the bind mount targets.
+    MountCheck,
+    /// Never started, copies a named volume in or out for `export` and `import`.
+    VolumeCopy,
+}
+
+impl fmt::Display for AuxKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AuxKind::PreStart => write!(f, "pre-start"),
+            AuxKind::Probe => write!(f, "probe"),
+            AuxKind::MountCheck => write!(f, "mount-check"),
+            AuxKind::VolumeCopy => write!(f, "volume-copy"),
+        }
+    }
+}
+
+/// The labels of a one-off container of `app`. Without a role label nothing takes it for the app itself.
+pub fn aux_labels(app: &str, kind: AuxKind) -> HashMap<String, String> {
+    let mut labels = HashMap::from([
+        (APP_LABEL.to_string(), app.to_string()),
+        (AUX_LABEL.to_string(), kind.to_string()),
+    ]);
+    if let Some(deployment) = DEPLOYMENT.lock().unwrap().clone() {
+        labels.insert(DEPLOYMENT_LABEL.to_string(), deployment);
+    }
+    labels
+}
+
+/// Whether `summary` is a one-off container rather than one of the app.
+pub fn is_aux(summary: &ContainerSummary) -> bool {
+    summary
+        .labels
+        .as_ref()
+        .is_some_and(|labels| labels.contains_key(AUX_LABEL))
+}
+
+/// How long ago the container was created, none when Docker doesn't say.
+pub fn age(summary: &ContainerSummary) -> Option<Duration> {
+    let created = summary.created?;
+    Some(Duration::from_secs((Utc::now().timestamp() - created).max(0) as u64))
+}
+
+/// The one-off containers of `app`, or of every app.
+pub async fn list_aux(docker: &Docker, app: Option<&str>) -> Result<Vec<ContainerSummary>, Error> {
+    let app_filter = app.map(|app| format!("{}={}", APP_LABEL, app));
+    let mut labels = vec![AUX_LABEL];
+    labels.extend(app_filter.as_deref());
+    let options = ListContainersOptions {
+        all: true,
+        filters: HashMap::from([("label", labels)]),
+        ..Default::default()
+    };
+    docker.list_containers(Some(options)).await
+}
+
+/// A row of `list --aux`.
+pub fn describe_aux(summary: &ContainerSummary) -> String {
+    let labels = summary.labels.clone().unwrap_or_default();
+    let label = |name: &str| labels.get(name).cloned().unwrap_or("-".to_string());
+    let age = age(summary).map_or("-".to_string(), crate::units::format_duration);
+    format!(
+        "{:<28} {:<16} {:<10} {:<10} {:<8} {}",
+        get_container_name(summary).unwrap_or_default(),
+        label(APP_LABEL),
+        label(AUX_LABEL),
+        summary.state.as_deref().unwrap_or("unknown"),
+        age,
+        label(DEPLOYMENT_LABEL)
+    )
+}
+
+/// Removes a one-off container when dropped, so a run that errors, times out or is aborted by a deploy
+/// deadline doesn't leave it behind. A process that is killed can't, `repair` removes those.
+pub struct AuxGuard {
+    docker: Docker,
+    name: String,
+    removed: bool,
+}
+
+impl AuxGuard {
+    pub fn new(docker: &Docker, name: &str) -> AuxGuard {
+        AuxGuard {
+            docker: docker.clone(),
+            name: name.to_string(),
+            removed: false,
+        }
+    }
+
+    /// Remove the container now and wait for it.
+    pub async fn remove(mut self) {
+        remove(&self.docker, &self.name).await;
+        self.removed = true;
+    }
+}
+
+impl Drop for AuxGuard {
+    fn drop(&mut self) {
+        if self.removed {
+            return;
+        }
+        // Dropped in an async context, the removal can't be waited for here
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            let (docker, name) = (self.docker.clone(), self.name.clone());
+            runtime.spawn(async move { remove(&docker, &name).await });
+        }
+    }
+}
+
+/// Force remove the container `name`, one that doesn't exist is fine.
+pub async fn remove(docker: &Docker, name: &str) {
+    let options = RemoveContainerOptions {
+        force: true,
+        ..Default::default()
+    };
+    let _ = docker.remove_container(name, Some(options)).await;
+}
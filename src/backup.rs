@@ -0,0 +1,198 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use bollard::container::Config;
+use bollard::image::CommitContainerOptions;
+use bollard::Docker;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::connection::active_context;
+use crate::image::Image;
+use crate::logger::Logger;
+use crate::store;
+
+/// Repository prefix of backup images, `ruku-backup/<app>:<timestamp>`. No cleanup of app images
+/// touches it, backups are only pruned here.
+pub const BACKUP_REPOSITORY: &str = "ruku-backup";
+/// How many backups are kept per app, older ones are removed with their image.
+const BACKUP_RETENTION: usize = 3;
+/// How often a running commit is reported.
+const REPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// A container committed to an image before ruku removed it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Backup {
+    /// The backup image, `ruku-backup/<app>:<timestamp>`.
+    pub image: String,
+    pub container_name: String,
+    /// The image the container was running.
+    pub source_image: Option<String>,
+    /// What removed the container, e.g. `destroy`.
+    pub reason: String,
+    pub created_at: DateTime<Utc>,
+    /// The context whose daemon has the image, none for the local daemon.
+    #[serde(default)]
+    pub host: Option<String>,
+}
+
+/// The context in use, which backups are recorded with.
+fn here() -> Option<String> {
+    active_context().map(|(name, _)| name.clone())
+}
+
+/// The backups of an app, listed in `backups.json` in the app state directory, oldest first.
+pub struct Backups<'a> {
+    log: &'a Logger,
+    name: &'a str,
+    docker: &'a Docker,
+    path: PathBuf,
+    timeout: Duration,
+}
+
+impl<'a> Backups<'a> {
+    pub const FILE_NAME: &'static str = "backups.json";
+
+    pub fn new(log: &'a Logger, name: &'a str, docker: &'a Docker, state_dir: &Path) -> Backups<'a> {
+        Backups {
+            log,
+            name,
+            docker,
+            path: state_dir.join(Self::FILE_NAME),
+            timeout: Duration::from_secs(600),
+        }
+    }
+
+    /// Give up on a commit that takes longer than this.
+    pub fn with_timeout(mut self, timeout: Duration) -> Backups<'a> {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Commit the container to a backup image and record it, pruning all but the newest backups. Fails when
+    /// the commit fails or times out, so nothing is removed without its backup.
+    pub async fn create(
+        &self,
+        container_id: &str,
+        container_name: &str,
+        source_image: Option<&str>,
+        reason: &str,
+    ) -> Result<(), String> {
+        let created_at = Utc::now();
+        let repository = format!("{}/{}", BACKUP_REPOSITORY, self.name);
+        let tag = created_at.format("%Y%m%d%H%M%S").to_string();
+        let image = format!("{}:{}", repository, tag);
+        self.log
+            .step(&format!("Backing up container {} to {}", container_name, image));
+
+        let options = CommitContainerOptions {
+            container: container_id.to_string(),
+            repo: repository,
+            tag,
+            comment: format!("Backup before {}", reason),
+            author: "ruku".to_string(),
+            pause: true,
+            changes: None,
+        };
+        let started = Instant::now();
+        let commit = self.docker.commit_container(options, Config::<String>::default());
+        tokio::pin!(commit);
+        let mut report = tokio::time::interval_at(tokio::time::Instant::now() + REPORT_INTERVAL, REPORT_INTERVAL);
+        let result = loop {
+            tokio::select! {
+                result = &mut commit => break result,
+                _ = report.tick() => {
+                    if started.elapsed() >= self.timeout {
+                        return Err(format!(
+                            "The backup of {} was not done after {}s, pass --no-backup to go ahead without one",
+                            container_name,
+                            self.timeout.as_secs()
+                        ));
+                    }
+                    self.log
+                        .step(&format!("Still committing {}, {}s so far", container_name, started.elapsed().as_secs()));
+                }
+            }
+        };
+        if let Err(e) = result {
+            return Err(format!(
+                "Failed to back up {}: {}, pass --no-backup to go ahead without one",
+                container_name, e
+            ));
+        }
+        self.log.step(&format!(
+            "Backed up {} in {}s, `ruku undo {}` deploys it again",
+            container_name,
+            started.elapsed().as_secs(),
+            self.name
+        ));
+
+        // The backups of other hosts are kept as they are, their images are on other daemons
+        let (mut backups, mut others): (Vec<Backup>, Vec<Backup>) =
+            self.load()?.into_iter().partition(|backup| backup.host == here());
+        backups.push(Backup {
+            image,
+            container_name: container_name.to_string(),
+            source_image: source_image.map(|image| image.to_string()),
+            reason: reason.to_string(),
+            created_at,
+            host: here(),
+        });
+        let pruned = backups.len().saturating_sub(BACKUP_RETENTION);
+        let image_store = Image::new(self.log, self.docker);
+        for backup in backups.drain(..pruned) {
+            if image_store.exists(&backup.image).await {
+                image_store.remove(&backup.image).await;
+            }
+        }
+        others.extend(backups);
+        others.sort_by_key(|backup| backup.created_at);
+        self.save(&others)
+    }
+
+    /// The recorded backups on the daemon in use, oldest first.
+    pub fn list(&self) -> Result<Vec<Backup>, String> {
+        Ok(self
+            .load()?
+            .into_iter()
+            .filter(|backup| backup.host == here())
+            .collect())
+    }
+
+    fn load(&self) -> Result<Vec<Backup>, String> {
+        let Ok(content) = fs::read_to_string(&self.path) else {
+            return Ok(vec![]);
+        };
+        serde_json::from_str(&content).map_err(|e| format!("Error parsing {}: {}", self.path.display(), e))
+    }
+
+    /// The newest backup, or the one with tag `tag`, failing when there is none.
+    pub fn find(&self, tag: Option<&str>) -> Result<Backup, String> {
+        let backups = self.list()?;
+        let backup = match tag {
+            Some(tag) => backups
+                .iter()
+                .find(|backup| backup.image.rsplit_once(':').is_some_and(|(_, t)| t == tag)),
+            None => backups.last(),
+        };
+        match backup {
+            Some(backup) => Ok(backup.clone()),
+            None if backups.is_empty() => Err(format!("{} has no backups", self.name)),
+            None => {
+                let images: Vec<&str> = backups.iter().map(|backup| backup.image.as_str()).collect();
+                Err(format!(
+                    "No backup {} of {}, known: {}",
+                    tag.unwrap_or_default(),
+                    self.name,
+                    images.join(", ")
+                ))
+            }
+        }
+    }
+
+    fn save(&self, backups: &[Backup]) -> Result<(), String> {
+        store::write_atomic(&self.path, serde_json::to_string_pretty(backups).unwrap().as_bytes())
+            .map_err(|e| format!("Error writing {}: {}", self.path.display(), e))
+    }
+}
@@ -0,0 +1,69 @@
+use std::time::Duration;
+
+use crate::container::{is_managed, Container};
+use crate::logger::Logger;
+use crate::rolling::Rolling;
+use crate::smoke::SmokeResult;
+use crate::strategy::Strategy;
+
+/// Starts the new version as `<app>-green` next to the running one, with no published port and no
+/// alias so nothing reaches it but the health and smoke checks. The old version keeps serving until
+/// the new one passed them, then they are swapped the rolling way.
+pub struct BlueGreen<'a> {
+    log: &'a Logger,
+    stable: &'a Container<'a>,
+    green: Container<'a>,
+    rolling: Rolling<'a>,
+    health_timeout: Duration,
+}
+
+impl<'a> BlueGreen<'a> {
+    pub fn new(log: &'a Logger, stable: &'a Container<'a>, health_timeout: Duration) -> BlueGreen<'a> {
+        BlueGreen {
+            log,
+            stable,
+            green: stable.staged("green").with_publish(false),
+            // The green container ran the pre_start command already
+            rolling: Rolling::new(log, stable, health_timeout).with_skip_pre_start(true),
+            health_timeout,
+        }
+    }
+}
+
+impl Strategy for BlueGreen<'_> {
+    fn plan(&self) -> Vec<String> {
+        let mut plan = vec![format!(
+            "Checking the new version in {} while {} keeps serving",
+            self.green.container_name(),
+            self.stable.container_name()
+        )];
+        plan.extend(self.rolling.plan());
+        plan
+    }
+
+    async fn execute(&self) -> Result<Vec<SmokeResult>, String> {
+        self.green.discard().await?;
+        // Without a container of its own to keep, the rolling swap replaces it and gates on health alone
+        if self.stable.get().await?.is_some_and(|summary| is_managed(&summary)) {
+            self.green.prepare(self.stable.image_name()).await?;
+            self.green.resume().await?;
+            self.green.wait_healthy(self.health_timeout).await?;
+            self.green.smoke_test().await?;
+            self.log
+                .step(&format!("{} is healthy, switching over", self.green.container_name()));
+            self.green.discard().await?;
+        }
+        self.rolling.execute().await
+    }
+
+    async fn failed(&self) -> Vec<String> {
+        let mut failed = vec![self.green.container_name().to_string()];
+        failed.extend(self.rolling.failed().await);
+        failed
+    }
+
+    async fn rollback(&self) -> Result<(), String> {
+        self.green.discard().await?;
+        self.rolling.rollback().await
+    }
+}
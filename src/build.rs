@@ -0,0 +1,551 @@
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use cmd_lib::{run_cmd, run_fun};
+use nixpacks::nixpacks::builder::docker::docker_image_builder::DockerImageBuilder;
+use nixpacks::nixpacks::builder::docker::DockerBuilderOptions;
+use nixpacks::nixpacks::builder::ImageBuilder;
+use nixpacks::nixpacks::environment::Environment;
+use nixpacks::nixpacks::plan::{generator::GeneratePlanOptions, BuildPlan};
+use serde::{Deserialize, Serialize};
+
+use crate::build_cache::{build_step, CacheTally, CacheUse};
+use crate::container::{test_labels, APP_LABEL};
+use crate::context::{BuildContext, Compression};
+use crate::logger::{Logger, Target};
+use crate::model::Builder;
+use crate::store;
+use crate::units::{format_rate, format_size};
+
+/// Default builder image used with the pack CLI.
+pub const DEFAULT_PACK_BUILDER: &str = "paketobuildpacks/builder-jammy-base";
+
+/// Files whose presence identifies the language runtime of a project.
+const RUNTIME_MARKERS: [(&str, &str); 5] = [
+    ("package.json", "node"),
+    ("requirements.txt", "python"),
+    ("pyproject.toml", "python"),
+    ("go.mod", "go"),
+    ("Cargo.toml", "rust"),
+];
+
+/// Files that influence the nixpacks build plan, the cached plan is reused while they are unchanged.
+const PLAN_INPUTS: [&str; 14] = [
+    "nixpacks.toml",
+    "package.json",
+    "package-lock.json",
+    "yarn.lock",
+    "pnpm-lock.yaml",
+    "requirements.txt",
+    "pyproject.toml",
+    "poetry.lock",
+    "go.mod",
+    "go.sum",
+    "Cargo.toml",
+    "Cargo.lock",
+    "Procfile",
+    ".nvmrc",
+];
+
+const PLAN_CACHE_FILE: &str = "build-plan.json";
+/// The digest of the last Dockerfile build context and the image built from it.
+const CONTEXT_CACHE_FILE: &str = "build-context.json";
+/// How often the upload of the build context reports its progress.
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(2);
+
+/// The context an image was last built from, so the same context builds nothing new.
+#[derive(Debug, Serialize, Deserialize)]
+struct ContextCache {
+    digest: String,
+    image: String,
+    image_id: String,
+}
+
+/// Language runtime detected from the files at the root of the project.
+pub fn detect_runtime(path: &Path) -> Option<&'static str> {
+    RUNTIME_MARKERS
+        .iter()
+        .find(|(file, _)| path.join(file).exists())
+        .map(|(_, runtime)| *runtime)
+}
+
+/// The builder to use, an explicit choice wins over the presence of a Dockerfile.
+pub fn detect_builder(path: &Path, configured: Option<Builder>) -> Builder {
+    configured.unwrap_or(if path.join("Dockerfile").exists() {
+        Builder::Dockerfile
+    } else {
+        Builder::Nixpacks
+    })
+}
+
+#[derive(Serialize, Deserialize)]
+struct CachedPlan {
+    key: String,
+    plan: BuildPlan,
+}
+
+/// Where a build leaves the image.
+#[derive(Debug, Clone, PartialEq)]
+enum Output {
+    /// The local image store.
+    Local,
+    /// An OCI archive, for a multi-platform image that is scanned before it is pushed.
+    Archive(PathBuf),
+    /// The registry, straight from the builder.
+    Registry,
+}
+
+/// Builds the app image with one of the supported builders.
+pub struct ImageBuild<'a> {
+    log: &'a Logger,
+    name: &'a str,
+    path: &'a str,
+    state_path: &'a Path,
+    tag: String,
+    platforms: Vec<String>,
+    push: bool,
+    show_context: bool,
+    no_cache: bool,
+    pull: bool,
+    cache_from: Vec<String>,
+    archive: Option<PathBuf>,
+    /// `SOURCE_DATE_EPOCH` of a multi-platform build, the push builds the archived image again with it.
+    epoch: u64,
+}
+
+impl<'a> ImageBuild<'a> {
+    /// `push` builds straight into the registry, which multi-platform images need.
+    pub fn new(
+        log: &'a Logger,
+        name: &'a str,
+        path: &'a str,
+        state_path: &'a Path,
+        tag: String,
+        platforms: Vec<String>,
+        push: bool,
+    ) -> ImageBuild<'a> {
+        ImageBuild {
+            log,
+            name,
+            path,
+            state_path,
+            tag,
+            platforms,
+            push,
+            show_context: false,
+            no_cache: false,
+            pull: false,
+            cache_from: vec![],
+            archive: None,
+            epoch: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map(|elapsed| elapsed.as_secs())
+                .unwrap_or_default(),
+        }
+    }
+
+    /// List every path of the Dockerfile build context before it is sent.
+    pub fn with_show_context(mut self, show_context: bool) -> ImageBuild<'a> {
+        self.show_context = show_context;
+        self
+    }
+
+    /// Build every step again instead of taking it from the build cache.
+    pub fn with_no_cache(mut self, no_cache: bool) -> ImageBuild<'a> {
+        self.no_cache = no_cache;
+        self
+    }
+
+    /// Pull newer versions of the base images before building.
+    pub fn with_pull(mut self, pull: bool) -> ImageBuild<'a> {
+        self.pull = pull;
+        self
+    }
+
+    /// Images in a registry whose layers the build may take as cache.
+    pub fn with_cache_from(mut self, cache_from: Vec<String>) -> ImageBuild<'a> {
+        self.cache_from = cache_from;
+        self
+    }
+
+    /// Write a multi-platform image to the OCI archive `archive` instead of pushing it, so it can be
+    /// scanned first. [`ImageBuild::push`] pushes it once it passed.
+    pub fn with_archive(mut self, archive: Option<PathBuf>) -> ImageBuild<'a> {
+        self.archive = archive;
+        self
+    }
+
+    /// Build the image, returning how much of it came from the build cache when the builder tells.
+    pub async fn run(&self, builder: Builder, pack_builder: Option<&str>) -> Result<Option<CacheUse>, String> {
+        let output = match (&self.archive, self.push) {
+            (Some(archive), true) => Output::Archive(archive.clone()),
+            (None, true) => Output::Registry,
+            (_, false) => Output::Local,
+        };
+        self.build(builder, pack_builder, &output).await
+    }
+
+    /// Push the multi-platform image [`ImageBuild::run`] wrote to the archive, and remove the archive.
+    /// The builder takes every step from its cache, and a Dockerfile build its timestamps from the same
+    /// `SOURCE_DATE_EPOCH`, what reaches the registry is the image that was scanned.
+    pub async fn push(&self, builder: Builder, pack_builder: Option<&str>) -> Result<(), String> {
+        self.log.step(&format!("Pushing multi-platform image {}", self.tag));
+        self.build(builder, pack_builder, &Output::Registry).await?;
+        if let Some(archive) = &self.archive {
+            if let Err(e) = fs::remove_file(archive) {
+                self.log.warn(&format!("Could not remove {}: {}", archive.display(), e));
+            }
+        }
+        Ok(())
+    }
+
+    async fn build(
+        &self,
+        builder: Builder,
+        pack_builder: Option<&str>,
+        output: &Output,
+    ) -> Result<Option<CacheUse>, String> {
+        match detect_runtime(Path::new(self.path)) {
+            Some(runtime) => self
+                .log
+                .step(&format!("Building with {} (detected {} project)", builder, runtime)),
+            None => self.log.step(&format!("Building with {}", builder)),
+        }
+
+        match builder {
+            Builder::Dockerfile => self.dockerfile(output),
+            Builder::Nixpacks => {
+                if self.pull {
+                    self.log
+                        .warn("Nixpacks has no option to pull base images, --pull is ignored");
+                }
+                self.nixpacks(output).await?;
+                Ok(None)
+            }
+            Builder::Pack => {
+                self.pack(pack_builder.unwrap_or(DEFAULT_PACK_BUILDER))?;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Whether the build may skip or refresh the cache as asked, the push of an archived image has to
+    /// build what the archive holds.
+    fn fresh(&self, output: &Output) -> bool {
+        !(self.archive.is_some() && *output == Output::Registry)
+    }
+
+    fn dockerfile(&self, output: &Output) -> Result<Option<CacheUse>, String> {
+        let path = self.path;
+        let tag = &self.tag;
+        let label = format!("{}={}", APP_LABEL, self.name);
+
+        // The context is assembled here rather than by the docker CLI so .rukuignore applies too
+        let context = BuildContext::new(Path::new(path));
+        if self.show_context {
+            for included in &context.paths {
+                println!("{}", included);
+            }
+        }
+        self.log.step(&format!(
+            "Build context: {} files, {}",
+            context.file_count(),
+            format_size(context.size)
+        ));
+        let inputs: Vec<&str> = [label.as_str()]
+            .into_iter()
+            .chain(self.platforms.iter().map(String::as_str))
+            .chain(self.cache_from.iter().map(String::as_str))
+            .collect();
+        let digest = context.digest(&inputs);
+        // A pull may bring newer base images and a push has to reach the registry, both build anyway
+        if !self.no_cache && !self.pull && !self.push && self.reuse(&digest) {
+            return Ok(Some(CacheUse::Full));
+        }
+        // buildx only recognizes gzip among the compressed contexts
+        let compression = match self.push {
+            true => Compression::Gzip,
+            false => {
+                let versions =
+                    run_fun!(docker version --format "{{.Client.Version}} {{.Server.Version}}").unwrap_or_default();
+                let mut versions = versions.split_whitespace();
+                Compression::for_docker(versions.next(), versions.next())
+            }
+        };
+
+        let mut args: Vec<String> = if self.push {
+            let platforms = self.platforms.join(",");
+            let mut args = ["buildx", "build", "--platform", &platforms]
+                .map(str::to_string)
+                .to_vec();
+            match output {
+                Output::Archive(archive) => {
+                    args.extend(["--output".to_string(), format!("type=oci,dest={}", archive.display())])
+                }
+                _ => args.push("--push".to_string()),
+            }
+            args
+        } else {
+            let mut args = vec!["build".to_string()];
+            args.extend(
+                self.platforms
+                    .iter()
+                    .flat_map(|p| ["--platform".to_string(), p.clone()]),
+            );
+            args
+        };
+        // Plain progress shows which steps were cached
+        args.push("--progress=plain".to_string());
+        args.extend((self.no_cache && self.fresh(output)).then(|| "--no-cache".to_string()));
+        args.extend((self.pull && self.fresh(output)).then(|| "--pull".to_string()));
+        args.extend(
+            self.cache_from
+                .iter()
+                .flat_map(|image| ["--cache-from".to_string(), image.clone()]),
+        );
+        args.extend(
+            test_labels()
+                .into_iter()
+                .flat_map(|(key, value)| ["--label".to_string(), format!("{}={}", key, value)]),
+        );
+        args.extend([
+            "--label".to_string(),
+            label,
+            "-t".to_string(),
+            tag.clone(),
+            "-".to_string(),
+        ]);
+
+        let fail = |e: String| format!("Error building Dockerfile at path {}: {}", path, e);
+        let mut command = Command::new("docker");
+        if self.push {
+            command.env("SOURCE_DATE_EPOCH", self.epoch.to_string());
+        }
+        let mut child = command
+            .args(&args)
+            .env("DOCKER_BUILDKIT", "1")
+            .stdin(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| fail(e.to_string()))?;
+        let stdin = child.stdin.take().unwrap();
+        let mut tally = CacheTally::new();
+        let started = Instant::now();
+        let sent = thread::scope(|scope| {
+            // The context is compressed and streamed to docker while it starts building
+            let upload = scope.spawn(|| {
+                let mut reported = Instant::now();
+                context.write_compressed(stdin, compression, |raw, compressed| {
+                    if reported.elapsed() >= PROGRESS_INTERVAL {
+                        reported = Instant::now();
+                        let rate = compressed as f64 / started.elapsed().as_secs_f64();
+                        self.log.step(&format!(
+                            "Sending the build context: {} of {}, {}",
+                            format_size(raw),
+                            format_size(context.size),
+                            format_rate(rate)
+                        ));
+                    }
+                })
+            });
+            // BuildKit writes its progress to stderr, passed on as it comes
+            if let Some(stderr) = child.stderr.take() {
+                let out = self.log.stream();
+                for line in BufReader::new(stderr).lines().map_while(Result::ok) {
+                    out.line(Target::Stderr, &line);
+                    tally.record(&line);
+                    if let Some((step, total, instruction)) = build_step(&line) {
+                        self.log.build_step(step, total, &instruction);
+                    }
+                }
+            }
+            upload.join().unwrap()
+        });
+        let status = child.wait().map_err(|e| fail(e.to_string()))?;
+        match sent {
+            Ok((raw, compressed)) => self.log.step(&format!(
+                "Sent the build context with {}: {} compressed from {} in {:.1}s",
+                compression,
+                format_size(compressed),
+                format_size(raw),
+                started.elapsed().as_secs_f64()
+            )),
+            // docker closes its stdin when it fails early, its own error says why
+            Err(e) if status.success() => return Err(fail(format!("sending the build context: {}", e))),
+            Err(_) => {}
+        }
+        if !status.success() {
+            return Err(fail(format!("docker build exited with {}", status)));
+        }
+        if !self.push {
+            self.remember(&digest);
+        }
+        Ok(tally.result())
+    }
+
+    /// Tag the image built from the context with `digest` before as this build when it is still there,
+    /// returning whether it was.
+    fn reuse(&self, digest: &str) -> bool {
+        let cache_path = self.state_path.join(CONTEXT_CACHE_FILE);
+        let Some(cache) = fs::read_to_string(cache_path)
+            .ok()
+            .and_then(|content| serde_json::from_str::<ContextCache>(&content).ok())
+            .filter(|cache| cache.digest == digest)
+        else {
+            return false;
+        };
+        let image = &cache.image;
+        if run_fun!(docker image inspect --format "{{.Id}}" $image).ok().as_deref() != Some(cache.image_id.as_str()) {
+            return false;
+        }
+        let tag = &self.tag;
+        if image != tag && run_cmd!(docker tag $image $tag).is_err() {
+            return false;
+        }
+        self.log
+            .step(&format!("Context unchanged, reusing previous build {}", image));
+        true
+    }
+
+    /// Record the image built from the context with `digest`, a failure only costs the next build.
+    fn remember(&self, digest: &str) {
+        let tag = &self.tag;
+        let Ok(image_id) = run_fun!(docker image inspect --format "{{.Id}}" $tag) else {
+            return;
+        };
+        let cache = ContextCache {
+            digest: digest.to_string(),
+            image: tag.clone(),
+            image_id,
+        };
+        if let Err(e) = store::write_atomic(
+            &self.state_path.join(CONTEXT_CACHE_FILE),
+            serde_json::to_string(&cache).unwrap().as_bytes(),
+        ) {
+            self.log
+                .warn(&format!("Could not save the build context digest: {}", e));
+        }
+    }
+
+    fn pack(&self, pack_builder: &str) -> Result<(), String> {
+        if run_fun!(pack version).is_err() {
+            return Err("The pack CLI is not installed, see https://buildpacks.io/docs/for-platform-operators/how-to/integrate-ci/pack/".to_string());
+        }
+        if self.push {
+            return Err(
+                "The pack builder does not support multi-platform builds, use nixpacks or a Dockerfile".to_string(),
+            );
+        }
+
+        let path = self.path;
+        let tag = &self.tag;
+        let mut cache_args = vec![];
+        if self.no_cache {
+            cache_args.push("--clear-cache".to_string());
+        }
+        if self.pull {
+            cache_args.extend(["--pull-policy".to_string(), "always".to_string()]);
+        }
+        run_cmd!(pack build $tag --path $path --builder $pack_builder $[cache_args])
+            .map_err(|e| format!("Error building with pack at path {}: {}", path, e))
+    }
+
+    async fn nixpacks(&self, output: &Output) -> Result<(), String> {
+        let plan = self.nixpacks_plan()?;
+
+        let build_options = DockerBuilderOptions {
+            name: Some(if self.push {
+                self.tag.clone()
+            } else {
+                self.name.to_string()
+            }),
+            out_dir: None,
+            print_dockerfile: false,
+            tags: vec![self.tag.clone()],
+            labels: [(APP_LABEL.to_string(), self.name.to_string())]
+                .into_iter()
+                .chain(test_labels())
+                .map(|(key, value)| format!("{}={}", key, value))
+                .collect(),
+            quiet: false,
+            cache_key: None,
+            no_cache: self.no_cache && self.fresh(output),
+            inline_cache: false,
+            // Nixpacks takes a single cache image
+            cache_from: self.cache_from.first().cloned(),
+            platform: self.platforms.clone(),
+            current_dir: true,
+            no_error_without_start: false,
+            incremental_cache_image: None,
+            cpu_quota: None,
+            memory: None,
+            verbose: false,
+            docker_host: None,
+            docker_tls_verify: None,
+            docker_output: match output {
+                Output::Local => None,
+                Output::Archive(archive) => Some(format!("type=oci,dest={}", archive.display())),
+                Output::Registry => Some("type=registry".to_string()),
+            },
+            add_host: vec![],
+            docker_cert_path: None,
+        };
+
+        let builder = DockerImageBuilder::new(nixpacks::nixpacks::logger::Logger::new(), build_options);
+        builder
+            .create_image(self.path, &plan, &Environment::default())
+            .await
+            .map_err(|e| format!("Error creating Docker image at path {}: {}", self.path, e))
+    }
+
+    /// Generate the nixpacks plan, reusing the one from the previous deploy while its inputs are unchanged.
+    fn nixpacks_plan(&self) -> Result<BuildPlan, String> {
+        let cache_path = self.state_path.join(PLAN_CACHE_FILE);
+        let key = self.plan_cache_key();
+
+        let cached = fs::read_to_string(&cache_path)
+            .ok()
+            .and_then(|content| serde_json::from_str::<CachedPlan>(&content).ok())
+            .filter(|cached| cached.key == key);
+        if let Some(cached) = cached {
+            self.log.step("Reusing the build plan from the previous deploy");
+            return Ok(cached.plan);
+        }
+
+        let options = GeneratePlanOptions {
+            plan: Some(BuildPlan::default()),
+            config_file: None,
+        };
+        let plan = nixpacks::generate_build_plan(self.path, vec![], &options)
+            .map_err(|e| format!("Error generating build plan at path {}: {}", self.path, e))?;
+        if plan.phases.as_ref().map_or(0, |phases| phases.len()) == 0 {
+            return Err(
+                "Nixpacks was unable to generate a build plan, add a Dockerfile or set build.builder".to_string(),
+            );
+        }
+        if plan.start_phase.as_ref().and_then(|start| start.cmd.as_ref()).is_none() {
+            return Err("Nixpacks could not find a start command for the app".to_string());
+        }
+
+        // A stale or unwritable cache only costs a regeneration next time
+        let cached = CachedPlan { key, plan };
+        let _ = store::write_atomic(&cache_path, serde_json::to_string(&cached).unwrap().as_bytes());
+        Ok(cached.plan)
+    }
+
+    fn plan_cache_key(&self) -> String {
+        let mut hasher = DefaultHasher::new();
+        env!("CARGO_PKG_VERSION").hash(&mut hasher);
+        for input in PLAN_INPUTS {
+            input.hash(&mut hasher);
+            fs::read(Path::new(self.path).join(input)).ok().hash(&mut hasher);
+        }
+        format!("{:016x}", hasher.finish())
+    }
+}
@@ -0,0 +1,128 @@
+use std::collections::BTreeSet;
+use std::fmt;
+use std::sync::LazyLock;
+
+use cmd_lib::run_fun;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::logger::Logger;
+
+/// A build step in BuildKit's plain progress output, e.g. `#5 [2/3] RUN npm ci` or `#7 [build 1/4] ...`.
+static STEP: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^#(\d+) \[[^\]]*\d+/\d+\]").unwrap());
+/// The step number, step count and instruction of a step line, e.g. `2`, `3` and `RUN npm ci`.
+static STEP_PROGRESS: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^#\d+ \[(?:[^\]]* )?(\d+)/(\d+)\] (.+)$").unwrap());
+/// A step BuildKit took from its cache, `#5 CACHED`.
+static CACHED: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^#(\d+) CACHED").unwrap());
+
+/// How much of a build came from the build cache.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CacheUse {
+    Full,
+    Partial { cached: usize, steps: usize },
+    Cold,
+}
+
+impl fmt::Display for CacheUse {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CacheUse::Full => write!(f, "fully cached"),
+            CacheUse::Partial { cached, steps } => write!(f, "partially cached, {} of {} steps", cached, steps),
+            CacheUse::Cold => write!(f, "cold"),
+        }
+    }
+}
+
+/// Counts the steps of a build and those taken from the cache, line by line of its output.
+#[derive(Debug, Default)]
+pub struct CacheTally {
+    steps: BTreeSet<String>,
+    cached: BTreeSet<String>,
+}
+
+impl CacheTally {
+    pub fn new() -> CacheTally {
+        CacheTally::default()
+    }
+
+    pub fn record(&mut self, line: &str) {
+        if let Some(step) = STEP.captures(line) {
+            self.steps.insert(step[1].to_string());
+        } else if let Some(step) = CACHED.captures(line) {
+            self.cached.insert(step[1].to_string());
+        }
+    }
+
+    /// How cached the build was, none when the output showed no steps.
+    pub fn result(&self) -> Option<CacheUse> {
+        let steps = self.steps.len();
+        let cached = self.steps.intersection(&self.cached).count();
+        match (cached, steps) {
+            (_, 0) => None,
+            (0, _) => Some(CacheUse::Cold),
+            (cached, steps) if cached == steps => Some(CacheUse::Full),
+            (cached, steps) => Some(CacheUse::Partial { cached, steps }),
+        }
+    }
+}
+
+/// The step of a line of BuildKit's plain progress output, none for the lines in between.
+pub fn build_step(line: &str) -> Option<(u32, u32, String)> {
+    let step = STEP_PROGRESS.captures(line)?;
+    Some((step[1].parse().ok()?, step[2].parse().ok()?, step[3].to_string()))
+}
+
+/// The BuildKit build cache of the daemon, managed through the docker CLI as the API client has no
+/// call for it.
+pub struct BuildCache<'a> {
+    log: &'a Logger,
+}
+
+impl<'a> BuildCache<'a> {
+    pub fn new(log: &'a Logger) -> BuildCache<'a> {
+        BuildCache { log }
+    }
+
+    /// The size of the build cache and how much of it could be freed, as `docker system df` puts them.
+    pub fn usage(&self) -> Option<(String, String)> {
+        let output = run_fun!(docker system df --format "{{.Type}}\t{{.Size}}\t{{.Reclaimable}}").ok()?;
+        output.lines().find_map(|line| {
+            let mut fields = line.split('\t');
+            (fields.next()? == "Build Cache").then_some(())?;
+            Some((fields.next()?.to_string(), fields.next()?.to_string()))
+        })
+    }
+
+    /// Remove the cache no image refers to, or with `all` every cache record not in use, which other
+    /// tools building on this daemon may rely on as well.
+    pub fn prune(&self, all: bool) -> Result<(), String> {
+        if let Some((size, reclaimable)) = self.usage() {
+            self.log
+                .step(&format!("Build cache: {}, {} reclaimable", size, reclaimable));
+        }
+        let output = if all {
+            run_fun!(docker builder prune --force --all)
+        } else {
+            run_fun!(docker builder prune --force)
+        };
+        let output = output.map_err(|e| format!("Error pruning the build cache: {}", e))?;
+        // `Total reclaimed space: 1.2GB` or `Total:  1.2GB`, depending on the CLI version
+        let freed = output
+            .lines()
+            .filter_map(|line| line.strip_prefix("Total"))
+            .filter_map(|rest| rest.rsplit([':', '\t', ' ']).next())
+            .next_back()
+            .unwrap_or("0B");
+        self.log.step(&format!("Freed {} of build cache", freed));
+        if let Some((size, _)) = self.usage() {
+            self.log.step(&format!("Build cache is now {}", size));
+        }
+        if !all {
+            self.log
+                .step("Left the cache images still refer to, pass --all to remove every unused cache record");
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,89 @@
+use bollard::Docker;
+use cmd_lib::run_fun;
+
+use crate::logger::Logger;
+
+/// What the active buildx builder reports about itself.
+pub struct BuilderInfo {
+    pub driver: String,
+    pub platforms: Vec<String>,
+}
+
+impl BuilderInfo {
+    /// Parse the output of `docker buildx inspect`.
+    pub fn parse(output: &str) -> BuilderInfo {
+        let mut driver = String::new();
+        let mut platforms = vec![];
+        for line in output.lines() {
+            let Some((key, value)) = line.split_once(':') else {
+                continue;
+            };
+            match key.trim() {
+                "Driver" if driver.is_empty() => driver = value.trim().to_string(),
+                "Platforms" => {
+                    platforms.extend(
+                        value
+                            .split(',')
+                            .map(|p| p.trim().trim_end_matches('*').to_string())
+                            .filter(|p| !p.is_empty()),
+                    );
+                }
+                _ => {}
+            }
+        }
+        BuilderInfo { driver, platforms }
+    }
+}
+
+/// Checks that the Docker daemon can produce multi-platform images.
+pub struct Buildx<'a> {
+    log: &'a Logger,
+    docker: &'a Docker,
+}
+
+impl<'a> Buildx<'a> {
+    pub fn new(log: &'a Logger, docker: &'a Docker) -> Buildx<'a> {
+        Buildx { log, docker }
+    }
+
+    pub async fn check(&self, platforms: &[String]) -> Result<(), String> {
+        let output = run_fun!(docker buildx inspect).map_err(|_| {
+            "Multi-platform builds need BuildKit through docker buildx, install the buildx plugin first"
+        })?;
+        let builder = BuilderInfo::parse(&output);
+
+        if builder.driver == "docker" && !self.uses_containerd_store().await {
+            return Err("The default docker builder cannot build multi-platform images. \
+                 Create a BuildKit builder with `docker buildx create --use` or enable the containerd image store"
+                .to_string());
+        }
+
+        let missing: Vec<&str> = platforms
+            .iter()
+            .filter(|p| !builder.platforms.contains(p))
+            .map(|p| p.as_str())
+            .collect();
+        if !missing.is_empty() {
+            return Err(format!(
+                "The builder cannot build for {}, install qemu binfmt emulation with \
+                 `docker run --privileged --rm tonistiigi/binfmt --install all`",
+                missing.join(", ")
+            ));
+        }
+
+        self.log
+            .step(&format!("Building for platforms: {}", platforms.join(", ")));
+        Ok(())
+    }
+
+    async fn uses_containerd_store(&self) -> bool {
+        let Ok(info) = self.docker.info().await else {
+            return false;
+        };
+        info.driver_status
+            .unwrap_or_default()
+            .iter()
+            .flatten()
+            .any(|status| status.contains("io.containerd.snapshotter"))
+    }
+}
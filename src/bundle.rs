@@ -0,0 +1,256 @@
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use bollard::Docker;
+use chrono::{DateTime, Utc};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::{Compression, Crc};
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use tempfile::NamedTempFile;
+
+use crate::image::Image;
+use crate::logger::Logger;
+use crate::misc::get_image_name_with_version;
+use crate::store;
+use crate::units::format_size;
+
+/// Version of the bundle layout. Bump it whenever the layout changes in an incompatible way.
+pub const BUNDLE_FORMAT_VERSION: u32 = 1;
+
+const MANIFEST_FILE: &str = "manifest.json";
+const IMAGE_FILE: &str = "image.tar";
+/// Suffix of a bundle while it is written, it is renamed once complete.
+const PARTIAL_SUFFIX: &str = ".partial";
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// Describes the image in a bundle.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BundleManifest {
+    pub format_version: u32,
+    pub ruku_version: String,
+    pub app: String,
+    pub version: Option<String>,
+    pub image: String,
+    /// Image id, the same on every host the image is loaded on.
+    pub digest: String,
+    /// Size in bytes and CRC32 of the `docker save` tarball, a truncated copy fails the check.
+    pub size: u64,
+    pub crc32: u32,
+    pub created_at: DateTime<Utc>,
+}
+
+/// An image loaded from a bundle, `run` deploys it instead of building.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LoadedImage {
+    pub image: String,
+    pub version: Option<String>,
+    pub digest: String,
+    pub loaded_at: DateTime<Utc>,
+}
+
+impl LoadedImage {
+    pub const FILE_NAME: &'static str = "image.json";
+
+    /// The image registered for the app, if any.
+    pub fn read(state_dir: &Path) -> Option<LoadedImage> {
+        let content = fs::read_to_string(state_dir.join(Self::FILE_NAME)).ok()?;
+        serde_json::from_str(&content).ok()
+    }
+
+    /// Register the image for the app, `run` then deploys it instead of building.
+    pub fn write(&self, state_dir: &Path) -> std::io::Result<()> {
+        store::write_atomic(&state_dir.join(Self::FILE_NAME), &serde_json::to_vec_pretty(self)?)
+    }
+}
+
+/// Moves app images between hosts as files, for hosts without access to a registry.
+pub struct ImageBundle<'a> {
+    log: &'a Logger,
+    docker: &'a Docker,
+}
+
+impl<'a> ImageBundle<'a> {
+    pub fn new(log: &'a Logger, docker: &'a Docker) -> ImageBundle<'a> {
+        ImageBundle { log, docker }
+    }
+
+    /// Write the image of `app` at `version` to `output`. The bundle is written next to it with a
+    /// `.partial` suffix and only gets its name once complete.
+    pub async fn save(&self, app: &str, version: &Option<String>, output: &Path) -> Result<(), String> {
+        let image_name = get_image_name_with_version(app, version);
+        let image = Image::new(self.log, self.docker);
+        let Some(digest) = image.id(&image_name).await else {
+            return Err(format!("Image {} not found, deploy the app first", image_name));
+        };
+
+        self.log.step(&format!("Saving image {}", image_name));
+        let mut image_file = self.temp_file()?;
+        let mut crc = Crc::new();
+        let mut size = 0u64;
+        let mut stream = self.docker.export_image(&image_name);
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk.map_err(|e| format!("Error saving image {}: {}", image_name, e))?;
+            crc.update(&chunk);
+            size += chunk.len() as u64;
+            image_file
+                .write_all(&chunk)
+                .map_err(|e| format!("Error writing image to disk: {}", e))?;
+        }
+
+        let manifest = BundleManifest {
+            format_version: BUNDLE_FORMAT_VERSION,
+            ruku_version: env!("CARGO_PKG_VERSION").to_string(),
+            app: app.to_string(),
+            version: version.clone(),
+            image: image_name,
+            digest,
+            size,
+            crc32: crc.sum(),
+            created_at: Utc::now(),
+        };
+
+        let partial = PathBuf::from(format!("{}{}", output.display(), PARTIAL_SUFFIX));
+        self.write_bundle(&partial, &manifest, image_file.path())
+            .and_then(|_| fs::rename(&partial, output))
+            .map_err(|e| format!("Error writing bundle {}: {}", output.display(), e))?;
+
+        self.log.step(&format!(
+            "Saved {} ({}, crc32 {:08x}) to {}",
+            manifest.image,
+            format_size(manifest.size),
+            manifest.crc32,
+            output.display()
+        ));
+        Ok(())
+    }
+
+    fn write_bundle(&self, path: &Path, manifest: &BundleManifest, image_file: &Path) -> std::io::Result<()> {
+        let file = File::create(path)?;
+        let mut builder = tar::Builder::new(GzEncoder::new(file, Compression::default()));
+
+        // The manifest goes first so `load` can check it before reading the image
+        let manifest_content = serde_json::to_vec_pretty(manifest).unwrap();
+        let mut header = tar::Header::new_gnu();
+        header.set_size(manifest_content.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime(manifest.created_at.timestamp() as u64);
+        header.set_cksum();
+        builder.append_data(&mut header, MANIFEST_FILE, manifest_content.as_slice())?;
+        builder.append_path_with_name(image_file, IMAGE_FILE)?;
+        builder.into_inner()?.finish()?;
+        Ok(())
+    }
+
+    /// Verify and load a bundle for `app`, then register the image in the state directory of the app.
+    /// A bundle of another app is refused unless `rename` is set, the image is then tagged for `app`.
+    pub async fn load(&self, app: &str, file: &Path, state_dir: &Path, rename: bool) -> Result<LoadedImage, String> {
+        if file.to_string_lossy().ends_with(PARTIAL_SUFFIX) {
+            return Err("This bundle was not completely written, run image:save again".to_string());
+        }
+
+        self.log.step(&format!("Reading bundle {}", file.display()));
+        let (manifest, image_file) = self
+            .read_bundle(file)
+            .map_err(|e| format!("Error reading bundle {}: {}", file.display(), e))?;
+        if manifest.app != app && !rename {
+            return Err(format!(
+                "Bundle holds app {}, not {}, pass --rename to load it as {}",
+                manifest.app, app, app
+            ));
+        }
+
+        self.log.step(&format!("Loading image {}", manifest.image));
+        let image = Image::new(self.log, self.docker);
+        image.load(image_file.path()).await?;
+        if image.id(&manifest.image).await.as_deref() != Some(manifest.digest.as_str()) {
+            return Err(format!(
+                "Loaded image {} does not match digest {}",
+                manifest.image, manifest.digest
+            ));
+        }
+
+        let image_name = get_image_name_with_version(app, &manifest.version);
+        if image_name != manifest.image {
+            self.log.step(&format!("Tagging {} as {}", manifest.image, image_name));
+            image.tag(&manifest.image, &image_name).await?;
+        }
+
+        let loaded = LoadedImage {
+            image: image_name,
+            version: manifest.version,
+            digest: manifest.digest,
+            loaded_at: Utc::now(),
+        };
+        loaded
+            .write(state_dir)
+            .map_err(|e| format!("Error registering image: {}", e))?;
+        Ok(loaded)
+    }
+
+    /// Stream the bundle, checking the manifest and the checksum of the image it holds.
+    fn read_bundle(&self, file: &Path) -> Result<(BundleManifest, NamedTempFile), String> {
+        let file = File::open(file).map_err(|e| e.to_string())?;
+        let mut archive = tar::Archive::new(GzDecoder::new(file));
+        let mut manifest: Option<BundleManifest> = None;
+        let mut image: Option<(NamedTempFile, u64, u32)> = None;
+
+        // A truncated gzip stream surfaces as an error here
+        let incomplete = |e: std::io::Error| format!("bundle is incomplete or corrupt ({})", e);
+        for entry in archive.entries().map_err(incomplete)? {
+            let mut entry = entry.map_err(incomplete)?;
+            let path = entry.path().map_err(incomplete)?.to_string_lossy().to_string();
+            match path.as_str() {
+                MANIFEST_FILE => {
+                    let mut content = String::new();
+                    entry.read_to_string(&mut content).map_err(incomplete)?;
+                    let parsed: BundleManifest =
+                        serde_json::from_str(&content).map_err(|e| format!("invalid manifest: {}", e))?;
+                    if parsed.format_version == 0 || parsed.format_version > BUNDLE_FORMAT_VERSION {
+                        return Err(format!(
+                            "bundle format version {} is not supported, this ruku supports up to {}",
+                            parsed.format_version, BUNDLE_FORMAT_VERSION
+                        ));
+                    }
+                    manifest = Some(parsed);
+                }
+                IMAGE_FILE => {
+                    let mut image_file = self.temp_file()?;
+                    let mut crc = Crc::new();
+                    let mut size = 0u64;
+                    let mut buffer = vec![0; BUFFER_SIZE];
+                    loop {
+                        let read = entry.read(&mut buffer).map_err(incomplete)?;
+                        if read == 0 {
+                            break;
+                        }
+                        crc.update(&buffer[..read]);
+                        size += read as u64;
+                        image_file.write_all(&buffer[..read]).map_err(|e| e.to_string())?;
+                    }
+                    image = Some((image_file, size, crc.sum()));
+                }
+                _ => {}
+            }
+        }
+
+        let manifest = manifest.ok_or("bundle is missing its manifest")?;
+        let (image_file, size, crc32) = image.ok_or("bundle is missing the image, it may be incomplete")?;
+        if size != manifest.size || crc32 != manifest.crc32 {
+            return Err(format!(
+                "image checksum mismatch, expected {} with crc32 {:08x} but got {} with crc32 {:08x}",
+                format_size(manifest.size),
+                manifest.crc32,
+                format_size(size),
+                crc32
+            ));
+        }
+        Ok((manifest, image_file))
+    }
+
+    fn temp_file(&self) -> Result<NamedTempFile, String> {
+        NamedTempFile::new().map_err(|e| format!("Error creating temporary file: {}", e))
+    }
+}
@@ -0,0 +1,328 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::container::{Container, DEFAULT_HEALTH_TIMEOUT};
+use crate::logger::Logger;
+use crate::model::CanaryConfig;
+use crate::proxy::Proxy;
+use crate::smoke::SmokeResult;
+use crate::store;
+use crate::strategy::Strategy;
+
+/// How long the running stable version gets to show it is healthy before a canary goes next to it.
+const STABLE_HEALTH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Progress of a canary rollout, persisted so `promote` and `abort` can pick it up from another shell and
+/// the proxy sends the canary its share of the requests.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CanaryState {
+    pub container: String,
+    pub weight: u8,
+    pub started_at: DateTime<Utc>,
+}
+
+impl CanaryState {
+    /// The rollout of the app with the state directory `state_dir`, none when no canary runs.
+    pub fn read(state_dir: &Path) -> Option<CanaryState> {
+        let content = fs::read_to_string(state_dir.join(Canary::STATE_FILE)).ok()?;
+        serde_json::from_str(&content).ok()
+    }
+}
+
+/// Runs a new version next to the stable one and shifts traffic to it in steps.
+pub struct Canary<'a> {
+    log: &'a Logger,
+    stable: &'a Container<'a>,
+    canary: Container<'a>,
+    state_dir: PathBuf,
+    proxy: Proxy<'a>,
+    rollout: Option<&'a CanaryConfig>,
+    health_timeout: Duration,
+}
+
+impl<'a> Canary<'a> {
+    pub const STATE_FILE: &'static str = "canary.json";
+
+    pub fn new(log: &'a Logger, stable: &'a Container<'a>, state_dir: &Path) -> Canary<'a> {
+        Canary {
+            log,
+            stable,
+            canary: stable.canary(),
+            state_dir: state_dir.to_path_buf(),
+            // The state directories of the apps are in the state root
+            proxy: Proxy::new(log, stable.docker(), state_dir.parent().unwrap_or(state_dir)),
+            rollout: None,
+            health_timeout: Duration::from_secs(DEFAULT_HEALTH_TIMEOUT),
+        }
+    }
+
+    /// Wait up to `timeout` for the canary to become healthy after it starts and after every step.
+    pub fn with_health_timeout(mut self, timeout: Duration) -> Canary<'a> {
+        self.health_timeout = timeout;
+        self
+    }
+
+    /// The steps and pause the rollout goes through when deployed as a strategy.
+    pub fn with_rollout(mut self, rollout: &'a CanaryConfig) -> Canary<'a> {
+        self.rollout = Some(rollout);
+        self
+    }
+
+    /// Roll out the new version, returning the results of the smoke checks it passed. Fails as soon as
+    /// the canary fails a check or more of the requests the proxy sent it during a step failed than the
+    /// rollout allows, before the rollout is aborted.
+    pub async fn run(&self, steps: &[u8], pause: u64) -> Result<Vec<SmokeResult>, String> {
+        let stable = match self.stable.get().await? {
+            Some(_) => self.stable.wait_healthy(STABLE_HEALTH_TIMEOUT).await,
+            None => Err("none is running".to_string()),
+        };
+        if let Err(e) = stable {
+            self.log.step(&format!(
+                "No healthy stable version to shift traffic from ({}), deploying directly",
+                e.to_lowercase()
+            ));
+            self.stable.run().await?;
+            self.stable.wait_healthy(self.health_timeout).await?;
+            return self.stable.smoke_test().await;
+        }
+
+        self.log
+            .step(&format!("Starting canary container {}", self.canary.container_name()));
+        self.canary.run().await?;
+        self.canary
+            .wait_healthy(self.health_timeout)
+            .await
+            .map_err(|e| format!("Canary did not become healthy: {}", e))?;
+        // Checked before any traffic shifts, the stable version keeps serving everything on failure
+        let smoke = self
+            .canary
+            .smoke_test()
+            .await
+            .map_err(|e| format!("Canary failed its smoke checks: {}", e))?;
+        let started_at = Utc::now();
+
+        for &weight in steps {
+            if weight >= 100 {
+                self.promote().await?;
+                return Ok(smoke);
+            }
+
+            self.log.step(&format!("Shifting {}% of traffic to the canary", weight));
+            self.save_state(&CanaryState {
+                container: self.canary.container_name().to_string(),
+                weight,
+                started_at,
+            })?;
+            let shifted_at = Utc::now().timestamp();
+            self.route().await;
+
+            tokio::time::sleep(Duration::from_secs(pause)).await;
+            self.canary
+                .wait_healthy(self.health_timeout)
+                .await
+                .map_err(|e| format!("Canary is unhealthy at {}%: {}", weight, e))?;
+            self.check_error_rate(weight, shifted_at).await?;
+        }
+
+        self.log
+            .step("Canary is healthy, run `ruku promote` to complete the rollout or `ruku abort` to roll back");
+        Ok(smoke)
+    }
+
+    /// Replace the stable version with the canary and remove the canary container. The canary serves
+    /// every request while the stable container is recreated from its image.
+    pub async fn promote(&self) -> Result<(), String> {
+        let Some(canary) = self.canary.get().await? else {
+            return Err("No canary is running".to_string());
+        };
+        let image = canary.image.ok_or("The canary container has no image")?;
+
+        self.log.step("Promoting the canary to stable");
+        let started_at = CanaryState::read(&self.state_dir).map_or_else(Utc::now, |state| state.started_at);
+        self.save_state(&CanaryState {
+            container: self.canary.container_name().to_string(),
+            weight: 100,
+            started_at,
+        })?;
+        self.route().await;
+        self.stable.run_image(image).await?;
+        self.stable
+            .wait_healthy(self.health_timeout)
+            .await
+            .map_err(|e| format!("The promoted version did not become healthy: {}", e))?;
+        // The stable version gets all requests back before the canary stops answering them
+        self.clear_state()?;
+        self.route().await;
+        self.canary.end().await?;
+        self.log
+            .step("Rollout complete, 100% of traffic is served by the new version");
+        Ok(())
+    }
+
+    /// Remove the canary and send all traffic back to the stable version.
+    pub async fn abort(&self) -> Result<(), String> {
+        if let Some(state) = CanaryState::read(&self.state_dir) {
+            self.log.step(&format!(
+                "Removing the canary, it was serving {}% of traffic",
+                state.weight
+            ));
+        } else {
+            self.log.step("Removing the canary");
+        }
+        // The stable version gets all requests back before the canary stops answering them
+        self.clear_state()?;
+        self.route().await;
+        self.canary.end().await?;
+        self.log.step("100% of traffic is served by the stable version");
+        Ok(())
+    }
+
+    /// Have the proxy split the requests of the app as the saved state says.
+    async fn route(&self) {
+        if self.proxy.is_running().await {
+            self.proxy.refresh().await;
+        } else {
+            self.log
+                .warn("The proxy is not running, the canary only gets the requests sent to its own port");
+        }
+    }
+
+    /// Fail when more of the requests the proxy sent to the canary since the unix time `since` failed than
+    /// `max_error_rate` allows.
+    async fn check_error_rate(&self, weight: u8, since: i64) -> Result<(), String> {
+        let Some(rollout) = self.rollout else {
+            return Ok(());
+        };
+        let Some((requests, errors)) = self.proxy.canary_errors(self.stable.app(), since).await? else {
+            return Ok(());
+        };
+        if errors * 100 > usize::from(rollout.max_error_rate) * requests {
+            return Err(format!(
+                "Canary failed {} of {} requests at {}%, more than the max_error_rate of {}%",
+                errors, requests, weight, rollout.max_error_rate
+            ));
+        }
+        self.log.step(&format!(
+            "Canary answered {} requests at {}%, {} failed",
+            requests, weight, errors
+        ));
+        Ok(())
+    }
+
+    fn state_path(&self) -> PathBuf {
+        self.state_dir.join(Self::STATE_FILE)
+    }
+
+    fn save_state(&self, state: &CanaryState) -> Result<(), String> {
+        store::write_atomic(
+            &self.state_path(),
+            serde_json::to_string_pretty(state).unwrap().as_bytes(),
+        )
+        .map_err(|e| format!("Error writing canary state: {}", e))
+    }
+
+    fn clear_state(&self) -> Result<(), String> {
+        if self.state_path().exists() {
+            fs::remove_file(self.state_path()).map_err(|e| format!("Error removing canary state: {}", e))?;
+        }
+        Ok(())
+    }
+}
+
+impl Strategy for Canary<'_> {
+    fn plan(&self) -> Vec<String> {
+        let steps = self.rollout.map(|rollout| {
+            rollout
+                .steps
+                .iter()
+                .map(|weight| format!("{}%", weight))
+                .collect::<Vec<_>>()
+                .join(", ")
+        });
+        vec![format!(
+            "Starting {} next to {} and shifting {} of traffic to it",
+            self.canary.container_name(),
+            self.stable.container_name(),
+            steps.unwrap_or_default()
+        )]
+    }
+
+    async fn execute(&self) -> Result<Vec<SmokeResult>, String> {
+        let rollout = self.rollout.ok_or("the canary strategy needs a canary section")?;
+        self.run(&rollout.steps, rollout.pause).await
+    }
+
+    async fn failed(&self) -> Vec<String> {
+        vec![self.canary.container_name().to_string()]
+    }
+
+    /// Aborts the rollout, unless the new version was deployed directly and there is no canary.
+    async fn rollback(&self) -> Result<(), String> {
+        if self.canary.get().await?.is_some() {
+            self.abort().await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake_daemon::fake_daemon;
+    use crate::model::RukuConfig;
+
+    #[tokio::test]
+    async fn a_promotion_moves_the_requests_to_the_canary_before_replacing_stable() {
+        let (docker, requests) = fake_daemon(|method, path| match (method, path) {
+            ("GET", "/containers/json") => (
+                200,
+                r#"[{"Id": "abc", "Names": ["/ruku-shop-canary"], "Image": "shop:2.0", "State": "running",
+                     "Labels": {"ruku.app": "shop", "ruku.role": "canary", "ruku.proxy": "true"}}]"#
+                    .to_string(),
+            ),
+            ("GET", path) if path.starts_with("/containers/") => (
+                200,
+                r#"{"State": {"Status": "running", "Running": true, "Health": {"Status": "healthy"}}}"#.to_string(),
+            ),
+            ("GET", "/volumes") => (200, r#"{"Volumes": []}"#.to_string()),
+            ("GET", "/networks") => (200, "[]".to_string()),
+            ("POST", path) if path.ends_with("/create") => (201, r#"{"Id": "new", "Warnings": []}"#.to_string()),
+            _ => (204, String::new()),
+        })
+        .await;
+        let state_root = tempfile::tempdir().unwrap();
+        let state_dir = state_root.path().join("shop");
+        fs::create_dir(&state_dir).unwrap();
+        let config: RukuConfig = serde_yaml::from_str("version: '1.0'").unwrap();
+        let log = Logger::new();
+        let stable = Container::new(&log, "shop", &docker, &config);
+        Canary::new(&log, &stable, &state_dir).promote().await.unwrap();
+
+        let order: Vec<String> = requests
+            .all()
+            .into_iter()
+            .filter(|request| !request.starts_with("GET") && !request.starts_with("PUT"))
+            .filter(|request| !request.contains("/networks/") && !request.ends_with("/start"))
+            .collect();
+        // The fake daemon lists the canary under every name, the first stop and removal replace stable
+        assert_eq!(
+            order,
+            [
+                "POST /containers/ruku-proxy/kill",
+                "POST /containers/abc/stop",
+                "DELETE /containers/abc",
+                "POST /containers/create",
+                "POST /containers/ruku-proxy/kill",
+                "POST /containers/abc/stop",
+                "DELETE /containers/abc",
+            ]
+        );
+        // Stable is created from the image of the canary, not the version in ruku.yml
+        assert!(requests.count("GET /images/shop:2.0/json") > 0);
+        assert!(CanaryState::read(&state_dir).is_none());
+    }
+}
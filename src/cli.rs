@@ -0,0 +1,3406 @@
+//! The `ruku` command line, the binary only calls [`run`].
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::fs;
+use std::io::IsTerminal;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use chrono::Utc;
+use clap::{Parser, Subcommand};
+use colored::Colorize;
+
+use crate::api_trace;
+use crate::app_context::{resolve_app, AppSource, APP_ENV};
+use crate::app_url::{app_url, open_in_browser};
+use crate::archive::{Export, Import};
+use crate::audit::{AuditLog, PendingAudit};
+use crate::auxiliary::{describe_aux, list_aux};
+use crate::backup::Backups;
+use crate::build_cache::BuildCache;
+use crate::bundle::ImageBundle;
+use crate::canary::Canary;
+use crate::compose;
+use crate::config::{
+    get_dependencies, get_links, load_ruku_config, load_ruku_config_with_provenance, load_valid_ruku_config,
+};
+use crate::confirm::{Answer, Confirm};
+use crate::connection::{
+    get_docker, is_plaintext_remote, load_docker, local_docker_host, resolve_context, use_context, CONTEXT_ENV,
+};
+use crate::container::{
+    deployed_version, describe_bindings, describe_container, get_container_name, is_managed, render_labels, Container,
+    Takeover, APP_LABEL, DEFAULT_HEALTH_TIMEOUT, PREVIEW_LABEL,
+};
+use crate::daemon_network::{host_proxy, DaemonNetwork};
+use crate::dashboard::Dashboard;
+use crate::debug_bundle::DebugBundle;
+use crate::dependency::Dependencies;
+use crate::deploy_message::{check_message, DEPLOY_MESSAGE_ENV};
+use crate::deploys::{DeployOptions, DeployStatus, Deploys};
+use crate::diff::{is_secret_path, Change, ChangeKind, Renderer};
+use crate::drain::Drain;
+use crate::drift::Drift;
+use crate::env_export::{self, EnvFormat, Masking};
+use crate::error::RukuError;
+use crate::executor::DEFAULT_CONCURRENCY;
+use crate::failures::Failures;
+use crate::fleet::{self, Fleet};
+use crate::freeze;
+use crate::git::Git;
+use crate::health_server::HealthServer;
+use crate::history::History;
+use crate::image::Image;
+use crate::image_drift::{self, ImageDrift};
+use crate::image_wait::is_digest;
+use crate::init;
+use crate::inspect::{self, count_changes, filter_changes};
+use crate::links::{get_env_prefix, Links};
+use crate::logger::{self, Logger};
+use crate::logs::{self, require_healthy, LogFilter, Logs, RotatingWriter};
+use crate::maintenance::{Maintenance, MaintenanceState};
+use crate::metrics;
+use crate::migrate::Migration;
+use crate::misc::{
+    describe_version_drift, get_image_name_with_version, get_registry_image_name, get_version, sanitize_app_name,
+    validate_app_name,
+};
+use crate::model::{DeployStrategy, RukuConfig};
+use crate::network::Networks;
+use crate::overview::app_rows;
+use crate::pipeline::{DeployOutcome, DeployPipeline};
+use crate::plan::{Action, Plan, Resource};
+use crate::platform::{emulated_architectures, Platforms};
+use crate::preview::{Preview, Previews};
+use crate::proxy::{Proxy, PROXY_CONTAINER};
+use crate::read_only;
+use crate::release_logs::{release_of, ReleaseLogs};
+use crate::releases::{self, Releases};
+use crate::remote_config::{self, RemoteConfig, RemoteSource};
+use crate::repair::Repair;
+use crate::rollback::{self, Rollback};
+use crate::rootless::LowPortRedirect;
+use crate::routing;
+use crate::sbom::Sboms;
+use crate::selector::{strip_selector, AppGroup, Selector};
+use crate::server_config::ServerConfig;
+use crate::sidecar::Sidecars;
+use crate::spec::{HashChange, CONFIG_HASH_VERSION};
+use crate::staging::Staging;
+use crate::static_site::static_root;
+#[cfg(unix)]
+use crate::sudo;
+use crate::templates::{self, Templates};
+use crate::top::Top;
+use crate::units::{format_size, parse_duration, parse_size};
+use crate::version;
+use crate::volume::{describe_host_path, HostPathAction, HostPaths, Volumes};
+
+#[derive(Parser)]
+#[command(version = version::LONG_VERSION, about = "A CLI app for managing your server.")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+    /// Answer yes to confirmations of destructive operations, RUKU_ASSUME_YES=1 does the same
+    #[arg(short, long, global = true)]
+    yes: bool,
+    /// Run through sudo -n when the docker socket is not accessible, RUKU_DOCKER_SUDO=1 does the same
+    #[arg(long, global = true)]
+    sudo: bool,
+    /// The app of commands that are given none, before RUKU_APP and the nearest ruku.yml
+    #[arg(long = "app", value_name = "APP", global = true)]
+    app_flag: Option<String>,
+    /// The context of ~/.ruku/config.yml whose daemon to talk to, RUKU_CONTEXT does the same
+    #[arg(long, global = true)]
+    context: Option<String>,
+    /// The one host to act on of an app with hosts in its ruku.yml
+    #[arg(long, global = true, conflicts_with = "context")]
+    host: Option<String>,
+    /// Go through the hosts of an app all at once rather than one after the other
+    #[arg(long, global = true, conflicts_with = "host")]
+    parallel: bool,
+    /// Print details for troubleshooting such as the raw daemon errors, RUKU_DEBUG=1 does the same
+    #[arg(long, global = true)]
+    debug: bool,
+    /// Record every daemon API call to a trace in the state directory for a bug report,
+    /// RUKU_TRACE_DOCKER=1 does the same
+    #[arg(long, global = true)]
+    debug_api: bool,
+    /// Go ahead with a command that changes state during a freeze window, for this reason, which is kept
+    /// in the audit log and the deploy message. RUKU_OVERRIDE_FREEZE does the same
+    #[arg(long, global = true, value_name = "REASON")]
+    override_freeze: Option<String>,
+    /// Print every event, such as deploy stages, log lines and pull progress, as a JSON line on stdout,
+    /// RUKU_JSON_EVENTS=1 does the same
+    #[arg(long, global = true)]
+    json_events: bool,
+    /// Act on every app whose containers carry these labels, e.g. team=payments,tier=web, for list, run,
+    /// restart, stop and status
+    #[arg(long, global = true, value_name = "LABELS", conflicts_with = "app_flag")]
+    selector: Option<String>,
+}
+
+impl Command {
+    /// Whether the command goes through every host of an app with hosts when no `--host` is given.
+    fn fans_out(&self) -> bool {
+        matches!(
+            self,
+            Command::Run { .. } | Command::Status { .. } | Command::Stop { .. } | Command::Undo { .. }
+        )
+    }
+
+    /// The app argument of a command `--selector` runs for each app it picks, with the word for what it
+    /// did to an app, none for the commands it doesn't apply to.
+    fn selected_app(&self) -> Option<(&Option<String>, &'static str)> {
+        match self {
+            Command::Run { app, .. } => Some((app, "deployed")),
+            Command::Restart { app, .. } => Some((app, "restarted")),
+            Command::Stop { app, .. } => Some((app, "stopped")),
+            Command::Status { app, .. } => Some((app, "checked")),
+            _ => None,
+        }
+    }
+
+    /// Whether the command talks to the docker daemon.
+    fn uses_docker(&self) -> bool {
+        !matches!(
+            self,
+            Command::Init { .. }
+                | Command::ImportCompose { deploy: false, .. }
+                | Command::ConfigSet { .. }
+                | Command::ConfigGet { .. }
+                | Command::GitReceivePack { .. }
+                | Command::GitUploadPack { .. }
+                | Command::Audit { .. }
+                | Command::EnvExport { .. }
+        )
+    }
+
+    /// Whether a freeze window refuses the command when it changes state. The worker runs deploys that
+    /// were checked when they were queued.
+    fn checks_freeze(&self) -> bool {
+        self.mutates() && !matches!(self, Command::DeploysWorker { .. })
+    }
+
+    /// Whether the command changes Docker or ruku state, which read-only mode refuses. Every command is
+    /// listed, so a new one has to be put on a side.
+    fn mutates(&self) -> bool {
+        match self {
+            Command::Run { dry_run: true, .. }
+            | Command::Drift { fix: false, .. }
+            | Command::Undo { list: true, .. }
+            | Command::Undo { dry_run: true, .. }
+            | Command::Repair { dry_run: true, .. }
+            | Command::Stop { dry_run: true, .. }
+            | Command::Destroy { dry_run: true, .. }
+            | Command::Logs { .. }
+            | Command::ConfigGet { .. }
+            | Command::ConfigExplain { .. }
+            | Command::EnvExport { .. }
+            | Command::Init { .. }
+            | Command::DeploysStatus { .. }
+            | Command::DeploysLogs { .. }
+            | Command::Status { .. }
+            | Command::Open { .. }
+            | Command::Top { .. }
+            | Command::List { .. }
+            | Command::Dashboard
+            | Command::Server { .. }
+            | Command::Doctor
+            | Command::Version { .. }
+            | Command::Metrics { .. }
+            | Command::Volumes { .. }
+            | Command::Releases { .. }
+            | Command::ReleasesShow { .. }
+            | Command::ReleasesDiff { .. }
+            | Command::ReleasesSbom { .. }
+            | Command::PreviewList { .. }
+            | Command::Audit { .. }
+            | Command::DebugBundle { .. }
+            | Command::Diff { .. }
+            | Command::ImageHistory { .. }
+            | Command::ImageSave { .. }
+            | Command::ProxyStatus
+            | Command::GitUploadPack { .. } => false,
+            Command::Run { .. }
+            | Command::Drift { .. }
+            | Command::Rollback { .. }
+            | Command::Undo { .. }
+            | Command::ConfigSet { .. }
+            | Command::ImportCompose { .. }
+            | Command::DeploysWorker { .. }
+            | Command::Restart { .. }
+            | Command::Push { .. }
+            | Command::Link { .. }
+            | Command::Unlink { .. }
+            | Command::Repair { .. }
+            | Command::Deploy
+            | Command::Stop { .. }
+            | Command::Destroy { .. }
+            | Command::Stage { .. }
+            | Command::Promote { .. }
+            | Command::Abort { .. }
+            | Command::Export { .. }
+            | Command::Import { .. }
+            | Command::MaintenanceOn { .. }
+            | Command::MaintenanceOff { .. }
+            | Command::Preview { .. }
+            | Command::PreviewDestroy { .. }
+            | Command::PreviewReap
+            | Command::ImageLoad { .. }
+            | Command::BuildCachePrune { .. }
+            | Command::ProxyUp
+            | Command::ProxyDown
+            | Command::GitHook { .. }
+            | Command::GitReceivePack { .. } => true,
+        }
+    }
+}
+
+/// Enum representing the various commands that can be executed by the CLI.
+#[derive(Subcommand)]
+enum Command {
+    /// Show the application logs
+    Logs {
+        /// The app name, from --app, RUKU_APP or the nearest ruku.yml when left out
+        app: Option<String>,
+        /// Keep printing new output
+        #[arg(short, long)]
+        follow: bool,
+        /// Only show the last N lines
+        #[arg(long)]
+        tail: Option<usize>,
+        /// Follow the logs into this file instead of printing them, across container restarts
+        #[arg(long)]
+        save: Option<PathBuf>,
+        /// Size at which the saved file is rotated, e.g. 10M
+        #[arg(long, default_value = logs::DEFAULT_MAX_SIZE, requires = "save")]
+        max_size: String,
+        /// Number of rotated files to keep
+        #[arg(long, default_value_t = logs::DEFAULT_KEEP, requires = "save")]
+        keep: usize,
+        /// Show the logs of this sidecar instead of the app
+        #[arg(long)]
+        sidecar: Option<String>,
+        /// Only show lines matching this regex, highlighted on a terminal
+        #[arg(long, conflicts_with = "save")]
+        grep: Option<String>,
+        /// Leave out lines matching this regex
+        #[arg(long, conflicts_with = "save")]
+        exclude: Option<String>,
+        /// Only show lines at this level or above, going by tokens like WARN or level=error
+        #[arg(long, conflicts_with = "save")]
+        level: Option<String>,
+        /// Print every line as JSON with its timestamp, stream, level and the groups --grep matched
+        #[arg(long, conflicts_with = "save")]
+        json: bool,
+        /// Show the logs kept of an earlier release instead, by deployment id or `previous`
+        #[arg(long, value_name = "ID", conflicts_with_all = ["follow", "save", "sidecar"])]
+        release: Option<String>,
+    },
+    /// Set a configuration variable, e.g, VAR=12
+    #[command(name = "config:set")]
+    ConfigSet {
+        /// The configuration variable in the form KEY=VALUE
+        var: String,
+    },
+    /// Get a configuration variable
+    #[command(name = "config:get")]
+    ConfigGet {
+        /// The configuration variable name
+        key: String,
+    },
+    /// Convert the services of a docker-compose file to ruku apps
+    ImportCompose {
+        /// The compose file, compose.yaml or docker-compose.yml in the current directory by default
+        path: Option<PathBuf>,
+        /// Overwrite existing ruku.yml files
+        #[arg(long)]
+        force: bool,
+        /// Deploy the converted apps right away
+        #[arg(long)]
+        deploy: bool,
+    },
+    /// Generate a starter ruku.yml for the project in the current directory
+    Init {
+        /// Overwrite an existing ruku.yml
+        #[arg(long)]
+        force: bool,
+        /// Only write the required fields
+        #[arg(long)]
+        minimal: bool,
+    },
+    /// Show every effective config value and where it was set
+    #[command(name = "config:explain")]
+    ConfigExplain {
+        /// The app name
+        app: String,
+        /// Only show this key and the keys below it, e.g. canary
+        key: Option<String>,
+    },
+    /// Print the env the app container gets, to run the app locally with it
+    #[command(name = "env:export")]
+    EnvExport {
+        /// The app name, from --app, RUKU_APP or the nearest ruku.yml when left out
+        app: Option<String>,
+        /// dotenv for a .env file, shell to source, or json
+        #[arg(long, default_value = "dotenv")]
+        format: EnvFormat,
+        /// Mask every value, not only those of keys that name a credential
+        #[arg(long, conflicts_with = "show_secrets")]
+        mask: bool,
+        /// Print the values of keys that name a credential as well
+        #[arg(long)]
+        show_secrets: bool,
+    },
+    /// Run the application
+    Run {
+        /// The app name, from --app, RUKU_APP or the nearest ruku.yml when left out
+        app: Option<String>,
+        /// Do nothing when the configured version is already running
+        #[arg(long)]
+        only_if_changed: bool,
+        /// Take over a container with the app's name that ruku did not create, it is recreated with the ruku labels
+        #[arg(long, conflicts_with = "force_replace")]
+        adopt: bool,
+        /// Remove a container with the app's name that ruku did not create
+        #[arg(long)]
+        force_replace: bool,
+        /// List the files sent as the Dockerfile build context
+        #[arg(long)]
+        show_context: bool,
+        /// Fail the deploy unless the new container becomes healthy
+        #[arg(long)]
+        wait_healthy: bool,
+        /// Seconds to wait for the container to become healthy
+        #[arg(long, default_value_t = DEFAULT_HEALTH_TIMEOUT, requires = "wait_healthy")]
+        timeout: u64,
+        /// Print the rendered templates and what changes in the files and the container with secrets masked,
+        /// and stop before deploying
+        #[arg(long)]
+        dry_run: bool,
+        /// Deploy without the vulnerability scan configured in ruku.yml
+        #[arg(long)]
+        skip_scan: bool,
+        /// Start without running the pre_start command of ruku.yml
+        #[arg(long)]
+        skip_pre_start: bool,
+        /// Replace a container ruku did not create without committing it to a backup image first
+        #[arg(long, requires = "force_replace")]
+        no_backup: bool,
+        /// Replace the running version this way instead of the strategy in ruku.yml: recreate, rolling,
+        /// blue_green or canary
+        #[arg(long)]
+        strategy: Option<DeployStrategy>,
+        /// Build here even when another ruku process is building the same image, and don't let others wait on it
+        #[arg(long)]
+        no_share: bool,
+        /// Deploy without checking first that the host has the disk, memory and file handles for it
+        #[arg(long)]
+        skip_preflight: bool,
+        /// Build every step again instead of taking it from the build cache
+        #[arg(long)]
+        no_cache: bool,
+        /// Pull newer versions of the base images before building
+        #[arg(long)]
+        pull: bool,
+        /// Deploy the image in this docker-archive or OCI tarball instead of building, e.g. from `nix build`
+        #[arg(long, value_name = "PATH")]
+        image_tar: Option<PathBuf>,
+        /// Wait up to this many seconds for the image to be pushed to the registry of the build config, or
+        /// to the local store, and deploy it instead of building
+        #[arg(long, value_name = "SECONDS", conflicts_with = "image_tar")]
+        wait_for_image: Option<u64>,
+        /// Only deploy the pushed image once its tag points at this manifest digest, sha256:...
+        #[arg(long, requires = "wait_for_image")]
+        image_digest: Option<String>,
+        /// A note kept with the deployment, shown by releases:show and put on the container as the
+        /// ruku.deploy-message label, RUKU_DEPLOY_MESSAGE does the same
+        #[arg(long, short)]
+        message: Option<String>,
+        /// Queue the deploy to run in the background and print its id
+        #[arg(long, conflicts_with = "dry_run")]
+        detach: bool,
+        /// Deploy the ruku.yml at this HTTPS URL instead of the app's own, and keep fetching it on later
+        /// runs. RUKU_CONFIG_TOKEN is sent as a bearer token
+        #[arg(long, value_name = "URL")]
+        config_url: Option<String>,
+        /// Fetch the remote config without verifying the server certificate, or over plain HTTP
+        #[arg(long)]
+        insecure: bool,
+        /// Deploy the cached copy of the remote config when it can't be fetched
+        #[arg(long)]
+        allow_stale: bool,
+    },
+    /// Show the progress of a deploy started with `run --detach`
+    #[command(name = "deploys:status")]
+    DeploysStatus {
+        /// The deploy id `run --detach` printed
+        id: String,
+        /// Print the deploy as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Print the output of a deploy started with `run --detach`
+    #[command(name = "deploys:logs")]
+    DeploysLogs {
+        /// The deploy id `run --detach` printed
+        id: String,
+        /// Keep printing until the deploy finishes
+        #[arg(short, long)]
+        follow: bool,
+    },
+    /// Run a queued deploy, started by `run --detach`
+    #[command(name = "deploys:worker", hide = true)]
+    DeploysWorker { id: String },
+    /// Restart the application container
+    Restart {
+        /// The app name, from --app, RUKU_APP or the nearest ruku.yml when left out
+        app: Option<String>,
+        /// Fail unless the container becomes healthy again
+        #[arg(long)]
+        wait_healthy: bool,
+        /// Seconds to wait for the container to become healthy
+        #[arg(long, default_value_t = DEFAULT_HEALTH_TIMEOUT, requires = "wait_healthy")]
+        timeout: u64,
+        /// Restart without running the pre_start command of ruku.yml
+        #[arg(long)]
+        skip_pre_start: bool,
+    },
+    /// Push the application image to the configured registry
+    Push {
+        /// The app name, from --app, RUKU_APP or the nearest ruku.yml when left out
+        app: Option<String>,
+    },
+    /// Show the state and version of the application
+    Status {
+        /// The app name, from --app, RUKU_APP or the nearest ruku.yml when left out
+        app: Option<String>,
+        /// Show this sidecar instead of the app
+        #[arg(long)]
+        sidecar: Option<String>,
+    },
+    /// Print the URL of the app and open it in the browser of a desktop session
+    Open {
+        /// The app name, from --app, RUKU_APP or the nearest ruku.yml when left out
+        app: Option<String>,
+        /// Only print the URL
+        #[arg(long)]
+        print: bool,
+    },
+    /// List the processes running in the app container
+    Top {
+        /// The app name, from --app, RUKU_APP or the nearest ruku.yml when left out
+        app: Option<String>,
+        /// List the processes of this sidecar instead of the app
+        #[arg(long)]
+        sidecar: Option<String>,
+        /// Refresh the list every two seconds until interrupted
+        #[arg(long)]
+        watch: bool,
+        /// Print the titles and processes the daemon reports as JSON, a line per refresh with --watch
+        #[arg(long)]
+        json: bool,
+    },
+    /// List all applications managed by ruku
+    List {
+        /// List the one-off containers instead, e.g. probes and pre-start commands, with their age and deploy
+        #[arg(long)]
+        aux: bool,
+        /// Also list the containers ruku runs for itself, like the proxy
+        #[arg(long)]
+        all: bool,
+    },
+    /// Watch every app in a terminal dashboard, with keys to restart, stop and deploy the selected one
+    Dashboard,
+    /// Answer health checks of load balancers over HTTP, `/healthz` for ruku and `/apps/healthz` for the apps,
+    /// and serve the deploy metrics on `/metrics`
+    Server {
+        /// Address and port to listen on, overrides server.listen of ~/.ruku/config.yml
+        #[arg(long)]
+        listen: Option<String>,
+    },
+    /// Let an app reach another app by name, with <OTHER>_HOST and <OTHER>_PORT set on its next deploy
+    Link {
+        /// The app name
+        app: String,
+        /// The app to reach
+        other: String,
+    },
+    /// Remove a link between two apps
+    Unlink {
+        /// The app name
+        app: String,
+        /// The linked app
+        other: String,
+    },
+    /// Compare the running container against the config
+    Drift {
+        /// The app name, from --app, RUKU_APP or the nearest ruku.yml when left out
+        app: Option<String>,
+        /// Redeploy the container when it drifted
+        #[arg(long)]
+        fix: bool,
+        /// Print the drifted fields as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Check the host setup and report the published ports against the reserved port ranges
+    Doctor,
+    /// Print the version, commit and build date of ruku
+    Version {
+        /// Ask the release endpoint of update_check in ~/.ruku/config.yml whether a newer version exists,
+        /// the answer is cached for a day
+        #[arg(long)]
+        check: bool,
+        /// Print the build as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Clean up containers, state and images left behind by interrupted deploys
+    Repair {
+        /// The app name, from --app, RUKU_APP or the nearest ruku.yml when left out
+        app: Option<String>,
+        /// Print what would be removed, and change nothing
+        #[arg(long)]
+        dry_run: bool,
+        /// Print the plan of --dry-run as JSON
+        #[arg(long, requires = "dry_run")]
+        json: bool,
+    },
+    /// Print deploy and container metrics in the Prometheus text format
+    Metrics {
+        /// Only print the metrics of this app
+        app: Option<String>,
+    },
+    /// Deploy the application
+    Deploy,
+    /// Stop the application
+    Stop {
+        /// The app name, from --app, RUKU_APP or the nearest ruku.yml when left out
+        app: Option<String>,
+        /// Only stop the containers, so `docker logs` and `docker inspect` still work
+        #[arg(long, conflicts_with = "purge")]
+        keep: bool,
+        /// Also remove the image of the current version
+        #[arg(long)]
+        purge: bool,
+        /// Wait this long for open connections to close before stopping, e.g. 30s, overrides drain_period
+        #[arg(long)]
+        drain: Option<String>,
+        /// Exit non-zero when a container exits non-zero or has to be killed on the stop
+        #[arg(long)]
+        strict: bool,
+        /// Print what would be stopped, removed and kept, and change nothing
+        #[arg(long)]
+        dry_run: bool,
+        /// Print the plan of --dry-run as JSON
+        #[arg(long, requires = "dry_run")]
+        json: bool,
+    },
+    /// Stop the application and remove its containers
+    Destroy {
+        /// The app name, from --app, RUKU_APP or the nearest ruku.yml when left out
+        app: Option<String>,
+        /// Also remove the named volumes of the app and the data in them
+        #[arg(long)]
+        volumes: bool,
+        /// Remove the container without committing it to a backup image first
+        #[arg(long)]
+        no_backup: bool,
+        /// Exit non-zero when a container exits non-zero or has to be killed on the stop
+        #[arg(long)]
+        strict: bool,
+        /// Print what would be backed up, removed and kept, and change nothing
+        #[arg(long)]
+        dry_run: bool,
+        /// Print the plan of --dry-run as JSON
+        #[arg(long, requires = "dry_run")]
+        json: bool,
+    },
+    /// Go back to an earlier release of the app, the previous one or one picked from a list on a terminal.
+    /// Only the image goes back unless --with-config, the container is set up by the ruku.yml of now
+    Rollback {
+        /// The app name, from --app, RUKU_APP or the nearest ruku.yml when left out
+        app: Option<String>,
+        /// The release to go back to, by deployment id or version
+        #[arg(long, value_name = "VERSION|ID")]
+        to: Option<String>,
+        /// Set the container up with the settings the release was deployed with, from its config snapshot.
+        /// Secrets keep their values of now, the next deploy goes by ruku.yml again
+        #[arg(long)]
+        with_config: bool,
+    },
+    /// Deploy the app again from the backup taken when ruku last removed its container
+    Undo {
+        /// The app name, from --app, RUKU_APP or the nearest ruku.yml when left out
+        app: Option<String>,
+        /// Restore this backup instead of the newest, by its tag
+        #[arg(long)]
+        backup: Option<String>,
+        /// List the backups of the app and exit
+        #[arg(long, conflicts_with = "backup")]
+        list: bool,
+        /// Print which container would be replaced by which backup, and change nothing
+        #[arg(long, conflicts_with = "list")]
+        dry_run: bool,
+        /// Print the plan of --dry-run as JSON
+        #[arg(long, requires = "dry_run")]
+        json: bool,
+    },
+    /// List the named volumes of the application
+    Volumes {
+        /// The app name, from --app, RUKU_APP or the nearest ruku.yml when left out
+        app: Option<String>,
+    },
+    /// Build, check and create the next version without starting it, for `ruku promote` to put in place
+    Stage {
+        /// The app name, from --app, RUKU_APP or the nearest ruku.yml when left out
+        app: Option<String>,
+        /// Remove the staged deploy and its container instead
+        #[arg(long)]
+        abandon: bool,
+        /// Stage without the vulnerability scan configured in ruku.yml
+        #[arg(long, conflicts_with = "abandon")]
+        skip_scan: bool,
+        /// Build every step again instead of taking it from the build cache
+        #[arg(long, conflicts_with = "abandon")]
+        no_cache: bool,
+        /// Pull newer versions of the base images before building
+        #[arg(long, conflicts_with = "abandon")]
+        pull: bool,
+        /// A note kept with the deployment once promoted, RUKU_DEPLOY_MESSAGE does the same
+        #[arg(long, short, conflicts_with = "abandon")]
+        message: Option<String>,
+    },
+    /// Put the staged deploy in place, or complete a canary rollout when nothing is staged
+    Promote {
+        /// The app name, from --app, RUKU_APP or the nearest ruku.yml when left out
+        app: Option<String>,
+    },
+    /// Remove the canary and restore all traffic to the stable version
+    Abort {
+        /// The app name, from --app, RUKU_APP or the nearest ruku.yml when left out
+        app: Option<String>,
+    },
+    /// Export the app definition into a single archive
+    Export {
+        /// The app name, from --app, RUKU_APP or the nearest ruku.yml when left out
+        app: Option<String>,
+        /// Path of the archive to write, defaults to <app>.ruku.tar.gz
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        /// Do not include the current image, it will be pulled on import
+        #[arg(long)]
+        without_image: bool,
+        /// Do not include the app data directory and the named volumes
+        #[arg(long)]
+        without_volumes: bool,
+    },
+    /// Recreate and deploy an app from an exported archive
+    Import {
+        /// Path of the archive
+        file: PathBuf,
+    },
+    /// List the deployments of an app with the size of the logs kept of each
+    Releases {
+        /// The app name, from --app, RUKU_APP or the nearest ruku.yml when left out
+        app: Option<String>,
+    },
+    /// Print the config snapshot of a deployment, with secrets masked
+    #[command(name = "releases:show")]
+    ReleasesShow {
+        /// The app name
+        app: String,
+        /// The deployment id, as listed in the history or logged by a failed deploy
+        id: String,
+        /// Print the logs kept of the containers of the failed deploy instead
+        #[arg(long)]
+        logs: bool,
+    },
+    /// Compare the config snapshots of two deployments
+    #[command(name = "releases:diff")]
+    ReleasesDiff {
+        /// The app name
+        app: String,
+        /// The older deployment id
+        from: String,
+        /// The newer deployment id
+        to: String,
+        /// Print the changed fields as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Print the SBOM recorded for a deployment, the SPDX document when syft made one
+    #[command(name = "releases:sbom")]
+    ReleasesSbom {
+        /// The app name
+        app: String,
+        /// The deployment id, as listed in the history
+        id: String,
+        /// Write the whole record with layers, labels and build args to this file instead
+        #[arg(long, short, value_name = "PATH")]
+        output: Option<PathBuf>,
+    },
+    /// Stop the app and serve a maintenance page on its port instead
+    #[command(name = "maintenance:on")]
+    MaintenanceOn {
+        /// The app name, from --app, RUKU_APP or the nearest ruku.yml when left out
+        app: Option<String>,
+        /// How long the maintenance should take, e.g. 30m or 2h, shown in status and sent as Retry-After
+        #[arg(long = "for")]
+        duration: Option<String>,
+        /// Text shown on the maintenance page
+        #[arg(long)]
+        message: Option<String>,
+    },
+    /// Remove the maintenance page and bring the app back as it was
+    #[command(name = "maintenance:off")]
+    MaintenanceOff {
+        /// The app name, from --app, RUKU_APP or the nearest ruku.yml when left out
+        app: Option<String>,
+    },
+    /// Deploy a branch of the app as a separate preview app on an automatic port
+    Preview {
+        /// The app name, from --app, RUKU_APP or the nearest ruku.yml when left out
+        app: Option<String>,
+        /// The branch to deploy
+        #[arg(long)]
+        branch: String,
+        /// Days after which `preview:reap` removes the preview
+        #[arg(long)]
+        ttl: Option<u64>,
+    },
+    /// List the previews, grouped by app
+    #[command(name = "preview:list")]
+    PreviewList {
+        /// Only list the previews of this app
+        app: Option<String>,
+    },
+    /// Remove a preview with its containers, volumes and checkout
+    #[command(name = "preview:destroy")]
+    PreviewDestroy {
+        /// The app name
+        app: String,
+        /// The branch of the preview
+        branch: String,
+    },
+    /// Remove the previews that outlived their ttl
+    #[command(name = "preview:reap")]
+    PreviewReap,
+    /// Print the audit log of state-changing commands and verify its hash chain
+    Audit {
+        /// Only print the records of this app, the whole chain is verified either way
+        app: Option<String>,
+    },
+    /// Collect the config, container, logs and Docker details of an app for a bug report, secrets masked
+    #[command(name = "debug-bundle")]
+    DebugBundle {
+        /// The app name, from --app, RUKU_APP or the nearest ruku.yml when left out
+        app: Option<String>,
+        /// Include the record and log of this detached deploy
+        #[arg(long)]
+        deploy: Option<String>,
+        /// Where to write the bundle, `<app>-debug-<time>.tar.gz` by default
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Show the files the app container added, changed or deleted compared to its image
+    Diff {
+        /// The app name, from --app, RUKU_APP or the nearest ruku.yml when left out
+        app: Option<String>,
+        /// Only show changes at or below this path, e.g. /var/log
+        #[arg(long)]
+        path: Option<String>,
+        /// Print the changes as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Remove the build cache no image refers to and report the space freed
+    #[command(name = "build-cache:prune")]
+    BuildCachePrune {
+        /// Remove every unused cache record, including those other tools building here rely on
+        #[arg(long)]
+        all: bool,
+    },
+    /// Show the layers of the image the app runs, with their sizes and the instructions that created them
+    #[command(name = "image:history")]
+    ImageHistory {
+        /// The app name, from --app, RUKU_APP or the nearest ruku.yml when left out
+        app: Option<String>,
+        /// Print the layers as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Save the current image of the app to a bundle for a host without registry access
+    #[command(name = "image:save")]
+    ImageSave {
+        /// The app name, from --app, RUKU_APP or the nearest ruku.yml when left out
+        app: Option<String>,
+        /// Path of the bundle to write, defaults to <app>-<version>.image.tar.gz
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Load an image bundle so `run` deploys it without building or pulling
+    #[command(name = "image:load")]
+    ImageLoad {
+        /// The app name
+        app: String,
+        /// Path of the bundle
+        file: PathBuf,
+        /// Load a bundle saved for another app under this app's name
+        #[arg(long)]
+        rename: bool,
+    },
+    /// Start the proxy routing port 80 of the host to the apps by their domains
+    #[command(name = "proxy:up")]
+    ProxyUp,
+    /// Show whether the proxy runs and the domain of each app it routes
+    #[command(name = "proxy:status")]
+    ProxyStatus,
+    /// Remove the proxy, the apps stay reachable on their own ports
+    #[command(name = "proxy:down")]
+    ProxyDown,
+    /// Git hook
+    #[command(name = "git-hook")]
+    GitHook {
+        /// The git repository name
+        repo: String,
+    },
+    /// Git receive pack
+    #[command(name = "git-receive-pack")]
+    GitReceivePack {
+        /// The git repository name
+        repo: String,
+    },
+    /// Git upload pack
+    #[command(name = "git-upload-pack")]
+    GitUploadPack {
+        /// The git repository name
+        repo: String,
+    },
+}
+
+/// Runs the `ruku` command given on the command line, exiting with its status.
+pub async fn run() {
+    let log = Logger::default();
+
+    let server_config = ServerConfig::new().unwrap_or_else(|e| {
+        log.error(&format!("Error loading server config: {}", e));
+        std::process::exit(1);
+    });
+
+    let git = Git::new(&log, &server_config);
+    let cli = Cli::parse();
+    if cli.debug {
+        logger::set_debug();
+    }
+    if cli.json_events {
+        logger::set_json_events();
+    }
+    // Through the environment, so workers, fleet hosts and sudo trace too
+    if cli.debug_api {
+        std::env::set_var(api_trace::TRACE_ENV, "1");
+    }
+    if api_trace::is_requested() {
+        api_trace::enable(&log, &server_config.state_root);
+    }
+    if server_config.read_only {
+        read_only::set_read_only();
+    }
+    if cli.command.mutates() {
+        read_only::guard("run a command that changes state").unwrap_or_else(|e| exit_with(&log, e));
+    }
+    if let Some(reason) = cli.override_freeze.clone().or(std::env::var(freeze::OVERRIDE_ENV).ok()) {
+        freeze::set_override(&reason).or_exit(&log);
+    }
+    if cli.command.checks_freeze() {
+        let windows: Vec<_> = server_config
+            .freeze
+            .iter()
+            .map(|window| (window, "~/.ruku/config.yml"))
+            .collect();
+        freeze::guard(&log, &windows, "run a command that changes state", chrono::Utc::now()).or_exit(&log);
+    }
+    let confirm = Confirm::new(&log, cli.yes);
+    let requested_context = cli.context.clone().or(std::env::var(CONTEXT_ENV).ok());
+    let app_name = |app: &Option<String>| {
+        let cwd = std::env::current_dir().unwrap_or_default();
+        let app_env = std::env::var(APP_ENV).ok();
+        let (app, source) =
+            resolve_app(app.as_deref(), cli.app_flag.as_deref(), app_env.as_deref(), &cwd).or_exit(&log);
+        if let AppSource::Discovered(path) = source {
+            log.step(&format!("Using app {} from {}", app, path.display()));
+        }
+        let app = get_app_name(&log, &app);
+        // A process a fleet runs already has the context of its host
+        let hosts = match fleet::is_member() {
+            true => vec![],
+            false => load_ruku_config(&app, &server_config)
+                .map(|config| config.hosts)
+                .unwrap_or_default(),
+        };
+        let requested = match (&cli.host, hosts.is_empty()) {
+            (Some(_), true) => {
+                log.error(&format!("--host only applies to apps with hosts, {} has none", app));
+                std::process::exit(1);
+            }
+            (Some(host), false) if !hosts.contains(host) => {
+                log.error(&format!(
+                    "{} is not one of the hosts of {}: {}",
+                    host,
+                    app,
+                    hosts.join(", ")
+                ));
+                std::process::exit(1);
+            }
+            (Some(host), false) => Some(host.clone()),
+            (None, false) if cli.command.fans_out() => {
+                let fleet = Fleet::new(&log, &hosts)
+                    .with_parallel(cli.parallel)
+                    .with_halt_on_failure(matches!(cli.command, Command::Run { .. }));
+                fleet.check(&app, &server_config).or_exit(&log);
+                let args: Vec<String> = std::env::args().skip(1).collect();
+                let results = fleet.run(&args).or_exit(&log);
+                fleet.report(&results).or_exit(&log);
+                std::process::exit(0);
+            }
+            (None, _) => requested_context.clone(),
+        };
+        select_context(&log, &server_config, &app, requested.as_deref());
+        // The context may be read-only
+        if cli.command.mutates() {
+            read_only::guard(&format!("change {}", app)).unwrap_or_else(|e| exit_with(&log, e));
+        }
+        if cli.command.checks_freeze() {
+            if let Ok(config) = load_ruku_config(&app, &server_config) {
+                let source = format!("the ruku.yml of {}", app);
+                let windows: Vec<_> = config.freeze.iter().map(|window| (window, source.as_str())).collect();
+                freeze::guard(&log, &windows, &format!("change {}", app), chrono::Utc::now()).or_exit(&log);
+            }
+        }
+        app
+    };
+
+    #[cfg(unix)]
+    if cli.command.uses_docker() && !sudo::is_reexec() && sudo::docker_permission_denied() {
+        if sudo::sudo_requested(cli.sudo) {
+            let code = sudo::reexec(
+                &log,
+                &[&server_config.ruku_root, &server_config.apps_root],
+                &[&server_config.data_root],
+            )
+            .or_exit(&log);
+            std::process::exit(code);
+        }
+        let user = std::env::var("USER").unwrap_or("$USER".to_string());
+        log.error(&sudo::permission_hint(&user));
+        std::process::exit(1);
+    }
+
+    let selector = cli
+        .selector
+        .as_deref()
+        .map(|selector| Selector::parse(selector).or_exit(&log));
+    if let Some(selector) = &selector {
+        match cli.command.selected_app() {
+            Some((Some(app), _)) => {
+                log.error(&format!("--selector picks the apps, leave out {}", app));
+                std::process::exit(1);
+            }
+            Some((None, action)) => {
+                let docker = get_docker(&log).await.or_exit(&log);
+                let apps = selector.apps(&Container::list_all(&docker).await.or_exit(&log));
+                if apps.is_empty() {
+                    log.error(&format!("No app has containers labelled {}", selector));
+                    std::process::exit(1);
+                }
+                log.step(&format!("{} matches {}", selector, apps.join(", ")));
+                let args: Vec<String> = std::env::args().skip(1).collect();
+                AppGroup::new(&log, apps, DEFAULT_CONCURRENCY)
+                    .run(action, &strip_selector(&args))
+                    .await
+                    .or_exit(&log);
+                return;
+            }
+            None if matches!(cli.command, Command::List { aux: false, .. }) => {}
+            None => {
+                log.error("--selector only applies to list, run, restart, stop and status");
+                std::process::exit(1);
+            }
+        }
+    }
+
+    match &cli.command {
+        Command::Logs {
+            app,
+            follow,
+            tail,
+            save,
+            max_size,
+            keep,
+            sidecar,
+            grep,
+            exclude,
+            level,
+            json,
+            release,
+        } => {
+            let app = app_name(app);
+            let filter = LogFilter::new(grep.as_deref(), exclude.as_deref(), level.as_deref()).or_exit(&log);
+            let docker = load_docker().or_exit(&log);
+            let config = read_ruku_config(&log, &app, &server_config);
+            let container = Container::new(&log, &app, &docker, &config);
+            let container_name = match sidecar {
+                Some(sidecar) => {
+                    let sidecars = Sidecars::new(&log, &app, &docker, &config, &container);
+                    sidecars.container_name(sidecars.find(sidecar).or_exit(&log))
+                }
+                None => container.container_name().to_string(),
+            };
+            let logs = Logs::new(&log, &docker, &container_name)
+                .with_filter(filter)
+                .with_json(*json);
+            if let Some(release) = release {
+                let state_dir = server_config.state_root.join(&app);
+                let release_logs = ReleaseLogs::new(&log, &state_dir);
+                let id = release_logs.resolve(release).or_exit(&log);
+                if let Some(info) = release_logs.info(&id).or_exit(&log) {
+                    log.section(&format!("Logs of {}", info.describe()));
+                    let lines = release_logs.read(&id).or_exit(&log);
+                    logs.print_kept(&lines, *tail);
+                    return;
+                }
+                // `stop --keep` leaves the container of the release around
+                let current = container.get().await.or_exit(&log).and_then(|summary| {
+                    let history = History::new(&log, &state_dir).load_here().or_exit(&log);
+                    release_of(&history, &summary).filter(|current| current.id == id)
+                });
+                if let Some(current) = current {
+                    log.section(&format!(
+                        "Logs of release {}, version {}, from container {}",
+                        id,
+                        get_version(&current.version),
+                        container_name
+                    ));
+                    logs.print(false, *tail).await.or_exit(&log);
+                    return;
+                }
+                let failures = Failures::new(&log, &state_dir);
+                if failures.ids().contains(&id) {
+                    failures.print(&id).or_exit(&log);
+                    return;
+                }
+                let known = release_logs.ids();
+                match known.is_empty() {
+                    true => log.error(&format!("No logs kept for release {}", id)),
+                    false => log.error(&format!("No logs kept for release {}, known: {}", id, known.join(", "))),
+                }
+                std::process::exit(1);
+            }
+            match save {
+                Some(path) => {
+                    let max_size = parse_size(max_size).or_exit(&log);
+                    log.section(&format!("Saving logs to {}", path.display()));
+                    logs.save(&mut RotatingWriter::new(path, max_size, *keep))
+                        .await
+                        .or_exit(&log);
+                }
+                None => logs.print(*follow, *tail).await.or_exit(&log),
+            }
+        }
+        Command::ConfigSet { var } => {
+            let key = var.split('=').next().unwrap_or_default();
+            let audit = AuditLog::new(&server_config.state_root)
+                .begin(&log, "config:set", None, Some(key))
+                .unwrap_or_else(|e| exit_with(&log, e));
+            println!("Setting configuration: {}", var);
+            // Parse `var` into key and value
+            let parts: Vec<&str> = var.split('=').collect();
+            if parts.len() == 2 {
+                let key = parts[0];
+                let value = parts[1];
+                println!("Setting {} to {}", key, value);
+            } else {
+                log.error("Invalid format. Use KEY=VALUE");
+            }
+            audit.succeeded(&log);
+        }
+        Command::ConfigGet { key } => {
+            println!("Getting configuration for: {}", key);
+        }
+        Command::ConfigExplain { app, key } => {
+            let app = get_app_name(&log, app);
+            let (config, provenance) = load_ruku_config_with_provenance(&app, &server_config).or_exit(&log);
+            let fields: Vec<_> = provenance
+                .explain(&config)
+                .into_iter()
+                .filter(|field| match key {
+                    Some(key) => field.key == *key || field.key.starts_with(&format!("{}.", key)),
+                    None => true,
+                })
+                .collect();
+            if fields.is_empty() {
+                log.error(&format!("Unknown config key {}", key.as_deref().unwrap_or_default()));
+                std::process::exit(1);
+            }
+            for field in fields {
+                println!("{:<28} {:<32} {}", field.key, field.value, field.source);
+            }
+        }
+        Command::EnvExport {
+            app,
+            format,
+            mask,
+            show_secrets,
+        } => {
+            let app = app_name(app);
+            let config = read_ruku_config(&log, &app, &server_config);
+            // The client only connects on a request, the env is resolved without one
+            let docker = load_docker().or_exit(&log);
+            let container = Container::new(&log, &app, &docker, &config)
+                .with_links(get_links(&log, &app, &server_config).or_exit(&log));
+            let env = container.spec(container.image_name()).or_exit(&log).env;
+            let masking = match (mask, show_secrets) {
+                (true, _) => Masking::Everything,
+                (_, true) => Masking::Nothing,
+                _ => Masking::Secrets,
+            };
+            if *format != EnvFormat::Json {
+                for key in env_export::invalid_names(&env) {
+                    log.warn(&format!(
+                        "Leaving out {}, a shell can't set a variable of that name",
+                        key
+                    ));
+                }
+            }
+            let output = env_export::render(&env_export::mask(&env, masking, &config.secrets), *format);
+            if !output.is_empty() {
+                println!("{}", output);
+            }
+        }
+        Command::Init { force, minimal } => {
+            log.section("Generating ruku.yml");
+            let path = std::env::current_dir().unwrap_or_else(|e| {
+                log.error(&format!("Error reading the current directory: {}", e));
+                std::process::exit(1);
+            });
+            let config_path = path.join("ruku.yml");
+            if config_path.exists() && !force {
+                log.error("ruku.yml already exists, pass --force to overwrite it");
+                std::process::exit(1);
+            }
+
+            let info = init::detect(&path);
+            if let Some(port) = info.privileged_port {
+                log.warn(&format!(
+                    "The Dockerfile exposes port {}, ruku needs a port of 1024 or above",
+                    port
+                ));
+            }
+            fs::write(&config_path, init::render(&info, *minimal)).unwrap_or_else(|e| {
+                log.error(&format!("Error writing ruku.yml file: {}", e));
+                std::process::exit(1);
+            });
+            log.step(&format!(
+                "Wrote ruku.yml for {} on port {} ({})",
+                info.name, info.port, info.port_source
+            ));
+        }
+        Command::ImportCompose {
+            path,
+            force,
+            deploy: deploy_apps,
+        } => {
+            log.section("Importing compose file");
+            let path = path
+                .clone()
+                .or_else(|| {
+                    compose::DEFAULT_FILES
+                        .iter()
+                        .map(PathBuf::from)
+                        .find(|path| path.exists())
+                })
+                .unwrap_or_else(|| {
+                    log.error("No compose file found in the current directory");
+                    std::process::exit(1);
+                });
+            let import = compose::convert(&path).or_exit(&log);
+            for section in &import.ignored {
+                log.warn(&format!("Ignoring {}", section));
+            }
+            for (service, reason) in &import.skipped {
+                log.warn(&format!("Skipping service {}: {}", service, reason));
+            }
+            if import.apps.is_empty() {
+                log.error("No service could be converted");
+                std::process::exit(1);
+            }
+            let order = import.deploy_order().or_exit(&log);
+            if let Some(existing) = import
+                .apps
+                .iter()
+                .find(|app| app.dir.join("ruku.yml").exists() && !force)
+            {
+                log.error(&format!(
+                    "{} already exists, pass --force to overwrite it",
+                    existing.dir.join("ruku.yml").display()
+                ));
+                std::process::exit(1);
+            }
+            for app in &import.apps {
+                let written = compose::write(app).or_exit(&log);
+                log.step(&format!(
+                    "Wrote {} for service {} as app {}",
+                    written.display(),
+                    app.service,
+                    app.name
+                ));
+                for ignored in &app.ignored {
+                    log.warn(&format!("{}: {}", app.service, ignored));
+                }
+            }
+            if *deploy_apps {
+                for app in order {
+                    let audit = AuditLog::new(&server_config.state_root)
+                        .begin(
+                            &log,
+                            "import-compose",
+                            Some(&app.name),
+                            Some(&path.display().to_string()),
+                        )
+                        .unwrap_or_else(|e| exit_with(&log, e));
+                    compose::install(&log, &server_config, app).or_exit(&log);
+                    let outcome = deploy(&log, &app.name, &server_config, None, |pipeline| pipeline).await;
+                    audit.new_version(outcome.version);
+                    audit.succeeded(&log);
+                }
+            }
+        }
+        Command::Run {
+            app,
+            only_if_changed,
+            adopt,
+            force_replace,
+            show_context,
+            wait_healthy,
+            timeout,
+            dry_run,
+            skip_scan,
+            skip_pre_start,
+            no_backup,
+            strategy,
+            no_share,
+            skip_preflight,
+            no_cache,
+            pull,
+            image_tar,
+            wait_for_image,
+            image_digest,
+            message,
+            detach,
+            config_url,
+            insecure,
+            allow_stale,
+        } => {
+            log.section("Running application");
+            let app = app_name(app);
+            if let Some(digest) = image_digest.as_deref().filter(|digest| !is_digest(digest)) {
+                log.error(&format!(
+                    "--image-digest {} is not a sha256:<64 hex digits> digest",
+                    digest
+                ));
+                std::process::exit(1);
+            }
+            let message = message
+                .clone()
+                .or_else(|| std::env::var(DEPLOY_MESSAGE_ENV).ok())
+                .map(|message| {
+                    check_message(&message).unwrap_or_else(|e| {
+                        log.error(&format!("Error in the deploy message: {}", e));
+                        std::process::exit(1);
+                    })
+                });
+            let message = match (message, freeze::overridden()) {
+                (Some(message), Some(overridden)) => Some(format!("{}\n\n{}", message, overridden)),
+                (message, overridden) => message.or(overridden),
+            };
+            let state_dir = server_config.state_root.join(&app);
+            let refreshed = RemoteConfig::new(&log, &state_dir, &server_config.apps_root.join(&app).join("ruku.yml"))
+                .with_insecure(*insecure)
+                .with_allow_stale(*allow_stale)
+                .refresh(config_url.as_deref(), *dry_run)
+                .or_exit(&log);
+            if *dry_run {
+                if let Some(refreshed) = &refreshed {
+                    let diff = templates::unified_diff(
+                        refreshed.cached.as_deref().unwrap_or_default(),
+                        &refreshed.content,
+                        if refreshed.cached.is_some() {
+                            remote_config::CACHE_FILE
+                        } else {
+                            "/dev/null"
+                        },
+                        &refreshed.url,
+                    );
+                    match diff.is_empty() {
+                        true => log.step("The remote config matches the cached copy"),
+                        false => {
+                            // Only deploying caches it, the rest of the dry run goes by the cached copy
+                            log.section(&format!("The config at {} would change", refreshed.url));
+                            print!("{}", diff);
+                        }
+                    }
+                }
+                let config = get_ruku_config(&log, &app, &server_config);
+                let links = get_links(&log, &app, &server_config).or_exit(&log);
+                let variables = templates::variables(&app, &config, &links).or_exit(&log);
+                let templates = Templates::new(&log, &server_config.state_root.join(&app));
+                for rendered in templates.render_all(&config, &variables).or_exit(&log) {
+                    if !config.files.contains_key(&rendered.target) {
+                        log.section(&format!("{} -> {}", rendered.source, rendered.target));
+                        print!("{}", rendered.masked);
+                        continue;
+                    }
+                    let written = templates.written(&rendered);
+                    let diff = templates::unified_diff(
+                        written.as_deref().unwrap_or_default(),
+                        &rendered.masked,
+                        if written.is_some() {
+                            &rendered.target
+                        } else {
+                            "/dev/null"
+                        },
+                        &rendered.target,
+                    );
+                    match (written, diff.is_empty()) {
+                        (Some(_), true) => log.step(&format!("{} is unchanged", rendered.target)),
+                        (None, _) => {
+                            log.section(&format!("{} would be created", rendered.target));
+                            print!("{}", diff);
+                        }
+                        (Some(_), false) => {
+                            log.section(&format!("{} would change", rendered.target));
+                            print!("{}", diff);
+                        }
+                    }
+                }
+                // Without a daemon there is no container to compare with, the rest of the dry run still helps
+                let docker = load_docker().or_exit(&log);
+                if docker.ping().await.is_ok() {
+                    let container = Container::new(&log, &app, &docker, &config)
+                        .with_links(links.clone())
+                        .with_template_dir(templates.dir().to_path_buf())
+                        .with_static_root(static_root(&server_config.apps_root.join(&app), &config));
+                    match container.live_spec().await {
+                        Some(live) => {
+                            let image_name = get_image_name_with_version(&app, &config.version);
+                            container.use_image(&image_name).await.or_exit(&log);
+                            let changes = container.spec(image_name).or_exit(&log).diff(&live);
+                            match changes.is_empty() {
+                                true => log.step("The container matches the config"),
+                                false => {
+                                    log.section("The container would change");
+                                    let renderer =
+                                        Renderer::new().with_mask(|path| is_secret_path(path, &config.secrets));
+                                    print!("{}", renderer.render(&changes));
+                                }
+                            }
+                        }
+                        None => log.step("No container is deployed yet, the deploy would create it"),
+                    }
+                }
+                let routing = routing::routing_labels(&render_labels(&app, &config).or_exit(&log));
+                if !routing.is_empty() {
+                    log.section("Routing");
+                    for (key, value) in &routing {
+                        println!("{}={}", key, value);
+                    }
+                    for mismatch in routing::port_mismatches(&routing, &config) {
+                        log.warn(&mismatch);
+                    }
+                }
+                if config.create_host_paths.enabled {
+                    // The image user is only known once the image is built
+                    let owner = config.create_host_paths.owner;
+                    let plan = HostPaths::new(&log, &config.create_host_paths)
+                        .plan(&config.all_volume_specs(), owner)
+                        .or_exit(&log);
+                    for (path, action) in plan {
+                        if let HostPathAction::Create { owner, mode } = action {
+                            let line = describe_host_path(&path, owner, mode);
+                            match owner {
+                                Some(_) => log.step(&format!("Would create {}", line)),
+                                None => log.step(&format!("Would create {}, or owned by the image user", line)),
+                            }
+                        }
+                    }
+                }
+                log.step("Dry run, nothing was deployed");
+                return;
+            }
+            if *only_if_changed {
+                let config = read_ruku_config(&log, &app, &server_config);
+                let docker = get_docker(&log).await.or_exit(&log);
+                let container = Container::new(&log, &app, &docker, &config)
+                    .with_links(get_links(&log, &app, &server_config).or_exit(&log))
+                    .with_template_dir(
+                        Templates::new(&log, &server_config.state_root.join(&app))
+                            .dir()
+                            .to_path_buf(),
+                    )
+                    .with_static_root(static_root(&server_config.apps_root.join(&app), &config));
+                let running = container
+                    .get()
+                    .await
+                    .or_exit(&log)
+                    .filter(|c| c.state.as_deref() == Some("running"));
+                let live = running.as_ref().and_then(deployed_version);
+                if live.as_deref() == Some(get_version(&config.version)) {
+                    log.step(&describe_version_drift(live.as_deref(), get_version(&config.version)));
+                    // Same version, but a changed label or other setting still needs a new container
+                    let image_name = get_image_name_with_version(&app, &config.version);
+                    container.use_image(&image_name).await.or_exit(&log);
+                    let desired = container.spec(image_name).or_exit(&log);
+                    let live_spec = container.live_spec().await;
+                    let mut drift = match &live_spec {
+                        Some(live) => desired.diff(live),
+                        None => vec![],
+                    };
+                    let hash_change = live_spec.as_ref().and_then(|live| desired.hash_change(live));
+                    // A changed or missing sidecar needs the deploy as much as the app container does
+                    let sidecars = Sidecars::new(&log, &app, &docker, &config, &container);
+                    for sidecar in &config.sidecars {
+                        match sidecars.drift(sidecar).await {
+                            Some(sidecar_drift) => drift.extend(
+                                sidecar_drift
+                                    .into_iter()
+                                    .map(|change| change.nested(&format!("sidecars.{}", sidecar.name))),
+                            ),
+                            None => drift.push(Change {
+                                path: format!("sidecars.{}", sidecar.name),
+                                kind: ChangeKind::Added,
+                                from: None,
+                                to: Some("created".to_string()),
+                            }),
+                        }
+                    }
+                    // An unhealthy or crash looping container is replaced even when nothing changed
+                    let condition = container.condition().await;
+                    let failing = condition
+                        .as_ref()
+                        .filter(|condition| condition.is_unhealthy() || condition.is_crash_looping());
+                    match (failing, hash_change) {
+                        _ if !drift.is_empty() => {
+                            log.step("Config changed:");
+                            let renderer = Renderer::new().with_mask(|path| is_secret_path(path, &config.secrets));
+                            print!("{}", renderer.render(&drift));
+                        }
+                        // Only the hash differs, which after an upgrade is ruku's doing and not the config's
+                        (_, Some(HashChange::Version { live })) => log.step(&format!(
+                            "Recreating because this ruku hashes the config differently (v{} to v{}), \
+                             the config did not change",
+                            live.map(|version| version.to_string()).unwrap_or("?".to_string()),
+                            CONFIG_HASH_VERSION
+                        )),
+                        (_, Some(HashChange::Config)) => log.step("Config changed: config hash"),
+                        (Some(condition), None) => log.step(&format!("Replacing: {}", condition.describe())),
+                        (None, None) => {
+                            log.step("Nothing to deploy");
+                            return;
+                        }
+                    }
+                }
+            }
+            if *force_replace {
+                let config = read_ruku_config(&log, &app, &server_config);
+                let docker = get_docker(&log).await.or_exit(&log);
+                let existing = Container::new(&log, &app, &docker, &config).get().await.or_exit(&log);
+                if let Some(existing) = existing.filter(|c| !is_managed(c)) {
+                    let image = existing.image.as_deref().unwrap_or("unknown image");
+                    confirm
+                        .ask(
+                            "replace a container ruku did not create",
+                            &[format!("{} from {}", describe_container(&existing), image)],
+                            Answer::Yes,
+                        )
+                        .or_exit(&log);
+                }
+            }
+            let options = DeployOptions {
+                adopt: *adopt,
+                force_replace: *force_replace,
+                show_context: *show_context,
+                wait_healthy: wait_healthy.then_some(*timeout),
+                skip_scan: *skip_scan,
+                skip_pre_start: *skip_pre_start,
+                no_backup: *no_backup,
+                strategy: *strategy,
+                no_share: *no_share,
+                skip_preflight: *skip_preflight,
+                no_cache: *no_cache,
+                pull: *pull,
+                // Absolute, a detached deploy runs from another directory
+                image_tarball: image_tar
+                    .as_ref()
+                    .map(|path| std::path::absolute(path).unwrap_or(path.clone())),
+                wait_for_image: *wait_for_image,
+                image_digest: image_digest.clone(),
+                message: message.clone(),
+            };
+            if *detach {
+                // A broken ruku.yml fails here rather than in the background
+                get_ruku_config(&log, &app, &server_config);
+                let config_path = config_file(&log, &app, &server_config);
+                let request = Deploys::new(&log, &server_config)
+                    .queue(&app, &config_path, options)
+                    .or_exit(&log);
+                log.step(&format!(
+                    "Queued deploy {}, follow it with `ruku deploys:logs {} --follow`",
+                    request.id, request.id
+                ));
+                println!("{}", request.id);
+                return;
+            }
+            let audit = AuditLog::new(&server_config.state_root)
+                .begin(&log, "run", Some(&app), message.as_deref())
+                .unwrap_or_else(|e| exit_with(&log, e));
+            audit.old_version(live_version(&log, &app, &server_config).await);
+            let outcome = deploy(&log, &app, &server_config, None, |pipeline| options.apply(pipeline)).await;
+            audit.new_version(outcome.version);
+            audit.succeeded(&log);
+        }
+        Command::DeploysStatus { id, json } => {
+            let request = Deploys::new(&log, &server_config).get(id).or_exit(&log);
+            if *json {
+                let mut value = serde_json::to_value(&request).unwrap();
+                // The snapshot is for the worker, not worth printing on every poll
+                value.as_object_mut().unwrap().remove("config");
+                println!("{}", serde_json::to_string_pretty(&value).unwrap());
+                return;
+            }
+            let time = |at: Option<chrono::DateTime<chrono::Utc>>| {
+                at.map(|at| at.format("%Y-%m-%d %H:%M:%S UTC").to_string())
+                    .unwrap_or("-".to_string())
+            };
+            println!("Deploy:    {}", request.id);
+            println!("App:       {}", request.app);
+            println!("Status:    {}", request.status);
+            println!("Queued:    {}", time(Some(request.queued_at)));
+            println!("Started:   {}", time(request.started_at));
+            println!("Finished:  {}", time(request.finished_at));
+            if let Some(version) = &request.version {
+                println!("Version:   {}", version);
+            }
+            if let Some(error) = &request.error {
+                println!("Error:     {}", error);
+            }
+            if request.status == DeployStatus::Failed {
+                std::process::exit(1);
+            }
+        }
+        Command::DeploysLogs { id, follow } => {
+            Deploys::new(&log, &server_config)
+                .print_log(id, *follow)
+                .await
+                .or_exit(&log);
+        }
+        Command::DeploysWorker { id } => {
+            // The output goes to the deploy log
+            colored::control::set_override(false);
+            let deploys = Deploys::new(&log, &server_config);
+            let mut request = deploys.start(id).or_exit(&log);
+            log.section(&format!("Running detached deploy {}", request.id));
+            select_context(&log, &server_config, &request.app, requested_context.as_deref());
+            let audit = AuditLog::new(&server_config.state_root)
+                .begin(&log, "run", Some(&request.app), Some(id))
+                .unwrap_or_else(|e| exit_with(&log, e));
+            if let Some(message) = &request.options.message {
+                audit.detail(message);
+            }
+            let config_path = config_file(&log, &request.app, &server_config);
+            if fs::read_to_string(&config_path).ok().as_deref() != Some(request.config.as_str()) {
+                log.error("ruku.yml changed after the deploy was queued, run `ruku run --detach` again");
+                std::process::exit(1);
+            }
+            audit.old_version(live_version(&log, &request.app, &server_config).await);
+            let outcome = deploy(&log, &request.app, &server_config, Some(id), |pipeline| {
+                request.options.apply(pipeline).with_wait_for_lock(true)
+            })
+            .await;
+            audit.new_version(outcome.version.clone());
+            audit.succeeded(&log);
+            deploys.succeeded(&mut request, &outcome).or_exit(&log);
+            log.section(&format!("Deploy {} succeeded", request.id));
+        }
+        Command::Push { app } => {
+            log.section("Pushing image");
+            let app = app_name(app);
+            let config = read_ruku_config(&log, &app, &server_config);
+            let Some(registry) = config.build.as_ref().and_then(|b| b.registry.as_deref()) else {
+                log.error("No registry is configured, set build.registry in ruku.yml");
+                std::process::exit(1);
+            };
+            let docker = get_docker(&log).await.or_exit(&log);
+            let image_name_with_version = get_image_name_with_version(&app, &config.version);
+            let target = get_registry_image_name(registry, &app, &config.version);
+            Image::new(&log, &docker)
+                .push(&image_name_with_version, &target)
+                .await
+                .or_exit(&log);
+        }
+        Command::Restart {
+            app,
+            wait_healthy,
+            timeout,
+            skip_pre_start,
+        } => {
+            log.section("Restarting application");
+            let app = app_name(app);
+            let config = read_ruku_config(&log, &app, &server_config);
+            let docker = get_docker(&log).await.or_exit(&log);
+            let container = Container::new(&log, &app, &docker, &config).with_skip_pre_start(*skip_pre_start);
+            container.restart().await.or_exit(&log);
+            if *wait_healthy {
+                require_healthy(&log, &docker, &container, Duration::from_secs(*timeout))
+                    .await
+                    .or_exit(&log);
+            }
+        }
+        Command::Top {
+            app,
+            sidecar,
+            watch,
+            json,
+        } => {
+            let app = app_name(app);
+            let config = read_ruku_config(&log, &app, &server_config);
+            let docker = get_docker(&log).await.or_exit(&log);
+            let container = Container::new(&log, &app, &docker, &config);
+            let container_name = match sidecar {
+                Some(sidecar) => {
+                    let sidecars = Sidecars::new(&log, &app, &docker, &config, &container);
+                    sidecars.container_name(sidecars.find(sidecar).or_exit(&log))
+                }
+                None => container.container_name().to_string(),
+            };
+            Top::new(&docker, &container_name)
+                .print(*watch, *json)
+                .await
+                .or_exit(&log);
+        }
+        Command::Open { app, print } => {
+            let app = app_name(app);
+            let config = read_ruku_config(&log, &app, &server_config);
+            let docker = get_docker(&log).await.or_exit(&log);
+            let Some(summary) = Container::new(&log, &app, &docker, &config).get().await.or_exit(&log) else {
+                log.error(&format!("{} is not running, deploy it with `ruku run {}`", app, app));
+                std::process::exit(1);
+            };
+            let Some(url) = app_url(&summary, &local_docker_host(), &server_config.state_root) else {
+                log.error(&format!(
+                    "{} has no domain and publishes no TCP port, there is no URL to open",
+                    app
+                ));
+                std::process::exit(1);
+            };
+            println!("{}", url);
+            if !*print && !open_in_browser(&url) {
+                log.step("No browser to open it in, the URL is printed above");
+            }
+        }
+        Command::Status { app, sidecar } => {
+            let app = app_name(app);
+            let config = read_ruku_config(&log, &app, &server_config);
+            let docker = get_docker(&log).await.or_exit(&log);
+            let container = Container::new(&log, &app, &docker, &config);
+            let sidecars = Sidecars::new(&log, &app, &docker, &config, &container);
+            let sidecar_status = |containers: &[bollard::models::ContainerSummary], name: &str| {
+                containers
+                    .iter()
+                    .find(|summary| get_container_name(summary).as_deref() == Some(name))
+                    .map(|summary| summary.status.clone().unwrap_or("in an unknown state".to_string()))
+            };
+            if let Some(sidecar) = sidecar {
+                let sidecar = sidecars.find(sidecar).or_exit(&log);
+                let name = sidecars.container_name(sidecar);
+                match sidecar_status(&container.list_app().await.or_exit(&log), &name) {
+                    Some(status) => {
+                        log.step(&format!("{} is {}", name, status));
+                        log.step(&format!("Image: {}", sidecar.image));
+                        let drift = sidecars.drift(sidecar).await.unwrap_or_default();
+                        if !drift.is_empty() {
+                            let fields: Vec<&str> = drift.iter().map(|change| change.path.as_str()).collect();
+                            log.warn(&format!(
+                                "Differs from ruku.yml in {}, the next deploy recreates it",
+                                fields.join(", ")
+                            ));
+                        }
+                    }
+                    None => log.step(&format!("{} is not created, the next deploy starts it", name)),
+                }
+                return;
+            }
+            let summary = container.get().await.or_exit(&log);
+            if let Some(summary) = &summary {
+                Migration::new(&log, &server_config.state_root.join(&app))
+                    .run(summary)
+                    .or_exit(&log);
+            }
+            if let Some(maintenance) = MaintenanceState::read(&server_config.state_root.join(&app)) {
+                log.warn(&format!(
+                    "Maintenance mode is on {}, port {} serves the maintenance page",
+                    maintenance.describe(chrono::Utc::now()),
+                    maintenance.host_port
+                ));
+            }
+            if let Some(staged) = Staging::read(&log, &server_config.state_root.join(&app)).or_exit(&log) {
+                log.step(&format!(
+                    "{} is staged as {} since {}, `ruku promote {}` puts it in place",
+                    staged.image,
+                    staged.container,
+                    staged.staged_at.format("%Y-%m-%d %H:%M:%S UTC"),
+                    app
+                ));
+            }
+            if let Some(redirect) = LowPortRedirect::read(&log, &server_config.state_root.join(&app)).or_exit(&log) {
+                log.warn(&format!(
+                    "Port {} is published on {} because the daemon is rootless, {}",
+                    redirect.port,
+                    redirect.published_on,
+                    redirect.hint()
+                ));
+            }
+            let source = format!("the ruku.yml of {}", app);
+            let windows: Vec<_> = server_config
+                .freeze
+                .iter()
+                .map(|window| (window, "~/.ruku/config.yml"))
+                .chain(config.freeze.iter().map(|window| (window, source.as_str())))
+                .collect();
+            if let Some(freeze) = freeze::active(&windows, chrono::Utc::now()) {
+                log.warn(&format!("Changes are frozen: {}", freeze.describe()));
+            }
+            match summary {
+                // Left behind by `stop --keep`, the next run replaces it
+                Some(summary) if summary.state.as_deref() == Some("exited") => {
+                    log.step(&format!(
+                        "{} is stopped (kept), {}",
+                        app,
+                        summary.status.as_deref().unwrap_or("in an unknown state")
+                    ));
+                    log.step(&describe_version_drift(
+                        deployed_version(&summary).as_deref(),
+                        get_version(&config.version),
+                    ));
+                }
+                Some(summary) => {
+                    log.step(&format!(
+                        "{} is {}",
+                        app,
+                        summary.status.as_deref().unwrap_or("in an unknown state")
+                    ));
+                    log.step(&describe_version_drift(
+                        deployed_version(&summary).as_deref(),
+                        get_version(&config.version),
+                    ));
+                    let ports = describe_bindings(&summary);
+                    if !ports.is_empty() {
+                        log.step(&format!("Ports: {}", ports.join(", ")));
+                    }
+                    if let Some(url) = app_url(&summary, &local_docker_host(), &server_config.state_root) {
+                        log.step(&format!("URL: {}", url));
+                    }
+                    if config.port.auto {
+                        log.step(&format!(
+                            "Host port {} was assigned automatically",
+                            config.port.host_port
+                        ));
+                    }
+                    let network_mode = summary
+                        .host_config
+                        .as_ref()
+                        .and_then(|host_config| host_config.network_mode.clone())
+                        .unwrap_or(config.network_mode.to_string());
+                    let detail = match network_mode.as_str() {
+                        "host" => " (shares the network of the host)",
+                        "none" => " (no network access)",
+                        _ if config.internal => " (internal, no access outside of it)",
+                        _ => "",
+                    };
+                    log.step(&format!("Network mode: {}{}", network_mode, detail));
+                    if let Some(live) = container.live_spec().await {
+                        // What the container runs with, which the image decides when ruku.yml sets nothing
+                        let timezone = match live.env.get("TZ") {
+                            Some(timezone) => timezone.clone(),
+                            None if live.binds.iter().any(|bind| bind.starts_with("/etc/localtime:")) => {
+                                "the host's".to_string()
+                            }
+                            None => "the image's".to_string(),
+                        };
+                        let locale = live.env.get("LANG").cloned().unwrap_or("the image's".to_string());
+                        log.step(&format!("Timezone: {}, locale: {}", timezone, locale));
+                        let namespaces: Vec<String> = [
+                            ("ipc", &live.ipc_mode),
+                            ("pid", &live.pid_mode),
+                            ("uts", &live.uts_mode),
+                        ]
+                        .into_iter()
+                        .filter_map(|(field, mode)| mode.as_ref().map(|mode| format!("{}={}", field, mode)))
+                        .collect();
+                        if !namespaces.is_empty() {
+                            log.step(&format!("Namespaces: {}", namespaces.join(", ")));
+                        }
+                        if live.cpuset_cpus.is_some() || live.cpuset_mems.is_some() {
+                            log.step(&format!(
+                                "Pinned to CPUs {}, memory nodes {}",
+                                live.cpuset_cpus.as_deref().unwrap_or("any"),
+                                live.cpuset_mems.as_deref().unwrap_or("any")
+                            ));
+                        }
+                    }
+                    match container.pids().await {
+                        Some((current, Some(limit))) if current * 10 >= limit * 8 => {
+                            log.warn(&format!("Processes: {} of {}, close to the pids limit", current, limit))
+                        }
+                        Some((current, Some(limit))) => log.step(&format!("Processes: {} of {}", current, limit)),
+                        Some((current, None)) => log.step(&format!("Processes: {}, no pids limit", current)),
+                        None => {}
+                    }
+                }
+                None => log.step(&format!("{} is not deployed", app)),
+            }
+            match image_drift::check(&log, &docker, container.container_name()).await {
+                Some(ImageDrift::Current) | None => {}
+                Some(drift) => log.warn(&format!("Image: {}, a rollback can't go back to it by tag", drift)),
+            }
+            if !config.sidecars.is_empty() {
+                let containers = container.list_app().await.or_exit(&log);
+                let states: Vec<String> = config
+                    .sidecars
+                    .iter()
+                    .map(|sidecar| {
+                        let status = sidecar_status(&containers, &sidecars.container_name(sidecar));
+                        format!("{} ({})", sidecar.name, status.as_deref().unwrap_or("not created"))
+                    })
+                    .collect();
+                log.step(&format!("Sidecars: {}", states.join(", ")));
+            }
+            let links = Links::new(&server_config.state_root.join(&app)).load().or_exit(&log);
+            if !links.is_empty() {
+                log.step(&format!("Linked to: {}", links.join(", ")));
+            }
+            let dependencies = Dependencies::new(&log, &docker);
+            let mut states = vec![];
+            for dependency in get_dependencies(&config, &server_config) {
+                states.push(format!(
+                    "{} ({})",
+                    dependency.name,
+                    dependencies.state(&dependency).await
+                ));
+            }
+            if !states.is_empty() {
+                log.step(&format!("Depends on: {}", states.join(", ")));
+            }
+        }
+        Command::Link { app, other } => {
+            let app = get_app_name(&log, app);
+            let other = get_app_name(&log, other);
+            if app == other {
+                log.error("An app can't be linked to itself");
+                std::process::exit(1);
+            }
+            if !server_config.apps_root.join(&other).exists() {
+                log.error(&format!("App {} not found", other));
+                std::process::exit(1);
+            }
+            if !Links::new(&server_config.state_root.join(&app))
+                .add(&other)
+                .or_exit(&log)
+            {
+                log.step(&format!("{} is already linked to {}", app, other));
+                return;
+            }
+            // Join the network right away so the name resolves, the env vars need a redeploy
+            let config = read_ruku_config(&log, &app, &server_config);
+            let docker = get_docker(&log).await.or_exit(&log);
+            let container = Container::new(&log, &app, &docker, &config);
+            if container.get().await.or_exit(&log).is_some() && container.network().is_some() {
+                let networks = Networks::new(&log, &docker);
+                networks.ensure(&other, false).await.or_exit(&log);
+                networks
+                    .connect(container.container_name(), &other, &app)
+                    .await
+                    .or_exit(&log);
+            }
+            let prefix = get_env_prefix(&other);
+            log.step(&format!(
+                "Linked {} to {}, {}_HOST and {}_PORT are set on the next deploy",
+                app, other, prefix, prefix
+            ));
+        }
+        Command::Unlink { app, other } => {
+            let app = get_app_name(&log, app);
+            let other = get_app_name(&log, other);
+            if !Links::new(&server_config.state_root.join(&app))
+                .remove(&other)
+                .or_exit(&log)
+            {
+                log.step(&format!("{} is not linked to {}", app, other));
+                return;
+            }
+            let config = read_ruku_config(&log, &app, &server_config);
+            let docker = get_docker(&log).await.or_exit(&log);
+            let container = Container::new(&log, &app, &docker, &config);
+            if container.get().await.or_exit(&log).is_some() {
+                Networks::new(&log, &docker)
+                    .disconnect(container.container_name(), &other)
+                    .await;
+            }
+            log.step(&format!(
+                "Unlinked {} from {}, its env vars are removed on the next deploy",
+                app, other
+            ));
+        }
+        Command::Drift { app, fix, json } => {
+            log.section("Checking for drift");
+            let app = app_name(app);
+            let config = read_ruku_config(&log, &app, &server_config);
+            let docker = get_docker(&log).await.or_exit(&log);
+            let container = Container::new(&log, &app, &docker, &config)
+                .with_links(get_links(&log, &app, &server_config).or_exit(&log))
+                .with_template_dir(
+                    Templates::new(&log, &server_config.state_root.join(&app))
+                        .dir()
+                        .to_path_buf(),
+                )
+                .with_static_root(static_root(&server_config.apps_root.join(&app), &config));
+            Drift::new(&log, &app, &config, &container)
+                .run(*fix, *json)
+                .await
+                .or_exit(&log);
+        }
+        Command::List { aux: true, .. } => {
+            let docker = get_docker(&log).await.or_exit(&log);
+            let containers = list_aux(&docker, None).await.unwrap_or_else(|_| {
+                log.error("Failed to list containers");
+                std::process::exit(1);
+            });
+            for summary in &containers {
+                println!("{}", describe_aux(summary));
+            }
+        }
+        Command::List { aux: false, all } => {
+            let docker = get_docker(&log).await.or_exit(&log);
+            let mut summaries = Container::list_all(&docker).await.or_exit(&log);
+            if let Some(selector) = &selector {
+                summaries.retain(|summary| selector.picks(summary));
+                if summaries.is_empty() {
+                    log.error(&format!("No app has containers labelled {}", selector));
+                    std::process::exit(1);
+                }
+            }
+            for row in app_rows(&summaries, &server_config) {
+                let url = row.url.as_deref().unwrap_or("-");
+                println!("{:<24} {:<10} {:<28} {}", row.container, row.state, url, row.version);
+            }
+            if *all {
+                if let Some(summary) = Proxy::new(&log, &docker, &server_config.state_root).get().await {
+                    let state = summary.state.as_deref().unwrap_or("unknown");
+                    println!("{:<24} {:<10} {:<28} proxy", PROXY_CONTAINER, state, "-");
+                }
+            }
+        }
+        Command::Dashboard => {
+            let docker = get_docker(&log).await.or_exit(&log);
+            Dashboard::new(&log, &docker, &server_config).run().await.or_exit(&log);
+        }
+        Command::Server { listen } => {
+            // No preflight, the server answers /healthz while the daemon is down and /apps/healthz says so
+            let docker = load_docker().or_exit(&log);
+            let listen = listen.clone().unwrap_or(server_config.server.listen.clone());
+            HealthServer::new(&docker, server_config)
+                .run(&log, &listen)
+                .await
+                .or_exit(&log);
+        }
+        Command::Version { check, json } => {
+            let build = version::build_info();
+            if *json {
+                println!("{}", serde_json::to_string_pretty(&build).unwrap());
+            } else {
+                println!("ruku {}", build.version);
+                println!("Commit:  {}", build.git_sha);
+                println!("Built:   {}", build.build_date);
+            }
+            if *check {
+                match version::check(&log, &server_config.update_check, &server_config.state_root) {
+                    Ok((latest, true)) => log.warn(&format!("ruku {} is available, this is {}", latest, build.version)),
+                    Ok((latest, false)) => log.step(&format!("Up to date, the latest release is {}", latest)),
+                    Err(e) => {
+                        log.error(&format!("Could not check for a newer version: {}", e));
+                        std::process::exit(1);
+                    }
+                }
+            }
+        }
+        Command::Doctor => {
+            log.section("Checking the host");
+            let windows: Vec<_> = server_config
+                .freeze
+                .iter()
+                .map(|window| (window, "~/.ruku/config.yml"))
+                .collect();
+            match freeze::active(&windows, chrono::Utc::now()) {
+                Some(freeze) => log.warn(&format!("Changes are frozen: {}", freeze.describe())),
+                None if !windows.is_empty() => {
+                    log.step(&format!("No freeze now, {} windows configured", windows.len()))
+                }
+                None => {}
+            }
+            let host = &server_config.host;
+            match &host.path {
+                Some(path) => log.step(&format!("Host config: {}", path.display())),
+                None => log.step(&format!("No host config, {}", host.describe())),
+            }
+            let docker = get_docker(&log).await.or_exit(&log);
+            if let Some(platform) = Platforms::new(&log, &docker).host().await {
+                log.step(&format!("Docker runs {} natively", platform));
+            }
+            let emulated = emulated_architectures();
+            if emulated.is_empty() {
+                log.warn(
+                    "No qemu binfmt emulation, images for other architectures can't run here. \
+                     Install it with `docker run --privileged --rm tonistiigi/binfmt --install all`",
+                );
+            } else {
+                log.step(&format!("Emulated through qemu binfmt: {}", emulated.join(", ")));
+            }
+            if let Some(network) = DaemonNetwork::remembered() {
+                for (name, proxy) in [
+                    ("HTTP", &network.http_proxy),
+                    ("HTTPS", &network.https_proxy),
+                    ("No", &network.no_proxy),
+                ] {
+                    if let Some(proxy) = proxy {
+                        log.step(&format!("Docker pulls with {} proxy {}", name, proxy));
+                    }
+                }
+                match network.mirrors.is_empty() {
+                    true => log.step("No registry mirrors, Docker Hub images are pulled from Docker Hub"),
+                    false => log.step(&format!("Registry mirrors: {}", network.mirrors.join(", "))),
+                }
+                if let Some((name, _)) = host_proxy().filter(|_| !network.has_proxy()) {
+                    log.warn(&format!(
+                        "{} is set here but the Docker daemon has no proxy, pulls go out without one. The \
+                         daemon needs the proxy in its own environment or in /etc/docker/daemon.json",
+                        name
+                    ));
+                }
+            }
+            // Docker lists a binding once per host address family
+            let mut published: BTreeMap<u16, BTreeSet<String>> = BTreeMap::new();
+            for summary in Container::list_all(&docker).await.or_exit(&log) {
+                let Some(app) = summary.labels.as_ref().and_then(|labels| labels.get(APP_LABEL)) else {
+                    continue;
+                };
+                for port in summary.ports.iter().flatten().filter_map(|port| port.public_port) {
+                    published.entry(port).or_default().insert(app.clone());
+                }
+            }
+
+            for (name, range) in &host.port_ranges {
+                let in_range: Vec<String> = published
+                    .range(range.start..=range.end)
+                    .map(|(port, apps)| format!("{} ({})", port, apps.iter().cloned().collect::<Vec<_>>().join(", ")))
+                    .collect();
+                let size = u32::from(range.end - range.start) + 1;
+                log.step(&format!(
+                    "{} {}: {} of {} ports in use{}",
+                    name,
+                    range,
+                    in_range.len(),
+                    size,
+                    if in_range.is_empty() {
+                        String::new()
+                    } else {
+                        format!(", {}", in_range.join(", "))
+                    }
+                ));
+            }
+            let unreserved = published
+                .keys()
+                .filter(|port| host.reserved_by(**port).is_none())
+                .count();
+            log.step(&format!("{} published ports outside the reserved ranges", unreserved));
+
+            // Ports published before a range was reserved, or by apps whose ruku.yml changed since
+            let mut misplaced = 0;
+            for (port, apps) in &published {
+                for app in apps {
+                    let Ok(config) = load_ruku_config(app, &server_config) else {
+                        continue;
+                    };
+                    let checked = host
+                        .reservation(app, &config)
+                        .and_then(|reservation| host.check_port(reservation.as_ref(), *port));
+                    if let Err(e) = checked {
+                        log.warn(&format!("{}: {}", app, e));
+                        misplaced += 1;
+                    }
+                }
+            }
+            if misplaced > 0 {
+                log.error(&format!(
+                    "{} ports break the reserved ranges, the next deploy refuses them",
+                    misplaced
+                ));
+                std::process::exit(1);
+            }
+        }
+        Command::Repair { app, dry_run, json } => {
+            log.section("Repairing application");
+            let app = app_name(app);
+            let config = read_ruku_config(&log, &app, &server_config);
+            let docker = get_docker(&log).await.or_exit(&log);
+            let state_path = server_config.state_root.join(&app);
+            let repair = Repair::new(&log, &app, &config.container_prefix, &docker, &state_path)
+                .with_sidecars(config.sidecars.iter().map(|sidecar| sidecar.name.clone()).collect())
+                .with_aux_max_age(Duration::from_secs(server_config.aux_max_age));
+            let leftovers = repair.scan().await.or_exit(&log);
+            if *dry_run {
+                repair.plan(&leftovers).print(*json);
+                return;
+            }
+            if leftovers.is_empty() {
+                log.step("Nothing to repair");
+                return;
+            }
+            confirm
+                .ask("remove", &repair.plan(&leftovers).affected(), Answer::Yes)
+                .or_exit(&log);
+            repair.clean_all(&leftovers).await;
+        }
+        Command::Metrics { app } => {
+            // Left out it covers every app, only an explicit --app narrows it down
+            let apps: Vec<String> = match app.as_deref().or(cli.app_flag.as_deref()) {
+                Some(app) => vec![get_app_name(&log, app)],
+                None => metrics::recorded_apps(&server_config.state_root),
+            };
+
+            let docker = load_docker().or_exit(&log);
+            let live = Container::list_all(&docker).await.or_exit(&log);
+            let metrics = metrics::collect(&server_config.state_root, apps, &live);
+            print!("{}", metrics::render(&metrics));
+        }
+        Command::Deploy => {
+            log.section("Starting deployment");
+        }
+        Command::Stop {
+            app,
+            keep,
+            purge,
+            drain,
+            strict,
+            dry_run,
+            json,
+        } => {
+            log.section("Stopping application...");
+            let app = app_name(app);
+            let config = read_ruku_config(&log, &app, &server_config);
+            let docker = get_docker(&log).await.or_exit(&log);
+            let container = Container::new(&log, &app, &docker, &config);
+            let image = Image::new(&log, &docker);
+            let image_name = get_image_name_with_version(&app, &config.version);
+            let image_exists = image.exists(&image_name).await;
+            let history = History::new(&log, &server_config.state_root.join(&app));
+
+            let mut plan = Plan::new("stop", &app);
+            plan.add_containers(
+                if *keep { Action::Stop } else { Action::Remove },
+                &container.list_app().await.or_exit(&log),
+            );
+            let image_action = if *purge { Action::Remove } else { Action::Keep };
+            plan.add_image(&docker, image_action, &image_name).await;
+            plan.add_volumes(
+                Action::Keep,
+                &Volumes::new(&log, &app, &docker).sized().await.or_exit(&log),
+            );
+            if history.path().exists() {
+                plan.add(Action::Keep, Resource::StateFile, &history.path().display().to_string());
+            }
+            if *dry_run {
+                plan.print(*json);
+                return;
+            }
+
+            let audit = AuditLog::new(&server_config.state_root)
+                .begin(&log, "stop", Some(&app), None)
+                .unwrap_or_else(|e| exit_with(&log, e));
+            audit.old_version(live_version(&log, &app, &server_config).await);
+            if !plan.is_empty() {
+                let action = if *keep { "stop" } else { "stop and remove" };
+                confirm.ask(action, &plan.affected(), Answer::Yes).or_exit(&log);
+            }
+            let drain_period = match drain {
+                Some(drain) => parse_duration(drain, 'm').unwrap_or_else(|e| {
+                    log.error(&format!("Invalid drain period: {}", e));
+                    std::process::exit(1);
+                }),
+                None => Duration::from_secs(config.drain_period),
+            };
+            let running = container
+                .get()
+                .await
+                .or_exit(&log)
+                .filter(|summary| summary.state.as_deref() == Some("running"));
+            if let Some(summary) = running.filter(|_| !drain_period.is_zero()) {
+                // Without a port in ruku.yml the container listens on what its image exposes
+                if let Some(running_image) = &summary.image {
+                    container.use_image(running_image).await.or_exit(&log);
+                }
+                // Out of service first, the proxy sends it no new requests while the open ones finish
+                if !Proxy::new(&log, &docker, &server_config.state_root)
+                    .withdraw(&app)
+                    .await
+                {
+                    log.step(&format!(
+                        "{} has no route in the ruku proxy, requests to its published port keep coming in",
+                        app
+                    ));
+                }
+                Drain::new(&log, &docker, drain_period)
+                    .run(container.container_name(), container.container_port())
+                    .await;
+            }
+            container.end_all(config.concurrency, *keep).await.or_exit(&log);
+            record_stops(&log, &audit, &container, *strict);
+            if *purge {
+                image.remove(&image_name).await;
+            }
+
+            if *keep {
+                log.step(&format!("Containers were kept, `ruku stop {}` removes them", app));
+            }
+            if image_exists && !*purge {
+                log.step(&format!("Kept image {}, pass --purge to remove it", image_name));
+            }
+            let app_volumes = Volumes::new(&log, &app, &docker).describe().await.or_exit(&log);
+            if !app_volumes.is_empty() {
+                log.step(&format!(
+                    "Kept volumes {}, `ruku destroy {} --volumes` removes them",
+                    app_volumes.join(", "),
+                    app
+                ));
+            }
+            if history.path().exists() {
+                log.step(&format!("Kept the deploy history in {}", history.path().display()));
+            }
+            audit.succeeded(&log);
+        }
+        Command::Destroy {
+            app,
+            volumes,
+            no_backup,
+            strict,
+            dry_run,
+            json,
+        } => {
+            log.section("Destroying application");
+            let app = app_name(app);
+            let config = read_ruku_config(&log, &app, &server_config);
+            let docker = get_docker(&log).await.or_exit(&log);
+            let container = Container::new(&log, &app, &docker, &config);
+            let app_volumes = Volumes::new(&log, &app, &docker);
+
+            let mut plan = Plan::new("destroy", &app);
+            if let Some(summary) = container.get().await.or_exit(&log).filter(|_| !*no_backup) {
+                plan.add(Action::Backup, Resource::Container, container.container_name())
+                    .with(summary.id.as_deref(), None)
+                    .detail("to a backup image `ruku undo` restores");
+            }
+            plan.add_containers(Action::Remove, &container.list_app().await.or_exit(&log));
+            let volume_action = if *volumes { Action::Remove } else { Action::Keep };
+            plan.add_volumes(volume_action, &app_volumes.sized().await.or_exit(&log));
+            if *dry_run {
+                plan.print(*json);
+                return;
+            }
+
+            let detail = volumes.then_some("with volumes");
+            let audit = AuditLog::new(&server_config.state_root)
+                .begin(&log, "destroy", Some(&app), detail)
+                .unwrap_or_else(|e| exit_with(&log, e));
+            audit.old_version(live_version(&log, &app, &server_config).await);
+            if *volumes {
+                confirm
+                    .ask(
+                        "destroy the app and delete its data",
+                        &plan.affected(),
+                        Answer::Name(&app),
+                    )
+                    .or_exit(&log);
+            } else if !plan.is_empty() {
+                confirm
+                    .ask("destroy the app", &plan.affected(), Answer::Yes)
+                    .or_exit(&log);
+            }
+            if !*no_backup {
+                if let Some(summary) = container.get().await.or_exit(&log) {
+                    Backups::new(&log, &app, &docker, &server_config.state_root.join(&app))
+                        .with_timeout(Duration::from_secs(server_config.backup_timeout))
+                        .create(
+                            summary.id.as_deref().unwrap_or(container.container_name()),
+                            container.container_name(),
+                            summary.image.as_deref(),
+                            "destroy",
+                        )
+                        .await
+                        .or_exit(&log);
+                }
+            }
+            container.end_all(config.concurrency, false).await.or_exit(&log);
+            record_stops(&log, &audit, &container, *strict);
+            LowPortRedirect::clear(&server_config.state_root.join(&app));
+            Proxy::new(&log, &docker, &server_config.state_root).refresh().await;
+            if *volumes {
+                app_volumes.remove_all().await.or_exit(&log);
+            } else {
+                log.step("Named volumes were kept, pass --volumes to remove them");
+            }
+            audit.succeeded(&log);
+        }
+        Command::Rollback { app, to, with_config } => {
+            log.section("Rolling back");
+            let app = app_name(app);
+            let state_dir = server_config.state_root.join(&app);
+            let config = read_ruku_config(&log, &app, &server_config);
+            let docker = get_docker(&log).await.or_exit(&log);
+            let deployments = History::new(&log, &state_dir).load_here().or_exit(&log);
+            let container = Container::new(&log, &app, &docker, &config)
+                .with_links(get_links(&log, &app, &server_config).or_exit(&log))
+                .with_template_dir(Templates::new(&log, &state_dir).dir().to_path_buf())
+                .with_static_root(static_root(&server_config.apps_root.join(&app), &config));
+            let existing = container.get().await.or_exit(&log);
+            let current = existing.as_ref().and_then(|summary| release_of(&deployments, summary));
+            let rollback = Rollback::new(&log, &docker, &app, &deployments)
+                .with_registry(config.build.as_ref().and_then(|build| build.registry.as_deref()));
+            let exit = |e: String| -> ! {
+                log.error(&e);
+                std::process::exit(1);
+            };
+            let release = match to {
+                Some(to) => rollback.find(to).unwrap_or_else(|e| exit(e)),
+                None => {
+                    let previous = rollback.previous(current.as_ref()).unwrap_or_else(|e| exit(e));
+                    match std::io::stdin().is_terminal() && !confirm.assumes_yes() {
+                        true => rollback
+                            .pick(current.as_ref(), previous)
+                            .await
+                            .unwrap_or_else(|e| exit(e)),
+                        false => previous,
+                    }
+                }
+            };
+            if current.as_ref().is_some_and(|current| current.id == release.id) {
+                exit(format!("Release {} is the one running", release.id));
+            }
+            // Before anything is touched, a release that can't be brought back leaves the app as it is
+            let restored = with_config.then(|| {
+                let snapshot = Releases::new(&state_dir).load(&release.id).unwrap_or_else(|e| exit(e));
+                snapshot.restore(&config).unwrap_or_else(|e| exit(e))
+            });
+            let image = rollback.obtain(release).await.unwrap_or_else(|e| exit(e));
+            let audit = AuditLog::new(&server_config.state_root)
+                .begin(&log, "rollback", Some(&app), Some(&release.id))
+                .unwrap_or_else(|e| exit_with(&log, e));
+            audit.old_version(existing.as_ref().and_then(deployed_version));
+            let described = format!(
+                "release {}, version {} deployed {}",
+                release.id,
+                get_version(&release.version),
+                release.finished_at.format("%Y-%m-%d %H:%M UTC")
+            );
+            if let Some(existing) = &existing {
+                confirm
+                    .ask(
+                        &format!("replace it with {}", described),
+                        &[describe_container(existing)],
+                        Answer::Yes,
+                    )
+                    .or_exit(&log);
+            }
+            match &restored {
+                Some(restored) => {
+                    Container::new(&log, &app, &docker, restored)
+                        .with_links(get_links(&log, &app, &server_config).or_exit(&log))
+                        .with_template_dir(Templates::new(&log, &state_dir).dir().to_path_buf())
+                        .with_static_root(static_root(&server_config.apps_root.join(&app), restored))
+                        .run_image(image)
+                        .await
+                        .unwrap_or_else(|e| exit(e));
+                    log.step("The settings of the release apply until the next deploy, which goes by ruku.yml");
+                }
+                None => container.run_image(image).await.unwrap_or_else(|e| exit(e)),
+            }
+            log.step(&format!(
+                "{} runs {} again, {}",
+                app,
+                described,
+                rollback::health(release)
+            ));
+            audit.succeeded(&log);
+        }
+        Command::Undo {
+            app,
+            backup,
+            list,
+            dry_run,
+            json,
+        } => {
+            let app = app_name(app);
+            let docker = get_docker(&log).await.or_exit(&log);
+            let backups = Backups::new(&log, &app, &docker, &server_config.state_root.join(&app));
+            if *list {
+                for backup in backups.list().or_exit(&log).iter().rev() {
+                    println!(
+                        "{}  {}  before {}  from {}",
+                        backup.image,
+                        backup.created_at.to_rfc3339(),
+                        backup.reason,
+                        backup.source_image.as_deref().unwrap_or("unknown image")
+                    );
+                }
+                return;
+            }
+            log.section("Restoring backup");
+            let backup = backups.find(backup.as_deref()).or_exit(&log);
+            let config = read_ruku_config(&log, &app, &server_config);
+            let container = Container::new(&log, &app, &docker, &config)
+                .with_takeover(Takeover::ForceReplace)
+                .with_skip_pre_start(true);
+            if !Image::new(&log, &docker).exists(&backup.image).await {
+                log.error(&format!("Backup image {} is gone", backup.image));
+                std::process::exit(1);
+            }
+            let existing = container.get().await.or_exit(&log);
+            if *dry_run {
+                let mut plan = Plan::new("undo", &app);
+                let detail = format!(
+                    "with backup {} taken before {} at {}",
+                    backup.image,
+                    backup.reason,
+                    backup.created_at.to_rfc3339()
+                );
+                match &existing {
+                    Some(summary) => plan
+                        .add(Action::Replace, Resource::Container, container.container_name())
+                        .with(summary.id.as_deref(), None),
+                    None => plan.add(Action::Create, Resource::Container, container.container_name()),
+                }
+                .detail(&detail);
+                plan.print(*json);
+                return;
+            }
+            let audit = AuditLog::new(&server_config.state_root)
+                .begin(&log, "undo", Some(&app), Some(&backup.image))
+                .unwrap_or_else(|e| exit_with(&log, e));
+            audit.old_version(live_version(&log, &app, &server_config).await);
+            if let Some(existing) = existing {
+                confirm
+                    .ask(
+                        &format!("replace it with the backup from {}", backup.created_at.to_rfc3339()),
+                        &[describe_container(&existing)],
+                        Answer::Yes,
+                    )
+                    .or_exit(&log);
+            }
+            container.run_image(backup.image.clone()).await.or_exit(&log);
+            log.step(&format!(
+                "{} runs {}, taken before {} of {}",
+                app, backup.image, backup.reason, backup.container_name
+            ));
+            audit.succeeded(&log);
+        }
+        Command::Volumes { app } => {
+            let app = app_name(app);
+            let config = read_ruku_config(&log, &app, &server_config);
+            let docker = get_docker(&log).await.or_exit(&log);
+            Volumes::new(&log, &app, &docker)
+                .print(&config.all_volume_specs())
+                .await
+                .or_exit(&log);
+        }
+        Command::Stage { app, abandon: true, .. } => {
+            log.section("Abandoning the staged deploy");
+            let app = app_name(app);
+            let audit = AuditLog::new(&server_config.state_root)
+                .begin(&log, "stage", Some(&app), None)
+                .unwrap_or_else(|e| exit_with(&log, e));
+            let config = read_ruku_config(&log, &app, &server_config);
+            let docker = get_docker(&log).await.or_exit(&log);
+            let container = Container::new(&log, &app, &docker, &config);
+            Staging::new(&log, &container, &server_config.state_root.join(&app))
+                .abandon()
+                .await
+                .or_exit(&log);
+            audit.succeeded(&log);
+        }
+        Command::Stage {
+            app,
+            abandon: false,
+            skip_scan,
+            no_cache,
+            pull,
+            message,
+        } => {
+            let app = app_name(app);
+            let message = message
+                .clone()
+                .or_else(|| std::env::var(DEPLOY_MESSAGE_ENV).ok())
+                .map(|message| {
+                    check_message(&message).unwrap_or_else(|e| {
+                        log.error(&format!("Error in the deploy message: {}", e));
+                        std::process::exit(1);
+                    })
+                });
+            let audit = AuditLog::new(&server_config.state_root)
+                .begin(&log, "stage", Some(&app), message.as_deref())
+                .unwrap_or_else(|e| exit_with(&log, e));
+            let outcome = deploy(&log, &app, &server_config, None, |pipeline| {
+                pipeline
+                    .with_stage(true)
+                    .with_skip_scan(*skip_scan)
+                    .with_no_cache(*no_cache)
+                    .with_pull(*pull)
+                    .with_message(message.clone())
+            })
+            .await;
+            audit.new_version(outcome.version);
+            audit.succeeded(&log);
+        }
+        Command::Promote { app } => {
+            let app = app_name(app);
+            if Staging::read(&log, &server_config.state_root.join(&app))
+                .or_exit(&log)
+                .is_some()
+            {
+                log.section("Promoting the staged deploy");
+                let audit = AuditLog::new(&server_config.state_root)
+                    .begin(&log, "promote", Some(&app), None)
+                    .unwrap_or_else(|e| exit_with(&log, e));
+                audit.old_version(live_version(&log, &app, &server_config).await);
+                // The pipeline logged its error already
+                let outcome = DeployPipeline::new(&log, &app, &server_config)
+                    .promote()
+                    .await
+                    .unwrap_or_else(|e| std::process::exit(exit_code(&e)));
+                audit.new_version(outcome.version);
+                audit.succeeded(&log);
+                return;
+            }
+            log.section("Promoting canary");
+            let audit = AuditLog::new(&server_config.state_root)
+                .begin(&log, "promote", Some(&app), None)
+                .unwrap_or_else(|e| exit_with(&log, e));
+            audit.old_version(live_version(&log, &app, &server_config).await);
+            let config = read_ruku_config(&log, &app, &server_config);
+            let docker = get_docker(&log).await.or_exit(&log);
+            let container = Container::new(&log, &app, &docker, &config);
+            Canary::new(&log, &container, &server_config.state_root.join(&app))
+                .promote()
+                .await
+                .or_exit(&log);
+            audit.new_version(live_version(&log, &app, &server_config).await);
+            audit.succeeded(&log);
+        }
+        Command::Abort { app } => {
+            log.section("Aborting canary");
+            let app = app_name(app);
+            let audit = AuditLog::new(&server_config.state_root)
+                .begin(&log, "abort", Some(&app), None)
+                .unwrap_or_else(|e| exit_with(&log, e));
+            let config = read_ruku_config(&log, &app, &server_config);
+            let docker = get_docker(&log).await.or_exit(&log);
+            let container = Container::new(&log, &app, &docker, &config);
+            Canary::new(&log, &container, &server_config.state_root.join(&app))
+                .abort()
+                .await
+                .or_exit(&log);
+            audit.new_version(live_version(&log, &app, &server_config).await);
+            audit.succeeded(&log);
+        }
+        Command::Export {
+            app,
+            output,
+            without_image,
+            without_volumes,
+        } => {
+            log.section("Exporting application");
+            let app = app_name(app);
+            let config = read_ruku_config(&log, &app, &server_config);
+            let output = output
+                .clone()
+                .unwrap_or_else(|| PathBuf::from(format!("{}.ruku.tar.gz", app)));
+
+            let export = Export::new(&log, &app, &server_config, &config);
+            if *without_image && *without_volumes {
+                export.run(&output, None, false, false).await.or_exit(&log);
+            } else {
+                let docker = get_docker(&log).await.or_exit(&log);
+                export
+                    .run(&output, Some(&docker), !without_image, !without_volumes)
+                    .await
+                    .or_exit(&log);
+            }
+        }
+        Command::Import { file } => {
+            log.section("Importing application");
+            let import = Import::new(&log, &server_config);
+            let archive = import.open(file).or_exit(&log);
+            confirm
+                .ask("import the app", &import.affected(&archive), Answer::Yes)
+                .or_exit(&log);
+            import.restore(&archive).or_exit(&log);
+            let docker = get_docker(&log).await.or_exit(&log);
+            import.deploy(&archive, &docker).await.or_exit(&log);
+        }
+        Command::Releases { app } => {
+            let app = app_name(app);
+            let state_dir = server_config.state_root.join(&app);
+            let docker = get_docker(&log).await.or_exit(&log);
+            let config = read_ruku_config(&log, &app, &server_config);
+            let deployments = History::new(&log, &state_dir).load_here().or_exit(&log);
+            let current = Container::new(&log, &app, &docker, &config)
+                .get()
+                .await
+                .or_exit(&log)
+                .and_then(|summary| release_of(&deployments, &summary));
+            let release_logs = ReleaseLogs::new(&log, &state_dir);
+            println!("{:<16} {:<20} {:<18} LOGS", "ID", "VERSION", "DEPLOYED");
+            for deployment in deployments.iter().rev() {
+                let logs = match &current {
+                    Some(current) if current.id == deployment.id => "running".to_string(),
+                    _ => release_logs.size(&deployment.id).unwrap_or("-".to_string()),
+                };
+                println!(
+                    "{:<16} {:<20} {:<18} {}",
+                    deployment.id,
+                    get_version(&deployment.version),
+                    deployment.finished_at.format("%Y-%m-%d %H:%M"),
+                    logs
+                );
+            }
+        }
+        Command::ReleasesShow { app, id, logs } => {
+            let app = get_app_name(&log, app);
+            if *logs {
+                Failures::new(&log, &server_config.state_root.join(&app))
+                    .print(id)
+                    .or_exit(&log);
+                return;
+            }
+            let snapshot = Releases::new(&server_config.state_root.join(&app))
+                .load(id)
+                .or_exit(&log);
+            println!(
+                "Deployment {} of {} at {}, version {}",
+                snapshot.id,
+                snapshot.app,
+                snapshot.created_at.to_rfc3339(),
+                get_version(&snapshot.version)
+            );
+            if let Some(message) = &snapshot.message {
+                println!("Message: {}", message);
+            }
+            for field in snapshot.fields {
+                println!("{:<28} {:<32} {}", field.key, field.value, field.source);
+            }
+        }
+        Command::ReleasesDiff { app, from, to, json } => {
+            let app = get_app_name(&log, app);
+            let releases = Releases::new(&server_config.state_root.join(&app));
+            let changes = releases::diff(&releases.load(from).or_exit(&log), &releases.load(to).or_exit(&log));
+            if *json {
+                println!("{}", Renderer::new().json(&changes));
+                return;
+            }
+            if changes.is_empty() {
+                log.step(&format!("Deployments {} and {} ran the same config", from, to));
+            }
+            print!("{}", Renderer::new().render(&changes));
+        }
+        Command::ReleasesSbom { app, id, output } => {
+            let app = get_app_name(&log, app);
+            let sbom = Sboms::new(&log, &server_config.state_root.join(&app))
+                .load(id)
+                .or_exit(&log);
+            match (output, &sbom.spdx) {
+                (Some(output), _) => {
+                    let content = serde_json::to_string_pretty(&sbom).unwrap();
+                    std::fs::write(output, content).unwrap_or_else(|e| {
+                        log.error(&format!("Error writing {}: {}", output.display(), e));
+                        std::process::exit(1);
+                    });
+                    log.step(&format!("Wrote the SBOM of {} to {}", sbom.image, output.display()));
+                }
+                (None, Some(spdx)) => println!("{}", serde_json::to_string_pretty(spdx).unwrap()),
+                (None, None) => println!("{}", serde_json::to_string_pretty(&sbom).unwrap()),
+            }
+        }
+        Command::MaintenanceOn { app, duration, message } => {
+            log.section("Turning maintenance mode on");
+            let app = app_name(app);
+            let duration = duration.as_deref().map(|duration| {
+                parse_duration(duration, 'm')
+                    .ok()
+                    .and_then(|duration| chrono::Duration::from_std(duration).ok())
+                    .unwrap_or_else(|| {
+                        log.error(&format!("invalid duration '{}', use e.g. 90s, 30m or 1h30m", duration));
+                        std::process::exit(1);
+                    })
+            });
+            let audit = AuditLog::new(&server_config.state_root)
+                .begin(&log, "maintenance:on", Some(&app), None)
+                .unwrap_or_else(|e| exit_with(&log, e));
+            let config = read_ruku_config(&log, &app, &server_config);
+            let docker = get_docker(&log).await.or_exit(&log);
+            let container = Container::new(&log, &app, &docker, &config);
+            Maintenance::new(
+                &log,
+                &app,
+                &docker,
+                &config,
+                &container,
+                &server_config.state_root.join(&app),
+            )
+            .on(duration, message.as_deref())
+            .await
+            .or_exit(&log);
+            audit.succeeded(&log);
+        }
+        Command::MaintenanceOff { app } => {
+            log.section("Turning maintenance mode off");
+            let app = app_name(app);
+            let audit = AuditLog::new(&server_config.state_root)
+                .begin(&log, "maintenance:off", Some(&app), None)
+                .unwrap_or_else(|e| exit_with(&log, e));
+            let config = read_ruku_config(&log, &app, &server_config);
+            let docker = get_docker(&log).await.or_exit(&log);
+            let container = Container::new(&log, &app, &docker, &config);
+            Maintenance::new(
+                &log,
+                &app,
+                &docker,
+                &config,
+                &container,
+                &server_config.state_root.join(&app),
+            )
+            .off()
+            .await
+            .or_exit(&log);
+            audit.succeeded(&log);
+        }
+        Command::Preview { app, branch, ttl } => {
+            log.section("Deploying preview");
+            let app = app_name(app);
+            let preview = Previews::new(&log, &server_config)
+                .checkout(&app, branch, *ttl)
+                .or_exit(&log);
+            deploy(&log, &preview.name, &server_config, None, |pipeline| pipeline).await;
+            log.step(&format!(
+                "Deployed {} as {}, `ruku preview:destroy {} {}` removes it",
+                branch, preview.name, app, branch
+            ));
+        }
+        Command::PreviewList { app } => {
+            let app = app
+                .as_deref()
+                .or(cli.app_flag.as_deref())
+                .map(|app| get_app_name(&log, app));
+            let previews = Previews::new(&log, &server_config).list(app.as_deref()).or_exit(&log);
+            if previews.is_empty() {
+                log.step("No previews");
+                return;
+            }
+            let docker = load_docker().or_exit(&log);
+            let live = Container::list_all(&docker).await.or_exit(&log);
+            let now = chrono::Utc::now();
+            for (i, preview) in previews.iter().enumerate() {
+                if i == 0 || previews[i - 1].app != preview.app {
+                    log.section(&preview.app);
+                }
+                let state = live
+                    .iter()
+                    .find(|summary| {
+                        let labels = summary.labels.clone().unwrap_or_default();
+                        labels.get(APP_LABEL) == Some(&preview.name) && labels.contains_key(PREVIEW_LABEL)
+                    })
+                    .and_then(|summary| summary.state.clone())
+                    .unwrap_or("not deployed".to_string());
+                let port = load_ruku_config(&preview.name, &server_config)
+                    .ok()
+                    .filter(|config| config.port.host_port != 0)
+                    .map(|config| format!("port {}", config.port.host_port))
+                    .unwrap_or("no port yet".to_string());
+                let expiry = match preview.ttl_days {
+                    _ if preview.is_expired(now) => "expired".to_string(),
+                    Some(days) => format!("expires after {} days", days),
+                    None => "no ttl".to_string(),
+                };
+                println!(
+                    "{:<32} {:<24} {:<14} {}, created {}, {}",
+                    preview.name,
+                    preview.branch,
+                    state,
+                    port,
+                    preview.created_at.format("%Y-%m-%d"),
+                    expiry
+                );
+            }
+        }
+        Command::PreviewDestroy { app, branch } => {
+            log.section("Destroying preview");
+            let app = get_app_name(&log, app);
+            let previews = Previews::new(&log, &server_config);
+            let preview = previews.get(&app, branch).or_exit(&log);
+            let docker = get_docker(&log).await.or_exit(&log);
+            confirm
+                .ask(
+                    "destroy the preview and delete its data",
+                    &[format!("{} of branch {}", preview.name, preview.branch)],
+                    Answer::Yes,
+                )
+                .or_exit(&log);
+            destroy_preview(&log, &server_config, &docker, &previews, &preview).await;
+        }
+        Command::ProxyUp => {
+            log.section("Starting the proxy");
+            let docker = get_docker(&log).await.or_exit(&log);
+            Proxy::new(&log, &docker, &server_config.state_root)
+                .up()
+                .await
+                .or_exit(&log);
+        }
+        Command::ProxyStatus => {
+            let docker = get_docker(&log).await.or_exit(&log);
+            Proxy::new(&log, &docker, &server_config.state_root)
+                .status()
+                .await
+                .or_exit(&log);
+        }
+        Command::ProxyDown => {
+            log.section("Removing the proxy");
+            let docker = get_docker(&log).await.or_exit(&log);
+            Proxy::new(&log, &docker, &server_config.state_root)
+                .down()
+                .await
+                .or_exit(&log);
+        }
+        Command::PreviewReap => {
+            log.section("Reaping expired previews");
+            let previews = Previews::new(&log, &server_config);
+            let now = chrono::Utc::now();
+            let expired: Vec<Preview> = previews
+                .list(None)
+                .or_exit(&log)
+                .into_iter()
+                .filter(|preview| preview.is_expired(now))
+                .collect();
+            if expired.is_empty() {
+                log.step("No expired previews");
+                return;
+            }
+            let affected: Vec<String> = expired
+                .iter()
+                .map(|preview| format!("{} of branch {}", preview.name, preview.branch))
+                .collect();
+            confirm
+                .ask("destroy the previews and delete their data", &affected, Answer::Yes)
+                .or_exit(&log);
+            let docker = get_docker(&log).await.or_exit(&log);
+            for preview in &expired {
+                destroy_preview(&log, &server_config, &docker, &previews, preview).await;
+            }
+        }
+        Command::DebugBundle { app, deploy, output } => {
+            log.section("Writing debug bundle");
+            let app = app_name(app);
+            let output = output.clone().unwrap_or_else(|| {
+                PathBuf::from(format!("{}-debug-{}.tar.gz", app, Utc::now().format("%Y%m%d%H%M%S")))
+            });
+            let docker = get_docker(&log).await.or_exit(&log);
+            DebugBundle::new(&log, &app, &server_config, &docker)
+                .write(&output, deploy.as_deref())
+                .await
+                .or_exit(&log);
+        }
+        Command::Audit { app } => {
+            let app = app
+                .as_deref()
+                .or(cli.app_flag.as_deref())
+                .map(|app| get_app_name(&log, app));
+            let audit_log = AuditLog::new(&server_config.state_root);
+            let records = audit_log.verify().unwrap_or_else(|(line, e)| {
+                log.error(&format!(
+                    "Audit log {} is not intact at line {}: {}",
+                    audit_log.path().display(),
+                    line,
+                    e
+                ));
+                std::process::exit(1);
+            });
+            for record in records.iter().filter(|record| app.is_none() || record.app == app) {
+                let versions = match (&record.old_version, &record.new_version) {
+                    (None, None) => String::new(),
+                    (old, new) => format!(
+                        " {} -> {}",
+                        old.as_deref().unwrap_or("-"),
+                        new.as_deref().unwrap_or("-")
+                    ),
+                };
+                println!(
+                    "{:<6} {} {:<12} {:<12} {:<24}{}{} {}",
+                    record.seq,
+                    record.timestamp.format("%Y-%m-%d %H:%M:%S"),
+                    record.operator,
+                    record.command,
+                    record.app.as_deref().unwrap_or("-"),
+                    record
+                        .detail
+                        .as_ref()
+                        .map(|detail| format!(" ({})", detail))
+                        .unwrap_or_default(),
+                    versions,
+                    record.outcome
+                );
+            }
+            log.step(&format!("Audit log intact, {} records", records.len()));
+        }
+        Command::Diff { app, path, json } => {
+            let app = app_name(app);
+            let config = read_ruku_config(&log, &app, &server_config);
+            let docker = get_docker(&log).await.or_exit(&log);
+            let container = Container::new(&log, &app, &docker, &config);
+            if container.get().await.or_exit(&log).is_none() {
+                log.error(&format!("{} is not deployed", app));
+                std::process::exit(1);
+            }
+            let changes = inspect::container_changes(&docker, container.container_name())
+                .await
+                .or_exit(&log);
+            let changes = match path {
+                Some(path) => filter_changes(changes, path),
+                None => changes,
+            };
+            let (added, changed, deleted) = count_changes(&changes);
+            if *json {
+                let report = serde_json::json!({
+                    "container": container.container_name(),
+                    "added": added,
+                    "changed": changed,
+                    "deleted": deleted,
+                    "changes": changes,
+                });
+                println!("{}", serde_json::to_string_pretty(&report).unwrap());
+            } else {
+                for change in &changes {
+                    println!("{} {}", change.kind.letter(), change.path);
+                }
+                log.step(&format!(
+                    "{} added, {} changed, {} deleted in {}",
+                    added,
+                    changed,
+                    deleted,
+                    container.container_name()
+                ));
+            }
+        }
+        Command::BuildCachePrune { all } => {
+            log.section("Pruning the build cache");
+            BuildCache::new(&log).prune(*all).or_exit(&log);
+        }
+        Command::ImageHistory { app, json } => {
+            let app = app_name(app);
+            let config = read_ruku_config(&log, &app, &server_config);
+            let docker = get_docker(&log).await.or_exit(&log);
+            // The image the container runs, which may be older than the version in the config
+            let image = Container::new(&log, &app, &docker, &config)
+                .get()
+                .await
+                .or_exit(&log)
+                .and_then(|summary| summary.image)
+                .unwrap_or_else(|| get_image_name_with_version(&app, &config.version));
+            let layers = inspect::image_history(&docker, &image).await.or_exit(&log);
+            let total: i64 = layers.iter().map(|layer| layer.size).sum();
+            if *json {
+                let report = serde_json::json!({
+                    "image": image,
+                    "total_size": total,
+                    "total_size_human": format_size(total.max(0) as u64),
+                    "layers": layers,
+                });
+                println!("{}", serde_json::to_string_pretty(&report).unwrap());
+            } else {
+                for layer in &layers {
+                    let created = layer
+                        .created
+                        .map(|created| created.format("%Y-%m-%d %H:%M").to_string())
+                        .unwrap_or("unknown".to_string());
+                    let id = layer.short_id().unwrap_or("<missing>");
+                    println!(
+                        "{:<12} {:<16} {:>10}  {}",
+                        id,
+                        created,
+                        format_size(layer.size.max(0) as u64),
+                        layer.created_by
+                    );
+                }
+                log.step(&format!(
+                    "{} layers, {} in total for {}",
+                    layers.len(),
+                    format_size(total.max(0) as u64),
+                    image
+                ));
+            }
+        }
+        Command::ImageSave { app, output } => {
+            log.section("Saving image");
+            let app = app_name(app);
+            let config = read_ruku_config(&log, &app, &server_config);
+            let output = output
+                .clone()
+                .unwrap_or_else(|| PathBuf::from(format!("{}-{}.image.tar.gz", app, get_version(&config.version))));
+            let docker = get_docker(&log).await.or_exit(&log);
+            ImageBundle::new(&log, &docker)
+                .save(&app, &config.version, &output)
+                .await
+                .or_exit(&log);
+        }
+        Command::ImageLoad { app, file, rename } => {
+            log.section("Loading image");
+            let app = get_app_name(&log, app);
+            let docker = get_docker(&log).await.or_exit(&log);
+            let loaded = ImageBundle::new(&log, &docker)
+                .load(&app, file, &server_config.state_root.join(&app), *rename)
+                .await
+                .or_exit(&log);
+            log.step(&format!(
+                "Registered {}, `ruku run {}` deploys it while ruku.yml has version {}",
+                loaded.image,
+                app,
+                get_version(&loaded.version)
+            ));
+        }
+        Command::GitHook { repo } => {
+            let app = get_app_name(&log, repo);
+            select_context(&log, &server_config, &app, requested_context.as_deref());
+            let audit = AuditLog::new(&server_config.state_root)
+                .begin(&log, "git-hook", Some(&app), None)
+                .unwrap_or_else(|e| exit_with(&log, e));
+            git.cmd_git_hook(&app).or_exit(&log);
+            audit.old_version(live_version(&log, &app, &server_config).await);
+            let outcome = deploy(&log, &app, &server_config, None, |pipeline| pipeline).await;
+            audit.new_version(outcome.version);
+            audit.succeeded(&log);
+        }
+        Command::GitReceivePack { repo } => {
+            log.section("... RUKU ...");
+            let app = get_app_name(&log, repo);
+            let _ = get_ruku_config(&log, &app, &server_config);
+            git.cmd_git_receive_pack(&app).or_exit(&log);
+        }
+        Command::GitUploadPack { repo } => {
+            log.section("... RUKU ...");
+            let app = get_app_name(&log, repo);
+            git.cmd_git_upload_pack(&app).or_exit(&log);
+        }
+    }
+}
+
+/// Deploy the app of `repo` with the pipeline options `options` sets.
+async fn deploy(
+    log: &Logger,
+    repo: &str,
+    server_config: &ServerConfig,
+    deploy_id: Option<&str>,
+    options: impl for<'p> FnOnce(DeployPipeline<'p>) -> DeployPipeline<'p>,
+) -> DeployOutcome {
+    log.section("Deploying application");
+    let app = get_app_name(log, repo);
+    let hint = match deploy_id {
+        Some(id) => format!("ruku debug-bundle {} --deploy {}", app, id),
+        None => format!("ruku debug-bundle {}", app),
+    };
+    log.on_error(move |_| {
+        eprintln!(
+            "=> {}",
+            format!("Run `{}` to collect the details for a bug report", hint).yellow()
+        )
+    });
+    // The pipeline logged its error already
+    options(DeployPipeline::new(log, &app, server_config))
+        .run()
+        .await
+        .unwrap_or_else(|e| std::process::exit(exit_code(&e)))
+}
+
+/// Talk to the daemon of the context `app` is deployed to, and say which one that is when contexts are
+/// set up. Exits when `requested` is unknown or disagrees with the context the app pins.
+fn select_context(log: &Logger, server_config: &ServerConfig, app: &str, requested: Option<&str>) {
+    let pinned = load_ruku_config(app, server_config)
+        .ok()
+        .and_then(|config| config.context);
+    if server_config.contexts.is_empty() && pinned.is_none() && requested.is_none() {
+        return;
+    }
+    match resolve_context(&server_config.contexts, app, pinned.as_deref(), requested) {
+        Ok(Some((name, context))) => {
+            use_context(name, context);
+            if is_plaintext_remote(&context.host, context.tls.as_ref()) {
+                log.warn(&format!(
+                    "Context '{}' reaches {} unencrypted, anyone on the network path can control the daemon, \
+                     set its tls certificates or use an ssh:// address",
+                    name, context.host
+                ));
+            }
+            if context.read_only {
+                read_only::set_read_only();
+            }
+            log.step(
+                &format!("{} on context '{}' ({})", app, name, context.host)
+                    .bold()
+                    .to_string(),
+            );
+        }
+        Ok(None) => log.step(&format!(
+            "{} on the local daemon ({}), no context selected",
+            app,
+            local_docker_host()
+        )),
+        Err(e) => {
+            log.error(&e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// The version the stable container of the app runs, none when it is not deployed or can't be read.
+async fn live_version(log: &Logger, app: &str, server_config: &ServerConfig) -> Option<String> {
+    let config = load_ruku_config(app, server_config).ok()?;
+    let docker = load_docker().ok()?;
+    let summary = Container::new(log, app, &docker, &config).get().await.ok()??;
+    deployed_version(&summary)
+}
+
+/// Put how the stopped containers went down into the audit record, exiting with `strict` when one of
+/// them did not stop cleanly.
+fn record_stops(log: &Logger, audit: &PendingAudit, container: &Container<'_>, strict: bool) {
+    let reports = container.stop_reports();
+    for report in &reports {
+        audit.detail(&report.describe());
+    }
+    let unclean: Vec<&str> = reports
+        .iter()
+        .filter(|report| !report.is_clean())
+        .map(|report| report.container.as_str())
+        .collect();
+    if strict && !unclean.is_empty() {
+        log.error(&format!("{} did not stop cleanly", unclean.join(", ")));
+        std::process::exit(1);
+    }
+}
+
+/// Remove the containers and volumes of a preview, then its checkout and state.
+async fn destroy_preview(
+    log: &Logger,
+    server_config: &ServerConfig,
+    docker: &bollard::Docker,
+    previews: &Previews<'_>,
+    preview: &Preview,
+) {
+    match load_ruku_config(&preview.name, server_config) {
+        Ok(config) => {
+            let container = Container::new(log, &preview.name, docker, &config);
+            if !container.list_app().await.or_exit(log).is_empty() {
+                container.end_all(config.concurrency, false).await.or_exit(log);
+            }
+        }
+        Err(e) => log.warn(&format!("Skipping the containers of {}: {}", preview.name, e)),
+    }
+    Volumes::new(log, &preview.name, docker).remove_all().await.or_exit(log);
+    previews.remove(preview).or_exit(log);
+    log.step(&format!("Destroyed {}", preview.name));
+}
+
+/// The app name from the command line or git, exiting with a helpful message when Docker would reject it.
+fn get_app_name(log: &Logger, app: &str) -> String {
+    let app = sanitize_app_name(app);
+    validate_app_name(&app).or_exit(log);
+    app
+}
+
+fn get_ruku_config(log: &Logger, repo: &str, server_config: &ServerConfig) -> RukuConfig {
+    load_valid_ruku_config(repo, server_config).or_exit(log)
+}
+
+/// The file the config of an app is read from: the cached remote config once `run --config-url` fetched
+/// one, ruku.yml otherwise.
+fn config_file(log: &Logger, app: &str, server_config: &ServerConfig) -> PathBuf {
+    let state_dir = server_config.state_root.join(app);
+    match RemoteSource::read(log, &state_dir).or_exit(log) {
+        Some(remote) if !remote.included => remote_config::cache_path(&state_dir),
+        _ => server_config.apps_root.join(app).join("ruku.yml"),
+    }
+}
+
+/// Parse ruku.yml without validating it, for commands that act on an already deployed app.
+fn read_ruku_config(log: &Logger, repo: &str, server_config: &ServerConfig) -> RukuConfig {
+    load_ruku_config(repo, server_config).or_exit(log)
+}
+
+/// What the command exits with when the library fails with `error`, read-only refusals have a code of
+/// their own.
+fn exit_code(error: &RukuError) -> i32 {
+    match error {
+        RukuError::ReadOnly(_) => read_only::READ_ONLY_EXIT_CODE,
+        RukuError::Config(_) | RukuError::Failed(_) => 1,
+    }
+}
+
+/// Ends the command with `error`, which the library returns rather than logs.
+fn exit_with(log: &Logger, error: RukuError) -> ! {
+    log.error(&error.to_string());
+    std::process::exit(exit_code(&error));
+}
+
+/// Ends the command with what went wrong, which the library returns rather than logs.
+trait OrExit<T> {
+    fn or_exit(self, log: &Logger) -> T;
+}
+
+impl<T, E: fmt::Display> OrExit<T> for Result<T, E> {
+    fn or_exit(self, log: &Logger) -> T {
+        self.unwrap_or_else(|e| {
+            log.error(&e.to_string());
+            std::process::exit(1);
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    /// Every command that changes state, which read-only mode refuses.
+    const MUTATING: &[&str] = &[
+        "run shop",
+        "drift --fix shop",
+        "rollback shop",
+        "undo shop",
+        "config:set PORT=8080",
+        "import-compose",
+        "deploys:worker 1",
+        "restart shop",
+        "push shop",
+        "link shop db",
+        "unlink shop db",
+        "repair shop",
+        "deploy",
+        "stop shop",
+        "destroy shop",
+        "stage shop",
+        "promote shop",
+        "abort shop",
+        "export shop",
+        "import shop.tar.gz",
+        "maintenance:on shop",
+        "maintenance:off shop",
+        "preview --branch feature shop",
+        "preview:destroy shop feature",
+        "preview:reap",
+        "image:load shop shop.bundle",
+        "build-cache:prune",
+        "proxy:up",
+        "proxy:down",
+        "git-hook shop",
+        "git-receive-pack shop",
+    ];
+
+    /// The commands and flags that only look.
+    const LOOKING: &[&str] = &[
+        "run --dry-run shop",
+        "drift shop",
+        "undo --list shop",
+        "undo --dry-run shop",
+        "repair --dry-run shop",
+        "stop --dry-run shop",
+        "destroy --dry-run shop",
+        "logs shop",
+        "config:get PORT",
+        "config:explain shop",
+        "env:export shop",
+        "init",
+        "deploys:status 1",
+        "deploys:logs 1",
+        "status shop",
+        "open shop",
+        "top shop",
+        "list",
+        "dashboard",
+        "server",
+        "doctor",
+        "version",
+        "metrics",
+        "volumes shop",
+        "releases shop",
+        "releases:show shop 1",
+        "releases:diff shop 1 2",
+        "releases:sbom shop 1",
+        "preview:list",
+        "audit",
+        "debug-bundle shop",
+        "diff shop",
+        "image:history shop",
+        "image:save shop",
+        "proxy:status",
+        "git-upload-pack shop",
+    ];
+
+    fn parse(command: &str) -> Command {
+        Cli::try_parse_from(std::iter::once("ruku").chain(command.split_whitespace()))
+            .unwrap_or_else(|e| panic!("{}: {}", command, e))
+            .command
+    }
+
+    #[test]
+    fn read_only_mode_refuses_every_mutating_command() {
+        for command in MUTATING {
+            assert!(parse(command).mutates(), "{} changes state", command);
+        }
+        for command in LOOKING {
+            assert!(!parse(command).mutates(), "{} only looks", command);
+        }
+
+        let listed: BTreeSet<&str> = MUTATING
+            .iter()
+            .chain(LOOKING)
+            .map(|command| command.split_whitespace().next().unwrap())
+            .collect();
+        let cli = Cli::command();
+        let commands: BTreeSet<&str> = cli.get_subcommands().map(|command| command.get_name()).collect();
+        assert_eq!(listed, commands, "a new command has to be listed on one side");
+    }
+
+    #[test]
+    fn a_read_only_refusal_has_its_own_exit_code() {
+        let refusal = RukuError::ReadOnly("deploy shop".to_string());
+        assert_eq!(exit_code(&refusal), read_only::READ_ONLY_EXIT_CODE);
+        assert!(refusal
+            .to_string()
+            .starts_with("Refusing to deploy shop, ruku is read-only here"));
+        assert_eq!(exit_code(&RukuError::Failed("build failed".to_string())), 1);
+    }
+}
@@ -0,0 +1,823 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use cmd_lib::run_cmd;
+use serde_yaml::{Mapping, Value};
+use validator::Validate;
+
+use crate::container::RESERVED_LABEL_PREFIX;
+use crate::links::Links;
+use crate::logger::Logger;
+use crate::misc::validate_app_name;
+use crate::model::{PortConfig, RukuConfig};
+use crate::server_config::ServerConfig;
+use crate::volume::{is_host_path, resolve_host_path, VolumeSpec};
+
+/// Compose files looked for when `ruku import-compose` is given no path, in the order Compose tries them.
+pub const DEFAULT_FILES: [&str; 4] = [
+    "compose.yaml",
+    "compose.yml",
+    "docker-compose.yaml",
+    "docker-compose.yml",
+];
+
+/// Service keys that are converted, or reported on when they can't be, every other key is listed as ignored.
+const HANDLED_KEYS: [&str; 14] = [
+    "image",
+    "build",
+    "ports",
+    "expose",
+    "environment",
+    "env_file",
+    "volumes",
+    "restart",
+    "healthcheck",
+    "depends_on",
+    "networks",
+    "network_mode",
+    "labels",
+    "container_name",
+];
+
+/// A compose service turned into a ruku app.
+pub struct ComposeApp {
+    pub service: String,
+    /// The app name, the service name lowercased with everything but letters and digits turned into dashes.
+    pub name: String,
+    /// The build context of the service, where its ruku.yml is written.
+    pub dir: PathBuf,
+    /// The ruku.yml, validated.
+    pub config: String,
+    /// Apps of the same import this one depends on.
+    pub depends_on: Vec<String>,
+    /// What of the service has no equivalent in ruku.yml and was left out.
+    pub ignored: Vec<String>,
+}
+
+/// The result of converting a compose file.
+pub struct ComposeImport {
+    pub apps: Vec<ComposeApp>,
+    /// Services that could not be converted, with the reason.
+    pub skipped: Vec<(String, String)>,
+    /// Top-level sections that were left out.
+    pub ignored: Vec<String>,
+}
+
+impl ComposeImport {
+    /// The apps with every app after the ones it depends on.
+    pub fn deploy_order(&self) -> Result<Vec<&ComposeApp>, String> {
+        let mut ordered: Vec<&ComposeApp> = vec![];
+        while ordered.len() < self.apps.len() {
+            let ready: Vec<&ComposeApp> = self
+                .apps
+                .iter()
+                .filter(|app| !ordered.iter().any(|done| done.name == app.name))
+                .filter(|app| {
+                    app.depends_on
+                        .iter()
+                        .all(|dependency| ordered.iter().any(|done| done.name == *dependency))
+                })
+                .collect();
+            if ready.is_empty() {
+                return Err("The services depend on each other in a cycle".to_string());
+            }
+            ordered.extend(ready);
+        }
+        Ok(ordered)
+    }
+}
+
+/// Convert the services of the compose file at `path` to ruku apps. Relative paths in the file are
+/// relative to its directory, as Compose reads them.
+pub fn convert(path: &Path) -> Result<ComposeImport, String> {
+    let content = fs::read_to_string(path).map_err(|e| format!("Error reading {}: {}", path.display(), e))?;
+    let compose: Value =
+        serde_yaml::from_str(&content).map_err(|e| format!("Error parsing {}: {}", path.display(), e))?;
+    let base = path
+        .canonicalize()
+        .ok()
+        .and_then(|path| path.parent().map(Path::to_path_buf))
+        .unwrap_or_default();
+    let services = compose
+        .get("services")
+        .and_then(Value::as_mapping)
+        .ok_or_else(|| format!("{} has no services", path.display()))?;
+
+    let names: BTreeMap<String, String> = services
+        .keys()
+        .filter_map(Value::as_str)
+        .map(|service| (service.to_string(), app_name(service)))
+        .collect();
+    let mut import = ComposeImport {
+        apps: vec![],
+        skipped: vec![],
+        ignored: ["networks", "configs", "secrets"]
+            .into_iter()
+            .filter(|section| compose.get(section).is_some())
+            .map(|section| format!("top-level {}", section))
+            .collect(),
+    };
+    for (service, definition) in services {
+        let service = service.as_str().unwrap_or_default().to_string();
+        match convert_service(&service, definition, &base, &names, services) {
+            Ok(app) => match import.apps.iter().find(|other| other.dir == app.dir) {
+                Some(other) => import.skipped.push((
+                    service,
+                    format!(
+                        "it shares the build context {} with {}, ruku keeps one ruku.yml per directory",
+                        app.dir.display(),
+                        other.service
+                    ),
+                )),
+                None => import.apps.push(app),
+            },
+            Err(reason) => import.skipped.push((service, reason)),
+        }
+    }
+    // A dependency that was skipped can't be part of the deploy order
+    let converted: Vec<String> = import.apps.iter().map(|app| app.name.clone()).collect();
+    for app in import.apps.iter_mut() {
+        app.depends_on.retain(|dependency| converted.contains(dependency));
+    }
+    Ok(import)
+}
+
+/// The app name of a service, `Web_API` becomes `web-api`.
+pub fn app_name(service: &str) -> String {
+    let mut name = String::new();
+    for c in service.chars().map(|c| c.to_ascii_lowercase()) {
+        if c.is_ascii_lowercase() || c.is_ascii_digit() {
+            name.push(c);
+        } else if !name.is_empty() && !name.ends_with('-') {
+            name.push('-');
+        }
+    }
+    name.trim_end_matches('-').to_string()
+}
+
+fn convert_service(
+    service: &str,
+    definition: &Value,
+    base: &Path,
+    names: &BTreeMap<String, String>,
+    services: &Mapping,
+) -> Result<ComposeApp, String> {
+    let name = names.get(service).cloned().unwrap_or_default();
+    validate_app_name(&name)?;
+    let mut ignored: Vec<String> = definition
+        .as_mapping()
+        .into_iter()
+        .flat_map(|mapping| mapping.keys())
+        .filter_map(Value::as_str)
+        .filter(|key| !HANDLED_KEYS.contains(key) && !key.starts_with("x-"))
+        .map(|key| format!("{} is not supported", key))
+        .collect();
+    let mut config = Mapping::new();
+
+    let dir = match (definition.get("build"), definition.get("image")) {
+        (Some(build), image) => {
+            if let Some(image) = image.and_then(Value::as_str) {
+                ignored.push(format!("image {} is ignored, ruku builds the image itself", image));
+            }
+            convert_build(build, base, &mut ignored)?
+        }
+        (None, Some(_)) => {
+            return Err("it only runs a prebuilt image, ruku builds apps from source".to_string());
+        }
+        (None, None) => return Err("it has neither build nor image".to_string()),
+    };
+
+    let port = convert_ports(definition, &mut ignored)?;
+    config.insert("port".into(), port.to_string().into());
+    config.insert("version".into(), "0.1.0".into());
+    config.insert(
+        "build".into(),
+        Value::Mapping(Mapping::from_iter([("builder".into(), "dockerfile".into())])),
+    );
+
+    let depends_on = convert_depends_on(definition, names, &mut ignored);
+    if !depends_on.is_empty() {
+        config.insert("depends_on".into(), depends_on.clone().into());
+    }
+
+    let volumes = convert_volumes(definition, base, services, &mut ignored);
+    if !volumes.is_empty() {
+        config.insert("volumes".into(), volumes.into());
+    }
+
+    let labels = convert_labels(definition, &mut ignored);
+    if !labels.is_empty() {
+        config.insert("labels".into(), Value::Mapping(labels));
+    }
+
+    match definition.get("network_mode").and_then(Value::as_str) {
+        Some(mode @ ("host" | "none")) => {
+            config.insert("network_mode".into(), mode.into());
+        }
+        Some("bridge") | None => {}
+        Some(mode) => ignored.push(format!("network_mode {} is not supported", mode)),
+    }
+    if let Some(networks) = definition.get("networks") {
+        let names: Vec<&str> = match networks {
+            Value::Sequence(networks) => networks.iter().filter_map(Value::as_str).collect(),
+            Value::Mapping(networks) => networks.keys().filter_map(Value::as_str).collect(),
+            _ => vec![],
+        };
+        if names.iter().any(|network| *network != "default") {
+            ignored.push(format!(
+                "networks {} are ignored, every ruku app gets a network of its own and `ruku link` connects them",
+                names.join(", ")
+            ));
+        }
+    }
+
+    if let Some(probe) = convert_healthcheck(definition, &mut ignored) {
+        config.insert("probe".into(), Value::Mapping(probe));
+    }
+
+    if let Some(environment) = definition.get("environment") {
+        let keys = match environment {
+            Value::Sequence(entries) => entries
+                .iter()
+                .filter_map(Value::as_str)
+                .map(|entry| entry.split('=').next().unwrap_or_default().to_string())
+                .collect(),
+            Value::Mapping(entries) => entries.keys().filter_map(Value::as_str).map(str::to_string).collect(),
+            _ => vec![],
+        };
+        ignored.push(format!(
+            "environment is not supported by ruku.yml, {} are left out",
+            keys.join(", ")
+        ));
+    }
+    if definition.get("env_file").is_some() {
+        ignored.push("env_file is not supported by ruku.yml".to_string());
+    }
+    if let Some(restart) = definition.get("restart").and_then(Value::as_str) {
+        ignored.push(format!("restart {} is ignored, ruku sets no restart policy", restart));
+    }
+    if definition.get("container_name").is_some() {
+        ignored.push("container_name is ignored, ruku names the container after the app".to_string());
+    }
+
+    let config = format!(
+        "# Imported from the compose service {}\n{}",
+        service,
+        serde_yaml::to_string(&config).map_err(|e| e.to_string())?
+    );
+    let parsed: RukuConfig =
+        serde_yaml::from_str(&config).map_err(|e| format!("the converted config is invalid: {}", e))?;
+    parsed
+        .validate()
+        .map_err(|e| format!("the converted config is invalid: {}", e))?;
+
+    Ok(ComposeApp {
+        service: service.to_string(),
+        name,
+        dir,
+        config,
+        depends_on,
+        ignored,
+    })
+}
+
+/// The build context directory, short form `build: ./dir` or long form with `context`.
+fn convert_build(build: &Value, base: &Path, ignored: &mut Vec<String>) -> Result<PathBuf, String> {
+    let context = match build {
+        Value::String(context) => context.as_str(),
+        Value::Mapping(build) => {
+            if let Some(dockerfile) = build.get("dockerfile").and_then(Value::as_str) {
+                if dockerfile != "Dockerfile" {
+                    return Err(format!(
+                        "it builds from {}, ruku builds from the Dockerfile at the root of the context",
+                        dockerfile
+                    ));
+                }
+            }
+            for key in build.keys().filter_map(Value::as_str) {
+                if !["context", "dockerfile"].contains(&key) {
+                    ignored.push(format!("build.{} is not supported", key));
+                }
+            }
+            build.get("context").and_then(Value::as_str).unwrap_or(".")
+        }
+        _ => return Err("build must be a path or a mapping".to_string()),
+    };
+    if context.contains("://") || context.starts_with("git@") {
+        return Err(format!(
+            "its build context {} is remote, ruku builds from a local directory",
+            context
+        ));
+    }
+    let dir = resolve_host_path(context, base);
+    if !dir.join("Dockerfile").exists() {
+        return Err(format!("its build context {} has no Dockerfile", dir.display()));
+    }
+    Ok(dir)
+}
+
+/// The first published port, ruku apps publish one. A port without a host side, or on a host port below
+/// 1024, gets an automatically assigned host port.
+fn convert_ports(definition: &Value, ignored: &mut Vec<String>) -> Result<PortConfig, String> {
+    let ports: Vec<&Value> = definition
+        .get("ports")
+        .and_then(Value::as_sequence)
+        .into_iter()
+        .flatten()
+        .collect();
+    let first = match ports.split_first() {
+        Some((first, rest)) => {
+            if !rest.is_empty() {
+                let rest: Vec<String> = rest.iter().filter_map(|port| convert_port(port).ok()).collect();
+                ignored.push(format!(
+                    "ports {} are ignored, a ruku app publishes one port",
+                    rest.join(", ")
+                ));
+            }
+            convert_port(first)?
+        }
+        None => {
+            let exposed = definition
+                .get("expose")
+                .and_then(Value::as_sequence)
+                .and_then(|expose| expose.first())
+                .ok_or("it publishes no port, every ruku app listens on one")?;
+            let port = scalar(exposed).ok_or("expose must list ports")?;
+            ignored.push(format!(
+                "port {} is only exposed in compose, ruku publishes it on an automatically assigned host port",
+                port
+            ));
+            format!("auto:{}", port)
+        }
+    };
+    let mut port = parse_port(first)?;
+    if !port.auto && port.host_port < 1024 {
+        ignored.push(format!(
+            "host port {} is below 1024, ruku assigns a host port automatically instead",
+            port.host_port
+        ));
+        port.auto = true;
+        port.host_port = 0;
+    }
+    Ok(port)
+}
+
+/// One compose port in ruku's syntax, from the short form `[IP:][HOST:]CONTAINER[/PROTOCOL]` or the long
+/// form with `target`, `published`, `host_ip` and `protocol`.
+fn convert_port(port: &Value) -> Result<String, String> {
+    let (host_ip, published, target, protocol) = match port {
+        Value::Mapping(port) => (
+            port.get("host_ip").and_then(Value::as_str).map(str::to_string),
+            port.get("published").and_then(scalar),
+            port.get("target").and_then(scalar).ok_or("a port has no target")?,
+            port.get("protocol")
+                .and_then(Value::as_str)
+                .unwrap_or("tcp")
+                .to_string(),
+        ),
+        port => {
+            let port = scalar(port).ok_or("ports must be numbers or strings")?;
+            let (address, protocol) = port.split_once('/').unwrap_or((&port, "tcp"));
+            let (host_ip, ports) = match address.strip_prefix('[') {
+                Some(rest) => rest
+                    .split_once("]:")
+                    .map(|(ip, ports)| (Some(ip.to_string()), ports))
+                    .ok_or(format!("port {} is invalid", port))?,
+                None => match address.rsplitn(3, ':').collect::<Vec<_>>().as_slice() {
+                    [_, _, ip] => (Some(ip.to_string()), &address[ip.len() + 1..]),
+                    _ => (None, address),
+                },
+            };
+            let (published, target) = match ports.rsplit_once(':') {
+                Some((published, target)) => (Some(published.to_string()), target.to_string()),
+                None => (None, ports.to_string()),
+            };
+            (host_ip, published, target, protocol.to_string())
+        }
+    };
+    let published = published.filter(|published| !published.is_empty());
+    if target.contains('-') || published.as_deref().is_some_and(|published| published.contains('-')) {
+        return Err(format!("port range {} is not supported", target));
+    }
+    let host = match (host_ip, published) {
+        (Some(ip), published) if ip.contains(':') => format!("[{}]:{}:", ip, published.unwrap_or("auto".into())),
+        (Some(ip), published) => format!("{}:{}:", ip, published.unwrap_or("auto".into())),
+        (None, Some(published)) => format!("{}:", published),
+        // Compose publishes on an ephemeral host port
+        (None, None) => "auto:".to_string(),
+    };
+    Ok(format!("{}{}/{}", host, target, protocol))
+}
+
+fn parse_port(port: String) -> Result<PortConfig, String> {
+    serde_yaml::from_value(Value::String(port)).map_err(|e| e.to_string())
+}
+
+/// The services a service depends on as app names, from a list or a mapping with conditions.
+fn convert_depends_on(definition: &Value, names: &BTreeMap<String, String>, ignored: &mut Vec<String>) -> Vec<String> {
+    let services: Vec<&str> = match definition.get("depends_on") {
+        Some(Value::Sequence(services)) => services.iter().filter_map(Value::as_str).collect(),
+        Some(Value::Mapping(services)) => {
+            for (service, options) in services {
+                let condition = options.get("condition").and_then(Value::as_str);
+                if condition == Some("service_completed_successfully") {
+                    ignored.push(format!(
+                        "depends_on {} waits for the service to be running, not for it to complete",
+                        service.as_str().unwrap_or_default()
+                    ));
+                }
+            }
+            services.keys().filter_map(Value::as_str).collect()
+        }
+        _ => vec![],
+    };
+    services
+        .into_iter()
+        .map(|service| names.get(service).cloned().unwrap_or(app_name(service)))
+        .collect()
+}
+
+/// Named volumes and bind mounts, with bind mounts made absolute so they don't depend on where the
+/// ruku.yml ends up.
+fn convert_volumes(definition: &Value, base: &Path, services: &Mapping, ignored: &mut Vec<String>) -> Vec<String> {
+    let mut volumes = vec![];
+    for volume in definition
+        .get("volumes")
+        .and_then(Value::as_sequence)
+        .into_iter()
+        .flatten()
+    {
+        let (source, target, read_only) = match volume {
+            Value::String(volume) => {
+                let parts: Vec<&str> = volume.split(':').collect();
+                match parts.as_slice() {
+                    [source, target] => (source.to_string(), target.to_string(), false),
+                    [source, target, mode] => (
+                        source.to_string(),
+                        target.to_string(),
+                        mode.split(',').any(|flag| flag == "ro"),
+                    ),
+                    _ => {
+                        ignored.push(format!("volume {} is not supported, give it a source", volume));
+                        continue;
+                    }
+                }
+            }
+            Value::Mapping(volume) => {
+                let kind = volume.get("type").and_then(Value::as_str).unwrap_or("volume");
+                let source = volume.get("source").and_then(Value::as_str);
+                let target = volume.get("target").and_then(Value::as_str);
+                match (kind, source, target) {
+                    ("volume" | "bind", Some(source), Some(target)) => (
+                        source.to_string(),
+                        target.to_string(),
+                        volume.get("read_only").and_then(Value::as_bool).unwrap_or(false),
+                    ),
+                    (kind, _, target) => {
+                        ignored.push(format!(
+                            "{} volume at {} is not supported",
+                            kind,
+                            target.unwrap_or("an unknown path")
+                        ));
+                        continue;
+                    }
+                }
+            }
+            _ => continue,
+        };
+
+        let source = if is_host_path(&source) {
+            resolve_host_path(&source, base).display().to_string()
+        } else {
+            let shared = services
+                .values()
+                .filter(|other| {
+                    other
+                        .get("volumes")
+                        .and_then(Value::as_sequence)
+                        .into_iter()
+                        .flatten()
+                        .any(|volume| volume_source(volume) == Some(source.as_str()))
+                })
+                .count();
+            if shared > 1 {
+                ignored.push(format!(
+                    "volume {} is shared with other services, each ruku app gets a volume of its own",
+                    source
+                ));
+            }
+            source
+        };
+        let volume = format!("{}:{}{}", source, target, if read_only { ":ro" } else { "" });
+        match VolumeSpec::parse(&volume) {
+            Ok(_) => volumes.push(volume),
+            Err(e) => ignored.push(e),
+        }
+    }
+    volumes
+}
+
+fn volume_source(volume: &Value) -> Option<&str> {
+    match volume {
+        Value::String(volume) => volume.split_once(':').map(|(source, _)| source),
+        volume => volume.get("source").and_then(Value::as_str),
+    }
+}
+
+/// Labels as a mapping, from a list of `KEY=VALUE` or a mapping. Labels under `ruku.` are left out.
+fn convert_labels(definition: &Value, ignored: &mut Vec<String>) -> Mapping {
+    let labels: Vec<(String, String)> = match definition.get("labels") {
+        Some(Value::Sequence(labels)) => labels
+            .iter()
+            .filter_map(Value::as_str)
+            .map(|label| {
+                let (key, value) = label.split_once('=').unwrap_or((label, ""));
+                (key.to_string(), value.to_string())
+            })
+            .collect(),
+        Some(Value::Mapping(labels)) => labels
+            .iter()
+            .filter_map(|(key, value)| Some((key.as_str()?.to_string(), scalar(value).unwrap_or_default())))
+            .collect(),
+        _ => vec![],
+    };
+    let mut mapping = Mapping::new();
+    for (key, value) in labels {
+        if key.starts_with(RESERVED_LABEL_PREFIX) {
+            ignored.push(format!("label {} is reserved for ruku", key));
+        } else {
+            mapping.insert(key.into(), value.into());
+        }
+    }
+    mapping
+}
+
+/// A probe from a healthcheck that requests an HTTP URL on localhost, other healthchecks can't be expressed
+/// as a ruku probe.
+fn convert_healthcheck(definition: &Value, ignored: &mut Vec<String>) -> Option<Mapping> {
+    let healthcheck = definition.get("healthcheck")?;
+    if healthcheck.get("disable").and_then(Value::as_bool) == Some(true) {
+        return None;
+    }
+    let test = match healthcheck.get("test")? {
+        Value::String(test) => test.clone(),
+        Value::Sequence(test) => test
+            .iter()
+            .skip(1)
+            .filter_map(Value::as_str)
+            .collect::<Vec<_>>()
+            .join(" "),
+        _ => return None,
+    };
+    if test == "NONE" || test.is_empty() {
+        return None;
+    }
+    let path = test
+        .split_whitespace()
+        .map(|word| word.trim_matches(|c| c == '"' || c == '\''))
+        .find_map(|word| word.strip_prefix("http://").or(word.strip_prefix("https://")))
+        .filter(|url| url.starts_with("localhost") || url.starts_with("127.0.0.1"))
+        .map(|url| url.find('/').map_or("/", |slash| &url[slash..]).to_string());
+    let Some(path) = path else {
+        ignored.push(format!(
+            "healthcheck {} has no ruku equivalent, ruku probes the port instead",
+            test
+        ));
+        return None;
+    };
+
+    let mut probe = Mapping::from_iter([("path".into(), path.into())]);
+    if let Some(timeout) = healthcheck
+        .get("timeout")
+        .and_then(Value::as_str)
+        .and_then(parse_seconds)
+    {
+        probe.insert("timeout".into(), timeout.clamp(1, 60).into());
+    }
+    for key in ["interval", "retries", "start_period", "start_interval"] {
+        if healthcheck.get(key).is_some() {
+            ignored.push(format!("healthcheck.{} is not supported", key));
+        }
+    }
+    Some(probe)
+}
+
+/// Whole seconds of a compose duration such as `30s`, `1m30s` or `1h`, rounded up.
+fn parse_seconds(duration: &str) -> Option<u64> {
+    let mut millis = 0;
+    let mut number = String::new();
+    let mut chars = duration.trim().chars().peekable();
+    while let Some(c) = chars.next() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        let value: u64 = number.parse().ok()?;
+        number.clear();
+        let unit = match c {
+            'h' => 3_600_000,
+            'm' if chars.peek() == Some(&'s') => {
+                chars.next();
+                1
+            }
+            'm' => 60_000,
+            's' => 1000,
+            _ => return None,
+        };
+        millis += value * unit;
+    }
+    if !number.is_empty() {
+        return None;
+    }
+    Some(millis.div_ceil(1000))
+}
+
+/// A string, number or bool as text, compose accepts any of them for ports and label values.
+fn scalar(value: &Value) -> Option<String> {
+    match value {
+        Value::String(value) => Some(value.clone()),
+        Value::Number(value) => Some(value.to_string()),
+        Value::Bool(value) => Some(value.to_string()),
+        _ => None,
+    }
+}
+
+/// Write the ruku.yml of a converted app into its build context.
+pub fn write(app: &ComposeApp) -> Result<PathBuf, String> {
+    let path = app.dir.join("ruku.yml");
+    fs::write(&path, &app.config).map_err(|e| format!("Error writing {}: {}", path.display(), e))?;
+    Ok(path)
+}
+
+/// Copy the build context of an app into its app directory and link it to its dependencies, so it can
+/// be deployed like a pushed app. An app directory that is a git checkout is left alone.
+pub fn install(log: &Logger, server_config: &ServerConfig, app: &ComposeApp) -> Result<(), String> {
+    let app_path = server_config.apps_root.join(&app.name);
+    if app_path.join(".git").exists() {
+        return Err(format!(
+            "{} is deployed from its git repository, push the ruku.yml there instead",
+            app.name
+        ));
+    }
+    if app_path.exists() {
+        fs::remove_dir_all(&app_path).map_err(|e| format!("Error removing {}: {}", app_path.display(), e))?;
+    }
+    let source = format!("{}/.", app.dir.display());
+    let target = app_path.display().to_string();
+    run_cmd!(mkdir -p $target; cp -a $source $target)
+        .map_err(|e| format!("Error copying {} into {}: {}", app.dir.display(), target, e))?;
+
+    let links = Links::new(&server_config.state_root.join(&app.name));
+    for dependency in &app.depends_on {
+        if links.add(dependency)? {
+            log.step(&format!("Linked {} to {}", app.name, dependency));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A compose file next to a `web` and a `worker` build context.
+    fn project(compose: &str) -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        for context in ["web", "worker"] {
+            fs::create_dir(dir.path().join(context)).unwrap();
+            fs::write(dir.path().join(context).join("Dockerfile"), "FROM scratch\n").unwrap();
+        }
+        fs::write(dir.path().join("compose.yaml"), compose).unwrap();
+        dir
+    }
+
+    #[test]
+    fn service_names_become_app_names() {
+        assert_eq!(app_name("Web_API"), "web-api");
+        assert_eq!(app_name("__db__"), "db");
+        assert_eq!(app_name("a..b"), "a-b");
+    }
+
+    #[test]
+    fn ports_are_converted_to_ruku_syntax() {
+        let port = |yaml: &str| convert_port(&serde_yaml::from_str(yaml).unwrap());
+        assert_eq!(port("8080:80").unwrap(), "8080:80/tcp");
+        assert_eq!(port("80").unwrap(), "auto:80/tcp");
+        assert_eq!(port("127.0.0.1:8080:80/udp").unwrap(), "127.0.0.1:8080:80/udp");
+        assert_eq!(port("127.0.0.1::80").unwrap(), "127.0.0.1:auto:80/tcp");
+        assert_eq!(port("'[::1]:8080:80'").unwrap(), "[::1]:8080:80/tcp");
+        assert_eq!(port("{target: 80, published: 8080}").unwrap(), "8080:80/tcp");
+        assert_eq!(port("3000").unwrap(), "auto:3000/tcp");
+        assert!(port("8000-8010:80").is_err());
+        assert!(port("{published: 8080}").is_err());
+    }
+
+    #[test]
+    fn durations_round_up_to_seconds() {
+        assert_eq!(parse_seconds("30s"), Some(30));
+        assert_eq!(parse_seconds("1m30s"), Some(90));
+        assert_eq!(parse_seconds("1h"), Some(3600));
+        assert_eq!(parse_seconds("500ms"), Some(1));
+        assert_eq!(parse_seconds("30"), None);
+        assert_eq!(parse_seconds("1d"), None);
+    }
+
+    #[test]
+    fn services_are_converted_with_what_was_left_out() {
+        let dir = project(
+            "
+services:
+  web:
+    build: ./web
+    image: example/web
+    ports: ['80:8000', '9000:9000']
+    depends_on:
+      worker:
+        condition: service_healthy
+    volumes: ['./media:/app/media:ro', 'data:/data']
+    labels: ['team=web', 'ruku.app=other']
+    environment: [SECRET_KEY=x, DEBUG=1]
+    healthcheck:
+      test: ['CMD', 'curl', '-f', 'http://localhost:8000/health']
+      timeout: 5s
+      retries: 3
+    deploy:
+      replicas: 2
+  worker:
+    build:
+      context: ./worker
+    expose: [5000]
+  db:
+    image: postgres:16
+networks:
+  backend: {}
+",
+        );
+        let import = convert(&dir.path().join("compose.yaml")).unwrap();
+        assert_eq!(import.ignored, ["top-level networks"]);
+        assert_eq!(
+            import.skipped,
+            [(
+                "db".to_string(),
+                "it only runs a prebuilt image, ruku builds apps from source".to_string()
+            )]
+        );
+
+        let web = import.apps.iter().find(|app| app.name == "web").unwrap();
+        let config: RukuConfig = serde_yaml::from_str(&web.config).unwrap();
+        assert!(web.config.starts_with("# Imported from the compose service web\n"));
+        assert!(config.port.auto);
+        assert_eq!(config.port.number, 8000);
+        assert_eq!(web.depends_on, ["worker"]);
+        let media = dir.path().canonicalize().unwrap().join("media");
+        assert_eq!(
+            config.volumes,
+            [format!("{}:/app/media:ro", media.display()), "data:/data".to_string()]
+        );
+        assert_eq!(config.labels.get("team").map(String::as_str), Some("web"));
+        assert!(!config.labels.contains_key("ruku.app"));
+        assert_eq!(
+            web.ignored,
+            [
+                "deploy is not supported",
+                "image example/web is ignored, ruku builds the image itself",
+                "ports 9000:9000/tcp are ignored, a ruku app publishes one port",
+                "host port 80 is below 1024, ruku assigns a host port automatically instead",
+                "label ruku.app is reserved for ruku",
+                "healthcheck.retries is not supported",
+                "environment is not supported by ruku.yml, SECRET_KEY, DEBUG are left out",
+            ]
+        );
+
+        let worker = import.apps.iter().find(|app| app.name == "worker").unwrap();
+        assert!(worker.config.contains("port: auto:5000/tcp"));
+        let order: Vec<&str> = import
+            .deploy_order()
+            .unwrap()
+            .into_iter()
+            .map(|app| app.name.as_str())
+            .collect();
+        assert_eq!(order, ["worker", "web"]);
+    }
+
+    #[test]
+    fn dependency_cycles_have_no_deploy_order() {
+        let dir = project(
+            "
+services:
+  web:
+    build: ./web
+    ports: ['8080:80']
+    depends_on: [worker]
+  worker:
+    build: ./worker
+    ports: ['8081:80']
+    depends_on: [web]
+",
+        );
+        let import = convert(&dir.path().join("compose.yaml")).unwrap();
+        assert_eq!(import.apps.len(), 2);
+        assert!(import.deploy_order().is_err());
+    }
+}
@@ -0,0 +1,467 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde_yaml::Value;
+use validator::Validate;
+
+use crate::links::Links;
+use crate::logger::Logger;
+use crate::model::RukuConfig;
+use crate::ports::AssignedPort;
+use crate::preview::Preview;
+use crate::remote_config::{self, include_url, RemoteSource};
+use crate::rootless::LowPortRedirect;
+use crate::server_config::ServerConfig;
+use crate::volume::{is_host_path, resolve_host_path, split_source};
+
+pub use crate::dependency::Dependency;
+pub use crate::links::Link;
+pub use crate::provenance::{Field, Provenance, Source};
+
+/// Fragments a chain of `extends` may go through, the one of ruku.yml and the one that fragment extends.
+/// That covers defaults shared by a team on top of ones shared by every app on the host. A longer chain
+/// spreads one config over more files than `config:explain` keeps readable, and every deploy and command
+/// reads them all.
+const MAX_FRAGMENTS: usize = 2;
+
+/// Parse and validate the ruku.yml of an app.
+pub fn load_valid_ruku_config(repo: &str, server_config: &ServerConfig) -> Result<RukuConfig, String> {
+    let config = load_ruku_config(repo, server_config)?;
+    config
+        .validate()
+        .map_err(|e| format!("Error validating ruku.yml file: {}", e))?;
+    Ok(config)
+}
+
+/// Parse ruku.yml without validating it, for commands that act on an already deployed app.
+pub fn load_ruku_config(repo: &str, server_config: &ServerConfig) -> Result<RukuConfig, String> {
+    load_ruku_config_with_provenance(repo, server_config).map(|(config, _)| config)
+}
+
+/// Parse ruku.yml and keep track of where each value was set.
+pub fn load_ruku_config_with_provenance(
+    repo: &str,
+    server_config: &ServerConfig,
+) -> Result<(RukuConfig, Provenance), String> {
+    let repo_path = server_config.apps_root.join(repo);
+    let state_dir = server_config.state_root.join(repo);
+    let log = Logger::new();
+    let mut provenance = Provenance::new();
+
+    let config_path = repo_path.join("ruku.yml");
+    let remote = RemoteSource::read(&log, &state_dir)?;
+    let remote_path = remote_config::cache_path(&state_dir);
+    let config_content = match remote.as_ref().filter(|remote| !remote.included) {
+        // Fetched with --config-url, which stands in for ruku.yml
+        Some(remote) => {
+            let content = fs::read_to_string(&remote_path)
+                .map_err(|e| format!("Error reading the cached config of {}: {}", remote.url, e))?;
+            if extends(&content)?.is_some() {
+                return Err(format!(
+                    "The config of {} sets extends, only a local ruku.yml can extend fragment files",
+                    remote.url
+                ));
+            }
+            provenance.add_file(&remote_path, &content);
+            content
+        }
+        None => {
+            // Check for the presence of ruku.yml file
+            if !config_path.exists() {
+                return Err("ruku.yml file is missing in the repository".to_string());
+            }
+            let local = fs::read_to_string(&config_path).map_err(|e| format!("Error reading ruku.yml file: {}", e))?;
+            let (extended, fragments) = merge_fragments(&config_path, &local)?;
+            // The layers go in the order they are merged: the remote include, the fragments, ruku.yml
+            let content = match include_url(&local)? {
+                Some(url) => {
+                    let included = remote
+                        .filter(|remote| remote.url == url)
+                        .and_then(|_| fs::read_to_string(&remote_path).ok())
+                        .ok_or(format!("{} is not fetched yet, `ruku run` fetches it", url))?;
+                    provenance.add_file(&remote_path, &included);
+                    merge_included(&included, &extended)?
+                }
+                None => extended,
+            };
+            for (fragment, content) in &fragments {
+                provenance.add_file(fragment, content);
+            }
+            provenance.add_file(&config_path, &local);
+            content
+        }
+    };
+
+    // Parse the ruku.yml file
+    let mut config: RukuConfig =
+        serde_yaml::from_str(&config_content).map_err(|e| format!("Error parsing ruku.yml file: {}", e))?;
+    config.resolve_paths(&repo_path);
+    if let Some(preview) = Preview::read(&log, &state_dir)? {
+        let source = Source::Overlay {
+            name: format!("preview of {}", preview.branch),
+            path: state_dir.join(Preview::FILE_NAME),
+        };
+        for key in preview.apply(&mut config) {
+            provenance.set(key, source.clone());
+        }
+    }
+    if config.port.auto {
+        if let Some(assigned) = AssignedPort::read(&log, &state_dir)? {
+            config.port.host_port = assigned.host_port;
+            provenance.set(
+                "port.host_port",
+                Source::Overlay {
+                    name: "assigned port".to_string(),
+                    path: state_dir.join(AssignedPort::FILE_NAME),
+                },
+            );
+        }
+    }
+    if config.low_port_redirect {
+        if let Some(redirect) =
+            LowPortRedirect::read(&log, &state_dir)?.filter(|redirect| redirect.port == config.port.host_port)
+        {
+            config.port.host_port = redirect.published_on;
+            provenance.set(
+                "port.host_port",
+                Source::Overlay {
+                    name: format!("low port redirect from {}", redirect.port),
+                    path: state_dir.join(LowPortRedirect::FILE_NAME),
+                },
+            );
+        }
+    }
+
+    Ok((config, provenance))
+}
+
+/// The ruku.yml with `local` on top of the config it includes, `included`: mappings are merged key by key
+/// and any other value of ruku.yml replaces the included one.
+pub fn merge_included(included: &str, local: &str) -> Result<String, String> {
+    let parse = |content: &str, what: &str| {
+        serde_yaml::from_str::<Value>(content).map_err(|e| format!("Error parsing {}: {}", what, e))
+    };
+    let mut base = parse(included, "the included config")?;
+    if base.get("include").is_some() {
+        return Err("the included config has an include of its own, which is not supported".to_string());
+    }
+    merge(&mut base, parse(local, "ruku.yml file")?);
+    Ok(serde_yaml::to_string(&base).unwrap())
+}
+
+/// The `extends` of the YAML document `content`, none when it extends no fragment.
+fn extends(content: &str) -> Result<Option<String>, String> {
+    let value: Value = serde_yaml::from_str(content).map_err(|e| format!("Error parsing ruku.yml file: {}", e))?;
+    Ok(value.get("extends").and_then(|path| path.as_str()).map(str::to_string))
+}
+
+/// `content`, the ruku.yml at `path`, on top of the fragment files it extends, merged as an included
+/// config is. A fragment may extend one more, relative paths in each resolve from its own directory.
+/// Returns the merged config and the path and content of each fragment, in the order they were merged.
+fn merge_fragments(path: &Path, content: &str) -> Result<(String, Vec<(PathBuf, String)>), String> {
+    let mut chain: Vec<PathBuf> = vec![path.canonicalize().unwrap_or(path.to_path_buf())];
+    let mut fragments: Vec<(PathBuf, String, Value)> = vec![];
+    let mut next = extends(content)?.map(|extended| resolve_host_path(&extended, parent_dir(path)));
+    while let Some(fragment) = next {
+        // Relative paths in it resolve from where it is, not from where ruku.yml reached it through
+        let fragment = fragment.canonicalize().unwrap_or(fragment);
+        if chain.contains(&fragment) {
+            let cycle: Vec<String> = chain
+                .iter()
+                .chain([&fragment])
+                .map(|path| path.display().to_string())
+                .collect();
+            return Err(format!("The config extends itself: {}", cycle.join(" -> ")));
+        }
+        if fragments.len() == MAX_FRAGMENTS {
+            return Err(format!(
+                "{} extends {}, a fragment may extend one more but that one can't extend again",
+                chain.last().unwrap().display(),
+                fragment.display()
+            ));
+        }
+        let content = fs::read_to_string(&fragment)
+            .map_err(|e| format!("Error reading the fragment {}: {}", fragment.display(), e))?;
+        let mut value: Value = serde_yaml::from_str(&content)
+            .map_err(|e| format!("Error parsing the fragment {}: {}", fragment.display(), e))?;
+        if value.get("include").is_some() {
+            return Err(format!(
+                "The fragment {} sets include, only ruku.yml can include a remote config",
+                fragment.display()
+            ));
+        }
+        next = extends(&content)?.map(|extended| resolve_host_path(&extended, parent_dir(&fragment)));
+        if let Value::Mapping(mapping) = &mut value {
+            mapping.remove("extends");
+        }
+        resolve_fragment_paths(&mut value, parent_dir(&fragment));
+        chain.push(fragment.clone());
+        fragments.push((fragment, content, value));
+    }
+    if fragments.is_empty() {
+        return Ok((content.to_string(), vec![]));
+    }
+    let mut base = Value::Mapping(Default::default());
+    let mut merged = vec![];
+    // The deepest first, each one it extends goes underneath
+    for (fragment, content, value) in fragments.into_iter().rev() {
+        merge(&mut base, value);
+        merged.push((fragment, content));
+    }
+    let local = serde_yaml::from_str(content).map_err(|e| format!("Error parsing ruku.yml file: {}", e))?;
+    merge(&mut base, local);
+    Ok((serde_yaml::to_string(&base).unwrap(), merged))
+}
+
+fn parent_dir(path: &Path) -> &Path {
+    path.parent().unwrap_or(Path::new("."))
+}
+
+/// Make the relative host paths of the fragment `value` absolute from `base`, its directory, as
+/// [`RukuConfig::resolve_paths`] does for ruku.yml: bind mounts of the app and its sidecars, templates and
+/// the `source` of files.
+fn resolve_fragment_paths(value: &mut Value, base: &Path) {
+    let resolve_volumes = |volumes: Option<&mut Value>| {
+        let Some(Value::Sequence(volumes)) = volumes else {
+            return;
+        };
+        for volume in volumes.iter_mut() {
+            let Some(spec) = volume.as_str() else {
+                continue;
+            };
+            if let Some((source, rest)) = split_source(spec).filter(|(source, _)| is_host_path(source)) {
+                *volume = Value::String(format!("{}:{}", resolve_host_path(source, base).display(), rest));
+            }
+        }
+    };
+    resolve_volumes(value.get_mut("volumes"));
+    if let Some(Value::Sequence(sidecars)) = value.get_mut("sidecars") {
+        for sidecar in sidecars.iter_mut() {
+            resolve_volumes(sidecar.get_mut("volumes"));
+        }
+    }
+    if let Some(Value::Mapping(templates)) = value.get_mut("templates") {
+        *templates = std::mem::take(templates)
+            .into_iter()
+            .map(|(source, target)| match source.as_str() {
+                Some(path) => (
+                    Value::String(resolve_host_path(path, base).display().to_string()),
+                    target,
+                ),
+                None => (source, target),
+            })
+            .collect();
+    }
+    if let Some(Value::Mapping(files)) = value.get_mut("files") {
+        for file in files.values_mut() {
+            if let Some(source) = file.get_mut("source") {
+                if let Some(path) = source.as_str() {
+                    *source = Value::String(resolve_host_path(path, base).display().to_string());
+                }
+            }
+        }
+    }
+}
+
+fn merge(base: &mut Value, overlay: Value) {
+    match (base, overlay) {
+        (Value::Mapping(base), Value::Mapping(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(&key) {
+                    Some(existing) => merge(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
+/// The links of an app with the port of every linked app. A link stays valid when the other app is gone,
+/// its host still resolves once it is deployed again.
+pub fn get_links(log: &Logger, app: &str, server_config: &ServerConfig) -> Result<Vec<Link>, String> {
+    Ok(Links::new(&server_config.state_root.join(app))
+        .load()?
+        .into_iter()
+        .map(|other| {
+            let port = match load_ruku_config(&other, server_config) {
+                // The port of its image is only known once it is deployed
+                Ok(config) if config.port.is_from_image() => None,
+                Ok(config) => Some(config.port.number),
+                Err(e) => {
+                    log.warn(&format!("Not setting the port of linked app {}: {}", other, e));
+                    None
+                }
+            };
+            Link { app: other, port }
+        })
+        .collect())
+}
+
+/// The dependencies of an app, a name with a ruku.yml is a ruku app and anything else a container name.
+pub fn get_dependencies(config: &RukuConfig, server_config: &ServerConfig) -> Vec<Dependency> {
+    config
+        .depends_on
+        .iter()
+        .map(|name| {
+            let container_name = match load_ruku_config(name, server_config) {
+                Ok(other) => format!("{}{}", other.container_prefix, name),
+                Err(_) => name.clone(),
+            };
+            Dependency {
+                name: name.clone(),
+                container_name,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store;
+    use chrono::Utc;
+
+    const INCLUDE_URL: &str = "https://config.example.com/base.yml";
+
+    /// A home with the app `shop` whose ruku.yml is `config`, plus `files` next to it.
+    fn home(config: &str, files: &[(&str, &str)]) -> (tempfile::TempDir, ServerConfig) {
+        let home = tempfile::tempdir().unwrap();
+        let server_config = ServerConfig::at_home(home.path()).unwrap();
+        let app_path = server_config.apps_root.join("shop");
+        fs::create_dir_all(&app_path).unwrap();
+        fs::write(app_path.join("ruku.yml"), config).unwrap();
+        for (name, content) in files {
+            fs::write(app_path.join(name), content).unwrap();
+        }
+        (home, server_config)
+    }
+
+    /// Cache `content` as the fetched remote config the ruku.yml of `shop` includes.
+    fn fetch_include(server_config: &ServerConfig, content: &str) {
+        let state_dir = server_config.state_root.join("shop");
+        fs::create_dir_all(&state_dir).unwrap();
+        let remote = RemoteSource {
+            url: INCLUDE_URL.to_string(),
+            included: true,
+            etag: None,
+            fetched_at: Utc::now(),
+        };
+        store::save(&state_dir.join(RemoteSource::FILE_NAME), &remote).unwrap();
+        fs::write(remote_config::cache_path(&state_dir), content).unwrap();
+    }
+
+    fn file_source(source: Source) -> (String, usize) {
+        match source {
+            Source::File { path, line } => (path.file_name().unwrap().to_string_lossy().to_string(), line),
+            Source::Default => ("default".to_string(), 0),
+            source => panic!("{} is not a file", source),
+        }
+    }
+
+    #[test]
+    fn provenance_follows_the_merge_order() {
+        let (_home, server_config) = home(
+            &format!(
+                "include: {}\nextends: team.yml\nport: 3000\nversion: \"1.0\"\n",
+                INCLUDE_URL
+            ),
+            &[
+                ("team.yml", "extends: host.yml\ndrain_period: 20\n"),
+                ("host.yml", "drain_period: 10\ndependency_timeout: 30\nconcurrency: 3\n"),
+            ],
+        );
+        fetch_include(
+            &server_config,
+            "drain_period: 5\ndependency_timeout: 15\nconcurrency: 2\nsbom: true\n",
+        );
+
+        let (config, provenance) = load_ruku_config_with_provenance("shop", &server_config).unwrap();
+        // Merged: remote include, then host.yml, team.yml and ruku.yml on top
+        assert_eq!(config.drain_period, 20);
+        assert_eq!(config.dependency_timeout, 30);
+        assert!(config.sbom);
+        assert_eq!(
+            file_source(provenance.source("drain_period")),
+            ("team.yml".to_string(), 2)
+        );
+        assert_eq!(
+            file_source(provenance.source("dependency_timeout")),
+            ("host.yml".to_string(), 2)
+        );
+        assert_eq!(
+            file_source(provenance.source("concurrency")),
+            ("host.yml".to_string(), 3)
+        );
+        assert_eq!(
+            provenance.source("sbom"),
+            Source::File {
+                path: remote_config::cache_path(&server_config.state_root.join("shop")),
+                line: 4
+            }
+        );
+        assert_eq!(file_source(provenance.source("port")), ("ruku.yml".to_string(), 3));
+    }
+
+    #[test]
+    fn overrides_are_attributed_to_their_state_file() {
+        let (_home, server_config) = home("port: 3000\nstrategy: rolling\n", &[]);
+        let state_dir = server_config.state_root.join("shop");
+        fs::create_dir_all(&state_dir).unwrap();
+        let preview = Preview {
+            app: "web".to_string(),
+            branch: "feature-x".to_string(),
+            name: "shop".to_string(),
+            created_at: Utc::now(),
+            ttl_days: None,
+        };
+        store::save(&state_dir.join(Preview::FILE_NAME), &preview).unwrap();
+        let assigned = AssignedPort {
+            host_port: 8123,
+            assigned_at: Utc::now(),
+        };
+        store::save(&state_dir.join(AssignedPort::FILE_NAME), &assigned).unwrap();
+
+        let (config, provenance) = load_ruku_config_with_provenance("shop", &server_config).unwrap();
+        assert_eq!(config.port.host_port, 8123);
+        assert_eq!(
+            provenance.source("port.host_port").to_string(),
+            format!("assigned port ({})", state_dir.join("port.json").display())
+        );
+        assert_eq!(
+            provenance.source("strategy").to_string(),
+            format!("preview of feature-x ({})", state_dir.join("preview.json").display())
+        );
+        assert_eq!(
+            file_source(provenance.source("port.number")),
+            ("ruku.yml".to_string(), 1)
+        );
+    }
+
+    #[test]
+    fn fragments_extend_at_most_twice() {
+        let (_home, server_config) = home(
+            "extends: a.yml\nport: 3000\n",
+            &[("a.yml", "extends: b.yml\n"), ("b.yml", "drain_period: 7\n")],
+        );
+        let config = load_ruku_config("shop", &server_config).unwrap();
+        assert_eq!(config.drain_period, 7);
+
+        let (_home, server_config) = home(
+            "extends: a.yml\nport: 3000\n",
+            &[
+                ("a.yml", "extends: b.yml\n"),
+                ("b.yml", "extends: c.yml\n"),
+                ("c.yml", "drain_period: 7\n"),
+            ],
+        );
+        let error = load_ruku_config("shop", &server_config).err().unwrap();
+        assert!(
+            error.contains("b.yml extends ") && error.contains("c.yml, a fragment may extend one more"),
+            "{}",
+            error
+        );
+    }
+}
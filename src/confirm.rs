@@ -0,0 +1,181 @@
+use std::env;
+use std::io::{self, BufRead, IsTerminal, Write};
+
+use crate::logger::Logger;
+
+/// Environment variable that answers yes to every confirmation, for automation.
+pub const ASSUME_YES_ENV: &str = "RUKU_ASSUME_YES";
+
+/// What the operator has to type to go ahead.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Answer<'a> {
+    /// `y` or `yes`.
+    Yes,
+    /// The exact name, for operations that destroy data.
+    Name(&'a str),
+}
+
+/// The outcome of asking, split from the prompt itself so every TTY and flag combination is decided in
+/// one place.
+#[derive(Debug, PartialEq)]
+pub enum Decision {
+    Proceed,
+    /// stdin is not a terminal and nothing said yes up front.
+    NeedsYes,
+    Declined,
+}
+
+/// Decide whether to go ahead. `input` is only read when a prompt is shown.
+pub fn decide(assume_yes: bool, is_tty: bool, expected: Answer, input: impl FnOnce() -> Option<String>) -> Decision {
+    if assume_yes {
+        return Decision::Proceed;
+    }
+    if !is_tty {
+        return Decision::NeedsYes;
+    }
+    let Some(line) = input() else {
+        return Decision::Declined;
+    };
+    let line = line.trim();
+    let accepted = match expected {
+        Answer::Yes => line.eq_ignore_ascii_case("y") || line.eq_ignore_ascii_case("yes"),
+        Answer::Name(name) => line == name,
+    };
+    if accepted {
+        Decision::Proceed
+    } else {
+        Decision::Declined
+    }
+}
+
+/// Whether `RUKU_ASSUME_YES` is set to something other than empty, `0` or `false`.
+pub fn assume_yes_from_env() -> bool {
+    env::var(ASSUME_YES_ENV).is_ok_and(|value| !matches!(value.trim(), "" | "0" | "false"))
+}
+
+/// Asks before destructive operations, listing exactly what they affect.
+pub struct Confirm<'a> {
+    log: &'a Logger,
+    assume_yes: bool,
+}
+
+impl<'a> Confirm<'a> {
+    /// `yes` is the `--yes` flag, `RUKU_ASSUME_YES` counts the same.
+    pub fn new(log: &'a Logger, yes: bool) -> Confirm<'a> {
+        Confirm {
+            log,
+            assume_yes: yes || assume_yes_from_env(),
+        }
+    }
+
+    /// Whether `--yes` or `RUKU_ASSUME_YES` answers for the operator.
+    pub fn assumes_yes(&self) -> bool {
+        self.assume_yes
+    }
+
+    /// Fail unless the operator agrees to `action` on the `affected` items.
+    pub fn ask(&self, action: &str, affected: &[String], expected: Answer) -> Result<(), String> {
+        let is_tty = io::stdin().is_terminal();
+        let decision = decide(self.assume_yes, is_tty, expected, || {
+            self.log.warn(&format!("This will {}:", action));
+            for item in affected {
+                eprintln!("   {}", item);
+            }
+            // Nothing streamed may follow the question
+            self.log.flush();
+            match expected {
+                Answer::Yes => eprint!("Continue? [y/N] "),
+                Answer::Name(name) => eprint!("Type {} to confirm: ", name),
+            }
+            let _ = io::stderr().flush();
+            let mut line = String::new();
+            io::stdin().lock().read_line(&mut line).ok()?;
+            Some(line)
+        });
+
+        match decision {
+            Decision::Proceed => Ok(()),
+            Decision::NeedsYes => Err(format!(
+                "Refusing to {} without confirmation, pass --yes or set {}=1",
+                action, ASSUME_YES_ENV
+            )),
+            Decision::Declined => Err("Aborted".to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    /// `decide` with `line` typed at the prompt, and whether the prompt was shown.
+    fn decide_with(assume_yes: bool, is_tty: bool, expected: Answer, line: Option<&str>) -> (Decision, bool) {
+        let prompted = Cell::new(false);
+        let decision = decide(assume_yes, is_tty, expected, || {
+            prompted.set(true);
+            line.map(str::to_string)
+        });
+        (decision, prompted.get())
+    }
+
+    #[test]
+    fn yes_proceeds_without_a_prompt() {
+        for is_tty in [true, false] {
+            for expected in [Answer::Yes, Answer::Name("shop")] {
+                assert_eq!(
+                    decide_with(true, is_tty, expected, Some("n\n")),
+                    (Decision::Proceed, false)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn no_tty_without_yes_needs_yes() {
+        for expected in [Answer::Yes, Answer::Name("shop")] {
+            assert_eq!(
+                decide_with(false, false, expected, Some("y\n")),
+                (Decision::NeedsYes, false)
+            );
+        }
+    }
+
+    #[test]
+    fn tty_without_yes_asks() {
+        for line in ["y\n", "Y\n", "yes\n", " YES \n"] {
+            assert_eq!(
+                decide_with(false, true, Answer::Yes, Some(line)),
+                (Decision::Proceed, true),
+                "{:?}",
+                line
+            );
+        }
+        for line in ["\n", "n\n", "no\n", "yep\n", "shop\n"] {
+            assert_eq!(
+                decide_with(false, true, Answer::Yes, Some(line)),
+                (Decision::Declined, true),
+                "{:?}",
+                line
+            );
+        }
+        // Closed stdin
+        assert_eq!(decide_with(false, true, Answer::Yes, None), (Decision::Declined, true));
+    }
+
+    #[test]
+    fn tty_without_yes_needs_the_exact_name() {
+        assert_eq!(
+            decide_with(false, true, Answer::Name("shop"), Some("shop\n")),
+            (Decision::Proceed, true)
+        );
+        for line in ["y\n", "yes\n", "Shop\n", "shop2\n", "\n"] {
+            assert_eq!(
+                decide_with(false, true, Answer::Name("shop"), Some(line)),
+                (Decision::Declined, true),
+                "{:?}",
+                line
+            );
+        }
+    }
+}
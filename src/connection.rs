@@ -0,0 +1,356 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::path::Path;
+use std::sync::OnceLock;
+
+use bollard::errors::Error;
+use bollard::{Docker, API_DEFAULT_VERSION};
+
+use crate::api_trace;
+use crate::api_version::{unsupported_features, ApiVersion, MIN_API_VERSION};
+use crate::daemon_network::DaemonNetwork;
+use crate::dial;
+use crate::logger::Logger;
+use crate::model::RukuConfig;
+use crate::server_config::{ContextConfig, TlsConfig};
+
+/// Names the context of commands given no `--context`, it is how detached deploy workers get theirs.
+pub const CONTEXT_ENV: &str = "RUKU_CONTEXT";
+
+/// Seconds a request to the daemon may take, the same as bollard's own default.
+const TIMEOUT: u64 = 120;
+const UNREACHABLE: &str = "Ruku was unable to connect to docker";
+
+const LINUX_SOCKET: &str = "unix:///var/run/docker.sock";
+const WINDOWS_PIPE: &str = "npipe:////./pipe/docker_engine";
+/// Where Docker Desktop puts its socket on macOS when the `/var/run` symlink is not installed.
+const DESKTOP_SOCKET: &str = ".docker/run/docker.sock";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Platform {
+    Linux,
+    MacOs,
+    Windows,
+}
+
+impl Platform {
+    pub fn current() -> Platform {
+        match env::consts::OS {
+            "macos" => Platform::MacOs,
+            "windows" => Platform::Windows,
+            _ => Platform::Linux,
+        }
+    }
+}
+
+/// The daemon address to use: `DOCKER_HOST` when set, otherwise the platform default. On macOS the
+/// Docker Desktop socket in the home directory is preferred when it exists, since the one in `/var/run`
+/// is only a symlink Docker Desktop may not have installed.
+pub fn docker_host(
+    platform: Platform,
+    env_host: Option<&str>,
+    home: Option<&Path>,
+    exists: impl Fn(&Path) -> bool,
+) -> String {
+    if let Some(host) = env_host.filter(|host| !host.is_empty()) {
+        return host.to_string();
+    }
+    match platform {
+        Platform::Linux => LINUX_SOCKET.to_string(),
+        Platform::Windows => WINDOWS_PIPE.to_string(),
+        Platform::MacOs => match home.map(|home| home.join(DESKTOP_SOCKET)) {
+            Some(socket) if exists(&socket) => format!("unix://{}", socket.display()),
+            _ => LINUX_SOCKET.to_string(),
+        },
+    }
+}
+
+/// The context the commands of this process talk to, its name and daemon address, set once by
+/// [`use_context`].
+static CONTEXT: OnceLock<(String, String)> = OnceLock::new();
+/// The certificates of the context in use, when it has any.
+static CONTEXT_TLS: OnceLock<TlsConfig> = OnceLock::new();
+
+/// Talk to the daemon of `context` named `name` instead of the local one from here on.
+pub fn use_context(name: &str, context: &ContextConfig) {
+    if CONTEXT.set((name.to_string(), context.host.clone())).is_ok() {
+        if let Some(tls) = &context.tls {
+            let _ = CONTEXT_TLS.set(tls.clone());
+        }
+    }
+}
+
+/// The name and daemon address of the context in use, none for the local daemon.
+pub fn active_context() -> Option<&'static (String, String)> {
+    CONTEXT.get()
+}
+
+/// The context `app` is deployed to: the one its config pins, `requested` when it pins none, or else the
+/// one that lists it under `apps`. None for the local daemon. Asking for another context than the pinned
+/// one is an error, not an override.
+pub fn resolve_context<'c>(
+    contexts: &'c BTreeMap<String, ContextConfig>,
+    app: &str,
+    pinned: Option<&str>,
+    requested: Option<&str>,
+) -> Result<Option<(&'c str, &'c ContextConfig)>, String> {
+    let lookup = |name: &str| {
+        contexts
+            .get_key_value(name)
+            .map(|(name, context)| (name.as_str(), context))
+            .ok_or_else(|| match contexts.keys().cloned().collect::<Vec<_>>() {
+                known if known.is_empty() => format!("Unknown context {}, ~/.ruku/config.yml defines none", name),
+                known => format!("Unknown context {}, known: {}", name, known.join(", ")),
+            })
+    };
+    match (pinned, requested) {
+        (Some(pinned), Some(requested)) if pinned != requested => Err(format!(
+            "{} is pinned to context {} in its ruku.yml, refusing --context {}",
+            app, pinned, requested
+        )),
+        (Some(name), _) | (None, Some(name)) => lookup(name).map(Some),
+        (None, None) => Ok(contexts
+            .iter()
+            .find(|(_, context)| context.apps.iter().any(|listed| listed == app))
+            .map(|(name, context)| (name.as_str(), context))),
+    }
+}
+
+/// Whether ruku can connect to `context`.
+pub fn check_context(context: &ContextConfig) -> Result<(), String> {
+    let host = &context.host;
+    if !["unix://", "npipe://", "tcp://", "http://", "ssh://"]
+        .iter()
+        .any(|scheme| host.starts_with(scheme))
+    {
+        return Err(format!("{} is not a unix://, npipe://, tcp:// or ssh:// address", host));
+    }
+    if let Some(tls) = &context.tls {
+        for (option, path) in [("ca", &tls.ca), ("cert", &tls.cert), ("key", &tls.key)] {
+            if !path.is_file() {
+                return Err(format!("tls {} {} does not exist", option, path.display()));
+            }
+        }
+    }
+    let dialed = dial::dial_command(host, context.tls.as_ref())?;
+    if dialed.is_some() && !cfg!(unix) {
+        return Err(format!("{} can only be reached from unix hosts", host));
+    }
+    Ok(())
+}
+
+/// Whether the API goes to the daemon at `host` unencrypted over the network.
+pub fn is_plaintext_remote(host: &str, tls: Option<&TlsConfig>) -> bool {
+    let Some(address) = host.strip_prefix("tcp://").or(host.strip_prefix("http://")) else {
+        return false;
+    };
+    let name = address.rsplit_once(':').map_or(address, |(name, _)| name);
+    let loopback = name == "localhost"
+        || name
+            .trim_matches(['[', ']'])
+            .parse::<std::net::IpAddr>()
+            .is_ok_and(|ip| ip.is_loopback());
+    tls.is_none() && !loopback
+}
+
+/// The daemon address for this machine, or of the context in use.
+pub fn local_docker_host() -> String {
+    if let Some((_, host)) = active_context() {
+        return host.clone();
+    }
+    let env_host = env::var("DOCKER_HOST").ok();
+    let home = home::home_dir();
+    docker_host(Platform::current(), env_host.as_deref(), home.as_deref(), Path::exists)
+}
+
+/// Connect to the daemon at `host`, a `unix://` socket, a `npipe://` named pipe, a `tcp://` address or
+/// an `ssh://` one. A daemon over ssh, or over TLS with the certificates of the context in use, is
+/// reached through [`dial`]. While the API calls are traced, the connection goes through the socket
+/// recording them.
+pub fn connect(host: &str) -> Result<Docker, Error> {
+    let tls = active_context()
+        .filter(|(_, active)| active == host)
+        .and(CONTEXT_TLS.get());
+    let unsupported = || Error::UnsupportedURISchemeError { uri: host.to_string() };
+    let host = match dial::dial_command(host, tls).map_err(|_| unsupported())? {
+        Some(command) => dial::route(host, command).map_err(|err| Error::IOError { err })?,
+        None => host.to_string(),
+    };
+    match api_trace::route(&host).as_str() {
+        #[cfg(unix)]
+        host if host.starts_with("unix://") => Docker::connect_with_unix(host, TIMEOUT, API_DEFAULT_VERSION),
+        #[cfg(windows)]
+        host if host.starts_with("npipe://") => Docker::connect_with_named_pipe(host, TIMEOUT, API_DEFAULT_VERSION),
+        host if host.starts_with("tcp://") || host.starts_with("http://") => {
+            Docker::connect_with_http(host, TIMEOUT, API_DEFAULT_VERSION)
+        }
+        host => Err(Error::UnsupportedURISchemeError { uri: host.to_string() }),
+    }
+}
+
+/// Connect to the local daemon and check that it answers, failing when it doesn't.
+pub async fn get_docker(log: &Logger) -> Result<Docker, String> {
+    preflight(log, None).await
+}
+
+/// Connect to the local daemon like [`get_docker`] and check that its API has every feature `config`
+/// uses, failing when it doesn't.
+pub async fn get_docker_for(log: &Logger, config: &RukuConfig) -> Result<Docker, String> {
+    preflight(log, Some(config)).await
+}
+
+async fn preflight(log: &Logger, config: Option<&RukuConfig>) -> Result<Docker, String> {
+    // Older daemons reject requests made with a newer API version than their own
+    let docker = load_docker()?.negotiate_version().await.map_err(|_| UNREACHABLE)?;
+    let version = docker.version().await.map_err(|_| UNREACHABLE)?;
+    let engine = version.version.unwrap_or_default();
+    log.step(&format!("Docker engine version: {}", engine));
+    // Kept for explaining failed pulls, which go through the proxies of the daemon
+    if let Some(network) = DaemonNetwork::read(&docker).await {
+        network.remember();
+    }
+
+    let Some(api) = version.api_version.as_deref().and_then(ApiVersion::parse) else {
+        return Ok(docker);
+    };
+    let unsupported = config
+        .map(|config| unsupported_features(config, api))
+        .unwrap_or_default();
+    if let Some(required) = unsupported.iter().map(|feature| feature.version).max() {
+        let names: Vec<&str> = unsupported.iter().map(|feature| feature.name).collect();
+        return Err(format!(
+            "Docker {} (API {}) is below the minimum {} required for {}",
+            engine,
+            api,
+            required,
+            names.join(", ")
+        ));
+    }
+    if api < MIN_API_VERSION {
+        log.warn(&format!(
+            "Docker {} (API {}) is older than the minimum API {} ruku supports, upgrade the engine",
+            engine, api, MIN_API_VERSION
+        ));
+    }
+
+    Ok(docker)
+}
+
+/// Connect to the local daemon without checking it, failing when the address is unusable.
+pub fn load_docker() -> Result<Docker, String> {
+    connect(&local_docker_host()).map_err(|_| UNREACHABLE.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn never(_: &Path) -> bool {
+        false
+    }
+
+    #[test]
+    fn each_platform_has_its_default_daemon() {
+        assert_eq!(
+            docker_host(Platform::Linux, None, None, never),
+            "unix:///var/run/docker.sock"
+        );
+        assert_eq!(
+            docker_host(Platform::Windows, None, Some(Path::new("C:\\Users\\dev")), never),
+            "npipe:////./pipe/docker_engine"
+        );
+        assert_eq!(
+            docker_host(Platform::MacOs, None, Some(Path::new("/Users/dev")), never),
+            "unix:///var/run/docker.sock"
+        );
+    }
+
+    #[test]
+    fn macos_prefers_the_docker_desktop_socket_in_the_home() {
+        let desktop = |path: &Path| path == Path::new("/Users/dev/.docker/run/docker.sock");
+        assert_eq!(
+            docker_host(Platform::MacOs, None, Some(Path::new("/Users/dev")), desktop),
+            "unix:///Users/dev/.docker/run/docker.sock"
+        );
+        // Only on macOS, and only with a home to look in
+        assert_eq!(
+            docker_host(Platform::Linux, None, Some(Path::new("/Users/dev")), desktop),
+            "unix:///var/run/docker.sock"
+        );
+        assert_eq!(
+            docker_host(Platform::MacOs, None, None, |_| true),
+            "unix:///var/run/docker.sock"
+        );
+    }
+
+    #[test]
+    fn docker_host_overrides_the_platform_unless_empty() {
+        for platform in [Platform::Linux, Platform::MacOs, Platform::Windows] {
+            assert_eq!(
+                docker_host(
+                    platform,
+                    Some("tcp://10.0.0.2:2375"),
+                    Some(Path::new("/home/dev")),
+                    |_| true
+                ),
+                "tcp://10.0.0.2:2375"
+            );
+        }
+        assert_eq!(
+            docker_host(Platform::Linux, Some(""), None, never),
+            "unix:///var/run/docker.sock"
+        );
+    }
+
+    fn context(host: &str, tls: Option<TlsConfig>) -> ContextConfig {
+        ContextConfig {
+            host: host.to_string(),
+            tls,
+            apps: vec![],
+            read_only: false,
+        }
+    }
+
+    #[test]
+    fn contexts_are_checked_before_they_are_connected_to() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in ["ca.pem", "cert.pem", "key.pem"] {
+            std::fs::write(dir.path().join(name), "").unwrap();
+        }
+        let tls = TlsConfig {
+            ca: dir.path().join("ca.pem"),
+            cert: dir.path().join("cert.pem"),
+            key: dir.path().join("key.pem"),
+        };
+        assert_eq!(
+            check_context(&context("tcp://10.0.0.2:2376", Some(tls.clone()))),
+            Ok(())
+        );
+        assert_eq!(check_context(&context("ssh://deploy@10.0.0.2", None)), Ok(()));
+        assert!(check_context(&context("ftp://10.0.0.2", None)).is_err());
+        assert!(check_context(&context("unix:///var/run/docker.sock", Some(tls.clone()))).is_err());
+        let missing = TlsConfig {
+            key: dir.path().join("missing.pem"),
+            ..tls
+        };
+        let error = check_context(&context("tcp://10.0.0.2:2376", Some(missing))).unwrap_err();
+        assert!(error.contains("tls key") && error.contains("missing.pem"), "{}", error);
+    }
+
+    #[test]
+    fn only_remote_tcp_without_tls_is_plaintext() {
+        let tls = TlsConfig {
+            ca: "ca.pem".into(),
+            cert: "cert.pem".into(),
+            key: "key.pem".into(),
+        };
+        assert!(is_plaintext_remote("tcp://10.0.0.2:2375", None));
+        assert!(is_plaintext_remote("http://build.example.com:2375", None));
+        assert!(!is_plaintext_remote("tcp://10.0.0.2:2376", Some(&tls)));
+        assert!(!is_plaintext_remote("tcp://127.0.0.1:2375", None));
+        assert!(!is_plaintext_remote("tcp://[::1]:2375", None));
+        assert!(!is_plaintext_remote("tcp://localhost:2375", None));
+        assert!(!is_plaintext_remote("ssh://deploy@10.0.0.2", None));
+        assert!(!is_plaintext_remote("unix:///var/run/docker.sock", None));
+    }
+}
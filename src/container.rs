@@ -1,21 +1,263 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, TcpListener, UdpSocket};
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
-use bollard::container::{CreateContainerOptions, ListContainersOptions, StartContainerOptions};
+use bollard::container::NetworkingConfig;
+use bollard::container::{
+    CreateContainerOptions, ListContainersOptions, RemoveContainerOptions, RenameContainerOptions,
+    StartContainerOptions, StatsOptions, UpdateContainerOptions,
+};
+use bollard::errors::Error;
 use bollard::models::{
-    ContainerCreateResponse, ContainerStateStatusEnum, ContainerSummary, HostConfig, PortBinding, PortMap,
+    ContainerCreateResponse, ContainerInspectResponse, ContainerStateStatusEnum, ContainerSummary, EndpointSettings,
+    HealthStatusEnum, MountPointTypeEnum, RestartPolicy, RestartPolicyNameEnum,
 };
+use bollard::network::ConnectNetworkOptions;
 use bollard::Docker;
+use chrono::DateTime;
+use futures_util::StreamExt;
 
+use crate::backup::Backups;
+use crate::cpuset;
+use crate::daemon_error;
+use crate::deploy_message::label_value;
+use crate::executor::Executor;
+use crate::exit_status::StopReport;
+use crate::image::{is_not_found, Image, ImageDefaults};
+use crate::links::Link;
 use crate::logger::Logger;
-use crate::misc::get_image_name_with_version;
-use crate::model::RukuConfig;
+use crate::misc::{get_image_name_with_version, get_image_tag, get_version};
+use crate::model::{
+    publish_address, AppType, NetworkMode, Owner, Protocol, ResourcesConfig, RukuConfig, HOST_TIMEZONE,
+};
+use crate::network::{get_network_name, Networks};
+use crate::observability;
+use crate::platform::Platforms;
+use crate::prestart::PreStart;
+use crate::probe::{Probe, ProbeTarget};
+use crate::proxy::DOMAINS_LABEL;
+use crate::read_only::guard;
+use crate::secrets;
+use crate::smoke::{SmokeResult, SmokeTests};
+use crate::spec::{ContainerSpec, Inherited, PortSpec};
+use crate::static_site::{STATIC_ROOT, TYPE_LABEL};
+use crate::templates::{self, config_variables, files_digest, get_template_path, interpolate, render_files};
+use crate::units::format_duration;
+use crate::verify_env::EnvCheck;
+use crate::volume::{to_daemon_path, HostPaths, Shadowing, Volumes};
+
+/// Label holding the name of the app a container belongs to.
+pub const APP_LABEL: &str = "ruku.app";
+/// Label holding the version a container was deployed with.
+pub const VERSION_LABEL: &str = "ruku.version";
+/// Label telling the stable container of an app apart from its canary.
+pub const ROLE_LABEL: &str = "ruku.role";
+/// Label holding the host port assigned to an app with an `auto` port.
+pub const PORT_LABEL: &str = "ruku.port";
+/// Label with the note of the deploy that created the container, left out of the spec it is compared by.
+pub const DEPLOY_MESSAGE_LABEL: &str = "ruku.deploy-message";
+/// Label marking the containers of a preview deployed from a branch.
+pub const PREVIEW_LABEL: &str = "ruku.preview";
+/// Label with the digest of the `files` a container was created with, their content isn't in the spec.
+pub const FILES_LABEL: &str = "ruku.files";
+/// Label holding the versioned hash of the spec a container was created with.
+pub const CONFIG_HASH_LABEL: &str = "ruku.config-hash";
+/// Label holding the schema of the labels and naming a container was created with.
+pub const SCHEMA_LABEL: &str = "ruku.schema";
+/// The current schema: prefixed names and the app, role, version and schema labels.
+pub const SCHEMA_VERSION: u32 = 2;
+/// Labels ruku sets itself, the config can't override them.
+pub const RESERVED_LABEL_PREFIX: &str = "ruku.";
+/// Label marking the containers and volumes of an integration test run, which its cleanup removes.
+pub const TEST_LABEL: &str = "ruku.test";
+/// Env var the integration tests run ruku with to put [`TEST_LABEL`] on what it creates.
+pub const TEST_ENV: &str = "RUKU_TEST";
+
+/// [`TEST_LABEL`] when ruku runs under the integration tests, nothing otherwise.
+pub fn test_labels() -> Vec<(String, String)> {
+    std::env::var_os(TEST_ENV)
+        .map(|_| (TEST_LABEL.to_string(), "true".to_string()))
+        .into_iter()
+        .collect()
+}
+
+const REMOVAL_TIMEOUT: Duration = Duration::from_secs(30);
+const REMOVAL_POLL_INTERVAL: Duration = Duration::from_millis(500);
+const HEALTH_POLL_INTERVAL: Duration = Duration::from_millis(500);
+/// Starts tried when the host port isn't free yet, the kernel can hold the port of a container removed
+/// just before for a moment.
+const START_ATTEMPTS: u32 = 6;
+const START_RETRY_DELAY: Duration = Duration::from_millis(400);
+/// How long a container without a healthcheck has to stay up before it counts as healthy.
+const HEALTH_SETTLE_TIME: Duration = Duration::from_secs(3);
+/// Default time `--wait-healthy` waits for the container to become healthy.
+pub const DEFAULT_HEALTH_TIMEOUT: u64 = 60;
+/// Restarts after which a container with a restart policy counts as crash looping.
+const CRASH_LOOP_RESTARTS: i64 = 3;
+/// Interval Docker runs a healthcheck at when the image does not set one.
+const DEFAULT_HEALTHCHECK_INTERVAL: Duration = Duration::from_secs(30);
+/// Files that give a container the timezone of the host, mounted read-only with `timezone: host`.
+const HOST_TIMEZONE_FILES: [&str; 2] = ["/etc/localtime", "/etc/timezone"];
+
+/// What the daemon reports about a container beyond the state in its summary.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Condition {
+    pub state: ContainerStateStatusEnum,
+    pub health: Option<HealthStatusEnum>,
+    /// Roughly how long the healthcheck has been failing, from its failing streak and interval.
+    pub unhealthy_for: Option<Duration>,
+    pub restart_count: i64,
+    pub exit_code: Option<i64>,
+    /// The restart policy when it is not `no`.
+    pub restart_policy: Option<RestartPolicyNameEnum>,
+}
+
+impl Condition {
+    pub fn from_inspect(inspect: &ContainerInspectResponse) -> Condition {
+        let state = inspect.state.clone().unwrap_or_default();
+        let health = state.health.as_ref().and_then(|health| health.status);
+        let interval = inspect
+            .config
+            .as_ref()
+            .and_then(|config| config.healthcheck.as_ref())
+            .and_then(|healthcheck| healthcheck.interval)
+            .filter(|interval| *interval > 0)
+            .map(|interval| Duration::from_nanos(interval as u64))
+            .unwrap_or(DEFAULT_HEALTHCHECK_INTERVAL);
+        let unhealthy_for = state
+            .health
+            .as_ref()
+            .filter(|_| health == Some(HealthStatusEnum::UNHEALTHY))
+            .and_then(|health| health.failing_streak)
+            .map(|streak| interval * streak.max(0) as u32);
+        let restart_policy = inspect
+            .host_config
+            .as_ref()
+            .and_then(|host_config| host_config.restart_policy.as_ref())
+            .and_then(|policy| policy.name)
+            .filter(|name| *name != RestartPolicyNameEnum::NO && *name != RestartPolicyNameEnum::EMPTY);
+        Condition {
+            state: state.status.unwrap_or(ContainerStateStatusEnum::EMPTY),
+            health,
+            unhealthy_for,
+            restart_count: inspect.restart_count.unwrap_or_default(),
+            exit_code: state.exit_code,
+            restart_policy,
+        }
+    }
+
+    pub fn is_unhealthy(&self) -> bool {
+        self.health == Some(HealthStatusEnum::UNHEALTHY)
+    }
+
+    /// Docker keeps restarting the container because it keeps exiting.
+    pub fn is_crash_looping(&self) -> bool {
+        self.state == ContainerStateStatusEnum::RESTARTING
+            || (self.restart_policy.is_some() && self.restart_count >= CRASH_LOOP_RESTARTS)
+    }
+
+    /// Why the container is replaced, e.g. `container unhealthy for 6m, 14 restarts`.
+    pub fn describe(&self) -> String {
+        let state = match self.state {
+            ContainerStateStatusEnum::RUNNING if self.is_unhealthy() => match self.unhealthy_for {
+                Some(duration) => format!("unhealthy for {}", format_duration(duration)),
+                None => "unhealthy".to_string(),
+            },
+            ContainerStateStatusEnum::RESTARTING => "restarting in a crash loop".to_string(),
+            ContainerStateStatusEnum::EXITED | ContainerStateStatusEnum::DEAD => {
+                format!("{} with code {}", self.state, self.exit_code.unwrap_or_default())
+            }
+            ContainerStateStatusEnum::EMPTY => "in no state Docker reported".to_string(),
+            state => state.to_string(),
+        };
+        match self.restart_count {
+            0 => format!("container {}", state),
+            1 => format!("container {}, 1 restart", state),
+            count => format!("container {}, {} restarts", state, count),
+        }
+    }
+}
+
+/// The `labels` of the config with its variables filled in, an error for one that doesn't render.
+pub fn render_labels(app: &str, config: &RukuConfig) -> Result<BTreeMap<String, String>, String> {
+    let variables = config_variables(app, config);
+    config
+        .labels
+        .iter()
+        .map(|(key, value)| {
+            let value = interpolate(value, &variables).map_err(|e| format!("Error in label {}: {}", key, e))?;
+            Ok((key.clone(), value))
+        })
+        .collect()
+}
+
+/// The part a container plays in serving an app.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Role {
+    Stable,
+    Canary,
+    /// Serves the maintenance page while the app is stopped for maintenance.
+    Maintenance,
+    /// Runs next to the app as configured under `sidecars`.
+    Sidecar,
+}
+
+/// What ruku appends to the stable container's name for the containers it runs next to it,
+/// `<prefix><app>-<suffix>`: the canary, the maintenance page, and the staged containers of `ruku stage`
+/// and of the rolling and blue-green strategies. Sidecars add their own names.
+pub const CONTAINER_SUFFIXES: [&str; 6] = ["canary", "maintenance", "staged", "next", "previous", "green"];
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Stable => "stable",
+            Role::Canary => "canary",
+            Role::Maintenance => "maintenance",
+            Role::Sidecar => "sidecar",
+        }
+    }
+}
+
+/// What to do with a container that has the app's name but was not created by ruku.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum Takeover {
+    /// Leave it alone and stop.
+    #[default]
+    Refuse,
+    /// Take ownership, the container is recreated as it is with the ruku labels.
+    Adopt,
+    /// Remove it and deploy the app in its place.
+    ForceReplace,
+}
 
 pub struct Container<'a> {
     log: &'a Logger,
     name: &'a str,
     docker: &'a Docker,
     config: &'a RukuConfig,
+    role: Role,
+    container_name: String,
+    takeover: Takeover,
+    links: Vec<Link>,
+    template_dir: Option<PathBuf>,
+    static_root: Option<PathBuf>,
+    skip_pre_start: bool,
+    backups: Option<&'a Backups<'a>>,
+    /// Whether the port is published and the app's alias set, off for a container only ruku's checks reach.
+    publish: bool,
+    /// The result of the last lookup by name, so one command asks the daemon once until it changes the
+    /// container. `None` until looked up.
+    cached: Mutex<Option<Option<ContainerSummary>>>,
+    /// What the image to deploy declares, inspected once per deploy. `None` until inspected.
+    image_defaults: Mutex<Option<ImageDefaults>>,
+    /// How the containers this one stopped went down, in the order they were stopped.
+    stops: Mutex<Vec<StopReport>>,
+    /// The note of the deploy, put on the containers it creates.
+    deploy_message: Option<String>,
 }
 
 impl<'a> Container<'a> {
@@ -25,95 +267,996 @@ impl<'a> Container<'a> {
             name,
             docker,
             config,
+            role: Role::Stable,
+            container_name: format!("{}{}", config.container_prefix, name),
+            takeover: Takeover::default(),
+            links: vec![],
+            template_dir: None,
+            static_root: None,
+            skip_pre_start: false,
+            backups: None,
+            publish: true,
+            cached: Mutex::new(None),
+            image_defaults: Mutex::new(None),
+            stops: Mutex::new(vec![]),
+            deploy_message: None,
+        }
+    }
+
+    /// Allow replacing a container with the app's name that ruku did not create.
+    pub fn with_takeover(mut self, takeover: Takeover) -> Container<'a> {
+        self.takeover = takeover;
+        self
+    }
+
+    /// The apps this one is linked to, it joins their networks and gets their host and port as env vars.
+    pub fn with_links(mut self, links: Vec<Link>) -> Container<'a> {
+        self.links = links;
+        self
+    }
+
+    /// Mount the rendered templates from this directory, without it the templates are left out.
+    pub fn with_template_dir(mut self, template_dir: PathBuf) -> Container<'a> {
+        self.template_dir = Some(template_dir);
+        self
+    }
+
+    /// Start without running the `pre_start` command first.
+    pub fn with_skip_pre_start(mut self, skip_pre_start: bool) -> Container<'a> {
+        self.skip_pre_start = skip_pre_start;
+        self
+    }
+
+    /// Commit a container ruku did not create to a backup image before it is force replaced.
+    pub fn with_backups(mut self, backups: Option<&'a Backups<'a>>) -> Container<'a> {
+        self.backups = backups;
+        self
+    }
+
+    /// Directory mounted as the site of a static app, none for an app or an `immutable` site.
+    pub fn with_static_root(mut self, static_root: Option<PathBuf>) -> Container<'a> {
+        self.static_root = static_root;
+        self
+    }
+
+    /// Label the containers created with the note of the deploy.
+    pub fn with_deploy_message(mut self, message: Option<String>) -> Container<'a> {
+        self.deploy_message = message;
+        self
+    }
+
+    /// Keep the port unpublished and the app's alias off the network, so nothing but ruku's own checks
+    /// reaches the container.
+    pub fn with_publish(mut self, publish: bool) -> Container<'a> {
+        self.publish = publish;
+        self
+    }
+
+    /// The canary counterpart of this container, named `<prefix><app>-canary` and published on the canary port.
+    pub fn canary(&self) -> Container<'a> {
+        self.sibling(Role::Canary, "canary")
+    }
+
+    /// A container with the spec of this one under the name `<prefix><app>-<suffix>`, prepared next to it
+    /// and renamed into its place by the rolling and blue-green strategies.
+    pub fn staged(&self, suffix: &str) -> Container<'a> {
+        self.sibling(self.role, suffix)
+    }
+
+    fn sibling(&self, role: Role, suffix: &str) -> Container<'a> {
+        Container {
+            log: self.log,
+            name: self.name,
+            docker: self.docker,
+            config: self.config,
+            role,
+            container_name: format!("{}{}-{}", self.config.container_prefix, self.name, suffix),
+            takeover: self.takeover,
+            links: self.links.clone(),
+            template_dir: self.template_dir.clone(),
+            static_root: self.static_root.clone(),
+            skip_pre_start: self.skip_pre_start,
+            backups: self.backups,
+            publish: self.publish,
+            cached: Mutex::new(None),
+            image_defaults: Mutex::new(self.image_defaults.lock().unwrap().clone()),
+            stops: Mutex::new(vec![]),
+            deploy_message: self.deploy_message.clone(),
         }
     }
 
-    pub async fn run(&self) {
-        let image_name_with_version = get_image_name_with_version(self.name, &self.config.version);
+    pub fn container_name(&self) -> &str {
+        &self.container_name
+    }
+
+    /// The app the container belongs to.
+    pub fn app(&self) -> &'a str {
+        self.name
+    }
+
+    pub fn docker(&self) -> &'a Docker {
+        self.docker
+    }
 
-        if let Some(container) = self.get().await {
-            let container_id = container.id.as_deref().unwrap_or_else(|| {
-                self.log.error("Failed to get container id");
-                std::process::exit(1);
-            });
-            let container_state = container.state.as_deref().unwrap_or_else(|| {
-                self.log.error("Failed to get container state");
-                std::process::exit(1);
-            });
-            match ContainerStateStatusEnum::from_str(container_state).unwrap() {
-                ContainerStateStatusEnum::EMPTY => {}
-                ContainerStateStatusEnum::RUNNING | ContainerStateStatusEnum::RESTARTING => {
-                    self.stop_and_remove(container_id).await;
+    fn host_port(&self) -> u16 {
+        match (self.role, &self.config.canary) {
+            (Role::Canary, Some(canary)) => canary.port,
+            _ => self.config.port.host_port,
+        }
+    }
+
+    /// The port the app listens on in the container, the configured one or the one the image exposes once
+    /// [`Container::use_image`] inspected it.
+    pub fn container_port(&self) -> u16 {
+        if !self.config.port.is_from_image() {
+            return self.config.port.number;
+        }
+        self.image_defaults
+            .lock()
+            .unwrap()
+            .as_ref()
+            .and_then(|defaults| defaults.exposed_ports.first().copied())
+            .unwrap_or_default()
+    }
+
+    /// Forget what [`Container::use_image`] learned, the image is about to be built again.
+    pub fn forget_image(&self) {
+        *self.image_defaults.lock().unwrap() = None;
+    }
+
+    /// Inspect the image the container is created from for what ruku.yml leaves out, once. Fails when no
+    /// port is configured and the image exposes none, warns when the configured port is not exposed.
+    /// An image that isn't in the local store is left alone.
+    pub async fn use_image(&self, image_name: &str) -> Result<(), String> {
+        if self.image_defaults.lock().unwrap().is_some() {
+            return Ok(());
+        }
+        // A missing image fails the create with a better message than this could give
+        let Some(defaults) = Image::new(self.log, self.docker).defaults(image_name).await else {
+            return Ok(());
+        };
+        let exposed: Vec<String> = defaults.exposed_ports.iter().map(|port| port.to_string()).collect();
+        let port = &self.config.port;
+        match defaults.exposed_ports.first() {
+            None if port.is_from_image() => {
+                return Err(format!(
+                    "ruku.yml sets no port and image {} exposes none, set port in ruku.yml",
+                    image_name
+                ))
+            }
+            Some(first) if port.is_from_image() && exposed.len() > 1 => self.log.step(&format!(
+                "Image {} exposes ports {}, using {}, set port in ruku.yml for another",
+                image_name,
+                exposed.join(", "),
+                first
+            )),
+            Some(first) if port.is_from_image() => self
+                .log
+                .step(&format!("Using port {} exposed by image {}", first, image_name)),
+            Some(_) if !defaults.exposed_ports.contains(&port.number) => self.log.warn(&format!(
+                "ruku.yml sets port {} but image {} only exposes {}",
+                port.number,
+                image_name,
+                exposed.join(", ")
+            )),
+            _ => {}
+        }
+        *self.image_defaults.lock().unwrap() = Some(defaults);
+        Ok(())
+    }
+
+    /// Give the rendered files holding secrets to the user the image runs as, they are only readable by
+    /// their owner. `create_host_paths.owner` names the user when the image gives it by name.
+    pub async fn own_templates(&self, image_name: &str) -> Result<(), String> {
+        let Some(dir) = &self.template_dir else {
+            return Ok(());
+        };
+        let paths = templates::secret_files(dir, self.config.templates.values().chain(self.config.files.keys()));
+        if paths.is_empty() {
+            return Ok(());
+        }
+        self.use_image(image_name).await?;
+        let image_user = self
+            .image_defaults
+            .lock()
+            .unwrap()
+            .as_ref()
+            .and_then(|defaults| defaults.user.clone());
+        let owner = match (self.config.create_host_paths.owner, image_user) {
+            (Some(owner), _) => owner,
+            // Root reads them as they are
+            (None, None) => return Ok(()),
+            (None, Some(user)) => match user.parse::<Owner>() {
+                Ok(owner) => owner,
+                Err(_) => {
+                    self.log.warn(&format!(
+                        "The image runs as user {}, set create_host_paths.owner to its uid so it can read the \
+                         templates holding secrets",
+                        user
+                    ));
+                    return Ok(());
                 }
-                ContainerStateStatusEnum::REMOVING => {}
-                ContainerStateStatusEnum::CREATED
-                | ContainerStateStatusEnum::PAUSED
-                | ContainerStateStatusEnum::EXITED
-                | ContainerStateStatusEnum::DEAD => {
-                    self.remove(container_id).await;
+            },
+        };
+        #[cfg(unix)]
+        for path in paths {
+            if let Err(e) = std::os::unix::fs::chown(&path, Some(owner.uid), owner.gid) {
+                self.log.warn(&format!(
+                    "Error giving {} to user {}, the app may not be able to read it: {}",
+                    path.display(),
+                    owner,
+                    e
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    fn host_ip(&self) -> Option<String> {
+        publish_address(self.config.port.host_ip.or(self.config.bind_ip))
+    }
+
+    /// Fail when a port this container publishes is taken by anything but a container of the same app,
+    /// which a redeploy replaces anyway.
+    pub async fn check_ports(&self) -> Result<(), String> {
+        let owned = self.list_app().await?;
+        for port in self.spec(String::new())?.ports {
+            if is_port_free(&port) || owned.iter().any(|container| publishes(container, &port)) {
+                continue;
+            }
+            return Err(format!(
+                "Port {} on {} is already in use",
+                port.host_port,
+                port.host_ip.as_deref().unwrap_or("all interfaces")
+            ));
+        }
+        Ok(())
+    }
+
+    /// The host ports the container is configured to publish on.
+    pub fn host_ports(&self) -> Result<Vec<u16>, String> {
+        let mut ports: Vec<u16> = self
+            .spec(String::new())?
+            .ports
+            .iter()
+            .map(|port| port.host_port)
+            .collect();
+        ports.dedup();
+        Ok(ports)
+    }
+
+    /// The image of the configured version.
+    pub fn image_name(&self) -> String {
+        get_image_name_with_version(self.name, &self.config.version)
+    }
+
+    pub async fn run(&self) -> Result<(), String> {
+        self.run_image(self.image_name()).await
+    }
+
+    /// Replace the container with one from `image_name` instead of the configured version.
+    pub async fn run_image(&self, image_name: String) -> Result<(), String> {
+        if let Some(container) = self.get().await? {
+            self.clear(&container).await?;
+        }
+        let container_id = self.prepare(image_name).await?;
+        self.start(&container_id).await
+    }
+
+    /// Create the container from `image_name` and run the `pre_start` command, leaving it to be started
+    /// with [`Container::resume`]. Returns the container id.
+    pub async fn prepare(&self, image_name: String) -> Result<String, String> {
+        let container = self.create(image_name.clone()).await?;
+        self.pre_start(image_name).await?;
+        Ok(container.id)
+    }
+
+    /// Run the `pre_start` command, failing before the app starts when it fails.
+    async fn pre_start(&self, image_name: String) -> Result<(), String> {
+        let Some(pre_start) = self.config.pre_start.as_ref().filter(|_| !self.skip_pre_start) else {
+            return Ok(());
+        };
+        let spec = self.spec(image_name)?;
+        PreStart::new(self.log, self.docker, pre_start)
+            .run(self.name, &self.container_name, &spec)
+            .await
+    }
+
+    /// Get the existing container out of the way so a new one can take its name. The decision goes by
+    /// what inspecting the container reports, a crash looping container has its restart policy cleared
+    /// first so Docker does not start it again while it is removed.
+    async fn clear(&self, container: &ContainerSummary) -> Result<(), String> {
+        self.check_ownership(container)?;
+        let container_id = container.id.as_deref().ok_or("Failed to get container id")?;
+        let condition = match self.docker.inspect_container(container_id, None).await {
+            Ok(inspect) => Condition::from_inspect(&inspect),
+            Err(e) => {
+                self.log.warn(&format!(
+                    "Failed to inspect the container, going by its listed state: {}",
+                    e
+                ));
+                self.listed_condition(container)?
+            }
+        };
+        self.log.step(&format!("Replacing: {}", condition.describe()));
+        let replacing_foreign =
+            self.takeover == Takeover::ForceReplace && !is_managed(container) && !is_legacy(container, self.name);
+        if let Some(backups) = self.backups.filter(|_| replacing_foreign) {
+            backups
+                .create(
+                    container_id,
+                    &self.container_name,
+                    container.image.as_deref(),
+                    "force replace",
+                )
+                .await?;
+        }
+
+        match condition.state {
+            ContainerStateStatusEnum::RUNNING | ContainerStateStatusEnum::RESTARTING => {
+                if condition.is_crash_looping() && condition.restart_policy.is_some() {
+                    self.disable_restart(container_id).await;
                 }
+                self.stop_and_remove(container_id).await
             }
-            let new_container = self.create(image_name_with_version).await;
-            self.start(&new_container.id).await;
-        } else {
-            let container = self.create(image_name_with_version).await;
-            self.start(&container.id).await;
+            ContainerStateStatusEnum::REMOVING => self.wait_for_removal(container_id).await,
+            ContainerStateStatusEnum::EMPTY
+            | ContainerStateStatusEnum::CREATED
+            | ContainerStateStatusEnum::PAUSED
+            | ContainerStateStatusEnum::EXITED
+            | ContainerStateStatusEnum::DEAD => self.remove(container_id).await,
         }
     }
 
-    pub async fn end(&self) {
-        if let Some(container) = self.get().await {
-            let container_id = container.id.as_deref().unwrap_or_else(|| {
-                self.log.error("Failed to get container id");
-                std::process::exit(1);
-            });
-            self.stop_and_remove(container_id).await;
+    /// The condition from the container list alone, when the container can't be inspected.
+    fn listed_condition(&self, container: &ContainerSummary) -> Result<Condition, String> {
+        let listed_state = container.state.as_deref().ok_or("Failed to get container state")?;
+        let state = ContainerStateStatusEnum::from_str(listed_state).unwrap_or_else(|_| {
+            self.log.warn(&format!(
+                "Unknown container state '{}', treating it as stopped",
+                listed_state
+            ));
+            ContainerStateStatusEnum::EXITED
+        });
+        Ok(Condition {
+            state,
+            health: None,
+            unhealthy_for: None,
+            restart_count: 0,
+            exit_code: None,
+            restart_policy: None,
+        })
+    }
+
+    /// What inspecting the container reports, none when it does not exist.
+    pub async fn condition(&self) -> Option<Condition> {
+        let inspect = self.docker.inspect_container(&self.container_name, None).await.ok()?;
+        Some(Condition::from_inspect(&inspect))
+    }
+
+    /// Set the restart policy to `no`, so stopping the container is not raced by a restart.
+    async fn disable_restart(&self, container_id: &str) {
+        let options = UpdateContainerOptions::<String> {
+            restart_policy: Some(RestartPolicy {
+                name: Some(RestartPolicyNameEnum::NO),
+                maximum_retry_count: None,
+            }),
+            ..Default::default()
+        };
+        match self.docker.update_container(container_id, options).await {
+            Ok(()) => self
+                .log
+                .step("Cleared the restart policy of the crash looping container"),
+            Err(e) => self.log.warn(&format!(
+                "Failed to clear the restart policy, stopping the container anyway: {}",
+                e
+            )),
+        }
+    }
+
+    /// Poll until Docker has finished removing the container.
+    async fn wait_for_removal(&self, container_id: &str) -> Result<(), String> {
+        self.log
+            .step(&format!("Waiting for container {} to be removed", container_id));
+
+        let deadline = Instant::now() + REMOVAL_TIMEOUT;
+        while self.lookup().await?.is_some() {
+            if Instant::now() >= deadline {
+                return Err(format!(
+                    "Container {} was still being removed after {} seconds",
+                    container_id,
+                    REMOVAL_TIMEOUT.as_secs()
+                ));
+            }
+            tokio::time::sleep(REMOVAL_POLL_INTERVAL).await;
+        }
+        Ok(())
+    }
+
+    /// Whether ruku created the container.
+    fn is_owned(&self, container: &ContainerSummary) -> bool {
+        is_managed(container) || is_legacy(container, self.name)
+    }
+
+    /// Take over the container with the app's name that ruku did not create, with `--adopt`. Docker can't
+    /// change the labels of a container, so it is recreated as it is with the ruku labels added: the same
+    /// image, config, volumes and networks. Returns the recreated container when there was one to adopt,
+    /// the next deploy replaces it like any other.
+    pub async fn adopt(&self) -> Result<Option<ContainerSummary>, String> {
+        if self.takeover != Takeover::Adopt {
+            return Ok(None);
+        }
+        let Some(container) = self.get().await?.filter(|container| !self.is_owned(container)) else {
+            return Ok(None);
+        };
+        guard(&format!("adopt {}", self.container_name)).map_err(|e| e.to_string())?;
+        let container_id = container.id.as_deref().ok_or("Failed to get container id")?;
+        let inspect = self
+            .docker
+            .inspect_container(container_id, None)
+            .await
+            .map_err(|e| format!("Failed to inspect container {}: {}", self.container_name, e))?;
+        let mut config = recreate_config(&inspect, self.adoption_labels(&container));
+        // A tag moved since the container was created would recreate it on another image
+        if let Some(image) = config.image.clone() {
+            let tagged = self.docker.inspect_image(&image).await.ok().and_then(|image| image.id);
+            if tagged.is_none() || tagged != inspect.image {
+                config.image = inspect.image.clone();
+            }
+        }
+        let networks = extra_networks(&inspect);
+        let running = container.state.as_deref() == Some("running");
+        self.log.step(&format!(
+            "Adopting container {} running {}, it is recreated with the ruku labels",
+            self.container_name,
+            container.image.as_deref().unwrap_or("an unknown image")
+        ));
+
+        // The container is kept aside until the recreated one runs, and put back when it doesn't
+        if running {
+            self.stop(container_id).await?;
+        }
+        let aside = format!("{}-unadopted", self.container_name);
+        self.rename(container_id, &aside).await?;
+        if let Err(e) = self.recreate(config, networks, running).await {
+            self.log
+                .warn(&format!("Putting back container {}", self.container_name));
+            self.discard().await?;
+            self.rename(container_id, &self.container_name).await?;
+            if running {
+                self.start(container_id).await?;
+            }
+            return Err(format!("Failed to adopt container {}: {}", self.container_name, e));
+        }
+        self.remove(container_id).await?;
+        self.get().await
+    }
+
+    /// The ruku labels an adopted container gets, its version is the tag of the image it runs.
+    fn adoption_labels(&self, container: &ContainerSummary) -> BTreeMap<String, String> {
+        let mut labels = BTreeMap::from([
+            (APP_LABEL.to_string(), self.name.to_string()),
+            (ROLE_LABEL.to_string(), self.role.as_str().to_string()),
+            (SCHEMA_LABEL.to_string(), SCHEMA_VERSION.to_string()),
+        ]);
+        if let Some(tag) = container.image.as_deref().and_then(get_image_tag) {
+            labels.insert(VERSION_LABEL.to_string(), tag);
+        }
+        labels.extend(test_labels());
+        labels
+    }
+
+    /// Create the container by the app's name with `config`, join it to `networks` and start it when the
+    /// adopted one was running.
+    async fn recreate(
+        &self,
+        config: bollard::container::Config<String>,
+        networks: Vec<(String, EndpointSettings)>,
+        start: bool,
+    ) -> Result<(), String> {
+        let options = CreateContainerOptions {
+            name: self.container_name.as_str(),
+            platform: None,
+        };
+        let created = self.try_create(&options, config).await.map_err(|e| e.to_string())?;
+        self.log.step(&format!("Created container with id: {}", created.id));
+        for (network, endpoint_config) in networks {
+            let options = ConnectNetworkOptions {
+                container: created.id.clone(),
+                endpoint_config,
+            };
+            self.docker
+                .connect_network(&network, options)
+                .await
+                .map_err(|e| format!("Failed to connect it to network {}: {}", network, e))?;
+        }
+        if start {
+            self.start(&created.id).await?;
+        }
+        Ok(())
+    }
+
+    async fn rename(&self, container_id: &str, name: &str) -> Result<(), String> {
+        self.forget();
+        let options = RenameContainerOptions { name };
+        self.docker
+            .rename_container(container_id, options)
+            .await
+            .map_err(|e| format!("Failed to rename container {} to {}: {}", container_id, name, e))?;
+        self.log
+            .step(&format!("Renamed container {} to {}", container_id, name));
+        Ok(())
+    }
+
+    /// Fail unless the container was created by ruku or taking it over was allowed.
+    fn check_ownership(&self, container: &ContainerSummary) -> Result<(), String> {
+        if self.is_owned(container) {
+            return Ok(());
+        }
+
+        match self.takeover {
+            Takeover::Refuse => {
+                self.log.error(&format!(
+                    "Container {} already exists but was not created by ruku",
+                    self.container_name
+                ));
+                self.log
+                    .step(&format!("Image: {}", container.image.as_deref().unwrap_or("unknown")));
+                let created = container
+                    .created
+                    .and_then(|created| DateTime::from_timestamp(created, 0))
+                    .map(|created| created.to_rfc3339())
+                    .unwrap_or("unknown".to_string());
+                self.log.step(&format!("Created: {}", created));
+                return Err("Pass --adopt to take it over or --force-replace to replace it".to_string());
+            }
+            // `run --adopt` recreates the container before, one that appeared since is replaced
+            Takeover::Adopt => self.log.warn(&format!(
+                "Replacing container {} which was not created by ruku",
+                self.container_name
+            )),
+            Takeover::ForceReplace => self.log.warn(&format!(
+                "Replacing container {} which was not created by ruku",
+                self.container_name
+            )),
+        }
+        Ok(())
+    }
+
+    pub async fn end(&self) -> Result<(), String> {
+        guard(&format!("stop {}", self.container_name)).map_err(|e| e.to_string())?;
+        if let Some(container) = self.get().await? {
+            self.check_ownership(&container)?;
+            let container_id = container.id.as_deref().ok_or("Failed to get container id")?;
+            self.stop(container_id).await?;
+            self.report_stop(container_id).await;
+            self.remove(container_id).await?;
         } else {
             self.log.error("No application is running");
         }
+        Ok(())
+    }
+
+    /// Stop and remove every container of the app, the stable one and any canary, `concurrency` at a time.
+    /// With `keep` they are only stopped, so their logs and state can still be inspected.
+    pub async fn end_all(&self, concurrency: usize, keep: bool) -> Result<(), String> {
+        guard(&format!("stop {}", self.name)).map_err(|e| e.to_string())?;
+        let mut names: Vec<String> = self.list_app().await?.iter().filter_map(get_container_name).collect();
+        // Containers from before the labels are only found by name
+        if names.is_empty() {
+            if let Some(container) = self.get().await? {
+                self.check_ownership(&container)?;
+                names.extend(get_container_name(&container));
+            }
+        }
+        if names.is_empty() {
+            self.log.error("No application is running");
+            return Ok(());
+        }
+
+        let executor = Executor::new(self.log, concurrency);
+        let failures = if keep {
+            executor.run("stopped", names, |name| self.try_stop(name)).await
+        } else {
+            executor
+                .run("stopped and removed", names, |name| self.try_stop_and_remove(name))
+                .await
+        };
+        match failures.len() {
+            0 => Ok(()),
+            failed => Err(format!("Failed to stop {} of the containers of {}", failed, self.name)),
+        }
+    }
+
+    async fn try_stop_and_remove(&self, container: String) -> Result<(), Error> {
+        self.try_stop(container.clone()).await?;
+        self.forget();
+        self.docker.remove_container(&container, None).await
+    }
+
+    async fn try_stop(&self, container: String) -> Result<(), Error> {
+        self.forget();
+        match self.docker.stop_container(&container, None).await {
+            Ok(_) => {
+                self.report_stop(&container).await;
+                Ok(())
+            }
+            // 304 means the container was already stopped
+            Err(Error::DockerResponseServerError { status_code: 304, .. }) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Log how `container` went down, read before it is removed. A failed or killed stop is a warning.
+    async fn report_stop(&self, container: &str) {
+        let Ok(inspect) = self.docker.inspect_container(container, None).await else {
+            return;
+        };
+        let name = inspect
+            .name
+            .as_deref()
+            .map_or(container, |name| name.trim_start_matches('/'));
+        let Some(report) = StopReport::from_inspect(name, &inspect) else {
+            return;
+        };
+        if report.is_clean() {
+            self.log.step(&report.describe());
+        } else {
+            self.log.warn(&report.describe());
+        }
+        self.stops.lock().unwrap().push(report);
+    }
+
+    /// How the containers stopped so far went down.
+    pub fn stop_reports(&self) -> Vec<StopReport> {
+        self.stops.lock().unwrap().clone()
+    }
+
+    /// Wait until the container passes its healthcheck, or has stayed up for a few seconds when the image
+    /// has none. Fails as soon as the container exits or is reported unhealthy, or when `timeout` passes.
+    pub async fn wait_healthy(&self, timeout: Duration) -> Result<(), String> {
+        self.log.step(&format!(
+            "Waiting up to {}s for {} to become healthy",
+            timeout.as_secs(),
+            self.container_name
+        ));
+        let image_healthcheck = self
+            .image_defaults
+            .lock()
+            .unwrap()
+            .as_ref()
+            .is_some_and(|defaults| defaults.has_healthcheck);
+        if self.config.probe.is_none() && image_healthcheck {
+            self.log
+                .step("No probe in ruku.yml, going by the healthcheck of the image");
+        }
+        if self.config.tty && (self.config.probe.is_some() || image_healthcheck) {
+            self.log.warn(
+                "The container has a tty but the probe and healthcheck exec without one, \
+                 commands that check for a terminal may behave differently there",
+            );
+        }
+        let deadline = Instant::now() + timeout;
+        let mut running_since: Option<Instant> = None;
+        let mut probe_error: Option<String> = None;
+
+        loop {
+            let state = self
+                .docker
+                .inspect_container(&self.container_name, None)
+                .await
+                .map_err(|e| format!("Failed to inspect container: {}", e))?
+                .state
+                .unwrap_or_default();
+            let health = state.health.and_then(|health| health.status);
+
+            // A configured probe decides readiness, the image healthcheck can still fail the container
+            match health {
+                Some(HealthStatusEnum::HEALTHY) if self.config.probe.is_none() => return Ok(()),
+                Some(HealthStatusEnum::UNHEALTHY) => return Err("Container is unhealthy".to_string()),
+                _ => {}
+            }
+            if state.running.unwrap_or(false) && !state.restarting.unwrap_or(false) {
+                let since = *running_since.get_or_insert_with(Instant::now);
+                let has_healthcheck = health.is_some_and(|status| status != HealthStatusEnum::NONE);
+                match &self.config.probe {
+                    Some(probe) => match Probe::new(self.log, self.docker, probe)
+                        .check(&self.probe_target().await)
+                        .await
+                    {
+                        Ok(()) => return Ok(()),
+                        Err(e) => probe_error = Some(e),
+                    },
+                    None if !has_healthcheck && since.elapsed() >= HEALTH_SETTLE_TIME => return Ok(()),
+                    None => {}
+                }
+            } else if state.status == Some(ContainerStateStatusEnum::EXITED)
+                || state.status == Some(ContainerStateStatusEnum::DEAD)
+            {
+                return Err(format!(
+                    "Container exited with code {}",
+                    state.exit_code.unwrap_or_default()
+                ));
+            } else {
+                running_since = None;
+            }
+
+            if Instant::now() >= deadline {
+                let reason = probe_error
+                    .map(|e| format!(", the probe failed: {}", e))
+                    .unwrap_or_default();
+                return Err(format!(
+                    "Container was not healthy after {}s{}",
+                    timeout.as_secs(),
+                    reason
+                ));
+            }
+            tokio::time::sleep(HEALTH_POLL_INTERVAL).await;
+        }
+    }
+
+    /// Run the smoke checks of the app against this container and check the env it sees, the error says
+    /// what failed.
+    pub async fn smoke_test(&self) -> Result<Vec<SmokeResult>, String> {
+        if let Some(verify_env) = &self.config.verify_env {
+            let env = self.spec(self.image_name())?.env;
+            EnvCheck::new(self.log, self.docker, verify_env)
+                .run(&self.probe_target().await, &env)
+                .await?;
+        }
+        let Some(smoke) = &self.config.smoke else {
+            return Ok(vec![]);
+        };
+        let results = SmokeTests::new(self.log, self.docker, smoke)
+            .run(&self.probe_target().await)
+            .await;
+        let failed = results.iter().filter(|result| !result.passed).count();
+        if failed > 0 {
+            return Err(format!(
+                "{} of {} smoke checks failed against {}",
+                failed,
+                results.len(),
+                self.container_name
+            ));
+        }
+        Ok(results)
+    }
+
+    async fn probe_target(&self) -> ProbeTarget {
+        let publishes_tcp = self.config.port.protocols.contains(&Protocol::Tcp);
+        let host = match self.config.network_mode {
+            // The app listens on the host itself
+            NetworkMode::Host => publishes_tcp.then(|| ("127.0.0.1".to_string(), self.container_port())),
+            NetworkMode::None => None,
+            _ if publishes_tcp && self.config.port.is_published() && self.publish => {
+                Some((self.bound_address().await, self.host_port()))
+            }
+            _ => None,
+        };
+        ProbeTarget {
+            app: self.name.to_string(),
+            container_name: self.container_name.clone(),
+            network: self.network().unwrap_or(self.config.network_mode.to_string()),
+            container_port: self.container_port(),
+            host,
+        }
+    }
+
+    /// Where the probe reaches the published port, through the stack the daemon actually bound it on. IPv4
+    /// goes first when it is bound on both.
+    async fn bound_address(&self) -> String {
+        let bound = match self.docker.inspect_container(&self.container_name, None).await {
+            Ok(container) => bound_addresses(&container, self.container_port(), self.host_port()),
+            Err(_) => vec![],
+        };
+        let address = bound
+            .iter()
+            .find(|ip| ip.is_empty() || *ip == "0.0.0.0")
+            .or(bound.iter().find(|ip| *ip == "::"))
+            .or(bound.first())
+            .cloned()
+            .or(self.host_ip())
+            .unwrap_or_default();
+        local_address(&address)
+    }
+
+    /// Stop the container without removing it, so it can be started again as it was.
+    pub async fn suspend(&self) -> Result<(), String> {
+        self.stop(&self.container_name).await
+    }
+
+    /// Start the suspended or prepared container.
+    pub async fn resume(&self) -> Result<(), String> {
+        self.start(&self.container_name).await
+    }
+
+    /// Give the container the name of `other`, which has to be free by then.
+    pub async fn rename_to(&self, other: &Container<'_>) -> Result<(), String> {
+        self.forget();
+        other.forget();
+        let options = RenameContainerOptions {
+            name: other.container_name(),
+        };
+        self.docker
+            .rename_container(&self.container_name, options)
+            .await
+            .map_err(|e| {
+                format!(
+                    "Failed to rename container {} to {}: {}",
+                    self.container_name, other.container_name, e
+                )
+            })?;
+        self.log.step(&format!(
+            "Renamed container {} to {}",
+            self.container_name, other.container_name
+        ));
+        Ok(())
+    }
+
+    /// Remove the container whatever state it is in, when there is one.
+    pub async fn discard(&self) -> Result<(), String> {
+        guard(&format!("remove {}", self.container_name)).map_err(|e| e.to_string())?;
+        self.forget();
+        let options = RemoveContainerOptions {
+            force: true,
+            ..Default::default()
+        };
+        match self.docker.remove_container(&self.container_name, Some(options)).await {
+            Ok(_) => self.log.step(&format!("Removed container {}", self.container_name)),
+            Err(e) if is_not_found(&e) => {}
+            Err(e) => return Err(format!("Failed to remove container {}: {}", self.container_name, e)),
+        }
+        Ok(())
+    }
+
+    /// Restart the running container in place, keeping its configuration. With a `pre_start` command the
+    /// container is stopped, the command run and the container started again.
+    pub async fn restart(&self) -> Result<(), String> {
+        guard(&format!("restart {}", self.container_name)).map_err(|e| e.to_string())?;
+        let Some(container) = self.get().await? else {
+            return Err(format!("Container {} not found", self.container_name));
+        };
+        if self.config.pre_start.is_some() && !self.skip_pre_start {
+            // The image the container runs, which may be older than the version in the config
+            let image_name = container
+                .image
+                .unwrap_or_else(|| get_image_name_with_version(self.name, &self.config.version));
+            self.stop(&self.container_name).await?;
+            self.pre_start(image_name).await?;
+            self.start(&self.container_name).await?;
+            self.log.step(&format!("Restarted container {}", self.container_name));
+            return Ok(());
+        }
+        self.forget();
+        self.docker
+            .restart_container(&self.container_name, None)
+            .await
+            .map_err(|e| {
+                let explained = daemon_error::report(self.log, &e, self.name, self.config);
+                format!("Failed to restart container: {}", explained)
+            })?;
+        self.log.step(&format!("Restarted container {}", self.container_name));
+        Ok(())
     }
 
-    async fn stop_and_remove(&self, container_id: &str) {
-        self.stop(container_id).await;
-        self.remove(container_id).await;
+    async fn stop_and_remove(&self, container_id: &str) -> Result<(), String> {
+        self.stop(container_id).await?;
+        self.remove(container_id).await
     }
 
-    async fn stop(&self, container_id: &str) {
+    async fn stop(&self, container_id: &str) -> Result<(), String> {
+        self.forget();
         self.docker
             .stop_container(container_id, None)
             .await
-            .unwrap_or_else(|_| {
-                self.log.error("Failed to stop container");
-                std::process::exit(1);
-            });
+            .map_err(|_| "Failed to stop container")?;
         self.log.step(&format!("Stopped container with id: {}", container_id));
+        Ok(())
     }
 
-    async fn remove(&self, container_id: &str) {
+    async fn remove(&self, container_id: &str) -> Result<(), String> {
+        self.forget();
         self.docker
             .remove_container(container_id, None)
             .await
-            .unwrap_or_else(|_| {
-                self.log.error("Failed to remove container");
-                std::process::exit(1);
-            });
+            .map_err(|_| "Failed to remove container")?;
         self.log.step(&format!("Removed container with id: {}", container_id));
+        Ok(())
     }
 
-    async fn start(&self, container_id: &str) {
-        self.docker
-            .start_container(container_id, None::<StartContainerOptions<String>>)
-            .await
-            .unwrap_or_else(|_| {
-                self.log.error("Failed to start container");
-                std::process::exit(1);
-            });
+    async fn start(&self, container_id: &str) -> Result<(), String> {
+        self.forget();
+        let mut attempt = 1;
+        loop {
+            match self
+                .docker
+                .start_container(container_id, None::<StartContainerOptions<String>>)
+                .await
+            {
+                Ok(_) => break,
+                Err(e) if is_bind_failure(&e) && attempt < START_ATTEMPTS => {
+                    self.log.warn(&format!(
+                        "The host port isn't released yet, retrying the start ({}/{})",
+                        attempt,
+                        START_ATTEMPTS - 1
+                    ));
+                    attempt += 1;
+                    tokio::time::sleep(START_RETRY_DELAY).await;
+                }
+                Err(e) if is_bind_failure(&e) => {
+                    return Err(format!(
+                        "Failed to start container, {}",
+                        self.port_owners(container_id).await.join(", ")
+                    ))
+                }
+                Err(e) => {
+                    let explained = daemon_error::report(self.log, &e, self.name, self.config);
+                    return Err(format!("Failed to start container: {}", explained));
+                }
+            }
+        }
         self.log.step(&format!("Started container with id: {}", container_id));
+        Ok(())
+    }
+
+    /// Who holds each port the container publishes that can't be bound: a running container other than
+    /// `container_id` that publishes it, or else a process on the host.
+    async fn port_owners(&self, container_id: &str) -> Vec<String> {
+        let running = self
+            .docker
+            .list_containers(None::<ListContainersOptions<String>>)
+            .await
+            .unwrap_or_default();
+        let ports = self.spec(String::new()).map(|spec| spec.ports).unwrap_or_default();
+        let owners: Vec<String> = ports
+            .iter()
+            .filter(|port| !is_port_free(port))
+            .map(|port| {
+                let address = port.host_ip.as_deref().unwrap_or("all interfaces");
+                let owner = running
+                    .iter()
+                    .filter(|summary| summary.id.as_deref() != Some(container_id))
+                    .find(|summary| publishes(summary, port))
+                    .map(|summary| format!("container {}", get_container_name(summary).unwrap_or_default()))
+                    .unwrap_or("a process on the host".to_string());
+                format!("port {} on {} is held by {}", port.host_port, address, owner)
+            })
+            .collect();
+        if owners.is_empty() {
+            return vec!["a port it publishes stayed in use".to_string()];
+        }
+        owners
+    }
+
+    /// The container by the app's name, looked up once and then answered from the cache until this
+    /// container is created, started, stopped or removed.
+    pub async fn get(&self) -> Result<Option<ContainerSummary>, String> {
+        if let Some(cached) = self.cached.lock().unwrap().clone() {
+            return Ok(cached);
+        }
+        self.lookup().await
     }
 
-    pub async fn get(&self) -> Option<ContainerSummary> {
+    /// Ask the daemon for the container, bypassing the cache.
+    async fn lookup(&self) -> Result<Option<ContainerSummary>, String> {
+        let container = match self.find(&self.container_name).await? {
+            Some(container) => Some(container),
+            None => self.migrate_legacy().await?,
+        };
+        *self.cached.lock().unwrap() = Some(container.clone());
+        Ok(container)
+    }
+
+    /// Drop the cached lookup, the next [`Container::get`] asks the daemon again.
+    fn forget(&self) {
+        *self.cached.lock().unwrap() = None;
+    }
+
+    async fn find(&self, container_name: &str) -> Result<Option<ContainerSummary>, String> {
+        // Docker matches names by substring, anchor it so `<app>-canary` is not mistaken for `<app>`
+        let name_filter = format!("^/{}$", container_name);
         let mut filters = HashMap::new();
-        filters.insert("name", vec![self.name]);
+        filters.insert("name", vec![name_filter.as_str()]);
 
         let options = Some(ListContainersOptions {
             all: true,
@@ -121,51 +1264,1100 @@ impl<'a> Container<'a> {
             limit: Some(1),
             ..Default::default()
         });
-        let containers = self.docker.list_containers(options).await.unwrap_or_else(|_| {
-            self.log.error("Failed to list containers");
-            std::process::exit(1);
+        let containers = self
+            .docker
+            .list_containers(options)
+            .await
+            .map_err(|_| "Failed to list containers")?;
+        Ok(containers.into_iter().next())
+    }
+
+    /// Containers were named without a prefix before, rename one of those to the prefixed name so
+    /// deployments made by older versions keep being managed.
+    async fn migrate_legacy(&self) -> Result<Option<ContainerSummary>, String> {
+        let Some(legacy_name) = self.container_name.strip_prefix(&self.config.container_prefix) else {
+            return Ok(None);
+        };
+        if legacy_name == self.container_name {
+            return Ok(None);
+        }
+        let Some(legacy) = self.find(legacy_name).await? else {
+            return Ok(None);
+        };
+        if !is_managed(&legacy) && !is_legacy(&legacy, self.name) {
+            return Ok(None);
+        }
+
+        let options = RenameContainerOptions {
+            name: self.container_name.as_str(),
+        };
+        self.docker.rename_container(legacy_name, options).await.map_err(|e| {
+            format!(
+                "Failed to rename container {} to {}: {}",
+                legacy_name, self.container_name, e
+            )
+        })?;
+        self.log
+            .step(&format!("Renamed container {} to {}", legacy_name, self.container_name));
+        self.find(&self.container_name).await
+    }
+
+    /// Host ports published by the containers of the app.
+    pub async fn published_ports(&self) -> Result<Vec<u16>, String> {
+        Ok(self
+            .list_app()
+            .await?
+            .iter()
+            .flat_map(|container| container.ports.iter().flatten())
+            .filter_map(|port| port.public_port)
+            .collect())
+    }
+
+    /// List every container that belongs to this app.
+    pub async fn list_app(&self) -> Result<Vec<ContainerSummary>, String> {
+        let app_filter = format!("{}={}", APP_LABEL, self.name);
+        let mut filters = HashMap::new();
+        filters.insert("label", vec![app_filter.as_str()]);
+
+        let options = Some(ListContainersOptions {
+            all: true,
+            filters,
+            ..Default::default()
+        });
+        self.docker
+            .list_containers(options)
+            .await
+            .map_err(|_| "Failed to list containers".to_string())
+    }
+
+    /// List the containers of every app managed by ruku.
+    pub async fn list_all(docker: &Docker) -> Result<Vec<ContainerSummary>, String> {
+        Container::try_list_all(docker)
+            .await
+            .map_err(|_| "Failed to list containers".to_string())
+    }
+
+    /// List the containers of every app managed by ruku, leaving a failure to the caller.
+    pub async fn try_list_all(docker: &Docker) -> Result<Vec<ContainerSummary>, Error> {
+        let mut filters = HashMap::new();
+        filters.insert("label", vec![APP_LABEL]);
+
+        let options = Some(ListContainersOptions {
+            all: true,
+            filters,
+            ..Default::default()
         });
-        containers.into_iter().next()
+        docker.list_containers(options).await
+    }
+
+    /// What this container should look like according to the app config.
+    pub fn spec(&self, image_name: String) -> Result<ContainerSpec, String> {
+        let default_resources = ResourcesConfig::default();
+        let resources = self.config.resources.as_ref().unwrap_or(&default_resources);
+        let variables = config_variables(self.name, self.config);
+        let mut labels = observability::labels(self.name, self.config);
+        labels.extend(render_labels(self.name, self.config)?);
+        labels.extend([
+            (APP_LABEL.to_string(), self.name.to_string()),
+            (ROLE_LABEL.to_string(), self.role.as_str().to_string()),
+            (VERSION_LABEL.to_string(), get_version(&self.config.version).to_string()),
+            (SCHEMA_LABEL.to_string(), SCHEMA_VERSION.to_string()),
+        ]);
+        labels.extend(test_labels());
+        if self.config.app_type != AppType::App {
+            labels.insert(TYPE_LABEL.to_string(), self.config.app_type.to_string());
+        }
+        if self.config.port.auto {
+            labels.insert(PORT_LABEL.to_string(), self.config.port.host_port.to_string());
+        }
+        if !self.config.domains.is_empty() {
+            labels.insert(DOMAINS_LABEL.to_string(), self.config.domains.join(","));
+        }
+        let files = render_files(self.config, &templates::variables(self.name, self.config, &self.links)?)
+            .map_err(|e| format!("Error rendering {}", e))?;
+        if let Some(digest) = files_digest(&files) {
+            labels.insert(FILES_LABEL.to_string(), digest);
+        }
+        let mut env = observability::env(self.name, self.config);
+        env.extend(self.timezone_env());
+        env.extend(self.links.iter().flat_map(Link::env));
+        env.extend(secrets::resolve(&self.config.secrets)?);
+        if self.config.preview_branch.is_some() {
+            labels.insert(PREVIEW_LABEL.to_string(), "true".to_string());
+            let preview_env = self.config.preview.iter().flat_map(|preview| preview.env.iter());
+            for (key, value) in preview_env {
+                let value =
+                    interpolate(value, &variables).map_err(|e| format!("Error in preview env {}: {}", key, e))?;
+                env.insert(key.clone(), value);
+            }
+        }
+
+        let publishes = self.config.network_mode.publishes_ports() && self.config.port.is_published() && self.publish;
+        let network = self.network();
+        Ok(ContainerSpec {
+            image: image_name,
+            ports: self
+                .config
+                .port
+                .protocols
+                .iter()
+                .filter(|_| publishes)
+                .map(|protocol| PortSpec {
+                    container_port: self.container_port(),
+                    protocol: protocol.to_string(),
+                    host_ip: self.host_ip(),
+                    host_port: self.host_port(),
+                })
+                .collect(),
+            env,
+            labels,
+            restart_policy: None,
+            networks: network
+                .iter()
+                .cloned()
+                .chain(
+                    self.links
+                        .iter()
+                        .filter(|_| network.is_some())
+                        .map(|link| get_network_name(&link.app)),
+                )
+                .collect(),
+            network_mode: network.clone().unwrap_or(self.config.network_mode.to_string()),
+            publish_all: false,
+            aliases: match self.role {
+                Role::Stable if network.is_some() && self.publish => vec![self.name.to_string()],
+                _ => vec![],
+            },
+            binds: self
+                .config
+                .volume_specs()
+                .iter()
+                .map(|volume| volume.to_bind(self.name))
+                .chain(self.timezone_binds())
+                .chain(self.template_dir.iter().flat_map(|dir| {
+                    let files = self.config.files.keys();
+                    self.config.templates.values().chain(files).map(|target| {
+                        let path = get_template_path(dir, target).display().to_string();
+                        format!("{}:{}:ro", to_daemon_path(&path), target)
+                    })
+                }))
+                .chain(
+                    self.static_root
+                        .iter()
+                        .map(|dir| format!("{}:{}:ro", to_daemon_path(&dir.display().to_string()), STATIC_ROOT)),
+                )
+                .collect(),
+            pids_limit: resources.pids_limit,
+            oom_score_adj: resources.oom_score_adj,
+            oom_kill_disable: resources.oom_kill_disable,
+            cpuset_cpus: resources.cpuset_cpus.clone(),
+            cpuset_mems: resources.cpuset_mems.clone(),
+            tty: self.config.tty,
+            open_stdin: self.config.stdin_open,
+            ipc_mode: self.config.ipc.clone(),
+            pid_mode: self.config.pid.clone(),
+            uts_mode: self.config.uts.clone(),
+            inherited: Inherited::default(),
+        }
+        .with_config_hash())
+    }
+
+    /// `TZ` and `LANG` from `timezone` and `locale`, a host timezone comes from the mounted files instead.
+    fn timezone_env(&self) -> BTreeMap<String, String> {
+        let timezone = self
+            .config
+            .timezone
+            .as_ref()
+            .filter(|timezone| *timezone != HOST_TIMEZONE);
+        let timezone = timezone.map(|timezone| ("TZ".to_string(), timezone.clone()));
+        let locale = self
+            .config
+            .locale
+            .as_ref()
+            .map(|locale| ("LANG".to_string(), locale.clone()));
+        timezone.into_iter().chain(locale).collect()
+    }
+
+    /// The timezone files of the host that exist, with `timezone: host`.
+    fn timezone_binds(&self) -> Vec<String> {
+        if self.config.timezone.as_deref() != Some(HOST_TIMEZONE) {
+            return vec![];
+        }
+        // A missing source would be created as a directory on the host
+        HOST_TIMEZONE_FILES
+            .iter()
+            .filter(|path| Path::new(path).exists())
+            .map(|path| format!("{}:{}:ro", path, path))
+            .collect()
+    }
+
+    /// The network the container is created on, none with network_mode `host` and `none`.
+    pub fn network(&self) -> Option<String> {
+        match &self.config.network_mode {
+            NetworkMode::Bridge => Some(get_network_name(self.name)),
+            NetworkMode::Custom(name) => Some(name.clone()),
+            NetworkMode::Host | NetworkMode::None => None,
+        }
     }
 
-    pub async fn create(&self, image_name: String) -> ContainerCreateResponse {
+    /// Processes running in the container and its pids limit, none when there is no limit.
+    pub async fn pids(&self) -> Option<(u64, Option<u64>)> {
+        let options = StatsOptions {
+            stream: false,
+            one_shot: true,
+        };
+        let stats = self
+            .docker
+            .stats(&self.container_name, Some(options))
+            .next()
+            .await?
+            .ok()?;
+        // Without a limit the daemon reports 0, the cgroup maximum or nothing depending on the version
+        let limit = stats.pids_stats.limit.filter(|limit| *limit > 0 && *limit != u64::MAX);
+        Some((stats.pids_stats.current?, limit))
+    }
+
+    /// The spec of the live container, normalized against its image.
+    pub async fn live_spec(&self) -> Option<ContainerSpec> {
+        let container = self.docker.inspect_container(&self.container_name, None).await.ok()?;
+        let image = match container.image.as_deref() {
+            Some(image_id) => self.docker.inspect_image(image_id).await.ok(),
+            None => None,
+        };
+        Some(ContainerSpec::from_inspect(&container, image.as_ref()))
+    }
+
+    /// Warn about the namespaces shared with the host and fail when a container whose namespace the app
+    /// joins does not exist.
+    async fn check_namespaces(&self) -> Result<(), String> {
+        for (field, mode) in self.config.namespaces() {
+            if mode == "host" {
+                let reach = match field {
+                    "ipc" => "the shared memory and semaphores of the host",
+                    "pid" => "every process of the host, to inspect and signal",
+                    _ => "the hostname of the host, to change",
+                };
+                self.log.warn(&format!(
+                    "{}: host gives {} {}, only run it for apps you trust with the host",
+                    field, self.name, reach
+                ));
+            }
+            if let Some(other) = mode.strip_prefix("container:") {
+                if self.docker.inspect_container(other, None).await.is_err() {
+                    return Err(format!(
+                        "{}: {} joins the namespace of container {}, which does not exist",
+                        field, mode, other
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Fail when `resources.cpuset_cpus` names CPUs the daemon host does not have. Memory nodes are left to
+    /// the daemon, which refuses missing ones when the container starts.
+    async fn check_cpuset(&self) -> Result<(), String> {
+        let Some(cpus) = self
+            .config
+            .resources
+            .as_ref()
+            .and_then(|resources| resources.cpuset_cpus.as_deref())
+        else {
+            return Ok(());
+        };
+        let Some(available) = self
+            .docker
+            .info()
+            .await
+            .ok()
+            .and_then(|info| info.ncpu)
+            .filter(|n| *n > 0)
+        else {
+            return Ok(());
+        };
+        cpuset::check_cpus(cpus, available)
+    }
+
+    pub async fn create(&self, image_name: String) -> Result<ContainerCreateResponse, String> {
+        guard(&format!("create {}", self.container_name)).map_err(|e| e.to_string())?;
+        self.use_image(&image_name).await?;
+        Platforms::new(self.log, self.docker)
+            .check(&image_name, self.config.allow_emulation)
+            .await?;
+        self.check_namespaces().await?;
+        self.check_cpuset().await?;
+        Shadowing::new(self.log, self.name, self.docker)
+            .check(
+                &image_name,
+                &self.config.volume_specs(),
+                &self.container_name,
+                self.config.strict_mounts,
+            )
+            .await?;
+        if self.config.create_host_paths.enabled {
+            let host_paths = HostPaths::new(self.log, &self.config.create_host_paths);
+            let image_user = self
+                .image_defaults
+                .lock()
+                .unwrap()
+                .as_ref()
+                .and_then(|defaults| defaults.user.clone());
+            host_paths.ensure(&self.config.volume_specs(), host_paths.owner(image_user.as_deref()))?;
+        }
+        self.own_templates(&image_name).await?;
         let create_options = CreateContainerOptions {
-            name: self.name,
+            name: self.container_name.as_str(),
             platform: None,
         };
 
-        let exposed_port = format!("{}/tcp", self.config.port);
-        let mut host_config = HostConfig::default();
-        let mut port_bindings = PortMap::new();
-        port_bindings.insert(
-            exposed_port.clone(),
-            Some(vec![PortBinding {
-                host_ip: None,
-                host_port: Some(self.config.port.to_string()),
-            }]),
+        Volumes::new(self.log, self.name, self.docker)
+            .ensure(&self.config.all_volume_specs())
+            .await?;
+        let networks = Networks::new(self.log, self.docker);
+        let links: &[Link] = match &self.config.network_mode {
+            NetworkMode::Bridge => {
+                networks.ensure(self.name, self.config.internal).await?;
+                if self.config.internal {
+                    // Docker has no route from the host into an internal network
+                    self.log
+                        .warn("The app network is internal, its published port is not reachable from the host");
+                }
+                &self.links
+            }
+            NetworkMode::Custom(name) => {
+                networks.require(name).await?;
+                &self.links
+            }
+            mode => {
+                if mode == &NetworkMode::Host {
+                    self.log.step(&format!(
+                        "Skipping port publishing, with network_mode host the app listens on port {} of the host",
+                        self.container_port()
+                    ));
+                } else {
+                    self.log
+                        .step("Skipping port publishing, network_mode none leaves the container without a network");
+                }
+                if !self.links.is_empty() {
+                    self.log.warn(&format!("Links are ignored with network_mode {}", mode));
+                }
+                &[]
+            }
+        };
+        for link in links {
+            networks.ensure(&link.app, false).await?;
+        }
+        let mut create_container_config = self.spec(image_name.clone())?.to_create_config();
+        if let (Some(message), Some(labels)) = (&self.deploy_message, create_container_config.labels.as_mut()) {
+            labels.insert(DEPLOY_MESSAGE_LABEL.to_string(), label_value(message));
+        }
+
+        // Create the container, a name conflict means another container appeared since we last looked
+        let container = match self.try_create(&create_options, create_container_config.clone()).await {
+            Err(Error::DockerResponseServerError { status_code: 409, .. }) => {
+                self.log.warn("Container name is already in use, checking again");
+                if let Some(existing) = self.get().await? {
+                    self.clear(&existing).await?;
+                }
+                self.try_create(&create_options, create_container_config).await
+            }
+            result => result,
+        };
+        let container = match container {
+            Ok(container) => container,
+            // A typo in the configured version surfaces here as a missing image
+            Err(e) if is_not_found(&e) => {
+                self.log.debug(&format!("Docker daemon: {}", e));
+                let image = Image::new(self.log, self.docker);
+                return Err(format!(
+                    "Failed to create container: {}",
+                    image.describe_missing(&image_name).await
+                ));
+            }
+            Err(e) => {
+                let explained = daemon_error::report(self.log, &e, self.name, self.config);
+                return Err(format!("Failed to create container: {}", explained));
+            }
+        };
+        self.log.step(&format!("Created container with id: {}", container.id));
+
+        // Docker only attaches one network on create, the linked apps' networks are joined afterwards
+        for link in links {
+            networks.connect(&self.container_name, &link.app, self.name).await?;
+        }
+        Ok(container)
+    }
+
+    async fn try_create(
+        &self,
+        options: &CreateContainerOptions<&str>,
+        config: bollard::container::Config<String>,
+    ) -> Result<ContainerCreateResponse, Error> {
+        self.forget();
+        self.docker.create_container(Some(options.clone()), config).await
+    }
+}
+
+/// Whether the host port can be bound, SCTP can't be probed without privileges and is left to the daemon.
+/// A port on every interface is tried on both stacks, a stack the host doesn't have doesn't count.
+pub fn is_port_free(port: &PortSpec) -> bool {
+    let bind = |ip: IpAddr| match port.protocol.as_str() {
+        "tcp" => TcpListener::bind((ip, port.host_port)).map(drop),
+        "udp" => UdpSocket::bind((ip, port.host_port)).map(drop),
+        _ => Ok(()),
+    };
+    match port.host_ip.as_deref().and_then(|ip| ip.parse().ok()) {
+        Some(ip) => bind(ip).is_ok(),
+        None => [IpAddr::V4(Ipv4Addr::UNSPECIFIED), IpAddr::V6(Ipv6Addr::UNSPECIFIED)]
+            .into_iter()
+            .all(|ip| match bind(ip) {
+                Ok(()) => true,
+                Err(e) => e.kind() != ErrorKind::AddrInUse,
+            }),
+    }
+}
+
+/// The address a port published on `ip` is reached on from this host, the loopback of its stack for one
+/// on every interface.
+pub fn local_address(ip: &str) -> String {
+    match ip.trim_start_matches('[').trim_end_matches(']') {
+        "" | "0.0.0.0" => Ipv4Addr::LOCALHOST.to_string(),
+        "::" => Ipv6Addr::LOCALHOST.to_string(),
+        ip => ip.to_string(),
+    }
+}
+
+/// The ports the daemon actually published `summary` on, as `ip:host->container/protocol`. A port bound on
+/// every interface of both stacks is shown without an address, one on a single stack with its address.
+pub fn describe_bindings(summary: &ContainerSummary) -> Vec<String> {
+    let mut bindings: BTreeMap<(u16, u16, String), Vec<String>> = BTreeMap::new();
+    for port in summary.ports.iter().flatten() {
+        let Some(public) = port.public_port else {
+            continue;
+        };
+        let protocol = port.typ.map(|typ| typ.to_string()).unwrap_or("tcp".to_string());
+        let ip = port.ip.clone().unwrap_or_default();
+        bindings
+            .entry((public, port.private_port, protocol))
+            .or_default()
+            .push(ip);
+    }
+    bindings
+        .into_iter()
+        .flat_map(|((public, private, protocol), mut ips)| {
+            ips.sort();
+            ips.dedup();
+            let both_stacks = ips == ["0.0.0.0", "::"];
+            let ips = if both_stacks { vec![String::new()] } else { ips };
+            ips.into_iter().map(move |ip| match ip.as_str() {
+                "" => format!("{}->{}/{}", public, private, protocol),
+                ip if ip.contains(':') => format!("[{}]:{}->{}/{}", ip, public, private, protocol),
+                ip => format!("{}:{}->{}/{}", ip, public, private, protocol),
+            })
+        })
+        .collect()
+}
+
+/// The host addresses the daemon bound `host_port` of `container_port/tcp` on, from an inspect.
+fn bound_addresses(container: &ContainerInspectResponse, container_port: u16, host_port: u16) -> Vec<String> {
+    let key = format!("{}/tcp", container_port);
+    container
+        .network_settings
+        .as_ref()
+        .and_then(|settings| settings.ports.as_ref())
+        .and_then(|ports| ports.get(&key).cloned().flatten())
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|binding| binding.host_port.as_deref() == Some(host_port.to_string().as_str()))
+        .map(|binding| binding.host_ip.unwrap_or_default())
+        .collect()
+}
+
+/// Whether starting a container failed on binding a host port, as the daemon words it for a port held by
+/// a process or by another container.
+fn is_bind_failure(error: &Error) -> bool {
+    match error {
+        Error::DockerResponseServerError { message, .. } => {
+            message.contains("address already in use") || message.contains("port is already allocated")
+        }
+        _ => false,
+    }
+}
+
+/// Whether the container publishes the host port, a binding on all interfaces overlaps every address.
+fn publishes(container: &ContainerSummary, port: &PortSpec) -> bool {
+    container.ports.iter().flatten().any(|published| {
+        let protocol = published.typ.map(|typ| typ.to_string()).unwrap_or("tcp".to_string());
+        let ip = published.ip.as_deref().unwrap_or("0.0.0.0");
+        let all_interfaces = ip == "0.0.0.0" || ip == "::";
+        published.public_port == Some(port.host_port)
+            && protocol == port.protocol
+            && (all_interfaces || port.host_ip.is_none() || port.host_ip.as_deref() == Some(ip))
+    })
+}
+
+/// Version a container was deployed with, from its `ruku.version` label or else its image tag.
+pub fn deployed_version(container: &ContainerSummary) -> Option<String> {
+    container
+        .labels
+        .as_ref()
+        .and_then(|labels| labels.get(VERSION_LABEL).cloned())
+        .or_else(|| container.image.as_deref().and_then(get_image_tag))
+}
+
+/// A container as listed in confirmation prompts, e.g. `container app (3f2a9c1b7d4e, running)`.
+pub fn describe_container(container: &ContainerSummary) -> String {
+    let id = container.id.as_deref().unwrap_or("unknown");
+    format!(
+        "container {} ({}, {})",
+        get_container_name(container).unwrap_or_default(),
+        &id[..id.len().min(12)],
+        container.state.as_deref().unwrap_or("unknown")
+    )
+}
+
+/// Whether ruku created the container, going by its `ruku.app` label.
+pub fn is_managed(container: &ContainerSummary) -> bool {
+    container
+        .labels
+        .as_ref()
+        .is_some_and(|labels| labels.contains_key(APP_LABEL))
+}
+
+/// The schema a container was created with: 0 before the labels, 1 with labels but before the schema
+/// label, the label value after that.
+pub fn schema_version(container: &ContainerSummary) -> u32 {
+    let labels = container.labels.as_ref();
+    match labels.and_then(|labels| labels.get(SCHEMA_LABEL)) {
+        Some(schema) => schema.parse().unwrap_or(SCHEMA_VERSION),
+        None if is_managed(container) => 1,
+        None => 0,
+    }
+}
+
+/// The config creating the inspected container again as it is, with `labels` added. The volumes it
+/// mounts, anonymous ones too, are bound by name so their data stays with it, and the network it was
+/// created on keeps its aliases and addresses.
+fn recreate_config(
+    inspect: &ContainerInspectResponse,
+    labels: BTreeMap<String, String>,
+) -> bollard::container::Config<String> {
+    let mut config: bollard::container::Config<String> = inspect.config.clone().unwrap_or_default().into();
+    config.labels.get_or_insert_with(HashMap::new).extend(labels);
+    // Docker names the host after the container id unless it was given one
+    let id = inspect.id.as_deref().unwrap_or_default();
+    if config
+        .hostname
+        .as_deref()
+        .is_some_and(|hostname| id.starts_with(hostname))
+    {
+        config.hostname = None;
+    }
+
+    let mut host_config = inspect.host_config.clone().unwrap_or_default();
+    let mut mounted: Vec<String> = host_config
+        .binds
+        .iter()
+        .flatten()
+        .filter_map(|bind| bind.split(':').nth(1).map(str::to_string))
+        .collect();
+    mounted.extend(
+        host_config
+            .mounts
+            .iter()
+            .flatten()
+            .filter_map(|mount| mount.target.clone()),
+    );
+    for mount in inspect.mounts.iter().flatten() {
+        let (Some(MountPointTypeEnum::VOLUME), Some(name), Some(destination)) =
+            (mount.typ, mount.name.as_deref(), mount.destination.as_deref())
+        else {
+            continue;
+        };
+        if !mounted.iter().any(|mounted| mounted == destination) {
+            let read_only = if mount.rw == Some(false) { ":ro" } else { "" };
+            let binds = host_config.binds.get_or_insert_with(Vec::new);
+            binds.push(format!("{}:{}{}", name, destination, read_only));
+        }
+    }
+
+    let networks = inspect
+        .network_settings
+        .as_ref()
+        .and_then(|settings| settings.networks.as_ref());
+    let primary = host_config.network_mode.clone().unwrap_or_default();
+    config.networking_config = networks
+        .and_then(|networks| networks.get(&primary))
+        .map(|endpoint| NetworkingConfig {
+            endpoints_config: HashMap::from([(primary.clone(), endpoint_config(endpoint))]),
+        });
+    config.host_config = Some(host_config);
+    config
+}
+
+/// The networks the inspected container was joined to after it was created, with their endpoints.
+fn extra_networks(inspect: &ContainerInspectResponse) -> Vec<(String, EndpointSettings)> {
+    let primary = inspect
+        .host_config
+        .as_ref()
+        .and_then(|host_config| host_config.network_mode.as_deref());
+    let networks = inspect
+        .network_settings
+        .as_ref()
+        .and_then(|settings| settings.networks.as_ref());
+    let mut extra: Vec<(String, EndpointSettings)> = networks
+        .into_iter()
+        .flatten()
+        .filter(|(network, _)| Some(network.as_str()) != primary)
+        .map(|(network, endpoint)| (network.clone(), endpoint_config(endpoint)))
+        .collect();
+    extra.sort_by(|a, b| a.0.cmp(&b.0));
+    extra
+}
+
+/// What a container is joined to a network again with: its aliases and the addresses it was given.
+fn endpoint_config(endpoint: &EndpointSettings) -> EndpointSettings {
+    EndpointSettings {
+        aliases: endpoint.aliases.clone(),
+        ipam_config: endpoint.ipam_config.clone(),
+        ..Default::default()
+    }
+}
+
+/// Whether the container predates the ruku labels: it carries no labels of ours but runs an image ruku
+/// built for the app, which is always named `<app>:<version>`.
+fn is_legacy(container: &ContainerSummary, app: &str) -> bool {
+    !is_managed(container)
+        && container
+            .image
+            .as_deref()
+            .is_some_and(|image| image.split_once(':').is_some_and(|(name, _)| name == app))
+}
+
+/// Name of a container without the leading slash Docker reports.
+pub fn get_container_name(container: &ContainerSummary) -> Option<String> {
+    container
+        .names
+        .as_ref()
+        .and_then(|names| names.first())
+        .map(|name| name.trim_start_matches('/').to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::fake_daemon::{fake_daemon, Requests};
+
+    fn lists(requests: &Requests) -> usize {
+        requests.count("GET /containers/json")
+    }
+
+    #[tokio::test]
+    async fn lookups_are_cached_until_the_container_changes() {
+        let (docker, requests) = fake_daemon(|method, path| match (method, path) {
+            ("GET", path) if path.contains("/containers/json") => (
+                200,
+                r#"[{"Id":"abc","Names":["/ruku-shop"],"Labels":{"ruku.app":"shop"},"State":"running"}]"#.to_string(),
+            ),
+            _ => (204, String::new()),
+        })
+        .await;
+        let config: RukuConfig = serde_yaml::from_str("version: '1.0'").unwrap();
+        let log = Logger::new();
+        let container = Container::new(&log, "shop", &docker, &config);
+
+        assert_eq!(
+            container.get().await.unwrap().and_then(|summary| summary.id).as_deref(),
+            Some("abc")
         );
-        host_config.port_bindings = Some(port_bindings);
+        assert!(container.get().await.unwrap().is_some());
+        assert_eq!(lists(&requests), 1);
+
+        container.suspend().await.unwrap();
+        assert!(container.get().await.unwrap().is_some());
+        assert_eq!(lists(&requests), 2);
 
-        let mut exposed_ports_map: HashMap<String, HashMap<(), ()>> = HashMap::new();
-        exposed_ports_map.insert(exposed_port, HashMap::new());
+        // A copy for another role starts with nothing cached
+        container.canary().get().await.unwrap();
+        assert_eq!(lists(&requests), 3);
+        assert_eq!(requests.count("POST /containers/ruku-shop/stop"), 1);
+    }
+
+    fn inspect(json: &str) -> ContainerInspectResponse {
+        serde_json::from_str(json).unwrap()
+    }
 
-        let create_container_config = bollard::container::Config {
-            image: Some(image_name),
-            host_config: Some(host_config),
-            exposed_ports: Some(exposed_ports_map),
+    #[test]
+    fn condition_is_read_from_the_inspect() {
+        let unhealthy = Condition::from_inspect(&inspect(
+            r#"{"State": {"Status": "running", "Health": {"Status": "unhealthy", "FailingStreak": 12}},
+                "Config": {"Healthcheck": {"Interval": 30000000000}}, "RestartCount": 14,
+                "HostConfig": {"RestartPolicy": {"Name": "no"}}}"#,
+        ));
+        assert!(unhealthy.is_unhealthy());
+        assert_eq!(unhealthy.unhealthy_for, Some(Duration::from_secs(360)));
+        assert_eq!(unhealthy.restart_policy, None);
+        assert!(!unhealthy.is_crash_looping());
+        assert_eq!(unhealthy.describe(), "container unhealthy for 6m, 14 restarts");
+
+        let looping = Condition::from_inspect(&inspect(
+            r#"{"State": {"Status": "running"}, "RestartCount": 3,
+                "HostConfig": {"RestartPolicy": {"Name": "always"}}}"#,
+        ));
+        assert!(looping.is_crash_looping());
+        assert_eq!(looping.describe(), "container running, 3 restarts");
+
+        let exited = Condition::from_inspect(&inspect(
+            r#"{"State": {"Status": "exited", "ExitCode": 137}, "RestartCount": 1}"#,
+        ));
+        assert!(!exited.is_crash_looping());
+        assert_eq!(exited.describe(), "container exited with code 137, 1 restart");
+
+        let restarting = Condition::from_inspect(&inspect(r#"{"State": {"Status": "restarting"}}"#));
+        assert!(restarting.is_crash_looping());
+        assert_eq!(restarting.describe(), "container restarting in a crash loop");
+        assert_eq!(
+            Condition::from_inspect(&inspect("{}")).describe(),
+            "container in no state Docker reported"
+        );
+    }
+
+    async fn clear_requests(respond: fn(&str, &str) -> (u16, String), state: &str) -> Vec<String> {
+        let (docker, requests) = fake_daemon(respond).await;
+        let config: RukuConfig = serde_yaml::from_str("version: '1.0'").unwrap();
+        let log = Logger::new();
+        let summary = ContainerSummary {
+            id: Some("abc".to_string()),
+            names: Some(vec!["/ruku-shop".to_string()]),
+            labels: Some(HashMap::from([(APP_LABEL.to_string(), "shop".to_string())])),
+            state: Some(state.to_string()),
             ..Default::default()
         };
-
-        // Create the container
-        let container = self
-            .docker
-            .create_container(Some(create_options), create_container_config)
+        Container::new(&log, "shop", &docker, &config)
+            .clear(&summary)
             .await
-            .unwrap_or_else(|_| {
-                self.log.error("Failed to create container");
-                std::process::exit(1);
-            });
-        self.log.step(&format!("Created container with id: {}", container.id));
-        container
+            .unwrap();
+        requests.all()
+    }
+
+    #[tokio::test]
+    async fn crash_looping_containers_lose_their_restart_policy_first() {
+        let requests = clear_requests(
+            |method, path| match method {
+                "GET" if path.contains("/containers/abc/json") => (
+                    200,
+                    r#"{"State": {"Status": "restarting"}, "RestartCount": 7,
+                        "HostConfig": {"RestartPolicy": {"Name": "always"}}}"#
+                        .to_string(),
+                ),
+                "POST" if path.contains("/update") => (200, r#"{"Warnings": []}"#.to_string()),
+                _ => (204, String::new()),
+            },
+            "restarting",
+        )
+        .await;
+        assert_eq!(
+            requests,
+            [
+                "GET /containers/abc/json",
+                "POST /containers/abc/update",
+                "POST /containers/abc/stop",
+                "DELETE /containers/abc"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn stopped_containers_are_only_removed() {
+        let requests = clear_requests(
+            |method, path| match method {
+                "GET" if path.contains("/containers/abc/json") => {
+                    (200, r#"{"State": {"Status": "exited", "ExitCode": 1}}"#.to_string())
+                }
+                _ => (204, String::new()),
+            },
+            "exited",
+        )
+        .await;
+        assert_eq!(requests, ["GET /containers/abc/json", "DELETE /containers/abc"]);
+
+        // Without an inspect the listed state decides
+        let requests = clear_requests(
+            |method, _| match method {
+                "GET" => (500, r#"{"message": "boom"}"#.to_string()),
+                _ => (204, String::new()),
+            },
+            "running",
+        )
+        .await;
+        assert_eq!(
+            requests,
+            [
+                "GET /containers/abc/json",
+                "POST /containers/abc/stop",
+                "DELETE /containers/abc"
+            ]
+        );
+    }
+
+    const ADOPTED_INSPECT: &str = r#"{"Id": "abc123", "Image": "sha256:1111",
+        "Config": {"Image": "nginx:1.25", "Hostname": "abc", "Env": ["MODE=prod"]},
+        "HostConfig": {"NetworkMode": "bridge", "Binds": ["/srv/site:/usr/share/nginx/html:ro"]},
+        "Mounts": [
+            {"Type": "bind", "Source": "/srv/site", "Destination": "/usr/share/nginx/html", "RW": false},
+            {"Type": "volume", "Name": "f00d", "Destination": "/var/cache/nginx", "RW": true}
+        ],
+        "NetworkSettings": {"Networks": {
+            "bridge": {"IPAddress": "172.17.0.2"},
+            "backend": {"Aliases": ["web"], "IPAddress": "172.20.0.3"}
+        }}}"#;
+
+    #[test]
+    fn adopted_containers_are_recreated_as_they_are() {
+        let inspect = inspect(ADOPTED_INSPECT);
+        let labels = BTreeMap::from([(APP_LABEL.to_string(), "shop".to_string())]);
+        let config = recreate_config(&inspect, labels);
+        assert_eq!(config.image.as_deref(), Some("nginx:1.25"));
+        assert_eq!(config.env, Some(vec!["MODE=prod".to_string()]));
+        assert_eq!(config.labels.unwrap()[APP_LABEL], "shop");
+        // The hostname Docker made up from the id is made up again
+        assert_eq!(config.hostname, None);
+        let host_config = config.host_config.unwrap();
+        assert_eq!(
+            host_config.binds.unwrap(),
+            ["/srv/site:/usr/share/nginx/html:ro", "f00d:/var/cache/nginx"]
+        );
+        let endpoints = config.networking_config.unwrap().endpoints_config;
+        assert_eq!(endpoints.keys().collect::<Vec<_>>(), ["bridge"]);
+
+        let extra = extra_networks(&inspect);
+        assert_eq!(extra.len(), 1);
+        assert_eq!(extra[0].0, "backend");
+        assert_eq!(extra[0].1.aliases, Some(vec!["web".to_string()]));
+    }
+
+    async fn adopt_requests(respond: fn(&str, &str) -> (u16, String)) -> (Result<bool, String>, Vec<String>) {
+        let (docker, requests) = fake_daemon(respond).await;
+        let config: RukuConfig = serde_yaml::from_str("version: '1.0'").unwrap();
+        let log = Logger::new();
+        let adopted = Container::new(&log, "shop", &docker, &config)
+            .with_takeover(Takeover::Adopt)
+            .adopt()
+            .await;
+        (adopted.map(|adopted| adopted.is_some()), requests.all())
+    }
+
+    fn adopt_daemon(method: &str, path: &str) -> (u16, String) {
+        match (method, path) {
+            ("GET", "/containers/json") => (
+                200,
+                r#"[{"Id": "abc123", "Names": ["/ruku-shop"], "Image": "nginx:1.25", "State": "running"}]"#.to_string(),
+            ),
+            ("GET", "/containers/abc123/json") => (200, ADOPTED_INSPECT.to_string()),
+            ("GET", "/images/nginx:1.25/json") => (200, r#"{"Id": "sha256:1111"}"#.to_string()),
+            ("POST", "/containers/create") => (201, r#"{"Id": "def456", "Warnings": []}"#.to_string()),
+            _ => (204, String::new()),
+        }
+    }
+
+    #[tokio::test]
+    async fn adopting_recreates_the_container_with_the_labels() {
+        let (adopted, requests) = adopt_requests(adopt_daemon).await;
+        assert_eq!(adopted, Ok(true));
+        assert_eq!(
+            requests,
+            [
+                "GET /containers/json",
+                "GET /containers/abc123/json",
+                "GET /images/nginx:1.25/json",
+                "POST /containers/abc123/stop",
+                "POST /containers/abc123/rename",
+                "POST /containers/create",
+                "POST /networks/backend/connect",
+                "POST /containers/def456/start",
+                "DELETE /containers/abc123",
+                "GET /containers/json"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn a_failed_adoption_puts_the_container_back() {
+        let (adopted, requests) = adopt_requests(|method, path| match (method, path) {
+            ("POST", "/containers/def456/start") => (500, r#"{"message": "no such device"}"#.to_string()),
+            _ => adopt_daemon(method, path),
+        })
+        .await;
+        assert!(adopted.is_err());
+        assert_eq!(
+            requests[5..],
+            [
+                "POST /containers/create",
+                "POST /networks/backend/connect",
+                "POST /containers/def456/start",
+                "DELETE /containers/ruku-shop",
+                "POST /containers/abc123/rename",
+                "POST /containers/abc123/start"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn containers_ruku_created_are_not_adopted() {
+        let (adopted, requests) = adopt_requests(|method, path| match (method, path) {
+            ("GET", "/containers/json") => (
+                200,
+                r#"[{"Id": "abc123", "Names": ["/ruku-shop"], "Labels": {"ruku.app": "shop"}}]"#.to_string(),
+            ),
+            _ => adopt_daemon(method, path),
+        })
+        .await;
+        assert_eq!(adopted, Ok(false));
+        assert_eq!(requests, ["GET /containers/json"]);
+    }
+
+    fn server_error(message: &str) -> Error {
+        Error::DockerResponseServerError {
+            status_code: 500,
+            message: message.to_string(),
+        }
+    }
+
+    #[test]
+    fn bind_failures_are_told_apart() {
+        assert!(is_bind_failure(&server_error(
+            "driver failed programming external connectivity: Bind for 0.0.0.0:8080 failed: port is already allocated"
+        )));
+        assert!(is_bind_failure(&server_error(
+            "listen tcp4 127.0.0.1:8080: bind: address already in use"
+        )));
+        assert!(!is_bind_failure(&server_error("OCI runtime create failed")));
+    }
+
+    #[test]
+    fn publishing_on_all_interfaces_overlaps_every_address() {
+        let summary: ContainerSummary = serde_json::from_str(
+            r#"{"Ports": [{"IP": "0.0.0.0", "PrivatePort": 80, "PublicPort": 8080, "Type": "tcp"},
+                          {"IP": "127.0.0.1", "PrivatePort": 53, "PublicPort": 5353, "Type": "udp"}]}"#,
+        )
+        .unwrap();
+        let port = |host_ip: Option<&str>, host_port, protocol: &str| PortSpec {
+            container_port: 80,
+            protocol: protocol.to_string(),
+            host_ip: host_ip.map(str::to_string),
+            host_port,
+        };
+        assert!(publishes(&summary, &port(None, 8080, "tcp")));
+        assert!(publishes(&summary, &port(Some("10.0.0.5"), 8080, "tcp")));
+        assert!(!publishes(&summary, &port(None, 8080, "udp")));
+        assert!(publishes(&summary, &port(Some("127.0.0.1"), 5353, "udp")));
+        assert!(!publishes(&summary, &port(Some("10.0.0.5"), 5353, "udp")));
+    }
+
+    static START_FAILURES: AtomicUsize = AtomicUsize::new(0);
+
+    #[tokio::test]
+    async fn a_start_is_retried_until_the_port_is_released() {
+        let (docker, requests) = fake_daemon(|method, _| match method {
+            "POST" if START_FAILURES.fetch_sub(1, Ordering::SeqCst) > 0 => (
+                500,
+                r#"{"message": "Bind for 0.0.0.0:8080 failed: port is already allocated"}"#.to_string(),
+            ),
+            _ => (204, String::new()),
+        })
+        .await;
+        START_FAILURES.store(2, Ordering::SeqCst);
+        let config: RukuConfig = serde_yaml::from_str("version: '1.0'").unwrap();
+        let log = Logger::new();
+        Container::new(&log, "shop", &docker, &config).resume().await.unwrap();
+        assert_eq!(requests.count("POST /containers/ruku-shop/start"), 3);
+    }
+
+    /// The port the fake daemon reports the containers publish.
+    static HELD_PORT: AtomicUsize = AtomicUsize::new(0);
+
+    #[tokio::test]
+    async fn the_holder_of_a_port_is_named() {
+        let (docker, _) = fake_daemon(|_, _| {
+            let published = |ip: &str| {
+                format!(
+                    r#"[{{"IP": "{}", "PrivatePort": 80, "PublicPort": {}, "Type": "tcp"}}]"#,
+                    ip,
+                    HELD_PORT.load(Ordering::SeqCst)
+                )
+            };
+            let containers = format!(
+                r#"[{{"Id": "new", "Names": ["/ruku-shop"], "Ports": {}}},
+                    {{"Id": "old", "Names": ["/ruku-shop-previous"], "Ports": {}}}]"#,
+                published("127.0.0.1"),
+                published("0.0.0.0")
+            );
+            (200, containers)
+        })
+        .await;
+        let held = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = held.local_addr().unwrap().port();
+        HELD_PORT.store(port as usize, Ordering::SeqCst);
+        let yaml = format!("version: '1.0'\nport: 127.0.0.1:{}:80", port);
+        let config: RukuConfig = serde_yaml::from_str(&yaml).unwrap();
+        let log = Logger::new();
+        let container = Container::new(&log, "shop", &docker, &config);
+        assert_eq!(
+            container.port_owners("new").await,
+            [format!(
+                "port {} on 127.0.0.1 is held by container ruku-shop-previous",
+                port
+            )]
+        );
+        drop(held);
+        assert_eq!(
+            container.port_owners("new").await,
+            ["a port it publishes stayed in use"]
+        );
+    }
+
+    fn config_hash(yaml: &str) -> String {
+        let config: RukuConfig = serde_yaml::from_str(yaml).unwrap();
+        // Never connected, the spec is built from the config alone
+        let docker = Docker::connect_with_http("http://127.0.0.1:1", 1, bollard::API_DEFAULT_VERSION).unwrap();
+        let log = Logger::new();
+        Container::new(&log, "shop", &docker, &config)
+            .spec("shop:1.0".to_string())
+            .unwrap()
+            .config_hash()
+    }
+
+    #[test]
+    fn config_hash_is_stable_across_key_order_and_defaults() {
+        let yaml = "
+version: '1.0'
+labels:
+  team: web
+  tier: front
+volumes:
+  - data:/data
+  - /srv/shop/uploads:/app/uploads
+";
+        let minimal = config_hash(yaml);
+        let reordered = config_hash(
+            "
+volumes:
+  - /srv/shop/uploads:/app/uploads
+  - data:/data
+network_mode: bridge
+tty: false
+stdin_open: false
+internal: false
+type: app
+labels:
+  tier: front
+  team: web
+version: '1.0'
+",
+        );
+        assert_eq!(minimal, reordered);
+        assert_ne!(minimal, config_hash(&yaml.replace("team: web", "team: api")));
     }
 }
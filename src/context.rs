@@ -0,0 +1,459 @@
+use std::cell::Cell;
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::UNIX_EPOCH;
+
+use flate2::write::GzEncoder;
+
+use crate::sha256::sha256_hex;
+
+/// Ignore files read from the project root, later files can override earlier ones.
+const IGNORE_FILES: [&str; 2] = [".dockerignore", ".rukuignore"];
+
+/// Files the daemon needs even when an ignore pattern matches them.
+const ALWAYS_INCLUDED: [&str; 2] = ["Dockerfile", ".dockerignore"];
+
+/// First Docker release whose CLI and daemon recognize zstd build contexts.
+const ZSTD_ENGINE_MAJOR: u32 = 23;
+
+/// How the build context is compressed on its way to the daemon.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compression {
+    Gzip,
+    Zstd,
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Compression::Gzip => write!(f, "gzip"),
+            Compression::Zstd => write!(f, "zstd"),
+        }
+    }
+}
+
+impl Compression {
+    /// zstd when the docker CLI and the daemon of the `client` and `server` versions both recognize it,
+    /// gzip when either is older or unknown.
+    pub fn for_docker(client: Option<&str>, server: Option<&str>) -> Compression {
+        let recognizes_zstd = |version: Option<&str>| {
+            version
+                .and_then(|version| version.split('.').next())
+                .and_then(|major| major.parse::<u32>().ok())
+                .is_some_and(|major| major >= ZSTD_ENGINE_MAJOR)
+        };
+        match recognizes_zstd(client) && recognizes_zstd(server) {
+            true => Compression::Zstd,
+            false => Compression::Gzip,
+        }
+    }
+}
+
+/// Counts the bytes written through it.
+struct Counting<W> {
+    inner: W,
+    count: Rc<Cell<u64>>,
+}
+
+impl<W: Write> Write for Counting<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.count.set(self.count.get() + written as u64);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+enum Encoder<W: Write> {
+    Gzip(GzEncoder<W>),
+    Zstd(zstd::Encoder<'static, W>),
+}
+
+impl<W: Write> Encoder<W> {
+    fn new(out: W, compression: Compression) -> io::Result<Encoder<W>> {
+        Ok(match compression {
+            Compression::Gzip => Encoder::Gzip(GzEncoder::new(out, flate2::Compression::fast())),
+            Compression::Zstd => Encoder::Zstd(zstd::Encoder::new(out, 0)?),
+        })
+    }
+
+    fn finish(self) -> io::Result<W> {
+        match self {
+            Encoder::Gzip(encoder) => encoder.finish(),
+            Encoder::Zstd(encoder) => encoder.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Encoder::Gzip(encoder) => encoder.write(buf),
+            Encoder::Zstd(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Encoder::Gzip(encoder) => encoder.flush(),
+            Encoder::Zstd(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// A single `.dockerignore` rule.
+#[derive(Debug, Clone)]
+struct Pattern {
+    segments: Vec<String>,
+    exclusion: bool,
+    cleaned: String,
+}
+
+impl Pattern {
+    fn parse(line: &str) -> Option<Pattern> {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+        let (exclusion, pattern) = match line.strip_prefix('!') {
+            Some(rest) => (true, rest.trim()),
+            None => (false, line),
+        };
+        let cleaned = clean(pattern);
+        if cleaned.is_empty() {
+            return None;
+        }
+        Some(Pattern {
+            segments: cleaned.split('/').map(str::to_string).collect(),
+            exclusion,
+            cleaned,
+        })
+    }
+
+    /// Whether the pattern matches the path or one of its parent directories, a matched directory takes
+    /// everything inside it along.
+    fn matches(&self, path: &str) -> bool {
+        let parts: Vec<&str> = path.split('/').collect();
+        (1..=parts.len()).any(|len| match_segments(&self.segments, &parts[..len]))
+    }
+}
+
+/// Normalize a pattern the way Docker does: no leading or trailing slashes, no `.` segments and `..` folded.
+fn clean(pattern: &str) -> String {
+    let mut segments: Vec<&str> = vec![];
+    for segment in pattern.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            segment => segments.push(segment),
+        }
+    }
+    segments.join("/")
+}
+
+fn match_segments(pattern: &[String], path: &[&str]) -> bool {
+    match pattern.split_first() {
+        None => path.is_empty(),
+        // `**` matches any number of directories, including none
+        Some((first, rest)) if first == "**" => (0..=path.len()).any(|skip| match_segments(rest, &path[skip..])),
+        Some((first, rest)) => match path.split_first() {
+            Some((segment, path_rest)) => {
+                match_glob(first.as_bytes(), segment.as_bytes()) && match_segments(rest, path_rest)
+            }
+            None => false,
+        },
+    }
+}
+
+/// Match a single path segment against `*`, `?`, `[...]` and `\` escapes, like Go's `filepath.Match`.
+fn match_glob(pattern: &[u8], name: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some((b'*', rest)) => (0..=name.len()).any(|skip| match_glob(rest, &name[skip..])),
+        Some((b'?', rest)) => !name.is_empty() && match_glob(rest, &name[1..]),
+        Some((b'[', rest)) => {
+            let Some((&c, name_rest)) = name.split_first() else {
+                return false;
+            };
+            let (negated, mut class) = match rest.first() {
+                Some(b'^') | Some(b'!') => (true, &rest[1..]),
+                _ => (false, rest),
+            };
+            let mut matched = false;
+            let mut first = true;
+            loop {
+                match class.first() {
+                    None => return false,
+                    Some(b']') if !first => {
+                        class = &class[1..];
+                        break;
+                    }
+                    _ => {}
+                }
+                let (low, after) = take_class_char(class);
+                let (high, after) = match after {
+                    [b'-', ..] if after.get(1).is_some_and(|&b| b != b']') => take_class_char(&after[1..]),
+                    _ => (low, after),
+                };
+                if low <= c && c <= high {
+                    matched = true;
+                }
+                class = after;
+                first = false;
+            }
+            matched != negated && match_glob(class, name_rest)
+        }
+        Some((b'\\', rest)) if !rest.is_empty() => name.first() == Some(&rest[0]) && match_glob(&rest[1..], &name[1..]),
+        Some((&c, rest)) => name.first() == Some(&c) && match_glob(rest, &name[1..]),
+    }
+}
+
+fn take_class_char(class: &[u8]) -> (u8, &[u8]) {
+    match class {
+        [b'\\', c, rest @ ..] => (*c, rest),
+        [c, rest @ ..] => (*c, rest),
+        [] => (0, class),
+    }
+}
+
+/// The files of a project sent to the daemon as the build context, after applying the ignore files.
+pub struct BuildContext {
+    root: PathBuf,
+    patterns: Vec<Pattern>,
+    /// Included paths relative to the root, directories included, in walk order.
+    pub paths: Vec<String>,
+    /// Total size of the included files in bytes.
+    pub size: u64,
+}
+
+impl BuildContext {
+    pub fn new(root: &Path) -> BuildContext {
+        let patterns = IGNORE_FILES
+            .iter()
+            .filter_map(|file| fs::read_to_string(root.join(file)).ok())
+            .flat_map(|content| content.lines().filter_map(Pattern::parse).collect::<Vec<_>>())
+            .collect();
+        let mut context = BuildContext {
+            root: root.to_path_buf(),
+            patterns,
+            paths: vec![],
+            size: 0,
+        };
+        context.walk(root);
+        context
+    }
+
+    /// Number of files in the context, directories not counted.
+    pub fn file_count(&self) -> usize {
+        self.paths.iter().filter(|path| !self.root.join(path).is_dir()).count()
+    }
+
+    /// Whether the last pattern matching the path excludes it.
+    fn is_excluded(&self, path: &str) -> bool {
+        let mut excluded = false;
+        for pattern in &self.patterns {
+            if pattern.matches(path) {
+                excluded = !pattern.exclusion;
+            }
+        }
+        excluded
+    }
+
+    /// An excluded directory is skipped entirely unless an exception pattern starts inside it, which keeps
+    /// large ignored trees like node_modules from being walked. Docker applies the same shortcut.
+    fn may_include_below(&self, dir: &str) -> bool {
+        let dir_slash = format!("{}/", dir);
+        self.patterns
+            .iter()
+            .any(|pattern| pattern.exclusion && format!("{}/", pattern.cleaned).starts_with(&dir_slash))
+    }
+
+    fn walk(&mut self, dir: &Path) {
+        let Ok(entries) = fs::read_dir(dir) else {
+            return;
+        };
+        let mut entries: Vec<_> = entries.filter_map(|entry| entry.ok()).collect();
+        entries.sort_by_key(|entry| entry.file_name());
+
+        for entry in entries {
+            let path = entry.path();
+            let Ok(relative) = path.strip_prefix(&self.root) else {
+                continue;
+            };
+            let relative = relative.to_string_lossy().replace('\\', "/");
+            let Ok(file_type) = entry.file_type() else {
+                continue;
+            };
+            let excluded = self.is_excluded(&relative) && !ALWAYS_INCLUDED.contains(&relative.as_str());
+
+            if file_type.is_dir() {
+                if excluded && !self.may_include_below(&relative) {
+                    continue;
+                }
+                if !excluded {
+                    self.paths.push(relative);
+                }
+                self.walk(&path);
+            } else if !excluded {
+                self.size += entry.metadata().map(|m| m.len()).unwrap_or(0);
+                self.paths.push(relative);
+            }
+        }
+    }
+
+    /// Digest of the included paths with their sizes, modes and modification times, and of `inputs` of
+    /// the build that aren't files. File contents aren't read, an unchanged digest means an unchanged
+    /// context unless a file was rewritten with the same size and time.
+    pub fn digest(&self, inputs: &[&str]) -> String {
+        let mut listing = inputs.join("\n");
+        for path in &self.paths {
+            let metadata = fs::symlink_metadata(self.root.join(path)).ok();
+            let modified = metadata
+                .as_ref()
+                .and_then(|metadata| metadata.modified().ok())
+                .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                .map_or(0, |modified| modified.as_nanos());
+            let (size, readonly) = metadata.as_ref().map_or((0, false), |metadata| {
+                (metadata.len(), metadata.permissions().readonly())
+            });
+            listing.push_str(&format!("\n{}\t{}\t{}\t{}", path, size, readonly, modified));
+        }
+        sha256_hex(listing.as_bytes())
+    }
+
+    /// Stream the context as a tar archive compressed with `compression` into `out`, without a file in
+    /// between. `progress` is called after every file with the bytes of the archive before and after
+    /// compression so far, which are returned at the end.
+    pub fn write_compressed(
+        &self,
+        out: impl Write,
+        compression: Compression,
+        mut progress: impl FnMut(u64, u64),
+    ) -> io::Result<(u64, u64)> {
+        let compressed = Rc::new(Cell::new(0));
+        let uncompressed = Rc::new(Cell::new(0));
+        let encoder = Encoder::new(
+            Counting {
+                inner: out,
+                count: compressed.clone(),
+            },
+            compression,
+        )?;
+        let mut archive = tar::Builder::new(Counting {
+            inner: encoder,
+            count: uncompressed.clone(),
+        });
+        archive.follow_symlinks(false);
+        for path in &self.paths {
+            archive
+                .append_path_with_name(self.root.join(path), path)
+                .map_err(|e| io::Error::new(e.kind(), format!("adding {}: {}", path, e)))?;
+            progress(uncompressed.get(), compressed.get());
+        }
+        let mut out = archive.into_inner()?.inner.finish()?;
+        out.flush()?;
+        Ok((uncompressed.get(), compressed.get()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, path: &str) -> bool {
+        Pattern::parse(pattern).unwrap().matches(path)
+    }
+
+    /// The paths of a project with `files` and the ignore file `dockerignore`.
+    fn included(files: &[&str], dockerignore: &str) -> Vec<String> {
+        let root = tempfile::tempdir().unwrap();
+        for file in files {
+            let path = root.path().join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "").unwrap();
+        }
+        fs::write(root.path().join(".dockerignore"), dockerignore).unwrap();
+        BuildContext::new(root.path())
+            .paths
+            .into_iter()
+            .filter(|path| path != ".dockerignore")
+            .collect()
+    }
+
+    #[test]
+    fn double_star_matches_any_number_of_directories() {
+        assert!(matches("**/*.log", "debug.log"));
+        assert!(matches("**/*.log", "logs/app/debug.log"));
+        assert!(matches("src/**/test", "src/test"));
+        assert!(matches("src/**/test", "src/a/b/test/data.json"));
+        assert!(!matches("src/**/test", "lib/a/test"));
+        assert!(matches("**", "anything/at/all"));
+    }
+
+    #[test]
+    fn single_segment_globs_stop_at_slashes() {
+        assert!(matches("*.md", "README.md"));
+        assert!(!matches("*.md", "docs/guide.md"));
+        assert!(matches("docs/*.md", "docs/guide.md"));
+        assert!(matches("file?.txt", "file1.txt"));
+        assert!(!matches("file?.txt", "file10.txt"));
+        assert!(matches("[a-c]*.rs", "build.rs"));
+        assert!(!matches("[!a-c]*.rs", "build.rs"));
+        assert!(matches("\\*.txt", "*.txt"));
+        assert!(!matches("\\*.txt", "a.txt"));
+    }
+
+    #[test]
+    fn leading_and_trailing_slashes_and_dots_are_cleaned() {
+        assert_eq!(Pattern::parse("/target/").unwrap().cleaned, "target");
+        assert_eq!(Pattern::parse("./a/../b//c").unwrap().cleaned, "b/c");
+        assert!(matches("/target", "target/debug/ruku"));
+        assert!(matches("node_modules/", "node_modules/left-pad/index.js"));
+        assert!(!matches("/target", "crates/target"));
+        assert!(Pattern::parse("# comment").is_none());
+        assert!(Pattern::parse("  ").is_none());
+        assert!(Pattern::parse("/").is_none());
+    }
+
+    #[test]
+    fn exceptions_apply_in_order() {
+        let files = ["README.md", "CHANGELOG.md", "main.rs"];
+        assert_eq!(included(&files, "*.md\n!README.md\n"), ["README.md", "main.rs"]);
+        // A later exclusion wins over an earlier exception
+        assert_eq!(included(&files, "!README.md\n*.md\n"), ["main.rs"]);
+        assert_eq!(included(&files, "*.md\n! README.md\nREADME.md\n"), ["main.rs"]);
+    }
+
+    #[test]
+    fn excluded_directories_are_skipped_unless_an_exception_is_inside() {
+        let files = [
+            "node_modules/a/index.js",
+            "node_modules/keep/index.js",
+            "src/main.rs",
+            "Dockerfile",
+        ];
+        // The Dockerfile goes to the daemon even when a pattern matches it
+        assert_eq!(
+            included(&files, "node_modules/\nDockerfile\n"),
+            ["Dockerfile", "src", "src/main.rs"]
+        );
+        assert_eq!(
+            included(&files, "node_modules\n!node_modules/keep\n"),
+            [
+                "Dockerfile",
+                "node_modules/keep",
+                "node_modules/keep/index.js",
+                "src",
+                "src/main.rs"
+            ]
+        );
+    }
+}
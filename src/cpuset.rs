@@ -0,0 +1,112 @@
+use std::collections::BTreeSet;
+
+/// Highest CPU or memory node number taken, far above what hosts have, so a typo can't name billions.
+const MAX_NUMBER: u32 = 4095;
+
+/// Parse a cpuset list like `0-2,4` into the CPU or memory node numbers it names.
+pub fn parse(list: &str) -> Result<BTreeSet<u32>, String> {
+    let invalid = || format!("invalid cpuset '{}', use numbers and ranges like 0-2,4", list);
+    let mut numbers = BTreeSet::new();
+    for part in list.split(',').map(str::trim) {
+        let (first, last) = match part.split_once('-') {
+            Some((first, last)) => (first.trim(), last.trim()),
+            None => (part, part),
+        };
+        let first: u32 = first.parse().map_err(|_| invalid())?;
+        let last: u32 = last.parse().map_err(|_| invalid())?;
+        if last > MAX_NUMBER {
+            return Err(format!("invalid cpuset '{}', {} is above {}", list, last, MAX_NUMBER));
+        }
+        if last < first {
+            return Err(format!("invalid cpuset '{}', the range {} runs backwards", list, part));
+        }
+        numbers.extend(first..=last);
+    }
+    Ok(numbers)
+}
+
+/// The numbers in the shortest list form, e.g. `0-2,4`.
+pub fn format(numbers: &BTreeSet<u32>) -> String {
+    let mut ranges: Vec<(u32, u32)> = vec![];
+    for &number in numbers {
+        match ranges.last_mut() {
+            Some((_, last)) if *last + 1 == number => *last = number,
+            _ => ranges.push((number, number)),
+        }
+    }
+    ranges
+        .iter()
+        .map(|(first, last)| match first == last {
+            true => first.to_string(),
+            false => format!("{}-{}", first, last),
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Whether a host with `available` CPUs has every CPU of `resources.cpuset_cpus`, the error names the
+/// missing ones and the CPUs the host has.
+pub fn check_cpus(cpus: &str, available: i64) -> Result<(), String> {
+    let missing: BTreeSet<u32> = parse(cpus)
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|cpu| i64::from(*cpu) >= available)
+        .collect();
+    if missing.is_empty() {
+        return Ok(());
+    }
+    Err(format!(
+        "resources.cpuset_cpus {} names CPUs {} the host does not have, it has {} CPUs: {}",
+        cpus,
+        format(&missing),
+        available,
+        match available {
+            1 => "0".to_string(),
+            _ => format!("0-{}", available - 1),
+        }
+    ))
+}
+
+/// `list` in the shortest form, so `0,1,2` and `0-2` compare equal, or as given when it doesn't parse.
+pub fn normalize(list: &str) -> String {
+    parse(list).map(|numbers| format(&numbers)).unwrap_or(list.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lists_are_parsed_into_numbers() {
+        assert_eq!(parse("0-2,4").unwrap(), BTreeSet::from([0, 1, 2, 4]));
+        assert_eq!(parse(" 3 , 1-1 ").unwrap(), BTreeSet::from([1, 3]));
+        assert!(parse("").is_err());
+        assert!(parse("a-b").is_err());
+        assert_eq!(
+            parse("3-1").unwrap_err(),
+            "invalid cpuset '3-1', the range 3-1 runs backwards"
+        );
+        assert_eq!(
+            parse("0-5000").unwrap_err(),
+            "invalid cpuset '0-5000', 5000 is above 4095"
+        );
+    }
+
+    #[test]
+    fn lists_are_written_in_the_shortest_form() {
+        assert_eq!(format(&BTreeSet::from([0, 1, 2, 4, 6, 7])), "0-2,4,6-7");
+        assert_eq!(normalize("2,0,1"), "0-2");
+        assert_eq!(normalize("0-1,2"), "0-2");
+        assert_eq!(normalize("not a list"), "not a list");
+    }
+
+    #[test]
+    fn missing_cpus_are_named() {
+        assert!(check_cpus("0-3", 4).is_ok());
+        assert_eq!(
+            check_cpus("0-2,6", 2).unwrap_err(),
+            "resources.cpuset_cpus 0-2,6 names CPUs 2,6 the host does not have, it has 2 CPUs: 0-1"
+        );
+        assert!(check_cpus("1", 1).unwrap_err().ends_with("it has 1 CPUs: 0"));
+    }
+}
@@ -0,0 +1,276 @@
+use std::fmt;
+use std::sync::LazyLock;
+
+use bollard::errors::Error;
+use regex::Regex;
+
+use crate::logger::Logger;
+use crate::model::RukuConfig;
+
+/// A daemon error ruku knows how to explain. `{1}` in the message and hint is the first group of the
+/// pattern, `{app}` the app and `{field}` the config field a host path comes from.
+struct Rule {
+    /// HTTP status of the daemon response, any status when none.
+    status: Option<u16>,
+    pattern: &'static str,
+    message: &'static str,
+    hint: &'static str,
+}
+
+/// The daemon errors users run into most, first match wins.
+const RULES: &[Rule] = &[
+    Rule {
+        status: Some(409),
+        pattern: r#"container name "/?([^"]+)" is already in use"#,
+        message: "the container name {1} is taken by another container",
+        hint: "`ruku repair {app}` removes containers left behind by interrupted deploys",
+    },
+    Rule {
+        status: None,
+        pattern: r"bind source path does not exist: (\S+)",
+        message: "the host path {1} of a bind mount does not exist",
+        hint: "it comes from {field}, create it or set create_host_paths: true in ruku.yml",
+    },
+    Rule {
+        status: None,
+        pattern: r"create (.+?): .*includes invalid characters for a local volume name",
+        message: "{1} is not a valid volume name",
+        hint: "it comes from {field}, host paths start with ./ or / and volume names only hold letters, digits, _, . and -",
+    },
+    Rule {
+        status: None,
+        pattern: r"invalid (?:volume specification|mount config[^:]*): '?([^'\s]+)'?",
+        message: "the mount {1} is invalid",
+        hint: "it comes from {field}",
+    },
+    Rule {
+        status: Some(404),
+        pattern: r"No such image: (\S+)",
+        message: "image {1} does not exist",
+        hint: "check the version in ruku.yml, `ruku run {app}` names the versions closest to it",
+    },
+    Rule {
+        status: Some(404),
+        pattern: r"network (\S+) not found",
+        message: "network {1} does not exist",
+        hint: "`ruku run {app}` creates the app network again, a network_mode naming a network needs `docker network create`",
+    },
+    Rule {
+        status: None,
+        pattern: r"unknown or invalid runtime name: (\S+)",
+        message: "the daemon has no runtime {1}",
+        hint: "runtimes are set up in /etc/docker/daemon.json, `docker info` lists them",
+    },
+    Rule {
+        status: None,
+        pattern: r"(port is already allocated|address already in use)",
+        message: "a host port the container publishes is in use",
+        hint: "`ruku doctor` lists the published ports, or pick another port in ruku.yml",
+    },
+    Rule {
+        status: None,
+        pattern: r#"exec: "([^"]+)": executable file not found"#,
+        message: "the command {1} is not in the image",
+        hint: "check the entrypoint and command the image starts with",
+    },
+    Rule {
+        status: None,
+        pattern: r"(no space left on device)",
+        message: "the Docker host is out of disk space",
+        hint: "`docker system df` shows what takes it, `ruku build-cache:prune` frees the build cache",
+    },
+];
+
+static COMPILED: LazyLock<Vec<(Regex, &'static Rule)>> = LazyLock::new(|| {
+    RULES
+        .iter()
+        .map(|rule| (Regex::new(rule.pattern).unwrap(), rule))
+        .collect()
+});
+
+/// A daemon error as the user is shown it, with what to do about it when ruku knows.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Explained {
+    pub message: String,
+    pub hint: Option<String>,
+    /// What the daemon answered, for the debug log.
+    pub raw: String,
+}
+
+impl fmt::Display for Explained {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.hint {
+            Some(hint) => write!(f, "{}; {}", self.message, hint),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+/// Explain `error` of a request for `app`, the raw message when no rule matches.
+pub fn explain(error: &Error, app: &str, config: &RukuConfig) -> Explained {
+    let raw = error.to_string();
+    let (status, message) = match error {
+        Error::DockerResponseServerError { status_code, message } => (Some(*status_code), message.as_str()),
+        _ => (None, raw.as_str()),
+    };
+    let explained = explain_message(status, message, app, config).unwrap_or(Explained {
+        message: message.to_string(),
+        hint: None,
+        raw: String::new(),
+    });
+    Explained { raw, ..explained }
+}
+
+/// Explain `error` like [`explain`], with the raw daemon answer on the debug log.
+pub fn report(log: &Logger, error: &Error, app: &str, config: &RukuConfig) -> Explained {
+    let explained = explain(error, app, config);
+    log.debug(&format!("Docker daemon: {}", explained.raw));
+    explained
+}
+
+/// Explain the daemon answer `message` with the HTTP `status`, none when no rule matches.
+pub fn explain_message(status: Option<u16>, message: &str, app: &str, config: &RukuConfig) -> Option<Explained> {
+    COMPILED.iter().find_map(|(pattern, rule)| {
+        if rule.status.is_some_and(|wanted| Some(wanted) != status) {
+            return None;
+        }
+        let captures = pattern.captures(message)?;
+        let first = captures.get(1).map_or("", |group| group.as_str());
+        let fill = |template: &str| {
+            let filled = template.replace("{1}", first).replace("{app}", app);
+            match filled.contains("{field}") {
+                true => filled.replace("{field}", &mount_field(first, config)),
+                false => filled,
+            }
+        };
+        Some(Explained {
+            message: fill(rule.message),
+            hint: Some(fill(rule.hint)),
+            raw: message.to_string(),
+        })
+    })
+}
+
+/// The config field a mount of `path` comes from, e.g. the `volumes` entry `./data:/data`.
+fn mount_field(path: &str, config: &RukuConfig) -> String {
+    let path = path.trim_end_matches('/');
+    let mentions = |entry: &str| !path.is_empty() && entry.split(':').any(|part| part.trim_end_matches('/') == path);
+    if let Some(entry) = config.volumes.iter().find(|entry| mentions(entry)) {
+        return format!("the volumes entry `{}`", entry);
+    }
+    for sidecar in &config.sidecars {
+        if let Some(entry) = sidecar.volumes.iter().find(|entry| mentions(entry)) {
+            return format!("the volumes entry `{}` of sidecar {}", entry, sidecar.name);
+        }
+    }
+    if config
+        .static_site
+        .as_ref()
+        .is_some_and(|site| path.ends_with(site.dir.trim_start_matches("./").trim_end_matches('/')))
+    {
+        return "static.dir".to_string();
+    }
+    "a mount ruku adds for templates, files or the timezone".to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+
+    use super::*;
+    use crate::events::{Event, Level};
+
+    fn config() -> RukuConfig {
+        serde_yaml::from_str(
+            "
+version: '1.0'
+volumes:
+  - /srv/shop/uploads:/app/uploads
+sidecars:
+  - name: redis
+    image: redis:7
+    volumes:
+      - /srv/shop/redis/:/data
+",
+        )
+        .unwrap()
+    }
+
+    fn explained(status: u16, message: &str) -> String {
+        explain_message(Some(status), message, "shop", &config())
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn recorded_daemon_errors_are_explained() {
+        assert_eq!(
+            explained(
+                409,
+                r#"Conflict. The container name "/ruku-shop" is already in use by container "3f2a". You have to remove (or rename) that container to be able to reuse that name."#
+            ),
+            "the container name ruku-shop is taken by another container; `ruku repair shop` removes containers left behind by interrupted deploys"
+        );
+        assert_eq!(
+            explained(400, "invalid mount config for type \"bind\": bind source path does not exist: /srv/shop/uploads"),
+            "the host path /srv/shop/uploads of a bind mount does not exist; it comes from the volumes entry `/srv/shop/uploads:/app/uploads`, create it or set create_host_paths: true in ruku.yml"
+        );
+        assert_eq!(
+            explained(404, "No such image: shop:1.4.0"),
+            "image shop:1.4.0 does not exist; check the version in ruku.yml, `ruku run shop` names the versions closest to it"
+        );
+        assert_eq!(
+            explained(
+                500,
+                "failed to create task for container: failed to create shim task: OCI runtime create failed: runc create failed: unable to start container process: exec: \"gunicorn\": executable file not found in $PATH: unknown"
+            ),
+            "the command gunicorn is not in the image; check the entrypoint and command the image starts with"
+        );
+        assert_eq!(
+            explained(500, "write /var/lib/docker/tmp/GetImageBlob: no space left on device"),
+            "the Docker host is out of disk space; `docker system df` shows what takes it, `ruku build-cache:prune` frees the build cache"
+        );
+    }
+
+    #[test]
+    fn a_rule_with_a_status_only_matches_that_status() {
+        assert!(explain_message(Some(500), "No such image: shop:1.4.0", "shop", &config()).is_none());
+        assert!(explain_message(None, "network shop not found", "shop", &config()).is_none());
+        assert!(explain_message(Some(500), "something else broke", "shop", &config()).is_none());
+    }
+
+    #[test]
+    fn mounts_are_traced_to_their_field() {
+        let config = config();
+        assert_eq!(
+            mount_field("/srv/shop/redis", &config),
+            "the volumes entry `/srv/shop/redis/:/data` of sidecar redis"
+        );
+        assert_eq!(
+            mount_field("/etc/localtime", &config),
+            "a mount ruku adds for templates, files or the timezone"
+        );
+        let site: RukuConfig = serde_yaml::from_str("version: '1.0'\ntype: static\nstatic:\n  dir: ./dist").unwrap();
+        assert_eq!(mount_field("/home/ruku/apps/site/dist/", &site), "static.dir");
+    }
+
+    #[test]
+    fn unknown_errors_keep_the_daemon_message_and_log_it() {
+        let error = Error::DockerResponseServerError {
+            status_code: 500,
+            message: "something else broke".to_string(),
+        };
+        let (sender, receiver) = mpsc::channel();
+        let log = Logger::with_events(sender);
+        let explained = report(&log, &error, "shop", &config());
+        assert_eq!(explained.to_string(), "something else broke");
+        assert_eq!(explained.hint, None);
+        assert_eq!(
+            receiver.try_iter().collect::<Vec<_>>(),
+            [Event::LogLine {
+                level: Level::Debug,
+                message: format!("Docker daemon: {}", error),
+            }]
+        );
+    }
+}
@@ -0,0 +1,216 @@
+use std::env;
+use std::sync::OnceLock;
+
+use bollard::Docker;
+
+use crate::registry::split_registry;
+
+/// What the daemon was last seen to use, read during preflight.
+static SEEN: OnceLock<DaemonNetwork> = OnceLock::new();
+
+/// Names Docker Hub goes by in image references.
+const DOCKER_HUB_HOSTS: [&str; 3] = ["docker.io", "index.docker.io", "registry-1.docker.io"];
+
+/// Env vars a proxy of the host is set in, most specific first.
+const PROXY_ENV: [&str; 4] = ["HTTPS_PROXY", "https_proxy", "HTTP_PROXY", "http_proxy"];
+
+/// The proxies and registry mirrors the daemon pulls through, as `docker info` reports them. The daemon
+/// pulls, so these count and not the env ruku runs in.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DaemonNetwork {
+    pub http_proxy: Option<String>,
+    pub https_proxy: Option<String>,
+    pub no_proxy: Option<String>,
+    /// Docker Hub mirrors of `registry-mirrors` in daemon.json.
+    pub mirrors: Vec<String>,
+}
+
+impl DaemonNetwork {
+    /// What the daemon of `docker` reports, none when it can't be asked.
+    pub async fn read(docker: &Docker) -> Option<DaemonNetwork> {
+        let info = docker.info().await.ok()?;
+        let set = |value: Option<String>| value.filter(|value| !value.is_empty());
+        Some(DaemonNetwork {
+            http_proxy: set(info.http_proxy),
+            https_proxy: set(info.https_proxy),
+            no_proxy: set(info.no_proxy),
+            mirrors: info
+                .registry_config
+                .and_then(|config| config.mirrors)
+                .unwrap_or_default(),
+        })
+    }
+
+    /// Keep this as what the daemon uses for the rest of the process.
+    pub fn remember(self) {
+        let _ = SEEN.set(self);
+    }
+
+    /// What [`remember`](Self::remember) kept, none before preflight.
+    pub fn remembered() -> Option<&'static DaemonNetwork> {
+        SEEN.get()
+    }
+
+    pub fn has_proxy(&self) -> bool {
+        self.http_proxy.is_some() || self.https_proxy.is_some()
+    }
+
+    /// Why a pull that failed with `error` may have failed, when it looks like the network and the host
+    /// has a proxy the daemon doesn't.
+    pub fn pull_hint(&self, error: &str) -> Option<String> {
+        if self.has_proxy() || !is_network_error(error) {
+            return None;
+        }
+        let (name, _) = host_proxy()?;
+        Some(format!(
+            "{} is set here but the Docker daemon has no proxy, and the daemon pulls and not ruku. Set the \
+             proxy in the daemon's environment, e.g. HTTPS_PROXY in a systemd drop-in for docker.service or \
+             proxies in /etc/docker/daemon.json, and restart it",
+            name
+        ))
+    }
+}
+
+/// The proxy env var of the host ruku runs on and its value, none when no proxy is set.
+pub fn host_proxy() -> Option<(&'static str, String)> {
+    PROXY_ENV.iter().find_map(|name| {
+        env::var(name)
+            .ok()
+            .filter(|value| !value.is_empty())
+            .map(|value| (*name, value))
+    })
+}
+
+/// Whether a daemon error reads like the registry could not be reached.
+fn is_network_error(error: &str) -> bool {
+    const NETWORK_ERRORS: [&str; 8] = [
+        "dial tcp",
+        "i/o timeout",
+        "no such host",
+        "connection refused",
+        "connection reset",
+        "TLS handshake timeout",
+        "network is unreachable",
+        "Client.Timeout exceeded",
+    ];
+    NETWORK_ERRORS.iter().any(|pattern| error.contains(pattern))
+}
+
+/// `reference` pulled through the Docker Hub mirror `mirror`, e.g. `mirror.example.com/library/node:20`
+/// for `node:20`. Images of other registries and ones pinned by digest, which can't be tagged back, stay
+/// as they are.
+pub fn mirrored(reference: &str, mirror: &str) -> String {
+    if reference.contains('@') {
+        return reference.to_string();
+    }
+    let path = match split_registry(reference) {
+        Some((host, path)) if DOCKER_HUB_HOSTS.contains(&host) => path,
+        Some(_) => return reference.to_string(),
+        None => reference,
+    };
+    // Official images live under library/, which references to them leave out
+    let path = match path.contains('/') {
+        true => path.to_string(),
+        false => format!("library/{}", path),
+    };
+    format!("{}/{}", mirror_host(mirror), path)
+}
+
+/// `mirror` as the start of an image reference, without the scheme daemon.json writes mirrors with.
+fn mirror_host(mirror: &str) -> &str {
+    let mirror = mirror
+        .strip_prefix("https://")
+        .or_else(|| mirror.strip_prefix("http://"))
+        .unwrap_or(mirror);
+    mirror.trim_end_matches('/')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake_daemon::fake_daemon;
+
+    #[test]
+    fn docker_hub_images_are_rewritten_to_the_mirror() {
+        let mirror = "https://mirror.example.com/";
+        assert_eq!(mirrored("node:20", mirror), "mirror.example.com/library/node:20");
+        assert_eq!(mirrored("redis", mirror), "mirror.example.com/library/redis");
+        assert_eq!(
+            mirrored("grafana/grafana:11", mirror),
+            "mirror.example.com/grafana/grafana:11"
+        );
+        assert_eq!(
+            mirrored("docker.io/library/postgres:16", mirror),
+            "mirror.example.com/library/postgres:16"
+        );
+        assert_eq!(
+            mirrored("index.docker.io/nginx:1.27", mirror),
+            "mirror.example.com/library/nginx:1.27"
+        );
+        assert_eq!(
+            mirrored("node:20", "http://10.0.0.5:5000"),
+            "10.0.0.5:5000/library/node:20"
+        );
+        assert_eq!(
+            mirrored("node:20", "mirror.example.com:5000"),
+            "mirror.example.com:5000/library/node:20"
+        );
+    }
+
+    #[test]
+    fn other_registries_and_digests_are_left_alone() {
+        let mirror = "mirror.example.com";
+        assert_eq!(mirrored("ghcr.io/acme/shop:1.4.0", mirror), "ghcr.io/acme/shop:1.4.0");
+        assert_eq!(mirrored("localhost:5000/shop", mirror), "localhost:5000/shop");
+        let pinned = "node@sha256:4a1c0b2d3e4f5a6b7c8d9e0f1a2b3c4d5e6f7a8b9c0d1e2f3a4b5c6d7e8f9a0b";
+        assert_eq!(mirrored(pinned, mirror), pinned);
+    }
+
+    #[test]
+    fn hints_need_a_network_error_and_a_daemon_without_proxy() {
+        let with_proxy = DaemonNetwork {
+            https_proxy: Some("http://proxy:3128".to_string()),
+            ..Default::default()
+        };
+        assert!(with_proxy.has_proxy());
+        assert_eq!(
+            with_proxy.pull_hint("dial tcp: lookup registry-1.docker.io: i/o timeout"),
+            None
+        );
+        assert_eq!(
+            DaemonNetwork::default().pull_hint("unauthorized: authentication required"),
+            None
+        );
+        assert!(is_network_error(
+            "Get \"https://registry-1.docker.io/v2/\": net/http: TLS handshake timeout"
+        ));
+        assert!(!is_network_error("manifest unknown"));
+    }
+
+    #[tokio::test]
+    async fn proxies_and_mirrors_are_read_from_the_info() {
+        let (docker, _) = fake_daemon(|_, path| match path {
+            "/info" => (
+                200,
+                r#"{"HttpProxy":"","HttpsProxy":"http://proxy:3128","NoProxy":"localhost",
+                    "RegistryConfig":{"Mirrors":["https://mirror.example.com/"]}}"#
+                    .to_string(),
+            ),
+            _ => (404, "{}".to_string()),
+        })
+        .await;
+        let network = DaemonNetwork::read(&docker).await.unwrap();
+        assert_eq!(
+            network,
+            DaemonNetwork {
+                http_proxy: None,
+                https_proxy: Some("http://proxy:3128".to_string()),
+                no_proxy: Some("localhost".to_string()),
+                mirrors: vec!["https://mirror.example.com/".to_string()],
+            }
+        );
+
+        let (docker, _) = fake_daemon(|_, _| (500, r#"{"message":"down"}"#.to_string())).await;
+        assert_eq!(DaemonNetwork::read(&docker).await, None);
+    }
+}
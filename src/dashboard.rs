@@ -0,0 +1,633 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fs::File;
+use std::io::{self, IsTerminal, Read, Write};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Once};
+use std::thread;
+
+use bollard::container::{LogOutput, LogsOptions, StatsOptions};
+use bollard::Docker;
+use colored::Colorize;
+use futures_util::StreamExt;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::task::JoinHandle;
+
+use crate::container::{Container, APP_LABEL};
+use crate::event_stream::EventStream;
+use crate::logger::Logger;
+use crate::overview::{app_rows, AppRow, Usage};
+use crate::server_config::ServerConfig;
+use crate::units::format_size_binary;
+use crate::webhook::Webhook;
+
+const SPARKS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+/// Stats samples kept for the sparklines, the daemon sends about one a second.
+const HISTORY: usize = 120;
+/// Lines of output kept for the selected app.
+const LOG_LINES: usize = 200;
+/// Below this the dashboard only asks for a bigger terminal.
+const MIN_WIDTH: usize = 40;
+const MIN_HEIGHT: usize = 12;
+/// Container events that change what the app list shows, exec events of healthchecks are left out.
+const CHANGE_EVENTS: [&str; 10] = [
+    "create",
+    "start",
+    "restart",
+    "stop",
+    "die",
+    "kill",
+    "destroy",
+    "rename",
+    "pause",
+    "health_status",
+];
+
+/// A key the dashboard acts on.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Key {
+    Up,
+    Down,
+    Restart,
+    Stop,
+    Deploy,
+    Quit,
+}
+
+impl Key {
+    /// The keys in what the terminal sent, arrows arrive as escape sequences.
+    pub fn parse(bytes: &[u8]) -> Vec<Key> {
+        let mut keys = vec![];
+        let mut i = 0;
+        while i < bytes.len() {
+            let key = match &bytes[i..] {
+                [0x1b, b'[', b'A', ..] => Some(Key::Up),
+                [0x1b, b'[', b'B', ..] => Some(Key::Down),
+                [b'k', ..] => Some(Key::Up),
+                [b'j', ..] => Some(Key::Down),
+                [b'r', ..] => Some(Key::Restart),
+                [b's', ..] => Some(Key::Stop),
+                [b'd', ..] => Some(Key::Deploy),
+                // Ctrl-C, which raw mode delivers as a byte rather than SIGINT
+                [b'q' | 0x03, ..] => Some(Key::Quit),
+                _ => None,
+            };
+            i += if bytes[i] == 0x1b && bytes.len() >= i + 3 { 3 } else { 1 };
+            keys.extend(key);
+        }
+        keys
+    }
+
+    /// The ruku command the key runs on the selected app, none for the keys of the dashboard itself.
+    fn command(&self) -> Option<&'static str> {
+        match self {
+            Key::Restart => Some("restart"),
+            Key::Stop => Some("stop"),
+            Key::Deploy => Some("run"),
+            _ => None,
+        }
+    }
+}
+
+enum Update {
+    Key(Key),
+    /// A container of some app changed, the list is queried again.
+    Changed,
+    Usage(String, Usage),
+    Log(String, String),
+    /// The event stream could not be reconnected, the list is no longer refreshed on changes.
+    EventsLost(String),
+}
+
+/// A live view of every app: state, health, CPU and memory of the selected one and its output. The
+/// data comes from the queries `list` uses, refreshed on container events.
+pub struct Dashboard<'a> {
+    log: &'a Logger,
+    docker: &'a Docker,
+    server_config: &'a ServerConfig,
+    rows: Vec<AppRow>,
+    selected: usize,
+    /// The container the stats and logs follow, with the state it had when they started.
+    following: Option<(String, String)>,
+    streams: Vec<JoinHandle<()>>,
+    cpu: VecDeque<f64>,
+    memory: VecDeque<u64>,
+    usage: Option<Usage>,
+    logs: VecDeque<String>,
+    error: Option<String>,
+    /// Why the list stopped following container events.
+    events_lost: Option<String>,
+}
+
+impl<'a> Dashboard<'a> {
+    pub fn new(log: &'a Logger, docker: &'a Docker, server_config: &'a ServerConfig) -> Dashboard<'a> {
+        Dashboard {
+            log,
+            docker,
+            server_config,
+            rows: vec![],
+            selected: 0,
+            following: None,
+            streams: vec![],
+            cpu: VecDeque::new(),
+            memory: VecDeque::new(),
+            usage: None,
+            logs: VecDeque::new(),
+            error: None,
+            events_lost: None,
+        }
+    }
+
+    /// Show the dashboard until `q` or Ctrl-C. The keys for the selected app leave the dashboard to run
+    /// the ruku command in the terminal and come back once it is done.
+    pub async fn run(&mut self) -> Result<(), String> {
+        if !io::stdin().is_terminal() || !io::stdout().is_terminal() {
+            return Err("The dashboard needs an interactive terminal".to_string());
+        }
+        let saved = stty(&["-g"]).map_err(|e| format!("Error reading the terminal settings: {}", e))?;
+        restore_on_panic(saved.clone());
+
+        let (tx, mut rx) = unbounded_channel();
+        let webhook = self.server_config.events_webhook.as_deref().map(Webhook::new);
+        let events = tokio::spawn(watch_events(self.docker.clone(), webhook, tx.clone()));
+        self.refresh().await;
+        loop {
+            let key = {
+                let _screen = Screen::enter(&saved).map_err(|e| format!("Error setting up the terminal: {}", e))?;
+                let input = Input::start(tx.clone());
+                let key = self.interact(&mut rx, &tx).await;
+                input.stop();
+                key
+            };
+            let (Some(command), Some(row)) = (key.and_then(|key| key.command()), self.current()) else {
+                break;
+            };
+            let app = row.app.clone();
+            self.unfollow();
+            self.log.section(&format!("Running ruku {} {}", command, app));
+            self.run_command(command, &app);
+            print!("Press enter to return to the dashboard");
+            let _ = io::stdout().flush();
+            let _ = io::stdin().read_line(&mut String::new());
+            // What changed while the command ran arrived as events, the list is queried once instead
+            while rx.try_recv().is_ok() {}
+            self.refresh().await;
+        }
+        events.abort();
+        self.unfollow();
+        Ok(())
+    }
+
+    /// Draw and handle updates until a key leaves the dashboard, none for Ctrl-C from outside.
+    async fn interact(&mut self, rx: &mut UnboundedReceiver<Update>, tx: &UnboundedSender<Update>) -> Option<Key> {
+        let mut resize = Resize::new().ok();
+        self.follow(tx);
+        self.draw();
+        loop {
+            tokio::select! {
+                update = rx.recv() => match update? {
+                    Update::Key(Key::Up) => self.select(self.selected.saturating_sub(1), tx),
+                    Update::Key(Key::Down) => self.select(self.selected + 1, tx),
+                    // Nothing to run the command on
+                    Update::Key(key) if key.command().is_some() && self.current().is_none() => {}
+                    Update::Key(key) => return Some(key),
+                    Update::Changed => {
+                        self.refresh().await;
+                        self.follow(tx);
+                    }
+                    // The list stops following changes, which the footer has to say
+                    Update::EventsLost(error) => self.events_lost = Some(error),
+                    Update::Usage(container, usage) => {
+                        if self.is_following(&container) {
+                            push(&mut self.cpu, usage.cpu_percent, HISTORY);
+                            push(&mut self.memory, usage.memory, HISTORY);
+                            self.usage = Some(usage);
+                        }
+                    }
+                    Update::Log(container, line) => {
+                        if self.is_following(&container) {
+                            push(&mut self.logs, line, LOG_LINES);
+                        }
+                    }
+                },
+                _ = recv_resize(&mut resize) => {}
+                _ = tokio::signal::ctrl_c() => return Some(Key::Quit),
+            }
+            self.draw();
+        }
+    }
+
+    /// Query the app list again, keeping the selection on the same container.
+    async fn refresh(&mut self) {
+        let selected = self.current().map(|row| row.container.clone());
+        match Container::try_list_all(self.docker).await {
+            Ok(summaries) => {
+                self.rows = app_rows(&summaries, self.server_config)
+                    .into_iter()
+                    .filter(AppRow::is_stable)
+                    .collect();
+                self.rows.sort_by(|a, b| a.app.cmp(&b.app));
+                self.error = None;
+            }
+            Err(e) => self.error = Some(format!("Failed to list containers: {}", e)),
+        }
+        if let Some(position) = selected.and_then(|name| self.rows.iter().position(|row| row.container == name)) {
+            self.selected = position;
+        }
+        self.selected = self.selected.min(self.rows.len().saturating_sub(1));
+    }
+
+    fn current(&self) -> Option<&AppRow> {
+        self.rows.get(self.selected)
+    }
+
+    fn select(&mut self, selected: usize, tx: &UnboundedSender<Update>) {
+        self.selected = selected.min(self.rows.len().saturating_sub(1));
+        self.follow(tx);
+    }
+
+    fn is_following(&self, container: &str) -> bool {
+        self.following.as_ref().is_some_and(|(name, _)| name == container)
+    }
+
+    /// Stream the stats and logs of the selected container, again when it was restarted since.
+    fn follow(&mut self, tx: &UnboundedSender<Update>) {
+        let target = self.current().map(|row| (row.container.clone(), row.state.clone()));
+        if target == self.following {
+            return;
+        }
+        let same_container = self.following.as_ref().map(|(name, _)| name) == target.as_ref().map(|(name, _)| name);
+        self.unfollow();
+        if !same_container {
+            self.cpu.clear();
+            self.memory.clear();
+            self.usage = None;
+        }
+        // The log stream starts over with the tail
+        self.logs.clear();
+        if let Some((container, state)) = &target {
+            if state == "running" {
+                self.streams.push(tokio::spawn(stream_usage(
+                    self.docker.clone(),
+                    container.clone(),
+                    tx.clone(),
+                )));
+            }
+            self.streams.push(tokio::spawn(stream_logs(
+                self.docker.clone(),
+                container.clone(),
+                state == "running",
+                tx.clone(),
+            )));
+        }
+        self.following = target;
+    }
+
+    fn unfollow(&mut self) {
+        for stream in self.streams.drain(..) {
+            stream.abort();
+        }
+        self.following = None;
+    }
+
+    fn run_command(&self, command: &str, app: &str) {
+        let status = std::env::current_exe()
+            .and_then(|ruku| Command::new(ruku).args([command, app]).stdin(Stdio::inherit()).status());
+        match status {
+            Ok(status) if status.success() => {}
+            Ok(_) => self.log.warn(&format!("ruku {} {} failed", command, app)),
+            Err(e) => self.log.warn(&format!("Error running ruku {} {}: {}", command, app, e)),
+        }
+    }
+
+    fn draw(&self) {
+        let (width, height) = terminal_size().unwrap_or((80, 24));
+        let mut frame = String::from("\x1b[H");
+        for (i, line) in self.render(width, height).iter().enumerate() {
+            if i > 0 {
+                frame.push_str("\r\n");
+            }
+            frame.push_str(line);
+            frame.push_str("\x1b[K");
+        }
+        frame.push_str("\x1b[J");
+        let mut stdout = io::stdout().lock();
+        let _ = stdout.write_all(frame.as_bytes());
+        let _ = stdout.flush();
+    }
+
+    /// The lines of one frame, each at most `width` characters wide.
+    pub fn render(&self, width: usize, height: usize) -> Vec<String> {
+        if width < MIN_WIDTH || height < MIN_HEIGHT {
+            let message = format!("Make the terminal at least {}x{}", MIN_WIDTH, MIN_HEIGHT);
+            return vec![fit(&message, width)];
+        }
+        let mut lines = vec![
+            fit(&format!("ruku dashboard, {} apps", self.rows.len()), width)
+                .bold()
+                .to_string(),
+            fit(
+                &format!("  {:<20} {:<10} {:<10} VERSION", "APP", "STATE", "HEALTH"),
+                width,
+            )
+            .dimmed()
+            .to_string(),
+        ];
+
+        // A third of the screen for the apps, scrolled to keep the selection in view
+        let visible = (height / 3).max(1);
+        let first = self.selected.saturating_sub(visible - 1);
+        for (i, row) in self.rows.iter().enumerate().skip(first).take(visible) {
+            let marker = if i == self.selected { '>' } else { ' ' };
+            let line = fit(
+                &format!(
+                    "{} {:<20} {:<10} {:<10} {}",
+                    marker,
+                    row.app,
+                    row.state,
+                    row.health().unwrap_or("-"),
+                    row.version
+                ),
+                width,
+            );
+            lines.push(if i == self.selected {
+                line.reversed().to_string()
+            } else {
+                line
+            });
+        }
+        if self.rows.is_empty() {
+            lines.push(fit("  No apps are running", width));
+        }
+        lines.push(String::new());
+
+        if let Some(row) = self.current() {
+            let spark_width = width.saturating_sub(24);
+            let (cpu, memory) = match &self.usage {
+                Some(usage) => (
+                    format!("{:.1}%", usage.cpu_percent),
+                    match usage.memory_limit {
+                        Some(limit) => format!("{} / {}", format_size_binary(usage.memory), format_size_binary(limit)),
+                        None => format_size_binary(usage.memory),
+                    },
+                ),
+                None => ("-".to_string(), "-".to_string()),
+            };
+            let memory_max = self
+                .usage
+                .and_then(|usage| usage.memory_limit)
+                .map(|limit| limit as f64);
+            lines.push(fit(
+                &format!("CPU {:>19} {}", cpu, sparkline(&self.cpu, Some(100.0), spark_width)),
+                width,
+            ));
+            let memory_samples: VecDeque<f64> = self.memory.iter().map(|memory| *memory as f64).collect();
+            lines.push(fit(
+                &format!(
+                    "Mem {:>19} {}",
+                    memory,
+                    sparkline(&memory_samples, memory_max, spark_width)
+                ),
+                width,
+            ));
+            lines.push(String::new());
+            lines.push(fit(&format!("Output of {}", row.container), width).bold().to_string());
+        }
+
+        // The rest down to the footer is the log tail
+        let room = height.saturating_sub(lines.len() + 1);
+        let skip = self.logs.len().saturating_sub(room);
+        lines.extend(self.logs.iter().skip(skip).map(|line| fit(line, width)));
+        lines.resize(height - 1, String::new());
+
+        let footer = match self.error.as_ref().or(self.events_lost.as_ref()) {
+            Some(error) => fit(error, width).red().to_string(),
+            None => fit("↑/↓ select  r restart  s stop  d deploy  q quit", width)
+                .dimmed()
+                .to_string(),
+        };
+        lines.push(footer);
+        lines
+    }
+}
+
+fn push<T>(samples: &mut VecDeque<T>, sample: T, limit: usize) {
+    if samples.len() == limit {
+        samples.pop_front();
+    }
+    samples.push_back(sample);
+}
+
+/// `line` cut to `width` characters, with tabs and other control characters the terminal would act
+/// on replaced.
+fn fit(line: &str, width: usize) -> String {
+    line.chars()
+        .map(|c| if c.is_control() { ' ' } else { c })
+        .take(width)
+        .collect()
+}
+
+/// The last `width` samples as bars, scaled to `max` or to the largest sample.
+pub fn sparkline(samples: &VecDeque<f64>, max: Option<f64>, width: usize) -> String {
+    let skip = samples.len().saturating_sub(width);
+    let largest = samples.iter().skip(skip).cloned().fold(0.0, f64::max);
+    let max = max.unwrap_or(largest).max(largest);
+    samples
+        .iter()
+        .skip(skip)
+        .map(|sample| {
+            let level = if max > 0.0 {
+                sample / max * (SPARKS.len() - 1) as f64
+            } else {
+                0.0
+            };
+            SPARKS[(level.round() as usize).min(SPARKS.len() - 1)]
+        })
+        .collect()
+}
+
+async fn watch_events(docker: Docker, webhook: Option<Webhook>, tx: UnboundedSender<Update>) {
+    let filters = HashMap::from([
+        ("type", vec!["container"]),
+        ("label", vec![APP_LABEL]),
+        ("event", CHANGE_EVENTS.to_vec()),
+    ]);
+    let mut events = EventStream::new(docker, filters).with_webhook(webhook);
+    loop {
+        let update = match events.next().await {
+            Ok(_) => Update::Changed,
+            Err(e) => Update::EventsLost(e),
+        };
+        let lost = matches!(update, Update::EventsLost(_));
+        if tx.send(update).is_err() || lost {
+            break;
+        }
+    }
+}
+
+async fn stream_usage(docker: Docker, container: String, tx: UnboundedSender<Update>) {
+    let options = StatsOptions {
+        stream: true,
+        one_shot: false,
+    };
+    let mut stats = docker.stats(&container, Some(options));
+    while let Some(Ok(stats)) = stats.next().await {
+        if tx
+            .send(Update::Usage(container.clone(), Usage::from_stats(&stats)))
+            .is_err()
+        {
+            break;
+        }
+    }
+}
+
+async fn stream_logs(docker: Docker, container: String, follow: bool, tx: UnboundedSender<Update>) {
+    let options = LogsOptions::<String> {
+        follow,
+        stdout: true,
+        stderr: true,
+        tail: LOG_LINES.to_string(),
+        ..Default::default()
+    };
+    let mut stream = docker.logs(&container, Some(options));
+    // Docker may split a line across frames, the rest of each stream waits here for its newline
+    let mut partial: BTreeMap<&str, String> = BTreeMap::new();
+    while let Some(Ok(output)) = stream.next().await {
+        let (stream_tag, message) = match &output {
+            LogOutput::StdErr { message } => ("stderr", message),
+            LogOutput::StdOut { message } => ("stdout", message),
+            LogOutput::StdIn { message } => ("stdin", message),
+            LogOutput::Console { message } => ("console", message),
+        };
+        let buffer = partial.entry(stream_tag).or_default();
+        buffer.push_str(&String::from_utf8_lossy(message));
+        while let Some(end) = buffer.find('\n') {
+            let line: String = buffer.drain(..=end).collect();
+            let line = line.trim_end_matches(['\n', '\r']).to_string();
+            if tx.send(Update::Log(container.clone(), line)).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Run `stty` on the controlling terminal, returning what it prints.
+fn stty(args: &[&str]) -> io::Result<String> {
+    let output = Command::new("stty")
+        .args(args)
+        .stdin(File::open("/dev/tty")?)
+        .output()?;
+    if !output.status.success() {
+        return Err(io::Error::other(
+            String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Columns and rows of the terminal.
+pub fn terminal_size() -> Option<(usize, usize)> {
+    let size = stty(&["size"]).ok()?;
+    let (rows, columns) = size.split_once(' ')?;
+    Some((columns.parse().ok()?, rows.parse().ok()?))
+}
+
+/// Put the terminal back the way `saved` from `stty -g` describes it, on the main screen.
+fn restore(saved: &str) {
+    let _ = io::stdout().write_all(b"\x1b[?25h\x1b[?1049l");
+    let _ = io::stdout().flush();
+    let _ = stty(&[saved]);
+}
+
+/// Restore the terminal before a panic message is printed, it would be lost on the alternate screen.
+fn restore_on_panic(saved: String) {
+    static HOOK: Once = Once::new();
+    HOOK.call_once(|| {
+        let previous = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            restore(&saved);
+            previous(info);
+        }));
+    });
+}
+
+/// The terminal in raw mode on the alternate screen, restored when dropped.
+struct Screen<'a> {
+    saved: &'a str,
+}
+
+impl<'a> Screen<'a> {
+    fn enter(saved: &'a str) -> io::Result<Screen<'a>> {
+        // Reads return after a tenth of a second without input, so the input thread can be stopped
+        stty(&["raw", "-echo", "min", "0", "time", "1"])?;
+        let screen = Screen { saved };
+        io::stdout().write_all(b"\x1b[?1049h\x1b[?25l\x1b[2J")?;
+        io::stdout().flush()?;
+        Ok(screen)
+    }
+}
+
+impl Drop for Screen<'_> {
+    fn drop(&mut self) {
+        restore(self.saved);
+    }
+}
+
+/// Keys read from the terminal on a thread of their own, until stopped so a command run from the
+/// dashboard gets the terminal to itself.
+struct Input {
+    stop: Arc<AtomicBool>,
+    thread: thread::JoinHandle<()>,
+}
+
+impl Input {
+    fn start(tx: UnboundedSender<Update>) -> Input {
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        let thread = thread::spawn(move || {
+            let mut buffer = [0; 64];
+            while !stopped.load(Ordering::Relaxed) {
+                let read = match io::stdin().read(&mut buffer) {
+                    Ok(read) => read,
+                    Err(_) => break,
+                };
+                for key in Key::parse(&buffer[..read]) {
+                    if tx.send(Update::Key(key)).is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+        Input { stop, thread }
+    }
+
+    fn stop(self) {
+        self.stop.store(true, Ordering::Relaxed);
+        let _ = self.thread.join();
+    }
+}
+
+/// SIGWINCH, sent when the terminal is resized. Platforms without it never receive one.
+struct Resize {
+    #[cfg(unix)]
+    signal: tokio::signal::unix::Signal,
+}
+
+impl Resize {
+    fn new() -> io::Result<Resize> {
+        Ok(Resize {
+            #[cfg(unix)]
+            signal: tokio::signal::unix::signal(tokio::signal::unix::SignalKind::window_change())?,
+        })
+    }
+}
+
+async fn recv_resize(resize: &mut Option<Resize>) {
+    match resize {
+        #[cfg(unix)]
+        Some(resize) => {
+            resize.signal.recv().await;
+        }
+        _ => std::future::pending::<()>().await,
+    }
+}
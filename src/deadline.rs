@@ -0,0 +1,127 @@
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+use crate::model::RukuConfig;
+
+/// How long a deploy and its stages may take, from `deploy_timeout` and `stage_timeouts`.
+pub struct Deadlines {
+    started: Instant,
+    overall: Option<Duration>,
+    stages: BTreeMap<String, Duration>,
+}
+
+impl Deadlines {
+    /// The limits of `config`, the overall one counted from now.
+    pub fn new(config: &RukuConfig) -> Deadlines {
+        Deadlines {
+            started: Instant::now(),
+            overall: config.deploy_timeout.map(Duration::from_secs),
+            stages: config
+                .stage_timeouts
+                .iter()
+                .map(|(stage, seconds)| (stage.clone(), Duration::from_secs(*seconds)))
+                .collect(),
+        }
+    }
+
+    /// The time `stage` has left and the setting that sets it, none without a limit.
+    fn limit(&self, stage: &str) -> Option<(Duration, String)> {
+        let overall = self.overall.map(|overall| {
+            (
+                overall.saturating_sub(self.started.elapsed()),
+                format!("deploy_timeout of {}s", overall.as_secs()),
+            )
+        });
+        let own = self
+            .stages
+            .get(stage)
+            .map(|limit| (*limit, format!("stage_timeouts.{} of {}s", stage, limit.as_secs())));
+        match (overall, own) {
+            (Some(overall), Some(own)) => Some(if own.0 < overall.0 { own } else { overall }),
+            (overall, own) => overall.or(own),
+        }
+    }
+
+    /// Run `future` as `stage`, dropping it at an await point once the stage runs out of time.
+    pub async fn run<T>(&self, stage: &str, future: impl Future<Output = T>) -> Result<T, String> {
+        match self.limit(stage) {
+            Some((left, setting)) => tokio::time::timeout(left, future)
+                .await
+                .map_err(|_| format!("The {} stage timed out, the deploy ran over its {}", stage, setting)),
+            None => Ok(future.await),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use validator::Validate;
+
+    use super::*;
+
+    fn config(yaml: &str) -> RukuConfig {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn the_shorter_limit_applies() {
+        let deadlines = Deadlines::new(&config(
+            "version: '1.0'\ndeploy_timeout: 10m\nstage_timeouts:\n  health: 120\n  build: 1h",
+        ));
+        assert_eq!(
+            deadlines.limit("health").map(|(_, setting)| setting).as_deref(),
+            Some("stage_timeouts.health of 120s")
+        );
+        assert_eq!(
+            deadlines.limit("build").map(|(_, setting)| setting).as_deref(),
+            Some("deploy_timeout of 600s")
+        );
+        let (left, _) = deadlines.limit("smoke").unwrap();
+        assert!(left <= Duration::from_secs(600) && left > Duration::from_secs(590));
+        assert!(Deadlines::new(&config("version: '1.0'")).limit("build").is_none());
+    }
+
+    #[tokio::test]
+    async fn a_stage_is_cut_off_when_it_runs_out_of_time() {
+        let deadlines = Deadlines {
+            started: Instant::now(),
+            overall: None,
+            stages: BTreeMap::from([("health".to_string(), Duration::from_millis(20))]),
+        };
+        let slow = deadlines
+            .run("health", tokio::time::sleep(Duration::from_secs(5)))
+            .await;
+        assert_eq!(
+            slow,
+            Err("The health stage timed out, the deploy ran over its stage_timeouts.health of 0s".to_string())
+        );
+        assert_eq!(deadlines.run("health", async { 1 }).await, Ok(1));
+        assert_eq!(deadlines.run("build", async { 2 }).await, Ok(2));
+
+        // What the deploy already took counts against the overall limit
+        let spent = Deadlines {
+            started: Instant::now() - Duration::from_secs(60),
+            overall: Some(Duration::from_secs(60)),
+            stages: BTreeMap::new(),
+        };
+        assert!(spent
+            .run("smoke", tokio::time::sleep(Duration::from_secs(5)))
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn only_known_stages_can_be_limited() {
+        assert!(config("version: '1.0'\nstage_timeouts:\n  start: 30")
+            .validate()
+            .is_ok());
+        assert!(config("version: '1.0'\nstage_timeouts:\n  scan: 30")
+            .validate()
+            .is_err());
+        assert!(config("version: '1.0'\nstage_timeouts:\n  start: 0")
+            .validate()
+            .is_err());
+        assert!(config("version: '1.0'\ndeploy_timeout: 0").validate().is_err());
+    }
+}
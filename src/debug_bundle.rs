@@ -0,0 +1,430 @@
+use std::fs::File;
+use std::path::Path;
+
+use bollard::container::LogsOptions;
+use bollard::Docker;
+use chrono::Utc;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use futures_util::StreamExt;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::api_trace;
+use crate::config::load_ruku_config_with_provenance;
+use crate::container::Container;
+use crate::deploys::Deploys;
+use crate::diff::is_secret_path;
+use crate::logger::Logger;
+use crate::metrics::Metrics;
+use crate::model::RukuConfig;
+use crate::provenance::Provenance;
+use crate::registry::get_credentials;
+use crate::releases::is_secret_key;
+use crate::sbom::Sboms;
+use crate::server_config::ServerConfig;
+use crate::templates::SECRET_MASK;
+use crate::version::{self, BuildInfo};
+
+/// Lines of container output the bundle keeps.
+const LOG_LINES: usize = 500;
+/// Values shorter than this are not masked in free text, they would mask half the words of a log.
+const MIN_SECRET_LENGTH: usize = 4;
+
+/// Masks secrets in what goes into a debug bundle: the values of secret keys and env entries wherever
+/// they are found, and every known secret value in free text.
+#[derive(Debug, Default)]
+pub struct Redactor {
+    /// Known secret values, longest first so a secret containing another is masked whole.
+    secrets: Vec<String>,
+}
+
+impl Redactor {
+    pub fn new() -> Redactor {
+        Redactor::default()
+    }
+
+    /// Mask `value` wherever it appears, also in its JSON escaped form.
+    pub fn add_secret(&mut self, value: &str) {
+        if value.len() < MIN_SECRET_LENGTH {
+            return;
+        }
+        let escaped = serde_json::to_string(value).unwrap();
+        for secret in [value.to_string(), escaped[1..escaped.len() - 1].to_string()] {
+            if !self.secrets.contains(&secret) {
+                self.secrets.push(secret);
+            }
+        }
+        self.secrets.sort_by_key(|secret| std::cmp::Reverse(secret.len()));
+    }
+
+    /// The values of `KEY=VALUE` env entries are secret, the names are kept.
+    pub fn add_env(&mut self, env: &[String]) {
+        for entry in env {
+            if let Some((_, value)) = entry.split_once('=') {
+                self.add_secret(value);
+            }
+        }
+    }
+
+    pub fn redact_text(&self, text: &str) -> String {
+        self.secrets.iter().fold(text.to_string(), |text, secret| {
+            text.replace(secret.as_str(), SECRET_MASK)
+        })
+    }
+
+    /// Mask the values of secret keys and of `Env` entries, then the known secrets in every string.
+    pub fn redact_json(&self, value: &mut Value) {
+        match value {
+            Value::Object(object) => {
+                for (key, value) in object.iter_mut() {
+                    match value {
+                        Value::String(text) if is_secret_key(key) || key.eq_ignore_ascii_case("auth") => {
+                            *text = SECRET_MASK.to_string();
+                        }
+                        Value::Array(entries) if key.eq_ignore_ascii_case("env") => {
+                            for entry in entries.iter_mut() {
+                                if let Value::String(text) = entry {
+                                    if let Some((name, _)) = text.split_once('=') {
+                                        *text = format!("{}={}", name, SECRET_MASK);
+                                    }
+                                }
+                            }
+                        }
+                        Value::Object(env) if key.eq_ignore_ascii_case("env") => {
+                            for value in env.values_mut() {
+                                *value = Value::String(SECRET_MASK.to_string());
+                            }
+                        }
+                        value => self.redact_json(value),
+                    }
+                }
+            }
+            Value::Array(values) => values.iter_mut().for_each(|value| self.redact_json(value)),
+            Value::String(text) => *text = self.redact_text(text),
+            _ => {}
+        }
+    }
+}
+
+/// A config field in the bundle, secrets masked.
+#[derive(Debug, Serialize)]
+struct BundleField {
+    key: String,
+    value: String,
+    source: String,
+}
+
+#[derive(Debug, Serialize)]
+struct BundleManifest {
+    ruku_version: String,
+    /// The commit and build date along with the version, so a report from an old build is recognized.
+    ruku_build: BuildInfo,
+    app: String,
+    deploy: Option<String>,
+    created_at: String,
+    /// What could not be collected, the rest of the bundle is still written.
+    missing: Vec<String>,
+    /// Where the SBOM of the last deploy with one is kept on the host, left out of the bundle for its size.
+    sbom: Option<String>,
+}
+
+/// Collects what a bug report about a failed deploy needs into one `.tar.gz`, with secrets masked.
+pub struct DebugBundle<'a> {
+    log: &'a Logger,
+    name: &'a str,
+    server_config: &'a ServerConfig,
+    docker: &'a Docker,
+}
+
+impl<'a> DebugBundle<'a> {
+    pub fn new(log: &'a Logger, name: &'a str, server_config: &'a ServerConfig, docker: &'a Docker) -> DebugBundle<'a> {
+        DebugBundle {
+            log,
+            name,
+            server_config,
+            docker,
+        }
+    }
+
+    /// Write the bundle to `output`, with the record and log of the detached deploy `deploy_id`.
+    pub async fn write(&self, output: &Path, deploy_id: Option<&str>) -> Result<(), String> {
+        let mut redactor = Redactor::new();
+        let mut missing = vec![];
+        let mut files: Vec<(&str, Vec<u8>)> = vec![];
+
+        // Every secret has to be known before anything is redacted
+        let config = load_ruku_config_with_provenance(self.name, self.server_config);
+        let fields = match &config {
+            Ok((config, provenance)) => {
+                let fields = config_fields(config, provenance, &mut redactor);
+                if let Some(credentials) = config
+                    .build
+                    .as_ref()
+                    .and_then(|build| build.registry.as_deref())
+                    .and_then(get_credentials)
+                {
+                    let secrets = [credentials.password, credentials.auth, credentials.identitytoken];
+                    for secret in secrets.iter().flatten() {
+                        redactor.add_secret(secret);
+                    }
+                }
+                Some(fields)
+            }
+            Err(e) => {
+                missing.push(format!("config: {}", e));
+                None
+            }
+        };
+        if let Ok(password) = std::env::var("RUKU_REGISTRY_PASSWORD") {
+            redactor.add_secret(&password);
+        }
+        let inspect = match &config {
+            Ok((config, _)) => {
+                let container = Container::new(self.log, self.name, self.docker, config);
+                match self.docker.inspect_container(container.container_name(), None).await {
+                    Ok(inspect) => {
+                        let env = inspect.config.as_ref().and_then(|config| config.env.clone());
+                        redactor.add_env(&env.unwrap_or_default());
+                        Some((container.container_name().to_string(), inspect))
+                    }
+                    Err(e) => {
+                        missing.push(format!("container: {}", e));
+                        None
+                    }
+                }
+            }
+            Err(_) => None,
+        };
+
+        if let Some(fields) = fields {
+            let fields: Vec<BundleField> = fields
+                .into_iter()
+                .map(|field| BundleField {
+                    value: redactor.redact_text(&field.value),
+                    ..field
+                })
+                .collect();
+            files.push(("config.json", to_json(&fields, &redactor)));
+        }
+        let metrics = Metrics::new(self.log, &self.server_config.state_root.join(self.name)).load();
+        files.push(("timings.json", to_json(&metrics, &redactor)));
+        if let Some(id) = deploy_id {
+            let deploys = Deploys::new(self.log, self.server_config);
+            let mut request = deploys.get(id)?;
+            // The queued ruku.yml is in config.json, resolved and masked
+            request.config = SECRET_MASK.to_string();
+            files.push(("deploy.json", to_json(&request, &redactor)));
+            match deploys.read_log(id) {
+                Some(log) => files.push(("deploy.log", redactor.redact_text(&log).into_bytes())),
+                None => missing.push("deploy log: not found".to_string()),
+            }
+        }
+        if let Some((container_name, inspect)) = &inspect {
+            files.push(("container.json", to_json(inspect, &redactor)));
+            let output = self.container_output(container_name).await;
+            files.push(("container.log", redactor.redact_text(&output).into_bytes()));
+        }
+        match (self.docker.version().await, self.docker.info().await) {
+            (Ok(version), Ok(info)) => {
+                let docker = serde_json::json!({ "version": version, "info": info });
+                files.push(("docker.json", to_json(&docker, &redactor)));
+            }
+            (Err(e), _) | (_, Err(e)) => missing.push(format!("docker: {}", e)),
+        }
+        // Left by --debug-api, the calls are masked already but may carry the secrets of the app in free text
+        for (name, path) in api_trace::trace_files(&self.server_config.state_root) {
+            if let Ok(trace) = std::fs::read_to_string(path) {
+                files.push((name, redactor.redact_text(&trace).into_bytes()));
+            }
+        }
+        let sboms = Sboms::new(self.log, &self.server_config.state_root.join(self.name));
+        let manifest = BundleManifest {
+            ruku_version: version::LONG_VERSION.to_string(),
+            ruku_build: version::build_info(),
+            app: self.name.to_string(),
+            deploy: deploy_id.map(str::to_string),
+            created_at: Utc::now().to_rfc3339(),
+            missing,
+            sbom: sboms.ids().last().map(|id| sboms.path(id).display().to_string()),
+        };
+        files.insert(0, ("manifest.json", to_json(&manifest, &redactor)));
+
+        self.write_archive(output, &files)?;
+        for reason in &manifest.missing {
+            self.log.warn(&format!("Left out {}", reason));
+        }
+        self.log.step(&format!(
+            "Wrote {}, secrets are masked but look it over before attaching it to an issue",
+            output.display()
+        ));
+        Ok(())
+    }
+
+    /// The last lines of the container output, stdout and stderr interleaved with timestamps.
+    async fn container_output(&self, container_name: &str) -> String {
+        let options = LogsOptions::<String> {
+            stdout: true,
+            stderr: true,
+            timestamps: true,
+            tail: LOG_LINES.to_string(),
+            ..Default::default()
+        };
+        let mut output = String::new();
+        let mut stream = self.docker.logs(container_name, Some(options));
+        while let Some(Ok(chunk)) = stream.next().await {
+            // The stream of a tty container has the line endings of the terminal
+            output.push_str(&String::from_utf8_lossy(chunk.as_ref()).replace("\r\n", "\n"));
+        }
+        output
+    }
+
+    fn write_archive(&self, output: &Path, files: &[(&str, Vec<u8>)]) -> Result<(), String> {
+        let file = File::create(output).map_err(|e| format!("Error creating {}: {}", output.display(), e))?;
+        let mut builder = tar::Builder::new(GzEncoder::new(file, Compression::default()));
+        let mtime = Utc::now().timestamp() as u64;
+        let mut result = Ok(());
+        for (name, content) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            header.set_mtime(mtime);
+            header.set_cksum();
+            result = result.and_then(|_| builder.append_data(&mut header, name, content.as_slice()));
+        }
+        result
+            .and_then(|_| builder.into_inner())
+            .and_then(|encoder| encoder.finish())
+            .map_err(|e| format!("Error writing {}: {}", output.display(), e))?;
+        Ok(())
+    }
+}
+
+/// The resolved config with secret paths masked, their values and every env value noted as secrets.
+fn config_fields(config: &RukuConfig, provenance: &Provenance, redactor: &mut Redactor) -> Vec<BundleField> {
+    let env = config
+        .sidecars
+        .iter()
+        .flat_map(|sidecar| sidecar.env.values())
+        .chain(config.preview.iter().flat_map(|preview| preview.env.values()));
+    for value in env {
+        redactor.add_secret(value);
+    }
+    provenance
+        .explain(config)
+        .into_iter()
+        .map(|field| {
+            let secret = is_secret_path(&field.key, &config.secrets);
+            if secret {
+                redactor.add_secret(field.value.trim_matches('"'));
+            }
+            BundleField {
+                value: if secret { SECRET_MASK.to_string() } else { field.value },
+                source: field.source.to_string(),
+                key: field.key,
+            }
+        })
+        .collect()
+}
+
+fn to_json(value: &impl Serialize, redactor: &Redactor) -> Vec<u8> {
+    let mut value = serde_json::to_value(value).unwrap_or(Value::Null);
+    redactor.redact_json(&mut value);
+    serde_json::to_vec_pretty(&value).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    const CONFIG: &str = r#"
+version: "1.4"
+secrets:
+  STRIPE: vault:kv/data/shop#STRIPE
+  DB_PASSWORD: vault:kv/data/shop#DB_PASSWORD
+preview:
+  env:
+    STRIPE: sk_live_preview
+    BASE_URL: http://preview.example.com
+sidecars:
+  - name: db
+    image: postgres:16
+    env:
+      POSTGRES_PASSWORD: hunter2-db
+"#;
+
+    #[test]
+    fn secret_paths_are_the_credential_names_and_env_of_secrets() {
+        let secrets = BTreeMap::from([("STRIPE".to_string(), "vault:kv/data/shop#STRIPE".to_string())]);
+        for path in [
+            "secrets.DB_PASSWORD",
+            "build.registry_token",
+            "observability.api_key",
+            "env.STRIPE",
+            "preview.env.STRIPE",
+            "sidecars.env.STRIPE",
+        ] {
+            assert!(is_secret_path(path, &secrets), "{}", path);
+        }
+        for path in ["version", "env.BASE_URL", "preview.env.BASE_URL", "environment.STRIPE"] {
+            assert!(!is_secret_path(path, &secrets), "{}", path);
+        }
+    }
+
+    #[test]
+    fn config_fields_mask_every_secret_path() {
+        let config: RukuConfig = serde_yaml::from_str(CONFIG).unwrap();
+        let mut redactor = Redactor::new();
+        let fields = config_fields(&config, &Provenance::new(), &mut redactor);
+        let bundled = String::from_utf8(to_json(&fields, &redactor)).unwrap();
+
+        let value = |key: &str| fields.iter().find(|field| field.key == key).unwrap().value.clone();
+        assert_eq!(value("preview.env.STRIPE"), SECRET_MASK);
+        assert_eq!(value("secrets.DB_PASSWORD"), SECRET_MASK);
+        assert_eq!(value("version"), "\"1.4\"");
+        // Env values are masked wherever they appear, the sidecars are one field with their env inside
+        for leaked in [
+            "sk_live_preview",
+            "hunter2-db",
+            "preview.example.com",
+            "kv/data/shop#DB_PASSWORD",
+        ] {
+            assert!(!bundled.contains(leaked), "{} in {}", leaked, bundled);
+        }
+        assert!(bundled.contains("postgres:16"));
+    }
+
+    #[test]
+    fn inspect_output_keeps_names_and_masks_values() {
+        let mut redactor = Redactor::new();
+        redactor.add_env(&["TOKEN=abc\"def".to_string()]);
+        let mut inspect = serde_json::json!({
+            "Config": {
+                "Env": ["TOKEN=abc\"def", "PATH=/usr/bin"],
+                "Cmd": ["serve", "--token", "abc\"def"],
+                "Labels": {"registry_password": "hunter2", "team": "web"}
+            },
+            "Auth": "dXNlcjpwYXNz",
+            "env": {"A": "1"}
+        });
+        redactor.redact_json(&mut inspect);
+        let mask = SECRET_MASK;
+        assert_eq!(
+            inspect["Config"]["Env"],
+            serde_json::json!([format!("TOKEN={}", mask), format!("PATH={}", mask)])
+        );
+        assert_eq!(inspect["Config"]["Cmd"][2], mask);
+        assert_eq!(inspect["Config"]["Labels"]["registry_password"], mask);
+        assert_eq!(inspect["Config"]["Labels"]["team"], "web");
+        assert_eq!(inspect["Auth"], mask);
+        assert_eq!(inspect["env"]["A"], mask);
+        // Short values would mask common words, only in free text
+        redactor.add_secret("ab");
+        assert_eq!(redactor.redact_text("ab TOKEN abc\"def"), format!("ab TOKEN {}", mask));
+        assert_eq!(
+            redactor.redact_text(r#"{"t":"abc\"def"}"#),
+            format!(r#"{{"t":"{}"}}"#, mask)
+        );
+    }
+}
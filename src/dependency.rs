@@ -0,0 +1,103 @@
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use bollard::models::HealthStatusEnum;
+use bollard::Docker;
+
+use crate::logger::Logger;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A container the app needs running before it starts.
+pub struct Dependency {
+    /// The name in `depends_on`.
+    pub name: String,
+    /// The container, `<prefix><app>` for a ruku app or the name itself for any other container.
+    pub container_name: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DependencyState {
+    Missing,
+    Stopped,
+    /// Running, the healthcheck hasn't passed yet.
+    Starting,
+    Unhealthy,
+    /// Running and healthy, or running without a healthcheck.
+    Ready,
+}
+
+impl fmt::Display for DependencyState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let state = match self {
+            DependencyState::Missing => "missing",
+            DependencyState::Stopped => "stopped",
+            DependencyState::Starting => "starting",
+            DependencyState::Unhealthy => "unhealthy",
+            DependencyState::Ready => "ready",
+        };
+        write!(f, "{}", state)
+    }
+}
+
+/// Checks the dependencies of an app.
+pub struct Dependencies<'a> {
+    log: &'a Logger,
+    docker: &'a Docker,
+}
+
+impl<'a> Dependencies<'a> {
+    pub fn new(log: &'a Logger, docker: &'a Docker) -> Dependencies<'a> {
+        Dependencies { log, docker }
+    }
+
+    pub async fn state(&self, dependency: &Dependency) -> DependencyState {
+        let Ok(inspect) = self.docker.inspect_container(&dependency.container_name, None).await else {
+            return DependencyState::Missing;
+        };
+        let state = inspect.state.unwrap_or_default();
+        if !state.running.unwrap_or(false) {
+            return DependencyState::Stopped;
+        }
+        match state.health.and_then(|health| health.status) {
+            Some(HealthStatusEnum::STARTING) => DependencyState::Starting,
+            Some(HealthStatusEnum::UNHEALTHY) => DependencyState::Unhealthy,
+            _ => DependencyState::Ready,
+        }
+    }
+
+    /// Wait up to `timeout` for every dependency to be ready, failing with the ones that are not.
+    pub async fn wait(&self, dependencies: &[Dependency], timeout: Duration) -> Result<(), String> {
+        if dependencies.is_empty() {
+            return Ok(());
+        }
+        let deadline = Instant::now() + timeout;
+        let mut waiting_logged = false;
+
+        loop {
+            let mut not_ready = vec![];
+            for dependency in dependencies {
+                let state = self.state(dependency).await;
+                if state != DependencyState::Ready {
+                    not_ready.push(format!("{} is {}", dependency.name, state));
+                }
+            }
+            if not_ready.is_empty() {
+                self.log.step("All dependencies are ready");
+                return Ok(());
+            }
+            if Instant::now() >= deadline {
+                return Err(format!("Dependencies are not ready: {}", not_ready.join(", ")));
+            }
+            if !waiting_logged {
+                self.log.step(&format!(
+                    "Waiting up to {}s for dependencies: {}",
+                    timeout.as_secs(),
+                    not_ready.join(", ")
+                ));
+                waiting_logged = true;
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    }
+}
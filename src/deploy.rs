@@ -1,18 +1,69 @@
-use nixpacks::create_docker_image;
-use nixpacks::nixpacks::builder::docker::DockerBuilderOptions;
-use nixpacks::nixpacks::plan::{generator::GeneratePlanOptions, BuildPlan};
+use std::collections::BTreeMap;
+use std::fs;
+use std::future::Future;
+use std::path::Path;
+use std::time::{Duration, Instant};
 
-use crate::container::Container;
+use bollard::Docker;
+
+use crate::build::{detect_builder, ImageBuild};
+use crate::build_cache::CacheUse;
+use crate::buildx::Buildx;
+use crate::bundle::LoadedImage;
+use crate::container::{Container, DEFAULT_HEALTH_TIMEOUT};
+use crate::deadline::Deadlines;
+use crate::failures::Failures;
+use crate::image::Image;
+use crate::inflight::{InFlight, Turn};
 use crate::logger::Logger;
-use crate::misc::get_image_name_with_version;
-use crate::model::RukuConfig;
+use crate::misc::{get_image_name_with_version, get_registry_image_name, get_version};
+use crate::model::{Builder, RukuConfig};
+use crate::policy::{base_images, ImageFacts, Policy};
+use crate::reload::Reload;
+use crate::scan::{Scan, ScanSummary};
+use crate::sidecar::Sidecars;
+use crate::slots::DeploySlots;
+use crate::smoke::SmokeResult;
+use crate::static_site::{static_site, write_context};
+use crate::strategy;
+
+/// The OCI archive in the state directory a multi-platform image is scanned in before it is pushed.
+const MULTI_PLATFORM_ARCHIVE: &str = "multi-platform.oci.tar";
+
+/// What a finished deploy produced.
+pub struct DeployReport {
+    /// Registry digest of the image when it was pushed.
+    pub digest: Option<String>,
+    /// Seconds each stage took, keyed by stage name.
+    pub stages: BTreeMap<String, f64>,
+    /// Vulnerability counts when the image was scanned.
+    pub scan: Option<ScanSummary>,
+    /// Smoke checks the new version passed, when the strategy ran them itself.
+    pub smoke: Vec<SmokeResult>,
+    /// How much of the build came from the build cache, when it was built and the builder tells.
+    pub cache: Option<CacheUse>,
+}
 
 pub struct Deploy<'a> {
     log: &'a Logger,
     name: &'a str,
     path: &'a str,
+    state_path: &'a Path,
     config: &'a RukuConfig,
+    docker: &'a Docker,
     container: &'a Container<'a>,
+    show_context: bool,
+    skip_scan: bool,
+    no_cache: bool,
+    pull: bool,
+    slots: Option<&'a DeploySlots<'a>>,
+    in_flight: Option<&'a InFlight<'a>>,
+    reload: Option<&'a Reload<'a>>,
+    failures: Option<&'a Failures<'a>>,
+    deadlines: Option<&'a Deadlines>,
+    policy: Option<&'a Policy<'a>>,
+    health_timeout: Duration,
+    start: bool,
 }
 
 impl<'a> Deploy<'a> {
@@ -20,69 +71,387 @@ impl<'a> Deploy<'a> {
         log: &'a Logger,
         name: &'a str,
         path: &'a str,
+        state_path: &'a Path,
         config: &'a RukuConfig,
+        docker: &'a Docker,
         container: &'a Container<'a>,
     ) -> Deploy<'a> {
         Deploy {
             log,
             name,
             path,
+            state_path,
             config,
+            docker,
             container,
+            show_context: false,
+            skip_scan: false,
+            no_cache: false,
+            pull: false,
+            slots: None,
+            in_flight: None,
+            reload: None,
+            failures: None,
+            deadlines: None,
+            policy: None,
+            health_timeout: Duration::from_secs(DEFAULT_HEALTH_TIMEOUT),
+            start: true,
         }
     }
 
-    pub async fn run(&self) {
-        self.log.step(&format!("Running from {}", self.path));
+    /// List the paths of the Dockerfile build context while building.
+    pub fn with_show_context(mut self, show_context: bool) -> Deploy<'a> {
+        self.show_context = show_context;
+        self
+    }
+
+    /// Deploy without the configured vulnerability scan, for emergencies.
+    pub fn with_skip_scan(mut self, skip_scan: bool) -> Deploy<'a> {
+        self.skip_scan = skip_scan;
+        self
+    }
+
+    /// Build every step again instead of taking it from the build cache.
+    pub fn with_no_cache(mut self, no_cache: bool) -> Deploy<'a> {
+        self.no_cache = no_cache;
+        self
+    }
+
+    /// Pull newer versions of the base images before building.
+    pub fn with_pull(mut self, pull: bool) -> Deploy<'a> {
+        self.pull = pull;
+        self
+    }
+
+    /// Throttle the build and pull against the deploys of other apps on the host.
+    pub fn with_deploy_slots(mut self, slots: &'a DeploySlots<'a>) -> Deploy<'a> {
+        self.slots = Some(slots);
+        self
+    }
 
-        // Nix pack
-        let envs: Vec<&str> = vec![];
-        let cli_plan = BuildPlan::default();
-        let options = GeneratePlanOptions {
-            plan: Some(cli_plan),
-            config_file: None,
+    /// Reload the running container instead of replacing it when the build leaves the image as it was.
+    pub fn with_reload(mut self, reload: Option<&'a Reload<'a>>) -> Deploy<'a> {
+        self.reload = reload;
+        self
+    }
+
+    /// Check the image against `policy` before the running container is touched.
+    pub fn with_policy(mut self, policy: Option<&'a Policy<'a>>) -> Deploy<'a> {
+        self.policy = policy;
+        self
+    }
+
+    /// Keep the logs of the containers a failed deploy removes.
+    pub fn with_failures(mut self, failures: &'a Failures<'a>) -> Deploy<'a> {
+        self.failures = Some(failures);
+        self
+    }
+
+    /// Abort a stage that runs over the time it has left, rolling the new version back once it is started.
+    pub fn with_deadlines(mut self, deadlines: &'a Deadlines) -> Deploy<'a> {
+        self.deadlines = Some(deadlines);
+        self
+    }
+
+    /// Share the build with other ruku processes building the same image at the same time.
+    pub fn with_in_flight(mut self, in_flight: &'a InFlight<'a>) -> Deploy<'a> {
+        self.in_flight = Some(in_flight);
+        self
+    }
+
+    /// How long a strategy that gates on health waits for the new container.
+    pub fn with_health_timeout(mut self, timeout: Duration) -> Deploy<'a> {
+        self.health_timeout = timeout;
+        self
+    }
+
+    /// Stop once the image is built, scanned, checked and pushed, leaving the sidecars and the running
+    /// version as they are.
+    pub fn with_start(mut self, start: bool) -> Deploy<'a> {
+        self.start = start;
+        self
+    }
+
+    /// Build and start the app.
+    pub async fn run(&self) -> Result<DeployReport, String> {
+        self.log.step(&format!("Running from {}", self.path));
+        let mut stages = BTreeMap::new();
+        let mut stage_started = Instant::now();
+        let mut end_stage = |stage: &str| {
+            let seconds = stage_started.elapsed().as_secs_f64();
+            self.log.stage_completed(stage, seconds);
+            stages.insert(stage.to_string(), seconds);
+            stage_started = Instant::now();
         };
+        self.log.stage_started("build");
 
         let image_name_with_version = get_image_name_with_version(self.name, &self.config.version);
 
-        let build_options = DockerBuilderOptions {
-            name: Some(self.name.to_string()),
-            out_dir: None,
-            print_dockerfile: false,
-            tags: vec![image_name_with_version.clone()],
-            labels: vec![],
-            quiet: false,
-            cache_key: None,
-            no_cache: false,
-            inline_cache: false,
-            cache_from: None,
-            platform: vec![],
-            current_dir: true,
-            no_error_without_start: false,
-            incremental_cache_image: None,
-            cpu_quota: None,
-            memory: None,
-            verbose: false,
-            docker_host: None,
-            docker_tls_verify: None,
-            docker_output: None,
-            add_host: vec![],
-            docker_cert_path: None,
+        // Multi-platform images are pushed straight to the registry, they can't live only in the local store
+        let build = self.config.build.as_ref();
+        let platforms = build.map(|b| b.platforms.clone()).unwrap_or_default();
+        let registry_image = build
+            .filter(|b| b.is_multi_platform())
+            .and_then(|b| b.registry.as_deref())
+            .map(|registry| get_registry_image_name(registry, self.name, &self.config.version));
+        if registry_image.is_some() {
+            Buildx::new(self.log, self.docker).check(&platforms).await?;
+        }
+        let build_tag = registry_image.clone().unwrap_or(image_name_with_version.clone());
+        // A multi-platform image to scan is built into an archive and pushed once it passed
+        let archive = registry_image
+            .as_ref()
+            .filter(|_| self.config.scan.is_some() && !self.skip_scan)
+            .map(|_| self.state_path.join(MULTI_PLATFORM_ARCHIVE));
+        // Outlives the build, the push of an archived image sends the context again
+        let static_context;
+        let mut archived = None;
+
+        let mut cache = None;
+        let loaded = match LoadedImage::read(self.state_path) {
+            Some(loaded) if loaded.image == image_name_with_version => {
+                Image::new(self.log, self.docker).exists(&loaded.image).await
+            }
+            _ => false,
+        };
+        // Waited for without a deploy slot, the process building the image holds one
+        let turn = match self.in_flight {
+            Some(in_flight) if !loaded => Some(in_flight.turn(&build_tag, "build").await),
+            _ => None,
         };
+        let reused = match &turn {
+            Some(Turn::Reuse(pid)) => {
+                let exists = Image::new(self.log, self.docker).exists(&image_name_with_version).await;
+                if exists {
+                    self.log.step(&format!(
+                        "Using image {} built by pid {}, skipping the build",
+                        image_name_with_version, pid
+                    ));
+                }
+                exists
+            }
+            _ => false,
+        };
+        if loaded {
+            // Loaded with image:save and image:load, the source may not even be on this host
+            self.log.step(&format!(
+                "Using loaded image {}, skipping the build",
+                image_name_with_version
+            ));
+        } else if !reused {
+            // Held through the build and the pull of a multi-platform image, the heavy load on the daemon
+            let _slot = match self.slots {
+                Some(slots) => slots.acquire().await?,
+                None => None,
+            };
+            // A static site is built from a generated context on top of the web server image
+            static_context = match static_site(self.config) {
+                Some(_) => {
+                    let context = write_context(Path::new(self.path), self.state_path, self.config)
+                        .map_err(|e| format!("Error preparing the static site: {}", e))?;
+                    Some(context.display().to_string())
+                }
+                None => None,
+            };
+            let builder = match static_context {
+                Some(_) => Builder::Dockerfile,
+                None => detect_builder(Path::new(self.path), build.and_then(|b| b.builder)),
+            };
+            let image_build = ImageBuild::new(
+                self.log,
+                self.name,
+                static_context.as_deref().unwrap_or(self.path),
+                self.state_path,
+                build_tag,
+                platforms,
+                registry_image.is_some(),
+            )
+            .with_show_context(self.show_context)
+            .with_no_cache(self.no_cache)
+            .with_pull(self.pull)
+            .with_cache_from(build.map(|b| b.cache_from.clone()).unwrap_or_default())
+            .with_archive(archive.clone());
+            cache = self
+                .within(
+                    "build",
+                    image_build.run(builder, build.and_then(|b| b.pack_builder.as_deref())),
+                )
+                .await??;
 
-        create_docker_image(self.path, envs, &options, &build_options)
-            .await
-            .unwrap_or_else(|e| {
-                self.log
-                    .error(&format!("Error creating Docker image at path {}: {}", self.path, e));
-                std::process::exit(1);
+            match &registry_image {
+                Some(_) if archive.is_some() => archived = Some((image_build, builder)),
+                Some(registry_image) => {
+                    self.log
+                        .step(&format!("Pushed multi-platform image {}", registry_image));
+                    self.pull_variant(registry_image, &image_name_with_version).await?;
+                }
+                None => {}
+            }
+        }
+        if archived.is_none() {
+            self.log.step(&format!(
+                "Image created successfully with tag {}",
+                image_name_with_version
+            ));
+        }
+        end_stage("build");
+
+        // Scanned before it reaches the registry, the local store only gets it back from there
+        let mut archive_scan = None;
+        if let (Some((image_build, builder)), Some(registry_image), Some(archive)) =
+            (&archived, &registry_image, &archive)
+        {
+            let scan = self.config.scan.as_ref().unwrap();
+            self.log.stage_started("scan");
+            archive_scan = Some(Scan::new(self.log, scan).run_archive(registry_image, archive)?);
+            end_stage("scan");
+            self.log.stage_started("push");
+            let pack_builder = build.and_then(|b| b.pack_builder.as_deref());
+            self.within("push", image_build.push(*builder, pack_builder)).await??;
+            self.log
+                .step(&format!("Pushed multi-platform image {}", registry_image));
+            self.pull_variant(registry_image, &image_name_with_version).await?;
+            self.log.step(&format!(
+                "Image created successfully with tag {}",
+                image_name_with_version
+            ));
+            end_stage("push");
+        }
+        if let Some(Turn::Run(lead)) = turn {
+            lead.finish();
+        }
+
+        if let Some(reload) = self.reload {
+            if reload.image_unchanged(&image_name_with_version).await? {
+                self.log.stage_started("reload");
+                reload.run().await?;
+                end_stage("reload");
+                return Ok(DeployReport {
+                    digest: None,
+                    stages,
+                    scan: None,
+                    smoke: vec![],
+                    cache,
+                });
+            }
+            reload.recreate("the build changed the image")?;
+        }
+
+        let scan = match &self.config.scan {
+            Some(_) if archive_scan.is_some() => archive_scan,
+            Some(_) if self.skip_scan => {
+                self.log.warn("Skipping the image scan");
+                None
+            }
+            Some(scan) => {
+                self.log.stage_started("scan");
+                let summary = Scan::new(self.log, scan).run(&image_name_with_version)?;
+                end_stage("scan");
+                Some(summary)
+            }
+            None => None,
+        };
+
+        if let Some(policy) = self.policy {
+            self.log.stage_started("policy");
+            let tag = get_version(&self.config.version);
+            let mut facts = ImageFacts::inspect(self.docker, &image_name_with_version, tag)
+                .await
+                .map_err(|e| format!("Could not inspect {} for the policy: {}", image_name_with_version, e))?;
+            facts.base_images = fs::read_to_string(Path::new(self.path).join("Dockerfile"))
+                .map(|dockerfile| base_images(&dockerfile))
+                .unwrap_or_default();
+            // Only a push leaves the host, an image built here comes from no registry itself
+            facts.pushed_to = self
+                .config
+                .build
+                .as_ref()
+                .filter(|build| build.push)
+                .and_then(|build| build.registry.as_deref())
+                .map(|registry| get_registry_image_name(registry, self.name, &self.config.version));
+            policy.check(self.log, &facts)?;
+            end_stage("policy");
+        }
+
+        // Push before touching the running container so a failed push can still abort the deploy
+        let digest = match self.config.build.as_ref() {
+            Some(build) if build.push && registry_image.is_none() => {
+                self.log.stage_started("push");
+                let registry = build.registry.as_deref().unwrap();
+                let target = get_registry_image_name(registry, self.name, &self.config.version);
+                let image = Image::new(self.log, self.docker);
+                match self
+                    .within("push", image.push(&image_name_with_version, &target))
+                    .await?
+                {
+                    Ok(digest) => digest,
+                    Err(e) if build.push_required => return Err(e),
+                    Err(e) => {
+                        self.log.warn(&format!("{}, continuing with the deploy", e));
+                        None
+                    }
+                }
+            }
+            _ => None,
+        };
+        if digest.is_some() {
+            end_stage("push");
+        }
+        if !self.start {
+            return Ok(DeployReport {
+                digest,
+                stages,
+                scan,
+                smoke: vec![],
+                cache,
             });
+        }
+
+        // Sidecars come up first, one that fails stops the deploy while the old app container still runs
+        if !self.config.sidecars.is_empty() {
+            self.log.stage_started("sidecars");
+            let sidecars = Sidecars::new(self.log, self.name, self.docker, self.config, self.container);
+            self.within("sidecars", sidecars.ensure()).await??;
+            end_stage("sidecars");
+        }
+
+        self.log.stage_started("start");
+        let smoke = strategy::deploy(
+            self.log,
+            self.config,
+            self.container,
+            self.state_path,
+            self.health_timeout,
+            self.failures,
+            self.deadlines,
+        )
+        .await?;
+        end_stage("start");
 
-        self.log.step(&format!(
-            "Image created successfully with tag {}",
-            image_name_with_version
-        ));
+        Ok(DeployReport {
+            digest,
+            stages,
+            scan,
+            smoke,
+            cache,
+        })
+    }
 
-        self.container.run().await;
+    /// Bring the variant of the multi-platform image for this host into the local store under the usual tag.
+    async fn pull_variant(&self, registry_image: &str, image_name_with_version: &str) -> Result<(), String> {
+        let image = Image::new(self.log, self.docker);
+        image.pull(registry_image).await?;
+        image.tag(registry_image, image_name_with_version).await
+    }
+
+    /// Run a stage from before the running version is touched, failing when it runs out of time.
+    async fn within<T>(&self, stage: &str, future: impl Future<Output = T>) -> Result<T, String> {
+        let Some(deadlines) = self.deadlines else {
+            return Ok(future.await);
+        };
+        deadlines
+            .run(stage, future)
+            .await
+            .map_err(|timed_out| format!("{}, the running version was left in place", timed_out))
     }
 }
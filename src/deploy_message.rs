@@ -0,0 +1,37 @@
+/// Env var `run --message` falls back to, for CI that sets the note once.
+pub const DEPLOY_MESSAGE_ENV: &str = "RUKU_DEPLOY_MESSAGE";
+/// Longest note a deploy takes, kept in full in the history.
+pub const MAX_MESSAGE_LENGTH: usize = 1000;
+/// Longest note put on the container label, a longer one is cut there.
+pub const MAX_LABEL_LENGTH: usize = 200;
+
+/// The note of a deploy with surrounding whitespace trimmed, refused when empty or too long or when it
+/// holds control characters other than newlines and tabs.
+pub fn check_message(message: &str) -> Result<String, String> {
+    let message = message.trim();
+    if message.is_empty() {
+        return Err("the deploy message is empty".to_string());
+    }
+    let length = message.chars().count();
+    if length > MAX_MESSAGE_LENGTH {
+        return Err(format!(
+            "the deploy message has {} characters, at most {} are kept",
+            length, MAX_MESSAGE_LENGTH
+        ));
+    }
+    if message.chars().any(|c| c.is_control() && c != '\n' && c != '\t') {
+        return Err("the deploy message holds control characters".to_string());
+    }
+    Ok(message.to_string())
+}
+
+/// The note as a label value: on one line with runs of whitespace made one space, and cut at
+/// [`MAX_LABEL_LENGTH`] characters with an ellipsis.
+pub fn label_value(message: &str) -> String {
+    let line = message.split_whitespace().collect::<Vec<_>>().join(" ");
+    if line.chars().count() <= MAX_LABEL_LENGTH {
+        return line;
+    }
+    let cut: String = line.chars().take(MAX_LABEL_LENGTH - 1).collect();
+    format!("{}…", cut.trim_end())
+}
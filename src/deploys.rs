@@ -0,0 +1,388 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+#[cfg(unix)]
+use std::os::unix::process::CommandExt;
+#[cfg(windows)]
+use std::os::windows::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::connection::{active_context, CONTEXT_ENV};
+use crate::container::Takeover;
+use crate::logger::Logger;
+use crate::model::DeployStrategy;
+use crate::pipeline::{DeployOutcome, DeployPipeline};
+use crate::server_config::ServerConfig;
+use crate::store;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// How often a deploy waiting for the app lock says so.
+const REPORT_INTERVAL: Duration = Duration::from_secs(10);
+const LOCK_FILE_NAME: &str = "deploy.lock";
+/// Process creation flags that keep the worker running without the console it was started from.
+#[cfg(windows)]
+const DETACHED_PROCESS: u32 = 0x0000_0008;
+#[cfg(windows)]
+const CREATE_NEW_PROCESS_GROUP: u32 = 0x0000_0200;
+
+/// Held while a deploy of the app runs, so two deploys never repair, build or swap the same app at once.
+/// The lock belongs to the open file and goes away with the process however it ends.
+pub struct AppLock {
+    _file: File,
+}
+
+impl AppLock {
+    /// Take the deploy lock of the app, none when another deploy holds it.
+    pub fn try_acquire(state_path: &Path) -> Result<Option<AppLock>, String> {
+        fs::create_dir_all(state_path).map_err(|e| format!("Error creating directory: {}", e))?;
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(state_path.join(LOCK_FILE_NAME))
+            .map_err(|e| format!("Error opening deploy lock: {}", e))?;
+        Ok(file.try_lock().ok().map(|_| AppLock { _file: file }))
+    }
+
+    /// Take the deploy lock of the app, waiting for the deploy holding it when `wait` is set and failing
+    /// otherwise.
+    pub async fn acquire(log: &Logger, app: &str, state_path: &Path, wait: bool) -> Result<AppLock, String> {
+        let mut waited = 0;
+        loop {
+            match AppLock::try_acquire(state_path) {
+                Ok(Some(lock)) => return Ok(lock),
+                Ok(None) if wait => {
+                    if waited % REPORT_INTERVAL.as_secs() == 0 {
+                        log.step(&format!("Waiting for the running deploy of {} to finish", app));
+                    }
+                    tokio::time::sleep(POLL_INTERVAL).await;
+                    waited += POLL_INTERVAL.as_secs();
+                }
+                Ok(None) => {
+                    return Err(format!(
+                        "Another deploy of {} is running, see `ruku deploys:status` or try again once it is done",
+                        app
+                    ))
+                }
+                Err(e) => return Err(e.to_string()),
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeployStatus {
+    /// Queued, the worker has not started the deploy.
+    Pending,
+    /// The worker is deploying, or waiting for an earlier deploy of the app.
+    Running,
+    Succeeded,
+    Failed,
+}
+
+impl DeployStatus {
+    pub fn is_finished(&self) -> bool {
+        matches!(self, DeployStatus::Succeeded | DeployStatus::Failed)
+    }
+}
+
+impl std::fmt::Display for DeployStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let status = match self {
+            DeployStatus::Pending => "pending",
+            DeployStatus::Running => "running",
+            DeployStatus::Succeeded => "succeeded",
+            DeployStatus::Failed => "failed",
+        };
+        write!(f, "{}", status)
+    }
+}
+
+/// The `ruku run` flags a detached deploy was queued with.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DeployOptions {
+    pub adopt: bool,
+    pub force_replace: bool,
+    pub show_context: bool,
+    /// Seconds to wait for the container to become healthy.
+    pub wait_healthy: Option<u64>,
+    pub skip_scan: bool,
+    pub skip_pre_start: bool,
+    #[serde(default)]
+    pub no_backup: bool,
+    /// Strategy overriding the one in ruku.yml.
+    #[serde(default)]
+    pub strategy: Option<DeployStrategy>,
+    #[serde(default)]
+    pub no_share: bool,
+    #[serde(default)]
+    pub skip_preflight: bool,
+    #[serde(default)]
+    pub no_cache: bool,
+    #[serde(default)]
+    pub pull: bool,
+    /// Absolute path of the image tarball to deploy instead of building.
+    #[serde(default)]
+    pub image_tarball: Option<PathBuf>,
+    /// Seconds to wait for the image to be pushed before deploying it.
+    #[serde(default)]
+    pub wait_for_image: Option<u64>,
+    /// Manifest digest the tag has to point at.
+    #[serde(default)]
+    pub image_digest: Option<String>,
+    /// The note of the deploy, checked when it was queued.
+    #[serde(default)]
+    pub message: Option<String>,
+}
+
+impl DeployOptions {
+    pub fn apply<'p>(&self, pipeline: DeployPipeline<'p>) -> DeployPipeline<'p> {
+        let takeover = match (self.adopt, self.force_replace) {
+            (true, _) => Takeover::Adopt,
+            (_, true) => Takeover::ForceReplace,
+            _ => Takeover::Refuse,
+        };
+        pipeline
+            .with_takeover(takeover)
+            .with_show_context(self.show_context)
+            .with_wait_healthy(self.wait_healthy.map(Duration::from_secs))
+            .with_skip_scan(self.skip_scan)
+            .with_skip_pre_start(self.skip_pre_start)
+            .with_backup(!self.no_backup)
+            .with_strategy(self.strategy)
+            .with_share(!self.no_share)
+            .with_skip_preflight(self.skip_preflight)
+            .with_no_cache(self.no_cache)
+            .with_pull(self.pull)
+            .with_image_tarball(self.image_tarball.clone())
+            .with_wait_for_image(self.wait_for_image.map(Duration::from_secs), self.image_digest.clone())
+            .with_message(self.message.clone())
+    }
+}
+
+/// A deploy run in the background by `ruku deploys:worker`, as written to `<id>.json`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeployRequest {
+    pub id: String,
+    pub app: String,
+    pub status: DeployStatus,
+    pub options: DeployOptions,
+    /// The ruku.yml the deploy was queued with, the worker refuses to deploy a different one.
+    pub config: String,
+    pub queued_at: DateTime<Utc>,
+    pub started_at: Option<DateTime<Utc>>,
+    pub finished_at: Option<DateTime<Utc>>,
+    /// Process id of the worker.
+    pub pid: Option<u32>,
+    /// Id of the deployment in the app history once it succeeded.
+    pub deployment_id: Option<String>,
+    pub version: Option<String>,
+    pub error: Option<String>,
+}
+
+/// Detached deploys of every app, each a JSON record and the log its worker writes under
+/// `~/.ruku/deploys`. The worker holds a lock on its log for as long as it lives, so a record that
+/// is not finished while nobody holds the lock belongs to a worker that died.
+pub struct Deploys<'a> {
+    log: &'a Logger,
+    dir: PathBuf,
+    ruku_binary: PathBuf,
+}
+
+impl<'a> Deploys<'a> {
+    pub const DIR_NAME: &'static str = "deploys";
+
+    pub fn new(log: &'a Logger, server_config: &ServerConfig) -> Deploys<'a> {
+        Deploys {
+            log,
+            dir: server_config.ruku_root.join(Self::DIR_NAME),
+            // The binary that is running, which is the one that queued the deploy
+            ruku_binary: std::env::current_exe().unwrap_or(server_config.ruku_binary.clone()),
+        }
+    }
+
+    /// Record a pending deploy of `app` with a snapshot of its ruku.yml and start a worker for it,
+    /// returning as soon as the worker runs.
+    pub fn queue(&self, app: &str, config_path: &Path, options: DeployOptions) -> Result<DeployRequest, String> {
+        let config = fs::read_to_string(config_path).map_err(|e| format!("Error reading ruku.yml file: {}", e))?;
+        fs::create_dir_all(&self.dir).map_err(|e| format!("Error creating directory: {}", e))?;
+        let queued_at = Utc::now();
+        let id = self.new_id(app, queued_at);
+        // Locked before the record exists and handed to the worker as its output, so the deploy is never
+        // seen without a live owner
+        let log_file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.log_path(&id))
+            .map_err(|e| format!("Error creating deploy log: {}", e))?;
+        log_file
+            .try_lock()
+            .map_err(|e| format!("Error locking deploy log: {}", e))?;
+        let stderr = log_file
+            .try_clone()
+            .map_err(|e| format!("Error opening deploy log: {}", e))?;
+        let mut request = DeployRequest {
+            id: id.clone(),
+            app: app.to_string(),
+            status: DeployStatus::Pending,
+            options,
+            config,
+            queued_at,
+            started_at: None,
+            finished_at: None,
+            pid: None,
+            deployment_id: None,
+            version: None,
+            error: None,
+        };
+        self.save(&request)?;
+
+        let mut worker = Command::new(&self.ruku_binary);
+        worker
+            .args(["deploys:worker", &id])
+            .envs(active_context().map(|(name, _)| (CONTEXT_ENV, name)))
+            .stdin(Stdio::null())
+            .stdout(log_file)
+            .stderr(stderr);
+        // Its own process group, so closing the terminal or the SSH session does not take it down
+        #[cfg(unix)]
+        worker.process_group(0);
+        #[cfg(windows)]
+        worker.creation_flags(DETACHED_PROCESS | CREATE_NEW_PROCESS_GROUP);
+        // This process exits right after and init reaps the worker, which records its pid itself
+        #[allow(clippy::zombie_processes)]
+        if let Err(e) = worker.spawn() {
+            self.fail(&mut request, &format!("the worker did not start: {}", e))?;
+            return Err(format!("Error starting the deploy worker: {}", e));
+        }
+        Ok(request)
+    }
+
+    /// The deploy, marked as failed first when it is unfinished and its worker is gone.
+    pub fn get(&self, id: &str) -> Result<DeployRequest, String> {
+        let mut request = self
+            .read(id)
+            .ok_or_else(|| format!("No detached deploy with id {}", id))?;
+        if !request.status.is_finished() && !self.worker_alive(id) {
+            self.fail(&mut request, "the worker exited before the deploy finished")?;
+        }
+        Ok(request)
+    }
+
+    /// The output of the deploy so far, none when there is no log.
+    pub fn read_log(&self, id: &str) -> Option<String> {
+        fs::read_to_string(self.log_path(id)).ok()
+    }
+
+    /// Print the log of the deploy, and with `follow` keep printing it until the deploy finishes.
+    pub async fn print_log(&self, id: &str, follow: bool) -> Result<(), String> {
+        self.get(id)?;
+        let mut file = File::open(self.log_path(id)).map_err(|e| format!("Error reading deploy log: {}", e))?;
+        let mut position = 0;
+        loop {
+            let mut output = String::new();
+            file.seek(SeekFrom::Start(position)).ok();
+            if let Ok(read) = file.read_to_string(&mut output) {
+                position += read as u64;
+                print!("{}", output);
+                std::io::stdout().flush().ok();
+            }
+            if !follow || self.get(id)?.status.is_finished() && output.is_empty() {
+                return Ok(());
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    }
+
+    /// Claim the deploy for this worker and record it as failed when `log` reports an error.
+    pub fn start(&self, id: &str) -> Result<DeployRequest, String> {
+        let mut request = self
+            .read(id)
+            .ok_or_else(|| format!("No detached deploy with id {}", id))?;
+        if request.status != DeployStatus::Pending {
+            return Err(format!("Deploy {} was started already, it is {}", id, request.status));
+        }
+        request.status = DeployStatus::Running;
+        request.started_at = Some(Utc::now());
+        request.pid = Some(std::process::id());
+        self.save(&request)?;
+
+        let pending = Arc::new(Mutex::new(request.clone()));
+        let path = self.record_path(id);
+        self.log.on_error(move |message| {
+            let mut request = pending.lock().unwrap();
+            request.status = DeployStatus::Failed;
+            request.finished_at = Some(Utc::now());
+            request.error = Some(message.to_string());
+            // The error is in the deploy log either way, or the status falls back to the dead worker
+            let _ = write_record(&path, &request);
+        });
+        Ok(request)
+    }
+
+    pub fn succeeded(&self, request: &mut DeployRequest, outcome: &DeployOutcome) -> Result<(), String> {
+        request.status = DeployStatus::Succeeded;
+        request.finished_at = Some(Utc::now());
+        request.deployment_id = Some(outcome.deployment_id.clone());
+        request.version = outcome.version.clone();
+        self.save(request)
+    }
+
+    fn fail(&self, request: &mut DeployRequest, error: &str) -> Result<(), String> {
+        request.status = DeployStatus::Failed;
+        request.finished_at = Some(Utc::now());
+        request.error = Some(error.to_string());
+        self.save(request)
+    }
+
+    /// Whether a process holds the lock on the deploy log.
+    fn worker_alive(&self, id: &str) -> bool {
+        match OpenOptions::new().append(true).open(self.log_path(id)) {
+            Ok(file) => file.try_lock().is_err(),
+            Err(_) => false,
+        }
+    }
+
+    fn new_id(&self, app: &str, queued_at: DateTime<Utc>) -> String {
+        let base = format!("{}-{}", app, queued_at.format("%Y%m%d%H%M%S"));
+        let mut id = base.clone();
+        let mut n = 1;
+        while self.record_path(&id).exists() {
+            n += 1;
+            id = format!("{}-{}", base, n);
+        }
+        id
+    }
+
+    fn read(&self, id: &str) -> Option<DeployRequest> {
+        // Ids end up in paths, anything but a plain file name is not one of ours
+        if id.is_empty() || id.contains(['/', '\\']) || id.starts_with('.') {
+            return None;
+        }
+        let content = fs::read_to_string(self.record_path(id)).ok()?;
+        serde_json::from_str(&content).ok()
+    }
+
+    fn save(&self, request: &DeployRequest) -> Result<(), String> {
+        write_record(&self.record_path(&request.id), request).map_err(|e| format!("Error writing deploy record: {}", e))
+    }
+
+    fn record_path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", id))
+    }
+
+    fn log_path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}.log", id))
+    }
+}
+
+/// Write the record atomically, so a reader never sees half of it.
+fn write_record(path: &Path, request: &DeployRequest) -> Result<(), String> {
+    store::write_atomic(path, serde_json::to_string_pretty(request).unwrap().as_bytes()).map_err(|e| e.to_string())
+}
@@ -0,0 +1,262 @@
+//! Reaches the daemons bollard can't connect to by itself, over ssh or TLS, through a command that
+//! speaks the API on its stdin and stdout: `docker system dial-stdio`, run over ssh on the remote host or
+//! locally with the certificates. Bollard talks to a socket only this user can reach, and each
+//! connection to it starts the command.
+
+use std::collections::BTreeMap;
+use std::io;
+use std::path::Path;
+use std::sync::Mutex;
+
+#[cfg(unix)]
+use crate::api_trace;
+use crate::server_config::TlsConfig;
+
+/// Prefix of the directories holding the sockets daemons are dialed through.
+#[cfg(unix)]
+const SOCKET_DIR_PREFIX: &str = "ruku-dial-";
+
+/// The socket each daemon is dialed through by its address, so the command is set up once.
+static SOCKETS: Mutex<BTreeMap<String, String>> = Mutex::new(BTreeMap::new());
+
+/// The command that connects its stdin and stdout to the API of the daemon at `host`: over ssh for an
+/// `ssh://[user@]host[:port]` address, through the docker CLI with `tls` for a `tcp://` one. None for a
+/// daemon bollard connects to by itself.
+pub fn dial_command(host: &str, tls: Option<&TlsConfig>) -> Result<Option<Vec<String>>, String> {
+    if let Some(address) = host.strip_prefix("ssh://") {
+        let address = address.trim_end_matches('/');
+        if address.is_empty() || address.contains('/') {
+            return Err(format!("{} is not an ssh://[user@]host[:port] address", host));
+        }
+        // The colons of an IPv6 address are within brackets, ssh takes it without them
+        let (destination, port) = match address.rsplit_once(':') {
+            Some((destination, port)) if !port.contains(']') => {
+                let port = port
+                    .parse::<u16>()
+                    .map_err(|_| format!("{} has an invalid port", host))?;
+                (destination, Some(port))
+            }
+            _ => (address, None),
+        };
+        // A password or host key prompt is refused, there is no terminal to answer it on
+        let mut command = vec!["ssh", "-o", "BatchMode=yes", "-T"];
+        let port = port.map(|port| port.to_string());
+        if let Some(port) = &port {
+            command.extend(["-p", port]);
+        }
+        let destination = destination.replace(['[', ']'], "");
+        command.extend(["--", &destination, "docker", "system", "dial-stdio"]);
+        return Ok(Some(command.into_iter().map(str::to_string).collect()));
+    }
+    let Some(tls) = tls else {
+        return Ok(None);
+    };
+    if !host.starts_with("tcp://") {
+        return Err(format!("tls is only used with a tcp:// address, not {}", host));
+    }
+    let path = |path: &Path| path.display().to_string();
+    Ok(Some(vec![
+        "docker".to_string(),
+        "--host".to_string(),
+        host.to_string(),
+        "--tlsverify".to_string(),
+        "--tlscacert".to_string(),
+        path(&tls.ca),
+        "--tlscert".to_string(),
+        path(&tls.cert),
+        "--tlskey".to_string(),
+        path(&tls.key),
+        "system".to_string(),
+        "dial-stdio".to_string(),
+    ]))
+}
+
+/// The `unix://` address of a socket relaying each connection to `command`, started once per daemon
+/// address `host`.
+pub fn route(host: &str, command: Vec<String>) -> io::Result<String> {
+    let mut sockets = SOCKETS.lock().unwrap();
+    if let Some(address) = sockets.get(host) {
+        return Ok(address.clone());
+    }
+    let address = listen(command)?;
+    sockets.insert(host.to_string(), address.clone());
+    Ok(address)
+}
+
+#[cfg(unix)]
+fn listen(command: Vec<String>) -> io::Result<String> {
+    use std::os::unix::net::UnixListener;
+    use std::thread;
+
+    api_trace::remove_stale_sockets(SOCKET_DIR_PREFIX);
+    // Named after the process so a later run can tell it is left over
+    let dir = tempfile::Builder::new()
+        .prefix(&format!("{}{}-", SOCKET_DIR_PREFIX, std::process::id()))
+        .tempdir()?
+        .keep();
+    let socket = dir.join("docker.sock");
+    let listener = UnixListener::bind(&socket)?;
+    thread::spawn(move || {
+        for client in listener.incoming().flatten() {
+            let command = command.clone();
+            thread::spawn(move || relay(client, &command));
+        }
+    });
+    Ok(format!("unix://{}", socket.display()))
+}
+
+#[cfg(not(unix))]
+fn listen(_command: Vec<String>) -> io::Result<String> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "dialing a daemon over ssh or tls is only supported on unix",
+    ))
+}
+
+/// Copy one client connection to a run of `command` and back, until either side closes it.
+#[cfg(unix)]
+fn relay(client: std::os::unix::net::UnixStream, command: &[String]) {
+    use std::net::Shutdown;
+    use std::process::{Command, Stdio};
+    use std::thread;
+
+    use crate::logger::Logger;
+
+    let child = Command::new(&command[0])
+        .args(&command[1..])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn();
+    let mut child = match child {
+        Ok(child) => child,
+        Err(e) => {
+            // The client sees the connection close
+            Logger::new().warn(&format!("Could not run {} to reach the daemon: {}", command[0], e));
+            return;
+        }
+    };
+    let (Some(mut stdin), Some(mut stdout), Ok(mut reader)) =
+        (child.stdin.take(), child.stdout.take(), client.try_clone())
+    else {
+        let _ = child.kill();
+        return;
+    };
+    let sending = thread::spawn(move || {
+        pump(&mut reader, &mut stdin);
+        // Dropping stdin tells the command the client is done
+    });
+    let mut writer = &client;
+    let _ = io::copy(&mut stdout, &mut writer);
+    let _ = client.shutdown(Shutdown::Both);
+    let _ = sending.join();
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+/// Copy `from` to `to` until either ends. On Linux `io::copy` splices a socket into a pipe, which held on
+/// to a short request instead of passing it on.
+#[cfg(unix)]
+fn pump(from: &mut impl io::Read, to: &mut impl io::Write) {
+    let mut buffer = [0; 8192];
+    loop {
+        match from.read(&mut buffer) {
+            Ok(0) | Err(_) => return,
+            Ok(read) => {
+                if to.write_all(&buffer[..read]).and_then(|_| to.flush()).is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    fn command(host: &str) -> Vec<String> {
+        dial_command(host, None).unwrap().unwrap()
+    }
+
+    #[test]
+    fn ssh_addresses_run_dial_stdio_on_the_remote_host() {
+        let remote = ["docker", "system", "dial-stdio"];
+        assert_eq!(
+            command("ssh://deploy@build.example.com"),
+            [
+                &["ssh", "-o", "BatchMode=yes", "-T", "--", "deploy@build.example.com"][..],
+                &remote
+            ]
+            .concat()
+        );
+        assert_eq!(
+            command("ssh://deploy@10.0.0.2:2222/"),
+            [
+                &[
+                    "ssh",
+                    "-o",
+                    "BatchMode=yes",
+                    "-T",
+                    "-p",
+                    "2222",
+                    "--",
+                    "deploy@10.0.0.2"
+                ][..],
+                &remote
+            ]
+            .concat()
+        );
+        assert_eq!(command("ssh://[fd00::2]")[5], "fd00::2");
+        assert_eq!(command("ssh://root@[fd00::2]:22")[5..8], ["22", "--", "root@fd00::2"]);
+        assert!(dial_command("ssh://host:port", None)
+            .unwrap_err()
+            .contains("invalid port"));
+        assert!(dial_command("ssh://host/var/run/docker.sock", None).is_err());
+    }
+
+    #[test]
+    fn tls_goes_through_the_docker_cli_with_the_certificates() {
+        let tls = TlsConfig {
+            ca: PathBuf::from("/etc/ruku/ca.pem"),
+            cert: PathBuf::from("/etc/ruku/cert.pem"),
+            key: PathBuf::from("/etc/ruku/key.pem"),
+        };
+        assert_eq!(
+            dial_command("tcp://10.0.0.2:2376", Some(&tls))
+                .unwrap()
+                .unwrap()
+                .join(" "),
+            "docker --host tcp://10.0.0.2:2376 --tlsverify --tlscacert /etc/ruku/ca.pem \
+             --tlscert /etc/ruku/cert.pem --tlskey /etc/ruku/key.pem system dial-stdio"
+        );
+        assert!(dial_command("unix:///var/run/docker.sock", Some(&tls)).is_err());
+        assert_eq!(dial_command("tcp://10.0.0.2:2375", None), Ok(None));
+        assert_eq!(dial_command("unix:///var/run/docker.sock", None), Ok(None));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn connections_are_relayed_to_the_command() {
+        // A daemon on stdin and stdout answering the first request
+        let daemon = "read request; printf 'HTTP/1.1 200 OK\\r\\nContent-Length: 2\\r\\n\\r\\nOK'";
+        let command = ["sh", "-c", daemon].map(str::to_string).to_vec();
+        let address = route("ssh://test-relay", command.clone()).unwrap();
+        assert_eq!(route("ssh://test-relay", command).unwrap(), address);
+        {
+            use std::io::{Read, Write};
+            let mut s = std::os::unix::net::UnixStream::connect(address.trim_start_matches("unix://")).unwrap();
+            s.write_all(b"GET /_ping HTTP/1.1\r\nHost: x\r\n\r\n").unwrap();
+            let mut out = String::new();
+            s.read_to_string(&mut out).unwrap();
+            eprintln!("GOT {:?}", out);
+        }
+
+        let docker = bollard::Docker::connect_with_unix(&address, 5, bollard::API_DEFAULT_VERSION).unwrap();
+        assert_eq!(docker.ping().await.unwrap(), "OK");
+        // Every connection runs the command again
+        let docker = bollard::Docker::connect_with_unix(&address, 5, bollard::API_DEFAULT_VERSION).unwrap();
+        assert_eq!(docker.ping().await.unwrap(), "OK");
+    }
+}
@@ -0,0 +1,295 @@
+use std::collections::BTreeMap;
+use std::io::{self, IsTerminal};
+
+use colored::Colorize;
+use serde::Serialize;
+
+use crate::releases::is_secret_key;
+use crate::spec::PortSpec;
+use crate::templates::SECRET_MASK;
+
+/// How a field differs from one side of a diff to the other.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeKind {
+    Added,
+    Removed,
+    Changed,
+}
+
+impl ChangeKind {
+    fn marker(self) -> &'static str {
+        match self {
+            ChangeKind::Added => "+",
+            ChangeKind::Removed => "-",
+            ChangeKind::Changed => "~",
+        }
+    }
+}
+
+/// A field that differs between two normalized structures, by its path like `env.DATABASE_URL` or
+/// `ports[1].host_port`, none on the side where it is not set.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Change {
+    pub path: String,
+    pub kind: ChangeKind,
+    pub from: Option<String>,
+    pub to: Option<String>,
+}
+
+impl Change {
+    /// `path` under `prefix`, e.g. `sidecars.db.env.PGDATA`.
+    pub fn nested(self, prefix: &str) -> Change {
+        Change {
+            path: format!("{}.{}", prefix, self.path),
+            ..self
+        }
+    }
+}
+
+/// Every path whose value differs from `from` to `to`, in path order.
+pub fn compare(from: &BTreeMap<String, String>, to: &BTreeMap<String, String>) -> Vec<Change> {
+    let mut paths: Vec<&String> = from.keys().chain(to.keys()).collect();
+    paths.sort();
+    paths.dedup();
+    paths
+        .into_iter()
+        .filter_map(|path| {
+            let (from, to) = (from.get(path), to.get(path));
+            let kind = match (from, to) {
+                (None, Some(_)) => ChangeKind::Added,
+                (Some(_), None) => ChangeKind::Removed,
+                (Some(from), Some(to)) if from != to => ChangeKind::Changed,
+                _ => return None,
+            };
+            Some(Change {
+                path: path.clone(),
+                kind,
+                from: from.cloned(),
+                to: to.cloned(),
+            })
+        })
+        .collect()
+}
+
+/// `KEY=value` entries of a container env by name, an entry without `=` has an empty value.
+pub fn parse_env(env: &[String]) -> BTreeMap<String, String> {
+    env.iter()
+        .map(|var| match var.split_once('=') {
+            Some((k, v)) => (k.to_string(), v.to_string()),
+            None => (var.to_string(), String::new()),
+        })
+        .collect()
+}
+
+/// The entries of `map` with their path under `name`, e.g. `env.DATABASE_URL`.
+pub fn map_fields(name: &str, map: &BTreeMap<String, String>) -> BTreeMap<String, String> {
+    map.iter()
+        .map(|(key, value)| (format!("{}.{}", name, key), value.clone()))
+        .collect()
+}
+
+/// Published ports sorted, a path per part like `ports[1].host_port`, so the order Docker reports them
+/// in doesn't make a difference.
+pub fn port_fields(ports: &[PortSpec]) -> BTreeMap<String, String> {
+    let mut ports = ports.to_vec();
+    ports.sort();
+    let mut fields = BTreeMap::new();
+    for (index, port) in ports.iter().enumerate() {
+        let path = |part: &str| format!("ports[{}].{}", index, part);
+        fields.insert(path("container_port"), port.container_port.to_string());
+        fields.insert(path("protocol"), port.protocol.clone());
+        if let Some(host_ip) = &port.host_ip {
+            fields.insert(path("host_ip"), host_ip.clone());
+        }
+        fields.insert(path("host_port"), port.host_port.to_string());
+    }
+    fields
+}
+
+/// Bind mounts sorted as `volumes[0]`, ... in Docker's `source:target[:mode]` form.
+pub fn volume_fields(binds: &[String]) -> BTreeMap<String, String> {
+    let mut binds = binds.to_vec();
+    binds.sort();
+    binds
+        .into_iter()
+        .enumerate()
+        .map(|(index, bind)| (format!("volumes[{}]", index), bind))
+        .collect()
+}
+
+/// Whether the value at `path` is a secret: its last part names a credential, or it is the env var of an
+/// entry of `secrets` of ruku.yml.
+pub fn is_secret_path(path: &str, secrets: &BTreeMap<String, String>) -> bool {
+    let env_name = path
+        .strip_prefix("env.")
+        .or_else(|| path.rsplit_once(".env.").map(|(_, name)| name));
+    is_secret_key(path) || env_name.is_some_and(|name| secrets.contains_key(name))
+}
+
+/// Prints changes as aligned `<marker> <path>  <from> -> <to>` lines, `+` for added, `-` for removed and
+/// `~` for changed, colored when stdout is a terminal.
+pub struct Renderer<'a> {
+    mask: Box<dyn Fn(&str) -> bool + 'a>,
+    color: bool,
+}
+
+impl Default for Renderer<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a> Renderer<'a> {
+    pub fn new() -> Renderer<'a> {
+        Renderer {
+            mask: Box::new(|_| false),
+            color: io::stdout().is_terminal() && colored::control::SHOULD_COLORIZE.should_colorize(),
+        }
+    }
+
+    /// Print the values of the paths `mask` picks as `******`, in the JSON form as well.
+    pub fn with_mask(mut self, mask: impl Fn(&str) -> bool + 'a) -> Renderer<'a> {
+        self.mask = Box::new(mask);
+        self
+    }
+
+    /// The lines of `changes`, empty when there are none.
+    pub fn render(&self, changes: &[Change]) -> String {
+        let width = changes.iter().map(|change| change.path.len()).max().unwrap_or(0);
+        let mut output = String::new();
+        for change in self.masked(changes) {
+            let value = match (&change.from, &change.to) {
+                (Some(from), Some(to)) => format!("{} -> {}", from, to),
+                (Some(value), None) | (None, Some(value)) => value.clone(),
+                (None, None) => String::new(),
+            };
+            let label = format!("{} {:<width$}", change.kind.marker(), change.path, width = width);
+            let label = match (self.color, change.kind) {
+                (false, _) => label,
+                (true, ChangeKind::Added) => label.green().to_string(),
+                (true, ChangeKind::Removed) => label.red().to_string(),
+                (true, ChangeKind::Changed) => label.yellow().to_string(),
+            };
+            output.push_str(&format!("{}  {}\n", label, value));
+        }
+        output
+    }
+
+    /// `changes` as a JSON array of `path`, `kind`, `from` and `to` objects.
+    pub fn json(&self, changes: &[Change]) -> String {
+        serde_json::to_string_pretty(&self.masked(changes)).unwrap()
+    }
+
+    fn masked(&self, changes: &[Change]) -> Vec<Change> {
+        changes
+            .iter()
+            .map(|change| match (self.mask)(&change.path) {
+                true => Change {
+                    from: change.from.as_ref().map(|_| SECRET_MASK.to_string()),
+                    to: change.to.as_ref().map(|_| SECRET_MASK.to_string()),
+                    ..change.clone()
+                },
+                false => change.clone(),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    fn changes() -> Vec<Change> {
+        compare(
+            &fields(&[
+                ("env.DATABASE_PASSWORD", "old"),
+                ("env.RUST_LOG", "info"),
+                ("image", "shop:1.0"),
+            ]),
+            &fields(&[
+                ("env.DATABASE_PASSWORD", "new"),
+                ("image", "shop:1.1"),
+                ("labels.team", "web"),
+            ]),
+        )
+    }
+
+    #[test]
+    fn fields_are_added_removed_or_changed() {
+        let kinds: Vec<_> = changes().into_iter().map(|change| (change.path, change.kind)).collect();
+        assert_eq!(
+            kinds,
+            [
+                ("env.DATABASE_PASSWORD".to_string(), ChangeKind::Changed),
+                ("env.RUST_LOG".to_string(), ChangeKind::Removed),
+                ("image".to_string(), ChangeKind::Changed),
+                ("labels.team".to_string(), ChangeKind::Added),
+            ]
+        );
+        assert_eq!(compare(&fields(&[("a", "1")]), &fields(&[("a", "1")])), vec![]);
+        assert_eq!(
+            changes()[3].clone().nested("sidecars.db").path,
+            "sidecars.db.labels.team"
+        );
+    }
+
+    #[test]
+    fn changes_render_as_aligned_lines() {
+        let mut renderer = Renderer::new();
+        renderer.color = false;
+        assert_eq!(
+            renderer.render(&changes()),
+            "~ env.DATABASE_PASSWORD  old -> new\n\
+             - env.RUST_LOG           info\n\
+             ~ image                  shop:1.0 -> shop:1.1\n\
+             + labels.team            web\n"
+        );
+        assert_eq!(renderer.render(&[]), "");
+    }
+
+    #[test]
+    fn secrets_are_masked_in_both_forms() {
+        let secrets = BTreeMap::new();
+        let mut renderer = Renderer::new().with_mask(|path| is_secret_path(path, &secrets));
+        renderer.color = false;
+        let rendered = renderer.render(&changes());
+        assert!(rendered.starts_with(&format!("~ env.DATABASE_PASSWORD  {0} -> {0}\n", SECRET_MASK)));
+        assert!(!rendered.contains("old") && !rendered.contains("new"));
+
+        let json: serde_json::Value = serde_json::from_str(&renderer.json(&changes())).unwrap();
+        assert_eq!(
+            json[0],
+            serde_json::json!({"path": "env.DATABASE_PASSWORD", "kind": "changed", "from": SECRET_MASK, "to": SECRET_MASK})
+        );
+        assert_eq!(json[1]["to"], serde_json::Value::Null);
+        assert_eq!(json[2]["to"], "shop:1.1");
+    }
+
+    #[test]
+    fn ports_and_volumes_compare_in_any_order() {
+        let port = |container_port, host_port| PortSpec {
+            container_port,
+            protocol: "tcp".to_string(),
+            host_ip: None,
+            host_port,
+        };
+        assert_eq!(
+            port_fields(&[port(443, 8443), port(80, 8080)]),
+            port_fields(&[port(80, 8080), port(443, 8443)])
+        );
+        assert_eq!(port_fields(&[port(80, 8080)])["ports[0].host_port"], "8080");
+        assert_eq!(
+            volume_fields(&["/b:/b".to_string(), "/a:/a:ro".to_string()]),
+            fields(&[("volumes[0]", "/a:/a:ro"), ("volumes[1]", "/b:/b")])
+        );
+        assert_eq!(
+            parse_env(&["A=1=2".to_string(), "B".to_string()]),
+            fields(&[("A", "1=2"), ("B", "")])
+        );
+    }
+}
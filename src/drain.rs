@@ -0,0 +1,107 @@
+use std::time::{Duration, Instant};
+
+use bollard::Docker;
+
+use crate::logger::Logger;
+use crate::probe::exec_script;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// How often the countdown is logged.
+const REPORT_INTERVAL: Duration = Duration::from_secs(5);
+/// State of an established connection in `/proc/net/tcp`.
+const TCP_ESTABLISHED: &str = "01";
+
+/// Established connections to local `port` in the contents of `/proc/net/tcp` and `/proc/net/tcp6`.
+pub fn count_connections(proc_net: &str, port: u16) -> usize {
+    let port = format!("{:04X}", port);
+    proc_net
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let local = fields.nth(1)?;
+            let state = fields.nth(1)?;
+            Some((local.rsplit_once(':')?.1, state))
+        })
+        .filter(|(local_port, state)| local_port.eq_ignore_ascii_case(&port) && *state == TCP_ESTABLISHED)
+        .count()
+}
+
+/// Waits before the app container is stopped, so requests it is serving can finish.
+pub struct Drain<'a> {
+    log: &'a Logger,
+    docker: &'a Docker,
+    period: Duration,
+}
+
+impl<'a> Drain<'a> {
+    pub fn new(log: &'a Logger, docker: &'a Docker, period: Duration) -> Drain<'a> {
+        Drain { log, docker, period }
+    }
+
+    /// Wait out the drain period, ending early once no connection to `port` is left open in the
+    /// container. Ctrl-C ends the wait and the stop goes ahead.
+    pub async fn run(&self, container_name: &str, port: u16) {
+        let started = Instant::now();
+        let mut last_report: Option<Instant> = None;
+        // Listening from the start, so a Ctrl-C while the container is asked is not lost
+        #[cfg(unix)]
+        let mut interrupt = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::interrupt()).ok();
+        #[cfg(not(unix))]
+        let interrupt = tokio::signal::ctrl_c();
+        #[cfg(not(unix))]
+        tokio::pin!(interrupt);
+        self.log.step(&format!(
+            "Draining {} for up to {}s, Ctrl-C stops it right away",
+            container_name,
+            self.period.as_secs()
+        ));
+        loop {
+            // Images without a shell can't be asked, they get the full period
+            let connections = self.connections(container_name, port).await;
+            if connections == Some(0) {
+                self.log.step("No connections left, stopping");
+                return;
+            }
+            let elapsed = started.elapsed();
+            if elapsed >= self.period {
+                let left = connections.map(|count| format!(", {} connections still open", count));
+                self.log
+                    .step(&format!("Drain period is over{}, stopping", left.unwrap_or_default()));
+                return;
+            }
+            if last_report.is_none_or(|at| at.elapsed() >= REPORT_INTERVAL) {
+                let open = connections.map(|count| format!(", {} connections open", count));
+                self.log.step(&format!(
+                    "Stopping in {}s{}",
+                    (self.period - elapsed).as_secs(),
+                    open.unwrap_or_default()
+                ));
+                last_report = Some(Instant::now());
+            }
+            #[cfg(unix)]
+            let interrupted = async {
+                match interrupt.as_mut() {
+                    Some(interrupt) => interrupt.recv().await,
+                    None => std::future::pending().await,
+                }
+            };
+            #[cfg(not(unix))]
+            let interrupted = &mut interrupt;
+            tokio::select! {
+                _ = interrupted => {
+                    self.log.warn("Interrupted, skipping the rest of the drain");
+                    return;
+                }
+                _ = tokio::time::sleep(POLL_INTERVAL) => {}
+            }
+        }
+    }
+
+    async fn connections(&self, container_name: &str, port: u16) -> Option<usize> {
+        let script = "cat /proc/net/tcp /proc/net/tcp6 2>/dev/null".to_string();
+        match exec_script(self.docker, container_name, script).await {
+            Ok((output, _)) if !output.trim().is_empty() => Some(count_connections(&output, port)),
+            _ => None,
+        }
+    }
+}
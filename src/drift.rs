@@ -0,0 +1,60 @@
+use crate::container::Container;
+use crate::diff::{is_secret_path, Renderer};
+use crate::logger::Logger;
+use crate::misc::get_image_name_with_version;
+use crate::model::RukuConfig;
+
+/// Compares the live container against the spec derived from the app config.
+pub struct Drift<'a> {
+    log: &'a Logger,
+    name: &'a str,
+    config: &'a RukuConfig,
+    container: &'a Container<'a>,
+}
+
+impl<'a> Drift<'a> {
+    pub fn new(log: &'a Logger, name: &'a str, config: &'a RukuConfig, container: &'a Container<'a>) -> Drift<'a> {
+        Drift {
+            log,
+            name,
+            config,
+            container,
+        }
+    }
+
+    /// Print every drifted field, as JSON with `json`. With `fix` the container is redeployed from the
+    /// config, otherwise drift is an error.
+    pub async fn run(&self, fix: bool, json: bool) -> Result<(), String> {
+        let Some(live) = self.container.live_spec().await else {
+            return Err(format!("{} is not deployed", self.name));
+        };
+
+        let image_name = get_image_name_with_version(self.name, &self.config.version);
+        self.container.use_image(&image_name).await?;
+        let desired = self.container.spec(image_name)?;
+        let drift = desired.diff(&live);
+        let renderer = Renderer::new().with_mask(|path| is_secret_path(path, &self.config.secrets));
+        if json {
+            println!("{}", renderer.json(&drift));
+        }
+        if drift.is_empty() {
+            self.log.step("No drift, the container matches the config");
+            return Ok(());
+        }
+
+        self.log.warn(&format!(
+            "{} field(s) differ from the config, from the live value to the configured one:",
+            drift.len()
+        ));
+        if !json {
+            print!("{}", renderer.render(&drift));
+        }
+
+        if fix {
+            self.log.step("Redeploying to reconcile the container with the config");
+            self.container.run().await
+        } else {
+            Err(format!("{} has drifted from the config", self.name))
+        }
+    }
+}
@@ -0,0 +1,153 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::model::ENV_NAME;
+use crate::probe::quote;
+use crate::releases::is_secret_key;
+use crate::templates::SECRET_MASK;
+
+/// How `env:export` prints the env of an app.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EnvFormat {
+    /// `KEY=value` lines for a `.env` file.
+    Dotenv,
+    /// `export KEY='value'` lines to `source` in a shell.
+    Shell,
+    /// One JSON object.
+    Json,
+}
+
+impl fmt::Display for EnvFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EnvFormat::Dotenv => write!(f, "dotenv"),
+            EnvFormat::Shell => write!(f, "shell"),
+            EnvFormat::Json => write!(f, "json"),
+        }
+    }
+}
+
+impl FromStr for EnvFormat {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<EnvFormat, String> {
+        match format {
+            "dotenv" => Ok(EnvFormat::Dotenv),
+            "shell" => Ok(EnvFormat::Shell),
+            "json" => Ok(EnvFormat::Json),
+            _ => Err(format!("unknown format '{}', use dotenv, shell or json", format)),
+        }
+    }
+}
+
+/// Which values are replaced by the mask.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Masking {
+    /// Values of keys that name a credential, e.g. `DB_PASSWORD`, and of the `secrets` of the app.
+    Secrets,
+    Everything,
+    Nothing,
+}
+
+/// `env` with the values `masking` covers masked, `secrets` being the keys resolved from a secret backend.
+pub fn mask(
+    env: &BTreeMap<String, String>,
+    masking: Masking,
+    secrets: &BTreeMap<String, String>,
+) -> BTreeMap<String, String> {
+    env.iter()
+        .map(|(key, value)| {
+            let masked = match masking {
+                Masking::Secrets => is_secret_key(key) || secrets.contains_key(key),
+                Masking::Everything => true,
+                Masking::Nothing => false,
+            };
+            let value = if masked { SECRET_MASK.to_string() } else { value.clone() };
+            (key.clone(), value)
+        })
+        .collect()
+}
+
+/// The keys of `env` a shell can't set, which the dotenv and shell formats leave out.
+pub fn invalid_names(env: &BTreeMap<String, String>) -> Vec<String> {
+    env.keys().filter(|key| !ENV_NAME.is_match(key)).cloned().collect()
+}
+
+/// `env` in `format`, one line per variable but for JSON.
+pub fn render(env: &BTreeMap<String, String>, format: EnvFormat) -> String {
+    if format == EnvFormat::Json {
+        return serde_json::to_string_pretty(env).unwrap();
+    }
+    env.iter()
+        .filter(|(key, _)| ENV_NAME.is_match(key))
+        .map(|(key, value)| match format {
+            EnvFormat::Shell => format!("export {}={}", key, quote(value)),
+            _ => format!("{}={}", key, dotenv_quote(value)),
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// A value as dotenv parsers read it back. Single quotes keep it literal, a value with a single quote or
+/// a newline is double quoted with its `\`, `"`, `$` and newlines escaped.
+fn dotenv_quote(value: &str) -> String {
+    let plain = |c: char| c.is_ascii_alphanumeric() || "_-.,:/@%+=".contains(c);
+    if value.chars().all(plain) {
+        return value.to_string();
+    }
+    if !value.contains(['\'', '\n', '\r']) {
+        return format!("'{}'", value);
+    }
+    let escaped = value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('$', "\\$")
+        .replace('\r', "\\r")
+        .replace('\n', "\\n");
+    format!("\"{}\"", escaped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dotenv_values_are_quoted_as_little_as_they_need() {
+        let cases = [
+            ("", ""),
+            ("postgres://db:5432/shop", "postgres://db:5432/shop"),
+            ("two words", "'two words'"),
+            ("price $5 \\ \"net\"", "'price $5 \\ \"net\"'"),
+            ("it's", "\"it's\""),
+            ("$HOME's \\ \"x\"", "\"\\$HOME's \\\\ \\\"x\\\"\""),
+            ("line one\nline two", "\"line one\\nline two\""),
+            ("crlf\r\n", "\"crlf\\r\\n\""),
+        ];
+        for (value, quoted) in cases {
+            assert_eq!(dotenv_quote(value), quoted, "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn shell_lines_read_back_as_the_values() {
+        let env = BTreeMap::from_iter(
+            [
+                ("EMPTY", ""),
+                ("QUOTES", "it's \"quoted\""),
+                ("DOLLAR", "$HOME and `date` and $(id)"),
+                ("BACKSLASH", "C:\\path\\"),
+                ("NEWLINE", "one\ntwo\n"),
+            ]
+            .map(|(key, value)| (key.to_string(), value.to_string())),
+        );
+        let script = format!(
+            "{}\nfor name in {}; do eval \"printf '%s|' \\\"\\${{$name}}\\\"\"; done",
+            render(&env, EnvFormat::Shell),
+            env.keys().cloned().collect::<Vec<_>>().join(" ")
+        );
+        let output = std::process::Command::new("sh").args(["-c", &script]).output().unwrap();
+        let expected: String = env.values().map(|value| format!("{}|", value)).collect();
+        assert_eq!(String::from_utf8_lossy(&output.stdout), expected);
+    }
+}
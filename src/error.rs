@@ -0,0 +1,36 @@
+use std::fmt;
+
+use crate::read_only::READ_ONLY_ENV;
+
+/// Why an operation of the library failed. The message is what the CLI prints, the variant lets a caller
+/// tell a config that needs fixing from a deploy that went wrong.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RukuError {
+    /// ruku.yml or the options given with it don't make a deployable config.
+    Config(String),
+    /// The operation started and went wrong, what it changed has been rolled back where it could be.
+    Failed(String),
+    /// Read-only mode refused the operation before it changed anything, e.g. `deploy shop`.
+    ReadOnly(String),
+}
+
+impl fmt::Display for RukuError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RukuError::Config(message) | RukuError::Failed(message) => write!(f, "{}", message),
+            RukuError::ReadOnly(operation) => write!(
+                f,
+                "Refusing to {}, ruku is read-only here ({} or read_only in ~/.ruku/config.yml)",
+                operation, READ_ONLY_ENV
+            ),
+        }
+    }
+}
+
+impl std::error::Error for RukuError {}
+
+impl From<String> for RukuError {
+    fn from(message: String) -> RukuError {
+        RukuError::Failed(message)
+    }
+}
@@ -0,0 +1,255 @@
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+use bollard::errors::Error;
+use bollard::models::EventMessage;
+use bollard::system::EventsOptions;
+use bollard::Docker;
+use chrono::Utc;
+use futures_util::stream::BoxStream;
+use futures_util::StreamExt;
+use serde_json::json;
+
+use crate::webhook::Webhook;
+
+/// Reconnects in a row without an event in between before the stream gives up.
+pub const DEFAULT_RECONNECTS: u32 = 8;
+/// The longest wait between two reconnects.
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// The events of the daemon as one stream that outlives the connection. When the daemon restarts or the
+/// socket drops, it reconnects with `since` set to the last event it passed on, so the events in between
+/// are replayed, and leaves out the ones it already passed on.
+pub struct EventStream {
+    docker: Docker,
+    filters: HashMap<String, Vec<String>>,
+    stream: Option<BoxStream<'static, Result<EventMessage, Error>>>,
+    /// When the stream first connected in seconds, what a reconnect before any event replays from.
+    connected_at: Option<i64>,
+    /// Time of the last event passed on in nanoseconds.
+    last: Option<i64>,
+    /// Events of that very time passed on, a replay starts at the second and brings them again.
+    at_last: HashSet<String>,
+    failures: u32,
+    reconnects: u32,
+    /// Told when the stream gives up.
+    webhook: Option<Webhook>,
+}
+
+impl EventStream {
+    /// The events `filters` pick, e.g. `type` to `container`.
+    pub fn new(docker: Docker, filters: HashMap<&str, Vec<&str>>) -> EventStream {
+        EventStream {
+            docker,
+            filters: filters
+                .into_iter()
+                .map(|(key, values)| (key.to_string(), values.into_iter().map(str::to_string).collect()))
+                .collect(),
+            stream: None,
+            connected_at: None,
+            last: None,
+            at_last: HashSet::new(),
+            failures: 0,
+            reconnects: DEFAULT_RECONNECTS,
+            webhook: None,
+        }
+    }
+
+    /// Post a `docker_events_lost` notice to `webhook` when the stream gives up.
+    pub fn with_webhook(mut self, webhook: Option<Webhook>) -> EventStream {
+        self.webhook = webhook;
+        self
+    }
+
+    /// The next event not passed on before, an error when the daemon stayed out of reach through every
+    /// reconnect. The webhook is told before the error is returned.
+    pub async fn next(&mut self) -> Result<EventMessage, String> {
+        loop {
+            if self.stream.is_none() {
+                self.stream = Some(self.connect());
+            }
+            let Some(stream) = &mut self.stream else {
+                continue;
+            };
+            let error = match stream.next().await {
+                Some(Ok(event)) => {
+                    self.failures = 0;
+                    if self.pass_on(&event) {
+                        return Ok(event);
+                    }
+                    continue;
+                }
+                Some(Err(e)) => e.to_string(),
+                None => "the daemon closed the event stream".to_string(),
+            };
+            self.stream = None;
+            self.failures += 1;
+            if self.failures > self.reconnects {
+                let error = format!(
+                    "Lost the Docker event stream and could not reconnect {} times: {}",
+                    self.reconnects, error
+                );
+                return Err(notify(self.webhook.clone(), error).await);
+            }
+            tokio::time::sleep(backoff(self.failures)).await;
+        }
+    }
+
+    fn connect(&mut self) -> BoxStream<'static, Result<EventMessage, Error>> {
+        // The daemon takes `since` in seconds, the rest of the second is replayed and left out below
+        let since = match (self.last, self.connected_at) {
+            (Some(nanos), _) => Some(nanos.div_euclid(1_000_000_000)),
+            (None, Some(connected_at)) => Some(connected_at),
+            (None, None) => {
+                self.connected_at = Some(Utc::now().timestamp());
+                None
+            }
+        };
+        let options = EventsOptions {
+            since: since.map(|seconds| seconds.to_string()),
+            filters: self.filters.clone(),
+            ..Default::default()
+        };
+        self.docker.events(Some(options)).boxed()
+    }
+
+    /// Whether `event` is new, recording it when it is. Events come in time order, so one older than the
+    /// last was passed on before.
+    fn pass_on(&mut self, event: &EventMessage) -> bool {
+        let Some(time) = event.time_nano.or(event.time.map(|seconds| seconds * 1_000_000_000)) else {
+            return true;
+        };
+        let key = format!(
+            "{}:{}",
+            event
+                .actor
+                .as_ref()
+                .and_then(|actor| actor.id.as_deref())
+                .unwrap_or_default(),
+            event.action.as_deref().unwrap_or_default()
+        );
+        match self.last {
+            Some(last) if time < last => false,
+            Some(last) if time == last => self.at_last.insert(key),
+            _ => {
+                self.last = Some(time);
+                self.at_last = HashSet::from([key]);
+                true
+            }
+        }
+    }
+}
+
+/// Tell `webhook` the stream is lost with `error`, which is returned with a failed delivery added.
+async fn notify(webhook: Option<Webhook>, error: String) -> String {
+    let Some(webhook) = webhook else {
+        return error;
+    };
+    let notice = json!({
+        "event": "docker_events_lost",
+        "message": error,
+        "time": Utc::now().to_rfc3339(),
+    });
+    match webhook.send(&notice).await {
+        Ok(()) => error,
+        Err(e) => format!("{}, and the webhook wasn't told: {}", error, e),
+    }
+}
+
+/// The wait before reconnect `attempt`, one second doubling up to [`MAX_BACKOFF`].
+fn backoff(attempt: u32) -> Duration {
+    Duration::from_secs(1u64 << attempt.saturating_sub(1).min(5)).min(MAX_BACKOFF)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::fake_daemon::fake_daemon;
+    use crate::webhook::tests::endpoint;
+
+    fn event(id: &str, action: &str, time_nano: i64) -> String {
+        format!(
+            r#"{{"Type":"container","Action":"{}","Actor":{{"ID":"{}"}},"time":{},"timeNano":{}}}"#,
+            action,
+            id,
+            time_nano / 1_000_000_000,
+            time_nano
+        )
+    }
+
+    fn actions(events: &[EventMessage]) -> Vec<String> {
+        events
+            .iter()
+            .map(|event| {
+                let id = event
+                    .actor
+                    .as_ref()
+                    .and_then(|actor| actor.id.clone())
+                    .unwrap_or_default();
+                format!("{} {}", id, event.action.clone().unwrap_or_default())
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn reconnects_replay_without_duplicates_or_gaps() {
+        static CONNECTIONS: AtomicUsize = AtomicUsize::new(0);
+        // Every connection ends after its events, the next replays from the second of the last one
+        let (docker, requests) = fake_daemon(|_, _| {
+            let events = match CONNECTIONS.fetch_add(1, Ordering::SeqCst) {
+                0 => vec![
+                    event("a", "start", 100_000_000_001),
+                    event("b", "start", 100_000_000_005),
+                ],
+                1 => vec![
+                    event("a", "start", 100_000_000_001),
+                    event("b", "start", 100_000_000_005),
+                    event("c", "create", 100_000_000_005),
+                    event("b", "die", 101_000_000_000),
+                ],
+                2 => vec![event("b", "die", 101_000_000_000), event("c", "start", 102_000_000_000)],
+                _ => vec![],
+            };
+            (200, events.join("\n") + "\n")
+        })
+        .await;
+        let mut stream = EventStream::new(docker, HashMap::from([("type", vec!["container"])]));
+        let mut events = vec![];
+        for _ in 0..5 {
+            events.push(stream.next().await.unwrap());
+        }
+        assert_eq!(actions(&events), ["a start", "b start", "c create", "b die", "c start"]);
+        assert_eq!(requests.count("GET /events"), 3);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_the_reconnects() {
+        let (docker, requests) = fake_daemon(|_, _| (500, r#"{"message":"daemon is restarting"}"#.to_string())).await;
+        let mut stream = EventStream::new(docker, HashMap::new());
+        stream.reconnects = 0;
+        let error = stream.next().await.unwrap_err();
+        assert!(error.contains("daemon is restarting"), "{}", error);
+        assert_eq!(requests.count("GET /events"), 1);
+    }
+
+    #[tokio::test]
+    async fn the_webhook_is_told_when_the_stream_gives_up() {
+        let (docker, _) = fake_daemon(|_, _| (500, r#"{"message":"daemon is restarting"}"#.to_string())).await;
+        let (url, notices) = endpoint(&[]);
+        let mut stream = EventStream::new(docker, HashMap::new()).with_webhook(Some(Webhook::new(&url)));
+        stream.reconnects = 0;
+        let error = stream.next().await.unwrap_err();
+        let notices = notices.lock().unwrap();
+        assert_eq!(notices.len(), 1);
+        assert_eq!(notices[0]["event"], "docker_events_lost");
+        assert_eq!(notices[0]["message"], error.as_str());
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_limit() {
+        let waits: Vec<u64> = (1..=8).map(|attempt| backoff(attempt).as_secs()).collect();
+        assert_eq!(waits, [1, 2, 4, 8, 16, 30, 30, 30]);
+    }
+}
@@ -0,0 +1,106 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+/// Progress events a layer or build sends per second at most, the last one of a status always goes out.
+pub const PROGRESS_PER_SECOND: u32 = 4;
+
+/// How important a log line is.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Level {
+    Section,
+    Step,
+    Warn,
+    /// Details for troubleshooting such as raw daemon errors, the CLI only prints them in debug mode.
+    Debug,
+}
+
+/// Progress of a ruku operation. The CLI prints them, embedders receive them through a channel given
+/// to [`crate::logger::Logger::with_events`]. `--json-events` prints them as JSON lines tagged by `event`,
+/// e.g. `{"event":"stage_started","stage":"build"}`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    /// A deploy stage such as `build` or `start` began.
+    StageStarted {
+        stage: String,
+    },
+    /// A deploy stage finished after `seconds`.
+    StageCompleted {
+        stage: String,
+        seconds: f64,
+    },
+    LogLine {
+        level: Level,
+        message: String,
+    },
+    /// A layer of an image being pulled, e.g. `Downloading` with the bytes so far, or `Pull complete`.
+    PullProgress {
+        image: String,
+        layer: String,
+        status: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        current: Option<u64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        total: Option<u64>,
+    },
+    /// A Dockerfile build reached step `step` of `total` of a stage, as BuildKit numbers them.
+    BuildStep {
+        step: u32,
+        total: u32,
+        /// The instruction of the step, e.g. `RUN npm ci`.
+        instruction: String,
+    },
+    /// An error, usually the last event before the operation stops.
+    Error {
+        message: String,
+    },
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Level::Section => write!(f, "section"),
+            Level::Step => write!(f, "step"),
+            Level::Warn => write!(f, "warn"),
+            Level::Debug => write!(f, "debug"),
+        }
+    }
+}
+
+/// Lets through [`PROGRESS_PER_SECOND`] progress events per key, such as a layer, and every change of
+/// status, so a fast local pull doesn't flood whoever receives the events.
+pub struct Downsample {
+    interval: Duration,
+    last: HashMap<String, (Instant, String)>,
+}
+
+impl Downsample {
+    pub fn new() -> Downsample {
+        Downsample {
+            interval: Duration::from_secs(1) / PROGRESS_PER_SECOND,
+            last: HashMap::new(),
+        }
+    }
+
+    /// Whether an event of `key` with `status` goes out, recording it when it does.
+    pub fn admit(&mut self, key: &str, status: &str) -> bool {
+        let admitted = match self.last.get(key) {
+            Some((sent, last_status)) => last_status != status || sent.elapsed() >= self.interval,
+            None => true,
+        };
+        if admitted {
+            self.last.insert(key.to_string(), (Instant::now(), status.to_string()));
+        }
+        admitted
+    }
+}
+
+impl Default for Downsample {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,67 @@
+use std::fmt::Display;
+use std::future::Future;
+
+use futures_util::{stream, StreamExt};
+
+use crate::logger::Logger;
+
+/// Default number of container operations run at the same time.
+pub const DEFAULT_CONCURRENCY: usize = 4;
+
+/// Runs the same operation over many items concurrently, with a bound on how many run at once.
+pub struct Executor<'a> {
+    log: &'a Logger,
+    limit: usize,
+}
+
+impl<'a> Executor<'a> {
+    pub fn new(log: &'a Logger, limit: usize) -> Executor<'a> {
+        Executor {
+            log,
+            limit: limit.max(1),
+        }
+    }
+
+    /// Apply `operation` to every item. A failing item never stops the others, all failures are
+    /// reported once everything has finished and returned to the caller.
+    pub async fn run<T, F, Fut, E>(&self, action: &str, items: Vec<T>, operation: F) -> Vec<(T, E)>
+    where
+        T: Display + Clone,
+        F: Fn(T) -> Fut,
+        Fut: Future<Output = Result<(), E>>,
+        E: Display,
+    {
+        let total = items.len();
+        let mut done = 0;
+        let mut failures = vec![];
+
+        let mut results = stream::iter(items)
+            .map(|item| {
+                let future = operation(item.clone());
+                async move { (item, future.await) }
+            })
+            .buffer_unordered(self.limit);
+
+        // Results are reported here, one line each, so concurrent operations never interleave output
+        while let Some((item, result)) = results.next().await {
+            done += 1;
+            match result {
+                Ok(()) => self.log.step(&format!("[{}/{}] {} {}", done, total, action, item)),
+                Err(e) => {
+                    self.log
+                        .warn(&format!("[{}/{}] failed on {}: {}", done, total, item, e));
+                    failures.push((item, e));
+                }
+            }
+        }
+
+        if !failures.is_empty() {
+            self.log
+                .error(&format!("{} of {} operations failed:", failures.len(), total));
+            for (item, e) in &failures {
+                self.log.error(&format!("  {}: {}", item, e));
+            }
+        }
+        failures
+    }
+}
@@ -0,0 +1,79 @@
+use std::time::Duration;
+
+use bollard::models::ContainerInspectResponse;
+use chrono::DateTime;
+
+use crate::units::format_duration;
+
+/// Seconds Docker waits for a container to stop before it kills it, when the container sets none.
+pub const DEFAULT_STOP_TIMEOUT: i64 = 10;
+/// The exit code of a process killed by SIGKILL, 128 + 9.
+const KILLED: i64 = 137;
+
+/// How a container went down when ruku stopped it, read between the stop and the removal.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StopReport {
+    pub container: String,
+    pub exit_code: i64,
+    /// The signal the container is stopped with, `SIGTERM` unless its image or config sets another.
+    pub stop_signal: String,
+    /// Seconds Docker gave it before the SIGKILL.
+    pub grace_period: i64,
+    /// Whether it outlived the grace period and was killed.
+    pub killed: bool,
+    pub oom_killed: bool,
+    /// From its last start to the stop.
+    pub uptime: Option<Duration>,
+}
+
+impl StopReport {
+    /// The report of a stopped `container` from its inspect data, none when it has no state.
+    pub fn from_inspect(container: &str, inspect: &ContainerInspectResponse) -> Option<StopReport> {
+        let state = inspect.state.as_ref()?;
+        let config = inspect.config.as_ref();
+        let stop_signal = config
+            .and_then(|config| config.stop_signal.clone())
+            .unwrap_or("SIGTERM".to_string());
+        let exit_code = state.exit_code?;
+        let oom_killed = state.oom_killed.unwrap_or(false);
+        let parse = |time: &Option<String>| time.as_deref().and_then(|time| DateTime::parse_from_rfc3339(time).ok());
+        let uptime = match (parse(&state.started_at), parse(&state.finished_at)) {
+            (Some(started), Some(finished)) => (finished - started).to_std().ok(),
+            _ => None,
+        };
+        Some(StopReport {
+            container: container.to_string(),
+            exit_code,
+            killed: exit_code == KILLED && !oom_killed && !["SIGKILL", "KILL", "9"].contains(&stop_signal.as_str()),
+            stop_signal,
+            grace_period: config
+                .and_then(|config| config.stop_timeout)
+                .unwrap_or(DEFAULT_STOP_TIMEOUT),
+            oom_killed,
+            uptime,
+        })
+    }
+
+    /// Whether it exited 0 on the stop signal.
+    pub fn is_clean(&self) -> bool {
+        self.exit_code == 0 && !self.killed
+    }
+
+    /// One line on how it went down, e.g. `demo exited 0 on SIGTERM after 3h12m up`.
+    pub fn describe(&self) -> String {
+        let how = if self.killed {
+            format!(
+                "ignored {} for the {}s grace period and was killed",
+                self.stop_signal, self.grace_period
+            )
+        } else if self.oom_killed {
+            format!("exited {}, killed for running out of memory", self.exit_code)
+        } else {
+            format!("exited {} on {}", self.exit_code, self.stop_signal)
+        };
+        match self.uptime {
+            Some(uptime) => format!("{} {} after {} up", self.container, how, format_duration(uptime)),
+            None => format!("{} {}", self.container, how),
+        }
+    }
+}
@@ -0,0 +1,206 @@
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use bollard::container::LogsOptions;
+use bollard::Docker;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use futures_util::StreamExt;
+
+use crate::debug_bundle::Redactor;
+use crate::logger::Logger;
+
+/// Lines of output kept per container, the end of it where the failure shows.
+pub const CAPTURED_LOG_LINES: usize = 10_000;
+const LOG_SUFFIX: &str = ".log.gz";
+const INSPECT_SUFFIX: &str = ".inspect.json.gz";
+
+/// What the containers of a failed deploy printed and how they were set up, kept before the rollback
+/// removes them. Stored gzipped under `failures/<deployment id>` in the app state directory, one log
+/// and one inspect file per container, with the env values masked.
+pub struct Failures<'a> {
+    log: &'a Logger,
+    dir: PathBuf,
+    /// The daemon and id of the deploy whose containers are captured.
+    deployment: Option<(&'a Docker, String)>,
+    timeout: Duration,
+    retention: usize,
+}
+
+impl<'a> Failures<'a> {
+    pub const DIR_NAME: &'static str = "failures";
+
+    pub fn new(log: &'a Logger, state_dir: &Path) -> Failures<'a> {
+        Failures {
+            log,
+            dir: state_dir.join(Self::DIR_NAME),
+            deployment: None,
+            timeout: Duration::from_secs(30),
+            retention: 50,
+        }
+    }
+
+    /// Capture the containers of the deploy with this id.
+    pub fn with_deployment(mut self, docker: &'a Docker, id: &str) -> Failures<'a> {
+        self.deployment = Some((docker, id.to_string()));
+        self
+    }
+
+    /// Give up on the capture after `timeout`, the rollback waits for it.
+    pub fn with_timeout(mut self, timeout: Duration) -> Failures<'a> {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Keep the captures of the newest `retention` failed deploys, as many as there are config snapshots.
+    pub fn with_retention(mut self, retention: usize) -> Failures<'a> {
+        self.retention = retention;
+        self
+    }
+
+    /// Keep the output and inspect data of `containers` under the deployment id. Containers that are
+    /// gone are skipped, nothing here stops the rollback.
+    pub async fn capture(&self, containers: &[String]) {
+        let Some((docker, id)) = &self.deployment else {
+            return;
+        };
+        let dir = self.dir.join(id);
+        let captured = tokio::time::timeout(self.timeout, async {
+            let mut captured = vec![];
+            for container in containers {
+                match self.capture_container(docker, &dir, container).await {
+                    Ok(true) => captured.push(container.as_str()),
+                    Ok(false) => {}
+                    Err(e) => self
+                        .log
+                        .warn(&format!("Error keeping the logs of {}: {}", container, e)),
+                }
+            }
+            captured
+        })
+        .await;
+        match captured {
+            Ok(captured) if captured.is_empty() => {}
+            Ok(captured) => self.log.step(&format!(
+                "Kept the logs of {} in {}",
+                captured.join(", "),
+                dir.display()
+            )),
+            Err(_) => self.log.warn(&format!(
+                "Gave up keeping the logs of the failed deploy after {}s",
+                self.timeout.as_secs()
+            )),
+        }
+        self.prune();
+    }
+
+    async fn capture_container(&self, docker: &Docker, dir: &Path, container: &str) -> Result<bool, String> {
+        let Ok(inspect) = docker.inspect_container(container, None).await else {
+            return Ok(false);
+        };
+        let mut redactor = Redactor::new();
+        let env = inspect.config.as_ref().and_then(|config| config.env.clone());
+        redactor.add_env(&env.unwrap_or_default());
+        let mut inspect = serde_json::to_value(&inspect).map_err(|e| e.to_string())?;
+        redactor.redact_json(&mut inspect);
+
+        let options = LogsOptions::<String> {
+            stdout: true,
+            stderr: true,
+            timestamps: true,
+            tail: CAPTURED_LOG_LINES.to_string(),
+            ..Default::default()
+        };
+        let mut output = String::new();
+        let mut stream = docker.logs(container, Some(options));
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk.map_err(|e| e.to_string())?;
+            // The stream of a tty container has the line endings of the terminal
+            output.push_str(&String::from_utf8_lossy(chunk.as_ref()).replace("\r\n", "\n"));
+        }
+
+        fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+        let inspect = serde_json::to_vec_pretty(&inspect).map_err(|e| e.to_string())?;
+        write_gzip(&dir.join(format!("{}{}", container, INSPECT_SUFFIX)), &inspect)?;
+        write_gzip(
+            &dir.join(format!("{}{}", container, LOG_SUFFIX)),
+            redactor.redact_text(&output).as_bytes(),
+        )?;
+        Ok(true)
+    }
+
+    /// Ids of the deploys with captured logs, oldest first.
+    pub fn ids(&self) -> Vec<String> {
+        let mut ids: Vec<String> = fs::read_dir(&self.dir)
+            .into_iter()
+            .flatten()
+            .flatten()
+            .filter(|entry| entry.path().is_dir())
+            .map(|entry| entry.file_name().to_string_lossy().to_string())
+            .collect();
+        // Ids are timestamps, so name order is age order
+        ids.sort();
+        ids
+    }
+
+    /// Print the kept output of the containers of deployment `id`, failing when there is none.
+    pub fn print(&self, id: &str) -> Result<(), String> {
+        // Ids come from the command line, anything but a plain name can't be one
+        let valid = !id.is_empty() && id.chars().all(|c| c.is_ascii_alphanumeric());
+        let mut logs: Vec<PathBuf> = fs::read_dir(self.dir.join(id))
+            .into_iter()
+            .filter(|_| valid)
+            .flatten()
+            .flatten()
+            .map(|entry| entry.path())
+            .filter(|path| path.to_string_lossy().ends_with(LOG_SUFFIX))
+            .collect();
+        if logs.is_empty() {
+            let known = self.ids();
+            if known.is_empty() {
+                return Err("No logs of failed deploys kept".to_string());
+            }
+            return Err(format!(
+                "No logs kept for deployment {}, known: {}",
+                id,
+                known.join(", ")
+            ));
+        }
+        logs.sort();
+        for path in logs {
+            let name = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+            let container = name.trim_end_matches(LOG_SUFFIX);
+            self.log.section(&format!("Logs of {}", container));
+            let mut content = String::new();
+            File::open(&path)
+                .and_then(|file| GzDecoder::new(file).read_to_string(&mut content))
+                .map_err(|e| format!("Error reading {}: {}", path.display(), e))?;
+            let _ = io::stdout().write_all(content.as_bytes());
+            self.log.step(&format!(
+                "Inspect data in {}",
+                path.with_file_name(format!("{}{}", container, INSPECT_SUFFIX))
+                    .display()
+            ));
+        }
+        Ok(())
+    }
+
+    fn prune(&self) {
+        let ids = self.ids();
+        for id in ids.iter().take(ids.len().saturating_sub(self.retention)) {
+            let _ = fs::remove_dir_all(self.dir.join(id));
+        }
+    }
+}
+
+/// Write `content` gzipped to `path`.
+pub fn write_gzip(path: &Path, content: &[u8]) -> Result<(), String> {
+    let file = File::create(path).map_err(|e| e.to_string())?;
+    let mut encoder = GzEncoder::new(file, Compression::default());
+    encoder.write_all(content).map_err(|e| e.to_string())?;
+    encoder.finish().map_err(|e| e.to_string())?;
+    Ok(())
+}
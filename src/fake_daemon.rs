@@ -0,0 +1,63 @@
+//! A stand-in for the Docker daemon in tests, on a local port.
+
+use std::sync::{Arc, Mutex};
+
+use bollard::Docker;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+/// The requests a [`fake_daemon`] was sent, as `METHOD /path` without the API version and the query.
+#[derive(Clone, Default)]
+pub struct Requests(Arc<Mutex<Vec<String>>>);
+
+impl Requests {
+    pub fn all(&self) -> Vec<String> {
+        self.0.lock().unwrap().clone()
+    }
+
+    /// How many times `request` was sent, e.g. `GET /containers/json`.
+    pub fn count(&self, request: &str) -> usize {
+        self.all().iter().filter(|sent| *sent == request).count()
+    }
+}
+
+/// A daemon that answers every request with `respond(method, path)` and records the requests, enough to
+/// see what a command asks the daemon.
+pub async fn fake_daemon(respond: fn(&str, &str) -> (u16, String)) -> (Docker, Requests) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let requests = Requests::default();
+    let recorded = requests.clone();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let mut request = vec![];
+            let mut buffer = [0; 4096];
+            while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+                match stream.read(&mut buffer).await {
+                    Ok(0) | Err(_) => break,
+                    Ok(read) => request.extend_from_slice(&buffer[..read]),
+                }
+            }
+            let request = String::from_utf8_lossy(&request).to_string();
+            let line = request.lines().next().unwrap_or_default().to_string();
+            let mut parts = line.split(' ');
+            let method = parts.next().unwrap_or_default();
+            let path = parts.next().unwrap_or_default();
+            let path = path.split('?').next().unwrap_or_default();
+            let path = path
+                .strip_prefix(&format!("/v{}", bollard::API_DEFAULT_VERSION))
+                .unwrap_or(path);
+            let (status, body) = respond(method, path);
+            recorded.0.lock().unwrap().push(format!("{} {}", method, path));
+            let response = format!(
+                "HTTP/1.1 {} OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status,
+                body.len(),
+                body
+            );
+            let _ = stream.write_all(response.as_bytes()).await;
+        }
+    });
+    let docker = Docker::connect_with_http(&format!("http://{}", address), 5, bollard::API_DEFAULT_VERSION).unwrap();
+    (docker, requests)
+}
@@ -0,0 +1,227 @@
+use std::fmt;
+use std::io::{BufRead, BufReader, Read};
+use std::process::{Command, Stdio};
+use std::sync::Mutex;
+use std::time::Instant;
+
+use colored::Colorize;
+
+use crate::logger::Logger;
+use crate::server_config::ServerConfig;
+
+/// Set for the ruku processes a fleet runs, so each acts on its own host instead of going through the
+/// hosts again.
+pub const MEMBER_ENV: &str = "RUKU_FLEET_MEMBER";
+
+/// Whether this process runs a command on one host of a fleet.
+pub fn is_member() -> bool {
+    std::env::var_os(MEMBER_ENV).is_some()
+}
+
+/// How the command went on one host.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outcome {
+    Succeeded,
+    /// Exited with this code, none when it was killed or could not be started.
+    Failed(Option<i32>),
+    /// Not run, a host before it failed.
+    Skipped,
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Outcome::Succeeded => write!(f, "ok"),
+            Outcome::Failed(Some(code)) => write!(f, "failed (exit {})", code),
+            Outcome::Failed(None) => write!(f, "failed"),
+            Outcome::Skipped => write!(f, "skipped"),
+        }
+    }
+}
+
+pub struct HostResult {
+    pub host: String,
+    pub outcome: Outcome,
+    pub seconds: f64,
+}
+
+/// Runs a command of this ruku once for each host of an app, each with the context of its host, one host
+/// after the other or all at once.
+pub struct Fleet<'a> {
+    log: &'a Logger,
+    hosts: &'a [String],
+    parallel: bool,
+    halt_on_failure: bool,
+}
+
+impl<'a> Fleet<'a> {
+    pub fn new(log: &'a Logger, hosts: &'a [String]) -> Fleet<'a> {
+        Fleet {
+            log,
+            hosts,
+            parallel: false,
+            halt_on_failure: false,
+        }
+    }
+
+    /// Run on every host at once, their output prefixed with the host.
+    pub fn with_parallel(mut self, parallel: bool) -> Fleet<'a> {
+        self.parallel = parallel;
+        self
+    }
+
+    /// Skip the remaining hosts once one fails, so a deploy that fails its health gate goes no further.
+    /// Only applies one host after the other.
+    pub fn with_halt_on_failure(mut self, halt_on_failure: bool) -> Fleet<'a> {
+        self.halt_on_failure = halt_on_failure;
+        self
+    }
+
+    /// Fail unless every host is a context of `server_config`.
+    pub fn check(&self, app: &str, server_config: &ServerConfig) -> Result<(), String> {
+        let unknown: Vec<&str> = self
+            .hosts
+            .iter()
+            .filter(|host| !server_config.contexts.contains_key(host.as_str()))
+            .map(String::as_str)
+            .collect();
+        if !unknown.is_empty() {
+            return Err(format!(
+                "hosts of {} lists {}, which ~/.ruku/config.yml has no context for",
+                app,
+                unknown.join(", ")
+            ));
+        }
+        Ok(())
+    }
+
+    /// Run this process's command line, `args` without the program, on every host.
+    pub fn run(&self, args: &[String]) -> Result<Vec<HostResult>, String> {
+        let exe = std::env::current_exe().map_err(|e| format!("Could not find the ruku binary: {}", e))?;
+        let command = |host: &str| {
+            let mut command = Command::new(&exe);
+            command.arg("--context").arg(host).args(args).env(MEMBER_ENV, host);
+            command
+        };
+        if self.parallel {
+            let output = Mutex::new(());
+            return Ok(std::thread::scope(|scope| {
+                let runs: Vec<_> = self
+                    .hosts
+                    .iter()
+                    .map(|host| {
+                        let mut command = command(host);
+                        let output = &output;
+                        scope.spawn(move || run_prefixed(host, &mut command, output))
+                    })
+                    .collect();
+                runs.into_iter().map(|run| run.join().unwrap()).collect()
+            }));
+        }
+
+        let mut results = vec![];
+        for host in self.hosts {
+            let failed = results
+                .iter()
+                .any(|result: &HostResult| result.outcome != Outcome::Succeeded);
+            if self.halt_on_failure && failed {
+                results.push(HostResult {
+                    host: host.clone(),
+                    outcome: Outcome::Skipped,
+                    seconds: 0.0,
+                });
+                continue;
+            }
+            self.log.section(&format!("Host {}", host));
+            let started = Instant::now();
+            let outcome = match command(host).status() {
+                Ok(status) if status.success() => Outcome::Succeeded,
+                Ok(status) => Outcome::Failed(status.code()),
+                Err(e) => {
+                    self.log.warn(&format!("Could not run ruku for {}: {}", host, e));
+                    Outcome::Failed(None)
+                }
+            };
+            results.push(HostResult {
+                host: host.clone(),
+                outcome,
+                seconds: started.elapsed().as_secs_f64(),
+            });
+        }
+        Ok(results)
+    }
+
+    /// Print a line for each host and fail when one of them did not succeed.
+    pub fn report(&self, results: &[HostResult]) -> Result<(), String> {
+        self.log.section("Hosts");
+        let width = results.iter().map(|result| result.host.len()).max().unwrap_or(0).max(4);
+        println!("{:<width$}  {:<16} TIME", "HOST", "RESULT", width = width);
+        for result in results {
+            let outcome = match result.outcome {
+                Outcome::Succeeded => result.outcome.to_string().green(),
+                Outcome::Failed(_) => result.outcome.to_string().red(),
+                Outcome::Skipped => result.outcome.to_string().yellow(),
+            };
+            let time = match result.outcome {
+                Outcome::Skipped => "-".to_string(),
+                _ => format!("{:.1}s", result.seconds),
+            };
+            println!("{:<width$}  {:<16} {}", result.host, outcome, time, width = width);
+        }
+        let failed = results
+            .iter()
+            .filter(|result| result.outcome != Outcome::Succeeded)
+            .count();
+        if failed > 0 {
+            return Err(format!("{} of {} hosts did not succeed", failed, results.len()));
+        }
+        Ok(())
+    }
+}
+
+/// Run `command` with its output going line by line to ours, each line prefixed with `host`.
+fn run_prefixed(host: &str, command: &mut Command, output: &Mutex<()>) -> HostResult {
+    let started = Instant::now();
+    let child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn();
+    let mut child = match child {
+        Ok(child) => child,
+        Err(e) => {
+            eprintln!("[{}] Could not run ruku: {}", host, e);
+            return HostResult {
+                host: host.to_string(),
+                outcome: Outcome::Failed(None),
+                seconds: 0.0,
+            };
+        }
+    };
+    let stdout = child.stdout.take().unwrap();
+    let stderr = child.stderr.take().unwrap();
+    std::thread::scope(|scope| {
+        scope.spawn(|| forward(host, stdout, output, false));
+        scope.spawn(|| forward(host, stderr, output, true));
+    });
+    let outcome = match child.wait() {
+        Ok(status) if status.success() => Outcome::Succeeded,
+        Ok(status) => Outcome::Failed(status.code()),
+        Err(_) => Outcome::Failed(None),
+    };
+    HostResult {
+        host: host.to_string(),
+        outcome,
+        seconds: started.elapsed().as_secs_f64(),
+    }
+}
+
+fn forward(host: &str, stream: impl Read, output: &Mutex<()>, stderr: bool) {
+    for line in BufReader::new(stream).lines().map_while(Result::ok) {
+        let _guard = output.lock().unwrap();
+        match stderr {
+            true => eprintln!("[{}] {}", host, line),
+            false => println!("[{}] {}", host, line),
+        }
+    }
+}
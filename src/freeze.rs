@@ -0,0 +1,524 @@
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use chrono::{
+    DateTime, Datelike, Duration, FixedOffset, Local, LocalResult, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Utc,
+    Weekday,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::deploy_message::check_message;
+use crate::logger::Logger;
+use crate::zoneinfo::NamedZone;
+
+/// Env var `--override-freeze` falls back to.
+pub const OVERRIDE_ENV: &str = "RUKU_OVERRIDE_FREEZE";
+
+/// The reason `--override-freeze` gave to go ahead during a freeze.
+static OVERRIDE_REASON: Mutex<Option<String>> = Mutex::new(None);
+/// The freeze this process went ahead despite, once [`guard`] let it.
+static OVERRIDDEN: Mutex<Option<String>> = Mutex::new(None);
+
+/// Where the wall times of a weekly window are.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Zone {
+    /// The timezone of the host, with its daylight saving changes.
+    Local,
+    Fixed(FixedOffset),
+    /// A timezone of the tz database like `Europe/Berlin`, with its daylight saving changes.
+    Named(Arc<NamedZone>),
+}
+
+impl Zone {
+    fn parse(zone: &str) -> Result<Zone, String> {
+        Zone::parse_in(zone, &NamedZone::dir())
+    }
+
+    /// [`Zone::parse`] with the names looked up in the tz database in `zoneinfo_dir`.
+    fn parse_in(zone: &str, zoneinfo_dir: &Path) -> Result<Zone, String> {
+        match zone {
+            "local" => return Ok(Zone::Local),
+            "UTC" | "utc" | "Z" => return Ok(Zone::Fixed(FixedOffset::east_opt(0).unwrap())),
+            _ => {}
+        }
+        if let Ok(offset) = zone.parse::<FixedOffset>() {
+            return Ok(Zone::Fixed(offset));
+        }
+        NamedZone::load(zoneinfo_dir, zone)
+            .map(|named| Zone::Named(Arc::new(named)))
+            .map_err(|e| {
+                format!(
+                    "invalid timezone '{}' ({}), use local, UTC, an offset like +02:00 or a name like Europe/Berlin",
+                    zone, e
+                )
+            })
+    }
+
+    fn wall_time(&self, instant: DateTime<Utc>) -> NaiveDateTime {
+        match self {
+            Zone::Local => instant.with_timezone(&Local).naive_local(),
+            Zone::Fixed(offset) => instant.with_timezone(offset).naive_local(),
+            Zone::Named(named) => {
+                instant.naive_utc() + Duration::seconds(i64::from(named.offset_at(instant.timestamp())))
+            }
+        }
+    }
+
+    /// The instant the wall `time` is at, the first one when a daylight saving change repeats it and the
+    /// end of the gap when a change skips it.
+    fn earliest(&self, time: NaiveDateTime) -> DateTime<Utc> {
+        self.instant(time, false)
+    }
+
+    /// [`Zone::earliest`], but the last instant of a repeated wall time.
+    fn latest(&self, time: NaiveDateTime) -> DateTime<Utc> {
+        self.instant(time, true)
+    }
+
+    fn instant(&self, time: NaiveDateTime, latest: bool) -> DateTime<Utc> {
+        // Gaps last an hour or less, the wall times after it exist
+        (0..=24 * 60)
+            .find_map(|minutes| {
+                let result = self.resolve(time + Duration::minutes(minutes));
+                match latest {
+                    true => result.latest(),
+                    false => result.earliest(),
+                }
+            })
+            .unwrap_or_else(|| time.and_utc())
+    }
+
+    /// The instants the wall `time` is at.
+    fn resolve(&self, time: NaiveDateTime) -> LocalResult<DateTime<Utc>> {
+        match self {
+            Zone::Fixed(offset) => offset.from_local_datetime(&time).map(|instant| instant.to_utc()),
+            Zone::Local => Local.from_local_datetime(&time).map(|instant| instant.to_utc()),
+            Zone::Named(named) => {
+                let instants: Vec<DateTime<Utc>> = named
+                    .instants(time.and_utc().timestamp())
+                    .into_iter()
+                    .filter_map(|seconds| DateTime::from_timestamp(seconds, 0))
+                    .collect();
+                match instants[..] {
+                    [] => LocalResult::None,
+                    [instant] => LocalResult::Single(instant),
+                    [first, .., last] => LocalResult::Ambiguous(first, last),
+                }
+            }
+        }
+    }
+}
+
+/// When a freeze window applies.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FreezeTime {
+    /// Once, from one instant until another.
+    Range { from: DateTime<Utc>, until: DateTime<Utc> },
+    /// Every week on `days`, every day when there are none, from `start` until `end` in `zone`. A window
+    /// whose end is not after its start ends the next day.
+    Weekly {
+        days: Vec<Weekday>,
+        start: NaiveTime,
+        end: NaiveTime,
+        zone: Zone,
+    },
+}
+
+/// A time commands that change state are refused in, e.g. over a sale or a release weekend.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "FreezeValue", into = "FreezeValue")]
+pub struct FreezeWindow {
+    pub name: String,
+    /// Who set the window up, e.g. a team, named when a command is refused.
+    pub by: Option<String>,
+    pub time: FreezeTime,
+    value: FreezeValue,
+}
+
+/// A `freeze` entry as it is written, either `from` and `until` or `start` and `end` with optional `days`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FreezeValue {
+    name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    by: Option<String>,
+    /// RFC 3339 like `2026-11-27T00:00:00+01:00`, or `2026-11-27 00:00` in `timezone`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    from: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    until: Option<String>,
+    /// Weekdays like `fri` or `saturday`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    days: Vec<String>,
+    /// Wall times like `18:00`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    start: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    end: Option<String>,
+    /// `local` for the timezone of the host, `UTC`, an offset like `+02:00` or a name like
+    /// `Europe/Berlin`, UTC when not set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    timezone: Option<String>,
+}
+
+impl TryFrom<FreezeValue> for FreezeWindow {
+    type Error = String;
+
+    fn try_from(value: FreezeValue) -> Result<Self, Self::Error> {
+        let name = value.name.trim();
+        if name.is_empty() {
+            return Err("a freeze window needs a name".to_string());
+        }
+        let invalid = |message: String| format!("freeze window {}: {}", name, message);
+        let zone = Zone::parse(value.timezone.as_deref().unwrap_or("UTC")).map_err(invalid)?;
+        let time = match (&value.from, &value.until, &value.start, &value.end) {
+            (Some(from), Some(until), None, None) if value.days.is_empty() => {
+                let from = parse_instant(from, &zone).map_err(invalid)?;
+                let until = parse_instant(until, &zone).map_err(invalid)?;
+                if until <= from {
+                    return Err(invalid("until must come after from".to_string()));
+                }
+                FreezeTime::Range { from, until }
+            }
+            (None, None, Some(start), Some(end)) => FreezeTime::Weekly {
+                days: value
+                    .days
+                    .iter()
+                    .map(|day| {
+                        day.parse::<Weekday>()
+                            .map_err(|_| invalid(format!("invalid day '{}', use e.g. mon or friday", day)))
+                    })
+                    .collect::<Result<_, _>>()?,
+                start: parse_wall_time(start).map_err(invalid)?,
+                end: parse_wall_time(end).map_err(invalid)?,
+                zone,
+            },
+            _ => {
+                return Err(invalid(
+                    "set either from and until, or start and end with optional days".to_string(),
+                ))
+            }
+        };
+        Ok(FreezeWindow {
+            name: name.to_string(),
+            by: value.by.clone(),
+            time,
+            value,
+        })
+    }
+}
+
+impl From<FreezeWindow> for FreezeValue {
+    fn from(window: FreezeWindow) -> Self {
+        window.value
+    }
+}
+
+fn parse_instant(text: &str, zone: &Zone) -> Result<DateTime<Utc>, String> {
+    if let Ok(instant) = DateTime::parse_from_rfc3339(text) {
+        return Ok(instant.to_utc());
+    }
+    [
+        "%Y-%m-%d %H:%M",
+        "%Y-%m-%dT%H:%M",
+        "%Y-%m-%d %H:%M:%S",
+        "%Y-%m-%dT%H:%M:%S",
+    ]
+    .iter()
+    .find_map(|format| NaiveDateTime::parse_from_str(text, format).ok())
+    .or_else(|| {
+        NaiveDate::parse_from_str(text, "%Y-%m-%d")
+            .ok()
+            .map(|date| date.and_time(NaiveTime::MIN))
+    })
+    .map(|time| zone.earliest(time))
+    .ok_or(format!(
+        "invalid time '{}', use e.g. 2026-11-27T00:00:00+01:00 or 2026-11-27 00:00",
+        text
+    ))
+}
+
+fn parse_wall_time(text: &str) -> Result<NaiveTime, String> {
+    NaiveTime::parse_from_str(text, "%H:%M").map_err(|_| format!("invalid time '{}', use e.g. 18:00", text))
+}
+
+impl FreezeWindow {
+    /// The start and end of the occurrence of the window `at` falls in, none when it falls in none.
+    pub fn span_at(&self, at: DateTime<Utc>) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+        match &self.time {
+            FreezeTime::Range { from, until } => (*from <= at && at < *until).then_some((*from, *until)),
+            FreezeTime::Weekly { days, start, end, zone } => {
+                // An occurrence lasts a day at most, so it started yesterday or today
+                let today = zone.wall_time(at).date();
+                [today - Duration::days(1), today].into_iter().find_map(|date| {
+                    if !days.is_empty() && !days.contains(&date.weekday()) {
+                        return None;
+                    }
+                    let end_date = match end <= start {
+                        true => date + Duration::days(1),
+                        false => date,
+                    };
+                    let from = zone.earliest(date.and_time(*start));
+                    let until = zone.latest(end_date.and_time(*end));
+                    (from <= at && at < until).then_some((from, until))
+                })
+            }
+        }
+    }
+
+    /// Who set the window up and where, e.g. `by change-management in ~/.ruku/config.yml`.
+    fn origin(&self, source: &str) -> String {
+        match &self.by {
+            Some(by) => format!("set up by {} in {}", by, source),
+            None => format!("set up in {}", source),
+        }
+    }
+}
+
+/// A freeze window in effect, with where it is configured and when the freeze ends.
+pub struct ActiveFreeze<'a> {
+    pub window: &'a FreezeWindow,
+    pub source: &'a str,
+    /// When no window is active any more, windows that follow on from each other taken together.
+    pub until: DateTime<Utc>,
+}
+
+impl ActiveFreeze<'_> {
+    /// E.g. `black-friday (set up by change-management in ~/.ruku/config.yml) until 2026-11-30 23:00 +01:00`,
+    /// the end in the timezone of the host.
+    pub fn describe(&self) -> String {
+        format!(
+            "{} ({}) until {}",
+            self.window.name,
+            self.window.origin(self.source),
+            self.until.with_timezone(&Local).format("%Y-%m-%d %H:%M %:z")
+        )
+    }
+}
+
+/// The first window of `windows`, each with where it is configured, in effect at `now`.
+pub fn active<'a>(windows: &[(&'a FreezeWindow, &'a str)], now: DateTime<Utc>) -> Option<ActiveFreeze<'a>> {
+    let (window, source, mut until) = windows
+        .iter()
+        .find_map(|(window, source)| window.span_at(now).map(|(_, until)| (*window, *source, until)))?;
+    // Windows back to back are followed for a week at most, an always frozen app still gets an answer
+    while until - now < Duration::days(7) {
+        let next = windows
+            .iter()
+            .filter_map(|(window, _)| window.span_at(until))
+            .map(|(_, next)| next)
+            .max();
+        match next {
+            Some(next) if next > until => until = next,
+            _ => break,
+        }
+    }
+    Some(ActiveFreeze { window, source, until })
+}
+
+/// Go ahead during a freeze for `reason`, from `--override-freeze` or [`OVERRIDE_ENV`].
+pub fn set_override(reason: &str) -> Result<(), String> {
+    let reason = check_message(reason).map_err(|e| {
+        format!(
+            "Error in the freeze override reason: {}",
+            e.replace("the deploy message", "the reason")
+        )
+    })?;
+    *OVERRIDE_REASON.lock().unwrap() = Some(reason);
+    Ok(())
+}
+
+/// Fail before `operation` when one of `windows` is in effect at `now`, unless an override reason was
+/// given.
+pub fn guard(
+    log: &Logger,
+    windows: &[(&FreezeWindow, &str)],
+    operation: &str,
+    now: DateTime<Utc>,
+) -> Result<(), String> {
+    let Some(freeze) = active(windows, now) else {
+        return Ok(());
+    };
+    let reason = OVERRIDE_REASON.lock().unwrap().clone();
+    match reason {
+        Some(reason) => {
+            log.warn(&format!(
+                "Going ahead to {} during freeze {}: {}",
+                operation,
+                freeze.describe(),
+                reason
+            ));
+            *OVERRIDDEN.lock().unwrap() = Some(format!("freeze {} overridden: {}", freeze.window.name, reason));
+        }
+        None => {
+            return Err(format!(
+                "Refusing to {} during freeze {}, pass --override-freeze \"<reason>\" to go ahead anyway",
+                operation,
+                freeze.describe()
+            ))
+        }
+    }
+    Ok(())
+}
+
+/// The freeze this process went ahead despite, e.g. `freeze black-friday overridden: fix for the checkout`.
+pub fn overridden() -> Option<String> {
+    OVERRIDDEN.lock().unwrap().clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    fn window(yaml: &str) -> FreezeWindow {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    fn at(text: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(text).unwrap().to_utc()
+    }
+
+    #[test]
+    fn windows_are_parsed_from_either_form() {
+        let range = window("{name: black-friday, from: '2026-11-27 00:00', until: '2026-11-30', timezone: '+01:00'}");
+        assert_eq!(
+            range.time,
+            FreezeTime::Range {
+                from: at("2026-11-26T23:00:00Z"),
+                until: at("2026-11-29T23:00:00Z"),
+            }
+        );
+        let weekly = window("{name: weekend, days: [fri, saturday], start: '18:00', end: '08:00'}");
+        assert!(matches!(weekly.time, FreezeTime::Weekly { ref days, .. } if days == &[Weekday::Fri, Weekday::Sat]));
+        // Written back the way it was given
+        let written = serde_yaml::to_string(&weekly).unwrap();
+        assert!(written.contains("- saturday\n"));
+        assert_eq!(window(&written), weekly);
+    }
+
+    #[test]
+    fn invalid_windows_are_refused() {
+        let error = |yaml: &str| serde_yaml::from_str::<FreezeWindow>(yaml).unwrap_err().to_string();
+        assert!(error("{name: ' ', start: '18:00', end: '08:00'}").contains("a freeze window needs a name"));
+        assert!(error("{name: x, from: '2026-11-30', until: '2026-11-27'}").contains("until must come after from"));
+        assert!(error("{name: x, days: [someday], start: '18:00', end: '08:00'}").contains("invalid day 'someday'"));
+        assert!(error("{name: x, start: '6pm', end: '08:00'}").contains("invalid time '6pm'"));
+        assert!(error("{name: x, from: '2026-11-27', start: '18:00'}").contains("set either from and until"));
+        assert!(error("{name: x, start: '18:00', end: '08:00', timezone: Mars/Olympus}")
+            .contains("invalid timezone 'Mars/Olympus'"));
+        assert!(error("{name: x, start: '18:00', end: '08:00', owner: me}").contains("unknown field"));
+    }
+
+    #[test]
+    fn weekly_windows_run_past_midnight() {
+        let weekend = window("{name: weekend, days: [fri], start: '18:00', end: '08:00', timezone: '+02:00'}");
+        // Friday 2026-10-16, 18:00 in +02:00 is 16:00 UTC
+        assert_eq!(weekend.span_at(at("2026-10-16T15:59:00Z")), None);
+        let span = Some((at("2026-10-16T16:00:00Z"), at("2026-10-17T06:00:00Z")));
+        assert_eq!(weekend.span_at(at("2026-10-16T16:00:00Z")), span);
+        assert_eq!(weekend.span_at(at("2026-10-17T05:59:00Z")), span);
+        assert_eq!(weekend.span_at(at("2026-10-17T06:00:00Z")), None);
+        // Saturday evening is not a Friday
+        assert_eq!(weekend.span_at(at("2026-10-17T17:00:00Z")), None);
+
+        let nightly = window("{name: nightly, start: '22:00', end: '23:00'}");
+        assert!(nightly.span_at(at("2026-10-14T22:30:00Z")).is_some());
+        assert!(nightly.span_at(at("2026-10-14T23:00:00Z")).is_none());
+    }
+
+    /// Europe/Berlin from a tz database of its own, with only its current rule.
+    fn berlin() -> (Zone, tempfile::TempDir) {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("Europe")).unwrap();
+        let data = crate::zoneinfo::tests::tzif(&[], &[3600], "CET-1CEST,M3.5.0,M10.5.0/3");
+        fs::write(dir.path().join("Europe").join("Berlin"), data).unwrap();
+        (Zone::parse_in("Europe/Berlin", dir.path()).unwrap(), dir)
+    }
+
+    fn in_zone(mut window: FreezeWindow, zone: &Zone) -> FreezeWindow {
+        if let FreezeTime::Weekly { zone: window_zone, .. } = &mut window.time {
+            *window_zone = zone.clone();
+        }
+        window
+    }
+
+    #[test]
+    fn named_zones_follow_daylight_saving() {
+        let (berlin, _dir) = berlin();
+        let nightly = in_zone(
+            window("{name: nightly, days: [sun], start: '01:00', end: '04:00'}"),
+            &berlin,
+        );
+        // Three hours in winter, clocks go forward an hour at 02:00 on 2026-03-29, back at 03:00 on 10-25
+        let winter = (at("2026-03-22T00:00:00Z"), at("2026-03-22T03:00:00Z"));
+        assert_eq!(nightly.span_at(at("2026-03-22T01:00:00Z")), Some(winter));
+        let forward = (at("2026-03-29T00:00:00Z"), at("2026-03-29T02:00:00Z"));
+        assert_eq!(nightly.span_at(at("2026-03-29T01:30:00Z")), Some(forward));
+        assert_eq!(nightly.span_at(at("2026-03-29T02:00:00Z")), None);
+        let summer = (at("2026-04-04T23:00:00Z"), at("2026-04-05T02:00:00Z"));
+        assert_eq!(nightly.span_at(at("2026-04-05T01:00:00Z")), Some(summer));
+        let back = (at("2026-10-24T23:00:00Z"), at("2026-10-25T03:00:00Z"));
+        assert_eq!(nightly.span_at(at("2026-10-25T02:30:00Z")), Some(back));
+
+        // A start in the skipped hour is at its end, an end in the repeated hour at the second one
+        let skipped = in_zone(window("{name: x, days: [sun], start: '02:30', end: '05:00'}"), &berlin);
+        assert_eq!(
+            skipped.span_at(at("2026-03-29T01:00:00Z")),
+            Some((at("2026-03-29T01:00:00Z"), at("2026-03-29T03:00:00Z")))
+        );
+        let repeated = in_zone(window("{name: x, days: [sun], start: '00:00', end: '02:30'}"), &berlin);
+        assert_eq!(
+            repeated.span_at(at("2026-10-25T01:00:00Z")),
+            Some((at("2026-10-24T22:00:00Z"), at("2026-10-25T01:30:00Z")))
+        );
+
+        assert_eq!(
+            parse_instant("2026-07-01 00:00", &berlin),
+            Ok(at("2026-06-30T22:00:00Z"))
+        );
+        assert_eq!(
+            parse_instant("2026-12-01 00:00", &berlin),
+            Ok(at("2026-11-30T23:00:00Z"))
+        );
+    }
+
+    #[test]
+    fn the_guard_goes_by_the_clock_it_is_given() {
+        let (berlin, _dir) = berlin();
+        let nightly = in_zone(
+            window("{name: nightly, days: [sun], start: '01:00', end: '04:00'}"),
+            &berlin,
+        );
+        let windows = [(&nightly, "ruku.yml")];
+        let log = Logger::new();
+        let error = guard(&log, &windows, "deploy", at("2026-03-29T01:30:00Z")).unwrap_err();
+        assert!(error.starts_with("Refusing to deploy during freeze nightly (set up in ruku.yml) until"));
+        // 02:30 UTC is 04:30 in summer time, in the window by a fixed +01:00
+        assert!(guard(&log, &windows, "deploy", at("2026-03-29T02:30:00Z")).is_ok());
+    }
+
+    #[test]
+    fn back_to_back_windows_end_together() {
+        let evening = window("{name: evening, by: ops, start: '18:00', end: '22:00'}");
+        let night = window("{name: night, start: '22:00', end: '06:00'}");
+        let windows = [(&evening, "~/.ruku/config.yml"), (&night, "ruku.yml")];
+
+        let freeze = active(&windows, at("2026-10-14T19:00:00Z")).unwrap();
+        assert_eq!(freeze.window.name, "evening");
+        assert_eq!(freeze.until, at("2026-10-15T06:00:00Z"));
+        assert_eq!(
+            freeze.window.origin(freeze.source),
+            "set up by ops in ~/.ruku/config.yml"
+        );
+        assert_eq!(night.origin("ruku.yml"), "set up in ruku.yml");
+        assert!(active(&windows, at("2026-10-14T12:00:00Z")).is_none());
+
+        // Always frozen, the end is given up on after a week
+        let always = window("{name: always, start: '00:00', end: '00:00'}");
+        let now = at("2026-10-14T12:00:00Z");
+        let freeze = active(&[(&always, "ruku.yml")], now).unwrap();
+        assert!(freeze.until - now >= Duration::days(7));
+    }
+}
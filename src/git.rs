@@ -1,5 +1,6 @@
 use std::fs::File;
 use std::io::{BufRead, Write};
+#[cfg(unix)]
 use std::os::unix::fs::PermissionsExt;
 use std::path::Path;
 use std::{env, fs, io};
@@ -20,26 +21,20 @@ impl<'a> Git<'a> {
         Self { log, config }
     }
 
-    pub fn cmd_git_receive_pack(&self, app: &str) {
+    pub fn cmd_git_receive_pack(&self, app: &str) -> Result<(), String> {
         let app = sanitize_app_name(app);
         let git_root = self.config.git_root.as_path().to_str().unwrap();
         let hook_path = self.config.git_root.join(&app).join("hooks").join("post-receive");
 
         if !hook_path.exists() {
             self.log.step("Initializing git repository");
-            fs::create_dir_all(hook_path.parent().unwrap()).unwrap_or_else(|e| {
-                self.log.error(&format!("Error creating directory: {}", e));
-                std::process::exit(1);
-            });
+            fs::create_dir_all(hook_path.parent().unwrap()).map_err(|e| format!("Error creating directory: {}", e))?;
 
             run_cmd!(
                 cd $git_root;
                 git init --quiet --bare $app;
             )
-            .unwrap_or_else(|e| {
-                self.log.error(&format!("Error executing git init: {}", e));
-                std::process::exit(1);
-            });
+            .map_err(|e| format!("Error executing git init: {}", e))?;
 
             let hook_content = format!(
                 r#"#!/usr/bin/env bash
@@ -51,47 +46,39 @@ cat | RUKU_ROOT="{}" {} git-hook {}
                 app
             );
 
-            let mut file = File::create(&hook_path).unwrap_or_else(|e| {
-                self.log.error(&format!("Error creating file: {}", e));
-                std::process::exit(1);
-            });
-            file.write_all(hook_content.as_bytes()).unwrap_or_else(|e| {
-                self.log.error(&format!("Error writing to file: {}", e));
-                std::process::exit(1);
-            });
+            let mut file = File::create(&hook_path).map_err(|e| format!("Error creating file: {}", e))?;
+            file.write_all(hook_content.as_bytes())
+                .map_err(|e| format!("Error writing to file: {}", e))?;
 
             // Make the hook executable by our user
-            let mut perms = fs::metadata(&hook_path).unwrap().permissions();
-            perms.set_mode(perms.mode() | 0o100);
-            fs::set_permissions(&hook_path, perms).unwrap_or_else(|e| {
-                self.log.error(&format!("Error setting permissions: {}", e));
-                std::process::exit(1);
-            });
+            #[cfg(unix)]
+            {
+                let mut perms = fs::metadata(&hook_path).unwrap().permissions();
+                perms.set_mode(perms.mode() | 0o100);
+                fs::set_permissions(&hook_path, perms).map_err(|e| format!("Error setting permissions: {}", e))?;
+            }
         }
 
         // Handle the actual receive. We'll be called with 'git-hook' after it happens
-        self.run_git_shell(git_root, format!("git-receive-pack '{}'", app));
+        self.run_git_shell(git_root, format!("git-receive-pack '{}'", app))
     }
 
-    pub fn cmd_git_upload_pack(&self, app: &str) {
+    pub fn cmd_git_upload_pack(&self, app: &str) -> Result<(), String> {
         let app = sanitize_app_name(app);
         let git_root = self.config.git_root.as_path().to_str().unwrap();
 
-        self.run_git_shell(git_root, format!("git-upload-pack '{}'", app));
+        self.run_git_shell(git_root, format!("git-upload-pack '{}'", app))
     }
 
-    fn run_git_shell(&self, git_root: &str, git_command: String) {
+    fn run_git_shell(&self, git_root: &str, git_command: String) -> Result<(), String> {
         run_cmd!(
             cd $git_root;
             git-shell -c "$git_command";
         )
-        .unwrap_or_else(|e| {
-            self.log.error(&format!("Error executing git shell: {}", e));
-            std::process::exit(1);
-        });
+        .map_err(|e| format!("Error executing git shell: {}", e))
     }
 
-    pub fn cmd_git_hook(&self, app: &str) {
+    pub fn cmd_git_hook(&self, app: &str) -> Result<(), String> {
         let app = sanitize_app_name(app);
 
         let repo_path = self.config.git_root.join(&app);
@@ -108,29 +95,22 @@ cat | RUKU_ROOT="{}" {} git-hook {}
             let (_, new_rev, branch) = (parts[0], parts[1], parts[2]);
 
             if !app_path.exists() {
-                fs::create_dir_all(&app_path).unwrap_or_else(|e| {
-                    self.log.error(&format!("Error creating directory: {}", e));
-                    std::process::exit(1);
-                });
+                fs::create_dir_all(&app_path).map_err(|e| format!("Error creating directory: {}", e))?;
 
                 if !data_path.exists() {
-                    fs::create_dir_all(&data_path).unwrap_or_else(|e| {
-                        self.log.error(&format!("Error creating directory: {}", e));
-                        std::process::exit(1);
-                    });
+                    fs::create_dir_all(&data_path).map_err(|e| format!("Error creating directory: {}", e))?;
                 }
 
                 self.log.step("Cloning git repository");
-                run_cmd!(git clone --quiet --no-checkout $repo_path $app_path).unwrap_or_else(|e| {
-                    self.log.error(&format!("Error cloning git repo: {}", e));
-                    std::process::exit(1);
-                });
+                run_cmd!(git clone --quiet --no-checkout $repo_path $app_path)
+                    .map_err(|e| format!("Error cloning git repo: {}", e))?;
             }
 
-            self.checkout_latest(&app_path, new_rev, branch);
+            self.checkout_latest(&app_path, new_rev, branch)?;
         }
+        Ok(())
     }
-    fn checkout_latest(&self, app_path: &Path, new_rev: &str, branch: &str) {
+    fn checkout_latest(&self, app_path: &Path, new_rev: &str, branch: &str) -> Result<(), String> {
         unsafe {
             env::set_var("GIT_DIR", app_path.join(".git").display().to_string());
             env::set_var("GIT_WORK_TREE", app_path.display().to_string());
@@ -141,17 +121,12 @@ cat | RUKU_ROOT="{}" {} git-hook {}
             .step(&format!("Checking out the latest code from branch: {}", branch));
 
         // Get the current branch
-        let current_branch = run_fun!(git rev-parse --abbrev-ref HEAD).unwrap_or_else(|e| {
-            self.log.error(&format!("Error getting current branch: {}", e));
-            std::process::exit(1);
-        });
+        let current_branch =
+            run_fun!(git rev-parse --abbrev-ref HEAD).map_err(|e| format!("Error getting current branch: {}", e))?;
 
         // Check if the current branch is the same as the target branch
         if current_branch.trim() != branch {
-            run_cmd!(git checkout $branch).unwrap_or_else(|e| {
-                self.log.error(&format!("Error checking out latest code: {}", e));
-                std::process::exit(1);
-            });
+            run_cmd!(git checkout $branch).map_err(|e| format!("Error checking out latest code: {}", e))?;
         }
 
         // Checkout the latest code
@@ -159,9 +134,6 @@ cat | RUKU_ROOT="{}" {} git-hook {}
             git fetch --quiet;
             git reset --hard $new_rev;
         )
-        .unwrap_or_else(|e| {
-            self.log.error(&format!("Error checking out latest code: {}", e));
-            std::process::exit(1);
-        });
+        .map_err(|e| format!("Error checking out latest code: {}", e))
     }
 }
@@ -0,0 +1,261 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use bollard::models::{ContainerStateStatusEnum, HealthStatusEnum};
+use bollard::Docker;
+use serde::Serialize;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::container::{Condition, Container};
+use crate::logger::Logger;
+use crate::metrics;
+use crate::overview::app_rows;
+use crate::server_config::{ServeConfig, ServerConfig};
+
+/// How long the health of an app is answered from the cache before its container is inspected again, so
+/// a polling load balancer doesn't hit the Docker socket on every request.
+const CACHE_TTL: Duration = Duration::from_secs(5);
+/// Longest request head read, the endpoints take no body.
+const MAX_REQUEST: usize = 8192;
+/// Time a client has to send its request.
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How the stable container of an app is doing, as `/apps/healthz` reports it.
+#[derive(Debug, Clone, Serialize)]
+pub struct AppHealth {
+    pub app: String,
+    pub container: String,
+    /// The container state, e.g. `running` or `exited`.
+    pub state: String,
+    /// The healthcheck status, none without a healthcheck.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub health: Option<String>,
+    /// Running, and healthy when it has a healthcheck.
+    pub serving: bool,
+    /// The condition as `status` words it, e.g. `container unhealthy for 6m, 14 restarts`.
+    pub detail: String,
+}
+
+#[derive(Debug, Serialize)]
+struct AppsHealth<'a> {
+    serving: bool,
+    apps: &'a [AppHealth],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// The HTTP endpoints of `ruku server`: `/healthz` answers while the server is up, `/apps/healthz`
+/// with the health of every app, 503 when one isn't serving, and `/metrics` with the deploy metrics in
+/// the Prometheus text format.
+pub struct HealthServer {
+    docker: Docker,
+    server_config: ServerConfig,
+    cache: Mutex<HashMap<String, (Instant, AppHealth)>>,
+}
+
+impl HealthServer {
+    pub fn new(docker: &Docker, server_config: ServerConfig) -> HealthServer {
+        HealthServer {
+            docker: docker.clone(),
+            server_config,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Listen on `listen` and answer requests until the process is stopped.
+    pub async fn run(self, log: &Logger, listen: &str) -> Result<(), String> {
+        let settings = &self.server_config.server;
+        if settings.token.is_none() && !settings.healthz_public {
+            return Err(
+                "Set server.token or server.healthz_public in ~/.ruku/config.yml, nothing could be answered"
+                    .to_string(),
+            );
+        }
+        let listener = TcpListener::bind(listen)
+            .await
+            .map_err(|e| format!("Failed to listen on {}: {}", listen, e))?;
+        log.step(&format!("Listening on http://{}", listen));
+        let server = Arc::new(self);
+        loop {
+            let (stream, _) = match listener.accept().await {
+                Ok(connection) => connection,
+                Err(e) => {
+                    log.warn(&format!("Failed to accept a connection: {}", e));
+                    continue;
+                }
+            };
+            let server = server.clone();
+            tokio::spawn(async move { server.serve(stream).await });
+        }
+    }
+
+    async fn serve(&self, mut stream: TcpStream) {
+        let Ok(Some(head)) = tokio::time::timeout(READ_TIMEOUT, read_head(&mut stream)).await else {
+            return;
+        };
+        let (status, body) = self.answer(&head).await;
+        let content_type = match body.starts_with('{') {
+            true => "application/json",
+            false => "text/plain; version=0.0.4",
+        };
+        let response = format!(
+            "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            content_type,
+            body.len(),
+            body
+        );
+        let _ = stream.write_all(response.as_bytes()).await;
+        let _ = stream.shutdown().await;
+    }
+
+    /// The status line and body for the request with `head`, JSON but for `/metrics`.
+    async fn answer(&self, head: &str) -> (&'static str, String) {
+        let mut request_line = head.lines().next().unwrap_or_default().split_whitespace();
+        let method = request_line.next().unwrap_or_default();
+        let path = request_line.next().unwrap_or_default();
+        let path = path.split_once('?').map_or(path, |(path, _)| path);
+        let healthz = path == "/healthz" || path == "/apps/healthz";
+        let public = healthz && self.server_config.server.healthz_public;
+        if !public && !is_authorized(&self.server_config.server, head) {
+            return ("401 Unauthorized", error_body("missing or wrong token"));
+        }
+        if !healthz && path != "/metrics" {
+            return ("404 Not Found", error_body("not found"));
+        }
+        if method != "GET" {
+            return ("405 Method Not Allowed", error_body("only GET is answered"));
+        }
+        if path == "/metrics" {
+            return match Container::try_list_all(&self.docker).await {
+                Ok(live) => {
+                    let state_root = &self.server_config.state_root;
+                    let apps = metrics::collect(state_root, metrics::recorded_apps(state_root), &live);
+                    ("200 OK", metrics::render(&apps))
+                }
+                Err(e) => (
+                    "503 Service Unavailable",
+                    error_body(&format!("failed to list the containers: {}", e)),
+                ),
+            };
+        }
+        if path == "/healthz" {
+            return ("200 OK", "{\"status\":\"ok\"}".to_string());
+        }
+        let (apps, error) = match self.apps_health().await {
+            Ok(apps) => (apps, None),
+            Err(e) => (vec![], Some(e)),
+        };
+        let serving = error.is_none() && apps.iter().all(|app| app.serving);
+        let body = serde_json::to_string(&AppsHealth {
+            serving,
+            apps: &apps,
+            error,
+        })
+        .unwrap();
+        match serving {
+            true => ("200 OK", body),
+            false => ("503 Service Unavailable", body),
+        }
+    }
+
+    /// The health of every app with a stable container, one briefly cached inspect each.
+    pub async fn apps_health(&self) -> Result<Vec<AppHealth>, String> {
+        let summaries = Container::try_list_all(&self.docker)
+            .await
+            .map_err(|e| format!("failed to list the containers: {}", e))?;
+        let stable: Vec<_> = app_rows(&summaries, &self.server_config)
+            .into_iter()
+            .filter(|row| row.is_stable())
+            .collect();
+        let mut apps = vec![];
+        for row in stable {
+            apps.push(self.app_health(&row.app, &row.container).await);
+        }
+        apps.sort_by(|a, b| a.app.cmp(&b.app));
+        Ok(apps)
+    }
+
+    async fn app_health(&self, app: &str, container: &str) -> AppHealth {
+        if let Some((checked, health)) = self.cache.lock().unwrap().get(app) {
+            if checked.elapsed() < CACHE_TTL && health.container == container {
+                return health.clone();
+            }
+        }
+        let health = match self.docker.inspect_container(container, None).await {
+            Ok(inspect) => {
+                let condition = Condition::from_inspect(&inspect);
+                let healthy = matches!(
+                    condition.health,
+                    None | Some(HealthStatusEnum::HEALTHY)
+                        | Some(HealthStatusEnum::NONE)
+                        | Some(HealthStatusEnum::EMPTY)
+                );
+                AppHealth {
+                    app: app.to_string(),
+                    container: inspect
+                        .name
+                        .map(|name| name.trim_start_matches('/').to_string())
+                        .unwrap_or(container.to_string()),
+                    state: condition.state.to_string(),
+                    health: condition
+                        .health
+                        .filter(|health| *health != HealthStatusEnum::NONE && *health != HealthStatusEnum::EMPTY)
+                        .map(|health| health.to_string()),
+                    serving: condition.state == ContainerStateStatusEnum::RUNNING && healthy,
+                    detail: condition.describe(),
+                }
+            }
+            Err(e) => AppHealth {
+                app: app.to_string(),
+                container: container.to_string(),
+                state: "unknown".to_string(),
+                health: None,
+                serving: false,
+                detail: format!("failed to inspect the container: {}", e),
+            },
+        };
+        self.cache
+            .lock()
+            .unwrap()
+            .insert(app.to_string(), (Instant::now(), health.clone()));
+        health
+    }
+}
+
+/// Read the request line and headers, none when the client closes early or sends more than fits.
+async fn read_head(stream: &mut TcpStream) -> Option<String> {
+    let mut head = vec![];
+    let mut buffer = [0; 1024];
+    while !head.windows(4).any(|window| window == b"\r\n\r\n") {
+        let read = stream.read(&mut buffer).await.ok()?;
+        if read == 0 || head.len() + read > MAX_REQUEST {
+            return None;
+        }
+        head.extend_from_slice(&buffer[..read]);
+    }
+    Some(String::from_utf8_lossy(&head).to_string())
+}
+
+/// Whether the request sends the configured token, compared in constant time.
+fn is_authorized(settings: &ServeConfig, head: &str) -> bool {
+    let Some(token) = &settings.token else {
+        return false;
+    };
+    let sent = head.lines().skip(1).find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        let value = value.trim();
+        name.eq_ignore_ascii_case("authorization")
+            .then(|| value.strip_prefix("Bearer ").unwrap_or_default().trim().to_string())
+    });
+    let Some(sent) = sent else {
+        return false;
+    };
+    sent.len() == token.len() && sent.bytes().zip(token.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+fn error_body(message: &str) -> String {
+    serde_json::json!({ "error": message }).to_string()
+}
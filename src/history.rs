@@ -0,0 +1,120 @@
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::connection::active_context;
+use crate::logger::Logger;
+use crate::model::DeployStrategy;
+use crate::scan::ScanSummary;
+use crate::smoke::SmokeResult;
+use crate::store::{self, Document};
+use crate::version;
+
+/// A single successful deployment of an app.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Deployment {
+    pub id: String,
+    pub version: Option<String>,
+    pub image: String,
+    /// Local id of the image, which stays valid when the tag is moved or removed.
+    #[serde(default)]
+    pub image_id: Option<String>,
+    /// Registry digest of the image when it was pushed during the deploy.
+    #[serde(default)]
+    pub digest: Option<String>,
+    /// Vulnerability counts of the image when it was scanned during the deploy.
+    #[serde(default)]
+    pub scan: Option<ScanSummary>,
+    /// Smoke checks the new container passed.
+    #[serde(default)]
+    pub smoke: Vec<SmokeResult>,
+    /// How the new version replaced the old one, recreate for deployments from before strategies.
+    #[serde(default)]
+    pub strategy: DeployStrategy,
+    /// The note the operator deployed it with, in full.
+    #[serde(default)]
+    pub message: Option<String>,
+    /// The ruku build that deployed it, with its commit and build date.
+    #[serde(default)]
+    pub ruku_version: Option<String>,
+    /// The context it was deployed to, none for the local daemon and deployments from before contexts
+    /// were recorded.
+    #[serde(default)]
+    pub host: Option<String>,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: DateTime<Utc>,
+}
+
+impl Document for Vec<Deployment> {}
+
+/// The id of the deployment started at `started_at`, which failed deploys are kept under too.
+pub fn deployment_id(started_at: DateTime<Utc>) -> String {
+    started_at.format("%Y%m%d%H%M%S").to_string()
+}
+
+impl Deployment {
+    pub fn new(version: &Option<String>, image: &str, started_at: DateTime<Utc>) -> Deployment {
+        Deployment {
+            id: deployment_id(started_at),
+            version: version.clone(),
+            image: image.to_string(),
+            image_id: None,
+            digest: None,
+            scan: None,
+            smoke: vec![],
+            strategy: DeployStrategy::default(),
+            message: None,
+            ruku_version: Some(version::LONG_VERSION.to_string()),
+            host: active_context().map(|(name, _)| name.clone()),
+            started_at,
+            finished_at: Utc::now(),
+        }
+    }
+}
+
+/// Deployment history of an app, stored as JSON in the app state directory.
+pub struct History<'a> {
+    log: &'a Logger,
+    path: PathBuf,
+}
+
+impl<'a> History<'a> {
+    pub const FILE_NAME: &'static str = "history.json";
+
+    pub fn new(log: &'a Logger, state_dir: &Path) -> History<'a> {
+        History {
+            log,
+            path: state_dir.join(Self::FILE_NAME),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The recorded deployments, none when the history is missing or was unreadable and moved aside.
+    pub fn load(&self) -> Result<Vec<Deployment>, String> {
+        Ok(store::load::<Vec<Deployment>>(self.log, &self.path)?.unwrap_or_default())
+    }
+
+    /// The recorded deployments to the context in use, with those that were not recorded with one.
+    pub fn load_here(&self) -> Result<Vec<Deployment>, String> {
+        let here = active_context().map(|(name, _)| name);
+        Ok(self
+            .load()?
+            .into_iter()
+            .filter(|deployment| deployment.host.is_none() || deployment.host.as_ref() == here)
+            .collect())
+    }
+
+    pub fn record(&self, deployment: Deployment) -> Result<(), String> {
+        let mut deployments = self.load()?;
+        deployments.push(deployment);
+        self.save(&deployments)
+    }
+
+    pub fn save(&self, deployments: &[Deployment]) -> Result<(), String> {
+        store::save(&self.path, &deployments.to_vec()).map_err(|e| format!("Error writing deployment history: {}", e))
+    }
+}
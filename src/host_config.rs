@@ -0,0 +1,174 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::model::{PortRange, RukuConfig};
+
+/// Where the host config is looked for, the first file that exists is used.
+const SYSTEM_DIR: &str = "/etc/ruku";
+const USER_DIR: &str = ".config/ruku";
+
+#[derive(Deserialize, Default)]
+struct HostConfigFile {
+    #[serde(default)]
+    port_ranges: BTreeMap<String, PortRange>,
+    #[serde(default)]
+    apps: BTreeMap<String, String>,
+}
+
+/// A named range of host ports, reserved for the apps of a team or an environment.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Reservation {
+    pub name: String,
+    pub range: PortRange,
+}
+
+/// Restrictions the host puts on every app, which ruku.yml can name but not lift. Read from
+/// `/etc/ruku/host.yml`, else `~/.config/ruku/host.yml`:
+///
+/// ```yaml
+/// port_ranges:
+///   team-a: 21000-21999
+///   staging: 22000-22999
+/// apps:
+///   api: team-a
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct HostConfig {
+    /// The file the config was read from, none when no file exists.
+    pub path: Option<PathBuf>,
+    /// Host ports reserved by name, no other app may publish on them.
+    pub port_ranges: BTreeMap<String, PortRange>,
+    /// The range each app is held to, whatever its ruku.yml names.
+    pub apps: BTreeMap<String, String>,
+}
+
+impl HostConfig {
+    pub const FILE_NAME: &'static str = "host.yml";
+
+    /// The files searched for the host config, in order.
+    pub fn search_paths(home: Option<&Path>) -> Vec<PathBuf> {
+        let mut paths = vec![Path::new(SYSTEM_DIR).join(Self::FILE_NAME)];
+        paths.extend(home.map(|home| home.join(USER_DIR).join(Self::FILE_NAME)));
+        paths
+    }
+
+    /// Read the first host config of `paths` that exists, an empty one when none does.
+    pub fn load(paths: &[PathBuf]) -> Result<HostConfig, String> {
+        let Some(path) = paths.iter().find(|path| path.is_file()) else {
+            return Ok(HostConfig::default());
+        };
+        let content = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let file: HostConfigFile = serde_yaml::from_str(&content).map_err(|e| format!("{}: {}", path.display(), e))?;
+
+        let ranges: Vec<(&String, &PortRange)> = file.port_ranges.iter().collect();
+        for (i, (name, range)) in ranges.iter().enumerate() {
+            if range.start > range.end {
+                return Err(format!("{}: port range {} runs backwards", path.display(), name));
+            }
+            if let Some((other, _)) = ranges[i + 1..]
+                .iter()
+                .find(|(_, other)| other.start <= range.end && range.start <= other.end)
+            {
+                return Err(format!(
+                    "{}: port ranges {} and {} overlap",
+                    path.display(),
+                    name,
+                    other
+                ));
+            }
+        }
+        if let Some((app, name)) = file.apps.iter().find(|(_, name)| !file.port_ranges.contains_key(*name)) {
+            return Err(format!(
+                "{}: app {} is given port range {} which is not defined",
+                path.display(),
+                app,
+                name
+            ));
+        }
+        Ok(HostConfig {
+            path: Some(path.clone()),
+            port_ranges: file.port_ranges,
+            apps: file.apps,
+        })
+    }
+
+    /// The reservation the ports of `app` must stay in: the one the host config gives it, or else the one
+    /// its ruku.yml names. None leaves it every port that is not reserved.
+    pub fn reservation(&self, app: &str, config: &RukuConfig) -> Result<Option<Reservation>, String> {
+        let named = config.port_reservation.as_deref();
+        let name = match (self.apps.get(app), named) {
+            (Some(assigned), Some(named)) if assigned != named => {
+                return Err(format!(
+                    "{} is held to port range {} in {}, ruku.yml can't switch it to {}",
+                    app,
+                    assigned,
+                    self.describe(),
+                    named
+                ));
+            }
+            (Some(assigned), _) => assigned.as_str(),
+            (None, Some(named)) => named,
+            (None, None) => return Ok(None),
+        };
+        let range = self.port_ranges.get(name).ok_or_else(|| {
+            format!(
+                "port_reservation {} is not a port range defined in {}",
+                name,
+                self.describe()
+            )
+        })?;
+        Ok(Some(Reservation {
+            name: name.to_string(),
+            range: *range,
+        }))
+    }
+
+    /// Whether an app held to `reservation` may publish on host `port`.
+    pub fn check_port(&self, reservation: Option<&Reservation>, port: u16) -> Result<(), String> {
+        match reservation {
+            Some(reservation) if !reservation.range.contains(port) => Err(format!(
+                "Host port {} is outside the range {} reserved for {} in {}",
+                port,
+                reservation.range,
+                reservation.name,
+                self.describe()
+            )),
+            Some(_) => Ok(()),
+            None => match self.reserved_by(port) {
+                Some((name, range)) => Err(format!(
+                    "Host port {} is in the range {} reserved for {} in {}, set port_reservation: {} to use it",
+                    port,
+                    range,
+                    name,
+                    self.describe(),
+                    name
+                )),
+                None => Ok(()),
+            },
+        }
+    }
+
+    /// The named range `port` is in, none when it is not reserved.
+    pub fn reserved_by(&self, port: u16) -> Option<(&str, &PortRange)> {
+        self.port_ranges
+            .iter()
+            .find(|(_, range)| range.contains(port))
+            .map(|(name, range)| (name.as_str(), range))
+    }
+
+    /// The file the config came from, for messages.
+    pub fn describe(&self) -> String {
+        match &self.path {
+            Some(path) => path.display().to_string(),
+            None => format!(
+                "{}, which is in neither {} nor ~/{}",
+                Self::FILE_NAME,
+                SYSTEM_DIR,
+                USER_DIR
+            ),
+        }
+    }
+}
@@ -0,0 +1,354 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use bollard::errors::Error;
+use bollard::image::{
+    CreateImageOptions, ImportImageOptions, ListImagesOptions, PushImageOptions, RemoveImageOptions, TagImageOptions,
+};
+use bollard::Docker;
+use futures_util::{future, StreamExt, TryStreamExt};
+use tokio_util::codec::{BytesCodec, FramedRead};
+
+use crate::daemon_network::{mirrored, DaemonNetwork};
+use crate::logger::Logger;
+use crate::misc::describe_missing_tag;
+use crate::registry::{get_credentials, list_tags, split_registry};
+
+/// What an image declares for the settings ruku.yml may leave out.
+#[derive(Debug, Clone, Default)]
+pub struct ImageDefaults {
+    /// TCP ports of the `EXPOSE` instructions, ascending.
+    pub exposed_ports: Vec<u16>,
+    /// Whether the image has a `HEALTHCHECK` that is not `NONE`.
+    pub has_healthcheck: bool,
+    /// The `USER` the image runs as, none for root.
+    pub user: Option<String>,
+}
+
+/// Operations on images in the local Docker store.
+pub struct Image<'a> {
+    log: &'a Logger,
+    docker: &'a Docker,
+    mirror: Option<String>,
+}
+
+impl<'a> Image<'a> {
+    pub fn new(log: &'a Logger, docker: &'a Docker) -> Image<'a> {
+        Image {
+            log,
+            docker,
+            mirror: None,
+        }
+    }
+
+    /// Pull Docker Hub images through `mirror`, the `registry_mirror` of ruku.yml, instead of the mirrors
+    /// of the daemon.
+    pub fn with_mirror(mut self, mirror: Option<&str>) -> Image<'a> {
+        self.mirror = mirror.map(str::to_string);
+        self
+    }
+
+    pub async fn pull(&self, image_name: &str) -> Result<(), String> {
+        let source = match &self.mirror {
+            Some(mirror) => mirrored(image_name, mirror),
+            None => image_name.to_string(),
+        };
+        match source == image_name {
+            true => self.log.step(&format!("Pulling image {}", image_name)),
+            false => self.log.step(&format!("Pulling image {} as {}", image_name, source)),
+        }
+
+        let options = Some(CreateImageOptions {
+            from_image: source.as_str(),
+            ..Default::default()
+        });
+        // Docker Hub images go without, the credentials are the ones of the push registry
+        let credentials = split_registry(&source).and_then(|(host, _)| get_credentials(host));
+        let mut stream = self.docker.create_image(options, None, credentials);
+        let mut result = Ok(());
+        while let Some(info) = stream.next().await {
+            let info = match info {
+                Ok(info) => info,
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            };
+            // Lines without a layer, e.g. `Pulling from library/nginx` or the digest, are no progress
+            if let (Some(layer), Some(status)) = (&info.id, &info.status) {
+                let detail = info.progress_detail.as_ref();
+                let bytes = |value: Option<i64>| value.and_then(|value| u64::try_from(value).ok());
+                self.log.pull_progress(
+                    image_name,
+                    layer,
+                    status,
+                    bytes(detail.and_then(|detail| detail.current)),
+                    bytes(detail.and_then(|detail| detail.total)),
+                );
+            }
+        }
+        match result {
+            // Tagged back, so the containers find it under the name ruku.yml gives
+            Ok(_) if source != image_name => self.tag(&source, image_name).await,
+            Ok(_) => Ok(()),
+            Err(e) if is_not_found(&e) => {
+                let reason = self.describe_missing(image_name).await;
+                Err(format!("Error pulling image {}: {}", image_name, reason))
+            }
+            Err(e) => {
+                let network = match DaemonNetwork::remembered() {
+                    Some(network) => Some(network.clone()),
+                    None => DaemonNetwork::read(self.docker).await,
+                };
+                match network.and_then(|network| network.pull_hint(&e.to_string())) {
+                    Some(hint) => Err(format!("Error pulling image {}: {}. {}", source, e, hint)),
+                    None => Err(format!("Error pulling image {}: {}", source, e)),
+                }
+            }
+        }
+    }
+
+    /// Tags of `repository` in the local store.
+    pub async fn local_tags(&self, repository: &str) -> Vec<String> {
+        let options = ListImagesOptions {
+            filters: HashMap::from([("reference", vec![repository])]),
+            ..Default::default()
+        };
+        self.docker
+            .list_images(Some(options))
+            .await
+            .unwrap_or_default()
+            .into_iter()
+            .flat_map(|image| image.repo_tags)
+            .filter_map(|reference| {
+                let (repo, tag) = reference.rsplit_once(':')?;
+                (repo == repository).then(|| tag.to_string())
+            })
+            .collect()
+    }
+
+    /// Why `image_name` can't be found, with the closest tags of its repository locally and, when the
+    /// repository lives in a registry that lets ruku list it, remotely.
+    pub async fn describe_missing(&self, image_name: &str) -> String {
+        let (repository, tag) = image_name.rsplit_once(':').unwrap_or((image_name, "latest"));
+        let mut tags = self.local_tags(repository).await;
+        if split_registry(repository).is_some() {
+            match list_tags(repository) {
+                Ok(remote) => tags.extend(remote),
+                Err(e) => self.log.warn(&format!(
+                    "Could not list the tags of {} in the registry: {}",
+                    repository, e
+                )),
+            }
+        }
+        describe_missing_tag(tag, &tags)
+    }
+
+    /// Load a `docker save` or OCI tarball into the store, streamed from disk. Returns the references the
+    /// daemon reported loading, tags or the image id of an image without one.
+    pub async fn load(&self, image_file: &Path) -> Result<Vec<String>, String> {
+        let file = tokio::fs::File::open(image_file)
+            .await
+            .map_err(|e| format!("Error opening image file: {}", e))?;
+        // The body can't fail, a read error ends it early and is reported instead of what the daemon made
+        // of the truncated tarball
+        let read_error = Arc::new(Mutex::new(None));
+        let failed = read_error.clone();
+        let body = FramedRead::new(file, BytesCodec::new()).scan((), move |_, chunk| {
+            future::ready(match chunk {
+                Ok(chunk) => Some(chunk.freeze()),
+                Err(e) => {
+                    *failed.lock().unwrap() = Some(e);
+                    None
+                }
+            })
+        });
+
+        let loaded = self
+            .docker
+            .import_image_stream(ImportImageOptions { quiet: true }, body, None)
+            .try_collect::<Vec<_>>()
+            .await;
+        if let Some(e) = read_error.lock().unwrap().take() {
+            return Err(format!("Error reading image file {}: {}", image_file.display(), e));
+        }
+        Ok(loaded
+            .map_err(|e| format!("Error loading image: {}", e))?
+            .into_iter()
+            .filter_map(|info| info.stream)
+            .flat_map(|stream| {
+                stream
+                    .lines()
+                    .filter_map(|line| {
+                        line.strip_prefix("Loaded image: ")
+                            .or_else(|| line.strip_prefix("Loaded image ID: "))
+                    })
+                    .map(|reference| reference.trim().to_string())
+                    .collect::<Vec<_>>()
+            })
+            .collect())
+    }
+
+    /// The id of the image, e.g. `sha256:...`.
+    pub async fn id(&self, image_name: &str) -> Option<String> {
+        self.docker
+            .inspect_image(image_name)
+            .await
+            .ok()
+            .and_then(|image| image.id)
+    }
+
+    /// The exposed ports and healthcheck of the image, none when it is not in the local store.
+    pub async fn defaults(&self, image_name: &str) -> Option<ImageDefaults> {
+        let config = self
+            .docker
+            .inspect_image(image_name)
+            .await
+            .ok()?
+            .config
+            .unwrap_or_default();
+        let mut exposed_ports: Vec<u16> = config
+            .exposed_ports
+            .unwrap_or_default()
+            .keys()
+            .filter_map(|port| match port.split_once('/') {
+                Some((number, "tcp")) => number.parse().ok(),
+                Some(_) => None,
+                None => port.parse().ok(),
+            })
+            .collect();
+        exposed_ports.sort();
+        exposed_ports.dedup();
+        let has_healthcheck = config
+            .healthcheck
+            .and_then(|healthcheck| healthcheck.test)
+            .is_some_and(|test| test.first().is_some_and(|kind| kind != "NONE"));
+        Some(ImageDefaults {
+            exposed_ports,
+            has_healthcheck,
+            user: config.user.filter(|user| !user.is_empty()),
+        })
+    }
+
+    pub async fn exists(&self, image_name: &str) -> bool {
+        self.docker.inspect_image(image_name).await.is_ok()
+    }
+
+    /// Remove the `image_name` tag, the image itself goes once no other tag or container uses it.
+    pub async fn remove(&self, image_name: &str) {
+        match self
+            .docker
+            .remove_image(image_name, None::<RemoveImageOptions>, None)
+            .await
+        {
+            Ok(_) => self.log.step(&format!("Removed image {}", image_name)),
+            Err(Error::DockerResponseServerError { status_code: 404, .. }) => {}
+            Err(e) => self.log.warn(&format!("Failed to remove image {}: {}", image_name, e)),
+        }
+    }
+
+    /// Tag `source` as `target`, where `target` is a full `repo:tag` reference.
+    pub async fn tag(&self, source: &str, target: &str) -> Result<(), String> {
+        let (repo, tag) = target.rsplit_once(':').unwrap_or((target, "latest"));
+        let options = Some(TagImageOptions { repo, tag });
+        self.docker
+            .tag_image(source, options)
+            .await
+            .map_err(|e| format!("Error tagging image {} as {}: {}", source, target, e))
+    }
+
+    /// Tag `image_name` as `registry_image` and push it, returning the digest the registry reported.
+    pub async fn push(&self, image_name: &str, registry_image: &str) -> Result<Option<String>, String> {
+        self.tag(image_name, registry_image).await?;
+        self.log.step(&format!("Pushing image {}", registry_image));
+
+        let (repo, tag) = registry_image.rsplit_once(':').unwrap_or((registry_image, "latest"));
+        let options = Some(PushImageOptions { tag });
+        let mut stream = self.docker.push_image(repo, options, get_credentials(repo));
+
+        let mut digest = None;
+        while let Some(info) = stream.next().await {
+            let info = info.map_err(|e| format!("Error pushing image {}: {}", registry_image, e))?;
+            if let Some(error) = info.error {
+                return Err(format!("Error pushing image {}: {}", registry_image, error));
+            }
+            let Some(status) = info.status else {
+                continue;
+            };
+
+            // Only report the final state of each layer, the transfer updates are too noisy
+            if status.starts_with("Pushed")
+                || status.starts_with("Layer already exists")
+                || status.starts_with("Mounted")
+            {
+                self.log.step(&format!("Layer {}", status.to_lowercase()));
+            } else if let Some((_, rest)) = status.split_once("digest: ") {
+                digest = rest.split_whitespace().next().map(|d| d.to_string());
+            }
+        }
+
+        if let Some(digest) = &digest {
+            self.log
+                .step(&format!("Pushed {} with digest {}", registry_image, digest));
+        }
+        Ok(digest)
+    }
+}
+
+/// Whether a daemon error means the image or tag does not exist.
+pub fn is_not_found(error: &Error) -> bool {
+    match error {
+        Error::DockerResponseServerError { status_code: 404, .. } => true,
+        Error::DockerResponseServerError { message, .. } | Error::DockerStreamError { error: message } => {
+            message.contains("manifest unknown") || message.contains("not found")
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake_daemon::fake_daemon;
+
+    fn server_error(status_code: u16, message: &str) -> Error {
+        Error::DockerResponseServerError {
+            status_code,
+            message: message.to_string(),
+        }
+    }
+
+    #[test]
+    fn missing_images_are_recognised() {
+        assert!(is_not_found(&server_error(404, "pull access denied")));
+        assert!(is_not_found(&server_error(500, "manifest unknown: manifest unknown")));
+        assert!(is_not_found(&Error::DockerStreamError {
+            error: "manifest for shop:1.4.0 not found".to_string()
+        }));
+        assert!(!is_not_found(&server_error(500, "toomanyrequests: rate limit")));
+        assert!(!is_not_found(&Error::DockerStreamError {
+            error: "unauthorized".to_string()
+        }));
+    }
+
+    #[tokio::test]
+    async fn pulls_through_the_mirror_are_tagged_back() {
+        let (docker, requests) = fake_daemon(|_, _| (200, "{}".to_string())).await;
+        let log = Logger::new();
+        let image = Image::new(&log, &docker).with_mirror(Some("https://mirror.example.com"));
+        image.pull("redis:7").await.unwrap();
+        assert_eq!(
+            requests.all(),
+            [
+                "POST /images/create",
+                "POST /images/mirror.example.com/library/redis:7/tag"
+            ]
+        );
+
+        let (docker, requests) = fake_daemon(|_, _| (200, "{}".to_string())).await;
+        let image = Image::new(&log, &docker).with_mirror(Some("https://mirror.example.com"));
+        image.pull("ghcr.io/acme/shop:1.4.0").await.unwrap();
+        assert_eq!(requests.all(), ["POST /images/create"]);
+    }
+}
@@ -0,0 +1,168 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use bollard::container::ListContainersOptions;
+use bollard::Docker;
+
+use crate::container::APP_LABEL;
+use crate::history::Deployment;
+use crate::image::Image;
+use crate::logger::Logger;
+
+/// Deploys back from the newest whose images stay protected, the ones a rollback would go to.
+pub const ROLLBACK_WINDOW: usize = 5;
+
+/// How the image a container runs relates to the tag it was created from.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ImageDrift {
+    /// The tag still points at the image the container runs.
+    Current,
+    /// The tag was pointed at another image since the container was created.
+    TagMoved {
+        tag: String,
+        running: String,
+        tagged: String,
+    },
+    /// The tag is gone, the container runs from an image nothing refers to by name.
+    Untagged { tag: String, running: String },
+}
+
+impl fmt::Display for ImageDrift {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ImageDrift::Current => write!(f, "the tag points at the running image"),
+            ImageDrift::TagMoved { tag, running, tagged } => write!(
+                f,
+                "tag moved since deploy: {} is now {}, the container runs {}",
+                tag,
+                short_id(tagged),
+                short_id(running)
+            ),
+            ImageDrift::Untagged { tag, running } => write!(
+                f,
+                "image untagged/removed: {} no longer exists, the container runs from dangling image {}",
+                tag,
+                short_id(running)
+            ),
+        }
+    }
+}
+
+/// Compare the image id a container runs with the id its tag resolves to now, none when the tag is gone.
+pub fn compare(tag: &str, running: &str, tagged: Option<&str>) -> ImageDrift {
+    match tagged {
+        Some(tagged) if tagged == running => ImageDrift::Current,
+        Some(tagged) => ImageDrift::TagMoved {
+            tag: tag.to_string(),
+            running: running.to_string(),
+            tagged: tagged.to_string(),
+        },
+        None => ImageDrift::Untagged {
+            tag: tag.to_string(),
+            running: running.to_string(),
+        },
+    }
+}
+
+/// How the image of the container `name` relates to its tag, none when it can't be inspected or was
+/// created from an image id rather than a tag.
+pub async fn check(log: &Logger, docker: &Docker, name: &str) -> Option<ImageDrift> {
+    let container = docker.inspect_container(name, None).await.ok()?;
+    let running = container.image?;
+    let tag = container.config?.image?;
+    if tag.starts_with("sha256:") || tag.contains('@') {
+        return None;
+    }
+    let tagged = Image::new(log, docker).id(&tag).await;
+    Some(compare(&tag, &running, tagged.as_deref()))
+}
+
+/// The image ids no cleanup may remove for `app`: those of its containers, running or not, and those of
+/// the deploys in the rollback window, whatever they are tagged as now.
+pub async fn protected_images(docker: &Docker, app: &str, history: &[Deployment]) -> HashSet<String> {
+    let app_filter = format!("{}={}", APP_LABEL, app);
+    let options = ListContainersOptions {
+        all: true,
+        filters: HashMap::from([("label", vec![app_filter.as_str()])]),
+        ..Default::default()
+    };
+    let mut protected: HashSet<String> = docker
+        .list_containers(Some(options))
+        .await
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|summary| summary.image_id)
+        .collect();
+    protected.extend(
+        history
+            .iter()
+            .rev()
+            .take(ROLLBACK_WINDOW)
+            .filter_map(|deployment| deployment.image_id.clone()),
+    );
+    protected
+}
+
+fn short_id(id: &str) -> &str {
+    let id = id.trim_start_matches("sha256:");
+    &id[..id.len().min(12)]
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+
+    use super::*;
+    use crate::fake_daemon::fake_daemon;
+
+    #[test]
+    fn the_tag_is_compared_with_the_running_image() {
+        assert_eq!(
+            compare("shop:1.0", "sha256:aaa", Some("sha256:aaa")),
+            ImageDrift::Current
+        );
+        let moved = compare("shop:1.0", "sha256:aaaaaaaaaaaaaaaa", Some("sha256:bbbbbbbbbbbbbbbb"));
+        assert_eq!(
+            moved.to_string(),
+            "tag moved since deploy: shop:1.0 is now bbbbbbbbbbbb, the container runs aaaaaaaaaaaa"
+        );
+        let untagged = compare("shop:1.0", "sha256:aaaaaaaaaaaaaaaa", None);
+        assert_eq!(
+            untagged.to_string(),
+            "image untagged/removed: shop:1.0 no longer exists, the container runs from dangling image aaaaaaaaaaaa"
+        );
+    }
+
+    #[tokio::test]
+    async fn containers_and_the_rollback_window_are_protected() {
+        let (docker, requests) = fake_daemon(|_, _| {
+            (
+                200,
+                r#"[{"Id": "1", "ImageID": "sha256:running"}, {"Id": "2", "ImageID": "sha256:stopped"}]"#.to_string(),
+            )
+        })
+        .await;
+        let history: Vec<Deployment> = (0..7)
+            .map(|deploy| {
+                let mut deployment = Deployment::new(&Some(format!("1.{}", deploy)), "shop", Utc::now());
+                deployment.image_id = Some(format!("sha256:deploy{}", deploy));
+                deployment
+            })
+            .collect();
+        let mut protected: Vec<String> = protected_images(&docker, "shop", &history).await.into_iter().collect();
+        protected.sort();
+        assert_eq!(
+            protected,
+            [
+                "sha256:deploy2",
+                "sha256:deploy3",
+                "sha256:deploy4",
+                "sha256:deploy5",
+                "sha256:deploy6",
+                "sha256:running",
+                "sha256:stopped"
+            ]
+        );
+        assert_eq!(requests.all(), ["GET /containers/json"]);
+    }
+}
@@ -0,0 +1,159 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
+
+use bollard::Docker;
+use chrono::Utc;
+use flate2::read::GzDecoder;
+
+use crate::api_version::ApiVersion;
+use crate::bundle::LoadedImage;
+use crate::image::Image;
+use crate::logger::Logger;
+use crate::misc::get_image_name_with_version;
+
+/// The first API version whose image load takes OCI archives, Docker 25.
+const OCI_LOAD_API_VERSION: ApiVersion = ApiVersion(1, 44);
+
+/// The layout of an image tarball.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TarballFormat {
+    /// What `docker save` writes, with a `manifest.json` that lists the tags.
+    DockerArchive,
+    /// An OCI image layout with `oci-layout` and `index.json`, as rules_oci and nix2container write it.
+    OciArchive,
+}
+
+impl fmt::Display for TarballFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TarballFormat::DockerArchive => write!(f, "docker-archive"),
+            TarballFormat::OciArchive => write!(f, "oci-archive"),
+        }
+    }
+}
+
+/// The layout of the tarball at `path`, gzipped or not. Only the tar headers are read, the layers are
+/// skipped over. A tarball with both a `manifest.json` and an OCI layout is a docker-archive, the daemon
+/// loads those through the manifest.
+pub fn detect_format(path: &Path) -> Result<TarballFormat, String> {
+    let mut file = BufReader::new(File::open(path).map_err(|e| e.to_string())?);
+    let gzipped = file.fill_buf().map_err(|e| e.to_string())?.starts_with(&[0x1f, 0x8b]);
+    let reader: Box<dyn Read> = if gzipped {
+        Box::new(GzDecoder::new(file))
+    } else {
+        Box::new(file)
+    };
+
+    let mut archive = tar::Archive::new(reader);
+    let (mut oci_layout, mut index) = (false, false);
+    let corrupt = |e: std::io::Error| format!("not a readable tarball ({})", e);
+    for entry in archive.entries().map_err(corrupt)? {
+        let entry = entry.map_err(corrupt)?;
+        let path = entry.path().map_err(corrupt)?.to_string_lossy().to_string();
+        match path.trim_start_matches("./") {
+            "manifest.json" => return Ok(TarballFormat::DockerArchive),
+            "oci-layout" => oci_layout = true,
+            "index.json" => index = true,
+            _ => {}
+        }
+    }
+    if oci_layout && index {
+        return Ok(TarballFormat::OciArchive);
+    }
+    Err(
+        "neither a docker-archive with a manifest.json nor an OCI layout with oci-layout and index.json, \
+         a root file system tarball has to be made an image with `docker import` first"
+            .to_string(),
+    )
+}
+
+/// Loads image tarballs built outside of Docker, e.g. by `nix build` or rules_oci, so `run` deploys them
+/// instead of building.
+pub struct ImageTarball<'a> {
+    log: &'a Logger,
+    docker: &'a Docker,
+}
+
+impl<'a> ImageTarball<'a> {
+    pub fn new(log: &'a Logger, docker: &'a Docker) -> ImageTarball<'a> {
+        ImageTarball { log, docker }
+    }
+
+    /// Load `file` into the daemon, tag the image it holds for `app` at `version` and register it in the
+    /// state directory of the app. The tarball has to hold a single image, under any number of tags.
+    pub async fn load(
+        &self,
+        app: &str,
+        version: &Option<String>,
+        file: &Path,
+        state_dir: &Path,
+    ) -> Result<LoadedImage, String> {
+        let format =
+            detect_format(file).map_err(|e| format!("Error reading image tarball {}: {}", file.display(), e))?;
+        if format == TarballFormat::OciArchive {
+            self.check_oci_support().await?;
+        }
+
+        self.log.step(&format!("Loading {} {}", format, file.display()));
+        let image = Image::new(self.log, self.docker);
+        let references = image.load(file).await?;
+        let mut ids = vec![];
+        for reference in &references {
+            if let Some(id) = image.id(reference).await {
+                if !ids.contains(&id) {
+                    ids.push(id);
+                }
+            }
+        }
+        let digest = match ids.as_slice() {
+            [id] => id.clone(),
+            [] => return Err(format!("The daemon reported no image loaded from {}", file.display())),
+            _ => {
+                return Err(format!(
+                    "{} holds {} images ({}), ruku deploys one",
+                    file.display(),
+                    ids.len(),
+                    references.join(", ")
+                ))
+            }
+        };
+
+        let image_name = get_image_name_with_version(app, version);
+        if !references.contains(&image_name) {
+            self.log.step(&format!("Tagging {} as {}", references[0], image_name));
+            image.tag(&digest, &image_name).await?;
+        }
+
+        let loaded = LoadedImage {
+            image: image_name,
+            version: version.clone(),
+            digest,
+            loaded_at: Utc::now(),
+        };
+        loaded
+            .write(state_dir)
+            .map_err(|e| format!("Error registering image: {}", e))?;
+        Ok(loaded)
+    }
+
+    /// Fail when the daemon is too old to load OCI archives, it would reject the tarball as invalid.
+    async fn check_oci_support(&self) -> Result<(), String> {
+        let api = self
+            .docker
+            .version()
+            .await
+            .ok()
+            .and_then(|version| version.api_version)
+            .and_then(|version| ApiVersion::parse(&version));
+        if let Some(api) = api.filter(|api| *api < OCI_LOAD_API_VERSION) {
+            return Err(format!(
+                "The Docker daemon has API {}, loading oci-archive tarballs needs {} (Docker 25) or newer. \
+                 Build a docker-archive instead, e.g. with `oci_load` of rules_oci or `dockerTools.buildImage` of nix",
+                api, OCI_LOAD_API_VERSION
+            ));
+        }
+        Ok(())
+    }
+}
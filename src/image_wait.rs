@@ -0,0 +1,206 @@
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use bollard::Docker;
+use chrono::Utc;
+
+use crate::bundle::LoadedImage;
+use crate::image::Image;
+use crate::logger::Logger;
+use crate::misc::{get_image_name_with_version, get_registry_image_name};
+use crate::model::RukuConfig;
+use crate::registry::{head_manifest, ManifestHead};
+use crate::units::format_duration;
+
+/// Time between two looks for the image.
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+/// The longest wait after a rate limited request that gave no `Retry-After`.
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// Time between two progress lines while waiting.
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Why a look found nothing to deploy.
+enum LookError {
+    /// The registry is rate limiting, with the wait it asked for if it gave one.
+    RateLimited(Option<Duration>),
+    Failed(String),
+}
+
+/// Whether `digest` looks like a manifest digest, `sha256:` and 64 hex digits.
+pub fn is_digest(digest: &str) -> bool {
+    digest
+        .strip_prefix("sha256:")
+        .is_some_and(|hex| hex.len() == 64 && hex.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+/// Waits for the image of a deploy to be pushed before the deploy starts, for CI that pushes and calls
+/// ruku right after. The image is looked for in the registry of the build config, or in the local store
+/// when there is none, and registered so the deploy uses it instead of building.
+pub struct ImageWait<'a> {
+    log: &'a Logger,
+    docker: &'a Docker,
+    timeout: Duration,
+    digest: Option<String>,
+}
+
+impl<'a> ImageWait<'a> {
+    pub fn new(log: &'a Logger, docker: &'a Docker, timeout: Duration) -> ImageWait<'a> {
+        ImageWait {
+            log,
+            docker,
+            timeout,
+            digest: None,
+        }
+    }
+
+    /// Only take the tag once it points at this manifest digest, a retag to another image keeps waiting.
+    pub fn with_digest(mut self, digest: Option<String>) -> ImageWait<'a> {
+        self.digest = digest;
+        self
+    }
+
+    /// Wait for the image of `app`, failing once the timeout is over. Nothing of the app is touched before
+    /// the image is there.
+    pub async fn run(&self, app: &str, config: &RukuConfig, state_dir: &Path) -> Result<(), String> {
+        let image_name = get_image_name_with_version(app, &config.version);
+        let registry = config.build.as_ref().and_then(|build| build.registry.as_deref());
+        let source = match registry {
+            Some(registry) => get_registry_image_name(registry, app, &config.version),
+            None => image_name.clone(),
+        };
+        let target = match &self.digest {
+            Some(digest) => format!("{} at {}", source, digest),
+            None => source.clone(),
+        };
+        self.log.step(&format!(
+            "Waiting up to {} for {}",
+            format_duration(self.timeout),
+            target
+        ));
+
+        let started = Instant::now();
+        let mut last_progress = Instant::now();
+        let mut backoff = POLL_INTERVAL;
+        loop {
+            let wait = match self.look(registry.is_some(), &source).await {
+                Ok(Some(found)) => {
+                    return self.register(&source, &image_name, found, config, state_dir).await;
+                }
+                Ok(None) => {
+                    backoff = POLL_INTERVAL;
+                    POLL_INTERVAL
+                }
+                Err(LookError::RateLimited(Some(retry_after))) => retry_after,
+                Err(LookError::RateLimited(None)) => {
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                    backoff
+                }
+                Err(LookError::Failed(e)) => return Err(e),
+            };
+            let elapsed = started.elapsed();
+            if elapsed + wait > self.timeout {
+                return Err(format!(
+                    "{} did not show up within {}, the running container was left alone",
+                    target,
+                    format_duration(self.timeout)
+                ));
+            }
+            if last_progress.elapsed() >= PROGRESS_INTERVAL {
+                self.log.step(&format!(
+                    "Still waiting for {}, {} so far",
+                    target,
+                    format_duration(elapsed)
+                ));
+                last_progress = Instant::now();
+            }
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Whether the image is there with the digest asked for, with the digest it was found at.
+    async fn look(&self, in_registry: bool, source: &str) -> Result<Option<String>, LookError> {
+        if !in_registry {
+            let Some(id) = Image::new(self.log, self.docker).id(source).await else {
+                return Ok(None);
+            };
+            let found = self.matches(&id) || self.matches_repo_digest(source).await;
+            return Ok(found.then_some(id));
+        }
+        match head_manifest(source) {
+            Ok(ManifestHead::Found(digest)) => match (&self.digest, digest) {
+                (None, digest) => Ok(Some(digest.unwrap_or_default())),
+                (Some(_), Some(digest)) if self.matches(&digest) => Ok(Some(digest)),
+                (Some(_), Some(digest)) => {
+                    self.log
+                        .warn(&format!("{} points at {}, waiting for the retag", source, digest));
+                    Ok(None)
+                }
+                (Some(_), None) => Err(LookError::Failed(format!(
+                    "The registry sends no digest for {}, it can't be checked against --image-digest",
+                    source
+                ))),
+            },
+            Ok(ManifestHead::Missing) => Ok(None),
+            Ok(ManifestHead::RateLimited(retry_after)) => {
+                self.log.warn("The registry is rate limiting ruku, backing off");
+                Err(LookError::RateLimited(retry_after.map(Duration::from_secs)))
+            }
+            Err(e) => Err(LookError::Failed(format!("Error looking for {}: {}", source, e))),
+        }
+    }
+
+    fn matches(&self, digest: &str) -> bool {
+        self.digest.as_deref().is_none_or(|wanted| wanted == digest)
+    }
+
+    /// Whether a local image was pulled at the digest asked for, its id is the digest of its config.
+    async fn matches_repo_digest(&self, image_name: &str) -> bool {
+        let Some(wanted) = &self.digest else {
+            return true;
+        };
+        let repo_digests = self
+            .docker
+            .inspect_image(image_name)
+            .await
+            .ok()
+            .and_then(|image| image.repo_digests)
+            .unwrap_or_default();
+        repo_digests
+            .iter()
+            .any(|reference| reference.rsplit_once('@').is_some_and(|(_, digest)| digest == wanted))
+    }
+
+    /// Bring the image found into the local store under the tag of the app and register it, a registry
+    /// image is pulled by digest so a retag during the pull makes no difference.
+    async fn register(
+        &self,
+        source: &str,
+        image_name: &str,
+        digest: String,
+        config: &RukuConfig,
+        state_dir: &Path,
+    ) -> Result<(), String> {
+        let image = Image::new(self.log, self.docker);
+        if source != image_name {
+            let repository = source.rsplit_once(':').map_or(source, |(repository, _)| repository);
+            let reference = if digest.is_empty() {
+                source.to_string()
+            } else {
+                format!("{}@{}", repository, digest)
+            };
+            image.pull(&reference).await?;
+            image.tag(&reference, image_name).await?;
+        }
+        let loaded = LoadedImage {
+            image: image_name.to_string(),
+            version: config.version.clone(),
+            digest: image.id(image_name).await.unwrap_or(digest),
+            loaded_at: Utc::now(),
+        };
+        loaded
+            .write(state_dir)
+            .map_err(|e| format!("Error registering image: {}", e))?;
+        self.log.step(&format!("Found {}, deploying it", source));
+        Ok(())
+    }
+}
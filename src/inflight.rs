@@ -0,0 +1,226 @@
+use std::fs::{self, File, OpenOptions};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::logger::Logger;
+use crate::server_config::ServerConfig;
+use crate::store;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// How often a waiting process reports that it still waits.
+const REPORT_INTERVAL: Duration = Duration::from_secs(30);
+/// Finished entries older than this are removed, nothing waits on them anymore.
+const RETENTION: chrono::Duration = chrono::Duration::days(1);
+
+/// A build or pull of an image by some ruku process, as written to `<image>.json`.
+#[derive(Debug, Serialize, Deserialize)]
+struct InFlightRecord {
+    image: String,
+    /// `build` or `pull`.
+    kind: String,
+    pid: u32,
+    started_at: DateTime<Utc>,
+    /// Set once the image is in place, an entry without it and without a holder of its lock was left by
+    /// a process that died.
+    finished_at: Option<DateTime<Utc>>,
+}
+
+/// The right to build or pull an image, held until it is [finished](Lead::finish) or dropped. Dropped
+/// without finishing, other processes take the work over.
+pub struct Lead {
+    _file: Option<File>,
+    record: Option<(PathBuf, InFlightRecord)>,
+}
+
+impl Lead {
+    /// Record that the image is in place, for the processes that wait on it.
+    pub fn finish(mut self) {
+        if let Some((path, mut record)) = self.record.take() {
+            record.finished_at = Some(Utc::now());
+            if let Ok(content) = serde_json::to_string_pretty(&record) {
+                let _ = store::write_atomic(&path, content.as_bytes());
+            }
+        }
+    }
+}
+
+/// Whose turn it is to produce an image.
+pub enum Turn {
+    /// This process builds or pulls it.
+    Run(Lead),
+    /// The process with this pid produced it since this one started, it is used as it is.
+    Reuse(u32),
+}
+
+/// Builds and pulls in progress across every ruku process on the host, kept in
+/// `<ruku_root>/inflight`. The first process to reach an image does the work, later ones wait for it
+/// and use its result rather than doing the same work again.
+pub struct InFlight<'a> {
+    log: &'a Logger,
+    dir: PathBuf,
+    share: bool,
+    /// Results from before this are not reused, the image may be rebuilt on purpose.
+    since: DateTime<Utc>,
+    max_wait: Duration,
+}
+
+impl<'a> InFlight<'a> {
+    pub const DIR_NAME: &'static str = "inflight";
+
+    pub fn new(log: &'a Logger, server_config: &ServerConfig) -> InFlight<'a> {
+        InFlight {
+            log,
+            dir: server_config.ruku_root.join(Self::DIR_NAME),
+            share: true,
+            since: Utc::now(),
+            max_wait: Duration::from_secs(server_config.deploy_slot_timeout),
+        }
+    }
+
+    /// Always do the work here, without waiting on or telling other processes.
+    pub fn with_share(mut self, share: bool) -> InFlight<'a> {
+        self.share = share;
+        self
+    }
+
+    /// Wait while another process builds or pulls `image`, `kind` naming which for the messages. The
+    /// work is left to this process when no one else is at it, when the one that was died, or when it
+    /// takes longer than a deploy may wait for a slot.
+    pub async fn turn(&self, image: &str, kind: &str) -> Turn {
+        if !self.share {
+            return Turn::Run(Lead {
+                _file: None,
+                record: None,
+            });
+        }
+        if let Err(e) = fs::create_dir_all(&self.dir) {
+            self.log
+                .warn(&format!("Not sharing the {}, error creating directory: {}", kind, e));
+            return Turn::Run(Lead {
+                _file: None,
+                record: None,
+            });
+        }
+        self.expire();
+
+        let key = key(image);
+        let lock_path = self.dir.join(format!("{}.lock", key));
+        let record_path = self.dir.join(format!("{}.json", key));
+        let mut waited = Duration::ZERO;
+        loop {
+            // Read once the lock is held, the process that held it writes its result before letting go
+            if let Some(file) = lock(&lock_path) {
+                match read(&record_path) {
+                    Some(record)
+                        if record.image == image
+                            && record.finished_at.is_some_and(|finished| finished >= self.since) =>
+                    {
+                        return Turn::Reuse(record.pid);
+                    }
+                    Some(record) if record.finished_at.is_none() && waited > Duration::ZERO => {
+                        self.log.warn(&format!(
+                            "The {} of {} by pid {} did not finish, doing it here",
+                            kind, image, record.pid
+                        ));
+                    }
+                    _ => {}
+                }
+                let record = InFlightRecord {
+                    image: image.to_string(),
+                    kind: kind.to_string(),
+                    pid: std::process::id(),
+                    started_at: Utc::now(),
+                    finished_at: None,
+                };
+                if let Ok(content) = serde_json::to_string_pretty(&record) {
+                    let _ = store::write_atomic(&record_path, content.as_bytes());
+                }
+                return Turn::Run(Lead {
+                    _file: Some(file),
+                    record: Some((record_path, record)),
+                });
+            }
+
+            if waited >= self.max_wait {
+                self.log.warn(&format!(
+                    "Gave up waiting after {}s for the {} of {}, doing it here",
+                    waited.as_secs(),
+                    kind,
+                    image
+                ));
+                return Turn::Run(Lead {
+                    _file: None,
+                    record: None,
+                });
+            }
+            if waited.as_secs().is_multiple_of(REPORT_INTERVAL.as_secs()) {
+                let pid =
+                    read(&record_path).map_or("another process".to_string(), |record| format!("pid {}", record.pid));
+                self.log
+                    .step(&format!("Waiting for in-progress {} of {} by {}", kind, image, pid));
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+            waited += POLL_INTERVAL;
+        }
+    }
+
+    /// Remove the entries nobody holds that finished long ago or were left by a process that died.
+    fn expire(&self) {
+        for entry in fs::read_dir(&self.dir).into_iter().flatten().flatten() {
+            let path = entry.path();
+            if path.extension().is_none_or(|extension| extension != "json") {
+                continue;
+            }
+            let lock_path = path.with_extension("lock");
+            let Some(file) = lock(&lock_path) else {
+                continue;
+            };
+            match read(&path).and_then(|record| record.finished_at) {
+                Some(finished) if Utc::now() - finished > RETENTION => {
+                    let _ = fs::remove_file(&path);
+                    let _ = fs::remove_file(&lock_path);
+                }
+                Some(_) => {}
+                // Left by a process that died, the lock file stays for whoever opened it already
+                None => {
+                    let _ = fs::remove_file(&path);
+                }
+            }
+            drop(file);
+        }
+    }
+}
+
+/// A file name for `image`, which may hold slashes and colons.
+fn key(image: &str) -> String {
+    image
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '.' | '-') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+fn read(path: &Path) -> Option<InFlightRecord> {
+    let content = fs::read_to_string(path).ok()?;
+    serde_json::from_str(&content).ok()
+}
+
+/// Open `path` and take its lock without waiting, none when another process holds it.
+fn lock(path: &Path) -> Option<File> {
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path)
+        .ok()?;
+    file.try_lock().ok()?;
+    Some(file)
+}
@@ -0,0 +1,186 @@
+use std::fs;
+use std::path::Path;
+
+use crate::misc::sanitize_app_name;
+
+/// Port used when nothing in the project hints at one.
+pub const DEFAULT_PORT: u16 = 8080;
+
+/// What `ruku init` found out about a project.
+pub struct ProjectInfo {
+    pub name: String,
+    pub port: u16,
+    /// Where the port came from, written next to it in the generated config.
+    pub port_source: String,
+    /// A port the Dockerfile exposes that ruku can't publish, below 1024.
+    pub privileged_port: Option<u16>,
+}
+
+/// Ports from the `EXPOSE` instructions of a Dockerfile, in order.
+pub fn parse_expose(dockerfile: &str) -> Vec<u16> {
+    dockerfile
+        .lines()
+        .map(str::trim)
+        .filter_map(|line| {
+            let (instruction, args) = line.split_once(char::is_whitespace)?;
+            instruction.eq_ignore_ascii_case("EXPOSE").then_some(args)
+        })
+        .flat_map(|args| args.split_whitespace())
+        .filter_map(|port| port.split('/').next()?.parse().ok())
+        .collect()
+}
+
+/// The framework of a project and the port it listens on by default.
+pub fn detect_framework(path: &Path) -> Option<(&'static str, u16)> {
+    if let Ok(package) = fs::read_to_string(path.join("package.json")) {
+        let framework = [("\"next\"", "Next.js", 3000), ("\"vite\"", "Vite", 5173)]
+            .into_iter()
+            .find(|(dependency, _, _)| package.contains(dependency))
+            .map(|(_, framework, port)| (framework, port));
+        return Some(framework.unwrap_or(("Node.js", 3000)));
+    }
+    if path.join("manage.py").exists() {
+        return Some(("Django", 8000));
+    }
+    let requirements = fs::read_to_string(path.join("requirements.txt")).unwrap_or_default();
+    if requirements.to_lowercase().contains("flask") {
+        return Some(("Flask", 5000));
+    }
+    if path.join("Gemfile").exists() {
+        return Some(("Ruby", 3000));
+    }
+    if path.join("Cargo.toml").exists() {
+        return Some(("Rust", DEFAULT_PORT));
+    }
+    if path.join("go.mod").exists() {
+        return Some(("Go", DEFAULT_PORT));
+    }
+    None
+}
+
+/// Inspect the project at `path`, a Dockerfile `EXPOSE` wins over framework defaults.
+pub fn detect(path: &Path) -> ProjectInfo {
+    let name = path
+        .file_name()
+        .map(|name| sanitize_app_name(&name.to_string_lossy()))
+        .filter(|name| !name.is_empty())
+        .unwrap_or("app".to_string());
+
+    let exposed = fs::read_to_string(path.join("Dockerfile"))
+        .map(|dockerfile| parse_expose(&dockerfile))
+        .unwrap_or_default();
+    let privileged_port = exposed.iter().copied().find(|&port| port < 1024);
+
+    let (port, port_source) = match exposed.iter().copied().find(|&port| port >= 1024) {
+        Some(port) => (port, "EXPOSE in Dockerfile".to_string()),
+        None => match detect_framework(path) {
+            Some((framework, port)) => (port, format!("{} default", framework)),
+            None => (DEFAULT_PORT, "ruku default".to_string()),
+        },
+    };
+
+    ProjectInfo {
+        name,
+        port,
+        port_source,
+        privileged_port,
+    }
+}
+
+/// The starter ruku.yml, `minimal` leaves out everything but the required fields.
+pub fn render(info: &ProjectInfo, minimal: bool) -> String {
+    let mut config = format!(
+        "# ruku.yml for {}\nport: {} # {}\nversion: \"0.1.0\"\n",
+        info.name, info.port, info.port_source
+    );
+    if minimal {
+        return config;
+    }
+
+    let stubs = r#"
+# Env vars read from a secret backend, templates refer to them as secret.NAME
+# secrets:
+#   DB_PASSWORD: vault:kv/data/{name}#DB_PASSWORD
+
+# Named volumes (name:/path) or host directories (./dir:/path), add :ro for read only
+# volumes:
+#   - data:/var/lib/app
+
+# Readiness check used when waiting for the container to become healthy
+# probe:
+#   path: /health
+#   timeout: 5
+
+# Limits on what the container may use of the host
+# resources:
+#   pids_limit: 512
+
+# Image build settings
+# build:
+#   builder: dockerfile
+#   registry: registry.example.com/team
+#   push: true
+"#;
+    config.push_str(&stubs.replace("{name}", &info.name));
+    config
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::RukuConfig;
+    use serde_yaml::Value;
+
+    /// Whether a commented out line is a stubbed key or the indented lines under one.
+    fn is_stub(line: &str) -> bool {
+        line.starts_with(' ')
+            || line
+                .split_once(':')
+                .is_some_and(|(key, _)| !key.is_empty() && key.chars().all(|c| c.is_ascii_lowercase() || c == '_'))
+    }
+
+    /// The config with its stubbed keys uncommented.
+    fn uncommented(config: &str) -> String {
+        config
+            .lines()
+            .map(|line| match line.strip_prefix("# ") {
+                Some(stub) if is_stub(stub) => stub,
+                _ => line,
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// Keys of `written` that `parsed` does not have, as dotted paths.
+    fn unknown_keys(written: &Value, parsed: &Value, path: &str, unknown: &mut Vec<String>) {
+        let Value::Mapping(written) = written else {
+            return;
+        };
+        for (key, value) in written {
+            let key = key.as_str().unwrap();
+            let key_path = format!("{}{}", path, key);
+            match parsed.get(key) {
+                Some(parsed) => unknown_keys(value, parsed, &format!("{}.", key_path), unknown),
+                None => unknown.push(key_path),
+            }
+        }
+    }
+
+    #[test]
+    fn stubs_are_keys_of_the_schema() {
+        let info = ProjectInfo {
+            name: "shop".to_string(),
+            port: 3000,
+            port_source: "ruku default".to_string(),
+            privileged_port: None,
+        };
+        let config = uncommented(&render(&info, false));
+        assert!(config.contains("\nprobe:"), "{}", config);
+
+        let parsed: RukuConfig = serde_yaml::from_str(&config).unwrap();
+        let written: Value = serde_yaml::from_str(&config).unwrap();
+        let mut unknown = vec![];
+        unknown_keys(&written, &serde_yaml::to_value(&parsed).unwrap(), "", &mut unknown);
+        assert!(unknown.is_empty(), "not in the schema: {:?}", unknown);
+    }
+}
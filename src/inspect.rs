@@ -0,0 +1,119 @@
+use bollard::models::ChangeType;
+use bollard::Docker;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::units::format_size;
+
+/// How a path in the container differs from the image, in the order Docker reports it.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeKind {
+    Added,
+    Changed,
+    Deleted,
+}
+
+impl ChangeKind {
+    /// The letter `docker diff` prints for the change.
+    pub fn letter(&self) -> char {
+        match self {
+            ChangeKind::Added => 'A',
+            ChangeKind::Changed => 'C',
+            ChangeKind::Deleted => 'D',
+        }
+    }
+}
+
+/// A path the container wrote, changed or removed since it started from its image.
+#[derive(Debug, Clone, Serialize)]
+pub struct FileChange {
+    pub path: String,
+    pub kind: ChangeKind,
+}
+
+/// Changes of the container filesystem, a stopped container keeps them until it is removed.
+pub async fn container_changes(docker: &Docker, container_name: &str) -> Result<Vec<FileChange>, String> {
+    let changes = docker
+        .container_changes(container_name)
+        .await
+        .map_err(|e| format!("Failed to read the changes of {}: {}", container_name, e))?;
+    Ok(changes
+        .unwrap_or_default()
+        .into_iter()
+        .map(|change| FileChange {
+            path: change.path,
+            kind: match change.kind {
+                ChangeType::_0 => ChangeKind::Changed,
+                ChangeType::_1 => ChangeKind::Added,
+                ChangeType::_2 => ChangeKind::Deleted,
+            },
+        })
+        .collect())
+}
+
+/// The changes at or below `path`, matched by whole path components so `/var/lib` leaves out
+/// `/var/library`.
+pub fn filter_changes(changes: Vec<FileChange>, path: &str) -> Vec<FileChange> {
+    let path = path.trim_end_matches('/');
+    if path.is_empty() {
+        return changes;
+    }
+    changes
+        .into_iter()
+        .filter(|change| {
+            change.path == path || change.path.strip_prefix(path).is_some_and(|rest| rest.starts_with('/'))
+        })
+        .collect()
+}
+
+/// How many changes there are of each kind, added, changed and deleted.
+pub fn count_changes(changes: &[FileChange]) -> (usize, usize, usize) {
+    let count = |kind| changes.iter().filter(|change| change.kind == kind).count();
+    (
+        count(ChangeKind::Added),
+        count(ChangeKind::Changed),
+        count(ChangeKind::Deleted),
+    )
+}
+
+/// One layer of an image, newest first as Docker lists them.
+#[derive(Debug, Clone, Serialize)]
+pub struct Layer {
+    /// Image id of the layer, none for layers that were pulled or built elsewhere.
+    pub id: Option<String>,
+    pub created: Option<DateTime<Utc>>,
+    /// The instruction that created the layer.
+    pub created_by: String,
+    pub size: i64,
+    /// The size for people, next to the bytes so the JSON needs no parsing either way.
+    pub size_human: String,
+    pub comment: String,
+}
+
+impl Layer {
+    /// The first 12 characters of the layer id, like `docker history` prints it.
+    pub fn short_id(&self) -> Option<&str> {
+        let id = self.id.as_deref()?.trim_start_matches("sha256:");
+        Some(&id[..id.len().min(12)])
+    }
+}
+
+/// The layers of an image, whether the image was built here or pulled.
+pub async fn image_history(docker: &Docker, image_name: &str) -> Result<Vec<Layer>, String> {
+    let history = docker
+        .image_history(image_name)
+        .await
+        .map_err(|e| format!("Failed to read the history of {}: {}", image_name, e))?;
+    Ok(history
+        .into_iter()
+        .map(|item| Layer {
+            id: Some(item.id).filter(|id| id != "<missing>"),
+            created: DateTime::from_timestamp(item.created, 0),
+            created_by: item.created_by,
+            size: item.size,
+            size_human: format_size(item.size.max(0) as u64),
+            comment: item.comment,
+        })
+        .collect())
+}
@@ -0,0 +1,123 @@
+//! Ruku as a library: the config loader and the deploy pipeline behind the `ruku` CLI.
+//! [`pipeline::DeployPipeline`] runs a whole deploy, its progress can be received as typed
+//! [`events::Event`]s by creating the [`logger::Logger`] with a channel. The daemon and the containers
+//! stay behind the pipeline, no Docker client or bollard type is part of the API.
+
+mod api_trace;
+mod api_version;
+mod app_context;
+mod app_url;
+mod archive;
+mod audit;
+mod auxiliary;
+mod backup;
+mod blue_green;
+mod build;
+mod build_cache;
+mod buildx;
+mod bundle;
+mod canary;
+pub mod cli;
+mod compose;
+pub mod config;
+mod confirm;
+mod connection;
+mod container;
+mod context;
+mod cpuset;
+mod daemon_error;
+mod daemon_network;
+mod dashboard;
+mod deadline;
+mod debug_bundle;
+mod dependency;
+mod deploy;
+mod deploy_message;
+mod deploys;
+mod dial;
+mod diff;
+mod drain;
+mod drift;
+mod env_export;
+pub mod error;
+mod event_stream;
+pub mod events;
+mod executor;
+mod exit_status;
+mod failures;
+#[cfg(test)]
+mod fake_daemon;
+mod fleet;
+mod freeze;
+mod git;
+mod health_server;
+mod history;
+mod host_config;
+mod image;
+mod image_drift;
+mod image_tarball;
+mod image_wait;
+mod inflight;
+mod init;
+mod inspect;
+mod links;
+pub mod logger;
+mod logs;
+mod maintenance;
+mod metrics;
+mod migrate;
+mod misc;
+pub mod model;
+mod network;
+mod observability;
+#[cfg(feature = "otel")]
+mod otel;
+mod overview;
+pub mod pipeline;
+mod plan;
+mod platform;
+mod policy;
+mod ports;
+mod preflight;
+mod prestart;
+mod preview;
+mod probe;
+mod provenance;
+mod proxy;
+mod read_only;
+mod recreate;
+mod registry;
+mod release_logs;
+mod releases;
+mod reload;
+mod remote_config;
+mod repair;
+mod rollback;
+mod rolling;
+mod rootless;
+mod routing;
+mod sbom;
+mod scan;
+mod secrets;
+mod selector;
+pub mod server_config;
+mod sha256;
+mod sidecar;
+mod slots;
+mod smoke;
+mod spec;
+mod staging;
+mod static_site;
+mod store;
+mod strategy;
+#[cfg(unix)]
+mod sudo;
+mod templates;
+mod top;
+mod units;
+mod verify_env;
+mod version;
+mod volume;
+mod webhook;
+mod yaml_spans;
+mod zoneinfo;
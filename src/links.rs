@@ -0,0 +1,90 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::store;
+
+/// Another app this app talks to, resolved for a deploy.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Link {
+    pub app: String,
+    /// Container port of the linked app, unknown when its config can't be read or leaves it to the image.
+    pub port: Option<u16>,
+}
+
+impl Link {
+    /// The `<OTHER>_HOST` and `<OTHER>_PORT` variables injected into the linking app.
+    pub fn env(&self) -> Vec<(String, String)> {
+        let prefix = get_env_prefix(&self.app);
+        let mut env = vec![(format!("{}_HOST", prefix), self.app.clone())];
+        if let Some(port) = self.port {
+            env.push((format!("{}_PORT", prefix), port.to_string()));
+        }
+        env
+    }
+}
+
+/// The app name as an environment variable prefix, `my-worker` becomes `MY_WORKER`.
+pub fn get_env_prefix(app: &str) -> String {
+    app.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// The apps an app is linked to, stored as JSON in the app state directory.
+pub struct Links {
+    path: PathBuf,
+}
+
+impl Links {
+    pub const FILE_NAME: &'static str = "links.json";
+
+    pub fn new(state_dir: &Path) -> Links {
+        Links {
+            path: state_dir.join(Self::FILE_NAME),
+        }
+    }
+
+    pub fn load(&self) -> Result<Vec<String>, String> {
+        if !self.path.exists() {
+            return Ok(vec![]);
+        }
+
+        let content = fs::read_to_string(&self.path).map_err(|e| format!("Error reading links: {}", e))?;
+        serde_json::from_str(&content).map_err(|e| format!("Error parsing links: {}", e))
+    }
+
+    /// Add a link, returns false when it already existed.
+    pub fn add(&self, app: &str) -> Result<bool, String> {
+        let mut links = self.load()?;
+        if links.iter().any(|link| link == app) {
+            return Ok(false);
+        }
+        links.push(app.to_string());
+        links.sort();
+        self.save(&links)?;
+        Ok(true)
+    }
+
+    /// Remove a link, returns false when there was none.
+    pub fn remove(&self, app: &str) -> Result<bool, String> {
+        let mut links = self.load()?;
+        let count = links.len();
+        links.retain(|link| link != app);
+        if links.len() == count {
+            return Ok(false);
+        }
+        self.save(&links)?;
+        Ok(true)
+    }
+
+    fn save(&self, links: &[String]) -> Result<(), String> {
+        let content = serde_json::to_string_pretty(links).unwrap();
+        store::write_atomic(&self.path, content.as_bytes()).map_err(|e| format!("Error writing links: {}", e))
+    }
+}
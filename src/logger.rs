@@ -1,26 +1,394 @@
+use std::io::{self, IsTerminal, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
 use colored::Colorize;
 
-/// Used for reporting Docker build information to stdout.
-pub struct Logger {}
+use crate::events::{Downsample, Event, Level};
+#[cfg(feature = "otel")]
+use crate::otel::Trace;
+use crate::units::format_size;
+
+/// Longest time streamed output waits in the buffer.
+const FLUSH_INTERVAL: Duration = Duration::from_millis(50);
+/// Buffered bytes of one target that are written out without waiting for the interval.
+const FLUSH_SIZE: usize = 8 * 1024;
+/// Writes to a terminal per second past which a stream drops output, a terminal can't show more anyway.
+const MAX_TERMINAL_WRITES: usize = 20_000;
+
+/// Characters of a progress bar between its brackets.
+const BAR_WIDTH: usize = 24;
+
+/// Environment variable that makes the CLI print debug lines.
+pub const DEBUG_ENV: &str = "RUKU_DEBUG";
+/// Environment variable that makes the CLI print events as JSON lines.
+pub const JSON_EVENTS_ENV: &str = "RUKU_JSON_EVENTS";
+
+/// Set by `--debug`.
+static DEBUG: AtomicBool = AtomicBool::new(false);
+/// Set by `--json-events`.
+static JSON_EVENTS: AtomicBool = AtomicBool::new(false);
+/// Whether a progress bar is on the last line of the terminal, which the next line replaces.
+static BAR_SHOWN: AtomicBool = AtomicBool::new(false);
+
+type ErrorHook = Box<dyn FnOnce(&str) + Send>;
+
+/// Print debug lines from here on.
+pub fn set_debug() {
+    DEBUG.store(true, Ordering::Relaxed);
+}
+
+/// Whether debug lines are printed, by `--debug` or `RUKU_DEBUG`.
+pub fn is_debug() -> bool {
+    DEBUG.load(Ordering::Relaxed)
+        || std::env::var(DEBUG_ENV).is_ok_and(|value| !matches!(value.trim(), "" | "0" | "false"))
+}
+
+/// Print every event as a JSON line on stdout from here on, instead of the lines for people.
+pub fn set_json_events() {
+    JSON_EVENTS.store(true, Ordering::Relaxed);
+}
+
+/// Whether events are printed as JSON lines, by `--json-events` or `RUKU_JSON_EVENTS`.
+pub fn is_json_events() -> bool {
+    JSON_EVENTS.load(Ordering::Relaxed)
+        || std::env::var(JSON_EVENTS_ENV).is_ok_and(|value| !matches!(value.trim(), "" | "0" | "false"))
+}
+
+/// Where streamed output goes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Target {
+    Stdout,
+    Stderr,
+}
+
+/// Streamed output waiting to be written, with the writes dropped in the current second.
+struct StreamBuffer {
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+    last_flush: Instant,
+    second_started: Instant,
+    writes: usize,
+    suppressed: u64,
+}
+
+impl StreamBuffer {
+    fn new() -> StreamBuffer {
+        StreamBuffer {
+            stdout: vec![],
+            stderr: vec![],
+            last_flush: Instant::now(),
+            second_started: Instant::now(),
+            writes: 0,
+            suppressed: 0,
+        }
+    }
+
+    fn push(&mut self, target: Target, bytes: &[u8]) {
+        if self.second_started.elapsed() >= Duration::from_secs(1) {
+            self.flush();
+            self.second_started = Instant::now();
+            self.writes = 0;
+        }
+        self.writes += 1;
+        if self.writes > MAX_TERMINAL_WRITES && is_terminal(target) {
+            self.suppressed += bytes.iter().filter(|b| **b == b'\n').count().max(1) as u64;
+            return;
+        }
+        let buffer = match target {
+            Target::Stdout => &mut self.stdout,
+            Target::Stderr => &mut self.stderr,
+        };
+        buffer.extend_from_slice(bytes);
+        if buffer.len() >= FLUSH_SIZE || self.last_flush.elapsed() >= FLUSH_INTERVAL {
+            self.flush();
+        }
+    }
+
+    /// Write out what is buffered, followed by a notice of the dropped output once its second is over.
+    fn flush(&mut self) {
+        if !self.stdout.is_empty() {
+            let mut stdout = io::stdout().lock();
+            let _ = stdout.write_all(&self.stdout);
+            let _ = stdout.flush();
+            self.stdout.clear();
+        }
+        if !self.stderr.is_empty() {
+            let _ = io::stderr().lock().write_all(&self.stderr);
+            self.stderr.clear();
+        }
+        if self.second_started.elapsed() >= Duration::from_secs(1) {
+            self.notice();
+        }
+        self.last_flush = Instant::now();
+    }
+
+    /// Tell how much output was dropped since the last notice.
+    fn notice(&mut self) {
+        if self.suppressed > 0 {
+            let message = format!("… suppressed {} lines", std::mem::take(&mut self.suppressed));
+            eprintln!("=> {}", message.yellow());
+        }
+    }
+}
+
+fn is_terminal(target: Target) -> bool {
+    match target {
+        Target::Stdout => io::stdout().is_terminal(),
+        Target::Stderr => io::stderr().is_terminal(),
+    }
+}
+
+/// Streams high volume output, e.g. container logs or a build, through the buffer of the logger. The
+/// buffer is written out every 50ms or 8KB and when the stream is dropped.
+pub struct Stream {
+    buffer: Arc<Mutex<StreamBuffer>>,
+    done: Arc<AtomicBool>,
+    flusher: Option<thread::JoinHandle<()>>,
+}
+
+impl Stream {
+    /// Queue `bytes` for `target`.
+    pub fn write(&self, target: Target, bytes: &[u8]) {
+        self.buffer.lock().unwrap().push(target, bytes);
+    }
+
+    /// Queue `line` and a newline for `target`.
+    pub fn line(&self, target: Target, line: &str) {
+        let mut bytes = Vec::with_capacity(line.len() + 1);
+        bytes.extend_from_slice(line.as_bytes());
+        bytes.push(b'\n');
+        self.write(target, &bytes);
+    }
+}
+
+impl Drop for Stream {
+    fn drop(&mut self) {
+        self.done.store(true, Ordering::Relaxed);
+        if let Some(flusher) = self.flusher.take() {
+            flusher.thread().unpark();
+            let _ = flusher.join();
+        }
+        let mut buffer = self.buffer.lock().unwrap();
+        buffer.flush();
+        buffer.notice();
+    }
+}
+
+/// Reports progress, printed to stderr or sent as events to an embedder.
+pub struct Logger {
+    events: Option<Sender<Event>>,
+    /// Streamed output not written yet, see [`Logger::stream`].
+    stream: Arc<Mutex<StreamBuffer>>,
+    /// Run on the first error, e.g. to record the failure before the process exits.
+    on_error: Mutex<Vec<ErrorHook>>,
+    /// Thins out the pull and build progress events.
+    progress: Mutex<Downsample>,
+    /// The deploy being traced, its spans follow the stage events.
+    #[cfg(feature = "otel")]
+    trace: Mutex<Option<Trace>>,
+}
 
 impl Logger {
     pub fn new() -> Logger {
-        Logger {}
+        Logger {
+            events: None,
+            stream: Arc::new(Mutex::new(StreamBuffer::new())),
+            on_error: Mutex::new(vec![]),
+            progress: Mutex::new(Downsample::new()),
+            #[cfg(feature = "otel")]
+            trace: Mutex::new(None),
+        }
+    }
+
+    /// Send every message as an [`Event`] instead of printing it.
+    pub fn with_events(events: Sender<Event>) -> Logger {
+        Logger {
+            events: Some(events),
+            stream: Arc::new(Mutex::new(StreamBuffer::new())),
+            on_error: Mutex::new(vec![]),
+            progress: Mutex::new(Downsample::new()),
+            #[cfg(feature = "otel")]
+            trace: Mutex::new(None),
+        }
+    }
+
+    /// Start streaming output through the buffer, one stream at a time.
+    pub fn stream(&self) -> Stream {
+        let buffer = self.stream.clone();
+        let done = Arc::new(AtomicBool::new(false));
+        let flusher = {
+            let (buffer, done) = (buffer.clone(), done.clone());
+            // Output that stops coming in mid interval is still written out on time
+            thread::spawn(move || {
+                while !done.load(Ordering::Relaxed) {
+                    thread::park_timeout(FLUSH_INTERVAL);
+                    let mut buffer = buffer.lock().unwrap();
+                    if buffer.last_flush.elapsed() >= FLUSH_INTERVAL {
+                        buffer.flush();
+                    }
+                }
+            })
+        };
+        Stream {
+            buffer,
+            done,
+            flusher: Some(flusher),
+        }
+    }
+
+    /// Write out the streamed output still buffered, e.g. before a prompt.
+    pub fn flush(&self) {
+        self.stream.lock().unwrap().flush();
+    }
+
+    /// Call `hook` with the message of the next error, after the hooks set before it.
+    pub fn on_error(&self, hook: impl FnOnce(&str) + Send + 'static) {
+        self.on_error.lock().unwrap().push(Box::new(hook));
+    }
+
+    /// Record the stages from here on as spans of `trace`.
+    #[cfg(feature = "otel")]
+    pub fn start_trace(&self, trace: Trace) {
+        *self.trace.lock().unwrap() = Some(trace);
+    }
+
+    /// Add an attribute to the deploy span of the current trace.
+    #[cfg(feature = "otel")]
+    pub fn trace_attribute(&self, key: &str, value: &str) {
+        if let Some(trace) = self.trace.lock().unwrap().as_mut() {
+            trace.set(key, value);
+        }
+    }
+
+    /// Send the current trace, `error` marks the deploy as failed. An unreachable collector only costs
+    /// a warning.
+    #[cfg(feature = "otel")]
+    pub fn finish_trace(&self, error: Option<&str>) {
+        let trace = self.trace.lock().unwrap().take();
+        if let Err(e) = trace.map_or(Ok(()), |trace| trace.export(error)) {
+            self.warn(&e);
+        }
     }
 
     /// Pretty-print the given log section title.
     pub fn section(&self, msg: &str) {
-        eprintln!("=== {} ===", msg.magenta().bold());
+        self.line(Level::Section, msg);
     }
 
     /// Pretty-print the given log line.
     pub fn step(&self, msg: &str) {
-        eprintln!("=> {}", msg.cyan());
+        self.line(Level::Step, msg);
+    }
+
+    /// Pretty-print warning message
+    pub fn warn(&self, msg: &str) {
+        self.line(Level::Warn, msg);
+    }
+
+    /// Print a line for troubleshooting, only shown in debug mode.
+    pub fn debug(&self, msg: &str) {
+        self.line(Level::Debug, msg);
     }
 
     /// Pretty-print error message
     pub fn error(&self, msg: &str) {
-        eprintln!("=> {}", msg.red());
+        self.emit(Event::Error {
+            message: msg.to_string(),
+        });
+    }
+
+    pub fn stage_started(&self, stage: &str) {
+        self.emit(Event::StageStarted {
+            stage: stage.to_string(),
+        });
+    }
+
+    pub fn stage_completed(&self, stage: &str, seconds: f64) {
+        self.emit(Event::StageCompleted {
+            stage: stage.to_string(),
+            seconds,
+        });
+    }
+
+    /// Report the progress of `layer` of `image`, downsampled per layer.
+    pub fn pull_progress(&self, image: &str, layer: &str, status: &str, current: Option<u64>, total: Option<u64>) {
+        if !self.progress.lock().unwrap().admit(layer, status) {
+            return;
+        }
+        self.emit(Event::PullProgress {
+            image: image.to_string(),
+            layer: layer.to_string(),
+            status: status.to_string(),
+            current,
+            total,
+        });
+    }
+
+    /// Report that a build reached step `step` of `total`, once per step.
+    pub fn build_step(&self, step: u32, total: u32, instruction: &str) {
+        if !self
+            .progress
+            .lock()
+            .unwrap()
+            .admit("build", &format!("{}/{}", step, total))
+        {
+            return;
+        }
+        self.emit(Event::BuildStep {
+            step,
+            total,
+            instruction: instruction.to_string(),
+        });
+    }
+
+    fn line(&self, level: Level, msg: &str) {
+        self.emit(Event::LogLine {
+            level,
+            message: msg.to_string(),
+        });
+    }
+
+    /// Deliver an event, a receiver that went away only loses the remaining events.
+    pub fn emit(&self, event: Event) {
+        #[cfg(feature = "otel")]
+        {
+            if let Some(trace) = self.trace.lock().unwrap().as_mut() {
+                trace.record(&event);
+            }
+            // Errors are followed by the exit, the trace has to go out now
+            if let Event::Error { message } = &event {
+                self.finish_trace(Some(message));
+            }
+        }
+        let error = match &event {
+            Event::Error { message } => Some(message.clone()),
+            _ => None,
+        };
+        match &self.events {
+            Some(events) => {
+                let _ = events.send(event);
+            }
+            None if is_json_events() => {
+                self.flush();
+                println!("{}", serde_json::to_string(&event).unwrap());
+            }
+            None => {
+                // Never buffered, but shown after the output streamed before it
+                self.flush();
+                render(&event)
+            }
+        }
+        // After the error is shown, so what a hook prints follows it
+        if let Some(message) = error {
+            let hooks = std::mem::take(&mut *self.on_error.lock().unwrap());
+            for hook in hooks {
+                hook(&message);
+            }
+        }
     }
 }
 
@@ -29,3 +397,69 @@ impl Default for Logger {
         Self::new()
     }
 }
+
+/// How the CLI shows an event. Stages are already narrated by the log lines around them, and build steps
+/// by the build output.
+fn render(event: &Event) {
+    if let Event::PullProgress {
+        layer,
+        status,
+        current: Some(current),
+        total: Some(total),
+        ..
+    } = event
+    {
+        render_bar(layer, status, *current, *total);
+        return;
+    }
+    if BAR_SHOWN.swap(false, Ordering::Relaxed) {
+        eprint!("\r\x1b[K");
+    }
+    match event {
+        Event::LogLine {
+            level: Level::Section,
+            message,
+        } => eprintln!("=== {} ===", message.magenta().bold()),
+        Event::LogLine {
+            level: Level::Step,
+            message,
+        } => eprintln!("=> {}", message.cyan()),
+        Event::LogLine {
+            level: Level::Warn,
+            message,
+        } => eprintln!("=> {}", message.yellow()),
+        Event::LogLine {
+            level: Level::Debug,
+            message,
+        } => {
+            if is_debug() {
+                eprintln!("   {}", message.dimmed())
+            }
+        }
+        Event::Error { message } => eprintln!("=> {}", message.red()),
+        Event::StageStarted { .. }
+        | Event::StageCompleted { .. }
+        | Event::PullProgress { .. }
+        | Event::BuildStep { .. } => {}
+    }
+}
+
+/// Show the transfer of a layer as a bar on the last line of a terminal, each update replacing the one
+/// before so a pull takes one line.
+fn render_bar(layer: &str, status: &str, current: u64, total: u64) {
+    if total == 0 || !io::stderr().is_terminal() {
+        return;
+    }
+    let filled = (current.min(total) as f64 / total as f64 * BAR_WIDTH as f64) as usize;
+    let bar = format!("{}{}", "=".repeat(filled), " ".repeat(BAR_WIDTH - filled));
+    let layer: String = layer.chars().take(12).collect();
+    eprint!(
+        "\r\x1b[K   {} [{}] {} {} of {}",
+        layer,
+        bar,
+        status,
+        format_size(current),
+        format_size(total)
+    );
+    BAR_SHOWN.store(true, Ordering::Relaxed);
+}
@@ -0,0 +1,592 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::LazyLock;
+use std::time::Duration;
+
+use bollard::container::{LogOutput, LogsOptions};
+use bollard::Docker;
+use chrono::{DateTime, FixedOffset};
+use colored::Colorize;
+use futures_util::StreamExt;
+use regex::Regex;
+use serde::Serialize;
+
+use crate::container::Container;
+use crate::logger::{Logger, Stream, Target};
+
+/// Default size a saved log file grows to before it is rotated.
+pub const DEFAULT_MAX_SIZE: &str = "10M";
+/// Default number of rotated files kept next to the live one.
+pub const DEFAULT_KEEP: usize = 5;
+/// Lines of output shown when a container fails to come up.
+pub const RECENT_LOG_LINES: usize = 20;
+
+const RESOLVE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The first level token of a line, as a bare word, `[WARN]`, `level=warn` or `"level":"warn"`.
+static LEVEL_TOKEN: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?i)\b(trace|debug|info|notice|warn|warning|error|err|fatal|panic|crit|critical)\b").unwrap()
+});
+
+/// How severe a log line is, guessed from the level token most loggers print.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogLevel {
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+impl LogLevel {
+    /// The level of the first level token in `message`, none when it has none.
+    pub fn detect(message: &str) -> Option<LogLevel> {
+        let token = LEVEL_TOKEN.find(message)?.as_str().to_ascii_lowercase();
+        token.parse().ok()
+    }
+}
+
+impl fmt::Display for LogLevel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LogLevel::Debug => write!(f, "debug"),
+            LogLevel::Info => write!(f, "info"),
+            LogLevel::Warn => write!(f, "warn"),
+            LogLevel::Error => write!(f, "error"),
+        }
+    }
+}
+
+impl FromStr for LogLevel {
+    type Err = String;
+
+    fn from_str(level: &str) -> Result<LogLevel, String> {
+        match level.to_ascii_lowercase().as_str() {
+            "trace" | "debug" => Ok(LogLevel::Debug),
+            "info" | "notice" => Ok(LogLevel::Info),
+            "warn" | "warning" => Ok(LogLevel::Warn),
+            "error" | "err" | "fatal" | "panic" | "crit" | "critical" => Ok(LogLevel::Error),
+            _ => Err(format!("unknown log level '{}', use debug, info, warn or error", level)),
+        }
+    }
+}
+
+/// Which lines of the output `ruku logs` shows.
+#[derive(Debug, Clone, Default)]
+pub struct LogFilter {
+    grep: Option<Regex>,
+    exclude: Option<Regex>,
+    /// Lines below this level are left out, and so are lines without a level token.
+    level: Option<LogLevel>,
+}
+
+impl LogFilter {
+    /// Compile the patterns, so a typo is reported before anything connects to Docker.
+    pub fn new(grep: Option<&str>, exclude: Option<&str>, level: Option<&str>) -> Result<LogFilter, String> {
+        let compile =
+            |flag: &str, pattern: &str| Regex::new(pattern).map_err(|e| format!("invalid --{} pattern: {}", flag, e));
+        Ok(LogFilter {
+            grep: grep.map(|pattern| compile("grep", pattern)).transpose()?,
+            exclude: exclude.map(|pattern| compile("exclude", pattern)).transpose()?,
+            level: level.map(str::parse).transpose()?,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.grep.is_none() && self.exclude.is_none() && self.level.is_none()
+    }
+
+    /// Whether the line is shown.
+    pub fn matches(&self, message: &str) -> bool {
+        if self.grep.as_ref().is_some_and(|grep| !grep.is_match(message)) {
+            return false;
+        }
+        if self.exclude.as_ref().is_some_and(|exclude| exclude.is_match(message)) {
+            return false;
+        }
+        match self.level {
+            Some(min) => LogLevel::detect(message).is_some_and(|level| level >= min),
+            None => true,
+        }
+    }
+
+    /// The capture groups of the `--grep` pattern in `message`, by name or number.
+    pub fn groups(&self, message: &str) -> BTreeMap<String, String> {
+        let Some(grep) = &self.grep else {
+            return BTreeMap::new();
+        };
+        let Some(captures) = grep.captures(message) else {
+            return BTreeMap::new();
+        };
+        grep.capture_names()
+            .enumerate()
+            .skip(1)
+            .filter_map(|(i, name)| {
+                let value = captures.get(i)?.as_str().to_string();
+                Some((name.map(str::to_string).unwrap_or(i.to_string()), value))
+            })
+            .collect()
+    }
+
+    /// The line with the `--grep` matches in bold red.
+    pub fn highlight(&self, message: &str) -> String {
+        let Some(grep) = &self.grep else {
+            return message.to_string();
+        };
+        let mut highlighted = String::new();
+        let mut end = 0;
+        for found in grep.find_iter(message).filter(|found| !found.is_empty()) {
+            highlighted.push_str(&message[end..found.start()]);
+            highlighted.push_str(&found.as_str().red().bold().to_string());
+            end = found.end();
+        }
+        highlighted.push_str(&message[end..]);
+        highlighted
+    }
+}
+
+/// A line of container output as `ruku logs --json` prints it.
+#[derive(Debug, Serialize)]
+pub struct LogLine<'a> {
+    pub timestamp: &'a str,
+    pub stream: &'a str,
+    pub message: &'a str,
+    pub level: Option<LogLevel>,
+    /// Capture groups of the `--grep` pattern, by name or number.
+    pub groups: BTreeMap<String, String>,
+}
+
+/// Appends lines to a file, moving it to `<file>.1`, `<file>.2`, ... once it reaches `max_size`.
+pub struct RotatingWriter {
+    path: PathBuf,
+    max_size: u64,
+    keep: usize,
+    file: Option<File>,
+    size: u64,
+}
+
+impl RotatingWriter {
+    pub fn new(path: &Path, max_size: u64, keep: usize) -> RotatingWriter {
+        RotatingWriter {
+            path: path.to_path_buf(),
+            max_size,
+            keep,
+            file: None,
+            size: 0,
+        }
+    }
+
+    /// Close the file so the next write opens it again, for logrotate moving it away.
+    pub fn reopen(&mut self) {
+        self.file = None;
+    }
+
+    pub fn write_line(&mut self, line: &str) -> io::Result<()> {
+        if self.file.is_some() && self.size > 0 && self.size + line.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        if self.file.is_none() {
+            self.open()?;
+        }
+
+        let file = self.file.as_mut().unwrap();
+        let result = file.write_all(line.as_bytes()).and_then(|_| file.flush());
+        if result.is_err() {
+            // A short write on a full disk leaves the offset unknown, start over from the file length
+            self.file = None;
+        } else {
+            self.size += line.len() as u64;
+        }
+        result
+    }
+
+    fn open(&mut self) -> io::Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        self.size = file.metadata()?.len();
+        self.file = Some(file);
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file = None;
+        if self.keep == 0 {
+            return fs::remove_file(&self.path).or_else(ignore_not_found);
+        }
+        for n in (1..self.keep).rev() {
+            let from = self.rotated_path(n);
+            if from.exists() {
+                fs::rename(&from, self.rotated_path(n + 1))?;
+            }
+        }
+        fs::rename(&self.path, self.rotated_path(1)).or_else(ignore_not_found)
+    }
+
+    fn rotated_path(&self, n: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", n));
+        PathBuf::from(path)
+    }
+}
+
+/// SIGHUP, which logrotate sends after moving the file. Platforms without it never receive one.
+struct Hangup {
+    #[cfg(unix)]
+    signal: tokio::signal::unix::Signal,
+}
+
+impl Hangup {
+    fn new() -> io::Result<Hangup> {
+        Ok(Hangup {
+            #[cfg(unix)]
+            signal: tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?,
+        })
+    }
+
+    async fn recv(&mut self) {
+        #[cfg(unix)]
+        self.signal.recv().await;
+        #[cfg(not(unix))]
+        std::future::pending::<()>().await;
+    }
+}
+
+/// The lines already saved, so following the container again does not save them twice. Docker sends
+/// everything from the start of the second it is asked for, lines of that second are told apart by their
+/// text.
+#[derive(Default)]
+struct Saved {
+    last: Option<DateTime<FixedOffset>>,
+    /// The lines saved with the timestamp `last`.
+    at_last: HashSet<String>,
+}
+
+impl Saved {
+    /// Record the line, false when it was saved already.
+    fn insert(&mut self, timestamp: &str, line: &str) -> bool {
+        // Without a timestamp to go by every line is new
+        let Ok(timestamp) = DateTime::parse_from_rfc3339(timestamp) else {
+            return true;
+        };
+        match self.last {
+            Some(last) if timestamp < last => false,
+            Some(last) if timestamp == last => self.at_last.insert(line.to_string()),
+            _ => {
+                self.last = Some(timestamp);
+                self.at_last = HashSet::from([line.to_string()]);
+                true
+            }
+        }
+    }
+
+    /// The whole second to ask Docker for lines from, 0 for all.
+    fn since(&self) -> i64 {
+        self.last.map_or(0, |last| last.timestamp())
+    }
+}
+
+fn ignore_not_found(e: io::Error) -> io::Result<()> {
+    match e.kind() {
+        io::ErrorKind::NotFound => Ok(()),
+        _ => Err(e),
+    }
+}
+
+/// Output of the app container.
+pub struct Logs<'a> {
+    log: &'a Logger,
+    docker: &'a Docker,
+    container_name: &'a str,
+    filter: LogFilter,
+    json: bool,
+}
+
+impl<'a> Logs<'a> {
+    pub fn new(log: &'a Logger, docker: &'a Docker, container_name: &'a str) -> Logs<'a> {
+        Logs {
+            log,
+            docker,
+            container_name,
+            filter: LogFilter::default(),
+            json: false,
+        }
+    }
+
+    /// Only print the lines the filter matches.
+    pub fn with_filter(mut self, filter: LogFilter) -> Logs<'a> {
+        self.filter = filter;
+        self
+    }
+
+    /// Print every line as a JSON object with its timestamp, stream, level and matched groups.
+    pub fn with_json(mut self, json: bool) -> Logs<'a> {
+        self.json = json;
+        self
+    }
+
+    /// Print the container output to the terminal. The filter applies to the tail and to what follows, the
+    /// tail is taken first so `--tail 100` shows the matches among the last 100 lines.
+    pub async fn print(&self, follow: bool, tail: Option<usize>) -> Result<(), String> {
+        let by_line = self.json || !self.filter.is_empty();
+        let options = LogsOptions {
+            follow,
+            stdout: true,
+            stderr: true,
+            timestamps: self.json,
+            tail: tail.map(|n| n.to_string()).unwrap_or("all".to_string()),
+            ..Default::default()
+        };
+        let mut stream = self.docker.logs(self.container_name, Some(options));
+        let out = self.log.stream();
+        // Docker may split a line across frames, the rest of each stream waits here for its newline
+        let mut partial: BTreeMap<&str, String> = BTreeMap::new();
+        while let Some(output) = stream.next().await {
+            match output {
+                Ok(output) if by_line => {
+                    let (stream_tag, message) = match &output {
+                        LogOutput::StdErr { message } => ("stderr", message),
+                        LogOutput::StdOut { message } => ("stdout", message),
+                        LogOutput::StdIn { message } => ("stdin", message),
+                        LogOutput::Console { message } => ("console", message),
+                    };
+                    let buffer = partial.entry(stream_tag).or_default();
+                    buffer.push_str(&String::from_utf8_lossy(message));
+                    while let Some(end) = buffer.find('\n') {
+                        let line: String = buffer.drain(..=end).collect();
+                        self.print_line(&out, stream_tag, line.trim_end_matches(['\n', '\r']));
+                    }
+                }
+                Ok(LogOutput::StdErr { message }) => out.write(Target::Stderr, &message),
+                // The one stream of a tty container, with the line endings of the terminal
+                Ok(LogOutput::Console { message }) => {
+                    let message = String::from_utf8_lossy(&message).replace("\r\n", "\n");
+                    out.write(Target::Stdout, message.as_bytes());
+                }
+                Ok(output) => out.write(Target::Stdout, output.as_ref()),
+                Err(e) => return Err(format!("Error reading logs: {}", e)),
+            }
+        }
+        for (stream_tag, line) in partial {
+            if !line.is_empty() {
+                self.print_line(&out, stream_tag, &line);
+            }
+        }
+        Ok(())
+    }
+
+    /// Print kept output, lines with their stream and timestamp as [`crate::release_logs::ReleaseLogs`]
+    /// reads them. The filter applies after the tail as with [`Logs::print`].
+    pub fn print_kept(&self, lines: &[(String, String)], tail: Option<usize>) {
+        let out = self.log.stream();
+        let skip = tail.map_or(0, |tail| lines.len().saturating_sub(tail));
+        for (stream_tag, line) in &lines[skip..] {
+            let line = match self.json {
+                true => line.as_str(),
+                false => line.split_once(' ').map_or(line.as_str(), |(_, message)| message),
+            };
+            self.print_line(&out, stream_tag, line);
+        }
+    }
+
+    fn print_line(&self, out: &Stream, stream_tag: &str, line: &str) {
+        let (timestamp, message) = if self.json {
+            line.split_once(' ').unwrap_or(("", line))
+        } else {
+            ("", line)
+        };
+        if !self.filter.matches(message) {
+            return;
+        }
+        if self.json {
+            let line = LogLine {
+                timestamp,
+                stream: stream_tag,
+                message,
+                level: LogLevel::detect(message),
+                groups: self.filter.groups(message),
+            };
+            out.line(Target::Stdout, &serde_json::to_string(&line).unwrap());
+            return;
+        }
+        if stream_tag == "stderr" {
+            if io::stderr().is_terminal() {
+                out.line(Target::Stderr, &self.filter.highlight(message));
+            } else {
+                out.line(Target::Stderr, message);
+            }
+        } else if io::stdout().is_terminal() {
+            out.line(Target::Stdout, &self.filter.highlight(message));
+        } else {
+            out.line(Target::Stdout, message);
+        }
+    }
+
+    /// Follow the container output into a rotating file until interrupted. A restarted or redeployed
+    /// container is picked up again by name, SIGHUP reopens the file.
+    pub async fn save(&self, writer: &mut RotatingWriter) -> Result<(), String> {
+        let mut hangup = Hangup::new().map_err(|e| format!("Error installing the SIGHUP handler: {}", e))?;
+        let mut saved = Saved::default();
+        let mut failing = false;
+
+        loop {
+            let Some(container_id) = self.resolve().await else {
+                tokio::time::sleep(RESOLVE_INTERVAL).await;
+                continue;
+            };
+            self.log.step(&format!(
+                "Following container {}",
+                &container_id[..container_id.len().min(12)]
+            ));
+
+            // Docker only filters by whole seconds, lines already written in that second are skipped below
+            let since = saved.since();
+            let options = LogsOptions::<String> {
+                follow: true,
+                stdout: true,
+                stderr: true,
+                timestamps: true,
+                since,
+                tail: "all".to_string(),
+                ..Default::default()
+            };
+            let mut stream = self.docker.logs(&container_id, Some(options));
+            let mut console = String::new();
+
+            loop {
+                tokio::select! {
+                    output = stream.next() => {
+                        let Some(Ok(output)) = output else {
+                            break;
+                        };
+                        let (stream_tag, message) = match &output {
+                            LogOutput::StdErr { message } => ("stderr", message),
+                            LogOutput::StdOut { message } => ("stdout", message),
+                            LogOutput::StdIn { message } => ("stdin", message),
+                            LogOutput::Console { message } => ("console", message),
+                        };
+                        let message = String::from_utf8_lossy(message);
+                        // A tty container sends one raw stream, over TCP in chunks that don't end at a line
+                        let messages = if stream_tag == "console" {
+                            console.push_str(&message);
+                            let mut lines = vec![];
+                            while let Some(end) = console.find('\n') {
+                                lines.push(console.drain(..=end).collect::<String>());
+                            }
+                            lines
+                        } else {
+                            vec![message.into_owned()]
+                        };
+                        for message in messages {
+                            let (timestamp, text) = message.split_once(' ').unwrap_or(("", &message));
+                            let text = text.trim_end_matches(['\n', '\r']);
+                            let line = format!("{} {}", stream_tag, text);
+                            if !saved.insert(timestamp, &line) {
+                                continue;
+                            }
+
+                            match writer.write_line(&format!("{} {}\n", timestamp, line)) {
+                                Ok(()) if failing => {
+                                    self.log.step("Writing logs again");
+                                    failing = false;
+                                }
+                                Ok(()) => {}
+                                // Keep following on a full disk, lines are dropped until writes succeed again
+                                Err(e) if !failing => {
+                                    self.log.warn(&format!("Error writing logs, dropping lines: {}", e));
+                                    failing = true;
+                                }
+                                Err(_) => {}
+                            }
+                        }
+                    }
+                    _ = hangup.recv() => {
+                        self.log.step("Received SIGHUP, reopening the log file");
+                        writer.reopen();
+                    }
+                }
+            }
+
+            self.log.step("Log stream ended, waiting for the container");
+            tokio::time::sleep(RESOLVE_INTERVAL).await;
+        }
+    }
+
+    /// The id of the running container with the app name, it changes on every deploy.
+    async fn resolve(&self) -> Option<String> {
+        let inspect = self.docker.inspect_container(self.container_name, None).await.ok()?;
+        let running = inspect.state.and_then(|state| state.running).unwrap_or(false);
+        running.then_some(inspect.id).flatten()
+    }
+}
+
+/// Fail with the last lines of the container output shown unless it becomes healthy within `timeout`.
+pub async fn require_healthy(
+    log: &Logger,
+    docker: &Docker,
+    container: &Container<'_>,
+    timeout: Duration,
+) -> Result<(), String> {
+    if let Err(e) = container.wait_healthy(timeout).await {
+        log.section("Recent logs");
+        Logs::new(log, docker, container.container_name())
+            .print(false, Some(RECENT_LOG_LINES))
+            .await?;
+        return Err(e);
+    }
+    log.step(&format!("{} is healthy", container.container_name()));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn saved_skips_lines_sent_again() {
+        let mut saved = Saved::default();
+        assert!(saved.insert("2024-05-01T10:00:00.100000000Z", "stdout one"));
+        assert!(saved.insert("2024-05-01T10:00:00.200000000Z", "stdout two"));
+        assert_eq!(saved.since(), 1714557600);
+
+        // Following again from the start of the second
+        assert!(!saved.insert("2024-05-01T10:00:00.100000000Z", "stdout one"));
+        assert!(!saved.insert("2024-05-01T10:00:00.200000000Z", "stdout two"));
+        assert!(saved.insert("2024-05-01T10:00:00.300000000Z", "stdout three"));
+    }
+
+    #[test]
+    fn saved_keeps_lines_with_the_same_timestamp() {
+        let mut saved = Saved::default();
+        assert!(saved.insert("2024-05-01T10:00:00.1Z", "stdout one"));
+        assert!(saved.insert("2024-05-01T10:00:00.1Z", "stderr one"));
+        assert!(saved.insert("2024-05-01T10:00:00.1Z", "stdout two"));
+        assert!(!saved.insert("2024-05-01T10:00:00.1Z", "stderr one"));
+    }
+
+    #[test]
+    fn saved_compares_timestamps_not_strings() {
+        let mut saved = Saved::default();
+        // Docker trims trailing zeros, as strings the later line sorts first
+        assert!(saved.insert("2024-05-01T10:00:00Z", "stdout one"));
+        assert!(saved.insert("2024-05-01T10:00:00.5Z", "stdout two"));
+        assert!(!saved.insert("2024-05-01T10:00:00.25Z", "stdout three"));
+
+        let mut saved = Saved::default();
+        assert!(saved.insert("2024-05-01T12:00:00.5+02:00", "stdout one"));
+        assert!(!saved.insert("2024-05-01T10:00:00.5Z", "stdout one"));
+    }
+
+    #[test]
+    fn saved_keeps_lines_without_a_timestamp() {
+        let mut saved = Saved::default();
+        assert!(saved.insert("", "stdout one"));
+        assert!(saved.insert("", "stdout one"));
+        assert_eq!(saved.since(), 0);
+    }
+}
@@ -0,0 +1,290 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use bollard::container::{Config, CreateContainerOptions, RemoveContainerOptions, StartContainerOptions};
+use bollard::models::{HostConfig, PortBinding, RestartPolicy, RestartPolicyNameEnum};
+use bollard::Docker;
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::container::{Container, Role, APP_LABEL, ROLE_LABEL};
+use crate::image::Image;
+use crate::logger::Logger;
+use crate::model::{publish_address, RukuConfig};
+use crate::probe::quote;
+use crate::store;
+
+/// Image of the maintenance container, it needs `nc`.
+const MAINTENANCE_IMAGE: &str = "busybox:stable";
+/// Port the maintenance page is served on inside its container.
+const MAINTENANCE_PORT: u16 = 8080;
+const DEFAULT_MESSAGE: &str = "We are doing some maintenance and will be right back.";
+
+/// What maintenance mode replaced, persisted so `maintenance:off` can put it back from any shell and
+/// after a reboot.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MaintenanceState {
+    pub enabled_at: DateTime<Utc>,
+    /// When maintenance was meant to end, nothing turns it off by itself.
+    pub until: Option<DateTime<Utc>>,
+    /// Id of the app container that was stopped for the maintenance.
+    pub container_id: String,
+    pub container_name: String,
+    /// Whether the app container was running, a stopped one stays stopped when maintenance ends.
+    pub was_running: bool,
+    /// The container serving the maintenance page on the app's port.
+    pub maintenance_container: String,
+    pub host_ip: Option<String>,
+    pub host_port: u16,
+}
+
+impl MaintenanceState {
+    pub const FILE_NAME: &'static str = "maintenance.json";
+
+    pub fn read(state_dir: &Path) -> Option<MaintenanceState> {
+        let content = fs::read_to_string(state_dir.join(Self::FILE_NAME)).ok()?;
+        serde_json::from_str(&content).ok()
+    }
+
+    /// How long maintenance mode has been on and when it was meant to end, for `ruku status`.
+    pub fn describe(&self, now: DateTime<Utc>) -> String {
+        let since = format!("since {}", self.enabled_at.format("%Y-%m-%d %H:%M:%S UTC"));
+        match self.until {
+            Some(until) if until < now => format!(
+                "{}, it was meant to end at {}",
+                since,
+                until.format("%Y-%m-%d %H:%M:%S UTC")
+            ),
+            Some(until) => format!("{} until {}", since, until.format("%Y-%m-%d %H:%M:%S UTC")),
+            None => since,
+        }
+    }
+}
+
+/// Swaps the app container for a static maintenance page on the same port and back.
+pub struct Maintenance<'a> {
+    log: &'a Logger,
+    name: &'a str,
+    docker: &'a Docker,
+    config: &'a RukuConfig,
+    container: &'a Container<'a>,
+    state_path: PathBuf,
+}
+
+impl<'a> Maintenance<'a> {
+    pub fn new(
+        log: &'a Logger,
+        name: &'a str,
+        docker: &'a Docker,
+        config: &'a RukuConfig,
+        container: &'a Container<'a>,
+        state_dir: &Path,
+    ) -> Maintenance<'a> {
+        Maintenance {
+            log,
+            name,
+            docker,
+            config,
+            container,
+            state_path: state_dir.join(MaintenanceState::FILE_NAME),
+        }
+    }
+
+    /// Stop the app container and serve a 503 page with `message` on its port until `off`.
+    pub async fn on(&self, duration: Option<Duration>, message: Option<&str>) -> Result<(), String> {
+        if let Some(state) = self.state() {
+            return Err(format!(
+                "{} is in maintenance mode already, {}",
+                self.name,
+                state.describe(Utc::now())
+            ));
+        }
+        if !self.config.port.is_published() {
+            return Err(format!(
+                "Maintenance mode needs a published port, {} sets no port in ruku.yml",
+                self.name
+            ));
+        }
+        if !self.config.network_mode.publishes_ports() {
+            return Err(format!(
+                "Maintenance mode needs a published port, {} runs with network_mode {}",
+                self.name, self.config.network_mode
+            ));
+        }
+        let Some(summary) = self.container.get().await? else {
+            return Err(format!("{} is not deployed", self.name));
+        };
+
+        let enabled_at = Utc::now();
+        let until = duration.map(|duration| enabled_at + duration);
+        let state = MaintenanceState {
+            enabled_at,
+            until,
+            container_id: summary.id.clone().unwrap_or_default(),
+            container_name: self.container.container_name().to_string(),
+            was_running: summary.state.as_deref() == Some("running"),
+            maintenance_container: format!("{}-maintenance", self.container.container_name()),
+            host_ip: publish_address(self.config.port.host_ip.or(self.config.bind_ip)),
+            host_port: self.config.port.host_port,
+        };
+        // Saved before anything changes, so an interrupted switch can still be undone with `off`
+        self.save_state(&state)?;
+
+        if state.was_running {
+            self.container.suspend().await?;
+        }
+        let image = Image::new(self.log, self.docker);
+        if !image.exists(MAINTENANCE_IMAGE).await {
+            image.pull(MAINTENANCE_IMAGE).await?;
+        }
+        if let Err(e) = self.start_page(&state, message.unwrap_or(DEFAULT_MESSAGE)).await {
+            self.log
+                .warn(&format!("Failed to start the maintenance container: {}", e));
+            self.restore(&state).await?;
+            return Err("Maintenance mode was not turned on, the app was put back as it was".to_string());
+        }
+
+        let until = until
+            .map(|until| format!(" until {}", until.format("%Y-%m-%d %H:%M:%S UTC")))
+            .unwrap_or_default();
+        self.log.step(&format!(
+            "{} is in maintenance mode{}, port {} serves the maintenance page",
+            self.name, until, state.host_port
+        ));
+        self.log.step(&format!(
+            "Run `ruku maintenance:off {}` to bring the app back",
+            self.name
+        ));
+        Ok(())
+    }
+
+    /// Remove the maintenance page and start the app container again if it was running before.
+    pub async fn off(&self) -> Result<(), String> {
+        let Some(state) = self.state() else {
+            return Err(format!("{} is not in maintenance mode", self.name));
+        };
+        self.restore(&state).await?;
+        self.log.step(&format!(
+            "{} is out of maintenance mode after {} minutes",
+            self.name,
+            (Utc::now() - state.enabled_at).num_minutes()
+        ));
+        Ok(())
+    }
+
+    async fn restore(&self, state: &MaintenanceState) -> Result<(), String> {
+        let options = RemoveContainerOptions {
+            force: true,
+            ..Default::default()
+        };
+        match self
+            .docker
+            .remove_container(&state.maintenance_container, Some(options))
+            .await
+        {
+            Ok(_) => self.log.step(&format!(
+                "Removed maintenance container {}",
+                state.maintenance_container
+            )),
+            // Never started, or removed by hand
+            Err(bollard::errors::Error::DockerResponseServerError { status_code: 404, .. }) => {}
+            Err(e) => return Err(format!("Failed to remove the maintenance container: {}", e)),
+        }
+        if state.was_running {
+            self.docker
+                .start_container(&state.container_id, None::<StartContainerOptions<String>>)
+                .await
+                .map_err(|e| format!("Failed to start {} again: {}", state.container_name, e))?;
+            self.log.step(&format!("Started container {}", state.container_name));
+        }
+        if self.state_path.exists() {
+            fs::remove_file(&self.state_path).map_err(|e| format!("Error removing maintenance state: {}", e))?;
+        }
+        Ok(())
+    }
+
+    /// Create and start the container answering every request with a 503 and the message.
+    async fn start_page(&self, state: &MaintenanceState, message: &str) -> Result<(), bollard::errors::Error> {
+        let body = format!(
+            "<!doctype html><title>Maintenance</title><h1>Be right back</h1><p>{}</p>\n",
+            escape_html(message)
+        );
+        let retry_after = state
+            .until
+            .map(|until| format!("Retry-After: {}\r\n", (until - state.enabled_at).num_seconds().max(0)))
+            .unwrap_or_default();
+        let response = format!(
+            "HTTP/1.1 503 Service Unavailable\r\nContent-Type: text/html; charset=utf-8\r\n{}\
+             Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+            retry_after,
+            body.len(),
+            body
+        );
+        let script = format!(
+            "while true; do printf '%s' {} | nc -l -p {} -w 2; done",
+            quote(&response),
+            MAINTENANCE_PORT
+        );
+
+        let port_key = format!("{}/tcp", MAINTENANCE_PORT);
+        let config = Config {
+            image: Some(MAINTENANCE_IMAGE.to_string()),
+            cmd: Some(vec!["sh".to_string(), "-c".to_string(), script]),
+            exposed_ports: Some(HashMap::from([(port_key.clone(), HashMap::new())])),
+            labels: Some(HashMap::from([
+                (APP_LABEL.to_string(), self.name.to_string()),
+                (ROLE_LABEL.to_string(), Role::Maintenance.as_str().to_string()),
+            ])),
+            host_config: Some(HostConfig {
+                port_bindings: Some(HashMap::from([(
+                    port_key,
+                    Some(vec![PortBinding {
+                        host_ip: state.host_ip.clone(),
+                        host_port: Some(state.host_port.to_string()),
+                    }]),
+                )])),
+                // Comes back after a reboot, like the app it stands in for
+                restart_policy: Some(RestartPolicy {
+                    name: Some(RestartPolicyNameEnum::UNLESS_STOPPED),
+                    maximum_retry_count: None,
+                }),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let options = CreateContainerOptions {
+            name: state.maintenance_container.as_str(),
+            platform: None,
+        };
+        self.docker.create_container(Some(options), config).await?;
+        self.docker
+            .start_container(&state.maintenance_container, None::<StartContainerOptions<String>>)
+            .await?;
+        self.log.step(&format!(
+            "Started maintenance container {}",
+            state.maintenance_container
+        ));
+        Ok(())
+    }
+
+    fn state(&self) -> Option<MaintenanceState> {
+        let content = fs::read_to_string(&self.state_path).ok()?;
+        serde_json::from_str(&content).ok()
+    }
+
+    fn save_state(&self, state: &MaintenanceState) -> Result<(), String> {
+        store::write_atomic(
+            &self.state_path,
+            serde_json::to_string_pretty(state).unwrap().as_bytes(),
+        )
+        .map_err(|e| format!("Error writing maintenance state: {}", e))
+    }
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
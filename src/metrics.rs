@@ -0,0 +1,243 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use bollard::models::ContainerSummary;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::container::{APP_LABEL, ROLE_LABEL};
+use crate::logger::Logger;
+use crate::store;
+
+/// Deploy counters and timings of an app, stored as JSON in the app state directory.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct DeployMetrics {
+    pub success: u64,
+    pub failure: u64,
+    /// Seconds each stage of the last successful deploy took.
+    #[serde(default)]
+    pub stages: BTreeMap<String, f64>,
+    pub duration: Option<f64>,
+    pub finished_at: Option<DateTime<Utc>>,
+    /// When the deploy that has not finished yet started, none between deploys.
+    #[serde(default)]
+    pub started_at: Option<DateTime<Utc>>,
+}
+
+impl DeployMetrics {
+    /// The metrics in the app state directory, zero when there are none.
+    pub fn read(state_dir: &Path) -> DeployMetrics {
+        fs::read_to_string(state_dir.join(Metrics::FILE_NAME))
+            .ok()
+            .and_then(|content| serde_json::from_str(&content).ok())
+            .unwrap_or_default()
+    }
+}
+
+pub struct Metrics<'a> {
+    log: &'a Logger,
+    path: PathBuf,
+}
+
+impl<'a> Metrics<'a> {
+    pub const FILE_NAME: &'static str = "metrics.json";
+
+    pub fn new(log: &'a Logger, state_dir: &Path) -> Metrics<'a> {
+        Metrics {
+            log,
+            path: state_dir.join(Self::FILE_NAME),
+        }
+    }
+
+    pub fn load(&self) -> DeployMetrics {
+        DeployMetrics::read(self.path.parent().unwrap())
+    }
+
+    /// Mark a deploy as started. A deploy that exits halfway never gets the chance to record its own
+    /// failure, one still marked when the next starts is counted as failed then. The counters only ever
+    /// go up, as Prometheus expects of a counter.
+    pub fn begin(&self) {
+        let mut metrics = self.load();
+        if metrics.started_at.is_some() {
+            metrics.failure += 1;
+        }
+        metrics.started_at = Some(Utc::now());
+        self.save(&metrics);
+    }
+
+    pub fn finish(&self, stages: BTreeMap<String, f64>, duration: f64) {
+        let mut metrics = self.load();
+        metrics.started_at = None;
+        metrics.success += 1;
+        metrics.stages = stages;
+        metrics.duration = Some(duration);
+        metrics.finished_at = Some(Utc::now());
+        self.save(&metrics);
+    }
+
+    fn save(&self, metrics: &DeployMetrics) {
+        // Metrics are best effort, failing to write them never fails a deploy
+        if let Err(e) = store::write_atomic(&self.path, serde_json::to_string_pretty(metrics).unwrap().as_bytes()) {
+            self.log.warn(&format!("Error writing deploy metrics: {}", e));
+        }
+    }
+}
+
+/// Metrics of one app for the exposition output.
+pub struct AppMetrics {
+    pub app: String,
+    pub deploys: DeployMetrics,
+    pub up: bool,
+}
+
+/// The apps with deploy metrics in `state_root`.
+pub fn recorded_apps(state_root: &Path) -> Vec<String> {
+    fs::read_dir(state_root)
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok())
+                .filter(|entry| entry.path().join(Metrics::FILE_NAME).exists())
+                .map(|entry| entry.file_name().to_string_lossy().to_string())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// The metrics of `apps`, up when their stable container is among the `live` ones.
+pub fn collect(state_root: &Path, apps: Vec<String>, live: &[ContainerSummary]) -> Vec<AppMetrics> {
+    let mut metrics: Vec<AppMetrics> = apps
+        .into_iter()
+        .map(|app| {
+            let up = live.iter().any(|summary| {
+                let labels = summary.labels.clone().unwrap_or_default();
+                labels.get(APP_LABEL) == Some(&app)
+                    && labels.get(ROLE_LABEL).map(String::as_str) == Some("stable")
+                    && summary.state.as_deref() == Some("running")
+            });
+            let deploys = DeployMetrics::read(&state_root.join(&app));
+            AppMetrics { app, deploys, up }
+        })
+        .collect();
+    metrics.sort_by(|a, b| a.app.cmp(&b.app));
+    metrics
+}
+
+/// Render the metrics in the Prometheus text exposition format.
+pub fn render(apps: &[AppMetrics]) -> String {
+    let mut out = String::new();
+
+    out.push_str("# HELP ruku_deploy_duration_seconds Duration of the last successful deploy, by stage.\n");
+    out.push_str("# TYPE ruku_deploy_duration_seconds gauge\n");
+    for app in apps {
+        let name = escape_label(&app.app);
+        for (stage, seconds) in &app.deploys.stages {
+            let _ = writeln!(
+                out,
+                "ruku_deploy_duration_seconds{{app=\"{}\",stage=\"{}\"}} {}",
+                name,
+                escape_label(stage),
+                seconds
+            );
+        }
+        if let Some(duration) = app.deploys.duration {
+            let _ = writeln!(
+                out,
+                "ruku_deploy_duration_seconds{{app=\"{}\",stage=\"total\"}} {}",
+                name, duration
+            );
+        }
+    }
+
+    out.push_str("# HELP ruku_deploy_total Deploys by result, a failure counts once the next deploy starts.\n");
+    out.push_str("# TYPE ruku_deploy_total counter\n");
+    for app in apps {
+        let name = escape_label(&app.app);
+        for (result, count) in [("success", app.deploys.success), ("failure", app.deploys.failure)] {
+            let _ = writeln!(
+                out,
+                "ruku_deploy_total{{app=\"{}\",result=\"{}\"}} {}",
+                name, result, count
+            );
+        }
+    }
+
+    out.push_str("# HELP ruku_container_up Whether the app container is running.\n");
+    out.push_str("# TYPE ruku_container_up gauge\n");
+    for app in apps {
+        let _ = writeln!(
+            out,
+            "ruku_container_up{{app=\"{}\"}} {}",
+            escape_label(&app.app),
+            u8::from(app.up)
+        );
+    }
+    out
+}
+
+/// Escape a label value, the exposition format only escapes backslash, double quote and newline.
+pub fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counters_never_go_down() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = Logger::new();
+        let metrics = Metrics::new(&log, dir.path());
+
+        metrics.begin();
+        assert_eq!((metrics.load().success, metrics.load().failure), (0, 0));
+        metrics.finish(BTreeMap::from([("build".to_string(), 2.0)]), 3.0);
+        assert_eq!((metrics.load().success, metrics.load().failure), (1, 0));
+
+        // Exits halfway, counted as failed when the next one starts
+        metrics.begin();
+        assert_eq!((metrics.load().success, metrics.load().failure), (1, 0));
+        metrics.begin();
+        assert_eq!((metrics.load().success, metrics.load().failure), (1, 1));
+        metrics.finish(BTreeMap::new(), 1.0);
+        assert_eq!((metrics.load().success, metrics.load().failure), (2, 1));
+        assert_eq!(metrics.load().started_at, None);
+    }
+
+    #[test]
+    fn render_exposes_counters_and_gauges() {
+        let apps = [AppMetrics {
+            app: "shop".to_string(),
+            deploys: DeployMetrics {
+                success: 3,
+                failure: 1,
+                stages: BTreeMap::from([("build".to_string(), 1.5)]),
+                duration: Some(4.0),
+                ..Default::default()
+            },
+            up: true,
+        }];
+        let out = render(&apps);
+        assert!(out.contains("# TYPE ruku_deploy_total counter\n"));
+        assert!(out.contains("ruku_deploy_total{app=\"shop\",result=\"success\"} 3\n"));
+        assert!(out.contains("ruku_deploy_total{app=\"shop\",result=\"failure\"} 1\n"));
+        assert!(out.contains("ruku_deploy_duration_seconds{app=\"shop\",stage=\"build\"} 1.5\n"));
+        assert!(out.contains("ruku_deploy_duration_seconds{app=\"shop\",stage=\"total\"} 4\n"));
+        assert!(out.contains("ruku_container_up{app=\"shop\"} 1\n"));
+    }
+
+    #[test]
+    fn collect_reads_the_recorded_apps() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = Logger::new();
+        Metrics::new(&log, &dir.path().join("shop")).begin();
+        fs::create_dir(dir.path().join("blog")).unwrap();
+
+        let apps = collect(dir.path(), recorded_apps(dir.path()), &[]);
+        assert_eq!(apps.len(), 1);
+        assert_eq!(apps[0].app, "shop");
+        assert!(!apps[0].up);
+    }
+}
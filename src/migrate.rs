@@ -0,0 +1,145 @@
+use std::path::Path;
+
+use bollard::models::ContainerSummary;
+use chrono::{DateTime, Utc};
+
+use crate::container::{deployed_version, get_container_name, schema_version, SCHEMA_VERSION};
+use crate::history::{Deployment, History};
+use crate::logger::Logger;
+
+/// Catches up on what an older ruku left behind for a container, so it is managed like one created by
+/// this version instead of being treated as foreign or recreated for no reason.
+pub struct Migration<'a> {
+    log: &'a Logger,
+    state_dir: &'a Path,
+}
+
+impl<'a> Migration<'a> {
+    pub fn new(log: &'a Logger, state_dir: &'a Path) -> Migration<'a> {
+        Migration { log, state_dir }
+    }
+
+    /// Migrate the state of `container`, returning what was done. Renaming an unprefixed container
+    /// happens when it is looked up, labels can't be changed on a container and are brought up to date
+    /// by the next deploy.
+    pub fn run(&self, container: &ContainerSummary) -> Result<Vec<String>, String> {
+        let schema = schema_version(container);
+        if schema >= SCHEMA_VERSION {
+            return Ok(vec![]);
+        }
+
+        let mut migrated = vec![];
+        let history = History::new(self.log, self.state_dir);
+        if history.load()?.is_empty() {
+            // Before the history existed the container itself was the only record of the deploy
+            if let Some(version) = deployed_version(container) {
+                let created = container
+                    .created
+                    .and_then(|created| DateTime::from_timestamp(created, 0))
+                    .unwrap_or_else(Utc::now);
+                let image = container.image.clone().unwrap_or_default();
+                let mut deployment = Deployment::new(&Some(version.clone()), &image, created);
+                deployment.finished_at = created;
+                history.record(deployment)?;
+                migrated.push(format!("backfilled the deploy history with version {}", version));
+            }
+        }
+        migrated.push(match schema {
+            0 => "labels are added on the next deploy".to_string(),
+            _ => "the schema label is added on the next deploy".to_string(),
+        });
+
+        self.log.step(&format!(
+            "Container {} was created by an older ruku (schema {} of {}), {}",
+            get_container_name(container).unwrap_or_default(),
+            schema,
+            SCHEMA_VERSION,
+            migrated.join(", ")
+        ));
+        Ok(migrated)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::container::{APP_LABEL, SCHEMA_LABEL, VERSION_LABEL};
+
+    fn container(labels: &[(&str, &str)]) -> ContainerSummary {
+        ContainerSummary {
+            names: Some(vec!["/ruku-shop".to_string()]),
+            image: Some("shop:1.3.0".to_string()),
+            created: Some(1_700_000_000),
+            labels: Some(
+                labels
+                    .iter()
+                    .map(|(key, value)| (key.to_string(), value.to_string()))
+                    .collect::<HashMap<_, _>>(),
+            ),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn schema_follows_the_labels() {
+        let schema = SCHEMA_VERSION.to_string();
+        assert_eq!(schema_version(&container(&[])), 0);
+        assert_eq!(schema_version(&container(&[(APP_LABEL, "shop")])), 1);
+        assert_eq!(
+            schema_version(&container(&[(APP_LABEL, "shop"), (SCHEMA_LABEL, &schema)])),
+            SCHEMA_VERSION
+        );
+    }
+
+    #[test]
+    fn current_containers_need_no_migration() {
+        let log = Logger::new();
+        let state_dir = tempfile::tempdir().unwrap();
+        let schema = SCHEMA_VERSION.to_string();
+        let current = container(&[(APP_LABEL, "shop"), (SCHEMA_LABEL, &schema)]);
+        assert!(Migration::new(&log, state_dir.path()).run(&current).unwrap().is_empty());
+        assert!(History::new(&log, state_dir.path()).load().unwrap().is_empty());
+    }
+
+    #[test]
+    fn history_is_backfilled_from_an_unlabeled_container() {
+        let log = Logger::new();
+        let state_dir = tempfile::tempdir().unwrap();
+        let migrated = Migration::new(&log, state_dir.path()).run(&container(&[])).unwrap();
+        assert_eq!(
+            migrated,
+            [
+                "backfilled the deploy history with version 1.3.0",
+                "labels are added on the next deploy"
+            ]
+        );
+        let history = History::new(&log, state_dir.path()).load().unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].version.as_deref(), Some("1.3.0"));
+        assert_eq!(history[0].image, "shop:1.3.0");
+        assert_eq!(history[0].finished_at.timestamp(), 1_700_000_000);
+    }
+
+    #[test]
+    fn an_existing_history_is_left_alone() {
+        let log = Logger::new();
+        let state_dir = tempfile::tempdir().unwrap();
+        let history = History::new(&log, state_dir.path());
+        history
+            .record(Deployment::new(&Some("1.2.0".to_string()), "shop:1.2.0", Utc::now()))
+            .unwrap();
+
+        let labeled = container(&[(APP_LABEL, "shop"), (VERSION_LABEL, "1.3.0")]);
+        let migrated = Migration::new(&log, state_dir.path()).run(&labeled).unwrap();
+        assert_eq!(migrated, ["the schema label is added on the next deploy"]);
+        let versions: Vec<_> = history
+            .load()
+            .unwrap()
+            .into_iter()
+            .map(|deployment| deployment.version)
+            .collect();
+        assert_eq!(versions, [Some("1.2.0".to_string())]);
+    }
+}
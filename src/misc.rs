@@ -1,9 +1,18 @@
 pub fn get_image_name_with_version(image: &str, version: &Option<String>) -> String {
-    let mut image_version = "latest";
-    if let Some(v) = version {
-        image_version = v;
-    }
-    format!("{}:{}", image, image_version)
+    format!("{}:{}", image, get_version(version))
+}
+
+/// Image reference inside a registry namespace, e.g. `registry.example.com/team/app:1.2.0`.
+pub fn get_registry_image_name(registry: &str, image: &str, version: &Option<String>) -> String {
+    format!(
+        "{}/{}",
+        registry.trim_end_matches('/'),
+        get_image_name_with_version(image, version)
+    )
+}
+
+pub fn get_version(version: &Option<String>) -> &str {
+    version.as_deref().unwrap_or("latest")
 }
 
 pub fn sanitize_app_name(app: &str) -> String {
@@ -14,3 +23,130 @@ pub fn sanitize_app_name(app: &str) -> String {
         .trim_end()
         .to_string()
 }
+
+/// Longest app name accepted, leaving room for the container prefix and the `-canary` suffix.
+pub const MAX_APP_NAME_LENGTH: usize = 48;
+
+/// App names end up in container, image and volume names, so they are held to the charset all of those allow.
+pub fn validate_app_name(app: &str) -> Result<(), String> {
+    let valid_chars = app
+        .chars()
+        .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-');
+    let valid_edges = !app.starts_with('-') && !app.ends_with('-');
+    if app.is_empty() || app.len() > MAX_APP_NAME_LENGTH || !valid_chars || !valid_edges {
+        return Err(format!(
+            "Invalid app name '{}', use 1 to {} lowercase letters, digits and dashes, not starting or ending with a dash",
+            app, MAX_APP_NAME_LENGTH
+        ));
+    }
+    Ok(())
+}
+
+/// The tag of an image reference such as `registry:5000/app:1.2.0`, if it has one.
+pub fn get_image_tag(image: &str) -> Option<String> {
+    let (_, tag) = image.rsplit_once(':')?;
+    if tag.contains('/') {
+        return None;
+    }
+    Some(tag.to_string())
+}
+
+/// Describe the running version against the configured one, e.g. "running 1.3.2, config wants 1.4.0".
+pub fn describe_version_drift(live: Option<&str>, configured: &str) -> String {
+    match live {
+        Some(live) if live == configured => format!("running {}, up to date with config", live),
+        Some(live) => format!("running {}, config wants {}", live, configured),
+        None => format!("running an unknown version, config wants {}", configured),
+    }
+}
+
+/// Tags listed when a version is not found.
+const SUGGESTED_TAGS: usize = 5;
+
+/// Levenshtein distance between two strings, counted in characters.
+pub fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a_char != *b_char);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+/// The tags closest to `wanted` first, ties in name order, without duplicates.
+pub fn rank_tags(wanted: &str, tags: &[String]) -> Vec<String> {
+    let mut ranked: Vec<(usize, &String)> = tags.iter().map(|tag| (edit_distance(wanted, tag), tag)).collect();
+    ranked.sort();
+    ranked.dedup_by(|a, b| a.1 == b.1);
+    ranked.into_iter().map(|(_, tag)| tag.clone()).collect()
+}
+
+/// Explain a missing version, e.g. "version '1.4.0' not found, did you mean '1.4.1'? available: 1.4.1, latest".
+/// Only a close tag is suggested, off by at most a third of its length.
+pub fn describe_missing_tag(wanted: &str, tags: &[String]) -> String {
+    let ranked = rank_tags(wanted, tags);
+    let mut message = format!("version '{}' not found", wanted);
+    match ranked.first() {
+        None => message.push_str(", no other versions are available"),
+        Some(closest) => {
+            if edit_distance(wanted, closest) <= (wanted.chars().count() / 3).max(1) {
+                message.push_str(&format!(", did you mean '{}'?", closest));
+            } else {
+                message.push(',');
+            }
+            let available: Vec<_> = ranked.iter().take(SUGGESTED_TAGS).map(String::as_str).collect();
+            message.push_str(&format!(" available: {}", available.join(", ")));
+        }
+    }
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tags(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn edit_distance_counts_characters() {
+        assert_eq!(edit_distance("1.4.0", "1.4.0"), 0);
+        assert_eq!(edit_distance("1.4.0", "1.4.1"), 1);
+        assert_eq!(edit_distance("", "abc"), 3);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("é", "e"), 1);
+    }
+
+    #[test]
+    fn tags_are_ranked_by_distance_then_name() {
+        let ranked = rank_tags("1.4.0", &tags(&["latest", "1.4.1", "1.3.9", "1.4.1", "1.4.2"]));
+        assert_eq!(ranked, ["1.4.1", "1.4.2", "1.3.9", "latest"]);
+    }
+
+    #[test]
+    fn only_a_close_tag_is_suggested() {
+        assert_eq!(
+            describe_missing_tag("1.4.0", &tags(&["latest", "1.4.1", "1.3.9"])),
+            "version '1.4.0' not found, did you mean '1.4.1'? available: 1.4.1, 1.3.9, latest"
+        );
+        assert_eq!(
+            describe_missing_tag("1.4.0", &tags(&["latest", "stable"])),
+            "version '1.4.0' not found, available: latest, stable"
+        );
+        assert_eq!(
+            describe_missing_tag("1.4.0", &[]),
+            "version '1.4.0' not found, no other versions are available"
+        );
+        let many = tags(&["1.0", "1.1", "1.2", "1.3", "1.4", "1.5"]);
+        assert_eq!(
+            describe_missing_tag("1.9", &many),
+            "version '1.9' not found, did you mean '1.0'? available: 1.0, 1.1, 1.2, 1.3, 1.4"
+        );
+    }
+}
@@ -1,18 +1,1787 @@
-use port_selector::is_free;
-use serde::Deserialize;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::net::IpAddr;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::LazyLock;
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
 use validator::{Validate, ValidationError};
 
-#[derive(Debug, Validate, Deserialize)]
+use crate::container::RESERVED_LABEL_PREFIX;
+use crate::cpuset;
+use crate::executor::DEFAULT_CONCURRENCY;
+use crate::observability::{is_json, DATADOG_ANNOTATION_PREFIX};
+use crate::secrets::SecretRef;
+use crate::units;
+use crate::volume::{is_host_path, resolve_host_path, split_source};
+
+pub use crate::freeze::{FreezeTime, FreezeWindow, Zone};
+pub use crate::policy::PolicyConfig;
+pub use crate::volume::{VolumeSource, VolumeSpec};
+
+#[derive(Debug, Validate, Serialize, Deserialize)]
+#[validate(schema(function = "validate_strategy"))]
+#[validate(schema(function = "validate_network"))]
+#[validate(schema(function = "validate_app_type"))]
+#[validate(schema(function = "validate_file_targets"))]
+#[validate(schema(function = "validate_fleet"))]
+#[validate(schema(function = "validate_namespaces"))]
 pub struct RukuConfig {
-    #[validate(range(min = 1024, max = 65535), custom(function = "validate_port"))]
-    pub port: u16,
+    /// Port the app listens on, `8080`, `27015/udp`, `53/tcp+udp` for several protocols on one number, or
+    /// `127.0.0.1:8080:3000` to publish container port 3000 on host port 8080 of one address. Left out or
+    /// `image`, the port the image exposes is used and not published, `auto` publishes it on an assigned
+    /// host port.
+    #[serde(default)]
+    #[validate(custom(function = "validate_app_port"))]
+    pub port: PortConfig,
+    /// With a rootless daemon, which can't publish ports below 1024, publish a low host port 8000 higher,
+    /// e.g. 80 on 10080, and print how to forward it on the host instead of refusing the deploy.
+    #[serde(default)]
+    pub low_port_redirect: bool,
+    /// Host ports an `auto` port is picked from, e.g. `20000-30000`.
+    #[serde(default)]
+    #[validate(custom(function = "validate_port_range"))]
+    pub auto_port_range: PortRange,
+    /// Named range of host ports in the host config the app's ports must stay in, e.g. `team-a`. The host
+    /// config may hold the app to a range of its own, which this can't change.
+    pub port_reservation: Option<String>,
+    /// Prefix of the container names, keeps them apart from containers ruku didn't create.
+    #[serde(default = "default_container_prefix")]
+    #[validate(custom(function = "validate_container_prefix"))]
+    pub container_prefix: String,
+    /// Host address ports are published on when the port doesn't name one, e.g. `127.0.0.1`, or `::` for every
+    /// IPv6 interface only. Unset, ports are published on both stacks the daemon has.
+    pub bind_ip: Option<IpAddr>,
+    /// `bridge` for the app's own network, `none` for no network at all, `host` for the network stack of
+    /// the host, or the name of an existing Docker network.
+    #[serde(default)]
+    pub network_mode: NetworkMode,
+    /// Create the app's network as internal, its containers reach each other but not the outside world.
+    #[serde(default)]
+    pub internal: bool,
+    /// IPC namespace: `private`, `shareable`, `none`, `host`, or `container:<name>` to join that of another
+    /// container. Docker's default when left out.
+    #[validate(custom(function = "validate_ipc_mode"))]
+    pub ipc: Option<String>,
+    /// PID namespace: `host`, or `container:<name>` to see the processes of another container, e.g. for a
+    /// profiler. The container's own when left out.
+    #[validate(custom(function = "validate_pid_mode"))]
+    pub pid: Option<String>,
+    /// UTS namespace, which holds the hostname: `host`, or the container's own when left out.
+    #[validate(custom(function = "validate_uts_mode"))]
+    pub uts: Option<String>,
+    /// Allow `host` for `ipc`, `pid` or `uts`. The app then reaches the shared memory, processes or hostname
+    /// of the host, which a compromised app can use against it.
+    #[serde(default)]
+    pub acknowledge_host_namespaces: bool,
+    /// Times commands that change the app are refused in, unless `--override-freeze` gives a reason. Each
+    /// has a `name`, optionally who set it up as `by`, and either `from` and `until`, e.g.
+    /// `2026-11-27T00:00:00+01:00`, or `start` and `end` wall times like `18:00` with optional `days` and
+    /// a `timezone` of `local`, `UTC` or an offset.
+    #[serde(default)]
+    pub freeze: Vec<FreezeWindow>,
+    /// Hostnames the ruku proxy routes to the app on port 80, e.g. `example.com` or `*.example.com`. Only
+    /// used once `ruku proxy:up` runs the proxy on the host.
+    #[serde(default)]
+    #[validate(custom(function = "validate_domains"))]
+    pub domains: Vec<String>,
     #[validate(length(min = 1, max = 20))]
     pub version: Option<String>,
+    /// HTTPS URL of a shared config this one goes on top of, fetched by `run` and cached in the app
+    /// state directory.
+    pub include: Option<String>,
+    /// A local fragment file this one goes on top of, relative to ruku.yml, e.g. `../shared/service.yml`
+    /// for what many apps have in common. The fragment may extend one more.
+    pub extends: Option<String>,
+    /// The context of `~/.ruku/config.yml` whose daemon the app is deployed to, `--context` can't
+    /// override it.
+    pub context: Option<String>,
+    /// Contexts of `~/.ruku/config.yml` the app runs on side by side, e.g. behind DNS round-robin. `run`,
+    /// `status`, `stop` and `undo` go through them in turn, `--host` picks one.
+    #[serde(default)]
+    #[validate(custom(function = "validate_hosts"))]
+    pub hosts: Vec<String>,
+    /// How a new version replaces the running one, `deploy_strategy` in older ruku.yml files.
+    #[serde(default, alias = "deploy_strategy")]
+    pub strategy: DeployStrategy,
+    #[validate(nested)]
+    pub canary: Option<CanaryConfig>,
+    /// `app` for an image built from the project, `static` for the files of `static.dir` served by a web
+    /// server image ruku brings.
+    #[serde(default, rename = "type")]
+    pub app_type: AppType,
+    /// The site of a `type: static` app.
+    #[serde(rename = "static")]
+    #[validate(nested)]
+    pub static_site: Option<StaticConfig>,
+    #[validate(nested)]
+    pub build: Option<BuildConfig>,
+    /// Image tarball to deploy instead of building one, relative to the app directory, e.g. the output of
+    /// `nix build` or rules_oci. Both `docker save` and OCI archives are loaded, gzipped or not.
+    pub image_tarball: Option<String>,
+    /// Start images built for another architecture than the Docker host's even without qemu binfmt
+    /// emulation detected, e.g. when the daemon runs on another machine.
+    #[serde(default)]
+    pub allow_emulation: bool,
+    /// Scan the image for vulnerabilities after the build and stop the deploy on serious findings.
+    pub scan: Option<ScanConfig>,
+    /// Rules the image has to follow, checked before the container is replaced, on top of the policy of
+    /// `~/.ruku/config.yml`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub policy: Option<PolicyConfig>,
+    /// Record an SBOM of the deployed image, with syft when it is on PATH, see `ruku releases:sbom`.
+    #[serde(default)]
+    pub sbom: bool,
+    /// Readiness check used when waiting for the container to become healthy.
+    #[validate(nested)]
+    pub probe: Option<ProbeConfig>,
+    /// Limits on what the container may use of the host.
+    #[validate(nested)]
+    pub resources: Option<ResourcesConfig>,
+    /// HTTP checks run against the new container once it started, a failing check fails the deploy.
+    #[validate(nested)]
+    pub smoke: Option<SmokeConfig>,
+    /// Env vars read back from the new container once it started, the deploy fails when the app sees
+    /// other values than ruku set, e.g. because an entrypoint script overrides them.
+    #[validate(nested)]
+    pub verify_env: Option<VerifyEnvConfig>,
+    /// Command run in a one-off container before every start of the app, e.g. database migrations.
+    #[validate(nested)]
+    pub pre_start: Option<PreStartConfig>,
+    /// Containers that run next to the app on its network and share its lifecycle, e.g. a local cache.
+    #[serde(default)]
+    #[validate(nested, custom(function = "validate_sidecars"))]
+    pub sidecars: Vec<SidecarConfig>,
+    /// How many container operations run at the same time.
+    #[serde(default = "default_concurrency")]
+    #[validate(range(min = 1, max = 32))]
+    pub concurrency: usize,
+    /// Volumes mounted into the container, `name:/path` for a named volume or `./dir:/path` for a
+    /// host directory, with an optional `:ro`.
+    #[serde(default)]
+    #[validate(custom(function = "validate_volumes"))]
+    pub volumes: Vec<String>,
+    /// Fail the deploy instead of warning when an empty bind mounted host directory hides what the image
+    /// ships at its target.
+    #[serde(default)]
+    pub strict_mounts: bool,
+    /// Create missing host directories of bind mounts before the containers start, `true` or a mapping
+    /// with the `owner` (`uid[:gid]`) and `mode` (e.g. `"0750"`) they get.
+    #[serde(default)]
+    pub create_host_paths: HostPathsConfig,
+    /// Files rendered at deploy time and mounted read-only into the container, template path to container
+    /// path, e.g. `app.conf.tpl: /etc/app/app.conf`.
+    #[serde(default)]
+    #[validate(custom(function = "validate_templates"))]
+    pub templates: BTreeMap<String, String>,
+    /// Files mounted read-only into the container as they are written, container path to the content, or
+    /// to a mapping with the `content` or a local `source` file and the `mode`. Content may refer to
+    /// config fields such as `${version}` and to `${env.NAME}` of the environment ruku runs in.
+    #[serde(default)]
+    #[validate(custom(function = "validate_files"))]
+    pub files: BTreeMap<String, FileConfig>,
+    /// Extra labels on the containers, values may refer to config fields such as `${version}`. Keys under
+    /// `ruku.` are reserved.
+    #[serde(default)]
+    #[validate(custom(function = "validate_labels"))]
+    pub labels: BTreeMap<String, String>,
+    /// Env vars of the app read from a secret backend by reference, e.g.
+    /// `DB_PASSWORD: vault:kv/data/myapp#DB_PASSWORD` or `API_KEY: sops:secrets.enc.yaml#api_key`. Templates
+    /// and files refer to them as `secret.NAME`, masked wherever they are shown.
+    #[serde(default)]
+    #[validate(custom(function = "validate_secrets"))]
+    pub secrets: BTreeMap<String, String>,
+    /// Docker Hub mirror the sidecar images are pulled through instead of the mirrors of the daemon, e.g.
+    /// `mirror.example.com:5000`. The images are tagged with their usual names after the pull.
+    #[validate(custom(function = "validate_registry_mirror"))]
+    pub registry_mirror: Option<String>,
+    /// Labels and env log shippers and APMs discover the app by, e.g. `com.datadoghq.ad.logs` and
+    /// `OTEL_SERVICE_NAME`, generated from one place. `labels` override the generated labels.
+    #[validate(nested)]
+    pub observability: Option<ObservabilityConfig>,
+    /// Other ruku apps or containers that must be running, and healthy if they have a healthcheck,
+    /// before the app starts.
+    #[serde(default)]
+    #[validate(custom(function = "validate_depends_on"))]
+    pub depends_on: Vec<String>,
+    /// Seconds a deploy waits for the dependencies to become ready, by default they must be ready already.
+    /// Like the other timeouts, also takes a duration like `90s` or `1h30m`.
+    #[serde(default, deserialize_with = "units::seconds")]
+    #[validate(range(max = 3600))]
+    pub dependency_timeout: u64,
+    /// Seconds the whole deploy may take. When it runs over, the stage it is in is aborted and the new
+    /// version rolled back.
+    #[serde(default, deserialize_with = "units::optional_seconds")]
+    #[validate(range(min = 1))]
+    pub deploy_timeout: Option<u64>,
+    /// Seconds single stages of the deploy may take, by stage name, e.g. `health: 2m`.
+    #[serde(default, deserialize_with = "units::seconds_map")]
+    #[validate(custom(function = "validate_stage_timeouts"))]
+    pub stage_timeouts: BTreeMap<String, u64>,
+    /// Seconds `ruku stop` waits for open connections to the app to close before stopping it.
+    #[serde(default, deserialize_with = "units::seconds")]
+    #[validate(range(max = 3600))]
+    pub drain_period: u64,
+    /// Timezone of the app container as an IANA name, e.g. `Europe/Berlin`, set as `TZ`. `host` mounts
+    /// the timezone files of the host read-only instead.
+    #[validate(custom(function = "validate_timezone"))]
+    pub timezone: Option<String>,
+    /// Locale of the app container, set as `LANG`, e.g. `en_US.UTF-8`.
+    #[validate(custom(function = "validate_locale"))]
+    pub locale: Option<String>,
+    /// Signal that makes the app read its templates again, e.g. `SIGHUP`. When only rendered templates
+    /// changed, a deploy rewrites them and sends it instead of recreating the container.
+    #[validate(custom(function = "validate_reload_signal"))]
+    pub reload_signal: Option<String>,
+    /// Give the app container a pseudo-terminal, for images that only behave on one. Its output is then a
+    /// single stream, stderr can't be told apart from stdout.
+    #[serde(default)]
+    pub tty: bool,
+    /// Keep stdin of the app container open, for entrypoints that exit at the end of their input.
+    #[serde(default)]
+    pub stdin_open: bool,
+    /// Settings for the copies of the app `ruku preview` deploys from a branch.
+    pub preview: Option<PreviewConfig>,
+    /// The branch this config is deployed from as a preview, set by ruku and never read from ruku.yml.
+    #[serde(skip)]
+    pub preview_branch: Option<String>,
+}
+
+impl RukuConfig {
+    /// The namespaces the config sets a mode for, by field name: `ipc`, `pid` and `uts`.
+    pub fn namespaces(&self) -> Vec<(&'static str, &str)> {
+        [("ipc", &self.ipc), ("pid", &self.pid), ("uts", &self.uts)]
+            .into_iter()
+            .filter_map(|(field, mode)| mode.as_deref().map(|mode| (field, mode)))
+            .collect()
+    }
+
+    /// Make the host paths of bind mounts absolute, relative paths are relative to the app directory.
+    pub fn resolve_paths(&mut self, base: &Path) {
+        for volume in self.volumes.iter_mut() {
+            if let Some((source, rest)) = split_source(volume) {
+                if is_host_path(source) {
+                    *volume = format!("{}:{}", resolve_host_path(source, base).display(), rest);
+                }
+            }
+        }
+        for sidecar in self.sidecars.iter_mut() {
+            for volume in sidecar.volumes.iter_mut() {
+                if let Some((source, rest)) = split_source(volume) {
+                    if is_host_path(source) {
+                        *volume = format!("{}:{}", resolve_host_path(source, base).display(), rest);
+                    }
+                }
+            }
+        }
+        self.templates = std::mem::take(&mut self.templates)
+            .into_iter()
+            .map(|(source, target)| (resolve_host_path(&source, base).display().to_string(), target))
+            .collect();
+        for file in self.files.values_mut() {
+            if let FileSource::Path(path) = &mut file.source {
+                *path = resolve_host_path(path, base).display().to_string();
+            }
+        }
+    }
+
+    /// The parsed volume entries, entries that don't parse are rejected by validation.
+    pub fn volume_specs(&self) -> Vec<VolumeSpec> {
+        self.volumes.iter().filter_map(|v| VolumeSpec::parse(v).ok()).collect()
+    }
+
+    /// The volume entries of the app and its sidecars, which share the app's named volumes.
+    pub fn all_volume_specs(&self) -> Vec<VolumeSpec> {
+        let mut specs = self.volume_specs();
+        specs.extend(self.sidecars.iter().flat_map(SidecarConfig::volume_specs));
+        specs
+    }
+}
+
+#[derive(Debug, Validate, Serialize, Deserialize)]
+#[validate(schema(function = "validate_build"))]
+pub struct BuildConfig {
+    /// Builder to use instead of detecting one from the project.
+    pub builder: Option<Builder>,
+    /// Builder image for the pack CLI, defaults to the Paketo Jammy base builder.
+    pub pack_builder: Option<String>,
+    /// Platforms to build the image for, e.g. `linux/amd64`.
+    #[serde(default)]
+    #[validate(custom(function = "validate_platforms"))]
+    pub platforms: Vec<String>,
+    /// Registry and namespace the image is pushed to, e.g. `registry.example.com/team`.
+    #[validate(length(min = 1))]
+    pub registry: Option<String>,
+    /// Push the image to the registry after every build.
+    #[serde(default)]
+    pub push: bool,
+    /// Abort the deploy when the push fails instead of only warning.
+    #[serde(default)]
+    pub push_required: bool,
+    /// Registry images whose layers a build may take as cache, e.g. `registry.example.com/team/app:cache`.
+    #[serde(default)]
+    pub cache_from: Vec<String>,
+}
+
+impl BuildConfig {
+    pub fn is_multi_platform(&self) -> bool {
+        self.platforms.len() > 1
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ScanConfig {
+    #[serde(default)]
+    pub scanner: Scanner,
+    /// Lowest severity that fails the deploy.
+    #[serde(default = "default_fail_on")]
+    pub fail_on: Severity,
+}
+
+#[derive(Debug, Default, Validate, Serialize, Deserialize)]
+#[validate(schema(function = "validate_resources"))]
+pub struct ResourcesConfig {
+    /// Most processes and threads the container may run, a fork bomb stops there.
+    #[validate(range(min = 1))]
+    pub pids_limit: Option<i64>,
+    /// Adjusts how likely the kernel OOM killer picks the container, -1000 never to 1000 first.
+    #[validate(range(min = -1000, max = 1000))]
+    pub oom_score_adj: Option<i64>,
+    /// Keep the OOM killer away from the container. Once out of memory it hangs instead, and may take
+    /// the host with it, so it needs `acknowledge_oom_kill_disable`. Ignored on cgroup v2 hosts.
+    #[serde(default)]
+    pub oom_kill_disable: bool,
+    #[serde(default)]
+    pub acknowledge_oom_kill_disable: bool,
+    /// CPUs the container may run on, e.g. `0-2,4`, checked against the CPUs of the daemon host.
+    #[validate(custom(function = "validate_cpuset"))]
+    pub cpuset_cpus: Option<String>,
+    /// NUMA memory nodes the container may allocate from, e.g. `0`, only of use on NUMA hosts.
+    #[validate(custom(function = "validate_cpuset"))]
+    pub cpuset_mems: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct PreviewConfig {
+    /// Env vars set on preview containers only, values may refer to config fields and `${preview.branch}`,
+    /// e.g. `BASE_URL: http://preview.example.com:${port.host_port}`.
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Validate, Serialize, Deserialize)]
+#[validate(schema(function = "validate_probe"))]
+pub struct ProbeConfig {
+    #[serde(default)]
+    pub mode: ProbeMode,
+    /// HTTP path to request, e.g. `/health`. Without one the probe only opens a TCP connection.
+    pub path: Option<String>,
+    /// Seconds a single attempt may take.
+    #[serde(default = "default_probe_timeout")]
+    #[validate(range(min = 1, max = 60))]
+    pub timeout: u64,
+    /// Status codes that count as ready, any 2xx or 3xx by default.
+    #[serde(default)]
+    pub status: Vec<u16>,
+    /// Text the response body has to contain.
+    pub body: Option<String>,
+    /// Image of the probe container in network mode, it needs `nc` and `wget`.
+    #[serde(default = "default_probe_image")]
+    pub image: String,
+}
+
+#[derive(Debug, Validate, Serialize, Deserialize)]
+pub struct SmokeConfig {
+    #[validate(length(min = 1), nested)]
+    pub checks: Vec<SmokeCheck>,
+    /// Times a failing check is tried again before the deploy fails, so the app can warm up.
+    #[serde(default = "default_smoke_retries")]
+    #[validate(range(max = 100))]
+    pub retries: u32,
+    /// Seconds between the attempts of a check.
+    #[serde(default = "default_smoke_interval")]
+    #[validate(range(max = 300))]
+    pub interval: u64,
+    /// Seconds a single request may take.
+    #[serde(default = "default_smoke_timeout")]
+    #[validate(range(min = 1, max = 60))]
+    pub timeout: u64,
+    /// Image the checks run from when the container publishes no port, it needs `nc`.
+    #[serde(default = "default_probe_image")]
+    pub image: String,
+}
+
+#[derive(Debug, Validate, Serialize, Deserialize)]
+pub struct VerifyEnvConfig {
+    #[validate(length(min = 1), custom(function = "validate_env_names"))]
+    pub vars: Vec<String>,
+    /// Path answering with a JSON object of the env the app sees, asked through the published port
+    /// instead of running `printenv` in the container, e.g. `/debug/env`.
+    #[validate(custom(function = "validate_verify_env_endpoint"))]
+    pub endpoint: Option<String>,
+}
+
+/// One HTTP request and what its response has to look like.
+#[derive(Debug, Validate, Serialize, Deserialize)]
+pub struct SmokeCheck {
+    /// Path to request, e.g. `/api/status`.
+    #[validate(custom(function = "validate_smoke_path"))]
+    pub path: String,
+    #[serde(default = "default_smoke_method")]
+    #[validate(custom(function = "validate_smoke_method"))]
+    pub method: String,
+    /// Expected status code, any 2xx or 3xx when unset.
+    #[validate(range(min = 100, max = 599))]
+    pub status: Option<u16>,
+    /// Text the response body has to contain.
+    pub body: Option<String>,
+    /// Headers the response has to carry, with values containing the given text. Names are matched
+    /// case-insensitively.
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+}
+
+/// Where the readiness probe runs from.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProbeMode {
+    /// Host when the container publishes a port, network otherwise.
+    #[default]
+    Auto,
+    /// From ruku itself, through the published host port.
+    Host,
+    /// From a short-lived container on the app network, through the container port.
+    Network,
+    /// Inside the container, with curl, wget or bash.
+    Exec,
+}
+
+/// Tool that scans the image for known vulnerabilities.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Scanner {
+    /// The trivy CLI.
+    #[default]
+    Trivy,
+    /// The `docker scout` CLI plugin.
+    Scout,
+}
+
+/// Severity of a vulnerability, ordered from least to most serious.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    Unknown,
+    Low,
+    Medium,
+    High,
+    Critical,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Severity::Unknown => write!(f, "unknown"),
+            Severity::Low => write!(f, "low"),
+            Severity::Medium => write!(f, "medium"),
+            Severity::High => write!(f, "high"),
+            Severity::Critical => write!(f, "critical"),
+        }
+    }
+}
+
+impl FromStr for Severity {
+    type Err = String;
+
+    /// Scanners report severities in any case, e.g. `CRITICAL` or `Critical`.
+    fn from_str(severity: &str) -> Result<Severity, String> {
+        match severity.to_ascii_lowercase().as_str() {
+            "unknown" | "unspecified" => Ok(Severity::Unknown),
+            "low" | "negligible" => Ok(Severity::Low),
+            "medium" => Ok(Severity::Medium),
+            "high" => Ok(Severity::High),
+            "critical" => Ok(Severity::Critical),
+            _ => Err(format!("unknown severity '{}'", severity)),
+        }
+    }
+}
+
+/// Tool that turns the project into an image.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Builder {
+    /// `docker build` with the Dockerfile at the root of the project.
+    Dockerfile,
+    /// Nixpacks build plans, used when the project has no Dockerfile.
+    Nixpacks,
+    /// Cloud Native Buildpacks through the pack CLI.
+    Pack,
+}
+
+impl fmt::Display for Builder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Builder::Dockerfile => write!(f, "Dockerfile"),
+            Builder::Nixpacks => write!(f, "nixpacks"),
+            Builder::Pack => write!(f, "pack"),
+        }
+    }
+}
+
+/// What kind of app ruku deploys.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AppType {
+    /// An image built from the project with a Dockerfile, nixpacks or pack.
+    #[default]
+    App,
+    /// Files served as they are by a bundled web server.
+    Static,
+}
+
+impl fmt::Display for AppType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AppType::App => write!(f, "app"),
+            AppType::Static => write!(f, "static"),
+        }
+    }
+}
+
+/// The files of a static site and how they are served.
+#[derive(Debug, Validate, Serialize, Deserialize)]
+pub struct StaticConfig {
+    /// Directory of the files, relative to the app directory, e.g. `./dist`.
+    #[validate(custom(function = "validate_static_dir"))]
+    pub dir: String,
+    /// Answer paths that match no file with `index.html`, for single page apps that route in the browser.
+    #[serde(default)]
+    pub spa: bool,
+    /// Headers added to every response, e.g. `Cache-Control: public, max-age=300`.
+    #[serde(default)]
+    #[validate(custom(function = "validate_static_headers"))]
+    pub headers: BTreeMap<String, String>,
+    /// Copy the files into the image instead of mounting the directory, so a deploy pins the site and a
+    /// rollback brings back the old files.
+    #[serde(default)]
+    pub immutable: bool,
+}
+
+/// What the app is called and where it runs for log shippers and APMs.
+#[derive(Debug, Validate, Serialize, Deserialize)]
+pub struct ObservabilityConfig {
+    /// Name of the service, the app name when left out.
+    #[validate(custom(function = "validate_observability_value"))]
+    pub service: Option<String>,
+    /// Environment the app runs in, e.g. `production`.
+    #[validate(custom(function = "validate_observability_value"))]
+    pub env: Option<String>,
+    /// What writes the logs, picks the parsing rules of the shipper, e.g. `nginx` or `python`.
+    #[validate(custom(function = "validate_observability_value"))]
+    pub source: Option<String>,
+    /// Conventions the labels and env are generated for, all of them when left out.
+    #[serde(default = "default_conventions")]
+    pub emit: Vec<Convention>,
+}
+
+/// A naming scheme for labels and env that tools discover containers by.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Convention {
+    /// `com.datadoghq.*` labels and `DD_*` env for the Datadog agent.
+    Datadog,
+    /// `OTEL_SERVICE_NAME` and `OTEL_RESOURCE_ATTRIBUTES` for OpenTelemetry SDKs.
+    Otel,
+    /// `org.opencontainers.image.*` labels.
+    Oci,
+}
+
+fn default_conventions() -> Vec<Convention> {
+    vec![Convention::Datadog, Convention::Otel, Convention::Oci]
+}
+
+/// How a new version replaces the running one.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeployStrategy {
+    /// Stop the running container and start the new one in its place.
+    #[default]
+    Recreate,
+    /// Create the new container while the old one runs, swap them and start the old one again when the
+    /// new one does not become healthy.
+    Rolling,
+    /// Prove the new version healthy in a container of its own before the old one stops.
+    BlueGreen,
+    /// Start the new version next to the old one and shift traffic to it in steps.
+    Canary,
+}
+
+impl DeployStrategy {
+    /// Whether the strategy waits for the new container to become healthy and runs the smoke checks
+    /// itself, it needs them to decide whether to roll back.
+    pub fn gates_health(&self) -> bool {
+        *self != DeployStrategy::Recreate
+    }
+}
+
+impl fmt::Display for DeployStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DeployStrategy::Recreate => write!(f, "recreate"),
+            DeployStrategy::Rolling => write!(f, "rolling"),
+            DeployStrategy::BlueGreen => write!(f, "blue_green"),
+            DeployStrategy::Canary => write!(f, "canary"),
+        }
+    }
+}
+
+impl FromStr for DeployStrategy {
+    type Err = String;
+
+    fn from_str(strategy: &str) -> Result<DeployStrategy, String> {
+        match strategy.replace('-', "_").as_str() {
+            "recreate" => Ok(DeployStrategy::Recreate),
+            "rolling" => Ok(DeployStrategy::Rolling),
+            "blue_green" => Ok(DeployStrategy::BlueGreen),
+            "canary" => Ok(DeployStrategy::Canary),
+            _ => Err(format!(
+                "unknown strategy '{}', expected recreate, rolling, blue_green or canary",
+                strategy
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Validate, Serialize, Deserialize)]
+pub struct PreStartConfig {
+    /// Shell command run with `sh -c` in the app image, with the app's env and network. A non-zero exit
+    /// stops the start.
+    #[validate(length(min = 1))]
+    pub command: String,
+    /// Seconds the command may run before it is killed and the start fails.
+    #[serde(default = "default_pre_start_timeout")]
+    #[validate(range(min = 1, max = 3600))]
+    pub timeout: u64,
+}
+
+/// A container started before the app on its network, named `<prefix><app>-<name>` and reachable by
+/// the app under its name.
+#[derive(Debug, Validate, Serialize, Deserialize)]
+pub struct SidecarConfig {
+    #[validate(custom(function = "validate_sidecar_name"))]
+    pub name: String,
+    #[validate(length(min = 1))]
+    pub image: String,
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    /// Mounted like the app volumes, a named volume is shared with the app when both use the same name.
+    #[serde(default)]
+    #[validate(custom(function = "validate_volumes"))]
+    pub volumes: Vec<String>,
+    /// Ports published on the host, in the form of the app port. Nothing is published by default.
+    #[serde(default)]
+    #[validate(custom(function = "validate_sidecar_ports"))]
+    pub ports: Vec<PortConfig>,
+}
+
+impl SidecarConfig {
+    /// The parsed volume entries, entries that don't parse are rejected by validation.
+    pub fn volume_specs(&self) -> Vec<VolumeSpec> {
+        self.volumes.iter().filter_map(|v| VolumeSpec::parse(v).ok()).collect()
+    }
+}
+
+#[derive(Debug, Validate, Serialize, Deserialize)]
+pub struct CanaryConfig {
+    /// Host port the canary container is published on while both versions run.
+    #[validate(range(min = 1024, max = 65535))]
+    pub port: u16,
+    /// Traffic percentages to shift to the canary, in order.
+    #[serde(default = "default_canary_steps")]
+    #[validate(custom(function = "validate_canary_steps"))]
+    pub steps: Vec<u8>,
+    /// Seconds to wait between steps before checking the canary health.
+    #[serde(default = "default_canary_pause")]
+    pub pause: u64,
+    /// Percentage of the requests the proxy sent to the canary during a step that may fail with a 5xx
+    /// before the rollout is rolled back.
+    #[serde(default = "default_canary_max_error_rate")]
+    #[validate(range(max = 100))]
+    pub max_error_rate: u8,
+}
+
+/// Transport protocol of a published port.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    Tcp,
+    Udp,
+    Sctp,
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Protocol::Tcp => write!(f, "tcp"),
+            Protocol::Udp => write!(f, "udp"),
+            Protocol::Sctp => write!(f, "sctp"),
+        }
+    }
+}
+
+impl FromStr for Protocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "tcp" => Ok(Protocol::Tcp),
+            "udp" => Ok(Protocol::Udp),
+            "sctp" => Ok(Protocol::Sctp),
+            _ => Err(format!("unknown protocol '{}', use tcp, udp or sctp", s)),
+        }
+    }
+}
+
+/// The app port: the container port, where it is published on the host and for which protocols.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(try_from = "PortValue")]
+pub struct PortConfig {
+    /// Port the app listens on inside the container.
+    pub number: u16,
+    /// With `auto` this is the assigned port, 0 until one is assigned.
+    pub host_port: u16,
+    /// The host port is picked by ruku from `auto_port_range`, written as `auto:3000`.
+    pub auto: bool,
+    /// Address the port is published on, all interfaces when unset.
+    pub host_ip: Option<IpAddr>,
+    pub protocols: Vec<Protocol>,
+}
+
+/// The host address a port on `ip` is published on as the daemon takes it. None, as for `0.0.0.0`, is
+/// every interface on both stacks the daemon has, `::` every IPv6 interface only.
+pub fn publish_address(ip: Option<IpAddr>) -> Option<String> {
+    ip.filter(|ip| !(ip.is_ipv4() && ip.is_unspecified()))
+        .map(|ip| ip.to_string())
+}
+
+impl PortConfig {
+    /// Whether the container port is the one the image exposes, as with no `port` or `port: auto`.
+    pub fn is_from_image(&self) -> bool {
+        self.number == 0
+    }
+
+    /// Whether the port is published on the host, a port taken from the image only is with `auto`.
+    pub fn is_published(&self) -> bool {
+        self.auto || self.host_port != 0
+    }
+}
+
+impl Default for PortConfig {
+    /// No `port` in ruku.yml, the container port comes from the image and is not published.
+    fn default() -> Self {
+        PortConfig {
+            number: 0,
+            host_port: 0,
+            auto: false,
+            host_ip: None,
+            protocols: vec![Protocol::Tcp],
+        }
+    }
+}
+
+impl fmt::Display for PortConfig {
+    /// Written back in the shortest form the config accepts.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.is_from_image(), self.auto) {
+            (true, true) => return write!(f, "auto"),
+            (true, false) => return write!(f, "image"),
+            _ => {}
+        }
+        let protocols: Vec<String> = self.protocols.iter().map(|p| p.to_string()).collect();
+        let host_port = if self.auto {
+            "auto".to_string()
+        } else {
+            self.host_port.to_string()
+        };
+        match self.host_ip {
+            Some(IpAddr::V6(ip)) => write!(f, "[{}]:{}:", ip, host_port)?,
+            Some(ip) => write!(f, "{}:{}:", ip, host_port)?,
+            None if self.auto || self.host_port != self.number => write!(f, "{}:", host_port)?,
+            None => {}
+        }
+        write!(f, "{}/{}", self.number, protocols.join("+"))
+    }
+}
+
+impl Serialize for PortConfig {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PortValue {
+    Number(u16),
+    Text(String),
+}
+
+impl TryFrom<PortValue> for PortConfig {
+    type Error = String;
+
+    /// Parse `[IP:]HOST:CONTAINER[/PROTOCOLS]` or a single port used on both sides, IPv6 addresses go in
+    /// brackets. HOST may be `auto`.
+    fn try_from(value: PortValue) -> Result<Self, Self::Error> {
+        let text = match value {
+            PortValue::Number(number) => {
+                return Ok(PortConfig {
+                    number,
+                    host_port: number,
+                    auto: false,
+                    host_ip: None,
+                    protocols: vec![Protocol::Tcp],
+                })
+            }
+            PortValue::Text(text) if text.trim() == "image" => return Ok(PortConfig::default()),
+            PortValue::Text(text) if text.trim() == "auto" => {
+                return Ok(PortConfig {
+                    auto: true,
+                    ..PortConfig::default()
+                })
+            }
+            PortValue::Text(text) => text,
+        };
+        let invalid = || {
+            format!(
+                "invalid port '{}', use e.g. 8080, 27015/udp, 127.0.0.1:8080:3000, auto:3000 or auto",
+                text
+            )
+        };
+
+        let (address, protocols) = text.split_once('/').unwrap_or((&text, "tcp"));
+        let (host_ip, ports) = match address.strip_prefix('[') {
+            Some(rest) => {
+                let (ip, ports) = rest.split_once("]:").ok_or_else(invalid)?;
+                (Some(ip), ports)
+            }
+            None => match address.matches(':').count() {
+                2 => {
+                    let (ip, ports) = address.split_once(':').unwrap();
+                    (Some(ip), ports)
+                }
+                0 | 1 => (None, address),
+                _ => {
+                    return Err(format!(
+                        "invalid port '{}', an IPv6 address goes in brackets, e.g. [::1]:8080:3000",
+                        text
+                    ))
+                }
+            },
+        };
+        let host_ip = host_ip
+            .map(|ip| {
+                ip.trim()
+                    .parse::<IpAddr>()
+                    .map_err(|_| format!("invalid host IP '{}'", ip))
+            })
+            .transpose()?;
+
+        let parse = |port: &str| port.trim().parse::<u16>().map_err(|_| invalid());
+        let auto = ports.split_once(':').is_some_and(|(host, _)| host.trim() == "auto");
+        let (host_port, number) = match ports.split_once(':') {
+            Some((_, container)) if auto => (0, parse(container)?),
+            Some((host, container)) => (parse(host)?, parse(container)?),
+            None if host_ip.is_some() => return Err(invalid()),
+            None => {
+                let port = parse(ports)?;
+                (port, port)
+            }
+        };
+
+        let mut protocols = protocols
+            .split('+')
+            .map(|p| p.trim().parse())
+            .collect::<Result<Vec<Protocol>, String>>()?;
+        protocols.sort();
+        protocols.dedup();
+        Ok(PortConfig {
+            number,
+            host_port,
+            auto,
+            host_ip,
+            protocols,
+        })
+    }
+}
+
+/// A file of `files`: what goes in it and the permission bits it gets.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(try_from = "FileValue")]
+pub struct FileConfig {
+    pub source: FileSource,
+    /// Permission bits, 0644 or 0600 for content that refers to the environment when unset.
+    pub mode: Option<u32>,
+}
+
+/// Where the content of a file comes from.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FileSource {
+    /// Written in ruku.yml, with `${name}` replaced as in labels.
+    Content(String),
+    /// A local file copied as it is, relative to the app directory.
+    Path(String),
+}
+
+impl Serialize for FileConfig {
+    /// The content alone when nothing else is set.
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut settings = BTreeMap::new();
+        match (&self.source, self.mode) {
+            (FileSource::Content(content), None) => return serializer.serialize_str(content),
+            (FileSource::Content(content), _) => settings.insert("content", content.clone()),
+            (FileSource::Path(path), _) => settings.insert("source", path.clone()),
+        };
+        if let Some(mode) = self.mode {
+            settings.insert("mode", format!("{:04o}", mode));
+        }
+        settings.serialize(serializer)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum FileValue {
+    Content(String),
+    Settings {
+        content: Option<String>,
+        source: Option<String>,
+        mode: Option<ModeValue>,
+    },
+}
+
+impl TryFrom<FileValue> for FileConfig {
+    type Error = String;
+
+    fn try_from(value: FileValue) -> Result<Self, Self::Error> {
+        let (content, source, mode) = match value {
+            FileValue::Content(content) => {
+                return Ok(FileConfig {
+                    source: FileSource::Content(content),
+                    mode: None,
+                })
+            }
+            FileValue::Settings { content, source, mode } => (content, source, mode),
+        };
+        let source = match (content, source) {
+            (Some(content), None) => FileSource::Content(content),
+            (None, Some(source)) => FileSource::Path(source),
+            _ => return Err("a file needs either content or a source".to_string()),
+        };
+        Ok(FileConfig {
+            source,
+            mode: mode.map(parse_mode).transpose()?,
+        })
+    }
+}
+
+/// Octal permission bits, YAML reads 0750 as the number 750 but the digits are octal either way.
+fn parse_mode(mode: ModeValue) -> Result<u32, String> {
+    let mode = match mode {
+        ModeValue::Number(number) => number.to_string(),
+        ModeValue::Text(text) => text,
+    };
+    u32::from_str_radix(mode.trim(), 8)
+        .ok()
+        .filter(|mode| *mode <= 0o7777)
+        .ok_or_else(|| format!("invalid mode '{}', use octal digits like 0750", mode))
+}
+
+/// How missing host directories of bind mounts are created. Without an owner they belong to the image
+/// user when it is numeric, or else to the user ruku runs as.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(try_from = "HostPathsValue")]
+pub struct HostPathsConfig {
+    pub enabled: bool,
+    /// User and group id the directories are given.
+    pub owner: Option<Owner>,
+    /// Permission bits of the directories.
+    pub mode: u32,
+}
+
+impl HostPathsConfig {
+    pub const DEFAULT_MODE: u32 = 0o755;
+}
+
+impl Default for HostPathsConfig {
+    fn default() -> Self {
+        HostPathsConfig {
+            enabled: false,
+            owner: None,
+            mode: Self::DEFAULT_MODE,
+        }
+    }
+}
+
+impl Serialize for HostPathsConfig {
+    /// `true` or `false` when only enabled, a mapping when the owner or mode is set.
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if self.owner.is_none() && self.mode == Self::DEFAULT_MODE {
+            return serializer.serialize_bool(self.enabled);
+        }
+        let mut settings = BTreeMap::new();
+        if let Some(owner) = self.owner {
+            settings.insert("owner", owner.to_string());
+        }
+        settings.insert("mode", format!("{:04o}", self.mode));
+        settings.serialize(serializer)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum HostPathsValue {
+    Enabled(bool),
+    Settings {
+        owner: Option<String>,
+        mode: Option<ModeValue>,
+    },
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ModeValue {
+    Number(u32),
+    Text(String),
+}
+
+impl TryFrom<HostPathsValue> for HostPathsConfig {
+    type Error = String;
+
+    fn try_from(value: HostPathsValue) -> Result<Self, Self::Error> {
+        let (owner, mode) = match value {
+            HostPathsValue::Enabled(enabled) => {
+                return Ok(HostPathsConfig {
+                    enabled,
+                    ..HostPathsConfig::default()
+                })
+            }
+            HostPathsValue::Settings { owner, mode } => (owner, mode),
+        };
+        let mode = match mode {
+            Some(mode) => parse_mode(mode)?,
+            None => Self::DEFAULT_MODE,
+        };
+        Ok(HostPathsConfig {
+            enabled: true,
+            owner: owner.map(|owner| owner.parse()).transpose()?,
+            mode,
+        })
+    }
+}
+
+/// A user id with an optional group id, `1000` or `1000:1000`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Owner {
+    pub uid: u32,
+    pub gid: Option<u32>,
+}
+
+impl fmt::Display for Owner {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.gid {
+            Some(gid) => write!(f, "{}:{}", self.uid, gid),
+            None => write!(f, "{}", self.uid),
+        }
+    }
+}
+
+impl FromStr for Owner {
+    type Err = String;
+
+    /// Only numeric ids, user names would have to be looked up inside the image.
+    fn from_str(owner: &str) -> Result<Owner, String> {
+        let invalid = || format!("invalid owner '{}', use a numeric uid or uid:gid", owner);
+        let (uid, gid) = match owner.trim().split_once(':') {
+            Some((uid, gid)) => (uid, Some(gid)),
+            None => (owner.trim(), None),
+        };
+        Ok(Owner {
+            uid: uid.parse().map_err(|_| invalid())?,
+            gid: gid.map(|gid| gid.parse().map_err(|_| invalid())).transpose()?,
+        })
+    }
+}
+
+/// The network a container runs in.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(from = "String")]
+pub enum NetworkMode {
+    /// The app's own bridge network, shared with the apps it is linked to.
+    #[default]
+    Bridge,
+    /// Only a loopback interface, no traffic in or out.
+    None,
+    /// The network stack of the host, the app listens on host ports directly.
+    Host,
+    /// A Docker network created outside of ruku.
+    Custom(String),
+}
+
+impl NetworkMode {
+    /// Whether ports can be published, Docker rejects port bindings with `host` and `none`.
+    pub fn publishes_ports(&self) -> bool {
+        !matches!(self, NetworkMode::None | NetworkMode::Host)
+    }
+}
+
+impl fmt::Display for NetworkMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NetworkMode::Bridge => write!(f, "bridge"),
+            NetworkMode::None => write!(f, "none"),
+            NetworkMode::Host => write!(f, "host"),
+            NetworkMode::Custom(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+impl Serialize for NetworkMode {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl From<String> for NetworkMode {
+    fn from(text: String) -> Self {
+        match text.as_str() {
+            "bridge" => NetworkMode::Bridge,
+            "none" => NetworkMode::None,
+            "host" => NetworkMode::Host,
+            _ => NetworkMode::Custom(text),
+        }
+    }
+}
+
+/// An inclusive range of host ports, written as `START-END`.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct PortRange {
+    pub start: u16,
+    pub end: u16,
+}
+
+impl Default for PortRange {
+    fn default() -> Self {
+        PortRange {
+            start: 20000,
+            end: 30000,
+        }
+    }
+}
+
+impl PortRange {
+    pub fn contains(&self, port: u16) -> bool {
+        (self.start..=self.end).contains(&port)
+    }
+}
+
+impl fmt::Display for PortRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}-{}", self.start, self.end)
+    }
+}
+
+impl Serialize for PortRange {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl TryFrom<String> for PortRange {
+    type Error = String;
+
+    fn try_from(text: String) -> Result<Self, Self::Error> {
+        let invalid = || format!("invalid port range '{}', use e.g. 20000-30000", text);
+        let (start, end) = text.split_once('-').ok_or_else(invalid)?;
+        Ok(PortRange {
+            start: start.trim().parse().map_err(|_| invalid())?,
+            end: end.trim().parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+fn default_container_prefix() -> String {
+    "ruku-".to_string()
+}
+
+fn default_concurrency() -> usize {
+    DEFAULT_CONCURRENCY
+}
+
+fn default_probe_timeout() -> u64 {
+    2
+}
+
+fn default_probe_image() -> String {
+    "busybox:stable".to_string()
+}
+
+fn default_fail_on() -> Severity {
+    Severity::Critical
+}
+
+fn default_canary_steps() -> Vec<u8> {
+    vec![10, 50, 100]
+}
+
+fn default_canary_pause() -> u64 {
+    30
+}
+
+fn default_canary_max_error_rate() -> u8 {
+    5
+}
+
+fn default_smoke_retries() -> u32 {
+    3
+}
+
+fn default_smoke_interval() -> u64 {
+    2
+}
+
+fn default_smoke_timeout() -> u64 {
+    5
+}
+
+fn default_smoke_method() -> String {
+    "GET".to_string()
+}
+
+fn default_pre_start_timeout() -> u64 {
+    300
+}
+
+fn validate_app_port(port: &PortConfig) -> Result<(), ValidationError> {
+    // Whether the port is free is checked against Docker before the deploy, the app may hold it already
+    if port.is_from_image() {
+        return Ok(());
+    }
+    if !port.auto && port.host_port < 1024 {
+        return Err(ValidationError::new("host port must be between 1024 and 65535"));
+    }
+    Ok(())
+}
+
+fn validate_port_range(range: &PortRange) -> Result<(), ValidationError> {
+    if range.start < 1024 || range.start > range.end {
+        return Err(ValidationError::new(
+            "auto_port_range must run from a port of at least 1024 up to a higher one",
+        ));
+    }
+    Ok(())
+}
+
+fn validate_container_prefix(prefix: &str) -> Result<(), ValidationError> {
+    let valid = prefix
+        .chars()
+        .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_' || c == '.');
+    if !valid || prefix.len() > 16 || prefix.starts_with(['-', '_', '.']) {
+        return Err(ValidationError::new(
+            "container_prefix must be up to 16 lowercase letters, digits, dashes, dots or underscores",
+        ));
+    }
+    Ok(())
+}
+
+fn validate_canary_steps(steps: &[u8]) -> Result<(), ValidationError> {
+    if steps.is_empty() || steps.iter().any(|&s| s == 0 || s > 100) {
+        return Err(ValidationError::new("canary steps must be between 1 and 100"));
+    }
+    if steps.windows(2).any(|w| w[0] >= w[1]) {
+        return Err(ValidationError::new("canary steps must be increasing"));
+    }
+    Ok(())
+}
+
+fn validate_platforms(platforms: &[String]) -> Result<(), ValidationError> {
+    let valid = |platform: &String| {
+        let parts: Vec<&str> = platform.split('/').collect();
+        (2..=3).contains(&parts.len()) && parts.iter().all(|part| !part.is_empty())
+    };
+    if !platforms.iter().all(valid) {
+        return Err(ValidationError::new("platforms must look like os/arch[/variant]"));
+    }
+    Ok(())
+}
+
+fn validate_volumes(volumes: &[String]) -> Result<(), ValidationError> {
+    let mut targets = vec![];
+    for volume in volumes {
+        let spec =
+            VolumeSpec::parse(volume).map_err(|_| ValidationError::new("volumes must look like source:/path[:ro]"))?;
+        if targets.contains(&spec.target) {
+            return Err(ValidationError::new("volume targets must be unique"));
+        }
+        targets.push(spec.target);
+    }
+    Ok(())
+}
+
+fn validate_templates(templates: &BTreeMap<String, String>) -> Result<(), ValidationError> {
+    let mut targets = vec![];
+    for target in templates.values() {
+        if !target.starts_with('/') {
+            return Err(ValidationError::new("template targets must be absolute paths"));
+        }
+        if targets.contains(&target) {
+            return Err(ValidationError::new("template targets must be unique"));
+        }
+        targets.push(target);
+    }
+    Ok(())
+}
+
+fn validate_files(files: &BTreeMap<String, FileConfig>) -> Result<(), ValidationError> {
+    if !files
+        .keys()
+        .all(|target| target.starts_with('/') && !target.ends_with('/'))
+    {
+        return Err(ValidationError::new("file targets must be absolute file paths"));
+    }
+    Ok(())
+}
+
+fn validate_file_targets(config: &RukuConfig) -> Result<(), ValidationError> {
+    // Both are kept in one directory by container path
+    if config
+        .templates
+        .values()
+        .any(|target| config.files.contains_key(target))
+    {
+        return Err(ValidationError::new(
+            "a container path can't be both a template and a file",
+        ));
+    }
+    Ok(())
+}
+
+/// The `timezone` that mounts the timezone of the host.
+pub const HOST_TIMEZONE: &str = "host";
+
+/// A registry host with an optional port and path, as daemon.json writes it with a scheme or without.
+static REGISTRY_MIRROR: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^(https?://)?[A-Za-z0-9][A-Za-z0-9.-]*(:[0-9]+)?(/[A-Za-z0-9._-]+)*/?$").unwrap());
+/// An IANA timezone name, `UTC`, `Europe/Berlin`, `America/Argentina/Buenos_Aires` or `Etc/GMT+5`.
+static TIMEZONE_NAME: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^[A-Za-z][A-Za-z0-9_+-]*(/[A-Za-z0-9][A-Za-z0-9_+-]*){0,2}$").unwrap());
+/// A signal by name, `SIGHUP` or `HUP`, or by number.
+static SIGNAL_NAME: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^((SIG)?[A-Z][A-Z0-9]{1,10}|[0-9]{1,2})$").unwrap());
+/// A name env vars can be set under.
+pub static ENV_NAME: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^[A-Za-z_][A-Za-z0-9_]*$").unwrap());
+/// A POSIX locale name, `C`, `C.UTF-8`, `en_US.UTF-8` or `sr_RS@latin`.
+static LOCALE_NAME: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^[A-Za-z]+(_[A-Za-z]+)?(\.[A-Za-z0-9-]+)?(@[A-Za-z0-9]+)?$").unwrap());
+
+fn validate_timezone(timezone: &str) -> Result<(), ValidationError> {
+    if timezone != HOST_TIMEZONE && (timezone.len() > 64 || !TIMEZONE_NAME.is_match(timezone)) {
+        return Err(ValidationError::new(
+            "timezone must be an IANA name such as Europe/Berlin, or host",
+        ));
+    }
+    Ok(())
+}
+
+fn validate_locale(locale: &str) -> Result<(), ValidationError> {
+    if locale.len() > 64 || !LOCALE_NAME.is_match(locale) {
+        return Err(ValidationError::new("locale must be a locale name such as en_US.UTF-8"));
+    }
+    Ok(())
+}
+
+fn validate_env_names(names: &[String]) -> Result<(), ValidationError> {
+    if !names.iter().all(|name| ENV_NAME.is_match(name)) {
+        return Err(ValidationError::new(
+            "verify_env vars must be env var names such as DATABASE_URL",
+        ));
+    }
+    Ok(())
+}
+
+fn validate_reload_signal(signal: &str) -> Result<(), ValidationError> {
+    if !SIGNAL_NAME.is_match(signal) {
+        return Err(ValidationError::new(
+            "reload_signal must be a signal such as SIGHUP or USR1",
+        ));
+    }
+    Ok(())
+}
+
+/// The deploy stages `stage_timeouts` can limit, the scan runs in one blocking call that can't be cut off.
+pub const TIMED_STAGES: [&str; 6] = ["build", "push", "sidecars", "start", "health", "smoke"];
+
+/// Names the other containers of an app end in.
+const RESERVED_SIDECAR_NAMES: [&str; 7] = [
+    "canary",
+    "maintenance",
+    "pre-start",
+    "next",
+    "previous",
+    "green",
+    "staged",
+];
+
+fn validate_sidecar_name(name: &str) -> Result<(), ValidationError> {
+    let valid = name.starts_with(|c: char| c.is_ascii_lowercase() || c.is_ascii_digit())
+        && name.len() <= 32
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-');
+    if !valid {
+        return Err(ValidationError::new(
+            "sidecar names must be up to 32 lowercase letters, digits or dashes",
+        ));
+    }
+    if RESERVED_SIDECAR_NAMES.contains(&name) {
+        return Err(ValidationError::new(
+            "sidecar names canary, maintenance and pre-start are taken by ruku",
+        ));
+    }
+    Ok(())
+}
+
+fn validate_sidecars(sidecars: &[SidecarConfig]) -> Result<(), ValidationError> {
+    let mut names = vec![];
+    for sidecar in sidecars {
+        if names.contains(&&sidecar.name) {
+            return Err(ValidationError::new("sidecar names must be unique"));
+        }
+        names.push(&sidecar.name);
+    }
+    Ok(())
+}
+
+fn validate_sidecar_ports(ports: &[PortConfig]) -> Result<(), ValidationError> {
+    if ports.iter().any(|port| port.auto) {
+        return Err(ValidationError::new(
+            "sidecar ports need a fixed host port, auto is for the app",
+        ));
+    }
+    if ports.iter().any(|port| port.number == 0 || port.host_port < 1024) {
+        return Err(ValidationError::new(
+            "sidecar host ports must be between 1024 and 65535",
+        ));
+    }
+    Ok(())
+}
+
+fn validate_depends_on(depends_on: &[String]) -> Result<(), ValidationError> {
+    let valid = |name: &String| {
+        name.starts_with(|c: char| c.is_ascii_alphanumeric())
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+    };
+    if !depends_on.iter().all(valid) {
+        return Err(ValidationError::new("depends_on must list app or container names"));
+    }
+    Ok(())
+}
+
+fn validate_probe(probe: &ProbeConfig) -> Result<(), ValidationError> {
+    match &probe.path {
+        Some(path) if !path.starts_with('/') => Err(ValidationError::new("probe.path must start with /")),
+        None if probe.body.is_some() || !probe.status.is_empty() => Err(ValidationError::new(
+            "probe.status and probe.body need an HTTP probe, set probe.path",
+        )),
+        _ => Ok(()),
+    }
+}
+
+fn validate_smoke_path(path: &str) -> Result<(), ValidationError> {
+    if !path.starts_with('/') || path.contains(char::is_whitespace) {
+        return Err(ValidationError::new(
+            "smoke check paths must start with / and contain no spaces",
+        ));
+    }
+    Ok(())
+}
+
+fn validate_verify_env_endpoint(path: &str) -> Result<(), ValidationError> {
+    if !path.starts_with('/') || path.contains(char::is_whitespace) {
+        return Err(ValidationError::new(
+            "verify_env endpoint must start with / and contain no spaces",
+        ));
+    }
+    Ok(())
+}
+
+fn validate_smoke_method(method: &str) -> Result<(), ValidationError> {
+    if !["GET", "HEAD", "POST", "PUT", "PATCH", "DELETE", "OPTIONS"].contains(&method) {
+        return Err(ValidationError::new(
+            "smoke check methods must be one of GET, HEAD, POST, PUT, PATCH, DELETE or OPTIONS",
+        ));
+    }
+    Ok(())
+}
+
+fn validate_resources(resources: &ResourcesConfig) -> Result<(), ValidationError> {
+    if resources.oom_kill_disable && !resources.acknowledge_oom_kill_disable {
+        return Err(ValidationError::new(
+            "resources.oom_kill_disable can hang the host when the app runs out of memory, set resources.acknowledge_oom_kill_disable to use it",
+        ));
+    }
+    Ok(())
+}
+
+fn validate_cpuset(list: &str) -> Result<(), ValidationError> {
+    if cpuset::parse(list).is_err() {
+        return Err(ValidationError::new(
+            "resources.cpuset_cpus and cpuset_mems must be numbers and ranges like 0-2,4",
+        ));
+    }
+    Ok(())
+}
+
+fn validate_labels(labels: &BTreeMap<String, String>) -> Result<(), ValidationError> {
+    if labels.keys().any(|key| key.starts_with(RESERVED_LABEL_PREFIX)) {
+        return Err(ValidationError::new("labels starting with ruku. are reserved for ruku"));
+    }
+    if labels
+        .iter()
+        .any(|(key, value)| key.starts_with(DATADOG_ANNOTATION_PREFIX) && !is_json(value))
+    {
+        return Err(ValidationError::new("com.datadoghq.ad. labels must hold valid JSON"));
+    }
+    Ok(())
+}
+
+fn validate_observability_value(value: &str) -> Result<(), ValidationError> {
+    // The values go into comma separated tag and `key=value` attribute lists
+    static VALUE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^[A-Za-z0-9][A-Za-z0-9_.:/-]{0,99}$").unwrap());
+    if !VALUE.is_match(value) {
+        return Err(ValidationError::new(
+            "observability values must be up to 100 letters, digits, _ . : / and -",
+        ));
+    }
+    Ok(())
+}
+
+fn validate_secrets(secrets: &BTreeMap<String, String>) -> Result<(), ValidationError> {
+    if secrets.values().any(|reference| SecretRef::parse(reference).is_err()) {
+        return Err(ValidationError::new(
+            "secrets must be references like vault:kv/data/myapp#DB_PASSWORD or sops:secrets.enc.yaml#api_key",
+        ));
+    }
+    Ok(())
+}
+
+fn validate_registry_mirror(mirror: &str) -> Result<(), ValidationError> {
+    if mirror.len() > 255 || !REGISTRY_MIRROR.is_match(mirror) {
+        return Err(ValidationError::new(
+            "registry_mirror must be a registry host with an optional port and path, e.g. mirror.example.com:5000",
+        ));
+    }
+    Ok(())
+}
+
+fn validate_stage_timeouts(timeouts: &BTreeMap<String, u64>) -> Result<(), ValidationError> {
+    if timeouts.keys().any(|stage| !TIMED_STAGES.contains(&stage.as_str())) {
+        return Err(ValidationError::new(
+            "stage_timeouts only knows build, push, sidecars, start, health and smoke",
+        ));
+    }
+    if timeouts.values().any(|seconds| *seconds == 0) {
+        return Err(ValidationError::new("stage timeouts must be at least 1 second"));
+    }
+    Ok(())
+}
+
+fn validate_build(build: &BuildConfig) -> Result<(), ValidationError> {
+    if build.is_multi_platform() && build.registry.is_none() {
+        return Err(ValidationError::new(
+            "multi-platform images cannot stay in the local store, set build.registry",
+        ));
+    }
+    if (build.push || build.push_required) && build.registry.is_none() {
+        return Err(ValidationError::new("pushing images requires build.registry"));
+    }
+    Ok(())
+}
+
+fn validate_app_type(config: &RukuConfig) -> Result<(), ValidationError> {
+    match (config.app_type, &config.static_site) {
+        (AppType::Static, None) => Err(ValidationError::new(
+            "type static needs a static section with the dir of the site",
+        )),
+        (AppType::App, Some(_)) => Err(ValidationError::new("the static section only applies to type static")),
+        (AppType::Static, Some(_)) if config.build.as_ref().is_some_and(|build| build.builder.is_some()) => Err(
+            ValidationError::new("static apps are served by ruku's web server image, build.builder doesn't apply"),
+        ),
+        _ => Ok(()),
+    }
+}
+
+fn validate_static_dir(dir: &str) -> Result<(), ValidationError> {
+    if dir.is_empty() || Path::new(dir).is_absolute() || Path::new(dir).components().any(|c| c.as_os_str() == "..") {
+        return Err(ValidationError::new(
+            "static.dir must be a directory inside the app directory, e.g. ./dist",
+        ));
+    }
+    Ok(())
+}
+
+fn validate_static_headers(headers: &BTreeMap<String, String>) -> Result<(), ValidationError> {
+    let token = |name: &str| {
+        !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c))
+    };
+    if !headers.keys().all(|name| token(name)) {
+        return Err(ValidationError::new("static.headers names must be HTTP header names"));
+    }
+    // The values go into the web server config as quoted strings, where `$` starts a variable
+    if headers
+        .values()
+        .any(|value| value.chars().any(|c| c.is_control() || c == '$'))
+    {
+        return Err(ValidationError::new(
+            "static.headers values can't hold control characters or $",
+        ));
+    }
+    Ok(())
+}
+
+fn validate_hosts(hosts: &[String]) -> Result<(), ValidationError> {
+    let mut seen = HashSet::new();
+    if hosts.iter().any(|host| !seen.insert(host)) {
+        return Err(ValidationError::new("hosts lists a context twice"));
+    }
+    Ok(())
+}
+
+fn validate_domains(domains: &[String]) -> Result<(), ValidationError> {
+    static DOMAIN: LazyLock<Regex> = LazyLock::new(|| {
+        Regex::new(r"^(\*\.)?[a-z0-9]([a-z0-9-]*[a-z0-9])?(\.[a-z0-9]([a-z0-9-]*[a-z0-9])?)*$").unwrap()
+    });
+    if domains
+        .iter()
+        .any(|domain| domain.len() > 253 || !DOMAIN.is_match(domain))
+    {
+        return Err(ValidationError::new(
+            "domains must be lowercase hostnames like example.com, optionally starting with *.",
+        ));
+    }
+    let mut seen = HashSet::new();
+    if domains.iter().any(|domain| !seen.insert(domain)) {
+        return Err(ValidationError::new("domains lists a hostname twice"));
+    }
+    Ok(())
+}
+
+fn validate_fleet(config: &RukuConfig) -> Result<(), ValidationError> {
+    if config.context.is_some() && !config.hosts.is_empty() {
+        return Err(ValidationError::new(
+            "context pins the app to one daemon, list it under hosts instead",
+        ));
+    }
+    Ok(())
+}
+
+fn validate_ipc_mode(mode: &str) -> Result<(), ValidationError> {
+    if !["private", "shareable", "none", "host"].contains(&mode) && !is_container_namespace(mode) {
+        return Err(ValidationError::new(
+            "ipc must be private, shareable, none, host or container:<name>",
+        ));
+    }
+    Ok(())
+}
+
+fn validate_pid_mode(mode: &str) -> Result<(), ValidationError> {
+    if mode != "host" && !is_container_namespace(mode) {
+        return Err(ValidationError::new("pid must be host or container:<name>"));
+    }
+    Ok(())
+}
+
+fn validate_uts_mode(mode: &str) -> Result<(), ValidationError> {
+    if mode != "host" {
+        return Err(ValidationError::new("uts can only be host"));
+    }
+    Ok(())
+}
+
+/// Whether `mode` is `container:<name>` with a valid container name or id.
+fn is_container_namespace(mode: &str) -> bool {
+    static NAME: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^[a-zA-Z0-9][a-zA-Z0-9_.-]*$").unwrap());
+    mode.strip_prefix("container:").is_some_and(|name| NAME.is_match(name))
+}
+
+fn validate_namespaces(config: &RukuConfig) -> Result<(), ValidationError> {
+    if !config.acknowledge_host_namespaces && config.namespaces().iter().any(|(_, mode)| *mode == "host") {
+        return Err(ValidationError::new(
+            "ipc, pid or uts host gives the app the namespace of the host, set acknowledge_host_namespaces to use it",
+        ));
+    }
+    Ok(())
+}
+
+fn validate_network(config: &RukuConfig) -> Result<(), ValidationError> {
+    if let NetworkMode::Custom(name) = &config.network_mode {
+        let valid = name.starts_with(|c: char| c.is_ascii_alphanumeric())
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '-');
+        if !valid {
+            return Err(ValidationError::new(
+                "network_mode must be bridge, none, host or the name of a Docker network",
+            ));
+        }
+    }
+    if config.internal && config.network_mode != NetworkMode::Bridge {
+        return Err(ValidationError::new(
+            "internal only applies to the app's own network, set network_mode to bridge",
+        ));
+    }
+    if !config.network_mode.publishes_ports() {
+        if config.port.auto {
+            return Err(ValidationError::new(
+                "auto ports need published ports, which network_mode host and none don't have",
+            ));
+        }
+        if config.strategy == DeployStrategy::Canary {
+            return Err(ValidationError::new(
+                "the canary strategy needs published ports, which network_mode host and none don't have",
+            ));
+        }
+    }
+    // Both versions would listen on the same port of the host
+    if config.network_mode == NetworkMode::Host && config.strategy == DeployStrategy::BlueGreen {
+        return Err(ValidationError::new(
+            "the blue_green strategy runs two versions at once, which network_mode host has no room for",
+        ));
+    }
+    Ok(())
 }
 
-fn validate_port(port: u16) -> Result<(), ValidationError> {
-    if !is_free(port) {
-        return Err(ValidationError::new("port is already in use"));
+fn validate_strategy(config: &RukuConfig) -> Result<(), ValidationError> {
+    if config.strategy == DeployStrategy::Canary {
+        match &config.canary {
+            None => return Err(ValidationError::new("canary strategy requires a canary section")),
+            Some(_) if !config.port.is_published() => {
+                return Err(ValidationError::new(
+                    "the canary strategy needs a published port, set port in ruku.yml or use auto",
+                ))
+            }
+            Some(canary) if !config.port.auto && canary.port == config.port.host_port => {
+                return Err(ValidationError::new("canary port must differ from the app port"))
+            }
+            _ => {}
+        }
     }
     Ok(())
 }
@@ -0,0 +1,113 @@
+use std::collections::HashMap;
+
+use bollard::errors::Error;
+use bollard::models::EndpointSettings;
+use bollard::network::{ConnectNetworkOptions, CreateNetworkOptions, DisconnectNetworkOptions};
+use bollard::Docker;
+
+use crate::container::{test_labels, APP_LABEL};
+use crate::logger::Logger;
+
+/// The bridge network of an app. Its container is reachable there by the app name, linked apps join it.
+pub fn get_network_name(app: &str) -> String {
+    format!("ruku-{}", app)
+}
+
+/// The Docker networks ruku creates for apps.
+pub struct Networks<'a> {
+    log: &'a Logger,
+    docker: &'a Docker,
+}
+
+impl<'a> Networks<'a> {
+    pub fn new(log: &'a Logger, docker: &'a Docker) -> Networks<'a> {
+        Networks { log, docker }
+    }
+
+    /// Create the network of `app` unless it exists, so apps that link each other can deploy in any order.
+    /// An `internal` network has no route out, one that already exists without that can't be changed.
+    pub async fn ensure(&self, app: &str, internal: bool) -> Result<(), String> {
+        let network_name = get_network_name(app);
+        if let Ok(network) = self.docker.inspect_network::<String>(&network_name, None).await {
+            if internal && network.internal != Some(true) {
+                return Err(format!(
+                    "Network {} is not internal, remove it with `docker network rm {}` while the app is stopped so it is recreated",
+                    network_name, network_name
+                ));
+            }
+            return Ok(());
+        }
+        let options = CreateNetworkOptions {
+            name: network_name.clone(),
+            driver: "bridge".to_string(),
+            internal,
+            labels: HashMap::from_iter(
+                [(APP_LABEL.to_string(), app.to_string())]
+                    .into_iter()
+                    .chain(test_labels()),
+            ),
+            ..Default::default()
+        };
+        match self.docker.create_network(options).await {
+            Ok(_) if internal => self.log.step(&format!("Created internal network {}", network_name)),
+            Ok(_) => self.log.step(&format!("Created network {}", network_name)),
+            // Another deploy created it in the meantime
+            Err(Error::DockerResponseServerError { status_code: 409, .. }) => {}
+            Err(e) => return Err(format!("Failed to create network {}: {}", network_name, e)),
+        }
+        Ok(())
+    }
+
+    /// Fail unless the network, created outside of ruku, exists.
+    pub async fn require(&self, network_name: &str) -> Result<(), String> {
+        if let Err(e) = self.docker.inspect_network::<String>(network_name, None).await {
+            return Err(format!("Network {} is not available: {}", network_name, e));
+        }
+        Ok(())
+    }
+
+    /// Attach a container to the network of `app`, reachable there by `alias`.
+    pub async fn connect(&self, container_name: &str, app: &str, alias: &str) -> Result<(), String> {
+        let network_name = get_network_name(app);
+        let options = ConnectNetworkOptions {
+            container: container_name.to_string(),
+            endpoint_config: EndpointSettings {
+                aliases: Some(vec![alias.to_string()]),
+                ..Default::default()
+            },
+        };
+        match self.docker.connect_network(&network_name, options).await {
+            Ok(()) => self
+                .log
+                .step(&format!("Connected {} to network {}", container_name, network_name)),
+            // 403 means the container is already attached
+            Err(Error::DockerResponseServerError { status_code: 403, .. }) => {}
+            Err(e) => {
+                return Err(format!(
+                    "Failed to connect {} to network {}: {}",
+                    container_name, network_name, e
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    /// Detach a container from the network of `app`, a container that isn't attached is left as is.
+    pub async fn disconnect(&self, container_name: &str, app: &str) {
+        let network_name = get_network_name(app);
+        let options = DisconnectNetworkOptions {
+            container: container_name.to_string(),
+            force: false,
+        };
+        match self.docker.disconnect_network(&network_name, options).await {
+            Ok(()) => self.log.step(&format!(
+                "Disconnected {} from network {}",
+                container_name, network_name
+            )),
+            Err(e) => self.log.warn(&format!(
+                "Failed to disconnect {} from network {}: {}",
+                container_name, network_name, e
+            )),
+        }
+    }
+}
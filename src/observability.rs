@@ -0,0 +1,71 @@
+use std::collections::BTreeMap;
+
+use serde_json::json;
+
+use crate::misc::get_version;
+use crate::model::{Convention, ObservabilityConfig, RukuConfig};
+
+/// Prefix of the Datadog autodiscovery labels, whose values the agent parses as JSON.
+pub const DATADOG_ANNOTATION_PREFIX: &str = "com.datadoghq.ad.";
+
+/// Whether `value` parses as JSON.
+pub fn is_json(value: &str) -> bool {
+    serde_json::from_str::<serde_json::Value>(value).is_ok()
+}
+
+/// The labels `observability` asks for, before the `labels` of the config, which override them.
+pub fn labels(app: &str, config: &RukuConfig) -> BTreeMap<String, String> {
+    let Some(observability) = &config.observability else {
+        return BTreeMap::new();
+    };
+    let service = service(app, observability);
+    let version = get_version(&config.version).to_string();
+    let mut labels = BTreeMap::new();
+    if observability.emit.contains(&Convention::Datadog) {
+        labels.insert("com.datadoghq.tags.service".to_string(), service.clone());
+        labels.insert("com.datadoghq.tags.version".to_string(), version.clone());
+        if let Some(env) = &observability.env {
+            labels.insert("com.datadoghq.tags.env".to_string(), env.clone());
+        }
+        let mut logs = json!({ "service": service });
+        if let Some(source) = &observability.source {
+            logs["source"] = json!(source);
+        }
+        labels.insert(format!("{}logs", DATADOG_ANNOTATION_PREFIX), json!([logs]).to_string());
+    }
+    if observability.emit.contains(&Convention::Oci) {
+        labels.insert("org.opencontainers.image.title".to_string(), service.clone());
+        labels.insert("org.opencontainers.image.version".to_string(), version);
+    }
+    labels
+}
+
+/// The env `observability` asks for.
+pub fn env(app: &str, config: &RukuConfig) -> BTreeMap<String, String> {
+    let Some(observability) = &config.observability else {
+        return BTreeMap::new();
+    };
+    let service = service(app, observability);
+    let version = get_version(&config.version).to_string();
+    let mut env = BTreeMap::new();
+    if observability.emit.contains(&Convention::Datadog) {
+        env.insert("DD_SERVICE".to_string(), service.clone());
+        env.insert("DD_VERSION".to_string(), version.clone());
+        if let Some(deploy_env) = &observability.env {
+            env.insert("DD_ENV".to_string(), deploy_env.clone());
+        }
+    }
+    if observability.emit.contains(&Convention::Otel) {
+        let mut attributes = vec![format!("service.version={}", version)];
+        if let Some(deploy_env) = &observability.env {
+            attributes.push(format!("deployment.environment={}", deploy_env));
+        }
+        env.insert("OTEL_SERVICE_NAME".to_string(), service);
+        env.insert("OTEL_RESOURCE_ATTRIBUTES".to_string(), attributes.join(","));
+    }
+    env
+}
+
+fn service(app: &str, observability: &ObservabilityConfig) -> String {
+    observability.service.clone().unwrap_or(app.to_string())
+}
@@ -0,0 +1,182 @@
+use std::env;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use cmd_lib::run_fun;
+use serde_json::{json, Value};
+
+use crate::events::Event;
+use crate::server_config::ServerConfig;
+
+/// Env var naming the OTLP collector, as the OpenTelemetry SDKs read it, e.g. `http://localhost:4318`.
+const ENDPOINT_ENV: &str = "OTEL_EXPORTER_OTLP_ENDPOINT";
+const SERVICE_NAME_ENV: &str = "OTEL_SERVICE_NAME";
+/// Seconds the export may take, the deploy is done by then and only waits for it.
+const EXPORT_TIMEOUT: u64 = 5;
+
+/// The OTLP/HTTP endpoint traces are sent to, from the env or `otel_endpoint` in the global config.
+pub fn endpoint(server_config: &ServerConfig) -> Option<String> {
+    env::var(ENDPOINT_ENV)
+        .ok()
+        .filter(|endpoint| !endpoint.is_empty())
+        .or(server_config.otel_endpoint.clone())
+}
+
+struct Span {
+    id: String,
+    name: String,
+    start: u128,
+    end: Option<u128>,
+}
+
+/// One deploy as a trace: a `deploy` span with a child span for every stage the logger reports, named
+/// like the stage so the spans line up with the log.
+pub struct Trace {
+    endpoint: String,
+    trace_id: String,
+    root: Span,
+    stages: Vec<Span>,
+    attributes: Vec<(String, String)>,
+}
+
+impl Trace {
+    pub fn new(endpoint: String, app: &str, version: &str) -> Trace {
+        Trace {
+            endpoint,
+            trace_id: random_id(16),
+            root: Span {
+                id: random_id(8),
+                name: "deploy".to_string(),
+                start: now(),
+                end: None,
+            },
+            stages: vec![],
+            attributes: vec![
+                ("ruku.app".to_string(), app.to_string()),
+                ("ruku.version".to_string(), version.to_string()),
+            ],
+        }
+    }
+
+    /// Add an attribute to the deploy span, e.g. the image digest once it is known.
+    pub fn set(&mut self, key: &str, value: &str) {
+        self.attributes.push((key.to_string(), value.to_string()));
+    }
+
+    /// Open and close stage spans as the stage events come in.
+    pub fn record(&mut self, event: &Event) {
+        match event {
+            Event::StageStarted { stage } => self.stages.push(Span {
+                id: random_id(8),
+                name: stage.clone(),
+                start: now(),
+                end: None,
+            }),
+            Event::StageCompleted { stage, .. } => {
+                if let Some(span) = self.stages.iter_mut().rev().find(|span| span.name == *stage) {
+                    span.end.get_or_insert_with(now);
+                }
+            }
+            Event::LogLine { .. } | Event::PullProgress { .. } | Event::BuildStep { .. } | Event::Error { .. } => {}
+        }
+    }
+
+    /// Close every open span and send the trace, `error` is the message a failed deploy stopped with.
+    pub fn export(mut self, error: Option<&str>) -> Result<(), String> {
+        let end = now();
+        self.root.end = Some(end);
+        self.attributes.push((
+            "ruku.outcome".to_string(),
+            if error.is_some() { "failed" } else { "succeeded" }.to_string(),
+        ));
+        // A failed deploy leaves the stage it failed in open
+        let status = |failed: bool| match (failed, error) {
+            (true, Some(message)) => json!({ "code": 2, "message": message }),
+            _ => json!({ "code": 1 }),
+        };
+        let mut spans = vec![self.span(&self.root, None, &self.attributes, status(true))];
+        for stage in &self.stages {
+            let attributes = [("ruku.stage".to_string(), stage.name.clone())];
+            let span = Span {
+                end: Some(stage.end.unwrap_or(end)),
+                id: stage.id.clone(),
+                name: stage.name.clone(),
+                start: stage.start,
+            };
+            spans.push(self.span(&span, Some(&self.root.id), &attributes, status(stage.end.is_none())));
+        }
+
+        let service = env::var(SERVICE_NAME_ENV).unwrap_or("ruku".to_string());
+        let body = json!({
+            "resourceSpans": [{
+                "resource": { "attributes": attributes(&[("service.name".to_string(), service)]) },
+                "scopeSpans": [{
+                    "scope": { "name": "ruku", "version": env!("CARGO_PKG_VERSION") },
+                    "spans": spans,
+                }],
+            }],
+        });
+        let mut file = tempfile::NamedTempFile::new().map_err(|e| e.to_string())?;
+        file.write_all(body.to_string().as_bytes()).map_err(|e| e.to_string())?;
+        let data = format!("@{}", file.path().display());
+        let url = format!("{}/v1/traces", self.endpoint.trim_end_matches('/'));
+        let timeout = EXPORT_TIMEOUT.to_string();
+        run_fun!(
+            curl --silent --fail --max-time $timeout
+                --header "Content-Type: application/json" --data-binary $data $url
+        )
+        .map(|_| ())
+        .map_err(|_| {
+            format!(
+                "Could not send the deploy trace to {}, the collector is unreachable or refused it",
+                self.endpoint
+            )
+        })
+    }
+
+    fn span(&self, span: &Span, parent: Option<&str>, attrs: &[(String, String)], status: Value) -> Value {
+        json!({
+            "traceId": self.trace_id,
+            "spanId": span.id,
+            "parentSpanId": parent.unwrap_or_default(),
+            "name": span.name,
+            // SPAN_KIND_INTERNAL
+            "kind": 1,
+            "startTimeUnixNano": span.start.to_string(),
+            "endTimeUnixNano": span.end.unwrap_or(span.start).to_string(),
+            "attributes": attributes(attrs),
+            "status": status,
+        })
+    }
+}
+
+fn attributes(attributes: &[(String, String)]) -> Value {
+    attributes
+        .iter()
+        .map(|(key, value)| json!({ "key": key, "value": { "stringValue": value } }))
+        .collect()
+}
+
+fn now() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or_default()
+}
+
+/// A hex id of `bytes` random bytes, trace ids take 16 and span ids 8.
+fn random_id(bytes: usize) -> String {
+    let mut buf = vec![0u8; bytes];
+    if File::open("/dev/urandom")
+        .and_then(|mut urandom| urandom.read_exact(&mut buf))
+        .is_err()
+    {
+        // Unique enough for a trace id, nothing depends on it being unpredictable
+        let seed = now() ^ (u128::from(std::process::id()) << 64);
+        for (i, b) in buf.iter_mut().enumerate() {
+            *b = (seed >> ((i % 16) * 8)) as u8 ^ (i as u8).wrapping_mul(31);
+        }
+    }
+    buf.iter().map(|b| format!("{:02x}", b)).collect()
+}
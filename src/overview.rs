@@ -0,0 +1,123 @@
+use bollard::container::{MemoryStatsStats, Stats};
+use bollard::models::ContainerSummary;
+
+use crate::app_url::app_url;
+use crate::auxiliary::is_aux;
+use crate::config::load_ruku_config;
+use crate::connection::local_docker_host;
+use crate::container::{deployed_version, get_container_name, APP_LABEL, ROLE_LABEL};
+use crate::misc::{describe_version_drift, get_version};
+use crate::server_config::ServerConfig;
+
+/// A container of a ruku app, as `list` and the dashboard show it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AppRow {
+    pub app: String,
+    pub container: String,
+    /// `stable`, `canary` or a sidecar role, none for containers from before the role label.
+    pub role: Option<String>,
+    pub state: String,
+    /// What Docker reports, e.g. `Up 3 minutes (healthy)`.
+    pub status: String,
+    /// The live version against the configured one.
+    pub version: String,
+    /// Where the app answers, none when it publishes no TCP port.
+    pub url: Option<String>,
+}
+
+impl AppRow {
+    pub fn from_summary(summary: &ContainerSummary, server_config: &ServerConfig) -> AppRow {
+        let container = get_container_name(summary).unwrap_or_default();
+        let labels = summary.labels.as_ref();
+        let app = labels
+            .and_then(|labels| labels.get(APP_LABEL))
+            .cloned()
+            .unwrap_or_else(|| container.clone());
+        let live = deployed_version(summary);
+        let version = match load_ruku_config(&app, server_config) {
+            Ok(config) => describe_version_drift(live.as_deref(), get_version(&config.version)),
+            Err(_) => format!("running {}", live.as_deref().unwrap_or("an unknown version")),
+        };
+        AppRow {
+            app,
+            container,
+            role: labels.and_then(|labels| labels.get(ROLE_LABEL)).cloned(),
+            state: summary.state.clone().unwrap_or("unknown".to_string()),
+            status: summary.status.clone().unwrap_or_default(),
+            version,
+            url: app_url(summary, &local_docker_host(), &server_config.state_root),
+        }
+    }
+
+    /// Whether this is the container that serves the app rather than a canary or sidecar.
+    pub fn is_stable(&self) -> bool {
+        self.role.as_deref().unwrap_or("stable") == "stable"
+    }
+
+    /// The health Docker appends to the status, none without a healthcheck.
+    pub fn health(&self) -> Option<&str> {
+        let (_, health) = self.status.rsplit_once('(')?;
+        let health = health.strip_suffix(')')?;
+        Some(health.strip_prefix("health: ").unwrap_or(health))
+    }
+}
+
+/// Rows for the containers of every app, in the order they are listed. One-off containers are left out,
+/// `list --aux` shows them.
+pub fn app_rows(summaries: &[ContainerSummary], server_config: &ServerConfig) -> Vec<AppRow> {
+    summaries
+        .iter()
+        .filter(|summary| !is_aux(summary))
+        .map(|summary| AppRow::from_summary(summary, server_config))
+        .collect()
+}
+
+/// CPU and memory of a container in one stats sample.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Usage {
+    /// Percent of one CPU, so a container busy on two CPUs is at 200.
+    pub cpu_percent: f64,
+    pub memory: u64,
+    pub memory_limit: Option<u64>,
+}
+
+impl Usage {
+    /// The usage since the previous sample, the way `docker stats` computes it: memory leaves out the
+    /// inactive page cache the kernel can reclaim.
+    pub fn from_stats(stats: &Stats) -> Usage {
+        let cpu_delta = stats
+            .cpu_stats
+            .cpu_usage
+            .total_usage
+            .saturating_sub(stats.precpu_stats.cpu_usage.total_usage);
+        let system_delta = stats
+            .cpu_stats
+            .system_cpu_usage
+            .unwrap_or_default()
+            .saturating_sub(stats.precpu_stats.system_cpu_usage.unwrap_or_default());
+        let cpus = stats.cpu_stats.online_cpus.unwrap_or_else(|| {
+            let per_cpu = stats.cpu_stats.cpu_usage.percpu_usage.as_ref();
+            per_cpu.map_or(1, |usage| usage.len() as u64)
+        });
+        let cpu_percent = if system_delta > 0 {
+            cpu_delta as f64 / system_delta as f64 * cpus as f64 * 100.0
+        } else {
+            0.0
+        };
+
+        let inactive = match &stats.memory_stats.stats {
+            Some(MemoryStatsStats::V1(stats)) => stats.total_inactive_file,
+            Some(MemoryStatsStats::V2(stats)) => stats.inactive_file,
+            None => 0,
+        };
+        Usage {
+            cpu_percent,
+            memory: stats.memory_stats.usage.unwrap_or_default().saturating_sub(inactive),
+            // Without a limit the daemon reports 0 or the cgroup maximum depending on the version
+            memory_limit: stats
+                .memory_stats
+                .limit
+                .filter(|limit| *limit > 0 && *limit != u64::MAX),
+        }
+    }
+}
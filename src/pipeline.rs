@@ -0,0 +1,723 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use bollard::Docker;
+use chrono::Utc;
+use validator::Validate;
+
+use crate::auxiliary::set_deployment;
+use crate::backup::Backups;
+use crate::config::{get_dependencies, get_links, load_ruku_config_with_provenance, load_valid_ruku_config};
+use crate::connection::get_docker_for;
+use crate::container::{deployed_version, is_managed, Container, DEFAULT_HEALTH_TIMEOUT};
+use crate::deadline::Deadlines;
+use crate::dependency::Dependencies;
+use crate::deploy::Deploy;
+use crate::deploys::AppLock;
+use crate::error::RukuError;
+use crate::failures::Failures;
+use crate::history::{deployment_id, Deployment, History};
+use crate::image::Image;
+use crate::image_drift::{self, ImageDrift};
+use crate::image_tarball::ImageTarball;
+use crate::image_wait::ImageWait;
+use crate::inflight::InFlight;
+use crate::logger::Logger;
+use crate::logs::require_healthy;
+use crate::maintenance::MaintenanceState;
+use crate::metrics::Metrics;
+use crate::migrate::Migration;
+use crate::misc::{describe_version_drift, get_image_name_with_version, get_version};
+use crate::model::{DeployStrategy, RukuConfig};
+use crate::policy::Policy;
+use crate::ports::PortAssigner;
+use crate::preflight::Preflight;
+use crate::provenance::{Provenance, Source};
+use crate::proxy::Proxy;
+use crate::read_only::guard;
+use crate::recreate::Recreate;
+use crate::release_logs::{release_of, ReleaseLogs};
+use crate::releases::{Releases, Snapshot};
+use crate::reload::{Reload, TemplateChecksums};
+use crate::repair::Repair;
+use crate::rootless::LowPorts;
+use crate::sbom::Sboms;
+use crate::server_config::ServerConfig;
+use crate::sidecar::Sidecars;
+use crate::slots::DeploySlots;
+use crate::staging::Staging;
+use crate::static_site::static_root;
+use crate::strategy;
+use crate::templates::{self, Templates};
+
+pub use crate::container::Takeover;
+pub use crate::scan::ScanSummary;
+
+/// What a successful deploy put in place.
+#[derive(Debug, Clone)]
+pub struct DeployOutcome {
+    pub app: String,
+    /// The id the deployment is kept under in the history and the config snapshots.
+    pub deployment_id: String,
+    pub version: Option<String>,
+    pub image: String,
+    /// Registry digest of the image when it was pushed.
+    pub digest: Option<String>,
+    pub scan: Option<ScanSummary>,
+    /// Host port the app is published on.
+    pub host_port: u16,
+    pub strategy: DeployStrategy,
+    /// Seconds each stage took, keyed by stage name.
+    pub stages: BTreeMap<String, f64>,
+    pub seconds: f64,
+    /// The note the deploy was run with.
+    pub message: Option<String>,
+}
+
+/// The whole deploy of an app from its ruku.yml, as `ruku run` does it: repair, dependency and port checks,
+/// templates, build, start, health gate and bookkeeping. Progress goes through the logger, which sends
+/// typed events when created with [`Logger::with_events`].
+pub struct DeployPipeline<'a> {
+    log: &'a Logger,
+    app: &'a str,
+    server_config: &'a ServerConfig,
+    takeover: Takeover,
+    show_context: bool,
+    wait_healthy: Option<Duration>,
+    skip_scan: bool,
+    skip_pre_start: bool,
+    wait_for_lock: bool,
+    backup: bool,
+    strategy: Option<DeployStrategy>,
+    share: bool,
+    skip_preflight: bool,
+    no_cache: bool,
+    pull: bool,
+    image_tarball: Option<PathBuf>,
+    wait_for_image: Option<(Duration, Option<String>)>,
+    message: Option<String>,
+    stage: bool,
+}
+
+impl<'a> DeployPipeline<'a> {
+    pub fn new(log: &'a Logger, app: &'a str, server_config: &'a ServerConfig) -> DeployPipeline<'a> {
+        DeployPipeline {
+            log,
+            app,
+            server_config,
+            takeover: Takeover::default(),
+            show_context: false,
+            wait_healthy: None,
+            skip_scan: false,
+            skip_pre_start: false,
+            wait_for_lock: false,
+            backup: true,
+            strategy: None,
+            share: true,
+            skip_preflight: false,
+            no_cache: false,
+            pull: false,
+            image_tarball: None,
+            wait_for_image: None,
+            message: None,
+            stage: false,
+        }
+    }
+
+    /// What to do with a container by the app's name that ruku did not create.
+    pub fn with_takeover(mut self, takeover: Takeover) -> DeployPipeline<'a> {
+        self.takeover = takeover;
+        self
+    }
+
+    /// List the paths of the Dockerfile build context while building.
+    pub fn with_show_context(mut self, show_context: bool) -> DeployPipeline<'a> {
+        self.show_context = show_context;
+        self
+    }
+
+    /// Fail the deploy unless the new container becomes healthy within the timeout.
+    pub fn with_wait_healthy(mut self, timeout: Option<Duration>) -> DeployPipeline<'a> {
+        self.wait_healthy = timeout;
+        self
+    }
+
+    /// Deploy without the configured vulnerability scan.
+    pub fn with_skip_scan(mut self, skip_scan: bool) -> DeployPipeline<'a> {
+        self.skip_scan = skip_scan;
+        self
+    }
+
+    /// Start the new container without running the configured `pre_start` command.
+    pub fn with_skip_pre_start(mut self, skip_pre_start: bool) -> DeployPipeline<'a> {
+        self.skip_pre_start = skip_pre_start;
+        self
+    }
+
+    /// Wait for a deploy of the same app that is running to finish instead of refusing to start.
+    pub fn with_wait_for_lock(mut self, wait_for_lock: bool) -> DeployPipeline<'a> {
+        self.wait_for_lock = wait_for_lock;
+        self
+    }
+
+    /// Commit a container ruku did not create to a backup image before force replacing it.
+    pub fn with_backup(mut self, backup: bool) -> DeployPipeline<'a> {
+        self.backup = backup;
+        self
+    }
+
+    /// Deploy with this strategy instead of the one in ruku.yml.
+    pub fn with_strategy(mut self, strategy: Option<DeployStrategy>) -> DeployPipeline<'a> {
+        self.strategy = strategy;
+        self
+    }
+
+    /// Wait for and use a build of the same image another ruku process is running, instead of building it
+    /// again.
+    pub fn with_share(mut self, share: bool) -> DeployPipeline<'a> {
+        self.share = share;
+        self
+    }
+
+    /// Deploy without checking the disk, memory and file handles of the host first.
+    pub fn with_skip_preflight(mut self, skip_preflight: bool) -> DeployPipeline<'a> {
+        self.skip_preflight = skip_preflight;
+        self
+    }
+
+    /// Build every step again instead of taking it from the build cache.
+    pub fn with_no_cache(mut self, no_cache: bool) -> DeployPipeline<'a> {
+        self.no_cache = no_cache;
+        self
+    }
+
+    /// Pull newer versions of the base images before building.
+    pub fn with_pull(mut self, pull: bool) -> DeployPipeline<'a> {
+        self.pull = pull;
+        self
+    }
+
+    /// Deploy the image in this tarball instead of the `image_tarball` of ruku.yml or a build.
+    pub fn with_image_tarball(mut self, image_tarball: Option<PathBuf>) -> DeployPipeline<'a> {
+        self.image_tarball = image_tarball;
+        self
+    }
+
+    /// Wait up to the timeout for the image to be pushed, and with a digest for the tag to point at it,
+    /// then deploy it instead of building.
+    pub fn with_wait_for_image(mut self, timeout: Option<Duration>, digest: Option<String>) -> DeployPipeline<'a> {
+        self.wait_for_image = timeout.map(|timeout| (timeout, digest));
+        self
+    }
+
+    /// Keep this note with the deployment and put it on the new container.
+    pub fn with_message(mut self, message: Option<String>) -> DeployPipeline<'a> {
+        self.message = message;
+        self
+    }
+
+    /// Build, check and create the new version under the staging name without starting it, for
+    /// [`DeployPipeline::promote`] to put in place later.
+    pub fn with_stage(mut self, stage: bool) -> DeployPipeline<'a> {
+        self.stage = stage;
+        self
+    }
+
+    /// Run the deploy. Its error is also sent as an [`Event::Error`](crate::events::Event::Error), after
+    /// what it changed has been rolled back.
+    pub async fn run(&self) -> Result<DeployOutcome, RukuError> {
+        let outcome = async {
+            guard(&format!("deploy {}", self.app))?;
+            let config = load_valid_ruku_config(self.app, self.server_config).and_then(|mut config| {
+                if let Some(strategy) = self.strategy {
+                    config.strategy = strategy;
+                    // The config has to suit the strategy, e.g. canary needs a canary section
+                    config
+                        .validate()
+                        .map_err(|e| format!("Error validating ruku.yml file with --strategy {}: {}", strategy, e))?;
+                }
+                Ok(config)
+            });
+            match config {
+                Ok(config) => self.deploy(config).await.map_err(RukuError::Failed),
+                Err(e) => Err(RukuError::Config(e)),
+            }
+        };
+        self.reported(outcome.await)
+    }
+
+    /// Put the deploy `ruku stage` left in place: write its templates, swap its container in for the
+    /// running one and gate on health, rolling back when it fails. Fails when nothing is staged or the
+    /// config changed since it was.
+    pub async fn promote(&self) -> Result<DeployOutcome, RukuError> {
+        let outcome = async {
+            guard(&format!("promote {}", self.app))?;
+            match load_valid_ruku_config(self.app, self.server_config) {
+                Ok(config) => self.promote_staged(config).await.map_err(RukuError::Failed),
+                Err(e) => Err(RukuError::Config(e)),
+            }
+        };
+        self.reported(outcome.await)
+    }
+
+    /// `outcome` with its error sent to the logger, which ends the events of the deploy.
+    fn reported(&self, outcome: Result<DeployOutcome, RukuError>) -> Result<DeployOutcome, RukuError> {
+        if let Err(e) = &outcome {
+            self.log.error(&e.to_string());
+        }
+        outcome
+    }
+
+    async fn deploy(&self, mut config: RukuConfig) -> Result<DeployOutcome, String> {
+        let (log, app, server_config) = (self.log, self.app, self.server_config);
+        let mut provenance = load_ruku_config_with_provenance(app, server_config)
+            .map(|(_, provenance)| provenance)
+            .unwrap_or_else(|_| Provenance::new());
+        if self.strategy.is_some() {
+            provenance.set(
+                "strategy",
+                Source::Cli {
+                    flag: "--strategy".to_string(),
+                },
+            );
+        }
+        #[cfg(feature = "otel")]
+        if let Some(endpoint) = crate::otel::endpoint(server_config) {
+            log.start_trace(crate::otel::Trace::new(endpoint, app, get_version(&config.version)));
+        }
+        let docker = get_docker_for(log, &config).await?;
+
+        let state_path = server_config.state_root.join(app);
+        // Held until the deploy returns, the repair below would otherwise clean up a running deploy
+        let _lock = AppLock::acquire(log, app, &state_path, self.wait_for_lock).await?;
+        // The maintenance page holds the port, and `maintenance:off` would start the old container again
+        if let Some(maintenance) = MaintenanceState::read(&state_path) {
+            return Err(format!(
+                "{} is in maintenance mode {}, run `ruku maintenance:off {}` before deploying",
+                app,
+                maintenance.describe(Utc::now()),
+                app
+            ));
+        }
+        if let Some(staged) = Staging::read(log, &state_path)?.filter(|_| !self.stage) {
+            log.warn(&format!(
+                "{} is staged and stays so, `ruku promote {}` would still put it in place and \
+                 `ruku stage {} --abandon` removes it",
+                staged.image, app, app
+            ));
+        }
+        // Before anything of the app is touched, a wait that times out leaves it as it is
+        if let Some((timeout, digest)) = &self.wait_for_image {
+            ImageWait::new(log, &docker, *timeout)
+                .with_digest(digest.clone())
+                .run(app, &config, &state_path)
+                .await?;
+        }
+        let app_path = server_config.apps_root.join(app);
+        let started_at = Utc::now();
+        // Counted once the app lock is held, a deploy queued behind another one is not running yet
+        let deadlines = Deadlines::new(&config);
+        set_deployment(&deployment_id(started_at));
+
+        // Looking the container up renames one from before the prefix, which would otherwise look like a
+        // leftover to the repair
+        Container::new(log, app, &docker, &config).get().await?;
+        // Clear out what an interrupted deploy left behind before starting a new one
+        Repair::new(log, app, &config.container_prefix, &docker, &state_path)
+            .with_sidecars(config.sidecars.iter().map(|sidecar| sidecar.name.clone()).collect())
+            .with_aux_max_age(Duration::from_secs(server_config.aux_max_age))
+            .run_quick()
+            .await?;
+
+        let reservation = server_config.host.reservation(app, &config)?;
+        if config.port.auto {
+            let owned = Container::new(log, app, &docker, &config).published_ports().await?;
+            PortAssigner::new(log, &server_config.state_root)
+                .with_reservation(&server_config.host, reservation.as_ref())
+                .assign(app, &mut config, &owned)?;
+        }
+        LowPorts::new(log, &docker, &state_path).check(&mut config).await?;
+        let config = config;
+
+        let links = get_links(log, app, server_config)?;
+        let templates = Templates::new(log, &state_path);
+        let backups = Backups::new(log, app, &docker, &state_path)
+            .with_timeout(Duration::from_secs(server_config.backup_timeout));
+        let container = Container::new(log, app, &docker, &config)
+            .with_takeover(self.takeover)
+            .with_links(links.clone())
+            .with_template_dir(templates.dir().to_path_buf())
+            .with_static_root(static_root(&app_path, &config))
+            .with_skip_pre_start(self.skip_pre_start)
+            .with_backups(self.backup.then_some(&backups))
+            .with_deploy_message(self.message.clone());
+        // Adopting recreates the container that holds the name and port as it is, there is nothing to deploy
+        if let Some(adopted) = container.adopt().await? {
+            let seconds = (Utc::now() - started_at).num_milliseconds() as f64 / 1000.0;
+            log.section(&format!(
+                "Adopted {}, the next `ruku run {}` replaces it with the configured version",
+                container.container_name(),
+                app
+            ));
+            return Ok(DeployOutcome {
+                app: app.to_string(),
+                deployment_id: deployment_id(started_at),
+                version: None,
+                image: adopted.image.unwrap_or_default(),
+                digest: None,
+                scan: None,
+                host_port: config.port.host_port,
+                strategy: config.strategy,
+                stages: BTreeMap::new(),
+                seconds,
+                message: self.message.clone(),
+            });
+        }
+        let mut host_ports = container.host_ports()?;
+        if config.strategy == DeployStrategy::Canary {
+            host_ports.extend(container.canary().host_ports()?);
+        }
+        if config.network_mode.publishes_ports() {
+            let sidecar_ports = config.sidecars.iter().flat_map(|sidecar| &sidecar.ports);
+            host_ports.extend(sidecar_ports.map(|port| port.host_port).filter(|port| *port != 0));
+        }
+        for port in host_ports {
+            server_config.host.check_port(reservation.as_ref(), port)?;
+        }
+        container.check_ports().await?;
+        if config.strategy == DeployStrategy::Canary {
+            container.canary().check_ports().await?;
+        }
+        if self.skip_preflight {
+            log.warn("Skipping the host resource checks");
+        } else {
+            // The new image is assumed to be about as big as the one running now
+            let running_image = container.get().await?.and_then(|summary| summary.image_id);
+            Preflight::new(log, &docker).run(running_image.as_deref()).await?;
+        }
+        Dependencies::new(log, &docker)
+            .wait(
+                &get_dependencies(&config, server_config),
+                Duration::from_secs(config.dependency_timeout),
+            )
+            .await?;
+        // Rendering errors stop the deploy while the old container is still untouched
+        let rendered = templates.render_all(&config, &templates::variables(app, &config, &links)?)?;
+        let reload = Reload::new(log, app, &docker, &config, &container, &state_path, &rendered);
+        // A staged deploy gets its templates when promoted, the running container keeps its own until then
+        let reloading = !self.stage && reload.plan().await?;
+        if let Some(summary) = container.get().await? {
+            Migration::new(log, &state_path).run(&summary)?;
+            log.step(&describe_version_drift(
+                deployed_version(&summary).as_deref(),
+                get_version(&config.version),
+            ));
+            // The running image is what a failed deploy rolls back to
+            match image_drift::check(log, &docker, container.container_name()).await {
+                Some(ImageDrift::Current) | None => {}
+                Some(drift) => log.warn(&format!("{}: {}", container.container_name(), drift)),
+            }
+        }
+
+        // Registered as a loaded image, which the build below takes instead of building
+        let image_tarball = self
+            .image_tarball
+            .clone()
+            .or(config.image_tarball.as_ref().map(|tarball| app_path.join(tarball)));
+        if let Some(tarball) = image_tarball {
+            ImageTarball::new(log, &docker)
+                .load(app, &config.version, &tarball, &state_path)
+                .await?;
+        }
+
+        let slots = DeploySlots::new(log, server_config);
+        // A build asked to skip the cache or pull must not be one another process made
+        let in_flight = InFlight::new(log, server_config).with_share(self.share && !self.no_cache && !self.pull);
+        let failures = Failures::new(log, &state_path)
+            .with_deployment(&docker, &deployment_id(started_at))
+            .with_timeout(Duration::from_secs(server_config.capture_timeout))
+            .with_retention(server_config.release_retention);
+        let policy = Policy::combine(server_config.policy.as_ref(), config.policy.as_ref());
+        let deploy = Deploy::new(
+            log,
+            app,
+            app_path.as_path().to_str().unwrap(),
+            &state_path,
+            &config,
+            &docker,
+            &container,
+        )
+        .with_show_context(self.show_context)
+        .with_skip_scan(self.skip_scan)
+        .with_no_cache(self.no_cache)
+        .with_pull(self.pull)
+        .with_deploy_slots(&slots)
+        .with_in_flight(&in_flight)
+        .with_failures(&failures)
+        .with_deadlines(&deadlines)
+        .with_reload(reloading.then_some(&reload))
+        .with_policy(policy.as_ref())
+        .with_start(!self.stage)
+        .with_health_timeout(self.wait_healthy.unwrap_or(Duration::from_secs(DEFAULT_HEALTH_TIMEOUT)));
+        // The container of the running release goes with the deploy, its output stays for `logs --release`
+        if !self.stage {
+            capture_release_logs(log, server_config, &docker, &container, &state_path).await?;
+        }
+        let metrics = Metrics::new(log, &state_path);
+        // The deploy metrics count what went live, a staged deploy counts once promoted
+        if !self.stage {
+            metrics.begin();
+        }
+        let mut report = deploy.run().await?;
+        if self.stage {
+            log.stage_started("stage");
+            let stage_started = Instant::now();
+            let staged = Staging::new(log, &container, &state_path)
+                .stage(started_at, &config.version, &report, self.message.clone())
+                .await?;
+            let seconds = stage_started.elapsed().as_secs_f64();
+            log.stage_completed("stage", seconds);
+            report.stages.insert("stage".to_string(), seconds);
+            let seconds = (Utc::now() - started_at).num_milliseconds() as f64 / 1000.0;
+            log.section(&format!(
+                "Staged {} as {} in {:.1}s, `ruku promote {}` puts it in place",
+                staged.image, staged.container, seconds, app
+            ));
+            return Ok(DeployOutcome {
+                app: app.to_string(),
+                deployment_id: staged.id,
+                version: staged.version,
+                image: staged.image,
+                digest: report.digest,
+                scan: report.scan,
+                host_port: config.port.host_port,
+                strategy: DeployStrategy::Rolling,
+                stages: report.stages,
+                seconds,
+                message: self.message.clone(),
+            });
+        }
+        let reloaded = report.stages.contains_key("reload");
+        if !reloaded {
+            if let Some(container_id) = container.get().await?.and_then(|summary| summary.id) {
+                TemplateChecksums::new(&container_id, &rendered).write(log, &state_path);
+            }
+        }
+        // Every strategy but recreate gates on health itself, it rolls back on failure
+        if let Some(timeout) = self.wait_healthy.filter(|_| !config.strategy.gates_health()) {
+            log.stage_started("health");
+            let health_started = Instant::now();
+            let healthy = deadlines.run("health", require_healthy(log, &docker, &container, timeout));
+            if let Err(timed_out) = healthy.await {
+                return roll_back_recreate(&container, &failures, &timed_out).await;
+            }
+            let seconds = health_started.elapsed().as_secs_f64();
+            log.stage_completed("health", seconds);
+            report.stages.insert("health".to_string(), seconds);
+        }
+        if (config.smoke.is_some() || config.verify_env.is_some()) && !config.strategy.gates_health() {
+            log.stage_started("smoke");
+            let smoke_started = Instant::now();
+            let smoke = deadlines.run("smoke", container.smoke_test()).await;
+            if let Err(timed_out) = &smoke {
+                return roll_back_recreate(&container, &failures, timed_out).await;
+            }
+            report.smoke = smoke.unwrap_or(Ok(vec![]))?;
+            let seconds = smoke_started.elapsed().as_secs_f64();
+            log.stage_completed("smoke", seconds);
+            report.stages.insert("smoke".to_string(), seconds);
+        }
+
+        let image_name_with_version = get_image_name_with_version(app, &config.version);
+        let mut deployment = Deployment::new(&config.version, &image_name_with_version, started_at);
+        deployment.digest = report.digest;
+        deployment.image_id = Image::new(log, &docker).id(&image_name_with_version).await;
+        deployment.scan = report.scan;
+        deployment.smoke = report.smoke;
+        deployment.strategy = config.strategy;
+        deployment.message = self.message.clone();
+        let seconds = (deployment.finished_at - started_at).num_milliseconds() as f64 / 1000.0;
+        let mut snapshot = Snapshot::new(&deployment.id, app, &config, &provenance);
+        snapshot.message = self.message.clone();
+        Releases::new(&state_path).save(&snapshot, server_config.release_retention)?;
+        if config.sbom {
+            Sboms::new(log, &state_path)
+                .record(
+                    &docker,
+                    &deployment.id,
+                    &image_name_with_version,
+                    deployment.image_id.as_deref(),
+                    server_config.release_retention,
+                )
+                .await;
+        }
+        let outcome = DeployOutcome {
+            app: app.to_string(),
+            deployment_id: deployment.id.clone(),
+            version: deployment.version.clone(),
+            image: deployment.image.clone(),
+            digest: deployment.digest.clone(),
+            scan: deployment.scan.clone(),
+            host_port: config.port.host_port,
+            strategy: config.strategy,
+            stages: report.stages.clone(),
+            seconds,
+            message: self.message.clone(),
+        };
+        History::new(log, &state_path).record(deployment)?;
+        match config.reload_signal.as_deref().filter(|_| reloaded) {
+            Some(signal) => log.section(&format!(
+                "Reloaded {} with {} in {:.1}s",
+                outcome.image, signal, seconds
+            )),
+            None => log.section(&format!(
+                "Deployed {} with the {} strategy in {:.1}s",
+                outcome.image, outcome.strategy, seconds
+            )),
+        }
+        if let Some(cache) = report.cache {
+            log.step(&format!("The build was {}", cache));
+        }
+        if config.port.auto {
+            log.section(&format!("Published on port {}", config.port.host_port));
+        }
+        // The new container may publish another port, and the domains may have changed
+        Proxy::new(log, &docker, &server_config.state_root).refresh().await;
+        #[cfg(feature = "otel")]
+        {
+            log.trace_attribute("ruku.image", &outcome.image);
+            if let Some(digest) = &outcome.digest {
+                log.trace_attribute("ruku.image.digest", digest);
+            }
+            log.finish_trace(None);
+        }
+        metrics.finish(report.stages, seconds);
+        Ok(outcome)
+    }
+
+    async fn promote_staged(&self, config: RukuConfig) -> Result<DeployOutcome, String> {
+        let (log, app, server_config) = (self.log, self.app, self.server_config);
+        let provenance = load_ruku_config_with_provenance(app, server_config)
+            .map(|(_, provenance)| provenance)
+            .unwrap_or_else(|_| Provenance::new());
+        let docker = get_docker_for(log, &config).await?;
+        let state_path = server_config.state_root.join(app);
+        let _lock = AppLock::acquire(log, app, &state_path, self.wait_for_lock).await?;
+        let Some(message) = Staging::read(log, &state_path)?.map(|staged| staged.message) else {
+            return Err("No deploy is staged, run `ruku stage` first".to_string());
+        };
+
+        let app_path = server_config.apps_root.join(app);
+        let links = get_links(log, app, server_config)?;
+        let templates = Templates::new(log, &state_path);
+        // Built the way the staging deploy built it, so its config hash compares
+        let container = Container::new(log, app, &docker, &config)
+            .with_links(links.clone())
+            .with_template_dir(templates.dir().to_path_buf())
+            .with_static_root(static_root(&app_path, &config))
+            .with_deploy_message(message);
+        let staging = Staging::new(log, &container, &state_path);
+        let staged = staging.check().await?;
+        set_deployment(&staged.id);
+        let rendered = templates.render_all(&config, &templates::variables(app, &config, &links)?)?;
+        let failures = Failures::new(log, &state_path)
+            .with_deployment(&docker, &staged.id)
+            .with_timeout(Duration::from_secs(server_config.capture_timeout))
+            .with_retention(server_config.release_retention);
+        capture_release_logs(log, server_config, &docker, &container, &state_path).await?;
+        let metrics = Metrics::new(log, &state_path);
+        metrics.begin();
+        let promote_started = Instant::now();
+        log.stage_started("start");
+        let timeout = self.wait_healthy.unwrap_or(Duration::from_secs(DEFAULT_HEALTH_TIMEOUT));
+        // Sidecars are left as they are by the staging, a changed one is recreated here
+        if !config.sidecars.is_empty() {
+            Sidecars::new(log, app, &docker, &config, &container).ensure().await?;
+        }
+        let mut deployment = staging.promote(staged, timeout, &rendered, &failures).await?;
+        let seconds = promote_started.elapsed().as_secs_f64();
+        log.stage_completed("start", seconds);
+        if let Some(container_id) = container.get().await?.and_then(|summary| summary.id) {
+            TemplateChecksums::new(&container_id, &rendered).write(log, &state_path);
+        }
+        deployment.image_id = Image::new(log, &docker).id(&deployment.image).await;
+        let mut snapshot = Snapshot::new(&deployment.id, app, &config, &provenance);
+        snapshot.message = deployment.message.clone();
+        Releases::new(&state_path).save(&snapshot, server_config.release_retention)?;
+        let outcome = DeployOutcome {
+            app: app.to_string(),
+            deployment_id: deployment.id.clone(),
+            version: deployment.version.clone(),
+            image: deployment.image.clone(),
+            digest: deployment.digest.clone(),
+            scan: deployment.scan.clone(),
+            host_port: config.port.host_port,
+            strategy: deployment.strategy,
+            stages: BTreeMap::from([("start".to_string(), seconds)]),
+            seconds,
+            message: deployment.message.clone(),
+        };
+        History::new(log, &state_path).record(deployment)?;
+        log.section(&format!("Promoted {} in {:.1}s", outcome.image, seconds));
+        Proxy::new(log, &docker, &server_config.state_root).refresh().await;
+        metrics.finish(outcome.stages.clone(), seconds);
+        Ok(outcome)
+    }
+}
+
+/// Keep the output of the release the stable container of the app runs, which the deploy replaces.
+async fn capture_release_logs(
+    log: &Logger,
+    server_config: &ServerConfig,
+    docker: &Docker,
+    container: &Container<'_>,
+    state_path: &Path,
+) -> Result<(), String> {
+    let Some(summary) = container.get().await?.filter(is_managed) else {
+        return Ok(());
+    };
+    if let Some(release) = release_of(&History::new(log, state_path).load_here()?, &summary) {
+        ReleaseLogs::new(log, state_path)
+            .with_timeout(Duration::from_secs(server_config.capture_timeout))
+            .with_retention(server_config.release_log_retention)
+            .capture(docker, container.container_name(), &release)
+            .await;
+    }
+    Ok(())
+}
+
+/// Remove the new container of a recreate deploy that ran out of time after it was started, failing
+/// with why.
+async fn roll_back_recreate<T>(
+    container: &Container<'_>,
+    failures: &Failures<'_>,
+    timed_out: &str,
+) -> Result<T, String> {
+    strategy::roll_back(&Recreate::new(container), Some(failures)).await?;
+    Err(format!("{}, the new version was rolled back", timed_out))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::Event;
+    use std::sync::mpsc;
+
+    #[tokio::test]
+    async fn an_error_is_returned_and_sent_as_the_last_event() {
+        let home = tempfile::tempdir().unwrap();
+        let server_config = ServerConfig::at_home(home.path()).unwrap();
+        let (sender, receiver) = mpsc::channel();
+        let log = Logger::with_events(sender);
+
+        // There is no ruku.yml for the app
+        let outcome = DeployPipeline::new(&log, "shop", &server_config).run().await;
+
+        let Err(RukuError::Config(message)) = outcome else {
+            panic!("expected a config error, got {:?}", outcome);
+        };
+        let events: Vec<Event> = receiver.try_iter().collect();
+        assert!(matches!(events.last(), Some(Event::Error { message: sent }) if *sent == message));
+    }
+}
@@ -0,0 +1,286 @@
+use std::fmt;
+
+use bollard::models::ContainerSummary;
+use bollard::Docker;
+use serde::Serialize;
+
+use crate::container::get_container_name;
+use crate::units::format_size;
+
+/// What a plan step touches.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Resource {
+    Container,
+    Image,
+    Volume,
+    StateFile,
+}
+
+impl fmt::Display for Resource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Resource::Container => write!(f, "container"),
+            Resource::Image => write!(f, "image"),
+            Resource::Volume => write!(f, "volume"),
+            Resource::StateFile => write!(f, "state file"),
+        }
+    }
+}
+
+/// What happens to the resource of a plan step.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    Stop,
+    Remove,
+    /// Committed to a backup image before it is removed.
+    Backup,
+    /// Replaced by a container from another image.
+    Replace,
+    /// Created and started.
+    Create,
+    /// Left alone, named so the operator knows it survives.
+    Keep,
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Action::Stop => write!(f, "stop"),
+            Action::Remove => write!(f, "remove"),
+            Action::Backup => write!(f, "back up"),
+            Action::Replace => write!(f, "replace"),
+            Action::Create => write!(f, "create"),
+            Action::Keep => write!(f, "keep"),
+        }
+    }
+}
+
+/// One thing a destructive command does.
+#[derive(Debug, Clone, Serialize)]
+pub struct Step {
+    pub action: Action,
+    pub resource: Resource,
+    pub name: String,
+    /// Short id of a container or image.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    /// Size in bytes where Docker reports it without extra work.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<i64>,
+    /// The size for people, next to the bytes so the JSON needs no parsing either way.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size_human: Option<String>,
+    /// How it stands now or why it is affected, e.g. the container state.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {} {}", self.action, self.resource, self.name)?;
+        let details: Vec<String> = self
+            .id
+            .iter()
+            .cloned()
+            .chain(self.size.filter(|size| *size >= 0).map(|size| format_size(size as u64)))
+            .chain(self.detail.iter().cloned())
+            .collect();
+        if !details.is_empty() {
+            write!(f, " ({})", details.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// Everything `stop`, `destroy`, `repair` or `undo` is about to do, built before anything is touched so it
+/// can be confirmed, printed by `--dry-run` or carried out.
+#[derive(Debug, Clone, Serialize)]
+pub struct Plan {
+    pub command: String,
+    pub app: String,
+    pub steps: Vec<Step>,
+}
+
+impl Plan {
+    pub fn new(command: &str, app: &str) -> Plan {
+        Plan {
+            command: command.to_string(),
+            app: app.to_string(),
+            steps: vec![],
+        }
+    }
+
+    /// Add a step, `id` and `size` go in with [`Step::with`].
+    pub fn add(&mut self, action: Action, resource: Resource, name: &str) -> &mut Step {
+        self.steps.push(Step {
+            action,
+            resource,
+            name: name.to_string(),
+            id: None,
+            size: None,
+            size_human: None,
+            detail: None,
+        });
+        self.steps.last_mut().unwrap()
+    }
+
+    /// A step for each of `containers`, with its id and state.
+    pub fn add_containers(&mut self, action: Action, containers: &[ContainerSummary]) {
+        for summary in containers {
+            self.add(
+                action,
+                Resource::Container,
+                &get_container_name(summary).unwrap_or_default(),
+            )
+            .with(summary.id.as_deref(), None)
+            .detail(summary.state.as_deref().unwrap_or("unknown"));
+        }
+    }
+
+    /// A step for the image `name` with its id and size, none when it is not in the local store.
+    pub async fn add_image(&mut self, docker: &Docker, action: Action, name: &str) {
+        if let Ok(image) = docker.inspect_image(name).await {
+            self.add(action, Resource::Image, name)
+                .with(image.id.as_deref(), image.size);
+        }
+    }
+
+    /// A step for each volume of `volumes`, by name with its size.
+    pub fn add_volumes(&mut self, action: Action, volumes: &[(String, Option<i64>)]) {
+        for (name, size) in volumes {
+            self.add(action, Resource::Volume, name).with(None, *size);
+        }
+    }
+
+    /// The steps that change something, in the form the confirmation lists them.
+    pub fn affected(&self) -> Vec<String> {
+        self.changes().map(|step| step.to_string()).collect()
+    }
+
+    pub fn changes(&self) -> impl Iterator<Item = &Step> {
+        self.steps.iter().filter(|step| step.action != Action::Keep)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.changes().next().is_none()
+    }
+
+    /// Print the plan for `--dry-run`, as JSON or one step a line.
+    pub fn print(&self, json: bool) {
+        if json {
+            println!("{}", serde_json::to_string_pretty(self).unwrap());
+            return;
+        }
+        if self.steps.is_empty() {
+            println!("Nothing to do");
+        }
+        for step in &self.steps {
+            println!("{}", step);
+        }
+    }
+}
+
+impl Step {
+    pub fn with(&mut self, id: Option<&str>, size: Option<i64>) -> &mut Step {
+        self.id = id.map(|id| {
+            let id = id.trim_start_matches("sha256:");
+            id[..id.len().min(12)].to_string()
+        });
+        self.size = size;
+        self.size_human = size.filter(|size| *size >= 0).map(|size| format_size(size as u64));
+        self
+    }
+
+    pub fn detail(&mut self, detail: &str) -> &mut Step {
+        self.detail = Some(detail.to_string());
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake_daemon::fake_daemon;
+
+    #[test]
+    fn steps_read_as_one_line() {
+        let mut plan = Plan::new("destroy", "shop");
+        plan.add(Action::Backup, Resource::Container, "ruku-shop")
+            .with(Some("sha256:0123456789abcdef"), None)
+            .detail("running");
+        plan.add(Action::Remove, Resource::Volume, "shop-data")
+            .with(None, Some(1_500_000));
+        plan.add(Action::Remove, Resource::Volume, "shop-cache")
+            .with(None, Some(-1));
+        plan.add(Action::Keep, Resource::Image, "shop:1.0");
+        assert_eq!(
+            plan.affected(),
+            [
+                "back up container ruku-shop (0123456789ab, running)",
+                "remove volume shop-data (1.5 MB)",
+                "remove volume shop-cache"
+            ]
+        );
+        assert_eq!(plan.steps[3].to_string(), "keep image shop:1.0");
+        assert!(!plan.is_empty());
+
+        let mut kept = Plan::new("stop", "shop");
+        kept.add(Action::Keep, Resource::Volume, "shop-data");
+        assert!(kept.is_empty());
+    }
+
+    #[test]
+    fn json_steps_leave_out_what_is_unknown() {
+        let mut plan = Plan::new("repair", "shop");
+        plan.add(Action::Remove, Resource::StateFile, "/var/lib/ruku/shop/canary.json");
+        plan.add(Action::Remove, Resource::Image, "<none>")
+            .with(Some("sha256:aaaabbbbccccdddd"), Some(2_000));
+        assert_eq!(
+            serde_json::to_value(&plan).unwrap(),
+            serde_json::json!({
+                "command": "repair",
+                "app": "shop",
+                "steps": [
+                    {"action": "remove", "resource": "state_file", "name": "/var/lib/ruku/shop/canary.json"},
+                    {
+                        "action": "remove",
+                        "resource": "image",
+                        "name": "<none>",
+                        "id": "aaaabbbbcccc",
+                        "size": 2000,
+                        "size_human": "2.0 KB"
+                    }
+                ]
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn containers_and_images_come_from_the_daemon() {
+        let (docker, _) = fake_daemon(|_, path| match path {
+            "/images/shop:1.0/json" => (200, r#"{"Id": "sha256:ffffeeeeddddcccc", "Size": 3000000}"#.to_string()),
+            _ => (404, r#"{"message": "No such image"}"#.to_string()),
+        })
+        .await;
+        let mut plan = Plan::new("destroy", "shop");
+        plan.add_containers(
+            Action::Stop,
+            &[ContainerSummary {
+                id: Some("0123456789abcdef".to_string()),
+                names: Some(vec!["/ruku-shop".to_string()]),
+                ..Default::default()
+            }],
+        );
+        plan.add_image(&docker, Action::Remove, "shop:1.0").await;
+        plan.add_image(&docker, Action::Remove, "shop:0.9").await;
+        assert_eq!(
+            plan.affected(),
+            [
+                "stop container ruku-shop (0123456789ab, unknown)",
+                "remove image shop:1.0 (ffffeeeedddd, 3.0 MB)"
+            ]
+        );
+    }
+}
@@ -0,0 +1,208 @@
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use bollard::models::{ImageInspect, SystemInfo};
+use bollard::Docker;
+
+use crate::logger::Logger;
+
+/// Where the kernel lists the interpreters it runs foreign binaries with.
+const BINFMT_DIR: &str = "/proc/sys/fs/binfmt_misc";
+
+/// The OS and CPU architecture an image is built for or a daemon runs, in the names Docker uses.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Platform {
+    pub os: String,
+    pub architecture: String,
+    /// E.g. `v7` for 32-bit arm, none when the image doesn't say.
+    pub variant: Option<String>,
+}
+
+impl Platform {
+    pub fn new(os: &str, architecture: &str, variant: Option<&str>) -> Platform {
+        Platform {
+            os: os.to_lowercase(),
+            architecture: normalize_architecture(architecture).to_string(),
+            variant: variant.filter(|variant| !variant.is_empty()).map(str::to_string),
+        }
+    }
+
+    /// The platform of an image in the local store. With several variants of a multi-platform image
+    /// stored, Docker resolves the tag to the one for the daemon if it has it.
+    pub fn from_image(image: &ImageInspect) -> Option<Platform> {
+        Some(Platform::new(
+            image.os.as_deref().unwrap_or("linux"),
+            image.architecture.as_deref()?,
+            image.variant.as_deref(),
+        ))
+    }
+
+    /// The platform the daemon runs containers on natively, which reports it the way `uname` does.
+    pub fn from_info(info: &SystemInfo) -> Option<Platform> {
+        Some(Platform::new(
+            info.os_type.as_deref().unwrap_or("linux"),
+            info.architecture.as_deref()?,
+            None,
+        ))
+    }
+
+    /// Whether binaries of `image` run here without emulation. x86 hosts run 32-bit x86 images as well.
+    pub fn runs(&self, image: &Platform) -> bool {
+        self.os == image.os
+            && (self.architecture == image.architecture
+                || (self.architecture == "amd64" && image.architecture == "386"))
+    }
+}
+
+impl fmt::Display for Platform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.os, self.architecture)?;
+        if let Some(variant) = &self.variant {
+            write!(f, "/{}", variant)?;
+        }
+        Ok(())
+    }
+}
+
+/// The Go name Docker gives an architecture `uname` may spell differently, e.g. `x86_64` is `amd64`.
+pub fn normalize_architecture(architecture: &str) -> &str {
+    match architecture {
+        "x86_64" | "x86-64" => "amd64",
+        "aarch64" | "armv8" | "armv8l" => "arm64",
+        "armv7l" | "armv7" | "armv6l" | "armhf" | "armel" => "arm",
+        "i386" | "i486" | "i586" | "i686" | "x86" => "386",
+        _ => architecture,
+    }
+}
+
+/// The name the qemu binfmt handler for `architecture` is registered under, `qemu-<name>`.
+fn qemu_name(architecture: &str) -> &str {
+    match architecture {
+        "amd64" => "x86_64",
+        "arm64" => "aarch64",
+        "386" => "i386",
+        "mips64le" => "mips64el",
+        _ => architecture,
+    }
+}
+
+/// The architectures the kernel of this host runs through an enabled qemu binfmt handler. Looked up
+/// locally, for a daemon on another host it says nothing.
+pub fn emulated_architectures() -> Vec<String> {
+    let mut architectures: Vec<String> = fs::read_dir(BINFMT_DIR)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().strip_prefix("qemu-")?.to_string();
+            let content = fs::read_to_string(entry.path()).ok()?;
+            (content.lines().next() == Some("enabled")).then(|| normalize_architecture(&name).to_string())
+        })
+        .collect();
+    architectures.sort();
+    architectures.dedup();
+    architectures
+}
+
+/// Whether the kernel runs binaries of `architecture` through qemu.
+pub fn is_emulated(architecture: &str) -> bool {
+    let handler = Path::new(BINFMT_DIR).join(format!("qemu-{}", qemu_name(architecture)));
+    fs::read_to_string(handler).is_ok_and(|content| content.lines().next() == Some("enabled"))
+}
+
+/// Checks that the daemon can run the platform of an image before a container is created from it.
+pub struct Platforms<'a> {
+    log: &'a Logger,
+    docker: &'a Docker,
+}
+
+impl<'a> Platforms<'a> {
+    pub fn new(log: &'a Logger, docker: &'a Docker) -> Platforms<'a> {
+        Platforms { log, docker }
+    }
+
+    /// The platform the daemon runs natively, none when it doesn't report it.
+    pub async fn host(&self) -> Option<Platform> {
+        let info = self.docker.info().await.ok()?;
+        Platform::from_info(&info)
+    }
+
+    /// Fail when the daemon can't run `image_name`, which would start a container that dies with an exec
+    /// format error. Through qemu emulation it runs slowly, which is only a warning then, as it is with
+    /// `allow_emulation`.
+    pub async fn check(&self, image_name: &str, allow_emulation: bool) -> Result<(), String> {
+        let Ok(image) = self.docker.inspect_image(image_name).await else {
+            return Ok(());
+        };
+        let (Some(platform), Some(host)) = (Platform::from_image(&image), self.host().await) else {
+            return Ok(());
+        };
+        if host.runs(&platform) {
+            return Ok(());
+        }
+        if is_emulated(&platform.architecture) {
+            self.log.warn(&format!(
+                "Image {} is built for {} and runs through qemu emulation on this {} host, expect it to be much slower",
+                image_name, platform, host
+            ));
+        } else if allow_emulation {
+            self.log.warn(&format!(
+                "Image {} is built for {} which this {} host can't run natively, starting it anyway as allow_emulation is set",
+                image_name, platform, host
+            ));
+        } else {
+            return Err(format!(
+                "Image {} is built for {} but the Docker host is {}, it would fail with an exec format error. \
+                 Build or pull the image for {}, install qemu binfmt emulation with \
+                 `docker run --privileged --rm tonistiigi/binfmt --install {}`, or set allow_emulation: true",
+                image_name, platform.architecture, host.architecture, host, platform.architecture
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn architectures_use_docker_names() {
+        assert_eq!(
+            Platform::new("Linux", "x86_64", None),
+            Platform::new("linux", "amd64", Some(""))
+        );
+        assert_eq!(normalize_architecture("aarch64"), "arm64");
+        assert_eq!(normalize_architecture("armv7l"), "arm");
+        assert_eq!(normalize_architecture("i686"), "386");
+        assert_eq!(normalize_architecture("riscv64"), "riscv64");
+        assert_eq!(qemu_name("arm64"), "aarch64");
+        assert_eq!(qemu_name("s390x"), "s390x");
+        assert_eq!(Platform::new("linux", "armv7l", Some("v7")).to_string(), "linux/arm/v7");
+    }
+
+    #[test]
+    fn platforms_come_from_the_image_and_the_daemon() {
+        let image: ImageInspect =
+            serde_json::from_str(r#"{"Architecture": "arm64", "Os": "linux", "Variant": "v8"}"#).unwrap();
+        assert_eq!(
+            Platform::from_image(&image),
+            Some(Platform::new("linux", "arm64", Some("v8")))
+        );
+        let info: SystemInfo = serde_json::from_str(r#"{"Architecture": "x86_64", "OSType": "linux"}"#).unwrap();
+        assert_eq!(Platform::from_info(&info), Some(Platform::new("linux", "amd64", None)));
+        assert_eq!(Platform::from_image(&ImageInspect::default()), None);
+    }
+
+    #[test]
+    fn hosts_run_their_own_architecture() {
+        let amd64 = Platform::new("linux", "x86_64", None);
+        let arm64 = Platform::new("linux", "aarch64", None);
+        assert!(amd64.runs(&Platform::new("linux", "amd64", Some("v3"))));
+        assert!(amd64.runs(&Platform::new("linux", "386", None)));
+        assert!(!amd64.runs(&arm64));
+        assert!(!arm64.runs(&Platform::new("linux", "arm", Some("v7"))));
+        assert!(!amd64.runs(&Platform::new("windows", "amd64", None)));
+    }
+}
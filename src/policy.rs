@@ -0,0 +1,390 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use bollard::Docker;
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::logger::Logger;
+use crate::registry::split_registry;
+use crate::units::{self, format_duration};
+
+/// Label of the OCI image spec with the SPDX license expression of the image.
+const LICENSES_LABEL: &str = "org.opencontainers.image.licenses";
+
+/// Rules an image has to follow to be deployed, the `policy` of ruku.yml or `~/.ruku/config.yml`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PolicyConfig {
+    /// Fail the deploy on a violation, or only warn when false. A ruku.yml can't turn off what
+    /// `~/.ruku/config.yml` enforces. Enforced when neither sets it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub enforce: Option<bool>,
+    /// Labels the image has to carry with a value, e.g. `org.opencontainers.image.source`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub required_labels: Vec<String>,
+    /// Registries the base images and the pushed image may come from, with an optional path like
+    /// `ghcr.io/acme`. Docker Hub is `docker.io`, any registry is allowed when none are listed.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allowed_registries: Vec<String>,
+    /// Tags the app version and the base images may not have, e.g. `latest`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub forbidden_tags: Vec<String>,
+    /// Licenses the `org.opencontainers.image.licenses` label may not name, e.g. `AGPL-3.0-only`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub denied_licenses: Vec<String>,
+    /// Oldest the image may be by its created time, in seconds or a duration like `90d`.
+    #[serde(
+        default,
+        deserialize_with = "units::optional_seconds",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub max_image_age: Option<u64>,
+}
+
+/// What a policy is checked against, from the image inspect and the build.
+#[derive(Debug, Clone, Default)]
+pub struct ImageFacts {
+    pub labels: HashMap<String, String>,
+    pub created: Option<DateTime<Utc>>,
+    /// The tag the app is deployed with, its version.
+    pub tag: String,
+    /// Images the app image is built from, the `FROM` images of its Dockerfile.
+    pub base_images: Vec<String>,
+    /// Where the image is pushed to, e.g. `ghcr.io/acme/app:1.2.0`.
+    pub pushed_to: Option<String>,
+}
+
+impl ImageFacts {
+    /// The facts of `image` in the local store, deployed with `tag`, the base images and push target left
+    /// to the caller.
+    pub async fn inspect(docker: &Docker, image: &str, tag: &str) -> Result<ImageFacts, String> {
+        let inspect = docker.inspect_image(image).await.map_err(|e| e.to_string())?;
+        Ok(ImageFacts {
+            labels: inspect.config.and_then(|config| config.labels).unwrap_or_default(),
+            created: inspect
+                .created
+                .and_then(|created| DateTime::parse_from_rfc3339(&created).ok())
+                .map(|created| created.to_utc()),
+            tag: tag.to_string(),
+            base_images: vec![],
+            pushed_to: None,
+        })
+    }
+}
+
+/// A rule an image broke.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Violation {
+    /// The setting of the rule, e.g. `required_labels`.
+    pub rule: &'static str,
+    pub message: String,
+    /// Where the rule is set, `ruku.yml` or `~/.ruku/config.yml`.
+    pub source: &'static str,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} (policy.{} in {})", self.message, self.rule, self.source)
+    }
+}
+
+/// The policy of the host and that of an app taken together. Every rule of both applies, so an app can
+/// only add rules, and the host decides whether they are enforced once it enforces.
+#[derive(Debug)]
+pub struct Policy<'a> {
+    rules: Vec<(&'a PolicyConfig, &'static str)>,
+    enforce: bool,
+    /// The ruku.yml asked for warnings only, which the host policy overrules.
+    overruled: bool,
+}
+
+impl<'a> Policy<'a> {
+    pub const HOST_SOURCE: &'static str = "~/.ruku/config.yml";
+    pub const APP_SOURCE: &'static str = "ruku.yml";
+
+    /// The policy of `host` and `app`, none when neither has one.
+    pub fn combine(host: Option<&'a PolicyConfig>, app: Option<&'a PolicyConfig>) -> Option<Policy<'a>> {
+        let rules: Vec<_> = [(host, Self::HOST_SOURCE), (app, Self::APP_SOURCE)]
+            .into_iter()
+            .filter_map(|(config, source)| config.map(|config| (config, source)))
+            .collect();
+        if rules.is_empty() {
+            return None;
+        }
+        let host_enforce = host.and_then(|host| host.enforce);
+        let app_enforce = app.and_then(|app| app.enforce);
+        let enforce = match (host_enforce, app_enforce) {
+            (Some(true), _) => true,
+            (_, Some(app)) => app,
+            (host, None) => host.unwrap_or(true),
+        };
+        Some(Policy {
+            rules,
+            enforce,
+            overruled: host_enforce == Some(true) && app_enforce == Some(false),
+        })
+    }
+
+    /// Every rule `facts` break at `now`, host rules first.
+    pub fn evaluate(&self, facts: &ImageFacts, now: DateTime<Utc>) -> Vec<Violation> {
+        let mut violations = vec![];
+        for (config, source) in &self.rules {
+            let mut violation = |rule: &'static str, message: String| {
+                violations.push(Violation { rule, message, source });
+            };
+            for label in &config.required_labels {
+                if facts.labels.get(label).is_none_or(|value| value.trim().is_empty()) {
+                    violation("required_labels", format!("the image has no {} label", label));
+                }
+            }
+            if !config.allowed_registries.is_empty() {
+                for reference in facts.base_images.iter().chain(&facts.pushed_to) {
+                    let name = qualified_name(reference);
+                    if !config
+                        .allowed_registries
+                        .iter()
+                        .any(|allowed| in_registry(&name, allowed))
+                    {
+                        violation(
+                            "allowed_registries",
+                            format!("{} is not from {}", reference, config.allowed_registries.join(", ")),
+                        );
+                    }
+                }
+            }
+            if config.forbidden_tags.contains(&facts.tag) {
+                violation(
+                    "forbidden_tags",
+                    format!("the app is deployed as version {}", facts.tag),
+                );
+            }
+            for reference in &facts.base_images {
+                if let Some(tag) = tag_of(reference).filter(|tag| config.forbidden_tags.iter().any(|t| t == tag)) {
+                    violation("forbidden_tags", format!("{} uses the tag {}", reference, tag));
+                }
+            }
+            if let Some(licenses) = facts.labels.get(LICENSES_LABEL) {
+                for license in licenses_of(licenses) {
+                    if config
+                        .denied_licenses
+                        .iter()
+                        .any(|denied| denied.eq_ignore_ascii_case(license))
+                    {
+                        violation("denied_licenses", format!("the image is licensed under {}", license));
+                    }
+                }
+            }
+            if let Some(max_age) = config.max_image_age {
+                let max_age = Duration::seconds(max_age as i64);
+                match facts.created {
+                    Some(created) if now - created > max_age => violation(
+                        "max_image_age",
+                        format!(
+                            "the image was created {} ago, more than {}",
+                            format_duration((now - created).to_std().unwrap_or_default()),
+                            format_duration(max_age.to_std().unwrap_or_default())
+                        ),
+                    ),
+                    Some(_) => {}
+                    None => violation("max_image_age", "the image has no created time".to_string()),
+                }
+            }
+        }
+        violations
+    }
+
+    /// Fail listing the violations of `facts`, or only warn about them when the policy isn't enforced.
+    pub fn check(&self, log: &Logger, facts: &ImageFacts) -> Result<(), String> {
+        if self.overruled {
+            log.warn("The policy of ~/.ruku/config.yml is enforced, policy.enforce: false in ruku.yml does not apply");
+        }
+        let violations = self.evaluate(facts, Utc::now());
+        if violations.is_empty() {
+            log.step("The image follows the policy");
+            return Ok(());
+        }
+        let listed: Vec<String> = violations.iter().map(|violation| format!("  {}", violation)).collect();
+        let message = format!(
+            "The image breaks {} policy rules:\n{}",
+            violations.len(),
+            listed.join("\n")
+        );
+        if !self.enforce {
+            log.warn(&format!("{}\nDeploying anyway, the policy is not enforced", message));
+            return Ok(());
+        }
+        Err(message)
+    }
+}
+
+/// The images the `FROM` instructions of a Dockerfile name, leaving out earlier stages, `scratch` and
+/// images given by build args.
+pub fn base_images(dockerfile: &str) -> Vec<String> {
+    let mut stages: Vec<String> = vec![];
+    let mut images = vec![];
+    for line in dockerfile.lines() {
+        let mut words = line.split_whitespace();
+        if !words.next().is_some_and(|word| word.eq_ignore_ascii_case("FROM")) {
+            continue;
+        }
+        let mut words = words.skip_while(|word| word.starts_with("--"));
+        let Some(image) = words.next() else {
+            continue;
+        };
+        let is_stage = stages.iter().any(|stage| stage.eq_ignore_ascii_case(image));
+        if !is_stage && image != "scratch" && !image.contains('$') && !images.iter().any(|known| known == image) {
+            images.push(image.to_string());
+        }
+        if words.next().is_some_and(|word| word.eq_ignore_ascii_case("AS")) {
+            stages.extend(words.next().map(str::to_string));
+        }
+    }
+    images
+}
+
+/// `reference` with its registry, without tag or digest, e.g. `docker.io/library/node` for `node:20`.
+fn qualified_name(reference: &str) -> String {
+    let name = reference.split('@').next().unwrap_or(reference);
+    let name = match name.rsplit_once(':') {
+        Some((repository, tag)) if !tag.contains('/') => repository,
+        _ => name,
+    };
+    match split_registry(name) {
+        Some(_) => name.to_string(),
+        None if name.contains('/') => format!("docker.io/{}", name),
+        None => format!("docker.io/library/{}", name),
+    }
+}
+
+/// Whether the repository `name` is in `allowed`, a registry or a path inside one.
+fn in_registry(name: &str, allowed: &str) -> bool {
+    let allowed = allowed.trim_end_matches('/');
+    name == allowed || name.starts_with(&format!("{}/", allowed))
+}
+
+/// The tag of `reference`, `latest` when it has none and none when a digest pins it.
+fn tag_of(reference: &str) -> Option<&str> {
+    if reference.contains('@') {
+        return None;
+    }
+    match reference.rsplit_once(':') {
+        Some((_, tag)) if !tag.contains('/') => Some(tag),
+        _ => Some("latest"),
+    }
+}
+
+/// The licenses of an SPDX expression like `(MIT OR Apache-2.0) AND BSD-3-Clause`.
+fn licenses_of(expression: &str) -> impl Iterator<Item = &str> {
+    expression
+        .split(|c: char| c.is_whitespace() || c == '(' || c == ')')
+        .filter(|word| !word.is_empty() && !matches!(word.to_ascii_uppercase().as_str(), "AND" | "OR" | "WITH"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(yaml: &str) -> PolicyConfig {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    fn at(text: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(text).unwrap().to_utc()
+    }
+
+    #[test]
+    fn the_host_decides_enforcement_once_it_enforces() {
+        let enforced = policy("enforce: true");
+        let warn_only = policy("enforce: false");
+        let unset = PolicyConfig::default();
+        let combine = |host, app| Policy::combine(host, app).map(|policy| (policy.enforce, policy.overruled));
+
+        assert_eq!(combine(None, None), None);
+        assert_eq!(combine(Some(&unset), None), Some((true, false)));
+        assert_eq!(combine(None, Some(&warn_only)), Some((false, false)));
+        assert_eq!(combine(Some(&warn_only), Some(&enforced)), Some((true, false)));
+        assert_eq!(combine(Some(&warn_only), Some(&unset)), Some((false, false)));
+        assert_eq!(combine(Some(&enforced), Some(&warn_only)), Some((true, true)));
+    }
+
+    #[test]
+    fn every_rule_is_checked_with_its_source() {
+        let host = policy(
+            "
+required_labels: [org.opencontainers.image.source]
+allowed_registries: [ghcr.io/acme, docker.io/library]
+max_image_age: 90d
+",
+        );
+        let app = policy("forbidden_tags: [latest]\ndenied_licenses: [agpl-3.0-only]");
+        let policy = Policy::combine(Some(&host), Some(&app)).unwrap();
+        let facts = ImageFacts {
+            labels: HashMap::from([
+                ("org.opencontainers.image.source".to_string(), " ".to_string()),
+                (LICENSES_LABEL.to_string(), "(MIT OR AGPL-3.0-only)".to_string()),
+            ]),
+            created: Some(at("2026-06-01T00:00:00Z")),
+            tag: "latest".to_string(),
+            base_images: vec!["node:20".to_string(), "quay.io/team/tools".to_string()],
+            pushed_to: Some("ghcr.io/acme/shop:1.0".to_string()),
+        };
+        let violations: Vec<String> = policy
+            .evaluate(&facts, at("2026-10-14T00:00:00Z"))
+            .iter()
+            .map(Violation::to_string)
+            .collect();
+        assert_eq!(
+            violations,
+            [
+                "the image has no org.opencontainers.image.source label (policy.required_labels in ~/.ruku/config.yml)",
+                "quay.io/team/tools is not from ghcr.io/acme, docker.io/library (policy.allowed_registries in ~/.ruku/config.yml)",
+                "the image was created 135d0h ago, more than 90d0h (policy.max_image_age in ~/.ruku/config.yml)",
+                "the app is deployed as version latest (policy.forbidden_tags in ruku.yml)",
+                "quay.io/team/tools uses the tag latest (policy.forbidden_tags in ruku.yml)",
+                "the image is licensed under AGPL-3.0-only (policy.denied_licenses in ruku.yml)",
+            ]
+        );
+
+        let compliant = ImageFacts {
+            labels: HashMap::from([("org.opencontainers.image.source".to_string(), "https://x".to_string())]),
+            created: Some(at("2026-10-01T00:00:00Z")),
+            tag: "1.0".to_string(),
+            base_images: vec!["node:20".to_string()],
+            pushed_to: None,
+        };
+        assert_eq!(policy.evaluate(&compliant, at("2026-10-14T00:00:00Z")), vec![]);
+    }
+
+    #[test]
+    fn base_images_leave_out_stages_and_build_args() {
+        let dockerfile = "
+FROM --platform=$BUILDPLATFORM node:20 AS build
+FROM build AS test
+from scratch
+FROM ${BASE}
+FROM gcr.io/distroless/nodejs20@sha256:abc
+FROM node:20
+";
+        assert_eq!(
+            base_images(dockerfile),
+            ["node:20", "gcr.io/distroless/nodejs20@sha256:abc"]
+        );
+    }
+
+    #[test]
+    fn references_are_qualified_with_their_registry() {
+        assert_eq!(qualified_name("node:20"), "docker.io/library/node");
+        assert_eq!(qualified_name("acme/app"), "docker.io/acme/app");
+        assert_eq!(qualified_name("localhost:5000/app:1.0"), "localhost:5000/app");
+        assert_eq!(qualified_name("ghcr.io/acme/app@sha256:abc"), "ghcr.io/acme/app");
+        assert!(in_registry("ghcr.io/acme/app", "ghcr.io/acme/"));
+        assert!(!in_registry("ghcr.io/acmecorp/app", "ghcr.io/acme"));
+        assert_eq!(tag_of("localhost:5000/app"), Some("latest"));
+        assert_eq!(tag_of("app@sha256:abc"), None);
+        assert_eq!(
+            licenses_of("(MIT OR Apache-2.0) AND GPL-2.0 WITH Classpath-exception-2.0").collect::<Vec<_>>(),
+            ["MIT", "Apache-2.0", "GPL-2.0", "Classpath-exception-2.0"]
+        );
+    }
+}
@@ -0,0 +1,140 @@
+use std::fs;
+use std::path::Path;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::container::is_port_free;
+use crate::host_config::{HostConfig, Reservation};
+use crate::logger::Logger;
+use crate::model::{publish_address, RukuConfig};
+use crate::spec::PortSpec;
+use crate::store::{self, Document};
+
+/// The host port picked for an app with an `auto` port, kept so redeploys can reuse it.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AssignedPort {
+    pub host_port: u16,
+    pub assigned_at: DateTime<Utc>,
+}
+
+impl AssignedPort {
+    pub const FILE_NAME: &'static str = "port.json";
+
+    pub fn read(log: &Logger, state_dir: &Path) -> Result<Option<AssignedPort>, String> {
+        store::load(log, &state_dir.join(Self::FILE_NAME))
+    }
+}
+
+impl Document for AssignedPort {}
+
+/// Picks free host ports for apps with an `auto` port.
+pub struct PortAssigner<'a> {
+    log: &'a Logger,
+    state_root: &'a Path,
+    host: Option<&'a HostConfig>,
+    reservation: Option<&'a Reservation>,
+}
+
+impl<'a> PortAssigner<'a> {
+    pub fn new(log: &'a Logger, state_root: &'a Path) -> PortAssigner<'a> {
+        PortAssigner {
+            log,
+            state_root,
+            host: None,
+            reservation: None,
+        }
+    }
+
+    /// Pick from `reservation` instead of `auto_port_range`, or without one leave out the ports `host`
+    /// reserves for others.
+    pub fn with_reservation(mut self, host: &'a HostConfig, reservation: Option<&'a Reservation>) -> PortAssigner<'a> {
+        self.host = Some(host);
+        self.reservation = reservation;
+        self
+    }
+
+    /// Set the host port of `config`, keeping the previous port when it is still free or held by the app
+    /// itself, `owned` being the ports its containers publish. Ports assigned to other apps are never
+    /// picked, their containers may only be stopped.
+    pub fn assign(&self, app: &str, config: &mut RukuConfig, owned: &[u16]) -> Result<u16, String> {
+        let state_dir = self.state_root.join(app);
+        let range = self
+            .reservation
+            .map_or(config.auto_port_range, |reservation| reservation.range);
+        let taken = self.assigned_to_others(app)?;
+        let canary_port = config.canary.as_ref().map(|canary| canary.port);
+        let allowed = |port: u16| {
+            self.host
+                .is_none_or(|host| host.check_port(self.reservation, port).is_ok())
+        };
+        let usable =
+            |port: u16| range.contains(port) && !taken.contains(&port) && canary_port != Some(port) && allowed(port);
+        let free = |port: u16| {
+            config.port.protocols.iter().all(|protocol| {
+                is_port_free(&PortSpec {
+                    container_port: config.port.number,
+                    protocol: protocol.to_string(),
+                    host_ip: publish_address(config.port.host_ip.or(config.bind_ip)),
+                    host_port: port,
+                })
+            })
+        };
+
+        let previous = AssignedPort::read(self.log, &state_dir)?.map(|assigned| assigned.host_port);
+        let port = match previous.filter(|port| usable(*port) && (owned.contains(port) || free(*port))) {
+            Some(port) => {
+                self.log.step(&format!("Reusing assigned host port {}", port));
+                port
+            }
+            None => {
+                if let Some(previous) = previous {
+                    self.log.warn(&format!(
+                        "Assigned host port {} is no longer free, picking another",
+                        previous
+                    ));
+                }
+                // Start where the app name hashes to, so apps spread over the range instead of racing for its start
+                let size = u32::from(range.end - range.start) + 1;
+                let offset = app
+                    .bytes()
+                    .fold(0u32, |hash, b| hash.wrapping_mul(31).wrapping_add(u32::from(b)))
+                    % size;
+                let port = (0..size)
+                    .map(|i| range.start + ((offset + i) % size) as u16)
+                    .find(|port| usable(*port) && free(*port))
+                    .ok_or_else(|| match (self.reservation, self.host) {
+                        (Some(reservation), Some(host)) => format!(
+                            "No free host port left in {} reserved for {} in {}",
+                            range,
+                            reservation.name,
+                            host.describe()
+                        ),
+                        _ => format!("No free host port left in {}", range),
+                    })?;
+                self.log.step(&format!("Assigned host port {}", port));
+                port
+            }
+        };
+
+        let assigned = AssignedPort {
+            host_port: port,
+            assigned_at: Utc::now(),
+        };
+        store::save(&state_dir.join(AssignedPort::FILE_NAME), &assigned)
+            .map_err(|e| format!("Error recording the assigned port: {}", e))?;
+        config.port.host_port = port;
+        Ok(port)
+    }
+
+    fn assigned_to_others(&self, app: &str) -> Result<Vec<u16>, String> {
+        let mut ports = vec![];
+        for entry in fs::read_dir(self.state_root).into_iter().flatten().flatten() {
+            if entry.file_name().to_string_lossy() == app {
+                continue;
+            }
+            ports.extend(AssignedPort::read(self.log, &entry.path())?.map(|assigned| assigned.host_port));
+        }
+        Ok(ports)
+    }
+}
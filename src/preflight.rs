@@ -0,0 +1,282 @@
+use std::fs;
+use std::path::Path;
+
+use bollard::Docker;
+use cmd_lib::run_fun;
+
+use crate::connection::local_docker_host;
+use crate::logger::Logger;
+use crate::units::{format_size, format_size_binary};
+
+/// Free space kept on top of the image, a build or pull unpacks its layers next to the compressed ones.
+pub const DISK_MARGIN: u64 = 1 << 30;
+/// Memory the host should have available for a new container to start without swapping.
+pub const MIN_MEMORY: u64 = 256 << 20;
+/// File handles the host should have left, a deploy opens sockets, layers and log files.
+pub const MIN_FILE_HANDLES: u64 = 1024;
+
+/// What the host has left, none for what can't be read. Read on this host, so only for a local daemon.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Readings {
+    /// Where the daemon keeps images and containers, e.g. `/var/lib/docker`.
+    pub data_root: Option<String>,
+    /// Bytes free on the file system of the data root.
+    pub disk_free: Option<u64>,
+    /// Bytes the image is expected to take, from the image the container of the app runs now.
+    pub image_size: Option<u64>,
+    /// `MemAvailable` of `/proc/meminfo` in bytes.
+    pub memory_available: Option<u64>,
+    /// File handles allocated and the most the kernel hands out, from `/proc/sys/fs/file-nr`.
+    pub file_handles: Option<(u64, u64)>,
+}
+
+/// The outcome of one check.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Finding {
+    Pass(String),
+    /// The check could not be made or came close, the deploy goes on.
+    Warn(String),
+    Fail(String),
+}
+
+/// Whether the data root has room for the image and the margin.
+pub fn check_disk(readings: &Readings) -> Finding {
+    let data_root = readings.data_root.as_deref().unwrap_or("the Docker data root");
+    let Some(free) = readings.disk_free else {
+        return Finding::Warn(format!("Can't tell how much space is free on {}", data_root));
+    };
+    let need = readings.image_size.unwrap_or_default() + DISK_MARGIN;
+    let estimate = if readings.image_size.is_some() {
+        ""
+    } else {
+        " without knowing the image size"
+    };
+    if free < need {
+        return Finding::Fail(format!(
+            "Need ~{}, only {} free on {}{}",
+            format_size(need),
+            format_size(free),
+            data_root,
+            estimate
+        ));
+    }
+    let summary = format!("{} free on {}", format_size(free), data_root);
+    match readings.image_size {
+        Some(_) => Finding::Pass(summary),
+        None => Finding::Warn(format!("{}, the size of the new image is not known", summary)),
+    }
+}
+
+/// Whether the host has the memory a new container needs to start.
+pub fn check_memory(readings: &Readings) -> Finding {
+    match readings.memory_available {
+        None => Finding::Warn("Can't tell how much memory is available".to_string()),
+        Some(available) if available < MIN_MEMORY => Finding::Fail(format!(
+            "Only {} of memory available, a new container needs at least {}",
+            format_size_binary(available),
+            format_size_binary(MIN_MEMORY)
+        )),
+        Some(available) => Finding::Pass(format!("{} of memory available", format_size_binary(available))),
+    }
+}
+
+/// Whether the kernel has file handles left for the deploy.
+pub fn check_file_handles(readings: &Readings) -> Finding {
+    match readings.file_handles {
+        None => Finding::Warn("Can't tell how many file handles are left".to_string()),
+        Some((allocated, max)) if max.saturating_sub(allocated) < MIN_FILE_HANDLES => Finding::Fail(format!(
+            "Only {} of {} file handles left, raise fs.file-max or close files",
+            max.saturating_sub(allocated),
+            max
+        )),
+        Some((allocated, max)) => Finding::Pass(format!("{} of {} file handles left", max - allocated, max)),
+    }
+}
+
+/// Every check against `readings`.
+pub fn evaluate(readings: &Readings) -> Vec<Finding> {
+    vec![
+        check_disk(readings),
+        check_memory(readings),
+        check_file_handles(readings),
+    ]
+}
+
+/// Checks that the host has the disk, memory and file handles a deploy needs before it starts, rather
+/// than failing halfway through a pull.
+pub struct Preflight<'a> {
+    log: &'a Logger,
+    docker: &'a Docker,
+}
+
+impl<'a> Preflight<'a> {
+    pub fn new(log: &'a Logger, docker: &'a Docker) -> Preflight<'a> {
+        Preflight { log, docker }
+    }
+
+    /// What the host has left, `image` being the image the new one is expected to be about as big as.
+    pub async fn read(&self, image: Option<&str>) -> Readings {
+        let data_root = self.docker.info().await.ok().and_then(|info| info.docker_root_dir);
+        let image_size = match image {
+            Some(image) => self
+                .docker
+                .inspect_image(image)
+                .await
+                .ok()
+                .and_then(|image| image.size)
+                .map(|size| size.max(0) as u64),
+            None => None,
+        };
+        // The readings of this host say nothing about a daemon on another one
+        if !local_docker_host().starts_with("unix://") {
+            return Readings {
+                data_root,
+                image_size,
+                ..Default::default()
+            };
+        }
+        Readings {
+            disk_free: data_root
+                .as_deref()
+                .filter(|root| Path::new(root).exists())
+                .and_then(disk_free),
+            data_root,
+            image_size,
+            memory_available: memory_available(),
+            file_handles: file_handles(),
+        }
+    }
+
+    /// Log every finding, failing when a check failed.
+    pub async fn run(&self, image: Option<&str>) -> Result<(), String> {
+        let findings = evaluate(&self.read(image).await);
+        let mut failed = false;
+        for finding in findings {
+            match finding {
+                Finding::Pass(message) => self.log.step(&message),
+                Finding::Warn(message) => self.log.warn(&message),
+                Finding::Fail(message) => {
+                    self.log.warn(&message);
+                    failed = true;
+                }
+            }
+        }
+        if failed {
+            return Err(
+                "The host is short on resources for the deploy, pass --skip-preflight to deploy anyway".to_string(),
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Bytes available to unprivileged users on the file system of `path`.
+fn disk_free(path: &str) -> Option<u64> {
+    let output = run_fun!(df -Pk $path).ok()?;
+    let available: u64 = output.lines().nth(1)?.split_whitespace().nth(3)?.parse().ok()?;
+    Some(available * 1024)
+}
+
+fn memory_available() -> Option<u64> {
+    let meminfo = fs::read_to_string("/proc/meminfo").ok()?;
+    let line = meminfo.lines().find(|line| line.starts_with("MemAvailable:"))?;
+    let kilobytes: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kilobytes * 1024)
+}
+
+fn file_handles() -> Option<(u64, u64)> {
+    let file_nr = fs::read_to_string("/proc/sys/fs/file-nr").ok()?;
+    let values: Vec<u64> = file_nr
+        .split_whitespace()
+        .filter_map(|value| value.parse().ok())
+        .collect();
+    match values.as_slice() {
+        [allocated, _, max] => Some((*allocated, *max)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn readings() -> Readings {
+        Readings {
+            data_root: Some("/var/lib/docker".to_string()),
+            disk_free: Some(10_000_000_000),
+            image_size: Some(500_000_000),
+            memory_available: Some(2 << 30),
+            file_handles: Some((5_000, 100_000)),
+        }
+    }
+
+    #[test]
+    fn a_host_with_room_passes() {
+        assert_eq!(
+            evaluate(&readings()),
+            [
+                Finding::Pass("10.0 GB free on /var/lib/docker".to_string()),
+                Finding::Pass("2.0 GiB of memory available".to_string()),
+                Finding::Pass("95000 of 100000 file handles left".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn the_disk_needs_the_image_and_the_margin() {
+        let short = Readings {
+            disk_free: Some(1_500_000_000),
+            ..readings()
+        };
+        assert_eq!(
+            check_disk(&short),
+            Finding::Fail("Need ~1.6 GB, only 1.5 GB free on /var/lib/docker".to_string())
+        );
+
+        let unknown_image = Readings {
+            disk_free: Some(DISK_MARGIN - 1),
+            image_size: None,
+            ..readings()
+        };
+        assert!(
+            matches!(check_disk(&unknown_image), Finding::Fail(message) if message.ends_with("without knowing the image size"))
+        );
+        let unknown_image = Readings {
+            image_size: None,
+            ..readings()
+        };
+        assert!(matches!(check_disk(&unknown_image), Finding::Warn(_)));
+
+        let unread = Readings {
+            data_root: None,
+            disk_free: None,
+            ..readings()
+        };
+        assert_eq!(
+            check_disk(&unread),
+            Finding::Warn("Can't tell how much space is free on the Docker data root".to_string())
+        );
+    }
+
+    #[test]
+    fn memory_and_file_handles_have_a_floor() {
+        let low = Readings {
+            memory_available: Some(MIN_MEMORY - 1),
+            file_handles: Some((99_500, 100_000)),
+            ..readings()
+        };
+        assert!(matches!(check_memory(&low), Finding::Fail(_)));
+        assert_eq!(
+            check_file_handles(&low),
+            Finding::Fail("Only 500 of 100000 file handles left, raise fs.file-max or close files".to_string())
+        );
+        // More allocated than the maximum after it was lowered
+        let over = Readings {
+            file_handles: Some((200_000, 100_000)),
+            ..readings()
+        };
+        assert!(matches!(check_file_handles(&over), Finding::Fail(message) if message.starts_with("Only 0 of")));
+        assert!(matches!(check_memory(&Readings::default()), Finding::Warn(_)));
+        assert!(matches!(check_file_handles(&Readings::default()), Finding::Warn(_)));
+    }
+}
@@ -0,0 +1,113 @@
+use std::time::Duration;
+
+use bollard::container::{CreateContainerOptions, LogOutput, LogsOptions, WaitContainerOptions};
+use bollard::errors::Error;
+use bollard::Docker;
+use futures_util::StreamExt;
+
+use crate::auxiliary::{aux_labels, remove, AuxGuard, AuxKind};
+use crate::logger::Logger;
+use crate::model::PreStartConfig;
+use crate::spec::ContainerSpec;
+
+/// Runs the `pre_start` command of an app in a one-off container before the app starts.
+pub struct PreStart<'a> {
+    log: &'a Logger,
+    docker: &'a Docker,
+    config: &'a PreStartConfig,
+}
+
+impl<'a> PreStart<'a> {
+    pub fn new(log: &'a Logger, docker: &'a Docker, config: &'a PreStartConfig) -> PreStart<'a> {
+        PreStart { log, docker, config }
+    }
+
+    /// Run the command in a container like the one described by `spec`, named after `container_name`,
+    /// with its output logged line by line. The error says why the app must not start.
+    pub async fn run(&self, app: &str, container_name: &str, spec: &ContainerSpec) -> Result<(), String> {
+        let name = format!("{}-pre-start", container_name);
+        let mut config = spec.to_create_config();
+        config.cmd = Some(vec!["sh".to_string(), "-c".to_string(), self.config.command.clone()]);
+        config.exposed_ports = None;
+        // Nothing writes to it, a command reading stdin would wait for the timeout
+        config.open_stdin = None;
+        // Labeled as a one-off of the app, so `ruku repair` finds it but nothing takes it for the app itself
+        config.labels = Some(aux_labels(app, AuxKind::PreStart));
+        if let Some(host_config) = config.host_config.as_mut() {
+            // The app container may hold the ports already, and the command must not be restarted
+            host_config.port_bindings = None;
+            host_config.restart_policy = None;
+        }
+        // Not reachable under the app's name
+        if let Some(networking) = config.networking_config.as_mut() {
+            for endpoint in networking.endpoints_config.values_mut() {
+                endpoint.aliases = None;
+            }
+        }
+
+        self.log
+            .step(&format!("Running pre-start command: {}", self.config.command));
+        // A container left by an interrupted run would block the name
+        remove(self.docker, &name).await;
+        let options = CreateContainerOptions {
+            name: name.as_str(),
+            platform: None,
+        };
+        self.docker
+            .create_container(Some(options), config)
+            .await
+            .map_err(|e| format!("Failed to create the pre-start container: {}", e))?;
+        let guard = AuxGuard::new(self.docker, &name);
+
+        let timeout = Duration::from_secs(self.config.timeout);
+        let result = match tokio::time::timeout(timeout, self.attach(&name)).await {
+            Ok(result) => result,
+            Err(_) => Err(format!("Pre-start command timed out after {}s", self.config.timeout)),
+        };
+        guard.remove().await;
+        result
+    }
+
+    async fn attach(&self, name: &str) -> Result<(), String> {
+        self.docker
+            .start_container::<String>(name, None)
+            .await
+            .map_err(|e| format!("Failed to start the pre-start container: {}", e))?;
+
+        let options = LogsOptions::<String> {
+            follow: true,
+            stdout: true,
+            stderr: true,
+            ..Default::default()
+        };
+        let mut logs = self.docker.logs(name, Some(options));
+        while let Some(Ok(chunk)) = logs.next().await {
+            let warn = matches!(chunk, LogOutput::StdErr { .. });
+            for line in String::from_utf8_lossy(chunk.as_ref()).lines() {
+                let line = format!("pre-start: {}", line);
+                if warn {
+                    self.log.warn(&line);
+                } else {
+                    self.log.step(&line);
+                }
+            }
+        }
+
+        let exit_code = match self
+            .docker
+            .wait_container(name, None::<WaitContainerOptions<String>>)
+            .next()
+            .await
+        {
+            Some(Ok(response)) => response.status_code,
+            Some(Err(Error::DockerContainerWaitError { code, .. })) => code,
+            Some(Err(e)) => return Err(e.to_string()),
+            None => 0,
+        };
+        if exit_code != 0 {
+            return Err(format!("Pre-start command exited with code {}", exit_code));
+        }
+        self.log.step("Pre-start command succeeded");
+        Ok(())
+    }
+}
@@ -0,0 +1,281 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Duration, Utc};
+use cmd_lib::{run_cmd, run_fun};
+use flate2::Crc;
+use serde::{Deserialize, Serialize};
+
+use crate::config::load_ruku_config;
+use crate::container::CONTAINER_SUFFIXES;
+use crate::logger::Logger;
+use crate::misc::{validate_app_name, MAX_APP_NAME_LENGTH};
+use crate::model::{DeployStrategy, RukuConfig};
+use crate::server_config::ServerConfig;
+use crate::store::{self, Document};
+
+/// Hex digits of the branch hash added to preview names that had to be shortened.
+const NAME_HASH_LENGTH: usize = 6;
+
+/// Put between the app name and the branch, no container of the app itself is named `<app>-pr-…`.
+const PREVIEW_INFIX: &str = "pr";
+
+/// The app name of the preview of `branch`, `<app>-pr-<branch>` with the branch lowercased and everything
+/// but letters and digits turned into single dashes, e.g. `feature/Login_Form` becomes
+/// `shop-pr-feature-login-form`. Names over the app name limit are cut short and end in a hash of the
+/// branch, so long branches sharing a prefix still get names of their own. A name ending like the
+/// containers run next to another preview's, e.g. `shop-pr-login-canary` next to `shop-pr-login`, or like
+/// one of the app's `sidecars`, is refused.
+pub fn preview_name(app: &str, branch: &str, sidecars: &[String]) -> Result<String, String> {
+    let mut slug = String::new();
+    for c in branch.chars().map(|c| c.to_ascii_lowercase()) {
+        if c.is_ascii_lowercase() || c.is_ascii_digit() {
+            slug.push(c);
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    let slug = slug.trim_end_matches('-');
+    if slug.is_empty() {
+        return Err(format!(
+            "Branch '{}' has no letters or digits to name a preview after",
+            branch
+        ));
+    }
+
+    let mut name = format!("{}-{}-{}", app, PREVIEW_INFIX, slug);
+    if name.len() > MAX_APP_NAME_LENGTH {
+        let keep = MAX_APP_NAME_LENGTH.saturating_sub(app.len() + PREVIEW_INFIX.len() + NAME_HASH_LENGTH + 3);
+        if keep == 0 {
+            return Err(format!("App name '{}' is too long to name previews after", app));
+        }
+        let mut crc = Crc::new();
+        crc.update(branch.as_bytes());
+        let hash = format!("{:08x}", crc.sum());
+        // The slug is ASCII, any byte index is a char boundary
+        name = format!(
+            "{}-{}-{}-{}",
+            app,
+            PREVIEW_INFIX,
+            slug[..keep].trim_end_matches('-'),
+            &hash[..NAME_HASH_LENGTH]
+        );
+    }
+    validate_app_name(&name)?;
+    let mut suffixes = CONTAINER_SUFFIXES
+        .iter()
+        .copied()
+        .chain(sidecars.iter().map(String::as_str));
+    if let Some(suffix) = suffixes.find(|suffix| name.ends_with(&format!("-{}", suffix))) {
+        return Err(format!(
+            "The preview of branch '{}' would be named {}, which ends like the {} container ruku runs next to \
+             an app, rename the branch",
+            branch, name, suffix
+        ));
+    }
+    Ok(name)
+}
+
+/// A preview deployed from a branch, kept in the state directory of the preview.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Preview {
+    /// The app the preview is a copy of.
+    pub app: String,
+    pub branch: String,
+    /// The app name the preview is deployed under.
+    pub name: String,
+    pub created_at: DateTime<Utc>,
+    /// Days after which `preview:reap` removes the preview, kept until destroyed when unset.
+    pub ttl_days: Option<u64>,
+}
+
+impl Document for Preview {}
+
+impl Preview {
+    pub const FILE_NAME: &'static str = "preview.json";
+
+    pub fn read(log: &Logger, state_dir: &Path) -> Result<Option<Preview>, String> {
+        store::load(log, &state_dir.join(Self::FILE_NAME))
+    }
+
+    /// Whether the preview has outlived its ttl at `now`.
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.ttl_days
+            .is_some_and(|days| now - self.created_at > Duration::days(days as i64))
+    }
+
+    /// Turn the branch's config into the preview's: an automatic port, no canary and the preview env.
+    /// Returns the keys it set.
+    pub fn apply(&self, config: &mut RukuConfig) -> Vec<&'static str> {
+        let mut keys = vec![];
+        if !config.port.auto {
+            config.port.auto = true;
+            config.port.host_port = 0;
+            keys.extend(["port.auto", "port.host_port"]);
+        }
+        config.strategy = DeployStrategy::Recreate;
+        config.canary = None;
+        config.preview_branch = Some(self.branch.clone());
+        keys.extend(["strategy", "canary", "preview_branch"]);
+        keys
+    }
+}
+
+/// Checks out branches of an app into preview apps and keeps track of them.
+pub struct Previews<'a> {
+    log: &'a Logger,
+    server_config: &'a ServerConfig,
+}
+
+impl<'a> Previews<'a> {
+    pub fn new(log: &'a Logger, server_config: &'a ServerConfig) -> Previews<'a> {
+        Previews { log, server_config }
+    }
+
+    /// Check out `branch` of the app's repository as its preview app, or update the checkout of an
+    /// existing preview. The ttl of an existing preview is only replaced when one is given.
+    pub fn checkout(&self, app: &str, branch: &str, ttl_days: Option<u64>) -> Result<Preview, String> {
+        // The sidecars of the app's own ruku.yml, the branch's are only known after the checkout
+        let sidecars: Vec<String> = load_ruku_config(app, self.server_config)
+            .map(|config| config.sidecars.into_iter().map(|sidecar| sidecar.name).collect())
+            .unwrap_or_default();
+        let name = preview_name(app, branch, &sidecars)?;
+        let repo_path = self.server_config.git_root.join(app);
+        let app_path = self.server_config.apps_root.join(&name);
+        let state_dir = self.server_config.state_root.join(&name);
+        let existing = Preview::read(self.log, &state_dir)?;
+        if let Some(existing) = existing.as_ref().filter(|existing| existing.branch != branch) {
+            return Err(format!(
+                "{} is already the preview of branch {} of {}",
+                name, existing.branch, existing.app
+            ));
+        }
+        if existing.is_none() && app_path.exists() {
+            return Err(format!("An app named {} already exists and is not a preview", name));
+        }
+        if !repo_path.exists() {
+            return Err(format!("{} has no git repository to preview", app));
+        }
+        let git_dir = repo_path.display().to_string();
+        let branch_ref = format!("refs/heads/{}", branch);
+        run_fun!(git --git-dir=$git_dir rev-parse --verify --quiet $branch_ref)
+            .map_err(|_| format!("{} has no branch {}", app, branch))?;
+
+        let work_tree = app_path.display().to_string();
+        if app_path.exists() {
+            self.log.step(&format!("Updating {} to the latest {}", name, branch));
+            run_cmd!(
+                git -C $work_tree fetch --quiet origin $branch;
+                git -C $work_tree reset --quiet --hard FETCH_HEAD;
+            )
+        } else {
+            self.log.step(&format!("Checking out {} into {}", branch, name));
+            run_cmd!(git clone --quiet --branch $branch $git_dir $work_tree)
+        }
+        .map_err(|e| format!("Error checking out branch {}: {}", branch, e))?;
+
+        let preview = Preview {
+            app: app.to_string(),
+            branch: branch.to_string(),
+            name,
+            created_at: existing.as_ref().map_or_else(Utc::now, |existing| existing.created_at),
+            ttl_days: ttl_days.or(existing.and_then(|existing| existing.ttl_days)),
+        };
+        store::save(&state_dir.join(Preview::FILE_NAME), &preview)
+            .map_err(|e| format!("Error recording the preview: {}", e))?;
+        Ok(preview)
+    }
+
+    /// Every recorded preview, of `app` only when given, by app and then branch.
+    pub fn list(&self, app: Option<&str>) -> Result<Vec<Preview>, String> {
+        let mut previews = fs::read_dir(&self.server_config.state_root)
+            .into_iter()
+            .flatten()
+            .flatten()
+            .filter_map(|entry| Preview::read(self.log, &entry.path()).transpose())
+            .collect::<Result<Vec<Preview>, String>>()?;
+        previews.retain(|preview| app.is_none_or(|app| preview.app == app));
+        previews.sort_by(|a, b| (&a.app, &a.branch).cmp(&(&b.app, &b.branch)));
+        Ok(previews)
+    }
+
+    /// The preview of `branch` of the app, failing when there is none.
+    pub fn get(&self, app: &str, branch: &str) -> Result<Preview, String> {
+        self.list(Some(app))?
+            .into_iter()
+            .find(|preview| preview.branch == branch)
+            .ok_or_else(|| format!("{} has no preview of branch {}", app, branch))
+    }
+
+    /// Delete the checkout, state and data of a preview, its containers have to be removed first.
+    pub fn remove(&self, preview: &Preview) -> Result<(), String> {
+        for path in self.paths(preview) {
+            if path.exists() {
+                fs::remove_dir_all(&path).map_err(|e| format!("Error removing {}: {}", path.display(), e))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn paths(&self, preview: &Preview) -> [PathBuf; 3] {
+        [
+            self.server_config.apps_root.join(&preview.name),
+            self.server_config.data_root.join(&preview.name),
+            self.server_config.state_root.join(&preview.name),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn branches_are_slugged_after_the_infix() {
+        assert_eq!(
+            preview_name("shop", "feature/Login_Form", &[]).unwrap(),
+            "shop-pr-feature-login-form"
+        );
+        assert_eq!(preview_name("shop", "--fix--#12--", &[]).unwrap(), "shop-pr-fix-12");
+        assert!(preview_name("shop", "///", &[]).is_err());
+    }
+
+    #[test]
+    fn previews_never_take_a_role_container_name() {
+        // Without the infix, branch `worker` would be named like the app's `worker` sidecar
+        let sidecars = ["db".to_string()];
+        assert_eq!(preview_name("shop", "worker", &sidecars).unwrap(), "shop-pr-worker");
+        // and these like the app's canary, maintenance page and staged containers
+        for suffix in CONTAINER_SUFFIXES {
+            assert!(preview_name("shop", suffix, &sidecars).is_err(), "{}", suffix);
+        }
+    }
+
+    #[test]
+    fn branches_ending_like_a_role_container_are_refused() {
+        // `shop-pr-login-canary` would be the canary of the preview `shop-pr-login`
+        for suffix in CONTAINER_SUFFIXES {
+            assert!(
+                preview_name("shop", &format!("login/{}", suffix), &[]).is_err(),
+                "{}",
+                suffix
+            );
+        }
+        assert!(preview_name("shop", "login-worker", &["worker".to_string()]).is_err());
+        assert!(preview_name("shop", "login-worker", &[]).is_ok());
+        assert!(preview_name("shop", "canary-fix", &[]).is_ok());
+    }
+
+    #[test]
+    fn long_branches_are_cut_and_hashed() {
+        let first = preview_name("shop", &format!("feature/{}-one", "x".repeat(80)), &[]).unwrap();
+        let second = preview_name("shop", &format!("feature/{}-two", "x".repeat(80)), &[]).unwrap();
+        assert!(first.len() <= MAX_APP_NAME_LENGTH, "{}", first);
+        assert!(first.starts_with("shop-pr-feature-xxx"), "{}", first);
+        assert_ne!(first, second);
+        assert_eq!(
+            first,
+            preview_name("shop", &format!("feature/{}-one", "x".repeat(80)), &[]).unwrap()
+        );
+    }
+}
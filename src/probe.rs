@@ -0,0 +1,348 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use bollard::container::{Config, CreateContainerOptions, LogsOptions, WaitContainerOptions};
+use bollard::errors::Error;
+use bollard::exec::{CreateExecOptions, StartExecResults};
+use bollard::models::HostConfig;
+use bollard::Docker;
+use futures_util::StreamExt;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::auxiliary::{aux_labels, remove, AuxGuard, AuxKind};
+use crate::image::Image;
+use crate::logger::Logger;
+use crate::model::{ProbeConfig, ProbeMode};
+use crate::smoke::host_header;
+
+/// Where a container can be reached for probing.
+pub struct ProbeTarget {
+    pub app: String,
+    pub container_name: String,
+    /// The app network the container is attached to.
+    pub network: String,
+    pub container_port: u16,
+    /// Address and port published on the host, none when nothing is published over TCP.
+    pub host: Option<(String, u16)>,
+}
+
+/// Check an HTTP response against the expected status codes and body text.
+pub fn check_response(response: &str, config: &ProbeConfig) -> Result<(), String> {
+    let status = response
+        .lines()
+        .map(str::trim)
+        .find(|line| line.starts_with("HTTP/"))
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|code| code.parse::<u16>().ok())
+        .ok_or("no HTTP response")?;
+    let expected = if config.status.is_empty() {
+        (200..400).contains(&status)
+    } else {
+        config.status.contains(&status)
+    };
+    if !expected {
+        return Err(format!("unexpected status {}", status));
+    }
+    if let Some(body) = &config.body {
+        if !response.contains(body.as_str()) {
+            return Err(format!("response does not contain '{}'", body));
+        }
+    }
+    Ok(())
+}
+
+/// The socket address of `ip` and `port`, an IPv6 address with or without brackets.
+pub fn socket_address(ip: &str, port: u16) -> Result<SocketAddr, String> {
+    format!("{}:{}", ip, port)
+        .parse()
+        .or_else(|_| format!("[{}]:{}", ip, port).parse())
+        .map_err(|_| format!("invalid address {}", ip))
+}
+
+/// Connect to `address` and, with a `request`, send it and read the response until the other side
+/// closes the connection, all within `timeout`.
+pub async fn exchange(address: SocketAddr, request: Option<&str>, timeout: Duration) -> Result<String, String> {
+    let exchange = async {
+        let mut stream = TcpStream::connect(address).await?;
+        let mut response = vec![];
+        if let Some(request) = request {
+            stream.write_all(request.as_bytes()).await?;
+            stream.read_to_end(&mut response).await?;
+        }
+        Ok::<_, std::io::Error>(String::from_utf8_lossy(&response).to_string())
+    };
+    match tokio::time::timeout(timeout, exchange).await {
+        Ok(result) => result.map_err(|e| e.to_string()),
+        Err(_) => Err(format!("no answer within {}s", timeout.as_secs())),
+    }
+}
+
+/// Shell script probing `host:port` with whatever the image has: curl, wget, nc or bash.
+fn probe_script(host: &str, port: u16, config: &ProbeConfig) -> String {
+    let timeout = config.timeout;
+    match &config.path {
+        Some(path) => {
+            let url = quote(&format!("http://{}:{}{}", host, port, path));
+            let request = quote(&format!("GET {} HTTP/1.0\\r\\nHost: {}\\r\\n\\r\\n", path, host));
+            format!(
+                "if command -v curl >/dev/null 2>&1; then curl -s -i -m {timeout} {url}; \
+                 elif command -v wget >/dev/null 2>&1; then wget -q -S -O - -T {timeout} {url} 2>&1; \
+                 else bash -c 'exec 3<>/dev/tcp/{host}/{port} && printf \"$0\" >&3 && cat <&3' {request}; fi"
+            )
+        }
+        None => format!(
+            "if command -v nc >/dev/null 2>&1; then nc -z -w {timeout} {host} {port}; \
+             else bash -c 'exec 3<>/dev/tcp/{host}/{port}'; fi"
+        ),
+    }
+}
+
+/// Quote a value for `sh`.
+pub fn quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+/// Runs the readiness check of an app.
+pub struct Probe<'a> {
+    log: &'a Logger,
+    docker: &'a Docker,
+    config: &'a ProbeConfig,
+}
+
+impl<'a> Probe<'a> {
+    pub fn new(log: &'a Logger, docker: &'a Docker, config: &'a ProbeConfig) -> Probe<'a> {
+        Probe { log, docker, config }
+    }
+
+    /// The configured mode, auto picks the host port when there is one.
+    pub fn mode(&self, target: &ProbeTarget) -> ProbeMode {
+        match self.config.mode {
+            ProbeMode::Auto if target.host.is_some() => ProbeMode::Host,
+            // Nothing outside the container can reach it
+            ProbeMode::Auto if target.network == "none" => ProbeMode::Exec,
+            ProbeMode::Auto => ProbeMode::Network,
+            mode => mode,
+        }
+    }
+
+    /// One attempt, the error says why the container is not ready yet.
+    pub async fn check(&self, target: &ProbeTarget) -> Result<(), String> {
+        match self.mode(target) {
+            ProbeMode::Host => self.check_host(target).await,
+            ProbeMode::Exec => self.check_exec(target).await,
+            _ => self.check_network(target).await,
+        }
+    }
+
+    async fn check_host(&self, target: &ProbeTarget) -> Result<(), String> {
+        let (ip, port) = target.host.as_ref().ok_or("no port is published on the host")?;
+        let address = socket_address(ip, *port)?;
+        let request = self.config.path.as_ref().map(|path| {
+            format!(
+                "GET {} HTTP/1.0\r\nHost: {}\r\nConnection: close\r\n\r\n",
+                path,
+                host_header(&address)
+            )
+        });
+        let timeout = Duration::from_secs(self.config.timeout);
+        let response = exchange(address, request.as_deref(), timeout).await?;
+        match request {
+            Some(_) => check_response(&response, self.config),
+            None => Ok(()),
+        }
+    }
+
+    async fn check_exec(&self, target: &ProbeTarget) -> Result<(), String> {
+        let script = probe_script("127.0.0.1", target.container_port, self.config);
+        let (output, exit_code) = exec_script(self.docker, &target.container_name, script).await?;
+        self.evaluate(&output, exit_code)
+    }
+
+    /// Probe from a throwaway container on the app network, the app is reached by its container name.
+    async fn check_network(&self, target: &ProbeTarget) -> Result<(), String> {
+        let script = probe_script(&target.container_name, target.container_port, self.config);
+        let (output, exit_code) = run_on_network(self.log, self.docker, &self.config.image, target, script).await?;
+        self.evaluate(&output, exit_code)
+    }
+
+    /// An HTTP probe is judged by the response, wget exits non-zero on error statuses it still printed.
+    fn evaluate(&self, output: &str, exit_code: i64) -> Result<(), String> {
+        match &self.config.path {
+            Some(_) => check_response(output, self.config),
+            None if exit_code == 0 => Ok(()),
+            None if output.trim().is_empty() => Err("connection failed".to_string()),
+            None => Err(format!("connection failed: {}", output.trim())),
+        }
+    }
+}
+
+/// Run `script` with `sh -c` inside the target container, returning its output and exit code.
+pub async fn exec_script(docker: &Docker, container_name: &str, script: String) -> Result<(String, i64), String> {
+    let options = CreateExecOptions {
+        cmd: Some(vec!["sh".to_string(), "-c".to_string(), script]),
+        attach_stdout: Some(true),
+        attach_stderr: Some(true),
+        ..Default::default()
+    };
+    let exec = docker
+        .create_exec(container_name, options)
+        .await
+        .map_err(|e| e.to_string())?;
+    let mut output = String::new();
+    if let StartExecResults::Attached { output: mut stream, .. } =
+        docker.start_exec(&exec.id, None).await.map_err(|e| e.to_string())?
+    {
+        while let Some(Ok(chunk)) = stream.next().await {
+            output.push_str(&String::from_utf8_lossy(chunk.as_ref()));
+        }
+    }
+    // Without an exit code the script can't count as passed
+    let exit_code = docker
+        .inspect_exec(&exec.id)
+        .await
+        .map_err(|e| format!("could not inspect the exec: {}", e))?
+        .exit_code
+        .ok_or("the exec reported no exit code")?;
+    Ok((output, exit_code))
+}
+
+/// Run `script` with `sh -c` in a throwaway container of `image` on the app network of the target,
+/// returning its output and exit code.
+pub async fn run_on_network(
+    log: &Logger,
+    docker: &Docker,
+    image: &str,
+    target: &ProbeTarget,
+    script: String,
+) -> Result<(String, i64), String> {
+    let images = Image::new(log, docker);
+    if !images.exists(image).await {
+        images.pull(image).await?;
+    }
+
+    let probe_name = format!("{}-probe", target.container_name);
+    let config = Config {
+        image: Some(image.to_string()),
+        cmd: Some(vec!["sh".to_string(), "-c".to_string(), script]),
+        host_config: Some(HostConfig {
+            network_mode: Some(target.network.clone()),
+            ..Default::default()
+        }),
+        // Labeled as a one-off of the app so `ruku repair` finds it when a run is interrupted
+        labels: Some(aux_labels(&target.app, AuxKind::Probe)),
+        ..Default::default()
+    };
+    let options = CreateContainerOptions {
+        name: probe_name.as_str(),
+        platform: None,
+    };
+    // A probe container left by an interrupted run would block the name
+    remove(docker, &probe_name).await;
+    docker
+        .create_container(Some(options), config)
+        .await
+        .map_err(|e| e.to_string())?;
+    let guard = AuxGuard::new(docker, &probe_name);
+
+    let result = run_container(docker, &probe_name).await;
+    guard.remove().await;
+    result
+}
+
+async fn run_container(docker: &Docker, name: &str) -> Result<(String, i64), String> {
+    docker
+        .start_container::<String>(name, None)
+        .await
+        .map_err(|e| e.to_string())?;
+    let exit_code = match docker
+        .wait_container(name, None::<WaitContainerOptions<String>>)
+        .next()
+        .await
+    {
+        Some(Ok(response)) => response.status_code,
+        Some(Err(Error::DockerContainerWaitError { code, .. })) => code,
+        Some(Err(e)) => return Err(e.to_string()),
+        None => return Err(format!("{} ended without an exit code", name)),
+    };
+
+    let options = LogsOptions::<String> {
+        stdout: true,
+        stderr: true,
+        ..Default::default()
+    };
+    let mut output = String::new();
+    let mut logs = docker.logs(name, Some(options));
+    while let Some(Ok(chunk)) = logs.next().await {
+        output.push_str(&String::from_utf8_lossy(chunk.as_ref()));
+    }
+    Ok((output, exit_code))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quoted_values_are_literal_to_sh() {
+        let cases = [
+            ("", "''"),
+            ("plain", "'plain'"),
+            ("it's", "'it'\\''s'"),
+            ("$HOME \\n \"x\"", "'$HOME \\n \"x\"'"),
+            ("a\nb", "'a\nb'"),
+        ];
+        for (value, quoted) in cases {
+            assert_eq!(quote(value), quoted);
+            let echoed = std::process::Command::new("sh")
+                .args(["-c", &format!("printf %s {}", quoted)])
+                .output()
+                .unwrap();
+            assert_eq!(String::from_utf8_lossy(&echoed.stdout), value);
+        }
+    }
+
+    #[tokio::test]
+    async fn exchanges_a_request_for_the_response() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = [0; 64];
+            let read = stream.read(&mut request).await.unwrap();
+            assert!(request[..read].starts_with(b"GET /"));
+            stream.write_all(b"HTTP/1.0 204 No Content\r\n\r\n").await.unwrap();
+        });
+        let response = exchange(address, Some("GET / HTTP/1.0\r\n\r\n"), Duration::from_secs(5)).await;
+        assert_eq!(response.as_deref(), Ok("HTTP/1.0 204 No Content\r\n\r\n"));
+    }
+
+    #[tokio::test]
+    async fn gives_up_on_a_silent_peer() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        // Accepted by the kernel but never answered
+        let response = exchange(address, Some("GET / HTTP/1.0\r\n\r\n"), Duration::from_millis(200)).await;
+        assert!(response.is_err());
+        drop(listener);
+    }
+
+    #[test]
+    fn reads_bracketed_and_bare_ipv6_addresses() {
+        assert_eq!(socket_address("::1", 80), "[::1]:80".parse().map_err(|_| String::new()));
+        assert_eq!(
+            socket_address("127.0.0.1", 80),
+            "127.0.0.1:80".parse().map_err(|_| String::new())
+        );
+        assert!(socket_address("not an ip", 80).is_err());
+    }
+
+    #[test]
+    fn judges_responses_by_status_and_body() {
+        let config: ProbeConfig = serde_yaml::from_str("path: /health\nbody: ok").unwrap();
+        assert_eq!(check_response("HTTP/1.1 200 OK\r\n\r\nok", &config), Ok(()));
+        assert!(check_response("HTTP/1.1 500 Error\r\n\r\nok", &config).is_err());
+        assert!(check_response("HTTP/1.1 200 OK\r\n\r\nnope", &config).is_err());
+        assert!(check_response("", &config).is_err());
+    }
+}
@@ -0,0 +1,239 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::LazyLock;
+
+use regex::Regex;
+use serde_yaml::Value;
+
+use crate::model::RukuConfig;
+use crate::yaml_spans::{key_spans, KeySpan};
+
+/// A reference to the environment ruku runs in, e.g. `${env.DATABASE_URL}`.
+static ENV_REFERENCE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\$\{env\.([A-Za-z_][A-Za-z0-9_]*)\}").unwrap());
+
+/// Where the effective value of a config field came from.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Source {
+    /// Set in a config file, at a 1-based line.
+    File { path: PathBuf, line: usize },
+    /// Taken from the environment variable `var` ruku runs in, by a `${env.NAME}` written in a config file.
+    Env { var: String, path: PathBuf, line: usize },
+    /// Replaced by ruku when loading the config, e.g. `preview feature-x`, with the state file it keeps.
+    Overlay { name: String, path: PathBuf },
+    /// Given on the command line, e.g. `--strategy`.
+    Cli { flag: String },
+    /// Not set anywhere, the built-in default applies.
+    Default,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Source::File { path, line } => write!(f, "{}:{}", path.display(), line),
+            Source::Env { var, path, line } => write!(f, "env {} ({}:{})", var, path.display(), line),
+            Source::Overlay { name, path } => write!(f, "{} ({})", name, path.display()),
+            Source::Cli { flag } => write!(f, "{}", flag),
+            Source::Default => write!(f, "default"),
+        }
+    }
+}
+
+/// An effective config field, e.g. `canary.pause`, with its value and source.
+pub struct Field {
+    pub key: String,
+    pub value: String,
+    pub source: Source,
+}
+
+/// The config together with where each of its keys was set.
+pub struct Provenance {
+    /// Key paths set in each layer, in the order the layers were applied.
+    layers: Vec<(PathBuf, BTreeMap<String, KeySpan>)>,
+    /// Keys replaced after the files were merged, by the overlays and the command line.
+    overrides: BTreeMap<String, Source>,
+}
+
+impl Provenance {
+    pub fn new() -> Provenance {
+        Provenance {
+            layers: vec![],
+            overrides: BTreeMap::new(),
+        }
+    }
+
+    /// Record the keys of a YAML document loaded from `path`, a later layer wins over earlier ones.
+    pub fn add_file(&mut self, path: &Path, content: &str) {
+        self.layers.push((path.to_path_buf(), key_spans(content)));
+    }
+
+    /// Record that `key` and the keys under it were replaced from `source` after the files were merged.
+    pub fn set(&mut self, key: &str, source: Source) {
+        self.overrides.retain(|overridden, _| !is_within(overridden, key));
+        self.overrides.insert(key.to_string(), source);
+    }
+
+    /// Where `key` was set. A key that is not set itself inherits the source of its closest parent when
+    /// that parent was written as a single value, a mapping only accounts for the keys it lists.
+    pub fn source(&self, key: &str) -> Source {
+        let overrides = self
+            .overrides
+            .iter()
+            .filter(|(overridden, _)| is_within(key, overridden));
+        // The closest override, `canary.port` over `canary`
+        if let Some((_, source)) = overrides.max_by_key(|(overridden, _)| overridden.len()) {
+            return source.clone();
+        }
+        for (path, keys) in self.layers.iter().rev() {
+            let mut candidate = key;
+            loop {
+                if let Some(span) = keys.get(candidate) {
+                    let prefix = format!("{}.", candidate);
+                    let is_mapping = keys.keys().any(|k| k.starts_with(&prefix));
+                    if candidate == key || !is_mapping {
+                        return file_source(path, span);
+                    }
+                    break;
+                }
+                match candidate.rsplit_once('.') {
+                    Some((parent, _)) => candidate = parent,
+                    None => break,
+                }
+            }
+        }
+        Source::Default
+    }
+
+    /// Every effective field of `config`, in the order the config declares them.
+    pub fn explain(&self, config: &RukuConfig) -> Vec<Field> {
+        let value = serde_yaml::to_value(config).unwrap_or(Value::Null);
+        flatten(&value)
+            .into_iter()
+            .map(|(key, value)| Field {
+                source: self.source(&key),
+                value: serde_json::to_string(value).unwrap_or_default(),
+                key,
+            })
+            .collect()
+    }
+}
+
+impl Default for Provenance {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Every leaf of a YAML document by its dotted key path, e.g. `canary.pause`, in document order. Leaves
+/// are the values that are not mappings, and the empty mappings.
+pub fn flatten(value: &Value) -> Vec<(String, &Value)> {
+    let mut leaves = vec![];
+    flatten_into("", value, &mut leaves);
+    leaves
+}
+
+fn flatten_into<'v>(prefix: &str, value: &'v Value, out: &mut Vec<(String, &'v Value)>) {
+    match value {
+        Value::Mapping(mapping) if !mapping.is_empty() => {
+            for (key, value) in mapping {
+                let key = key.as_str().map(str::to_string).unwrap_or_default();
+                let key = if prefix.is_empty() {
+                    key
+                } else {
+                    format!("{}.{}", prefix, key)
+                };
+                flatten_into(&key, value, out);
+            }
+        }
+        value => out.push((prefix.to_string(), value)),
+    }
+}
+
+/// Whether `key` is `parent` or one of the keys under it.
+fn is_within(key: &str, parent: &str) -> bool {
+    key == parent || key.strip_prefix(parent).is_some_and(|rest| rest.starts_with('.'))
+}
+
+/// The source of a key written at `span` of the file at `path`, the environment when its value is a
+/// `${env.NAME}` reference.
+fn file_source(path: &Path, span: &KeySpan) -> Source {
+    let reference = span.scalar.as_deref().and_then(|scalar| ENV_REFERENCE.captures(scalar));
+    match reference {
+        Some(captures) => Source::Env {
+            var: captures[1].to_string(),
+            path: path.to_path_buf(),
+            line: span.line,
+        },
+        None => Source::File {
+            path: path.to_path_buf(),
+            line: span.line,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overrides_win_over_the_files() {
+        let mut provenance = Provenance::new();
+        provenance.add_file(Path::new("ruku.yml"), "canary:\n  port: 3001\nstrategy: canary\n");
+        provenance.set(
+            "canary",
+            Source::Cli {
+                flag: "--no-canary".to_string(),
+            },
+        );
+        provenance.set(
+            "canary.port",
+            Source::Cli {
+                flag: "--canary-port".to_string(),
+            },
+        );
+        assert_eq!(provenance.source("canary.port").to_string(), "--canary-port");
+        assert_eq!(provenance.source("canary.steps").to_string(), "--no-canary");
+        assert_eq!(provenance.source("strategy").to_string(), "ruku.yml:3");
+
+        // A later override of a parent replaces the ones of its keys
+        provenance.set(
+            "canary",
+            Source::Cli {
+                flag: "--strategy".to_string(),
+            },
+        );
+        assert_eq!(provenance.source("canary.port").to_string(), "--strategy");
+        assert_eq!(provenance.source("canaryish").to_string(), "default");
+    }
+
+    #[test]
+    fn env_references_are_attributed_to_their_variable() {
+        let mut provenance = Provenance::new();
+        provenance.add_file(
+            Path::new("ruku.yml"),
+            "files:\n  /etc/app.conf: \"url=${env.DATABASE_URL}\"\n",
+        );
+        assert_eq!(
+            provenance.source("files./etc/app.conf"),
+            Source::Env {
+                var: "DATABASE_URL".to_string(),
+                path: PathBuf::from("ruku.yml"),
+                line: 2
+            }
+        );
+    }
+
+    #[test]
+    fn flatten_keeps_document_order_and_empty_mappings() {
+        let value: Value = serde_yaml::from_str("b:\n  d: 1\n  c: x\na: {}\ne: null\n").unwrap();
+        let leaves: Vec<(String, String)> = flatten(&value)
+            .into_iter()
+            .map(|(key, value)| (key, serde_json::to_string(value).unwrap()))
+            .collect();
+        let expected = [("b.d", "1"), ("b.c", "\"x\""), ("a", "{}"), ("e", "null")];
+        assert_eq!(
+            leaves,
+            expected.map(|(key, value)| (key.to_string(), value.to_string()))
+        );
+    }
+}
@@ -0,0 +1,614 @@
+use std::collections::HashMap;
+use std::fs;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use bollard::container::{
+    Config, CreateContainerOptions, KillContainerOptions, ListContainersOptions, LogsOptions, RemoveContainerOptions,
+    StartContainerOptions, UploadToContainerOptions,
+};
+use bollard::errors::Error;
+use bollard::models::{ContainerSummary, HostConfig, PortTypeEnum, RestartPolicy, RestartPolicyNameEnum};
+use bollard::Docker;
+use futures_util::StreamExt;
+
+use crate::canary::CanaryState;
+use crate::container::{Container, APP_LABEL, PORT_LABEL, ROLE_LABEL};
+use crate::image::Image;
+use crate::logger::Logger;
+
+/// Marks the container of the ruku proxy.
+pub const PROXY_LABEL: &str = "ruku.proxy";
+/// The `domains` of an app, comma separated, which the proxy routes to its container.
+pub const DOMAINS_LABEL: &str = "ruku.domains";
+pub const PROXY_CONTAINER: &str = "ruku-proxy";
+const PROXY_IMAGE: &str = "nginx:1.27-alpine";
+/// Certificates of the routes served over HTTPS, in the state root: `<domain>.crt` and `<domain>.key`
+/// named after the first domain of the app, a leading `*` written as `_`.
+const CERTS_DIR: &str = "proxy/certs";
+/// Where the routes go in the proxy container, in place of the default site of the image.
+const SITE_CONFIG: &str = "conf.d/default.conf";
+/// Starts the access log lines of the routes with a canary, `<upstreams>|<statuses>` as nginx lists them
+/// for a request.
+const UPSTREAM_LOG_PREFIX: &str = "ruku_upstream ";
+
+/// Where the proxy sends the requests for the domains of one app.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Route {
+    pub app: String,
+    pub domains: Vec<String>,
+    /// `address:port` of the published port of the app on the host, none when it publishes none.
+    pub upstream: Option<String>,
+    /// Whether a certificate was found for the app, it is then served on port 443 too.
+    pub tls: bool,
+    /// `address:port` of the canary of the app and the percentage of the requests it gets, while a
+    /// canary rollout runs.
+    pub canary: Option<(String, u8)>,
+}
+
+/// The routes to the running app containers among `summaries` with domains. Sidecars get no route of
+/// their own, a canary shares the route of its app with the weight of its rollout in `state_root`.
+pub fn routes(summaries: &[ContainerSummary], state_root: &Path) -> Vec<Route> {
+    let running: Vec<&ContainerSummary> = summaries
+        .iter()
+        .filter(|summary| summary.state.as_deref() == Some("running"))
+        .collect();
+    let canaries: HashMap<&str, String> = running
+        .iter()
+        .filter_map(|summary| {
+            let labels = summary.labels.as_ref()?;
+            if labels.get(ROLE_LABEL).map(String::as_str) != Some("canary") {
+                return None;
+            }
+            Some((labels.get(APP_LABEL)?.as_str(), upstream(summary)?))
+        })
+        .collect();
+    let mut routes: Vec<Route> = running
+        .iter()
+        .filter_map(|summary| {
+            let labels = summary.labels.as_ref()?;
+            let domains: Vec<String> = labels.get(DOMAINS_LABEL)?.split(',').map(str::to_string).collect();
+            if labels.get(ROLE_LABEL).is_some_and(|role| role != "stable") {
+                return None;
+            }
+            let app = labels.get(APP_LABEL)?.clone();
+            let canary = canaries
+                .get(app.as_str())
+                .zip(CanaryState::read(&state_root.join(&app)))
+                .map(|(upstream, state)| (upstream.clone(), state.weight));
+            Some(Route {
+                tls: has_certificate(state_root, &domains[0]),
+                domains,
+                upstream: upstream(summary),
+                canary,
+                app,
+            })
+        })
+        .collect();
+    routes.sort_by(|a, b| a.app.cmp(&b.app));
+    routes
+}
+
+/// The domains more than one of `routes` claims, the proxy sends them to the first.
+pub fn claimed_twice(routes: &[Route]) -> Vec<String> {
+    let mut claimed: HashMap<&str, usize> = HashMap::new();
+    for domain in routes.iter().flat_map(|route| &route.domains) {
+        *claimed.entry(domain).or_default() += 1;
+    }
+    let mut twice: Vec<String> = claimed
+        .into_iter()
+        .filter(|(_, count)| *count > 1)
+        .map(|(domain, _)| domain.to_string())
+        .collect();
+    twice.sort();
+    twice
+}
+
+/// The nginx config for `routes`: a server for each app, and one answering 404 for any other host. The
+/// requests of an app with a canary are split between the two by weight.
+pub fn render(routes: &[Route]) -> String {
+    let mut config = String::from(
+        "# Written by ruku from the domains of the running apps, changes are overwritten\n\
+         map $http_upgrade $connection_upgrade {\n    default upgrade;\n    '' close;\n}\n\n\
+         log_format ruku_upstream 'ruku_upstream $upstream_addr|$upstream_status';\n\n\
+         server {\n    listen 80 default_server;\n    server_name _;\n    return 404;\n}\n",
+    );
+    for route in routes {
+        let Some(stable) = &route.upstream else {
+            continue;
+        };
+        config.push_str(&format!("\n# {}\n", route.app));
+        let upstream = match &route.canary {
+            // A promotion sends the canary everything while the stable container is replaced
+            Some((canary, weight)) if *weight >= 100 => canary.clone(),
+            Some((canary, weight)) => {
+                let name = format!("ruku-{}", route.app);
+                // nginx takes no weight of 0
+                let weight = (*weight).max(1);
+                config.push_str(&format!(
+                    "upstream {} {{\n    server {} weight={};\n    server {} weight={};\n}}\n\n",
+                    name,
+                    stable,
+                    100 - weight,
+                    canary,
+                    weight
+                ));
+                name
+            }
+            None => stable.clone(),
+        };
+        config.push_str("server {\n    listen 80;\n");
+        if route.tls {
+            let name = cert_name(&route.domains[0]);
+            config.push_str(&format!(
+                "    listen 443 ssl;\n    ssl_certificate /etc/nginx/certs/{0}.crt;\n    \
+                 ssl_certificate_key /etc/nginx/certs/{0}.key;\n",
+                name
+            ));
+        }
+        // The error rate of the canary is counted from these lines
+        if route.canary.is_some() {
+            config.push_str(
+                "    access_log /var/log/nginx/access.log main;\n    \
+                 access_log /var/log/nginx/access.log ruku_upstream;\n",
+            );
+        }
+        config.push_str(&format!(
+            "    server_name {};\n    client_max_body_size 0;\n    location / {{\n        \
+             proxy_pass http://{};\n        proxy_http_version 1.1;\n        \
+             proxy_set_header Host $host;\n        proxy_set_header X-Real-IP $remote_addr;\n        \
+             proxy_set_header X-Forwarded-For $proxy_add_x_forwarded_for;\n        \
+             proxy_set_header X-Forwarded-Proto $scheme;\n        \
+             proxy_set_header Upgrade $http_upgrade;\n        \
+             proxy_set_header Connection $connection_upgrade;\n    }}\n}}\n",
+            route.domains.join(" "),
+            upstream
+        ));
+    }
+    config
+}
+
+/// How many of the requests in the proxy `output` went to `upstream` and how many of those it answered
+/// with a 5xx, including the ones nginx failed to pass on. Only the routes with a canary log them.
+pub fn upstream_errors(output: &str, upstream: &str) -> (usize, usize) {
+    let mut requests = 0;
+    let mut errors = 0;
+    for line in output.lines() {
+        let Some((addresses, statuses)) = line
+            .split_once(UPSTREAM_LOG_PREFIX)
+            .and_then(|(_, logged)| logged.trim().split_once('|'))
+        else {
+            continue;
+        };
+        // A request nginx retried on another server lists every try
+        for (address, status) in addresses.split(", ").zip(statuses.split(", ")) {
+            if address == upstream {
+                requests += 1;
+                if status.parse::<u16>().is_ok_and(|status| status >= 500) {
+                    errors += 1;
+                }
+            }
+        }
+    }
+    (requests, errors)
+}
+
+/// The host port of the app in `summary`, the one ruku published for it or else the first TCP port, on
+/// the address it is bound to. The proxy shares the network of the host, so loopback works.
+fn upstream(summary: &ContainerSummary) -> Option<String> {
+    let ports: Vec<_> = summary
+        .ports
+        .iter()
+        .flatten()
+        .filter(|port| port.public_port.is_some() && port.typ != Some(PortTypeEnum::UDP))
+        .collect();
+    let labeled: Option<u16> = summary
+        .labels
+        .as_ref()
+        .and_then(|labels| labels.get(PORT_LABEL))
+        .and_then(|port| port.parse().ok());
+    let port = ports
+        .iter()
+        .find(|port| port.public_port == labeled)
+        .or(ports.first())?;
+    let address = match port.ip.as_deref().and_then(|ip| ip.parse::<IpAddr>().ok()) {
+        Some(ip) if ip.is_unspecified() => "127.0.0.1".to_string(),
+        Some(IpAddr::V6(ip)) => format!("[{}]", ip),
+        Some(ip) => ip.to_string(),
+        None => "127.0.0.1".to_string(),
+    };
+    Some(format!("{}:{}", address, port.public_port?))
+}
+
+fn cert_name(domain: &str) -> String {
+    domain.replacen('*', "_", 1)
+}
+
+/// Whether the proxy serves the app whose first domain is `domain` over HTTPS.
+pub fn has_certificate(state_root: &Path, domain: &str) -> bool {
+    cert_paths(&state_root.join(CERTS_DIR), domain)
+        .iter()
+        .all(|path| path.exists())
+}
+
+fn cert_paths(certs_dir: &Path, domain: &str) -> [PathBuf; 2] {
+    let name = cert_name(domain);
+    [
+        certs_dir.join(format!("{}.crt", name)),
+        certs_dir.join(format!("{}.key", name)),
+    ]
+}
+
+/// The proxy ruku runs on the host to route port 80, and 443 for apps with a certificate, to apps by
+/// hostname. It shares the network of the host and is configured from the labels of the app containers.
+pub struct Proxy<'a> {
+    log: &'a Logger,
+    docker: &'a Docker,
+    state_root: PathBuf,
+}
+
+impl<'a> Proxy<'a> {
+    pub fn new(log: &'a Logger, docker: &'a Docker, state_root: &Path) -> Proxy<'a> {
+        Proxy {
+            log,
+            docker,
+            state_root: state_root.to_path_buf(),
+        }
+    }
+
+    /// The proxy container, none when it is not created.
+    pub async fn get(&self) -> Option<ContainerSummary> {
+        let options = ListContainersOptions {
+            all: true,
+            filters: HashMap::from([("label", vec![PROXY_LABEL])]),
+            ..Default::default()
+        };
+        self.docker
+            .list_containers(Some(options))
+            .await
+            .ok()
+            .and_then(|containers| containers.into_iter().next())
+    }
+
+    pub async fn is_running(&self) -> bool {
+        self.get().await.and_then(|summary| summary.state).as_deref() == Some("running")
+    }
+
+    /// Start the proxy with the routes of the running apps, creating it first when needed.
+    pub async fn up(&self) -> Result<(), String> {
+        let routes = self.current_routes().await?;
+        match self.get().await {
+            Some(summary) if summary.state.as_deref() == Some("running") => {
+                self.log.step("The proxy is running already");
+                self.refresh().await;
+                return Ok(());
+            }
+            Some(_) => {}
+            None => {
+                if self.docker.inspect_container(PROXY_CONTAINER, None).await.is_ok() {
+                    return Err(format!(
+                        "A container named {} exists that ruku did not create, remove it first",
+                        PROXY_CONTAINER
+                    ));
+                }
+                self.create().await?;
+            }
+        }
+        // Installed before the start, so nginx comes up with the routes
+        if let Err(e) = self.install(&routes).await {
+            return Err(format!("Failed to configure the proxy: {}", e));
+        }
+        self.docker
+            .start_container(PROXY_CONTAINER, None::<StartContainerOptions<String>>)
+            .await
+            .map_err(|e| format!("Failed to start the proxy: {}", e))?;
+        // nginx exits right away when it can't listen
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        if !self.is_running().await {
+            return Err(format!(
+                "The proxy stopped right after starting, port 80 or 443 may be taken, see `docker logs {}`",
+                PROXY_CONTAINER
+            ));
+        }
+        let routed = routes.iter().filter(|route| route.upstream.is_some()).count();
+        self.log.step(&format!(
+            "The proxy is listening on port 80 of the host and routing {} apps",
+            routed
+        ));
+        Ok(())
+    }
+
+    /// Remove the proxy container, the apps stay reachable on their own ports.
+    pub async fn down(&self) -> Result<(), String> {
+        let options = RemoveContainerOptions {
+            force: true,
+            ..Default::default()
+        };
+        match self.docker.remove_container(PROXY_CONTAINER, Some(options)).await {
+            Ok(_) => self
+                .log
+                .step(&format!("Removed the proxy container {}", PROXY_CONTAINER)),
+            Err(Error::DockerResponseServerError { status_code: 404, .. }) => self.log.step("The proxy is not running"),
+            Err(e) => return Err(format!("Failed to remove the proxy: {}", e)),
+        }
+        Ok(())
+    }
+
+    /// Print whether the proxy is running and where it routes each domain.
+    pub async fn status(&self) -> Result<(), String> {
+        match self.get().await {
+            Some(summary) => self.log.step(&format!(
+                "Proxy {}: {}",
+                PROXY_CONTAINER,
+                summary.status.unwrap_or_default()
+            )),
+            None => self.log.step("The proxy is not running, start it with `ruku proxy:up`"),
+        }
+        let routes = self.current_routes().await?;
+        if routes.is_empty() {
+            self.log.step("No running app has domains");
+            return Ok(());
+        }
+        let width = routes
+            .iter()
+            .flat_map(|route| &route.domains)
+            .map(String::len)
+            .max()
+            .unwrap_or(0)
+            .max(6);
+        println!(
+            "{:<width$}  {:<24} {:<22} TLS",
+            "DOMAIN",
+            "APP",
+            "UPSTREAM",
+            width = width
+        );
+        for route in &routes {
+            for domain in &route.domains {
+                println!(
+                    "{:<width$}  {:<24} {:<22} {}",
+                    domain,
+                    route.app,
+                    route.upstream.as_deref().unwrap_or("-"),
+                    if route.tls { "yes" } else { "no" },
+                    width = width
+                );
+            }
+        }
+        for route in &routes {
+            if let Some((canary, weight)) = &route.canary {
+                self.log.step(&format!(
+                    "{}% of the requests to {} go to its canary at {}",
+                    weight, route.app, canary
+                ));
+            }
+        }
+        for route in routes.iter().filter(|route| route.upstream.is_none()) {
+            self.log.warn(&format!(
+                "{} publishes no port, the proxy has no route to it",
+                route.app
+            ));
+        }
+        for domain in claimed_twice(&routes) {
+            self.log.warn(&format!(
+                "{} is in the domains of several apps, the first one gets it",
+                domain
+            ));
+        }
+        Ok(())
+    }
+
+    /// Regenerate the routes from the running apps and have the proxy reload them. Nothing to do when
+    /// the proxy is not running, a failure is only a warning as the apps themselves are fine.
+    pub async fn refresh(&self) {
+        self.reload(None).await;
+    }
+
+    /// Take the route of `app` out of the proxy while its container still runs, so it gets no new
+    /// requests and the ones it is serving can finish. Whether it had a route at all is returned.
+    pub async fn withdraw(&self, app: &str) -> bool {
+        self.reload(Some(app)).await
+    }
+
+    /// Install the routes of the running apps but `except` and have the proxy reload them, whether the
+    /// route of `except` was among them.
+    async fn reload(&self, except: Option<&str>) -> bool {
+        if !self.is_running().await {
+            return false;
+        }
+        let mut routes = match self.current_routes().await {
+            Ok(routes) => routes,
+            Err(e) => {
+                self.log.warn(&format!("Failed to reload the proxy: {}", e));
+                return false;
+            }
+        };
+        let before = routes.len();
+        routes.retain(|route| Some(route.app.as_str()) != except);
+        let withdrawn = routes.len() != before;
+        let reloaded = match self.install(&routes).await {
+            Ok(()) => {
+                let options = KillContainerOptions { signal: "HUP" };
+                self.docker.kill_container(PROXY_CONTAINER, Some(options)).await
+            }
+            Err(e) => Err(e),
+        };
+        match reloaded {
+            Ok(()) => {
+                let domains: usize = routes
+                    .iter()
+                    .filter(|route| route.upstream.is_some())
+                    .map(|route| route.domains.len())
+                    .sum();
+                self.log.step(&format!("Reloaded the proxy with {} domains", domains));
+                withdrawn
+            }
+            Err(e) => {
+                self.log.warn(&format!("Failed to reload the proxy: {}", e));
+                false
+            }
+        }
+    }
+
+    async fn current_routes(&self) -> Result<Vec<Route>, String> {
+        Ok(routes(&Container::list_all(self.docker).await?, &self.state_root))
+    }
+
+    /// How many requests the proxy sent to the canary of `app` since the unix time `since` and how many
+    /// of them failed, none when it routes no canary of the app.
+    pub async fn canary_errors(&self, app: &str, since: i64) -> Result<Option<(usize, usize)>, String> {
+        if !self.is_running().await {
+            return Ok(None);
+        }
+        let routes = self.current_routes().await?;
+        let Some((upstream, _)) = routes
+            .into_iter()
+            .find(|route| route.app == app)
+            .and_then(|route| route.canary)
+        else {
+            return Ok(None);
+        };
+        let options = LogsOptions::<String> {
+            stdout: true,
+            since,
+            ..Default::default()
+        };
+        let mut output = String::new();
+        let mut logs = self.docker.logs(PROXY_CONTAINER, Some(options));
+        while let Some(chunk) = logs.next().await {
+            let chunk = chunk.map_err(|e| format!("Failed to read the proxy logs: {}", e))?;
+            output.push_str(&String::from_utf8_lossy(&chunk.into_bytes()));
+        }
+        Ok(Some(upstream_errors(&output, &upstream)))
+    }
+
+    async fn create(&self) -> Result<(), String> {
+        let image = Image::new(self.log, self.docker);
+        if !image.exists(PROXY_IMAGE).await {
+            image.pull(PROXY_IMAGE).await?;
+        }
+        let config = Config {
+            image: Some(PROXY_IMAGE.to_string()),
+            labels: Some(HashMap::from([(PROXY_LABEL.to_string(), "true".to_string())])),
+            host_config: Some(HostConfig {
+                // Reaches the apps on their published ports, also the ones bound to loopback
+                network_mode: Some("host".to_string()),
+                restart_policy: Some(RestartPolicy {
+                    name: Some(RestartPolicyNameEnum::UNLESS_STOPPED),
+                    maximum_retry_count: None,
+                }),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let options = CreateContainerOptions {
+            name: PROXY_CONTAINER,
+            platform: None,
+        };
+        self.docker
+            .create_container(Some(options), config)
+            .await
+            .map_err(|e| format!("Failed to create the proxy: {}", e))?;
+        self.log
+            .step(&format!("Created the proxy container {}", PROXY_CONTAINER));
+        Ok(())
+    }
+
+    /// Copy the config for `routes` and their certificates into the proxy container.
+    async fn install(&self, routes: &[Route]) -> Result<(), Error> {
+        let mut files = vec![(SITE_CONFIG.to_string(), render(routes).into_bytes(), 0o644)];
+        for route in routes.iter().filter(|route| route.tls) {
+            let [cert, key] = cert_paths(&self.state_root.join(CERTS_DIR), &route.domains[0]);
+            let name = cert_name(&route.domains[0]);
+            files.push((format!("certs/{}.crt", name), fs::read(cert)?, 0o644));
+            files.push((format!("certs/{}.key", name), fs::read(key)?, 0o600));
+        }
+        let mut archive = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Directory);
+        header.set_mode(0o755);
+        header.set_size(0);
+        archive.append_data(&mut header, "certs", std::io::empty())?;
+        for (path, content, mode) in &files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(*mode);
+            archive.append_data(&mut header, path, content.as_slice())?;
+        }
+        let archive = archive.into_inner()?;
+        let options = UploadToContainerOptions {
+            path: "/etc/nginx",
+            ..Default::default()
+        };
+        self.docker
+            .upload_to_container(PROXY_CONTAINER, Some(options), archive.into())
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bollard::models::Port;
+
+    use super::*;
+
+    fn summary(app: &str, role: &str, port: u16) -> ContainerSummary {
+        ContainerSummary {
+            state: Some("running".to_string()),
+            labels: Some(HashMap::from([
+                (APP_LABEL.to_string(), app.to_string()),
+                (ROLE_LABEL.to_string(), role.to_string()),
+                (DOMAINS_LABEL.to_string(), "shop.example.com".to_string()),
+            ])),
+            ports: Some(vec![Port {
+                ip: Some("0.0.0.0".to_string()),
+                private_port: 8080,
+                public_port: Some(port),
+                typ: Some(PortTypeEnum::TCP),
+            }]),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn a_canary_shares_the_route_of_its_app_by_weight() {
+        let state_root = tempfile::tempdir().unwrap();
+        let summaries = [summary("shop", "stable", 8000), summary("shop", "canary", 8001)];
+        assert_eq!(routes(&summaries, state_root.path())[0].canary, None);
+
+        fs::create_dir(state_root.path().join("shop")).unwrap();
+        let state = r#"{"container": "shop-canary", "weight": 10, "started_at": "2026-10-14T12:00:00Z"}"#;
+        fs::write(state_root.path().join("shop").join("canary.json"), state).unwrap();
+        let routes = routes(&summaries, state_root.path());
+        assert_eq!(routes.len(), 1);
+        assert_eq!(routes[0].upstream.as_deref(), Some("127.0.0.1:8000"));
+        assert_eq!(routes[0].canary, Some(("127.0.0.1:8001".to_string(), 10)));
+
+        let config = render(&routes);
+        assert!(config.contains(
+            "upstream ruku-shop {\n    server 127.0.0.1:8000 weight=90;\n    server 127.0.0.1:8001 weight=10;\n}\n"
+        ));
+        assert!(config.contains("proxy_pass http://ruku-shop;"));
+        assert!(config.contains("access_log /var/log/nginx/access.log ruku_upstream;"));
+
+        let promoting = state.replace("\"weight\": 10", "\"weight\": 100");
+        fs::write(state_root.path().join("shop").join("canary.json"), promoting).unwrap();
+        let config = render(&super::routes(&summaries, state_root.path()));
+        assert!(!config.contains("upstream ruku-shop"));
+        assert!(config.contains("proxy_pass http://127.0.0.1:8001;"));
+    }
+
+    #[test]
+    fn failed_requests_are_counted_per_upstream() {
+        let output = "\
+            ruku_upstream 127.0.0.1:8001|200\n\
+            ruku_upstream 127.0.0.1:8000|500\n\
+            ruku_upstream 127.0.0.1:8001|503\n\
+            ruku_upstream 127.0.0.1:8001, 127.0.0.1:8000|502, 200\n\
+            10.0.0.1 - - [14/Oct/2026:12:00:00 +0000] \"GET / HTTP/1.1\" 500 0\n";
+        assert_eq!(upstream_errors(output, "127.0.0.1:8001"), (3, 2));
+        assert_eq!(upstream_errors(output, "127.0.0.1:8000"), (2, 1));
+        assert_eq!(upstream_errors("", "127.0.0.1:8001"), (0, 0));
+    }
+}
@@ -0,0 +1,32 @@
+use std::env;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::error::RukuError;
+
+/// Environment variable that keeps ruku from changing anything, for accounts that only look.
+pub const READ_ONLY_ENV: &str = "RUKU_READ_ONLY";
+/// What the CLI exits with when read-only mode refuses an operation, `EX_NOPERM` of sysexits.h, so
+/// scripts can tell a refusal from a failure.
+pub const READ_ONLY_EXIT_CODE: i32 = 77;
+
+/// Set by `read_only` in `~/.ruku/config.yml` or the context in use.
+static READ_ONLY: AtomicBool = AtomicBool::new(false);
+
+/// Refuse every operation that changes Docker or ruku state from here on.
+pub fn set_read_only() {
+    READ_ONLY.store(true, Ordering::Relaxed);
+}
+
+/// Whether operations that change state are refused, by the config or `RUKU_READ_ONLY`.
+pub fn is_read_only() -> bool {
+    READ_ONLY.load(Ordering::Relaxed)
+        || env::var(READ_ONLY_ENV).is_ok_and(|value| !matches!(value.trim(), "" | "0" | "false"))
+}
+
+/// Fail with [`RukuError::ReadOnly`] before `operation` changes anything in read-only mode.
+pub fn guard(operation: &str) -> Result<(), RukuError> {
+    if is_read_only() {
+        return Err(RukuError::ReadOnly(operation.to_string()));
+    }
+    Ok(())
+}
@@ -0,0 +1,39 @@
+use crate::container::Container;
+use crate::smoke::SmokeResult;
+use crate::strategy::Strategy;
+
+/// Stops the running container and starts the new version in its place. The pipeline gates on the
+/// health of the new container afterwards, there is nothing left to roll back to by then. A rollback
+/// removes what is there of the new container, a deploy that ran out of time may have left it half set up.
+pub struct Recreate<'a> {
+    container: &'a Container<'a>,
+}
+
+impl<'a> Recreate<'a> {
+    pub fn new(container: &'a Container<'a>) -> Recreate<'a> {
+        Recreate { container }
+    }
+}
+
+impl Strategy for Recreate<'_> {
+    fn plan(&self) -> Vec<String> {
+        vec![format!(
+            "Replacing {} with the new version in place",
+            self.container.container_name()
+        )]
+    }
+
+    async fn execute(&self) -> Result<Vec<SmokeResult>, String> {
+        self.container.run().await?;
+        Ok(vec![])
+    }
+
+    async fn rollback(&self) -> Result<(), String> {
+        self.container.discard().await
+    }
+
+    /// The new container stays in place, its logs are kept all the same.
+    async fn failed(&self) -> Vec<String> {
+        vec![self.container.container_name().to_string()]
+    }
+}
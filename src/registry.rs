@@ -0,0 +1,182 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io::Write;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use bollard::auth::DockerCredentials;
+use cmd_lib::run_fun;
+use serde::Deserialize;
+
+/// The subset of `~/.docker/config.json` ruku reads credentials from.
+#[derive(Deserialize)]
+struct DockerConfig {
+    #[serde(default)]
+    auths: HashMap<String, DockerConfigAuth>,
+}
+
+#[derive(Deserialize)]
+struct DockerConfigAuth {
+    auth: Option<String>,
+}
+
+/// The registry host of an image reference or namespace, e.g. `registry.example.com` for
+/// `registry.example.com/team/app`.
+pub fn get_registry_host(registry: &str) -> &str {
+    registry.split('/').next().unwrap_or(registry)
+}
+
+/// The response of the registry's tag list endpoint.
+#[derive(Deserialize)]
+struct TagList {
+    tags: Option<Vec<String>>,
+}
+
+/// The registry host and repository path of an image repository, none for a Docker Hub name such as
+/// `team/app` whose first part is not a host.
+pub fn split_registry(repository: &str) -> Option<(&str, &str)> {
+    let (host, path) = repository.split_once('/')?;
+    let is_host = host.contains('.') || host.contains(':') || host == "localhost";
+    is_host.then_some((host, path))
+}
+
+/// Media types of the manifests a tag may point at, a single image or a multi-platform index.
+const MANIFEST_TYPES: &str = "application/vnd.oci.image.index.v1+json, \
+                              application/vnd.docker.distribution.manifest.list.v2+json, \
+                              application/vnd.oci.image.manifest.v1+json, \
+                              application/vnd.docker.distribution.manifest.v2+json";
+
+/// What the registry answered a manifest request for a tag with.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ManifestHead {
+    /// The tag exists, with the digest of its manifest when the registry sent it.
+    Found(Option<String>),
+    Missing,
+    /// The registry wants fewer requests, for the seconds of its `Retry-After` if it sent one.
+    RateLimited(Option<u64>),
+}
+
+/// The status and headers of a `curl --head` response, the last one when it followed redirects.
+pub fn parse_manifest_head(response: &str) -> Result<ManifestHead, String> {
+    let response = response
+        .rsplit("\r\n\r\n")
+        .find(|part| part.starts_with("HTTP/"))
+        .unwrap_or(response);
+    let mut lines = response.lines();
+    let status: u16 = lines
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|status| status.parse().ok())
+        .ok_or("the registry sent no HTTP response")?;
+    let header = |name: &str| {
+        response.lines().find_map(|line| {
+            let (key, value) = line.split_once(':')?;
+            key.trim().eq_ignore_ascii_case(name).then(|| value.trim().to_string())
+        })
+    };
+    match status {
+        200 => Ok(ManifestHead::Found(header("Docker-Content-Digest"))),
+        404 => Ok(ManifestHead::Missing),
+        429 => Ok(ManifestHead::RateLimited(
+            header("Retry-After").and_then(|after| after.parse().ok()),
+        )),
+        401 | 403 => Err(format!(
+            "the registry refused the credentials ruku has with {}, set RUKU_REGISTRY_USERNAME and \
+             RUKU_REGISTRY_PASSWORD or log in with docker login",
+            status
+        )),
+        status => Err(format!("the registry answered with {}", status)),
+    }
+}
+
+/// Ask the registry for the manifest of `image`, a `host/path:tag` reference, without downloading it.
+/// Authenticated like [`list_tags`], with the same lack of token support.
+pub fn head_manifest(image: &str) -> Result<ManifestHead, String> {
+    let (repository, tag) = image.rsplit_once(':').ok_or("the image has no tag")?;
+    let (host, path) = split_registry(repository).ok_or("not a registry repository")?;
+    let url = format!("https://{}/v2/{}/manifests/{}", host, path, tag);
+    let config = curl_config(host)?;
+    let config_path = config.path();
+    let accept = format!("Accept: {}", MANIFEST_TYPES);
+    let output = run_fun!(curl --silent --head --location --max-time 10 --config $config_path --header $accept $url)
+        .map_err(|e| format!("the registry can't be reached ({})", e))?;
+    parse_manifest_head(&output)
+}
+
+/// A curl config file with the credentials for `host`, so they don't show up in the process list.
+fn curl_config(host: &str) -> Result<tempfile::NamedTempFile, String> {
+    let mut config = tempfile::NamedTempFile::new().map_err(|e| e.to_string())?;
+    if let Some((Some(username), Some(password))) = get_credentials(host).map(|c| (c.username, c.password)) {
+        let escape = |value: String| value.replace('\\', "\\\\").replace('"', "\\\"");
+        writeln!(config, "user = \"{}:{}\"", escape(username), escape(password)).map_err(|e| e.to_string())?;
+    }
+    Ok(config)
+}
+
+/// The tags of a repository from the registry's tag list, authenticated with the credentials ruku has.
+/// Registries that hand out tokens, such as Docker Hub, are not supported.
+pub fn list_tags(repository: &str) -> Result<Vec<String>, String> {
+    let (host, path) = split_registry(repository).ok_or("not a registry repository")?;
+    let url = format!("https://{}/v2/{}/tags/list", host, path);
+    let config = curl_config(host)?;
+    let config_path = config.path();
+    let output = run_fun!(curl --silent --fail --max-time 10 --config $config_path $url).map_err(|e| e.to_string())?;
+    let list: TagList = serde_json::from_str(&output).map_err(|e| e.to_string())?;
+    Ok(list.tags.unwrap_or_default())
+}
+
+/// Credentials for a registry, from `RUKU_REGISTRY_USERNAME`/`RUKU_REGISTRY_PASSWORD` or else the docker CLI config.
+pub fn get_credentials(registry: &str) -> Option<DockerCredentials> {
+    let host = get_registry_host(registry);
+
+    if let (Ok(username), Ok(password)) = (env::var("RUKU_REGISTRY_USERNAME"), env::var("RUKU_REGISTRY_PASSWORD")) {
+        return Some(DockerCredentials {
+            username: Some(username),
+            password: Some(password),
+            serveraddress: Some(host.to_string()),
+            ..Default::default()
+        });
+    }
+
+    let config_path = home::home_dir()?.join(".docker").join("config.json");
+    let config: DockerConfig = serde_json::from_str(&fs::read_to_string(config_path).ok()?).ok()?;
+    let auth = config
+        .auths
+        .iter()
+        .find(|(server, _)| {
+            server
+                .trim_start_matches("https://")
+                .trim_start_matches("http://")
+                .split('/')
+                .next()
+                == Some(host)
+        })
+        .and_then(|(_, auth)| auth.auth.as_deref())?;
+
+    let decoded = String::from_utf8(STANDARD.decode(auth).ok()?).ok()?;
+    let (username, password) = decoded.split_once(':')?;
+    Some(DockerCredentials {
+        username: Some(username.to_string()),
+        password: Some(password.to_string()),
+        serveraddress: Some(host.to_string()),
+        ..Default::default()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_a_host_counts_as_registry() {
+        assert_eq!(
+            split_registry("registry.example.com/team/app"),
+            Some(("registry.example.com", "team/app"))
+        );
+        assert_eq!(split_registry("localhost:5000/app"), Some(("localhost:5000", "app")));
+        assert_eq!(split_registry("localhost/app"), Some(("localhost", "app")));
+        assert_eq!(split_registry("team/app"), None);
+        assert_eq!(split_registry("app"), None);
+    }
+}
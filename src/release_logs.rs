@@ -0,0 +1,254 @@
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use bollard::container::{LogOutput, LogsOptions};
+use bollard::models::ContainerSummary;
+use bollard::Docker;
+use chrono::{DateTime, Utc};
+use flate2::read::GzDecoder;
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+
+use crate::failures::{write_gzip, CAPTURED_LOG_LINES};
+use crate::history::Deployment;
+use crate::logger::Logger;
+use crate::store::{self, Document};
+use crate::units::format_size;
+
+const LOG_SUFFIX: &str = ".log.gz";
+const INFO_SUFFIX: &str = ".json";
+
+/// Which release the output of a replaced container belongs to and what time it covers.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReleaseLog {
+    /// The deployment id of the release.
+    pub id: String,
+    pub container: String,
+    pub version: Option<String>,
+    /// The time of the first line kept, none when the container printed nothing.
+    pub from: Option<DateTime<Utc>>,
+    /// When the output was kept, right before the release was replaced.
+    pub until: DateTime<Utc>,
+    pub lines: usize,
+}
+
+impl Document for ReleaseLog {}
+
+impl ReleaseLog {
+    /// E.g. `release 20260101120000, version 1.2.0, 2026-01-01 12:00 to 2026-01-03 09:30 UTC`.
+    pub fn describe(&self) -> String {
+        let from = self
+            .from
+            .map(|from| from.format("%Y-%m-%d %H:%M").to_string())
+            .unwrap_or("-".to_string());
+        format!(
+            "release {}, version {}, {} to {} UTC",
+            self.id,
+            self.version.as_deref().unwrap_or("latest"),
+            from,
+            self.until.format("%Y-%m-%d %H:%M")
+        )
+    }
+}
+
+/// The output of the container of a release, kept when a deploy replaces it so `ruku logs --release` can
+/// still show it. Stored gzipped under `release-logs/<deployment id>` in the app state directory, a line
+/// per output line as `<stream> <timestamp> <message>`.
+pub struct ReleaseLogs<'a> {
+    log: &'a Logger,
+    dir: PathBuf,
+    timeout: Duration,
+    retention: usize,
+}
+
+impl<'a> ReleaseLogs<'a> {
+    pub const DIR_NAME: &'static str = "release-logs";
+    /// What `--release` takes for the newest release before the current one.
+    pub const PREVIOUS: &'static str = "previous";
+
+    pub fn new(log: &'a Logger, state_dir: &Path) -> ReleaseLogs<'a> {
+        ReleaseLogs {
+            log,
+            dir: state_dir.join(Self::DIR_NAME),
+            timeout: Duration::from_secs(30),
+            retention: 10,
+        }
+    }
+
+    /// Give up on the capture after `timeout`, the deploy waits for it.
+    pub fn with_timeout(mut self, timeout: Duration) -> ReleaseLogs<'a> {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Keep the output of the newest `retention` releases.
+    pub fn with_retention(mut self, retention: usize) -> ReleaseLogs<'a> {
+        self.retention = retention;
+        self
+    }
+
+    /// Keep the last lines of output of `container`, which runs `release`. Nothing here stops the deploy.
+    pub async fn capture(&self, docker: &Docker, container: &str, release: &Deployment) {
+        if self.retention == 0 {
+            return;
+        }
+        let captured = tokio::time::timeout(self.timeout, read_output(docker, container)).await;
+        let output = match captured {
+            Ok(Ok(output)) => output,
+            Ok(Err(e)) => {
+                self.log
+                    .warn(&format!("Could not keep the logs of release {}: {}", release.id, e));
+                return;
+            }
+            Err(_) => {
+                self.log.warn(&format!(
+                    "Gave up keeping the logs of release {} after {}s",
+                    release.id,
+                    self.timeout.as_secs()
+                ));
+                return;
+            }
+        };
+        let info = ReleaseLog {
+            id: release.id.clone(),
+            container: container.to_string(),
+            version: release.version.clone(),
+            from: output
+                .lines()
+                .next()
+                .and_then(|line| line.split(' ').nth(1))
+                .and_then(|timestamp| DateTime::parse_from_rfc3339(timestamp).ok())
+                .map(|from| from.to_utc()),
+            until: Utc::now(),
+            lines: output.lines().count(),
+        };
+        let saved = fs::create_dir_all(&self.dir)
+            .map_err(|e| e.to_string())
+            .and_then(|_| write_gzip(&self.log_path(&release.id), output.as_bytes()))
+            .and_then(|_| store::save(&self.info_path(&release.id), &info).map_err(|e| e.to_string()));
+        match saved {
+            Ok(()) => self
+                .log
+                .step(&format!("Kept {} lines of logs of release {}", info.lines, release.id)),
+            Err(e) => self
+                .log
+                .warn(&format!("Could not keep the logs of release {}: {}", release.id, e)),
+        }
+        self.prune();
+    }
+
+    /// Ids of the releases with kept logs, oldest first.
+    pub fn ids(&self) -> Vec<String> {
+        let mut ids: Vec<String> = fs::read_dir(&self.dir)
+            .into_iter()
+            .flatten()
+            .flatten()
+            .filter_map(|entry| {
+                let name = entry.file_name().to_string_lossy().to_string();
+                name.strip_suffix(LOG_SUFFIX).map(str::to_string)
+            })
+            .collect();
+        // Ids are timestamps, so name order is age order
+        ids.sort();
+        ids
+    }
+
+    /// The release `release` names, `previous` for the newest one with kept logs.
+    pub fn resolve(&self, release: &str) -> Result<String, String> {
+        if release != Self::PREVIOUS {
+            return Ok(release.to_string());
+        }
+        self.ids()
+            .pop()
+            .ok_or("No logs of earlier releases kept yet, they are kept when a deploy replaces a release".to_string())
+    }
+
+    /// What the kept logs of release `id` cover, none when there are none.
+    pub fn info(&self, id: &str) -> Result<Option<ReleaseLog>, String> {
+        // Ids come from the command line, anything but a plain name can't be one
+        if id.is_empty() || !id.chars().all(|c| c.is_ascii_alphanumeric()) || !self.log_path(id).exists() {
+            return Ok(None);
+        }
+        store::load(self.log, &self.info_path(id))
+    }
+
+    /// The compressed size of the kept logs of release `id`, e.g. `12.4 KB`.
+    pub fn size(&self, id: &str) -> Option<String> {
+        fs::metadata(self.log_path(id))
+            .ok()
+            .map(|metadata| format_size(metadata.len()))
+    }
+
+    /// The kept lines of release `id` as stream and the line with its timestamp.
+    pub fn read(&self, id: &str) -> Result<Vec<(String, String)>, String> {
+        let path = self.log_path(id);
+        let mut content = String::new();
+        File::open(&path)
+            .and_then(|file| GzDecoder::new(file).read_to_string(&mut content))
+            .map_err(|e| format!("Error reading {}: {}", path.display(), e))?;
+        Ok(content
+            .lines()
+            .map(|line| match line.split_once(' ') {
+                Some((stream, line)) => (stream.to_string(), line.to_string()),
+                None => ("stdout".to_string(), line.to_string()),
+            })
+            .collect())
+    }
+
+    fn log_path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}{}", id, LOG_SUFFIX))
+    }
+
+    fn info_path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}{}", id, INFO_SUFFIX))
+    }
+
+    fn prune(&self) {
+        let ids = self.ids();
+        for id in ids.iter().take(ids.len().saturating_sub(self.retention)) {
+            let _ = fs::remove_file(self.log_path(id));
+            let _ = fs::remove_file(self.info_path(id));
+        }
+    }
+}
+
+/// The release of `deployments` the container of `summary` runs, the newest one with its image, or the
+/// newest one when it has no image id recorded.
+pub fn release_of(deployments: &[Deployment], summary: &ContainerSummary) -> Option<Deployment> {
+    deployments
+        .iter()
+        .rev()
+        .find(|deployment| deployment.image_id.is_some() && deployment.image_id == summary.image_id)
+        .or_else(|| deployments.last().filter(|deployment| deployment.image_id.is_none()))
+        .cloned()
+}
+
+/// The last lines of output of `container` as `<stream> <timestamp> <message>` lines.
+async fn read_output(docker: &Docker, container: &str) -> Result<String, String> {
+    let options = LogsOptions::<String> {
+        stdout: true,
+        stderr: true,
+        timestamps: true,
+        tail: CAPTURED_LOG_LINES.to_string(),
+        ..Default::default()
+    };
+    let mut output = String::new();
+    let mut stream = docker.logs(container, Some(options));
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.map_err(|e| e.to_string())?;
+        let stream_tag = match &chunk {
+            LogOutput::StdErr { .. } => "stderr",
+            _ => "stdout",
+        };
+        // With timestamps every frame starts a line, a tty container has the line endings of the terminal
+        for line in String::from_utf8_lossy(chunk.as_ref()).replace("\r\n", "\n").lines() {
+            output.push_str(stream_tag);
+            output.push(' ');
+            output.push_str(line);
+            output.push('\n');
+        }
+    }
+    Ok(output)
+}
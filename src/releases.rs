@@ -0,0 +1,325 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use validator::Validate;
+
+use crate::diff::{self, is_secret_path, Change};
+use crate::model::RukuConfig;
+use crate::provenance::Provenance;
+use crate::store;
+use crate::templates::SECRET_MASK;
+
+/// Key names whose values are masked in snapshots.
+const SECRET_KEY_PARTS: [&str; 5] = ["password", "secret", "token", "credential", "api_key"];
+
+/// A config field as it was live for a deploy.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SnapshotField {
+    pub key: String,
+    pub value: String,
+    /// Where the value was set, a `path:line` or `default`.
+    pub source: String,
+    /// The value is masked, it is not kept anywhere.
+    #[serde(default)]
+    pub secret: bool,
+}
+
+/// The resolved config of one deploy, keyed by the deployment id.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub id: String,
+    pub app: String,
+    pub version: Option<String>,
+    pub created_at: DateTime<Utc>,
+    /// The note of the deploy.
+    #[serde(default)]
+    pub message: Option<String>,
+    pub fields: Vec<SnapshotField>,
+    /// The config as it was deployed without its secret values, what `rollback --with-config` restores.
+    /// Snapshots from before it was kept have none.
+    #[serde(default)]
+    pub settings: Option<Value>,
+}
+
+impl Snapshot {
+    pub fn new(id: &str, app: &str, config: &RukuConfig, provenance: &Provenance) -> Snapshot {
+        let fields = provenance
+            .explain(config)
+            .into_iter()
+            .map(|field| {
+                let secret = is_secret_key(&field.key);
+                let value = match serde_json::from_str(&field.value) {
+                    _ if secret => SECRET_MASK.to_string(),
+                    // A list is one field with whole items, e.g. the sidecars with their env
+                    Ok(mut items @ Value::Array(_)) => {
+                        hide_secrets(&field.key, &mut items, &config.secrets, Some(SECRET_MASK));
+                        items.to_string()
+                    }
+                    _ => field.value,
+                };
+                SnapshotField {
+                    value,
+                    source: field.source.to_string(),
+                    key: field.key,
+                    secret,
+                }
+            })
+            .collect();
+        Snapshot {
+            id: id.to_string(),
+            app: app.to_string(),
+            version: config.version.clone(),
+            created_at: Utc::now(),
+            message: None,
+            fields,
+            settings: serde_json::to_value(config).ok().map(|mut settings| {
+                hide_secrets("", &mut settings, &config.secrets, None);
+                settings
+            }),
+        }
+    }
+
+    /// The config of this release, with the secret values it left out taken from `current`. Fails when
+    /// the settings no longer make a valid config.
+    pub fn restore(&self, current: &RukuConfig) -> Result<RukuConfig, String> {
+        let Some(mut settings) = self.settings.clone() else {
+            return Err(format!(
+                "The snapshot of release {} was taken before ruku kept the settings, roll back without \
+                 --with-config",
+                self.id
+            ));
+        };
+        let current_value = serde_json::to_value(current).map_err(|e| e.to_string())?;
+        // Secret then or now, an env entry that was a secret then is one still
+        let mut secrets: BTreeMap<String, String> =
+            serde_json::from_value(settings["secrets"].clone()).unwrap_or_default();
+        secrets.extend(current.secrets.clone());
+        fill_secrets("", &mut settings, &current_value, &secrets);
+        let config: RukuConfig = serde_json::from_value(settings)
+            .map_err(|e| format!("The settings of release {} no longer make a config: {}", self.id, e))?;
+        config
+            .validate()
+            .map_err(|e| format!("The settings of release {} are no longer valid: {}", self.id, e))?;
+        Ok(config)
+    }
+}
+
+fn child_path(prefix: &str, key: &str) -> String {
+    match prefix {
+        "" => key.to_string(),
+        prefix => format!("{}.{}", prefix, key),
+    }
+}
+
+/// Replace the secret values with `mask`, or remove them without one. List items like the sidecars
+/// share the path of their list.
+fn hide_secrets(prefix: &str, value: &mut Value, secrets: &BTreeMap<String, String>, mask: Option<&str>) {
+    match value {
+        Value::Object(object) => object.retain(|key, value| {
+            let path = child_path(prefix, key);
+            hide_secrets(&path, value, secrets, mask);
+            if value.is_object() || value.is_array() || !is_secret_path(&path, secrets) {
+                return true;
+            }
+            if let Some(mask) = mask {
+                *value = Value::String(mask.to_string());
+            }
+            mask.is_some()
+        }),
+        Value::Array(items) => items
+            .iter_mut()
+            .for_each(|item| hide_secrets(prefix, item, secrets, mask)),
+        _ => {}
+    }
+}
+
+/// Put the secret values of `current` back where [`hide_secrets`] took them out. List items go by their
+/// `name` when they have one, a sidecar gets the secrets of the sidecar of the same name.
+fn fill_secrets(prefix: &str, settings: &mut Value, current: &Value, secrets: &BTreeMap<String, String>) {
+    match (settings, current) {
+        (Value::Object(settings), Value::Object(current)) => {
+            for (key, value) in current {
+                let path = child_path(prefix, key);
+                match settings.get_mut(key) {
+                    Some(setting) if setting.is_object() || setting.is_array() => {
+                        fill_secrets(&path, setting, value, secrets)
+                    }
+                    None if !value.is_object() && !value.is_array() && is_secret_path(&path, secrets) => {
+                        settings.insert(key.clone(), value.clone());
+                    }
+                    _ => {}
+                }
+            }
+        }
+        (Value::Array(items), Value::Array(current)) => {
+            for (index, item) in items.iter_mut().enumerate() {
+                let matching = match item.get("name") {
+                    Some(name) => current.iter().find(|other| other.get("name") == Some(name)),
+                    None => current.get(index),
+                };
+                if let Some(matching) = matching {
+                    fill_secrets(prefix, item, matching, secrets);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Whether the last part of a dotted key names a credential, e.g. `build.registry_password`.
+pub fn is_secret_key(key: &str) -> bool {
+    let name = key.rsplit('.').next().unwrap_or(key).to_lowercase();
+    SECRET_KEY_PARTS.iter().any(|part| name.contains(part))
+}
+
+/// Every field whose value differs from `from` to `to`, in key order.
+pub fn diff(from: &Snapshot, to: &Snapshot) -> Vec<Change> {
+    let values = |snapshot: &Snapshot| -> BTreeMap<String, String> {
+        snapshot
+            .fields
+            .iter()
+            .map(|field| (field.key.clone(), field.value.clone()))
+            .collect()
+    };
+    diff::compare(&values(from), &values(to))
+}
+
+/// Config snapshots of the deploys of an app, one JSON file each in the app state directory.
+pub struct Releases {
+    dir: PathBuf,
+}
+
+impl Releases {
+    pub const DIR_NAME: &'static str = "releases";
+
+    pub fn new(state_dir: &Path) -> Releases {
+        Releases {
+            dir: state_dir.join(Self::DIR_NAME),
+        }
+    }
+
+    /// Store a snapshot and prune all but the newest `retention` ones.
+    pub fn save(&self, snapshot: &Snapshot, retention: usize) -> Result<(), String> {
+        fs::create_dir_all(&self.dir).map_err(|e| format!("Error creating directory: {}", e))?;
+        let content = serde_json::to_string_pretty(snapshot).unwrap();
+        store::write_atomic(&self.path(&snapshot.id), content.as_bytes())
+            .map_err(|e| format!("Error writing config snapshot: {}", e))?;
+
+        let ids = self.ids();
+        for id in ids.iter().take(ids.len().saturating_sub(retention)) {
+            let _ = fs::remove_file(self.path(id));
+        }
+        Ok(())
+    }
+
+    /// Ids of the stored snapshots, oldest first.
+    pub fn ids(&self) -> Vec<String> {
+        let mut ids: Vec<String> = fs::read_dir(&self.dir)
+            .into_iter()
+            .flatten()
+            .flatten()
+            .filter_map(|entry| {
+                let name = entry.file_name().to_string_lossy().to_string();
+                name.strip_suffix(".json").map(str::to_string)
+            })
+            .collect();
+        // Ids are timestamps, so name order is age order
+        ids.sort();
+        ids
+    }
+
+    /// The snapshot of deployment `id`, failing when there is none.
+    pub fn load(&self, id: &str) -> Result<Snapshot, String> {
+        // Ids come from the command line, anything but a plain name can't be one
+        let valid = !id.is_empty() && id.chars().all(|c| c.is_ascii_alphanumeric());
+        let Some(content) = valid.then(|| fs::read_to_string(self.path(id)).ok()).flatten() else {
+            let known = self.ids();
+            if known.is_empty() {
+                return Err("No config snapshots recorded yet".to_string());
+            }
+            return Err(format!(
+                "No config snapshot for deployment {}, known: {}",
+                id,
+                known.join(", ")
+            ));
+        };
+        serde_json::from_str(&content).map_err(|e| format!("Error parsing config snapshot {}: {}", id, e))
+    }
+
+    fn path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(yaml: &str) -> RukuConfig {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    const RELEASE: &str = "
+version: '1.0'
+labels:
+  team: web
+sidecars:
+  - name: db
+    image: postgres:15
+    env:
+      POSTGRES_DB: shop
+      POSTGRES_PASSWORD: then
+";
+
+    #[test]
+    fn settings_are_restored_with_the_secrets_of_now() {
+        let snapshot = Snapshot::new("1", "shop", &config(RELEASE), &Provenance::new());
+        assert!(!serde_json::to_string(&snapshot).unwrap().contains("then"));
+        let sidecars = snapshot.fields.iter().find(|field| field.key == "sidecars").unwrap();
+        assert!(sidecars
+            .value
+            .contains(&format!("\"POSTGRES_PASSWORD\":\"{}\"", SECRET_MASK)));
+
+        let current = config(
+            "
+version: '2.0'
+labels:
+  team: api
+  tier: front
+sidecars:
+  - name: cache
+    image: redis:7
+  - name: db
+    image: postgres:16
+    env:
+      POSTGRES_DB: shop
+      POSTGRES_PASSWORD: now
+",
+        );
+        let restored = snapshot.restore(&current).unwrap();
+        assert_eq!(restored.version.as_deref(), Some("1.0"));
+        assert_eq!(
+            restored.labels,
+            BTreeMap::from([("team".to_string(), "web".to_string())])
+        );
+        assert_eq!(restored.sidecars.len(), 1);
+        assert_eq!(restored.sidecars[0].image, "postgres:15");
+        assert_eq!(restored.sidecars[0].env["POSTGRES_PASSWORD"], "now");
+        assert_eq!(restored.sidecars[0].env["POSTGRES_DB"], "shop");
+    }
+
+    #[test]
+    fn snapshots_without_settings_cant_be_restored() {
+        let mut snapshot = Snapshot::new("1", "shop", &config(RELEASE), &Provenance::new());
+        snapshot.settings = None;
+        let old: Snapshot = serde_json::from_str(&serde_json::to_string(&snapshot).unwrap()).unwrap();
+        assert!(old
+            .restore(&config(RELEASE))
+            .unwrap_err()
+            .contains("without --with-config"));
+    }
+}
@@ -0,0 +1,213 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use bollard::container::KillContainerOptions;
+use bollard::Docker;
+use serde::{Deserialize, Serialize};
+
+use crate::container::{deployed_version, Container};
+use crate::image::Image;
+use crate::logger::Logger;
+use crate::misc::get_version;
+use crate::model::{DeployStrategy, RukuConfig};
+use crate::sha256::sha256_hex;
+use crate::sidecar::Sidecars;
+use crate::store;
+use crate::templates::{Rendered, Templates};
+
+/// Checksums of the rendered templates a container was started or last reloaded with, as written to
+/// `templates.json`. Docker can't change the labels of a container, so they are kept next to it instead
+/// and its config hash stays what it was created with.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TemplateChecksums {
+    pub container_id: String,
+    /// SHA-256 of the content, keyed by the path in the container.
+    pub checksums: BTreeMap<String, String>,
+}
+
+impl TemplateChecksums {
+    pub const FILE_NAME: &'static str = "templates.json";
+
+    pub fn new(container_id: &str, rendered: &[Rendered]) -> TemplateChecksums {
+        TemplateChecksums {
+            container_id: container_id.to_string(),
+            checksums: rendered
+                .iter()
+                .map(|file| (file.target.clone(), sha256_hex(file.content.as_bytes())))
+                .collect(),
+        }
+    }
+
+    pub fn read(state_dir: &Path) -> Option<TemplateChecksums> {
+        let content = fs::read_to_string(state_dir.join(Self::FILE_NAME)).ok()?;
+        serde_json::from_str(&content).ok()
+    }
+
+    pub fn write(&self, log: &Logger, state_dir: &Path) {
+        let written = serde_json::to_string_pretty(self)
+            .map_err(|e| e.to_string())
+            .and_then(|content| {
+                store::write_atomic(&state_dir.join(Self::FILE_NAME), content.as_bytes()).map_err(|e| e.to_string())
+            });
+        if let Err(e) = written {
+            log.warn(&format!("Error recording the template checksums: {}", e));
+        }
+    }
+}
+
+/// Delivers changed templates to the running container with its `reload_signal` instead of replacing it.
+pub struct Reload<'a> {
+    log: &'a Logger,
+    name: &'a str,
+    docker: &'a Docker,
+    config: &'a RukuConfig,
+    container: &'a Container<'a>,
+    state_dir: &'a Path,
+    templates: Templates<'a>,
+    rendered: &'a [Rendered],
+}
+
+impl<'a> Reload<'a> {
+    pub fn new(
+        log: &'a Logger,
+        name: &'a str,
+        docker: &'a Docker,
+        config: &'a RukuConfig,
+        container: &'a Container<'a>,
+        state_dir: &'a Path,
+        rendered: &'a [Rendered],
+    ) -> Reload<'a> {
+        Reload {
+            log,
+            name,
+            docker,
+            config,
+            container,
+            state_dir,
+            templates: Templates::new(log, state_dir),
+            rendered,
+        }
+    }
+
+    /// Whether the deploy may end in a reload rather than a new container, which still depends on the
+    /// build leaving the image as it is. Says which way it goes and why, and writes the templates when
+    /// a new container gets them: the running one only sees files change in place.
+    pub async fn plan(&self) -> Result<bool, String> {
+        let Some(signal) = &self.config.reload_signal else {
+            self.templates.write(self.rendered)?;
+            return Ok(false);
+        };
+        match self.changed_templates().await {
+            Ok(changed) => {
+                self.log.step(&format!(
+                    "Only templates changed ({}), reloading with {} instead of recreating",
+                    changed.join(", "),
+                    signal
+                ));
+                Ok(true)
+            }
+            Err(reason) => {
+                self.recreate(&reason)?;
+                Ok(false)
+            }
+        }
+    }
+
+    /// Give up on the reload and write the templates for a new container, which goes by the image as
+    /// the build leaves it.
+    pub fn recreate(&self, reason: &str) -> Result<(), String> {
+        self.container.forget_image();
+        self.log.step(&format!("Recreating instead of reloading, {}", reason));
+        self.templates.write(self.rendered)
+    }
+
+    /// Whether the running container was started from the image `image_name` now tags.
+    pub async fn image_unchanged(&self, image_name: &str) -> Result<bool, String> {
+        let running = self.container.get().await?.and_then(|summary| summary.image_id);
+        Ok(running.is_some() && running == Image::new(self.log, self.docker).id(image_name).await)
+    }
+
+    /// The templates that changed when nothing else did, so a reload covers the deploy, or else why the
+    /// container has to be replaced.
+    async fn changed_templates(&self) -> Result<Vec<String>, String> {
+        if self.config.strategy == DeployStrategy::Canary {
+            return Err("the canary strategy moves traffic to a new container".to_string());
+        }
+        let summary = self
+            .container
+            .get()
+            .await?
+            .filter(|summary| summary.state.as_deref() == Some("running"))
+            .ok_or("no container is running")?;
+        let configured = get_version(&self.config.version);
+        if deployed_version(&summary).as_deref() != Some(configured) {
+            return Err(format!("the version changes to {}", configured));
+        }
+        if self
+            .container
+            .condition()
+            .await
+            .is_some_and(|condition| condition.is_unhealthy() || condition.is_crash_looping())
+        {
+            return Err("the running container is failing".to_string());
+        }
+
+        let image_name = self.container.image_name();
+        self.container.use_image(&image_name).await?;
+        let desired = self.container.spec(image_name)?;
+        let live = self
+            .container
+            .live_spec()
+            .await
+            .ok_or("the container can't be inspected")?;
+        let drift = desired.diff(&live);
+        if !drift.is_empty() {
+            let fields: Vec<&str> = drift.iter().map(|change| change.path.as_str()).collect();
+            return Err(format!("{} changed", fields.join(", ")));
+        }
+        if desired.hash_change(&live).is_some() {
+            return Err("the config hash changed".to_string());
+        }
+        let sidecars = Sidecars::new(self.log, self.name, self.docker, self.config, self.container);
+        for sidecar in &self.config.sidecars {
+            if sidecars.drift(sidecar).await.is_none_or(|drift| !drift.is_empty()) {
+                return Err(format!("sidecar {} changed", sidecar.name));
+            }
+        }
+
+        let container_id = summary.id.unwrap_or_default();
+        let recorded = TemplateChecksums::read(self.state_dir)
+            .filter(|recorded| recorded.container_id == container_id)
+            .ok_or("the templates the container runs with are not recorded")?;
+        let current = TemplateChecksums::new(&container_id, self.rendered);
+        let changed: Vec<String> = current
+            .checksums
+            .iter()
+            .filter(|(target, checksum)| recorded.checksums.get(*target) != Some(checksum))
+            .map(|(target, _)| target.clone())
+            .collect();
+        if changed.is_empty() {
+            return Err("no template changed".to_string());
+        }
+        Ok(changed)
+    }
+
+    /// Rewrite the templates under the running container and send it the signal.
+    pub async fn run(&self) -> Result<(), String> {
+        let signal = self.config.reload_signal.as_deref().unwrap_or("SIGHUP");
+        self.templates.rewrite(self.rendered)?;
+        let Some(container_id) = self.container.get().await?.and_then(|summary| summary.id) else {
+            return Err("The container went away during the reload".to_string());
+        };
+        let options = KillContainerOptions { signal };
+        self.docker
+            .kill_container(&container_id, Some(options))
+            .await
+            .map_err(|e| format!("Error sending {}: {}", signal, e))?;
+        TemplateChecksums::new(&container_id, self.rendered).write(self.log, self.state_dir);
+        self.log
+            .step(&format!("Sent {} to {}", signal, self.container.container_name()));
+        Ok(())
+    }
+}
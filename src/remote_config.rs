@@ -0,0 +1,233 @@
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Utc};
+use cmd_lib::run_fun;
+use serde::{Deserialize, Serialize};
+use validator::Validate;
+
+use crate::config::merge_included;
+use crate::logger::Logger;
+use crate::model::RukuConfig;
+use crate::store::{self, Document};
+
+/// Sent as a bearer token when fetching a remote config.
+pub const TOKEN_ENV: &str = "RUKU_CONFIG_TOKEN";
+/// The cached copy of the remote config, in the app state directory.
+pub const CACHE_FILE: &str = "remote-config.yml";
+/// Longest a fetch may take.
+const FETCH_TIMEOUT: u64 = 30;
+
+/// Where the remote config of an app comes from, kept next to the cached copy.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RemoteSource {
+    pub url: String,
+    /// Named by the `include` of ruku.yml, which then goes on top of it, rather than given with
+    /// `--config-url` to replace ruku.yml.
+    pub included: bool,
+    /// The ETag the server sent with the cached copy, for a conditional fetch.
+    pub etag: Option<String>,
+    pub fetched_at: DateTime<Utc>,
+}
+
+impl Document for RemoteSource {}
+
+impl RemoteSource {
+    pub const FILE_NAME: &'static str = "remote-config.json";
+
+    pub fn read(log: &Logger, state_dir: &Path) -> Result<Option<RemoteSource>, String> {
+        store::load(log, &state_dir.join(Self::FILE_NAME))
+    }
+}
+
+/// The path of the cached copy of the remote config of the app with `state_dir`.
+pub fn cache_path(state_dir: &Path) -> PathBuf {
+    state_dir.join(CACHE_FILE)
+}
+
+/// The remote config of an app before and after [`RemoteConfig::refresh`].
+pub struct Refreshed {
+    pub url: String,
+    /// The cached copy from before, none on the first fetch.
+    pub cached: Option<String>,
+    pub content: String,
+}
+
+/// Fetches the config of an app from a URL given with `--config-url` or named by the `include` of its
+/// ruku.yml, and caches it with its ETag so a redeploy works while the server can't be reached.
+pub struct RemoteConfig<'a> {
+    log: &'a Logger,
+    state_dir: PathBuf,
+    local_path: PathBuf,
+    insecure: bool,
+    allow_stale: bool,
+}
+
+impl<'a> RemoteConfig<'a> {
+    pub fn new(log: &'a Logger, state_dir: &Path, local_path: &Path) -> RemoteConfig<'a> {
+        RemoteConfig {
+            log,
+            state_dir: state_dir.to_path_buf(),
+            local_path: local_path.to_path_buf(),
+            insecure: false,
+            allow_stale: false,
+        }
+    }
+
+    /// Fetch without verifying the server certificate, and allow plain HTTP.
+    pub fn with_insecure(mut self, insecure: bool) -> RemoteConfig<'a> {
+        self.insecure = insecure;
+        self
+    }
+
+    /// Use the cached copy with a warning when the fetch fails.
+    pub fn with_allow_stale(mut self, allow_stale: bool) -> RemoteConfig<'a> {
+        self.allow_stale = allow_stale;
+        self
+    }
+
+    /// Fetch the remote config of the app: from `url`, else from the URL an earlier `--config-url`
+    /// recorded, else from the `include` of ruku.yml. It is validated and, unless `dry_run`, cached. None
+    /// when the app has no remote config.
+    pub fn refresh(&self, url: Option<&str>, dry_run: bool) -> Result<Option<Refreshed>, String> {
+        let recorded = RemoteSource::read(self.log, &self.state_dir)?;
+        let cached = fs::read_to_string(cache_path(&self.state_dir)).ok();
+        let local = fs::read_to_string(&self.local_path).ok();
+        let (url, included) = match url {
+            Some(url) => (url.to_string(), false),
+            None => match (&recorded, local.as_deref().map(include_url)) {
+                (Some(recorded), _) if !recorded.included => (recorded.url.clone(), false),
+                (_, Some(Ok(Some(url)))) => (url, true),
+                (_, Some(Err(e))) => return Err(e),
+                _ => return Ok(None),
+            },
+        };
+        let plain = url.starts_with("http://") && self.insecure;
+        if !url.starts_with("https://") && !plain {
+            return Err(format!(
+                "{} is not an https:// URL, pass --insecure to fetch it over plain HTTP",
+                url
+            ));
+        }
+        // Only a copy of the same URL is worth a conditional fetch or a fallback
+        let recorded = recorded.filter(|recorded| recorded.url == url && recorded.included == included);
+        let cached = cached.filter(|_| recorded.is_some());
+
+        let etag = recorded
+            .as_ref()
+            .and_then(|recorded| recorded.etag.as_deref())
+            .filter(|_| cached.is_some());
+        let (content, etag) = match self.fetch(&url, etag) {
+            Ok(Some((content, etag))) => (content, etag),
+            Ok(None) => {
+                self.log.step(&format!("The config at {} is unchanged", url));
+                (cached.clone().unwrap(), recorded.and_then(|recorded| recorded.etag))
+            }
+            Err(e) => match &cached {
+                Some(cached) if self.allow_stale => {
+                    self.log.warn(&format!(
+                        "Error fetching the config at {}: {}, using the copy cached at {}",
+                        url,
+                        e,
+                        recorded.unwrap().fetched_at.format("%Y-%m-%d %H:%M:%S UTC")
+                    ));
+                    return Ok(Some(Refreshed {
+                        url,
+                        cached: Some(cached.clone()),
+                        content: cached.clone(),
+                    }));
+                }
+                Some(_) => {
+                    return Err(format!(
+                        "Error fetching the config at {}: {}, pass --allow-stale to deploy the cached copy",
+                        url, e
+                    ))
+                }
+                None => return Err(format!("Error fetching the config at {}: {}", url, e)),
+            },
+        };
+
+        let effective = match included {
+            true => merge_included(&content, local.as_deref().unwrap_or_default()),
+            false => Ok(content.clone()),
+        };
+        effective
+            .and_then(|effective| {
+                serde_yaml::from_str::<RukuConfig>(&effective).map_err(|e| format!("Error parsing it: {}", e))
+            })
+            .and_then(|config| config.validate().map_err(|e| format!("Error validating it: {}", e)))
+            .map_err(|e| format!("The config at {} is not deployable. {}", url, e))?;
+
+        if !dry_run {
+            let source = RemoteSource {
+                url: url.clone(),
+                included,
+                etag,
+                fetched_at: Utc::now(),
+            };
+            store::write_atomic(&cache_path(&self.state_dir), content.as_bytes())
+                .and_then(|_| store::save(&self.state_dir.join(RemoteSource::FILE_NAME), &source))
+                .map_err(|e| format!("Error caching the remote config: {}", e))?;
+        }
+        Ok(Some(Refreshed { url, cached, content }))
+    }
+
+    /// The body and ETag at `url`, none when it still matches `etag`.
+    fn fetch(&self, url: &str, etag: Option<&str>) -> Result<Option<(String, Option<String>)>, String> {
+        self.log.step(&format!("Fetching the config at {}", url));
+        let dir = tempfile::tempdir().map_err(|e| e.to_string())?;
+        let config_path = dir.path().join("curl.conf");
+        let headers_path = dir.path().join("headers");
+        let body_path = dir.path().join("body");
+        // The token goes in a config file so it doesn't show up in the process list
+        let mut config = fs::File::create(&config_path).map_err(|e| e.to_string())?;
+        let escape = |value: &str| value.replace('\\', "\\\\").replace('"', "\\\"");
+        if let Ok(token) = std::env::var(TOKEN_ENV) {
+            writeln!(config, "header = \"Authorization: Bearer {}\"", escape(&token)).map_err(|e| e.to_string())?;
+        }
+        if let Some(etag) = etag {
+            writeln!(config, "header = \"If-None-Match: {}\"", escape(etag)).map_err(|e| e.to_string())?;
+        }
+        if self.insecure {
+            writeln!(config, "insecure").map_err(|e| e.to_string())?;
+        }
+        drop(config);
+
+        let status = run_fun!(
+            curl --silent --show-error --location --max-time $FETCH_TIMEOUT --config $config_path
+                --dump-header $headers_path --output $body_path --write-out "%{http_code}" $url
+        )
+        .map_err(|e| e.to_string())?;
+        match status.trim() {
+            "304" if etag.is_some() => Ok(None),
+            "200" => {
+                let content = fs::read_to_string(&body_path).map_err(|e| format!("the body is not text: {}", e))?;
+                let headers = fs::read_to_string(&headers_path).unwrap_or_default();
+                Ok(Some((content, last_header(&headers, "ETag"))))
+            }
+            "401" | "403" => Err(format!(
+                "the server refused the request with {}, set {} to a token it accepts",
+                status.trim(),
+                TOKEN_ENV
+            )),
+            status => Err(format!("the server answered with {}", status)),
+        }
+    }
+}
+
+/// The `include` URL of the ruku.yml with `content`, none when it has none.
+pub fn include_url(content: &str) -> Result<Option<String>, String> {
+    let value: serde_yaml::Value =
+        serde_yaml::from_str(content).map_err(|e| format!("Error parsing ruku.yml file: {}", e))?;
+    Ok(value.get("include").and_then(|url| url.as_str()).map(str::to_string))
+}
+
+/// The value of header `name` in the last response of `headers`, after any redirects.
+fn last_header(headers: &str, name: &str) -> Option<String> {
+    let response = headers.trim_end().rsplit("\r\n\r\n").next().unwrap_or(headers);
+    response.lines().find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        key.trim().eq_ignore_ascii_case(name).then(|| value.trim().to_string())
+    })
+}
@@ -0,0 +1,448 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use bollard::container::{ListContainersOptions, RemoveContainerOptions};
+use bollard::image::{ListImagesOptions, RemoveImageOptions};
+use bollard::models::ContainerSummary;
+use bollard::Docker;
+
+use crate::auxiliary::{age, is_aux, AUX_LABEL, DEFAULT_AUX_MAX_AGE};
+use crate::canary::Canary;
+use crate::container::{get_container_name, APP_LABEL};
+use crate::history::History;
+use crate::image_drift::protected_images;
+use crate::logger::Logger;
+use crate::maintenance::MaintenanceState;
+use crate::plan::{Action, Plan, Resource};
+use crate::staging::Staging;
+use crate::units::{format_duration, format_size};
+
+/// Something a crashed or cancelled deploy left behind.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Leftover {
+    /// A container of the app that is not the canonical one and not part of an active rollout.
+    Container { name: String, id: String, running: bool },
+    /// A one-off container of the app, e.g. a probe, older than any run of it takes.
+    Aux {
+        name: String,
+        id: String,
+        kind: String,
+        age: Duration,
+    },
+    /// Canary progress saved for a canary container that no longer exists.
+    CanaryState,
+    /// An untagged image from a build that never finished.
+    DanglingImage { id: String, size: i64 },
+}
+
+impl fmt::Display for Leftover {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Leftover::Container { name, running, .. } => {
+                let state = if *running { "running" } else { "stopped" };
+                write!(f, "{} container {}", state, name)
+            }
+            Leftover::Aux { name, kind, age, .. } => {
+                write!(f, "{} container {}, {} old", kind, name, format_duration(*age))
+            }
+            Leftover::CanaryState => write!(f, "canary state without a canary container"),
+            Leftover::DanglingImage { id, size } => {
+                write!(
+                    f,
+                    "dangling image {} ({})",
+                    short_id(id),
+                    format_size((*size).max(0) as u64)
+                )
+            }
+        }
+    }
+}
+
+/// Decide which containers of `app` are leftovers. The canonical `<prefix><app>` container, the `<app>`
+/// one from before the prefix that the next lookup renames to it, and the configured `sidecars` never are, a canary is only kept while its rollout state exists and the
+/// maintenance page while maintenance mode is on, and the staged container while a deploy is staged.
+pub fn scan_containers(
+    app: &str,
+    prefix: &str,
+    containers: &[ContainerSummary],
+    canary_state: bool,
+    maintenance: bool,
+    staged: bool,
+    sidecars: &[String],
+) -> Vec<Leftover> {
+    let stable_name = format!("{}{}", prefix, app);
+    let canary_name = format!("{}-canary", stable_name);
+    let maintenance_name = format!("{}-maintenance", stable_name);
+    let staged_name = format!("{}-{}", stable_name, Staging::SUFFIX);
+    let mut leftovers = vec![];
+    let mut canary_found = false;
+
+    for container in containers {
+        let Some(name) = get_container_name(container) else {
+            continue;
+        };
+        let owned = container
+            .labels
+            .as_ref()
+            .and_then(|labels| labels.get(APP_LABEL))
+            .is_some_and(|label| label == app);
+        let sidecar = name
+            .strip_prefix(&stable_name)
+            .and_then(|rest| rest.strip_prefix('-'))
+            .is_some_and(|sidecar| sidecars.iter().any(|s| s == sidecar));
+        if !owned
+            || name == stable_name
+            || name == app
+            || sidecar
+            || (maintenance && name == maintenance_name)
+            || (staged && name == staged_name)
+        {
+            continue;
+        }
+        if name == canary_name {
+            canary_found = true;
+            if canary_state {
+                continue;
+            }
+        }
+        leftovers.push(Leftover::Container {
+            name,
+            id: container.id.clone().unwrap_or_default(),
+            running: container.state.as_deref() == Some("running"),
+        });
+    }
+
+    if canary_state && !canary_found {
+        leftovers.push(Leftover::CanaryState);
+    }
+    leftovers
+}
+
+/// Finds and removes what interrupted deploys of an app left behind.
+pub struct Repair<'a> {
+    log: &'a Logger,
+    name: &'a str,
+    container_prefix: &'a str,
+    docker: &'a Docker,
+    canary_state_path: PathBuf,
+    maintenance_state_path: PathBuf,
+    staged_state_path: PathBuf,
+    sidecars: Vec<String>,
+    aux_max_age: Duration,
+    history: History<'a>,
+}
+
+impl<'a> Repair<'a> {
+    pub fn new(
+        log: &'a Logger,
+        name: &'a str,
+        container_prefix: &'a str,
+        docker: &'a Docker,
+        state_dir: &Path,
+    ) -> Repair<'a> {
+        Repair {
+            log,
+            name,
+            container_prefix,
+            docker,
+            canary_state_path: state_dir.join(Canary::STATE_FILE),
+            maintenance_state_path: state_dir.join(MaintenanceState::FILE_NAME),
+            staged_state_path: state_dir.join(Staging::FILE_NAME),
+            sidecars: vec![],
+            aux_max_age: Duration::from_secs(DEFAULT_AUX_MAX_AGE),
+            history: History::new(log, state_dir),
+        }
+    }
+
+    /// Names of the sidecars the config has, others left running are leftovers.
+    pub fn with_sidecars(mut self, sidecars: Vec<String>) -> Repair<'a> {
+        self.sidecars = sidecars;
+        self
+    }
+
+    /// How old a one-off container must be to count as a leftover, younger ones may still be running.
+    pub fn with_aux_max_age(mut self, aux_max_age: Duration) -> Repair<'a> {
+        self.aux_max_age = aux_max_age;
+        self
+    }
+
+    /// Every leftover of the app, containers, state and images.
+    pub async fn scan(&self) -> Result<Vec<Leftover>, String> {
+        let mut leftovers = self.scan_containers().await?;
+        leftovers.extend(self.scan_images().await?);
+        Ok(leftovers)
+    }
+
+    pub async fn clean_all(&self, leftovers: &[Leftover]) {
+        for leftover in leftovers {
+            self.clean(leftover).await;
+        }
+    }
+
+    /// The quick pass before a deploy: stopped leftovers, stale one-off containers and stale state are
+    /// cleaned, other running containers are only reported since another deploy may still own them.
+    pub async fn run_quick(&self) -> Result<(), String> {
+        for leftover in self.scan_containers().await? {
+            match leftover {
+                Leftover::Container { running: true, .. } => self.log.warn(&format!(
+                    "Found {}, run `ruku repair {}` to remove it",
+                    leftover, self.name
+                )),
+                leftover => self.clean(&leftover).await,
+            }
+        }
+        Ok(())
+    }
+
+    async fn scan_containers(&self) -> Result<Vec<Leftover>, String> {
+        let app_filter = format!("{}={}", APP_LABEL, self.name);
+        let options = ListContainersOptions {
+            all: true,
+            filters: HashMap::from([("label", vec![app_filter.as_str()])]),
+            ..Default::default()
+        };
+        let containers = self
+            .docker
+            .list_containers(Some(options))
+            .await
+            .map_err(|_| "Failed to list containers")?;
+        let (aux, containers): (Vec<_>, Vec<_>) = containers.into_iter().partition(is_aux);
+        let mut leftovers = scan_containers(
+            self.name,
+            self.container_prefix,
+            &containers,
+            self.canary_state_path.exists(),
+            self.maintenance_state_path.exists(),
+            self.staged_state_path.exists(),
+            &self.sidecars,
+        );
+        leftovers.extend(aux.iter().filter_map(|summary| {
+            let age = age(summary).filter(|age| *age >= self.aux_max_age)?;
+            Some(Leftover::Aux {
+                name: get_container_name(summary)?,
+                id: summary.id.clone().unwrap_or_default(),
+                kind: summary.labels.as_ref()?.get(AUX_LABEL)?.clone(),
+                age,
+            })
+        }));
+        Ok(leftovers)
+    }
+
+    async fn scan_images(&self) -> Result<Vec<Leftover>, String> {
+        let app_filter = format!("{}={}", APP_LABEL, self.name);
+        let options = ListImagesOptions {
+            filters: HashMap::from([("dangling", vec!["true"]), ("label", vec![app_filter.as_str()])]),
+            ..Default::default()
+        };
+        let images = self
+            .docker
+            .list_images(Some(options))
+            .await
+            .map_err(|_| "Failed to list images")?;
+        // A dangling image may still be what a container runs from or what a rollback goes back to
+        let protected = protected_images(self.docker, self.name, &self.history.load_here()?).await;
+        Ok(images
+            .into_iter()
+            .filter(|image| !protected.contains(&image.id))
+            .map(|image| Leftover::DanglingImage {
+                id: image.id,
+                size: image.size,
+            })
+            .collect())
+    }
+
+    /// The removals of `leftovers` as a plan.
+    pub fn plan(&self, leftovers: &[Leftover]) -> Plan {
+        let mut plan = Plan::new("repair", self.name);
+        for leftover in leftovers {
+            match leftover {
+                Leftover::Container { name, id, running } => {
+                    let state = if *running { "running" } else { "stopped" };
+                    plan.add(Action::Remove, Resource::Container, name)
+                        .with(Some(id), None)
+                        .detail(state);
+                }
+                Leftover::Aux { name, id, kind, age } => {
+                    plan.add(Action::Remove, Resource::Container, name)
+                        .with(Some(id), None)
+                        .detail(&format!("{}, {} old", kind, format_duration(*age)));
+                }
+                Leftover::CanaryState => {
+                    plan.add(
+                        Action::Remove,
+                        Resource::StateFile,
+                        &self.canary_state_path.display().to_string(),
+                    )
+                    .detail("canary state without a canary container");
+                }
+                Leftover::DanglingImage { id, size } => {
+                    plan.add(Action::Remove, Resource::Image, "<none>")
+                        .with(Some(id), Some(*size))
+                        .detail("dangling");
+                }
+            }
+        }
+        plan
+    }
+
+    async fn clean(&self, leftover: &Leftover) {
+        let result = match leftover {
+            Leftover::Container { id, .. } | Leftover::Aux { id, .. } => {
+                let options = RemoveContainerOptions {
+                    force: true,
+                    ..Default::default()
+                };
+                self.docker
+                    .remove_container(id, Some(options))
+                    .await
+                    .map_err(|e| e.to_string())
+            }
+            Leftover::CanaryState => fs::remove_file(&self.canary_state_path).map_err(|e| e.to_string()),
+            Leftover::DanglingImage { id, .. } => self
+                .docker
+                .remove_image(id, None::<RemoveImageOptions>, None)
+                .await
+                .map(|_| ())
+                .map_err(|e| e.to_string()),
+        };
+        match result {
+            Ok(()) => self.log.step(&format!("Removed {}", leftover)),
+            Err(e) => self.log.warn(&format!("Failed to remove {}: {}", leftover, e)),
+        }
+    }
+}
+
+fn short_id(id: &str) -> &str {
+    let id = id.trim_start_matches("sha256:");
+    &id[..id.len().min(12)]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn container(name: &str, app: &str) -> ContainerSummary {
+        ContainerSummary {
+            id: Some(format!("{}-id", name)),
+            names: Some(vec![format!("/{}", name)]),
+            labels: Some(HashMap::from([(APP_LABEL.to_string(), app.to_string())])),
+            state: Some("running".to_string()),
+            ..Default::default()
+        }
+    }
+
+    fn leftover_names(leftovers: &[Leftover]) -> Vec<String> {
+        leftovers.iter().map(|leftover| leftover.to_string()).collect()
+    }
+
+    #[test]
+    fn stable_and_legacy_containers_are_not_leftovers() {
+        let containers = [
+            container("ruku-shop", "shop"),
+            container("shop", "shop"),
+            container("ruku-shop-next", "shop"),
+        ];
+        let leftovers = scan_containers("shop", "ruku-", &containers, false, false, false, &[]);
+        assert_eq!(leftover_names(&leftovers), ["running container ruku-shop-next"]);
+    }
+
+    #[test]
+    fn kept_roles_follow_their_state() {
+        let containers = [
+            container("ruku-shop-canary", "shop"),
+            container("ruku-shop-maintenance", "shop"),
+            container("ruku-shop-staged", "shop"),
+            container("ruku-shop-redis", "shop"),
+            container("ruku-blog", "blog"),
+        ];
+        let sidecars = ["redis".to_string()];
+        let kept = scan_containers("shop", "ruku-", &containers, true, true, true, &sidecars);
+        assert_eq!(leftover_names(&kept), Vec::<String>::new());
+
+        let leftovers = scan_containers("shop", "ruku-", &containers, false, false, false, &sidecars);
+        assert_eq!(
+            leftover_names(&leftovers),
+            [
+                "running container ruku-shop-canary",
+                "running container ruku-shop-maintenance",
+                "running container ruku-shop-staged"
+            ]
+        );
+
+        // A canary rollout state without its container
+        let leftovers = scan_containers("shop", "ruku-", &[], true, false, false, &[]);
+        assert_eq!(leftover_names(&leftovers), ["canary state without a canary container"]);
+    }
+
+    #[test]
+    fn containers_of_other_apps_are_never_leftovers() {
+        let mut unlabeled = container("ruku-shop-next", "shop");
+        unlabeled.labels = None;
+        let mut stopped = container("ruku-shop-old", "shop");
+        stopped.state = Some("exited".to_string());
+        let containers = [unlabeled, container("ruku-shop-next", "blog"), stopped];
+        let leftovers = scan_containers("shop", "ruku-", &containers, false, false, false, &[]);
+        assert_eq!(
+            leftovers,
+            [Leftover::Container {
+                name: "ruku-shop-old".to_string(),
+                id: "ruku-shop-old-id".to_string(),
+                running: false,
+            }]
+        );
+    }
+
+    #[test]
+    fn plan_removes_every_leftover() {
+        let log = Logger::new();
+        let docker = Docker::connect_with_http("http://127.0.0.1:1", 1, bollard::API_DEFAULT_VERSION).unwrap();
+        let state_dir = tempfile::tempdir().unwrap();
+        let repair = Repair::new(&log, "shop", "ruku-", &docker, state_dir.path());
+        let leftovers = [
+            Leftover::Container {
+                name: "ruku-shop-next".to_string(),
+                id: "0123456789abcdef".to_string(),
+                running: false,
+            },
+            Leftover::Aux {
+                name: "ruku-shop-probe".to_string(),
+                id: "fedcba9876543210".to_string(),
+                kind: "probe".to_string(),
+                age: Duration::from_secs(7200),
+            },
+            Leftover::CanaryState,
+            Leftover::DanglingImage {
+                id: "sha256:aaaabbbbccccdddd".to_string(),
+                size: 2_500_000,
+            },
+        ];
+        assert_eq!(
+            leftover_names(&leftovers),
+            [
+                "stopped container ruku-shop-next",
+                "probe container ruku-shop-probe, 2h0m old",
+                "canary state without a canary container",
+                "dangling image aaaabbbbcccc (2.5 MB)"
+            ]
+        );
+
+        let plan = repair.plan(&leftovers);
+        let canary_state = state_dir.path().join(Canary::STATE_FILE);
+        assert_eq!(
+            plan.affected(),
+            [
+                "remove container ruku-shop-next (0123456789ab, stopped)".to_string(),
+                "remove container ruku-shop-probe (fedcba987654, probe, 2h0m old)".to_string(),
+                format!(
+                    "remove state file {} (canary state without a canary container)",
+                    canary_state.display()
+                ),
+                "remove image <none> (aaaabbbbcccc, 2.5 MB, dangling)".to_string(),
+            ]
+        );
+        assert!(repair.plan(&[]).is_empty());
+    }
+}
@@ -0,0 +1,176 @@
+use std::io::{self, BufRead, IsTerminal, Write};
+
+use bollard::Docker;
+
+use crate::history::Deployment;
+use crate::image::Image;
+use crate::logger::Logger;
+use crate::misc::get_version;
+
+/// Releases the picker lists, newest first.
+const PICKER_RELEASES: usize = 10;
+
+/// Finds the earlier release of an app to go back to in its deployment history and gets its image into
+/// the local store. Only the image goes back, the container is set up by the ruku.yml of now.
+pub struct Rollback<'a> {
+    log: &'a Logger,
+    docker: &'a Docker,
+    name: &'a str,
+    deployments: &'a [Deployment],
+    registry: Option<String>,
+}
+
+impl<'a> Rollback<'a> {
+    pub fn new(log: &'a Logger, docker: &'a Docker, name: &'a str, deployments: &'a [Deployment]) -> Rollback<'a> {
+        Rollback {
+            log,
+            docker,
+            name,
+            deployments,
+            registry: None,
+        }
+    }
+
+    /// Pull images that are gone from the local store from `registry`, the push registry of the build
+    /// config, by the digest recorded when they were pushed.
+    pub fn with_registry(mut self, registry: Option<&str>) -> Rollback<'a> {
+        self.registry = registry.map(str::to_string);
+        self
+    }
+
+    /// The release `target` names, a deployment id or a version for the newest deployment of it.
+    pub fn find(&self, target: &str) -> Result<&'a Deployment, String> {
+        self.deployments
+            .iter()
+            .find(|deployment| deployment.id == target)
+            .or_else(|| {
+                self.deployments
+                    .iter()
+                    .rev()
+                    .find(|deployment| get_version(&deployment.version) == target)
+            })
+            .ok_or(format!(
+                "No release {} in the history of {}, `ruku releases {}` lists them",
+                target, self.name, self.name
+            ))
+    }
+
+    /// The newest release before `current`, the running one.
+    pub fn previous(&self, current: Option<&Deployment>) -> Result<&'a Deployment, String> {
+        let before = match current.and_then(|current| self.deployments.iter().position(|d| d.id == current.id)) {
+            Some(index) => &self.deployments[..index],
+            None => self
+                .deployments
+                .split_last()
+                .map(|(_, before)| before)
+                .unwrap_or_default(),
+        };
+        before
+            .last()
+            .ok_or(format!("{} has no earlier release to go back to", self.name))
+    }
+
+    /// List the recent releases and let the operator pick one, `default` when they just press enter.
+    /// Fails when stdin is not a terminal or nothing is picked.
+    pub async fn pick(&self, current: Option<&Deployment>, default: &Deployment) -> Result<&'a Deployment, String> {
+        if !io::stdin().is_terminal() {
+            return Err("Pick a release with --to, stdin is not a terminal".to_string());
+        }
+        let candidates: Vec<&Deployment> = self.deployments.iter().rev().take(PICKER_RELEASES).collect();
+        let image = Image::new(self.log, self.docker);
+        eprintln!(
+            "     {:<16} {:<16} {:<18} {:<24} MESSAGE",
+            "ID", "VERSION", "DEPLOYED", "HEALTH"
+        );
+        for (number, deployment) in candidates.iter().enumerate() {
+            let state = match current {
+                Some(current) if current.id == deployment.id => " (running)",
+                _ if !self.is_local(&image, deployment).await => " (image gone)",
+                _ => "",
+            };
+            eprintln!(
+                "{:>3}  {:<16} {:<16} {:<18} {:<24} {}{}",
+                number + 1,
+                deployment.id,
+                get_version(&deployment.version),
+                deployment.finished_at.format("%Y-%m-%d %H:%M"),
+                health(deployment),
+                deployment
+                    .message
+                    .as_deref()
+                    .and_then(|m| m.lines().next())
+                    .unwrap_or("-"),
+                state
+            );
+        }
+        // Nothing streamed may follow the question
+        self.log.flush();
+        eprint!("Roll back to [default {}]: ", default.id);
+        let _ = io::stderr().flush();
+        let mut line = String::new();
+        if io::stdin().lock().read_line(&mut line).is_err() || line.is_empty() {
+            return Err("Aborted".to_string());
+        }
+        let picked = match line.trim() {
+            "" => Some(default.id.as_str()),
+            answer => match answer.parse::<usize>() {
+                Ok(number) => candidates
+                    .get(number.wrapping_sub(1))
+                    .map(|deployment| deployment.id.as_str()),
+                Err(_) => Some(answer),
+            },
+        };
+        picked
+            .and_then(|id| self.deployments.iter().find(|deployment| deployment.id == id))
+            .ok_or_else(|| format!("No release {}", line.trim()))
+    }
+
+    /// Get the image of `release` into the local store and return what to create the container from.
+    /// Nothing is pulled when it can't be, the error names what is missing.
+    pub async fn obtain(&self, release: &Deployment) -> Result<String, String> {
+        let image = Image::new(self.log, self.docker);
+        let tagged = image.id(&release.image).await;
+        match (&release.image_id, tagged) {
+            (Some(recorded), Some(tagged)) if *recorded == tagged => return Ok(release.image.clone()),
+            (None, Some(_)) => return Ok(release.image.clone()),
+            // The tag moved on, the image the release ran is still there by its id
+            (Some(recorded), _) if image.exists(recorded).await => return Ok(recorded.clone()),
+            _ => {}
+        }
+        let mut missing = vec![format!("image {} is not in the local store", release.image)];
+        if release.digest.is_none() {
+            missing.push("no registry digest was recorded for it".to_string());
+        }
+        if self.registry.is_none() {
+            missing.push("ruku.yml sets no build.registry to pull it from".to_string());
+        }
+        let (Some(digest), Some(registry)) = (&release.digest, &self.registry) else {
+            return Err(format!(
+                "Release {} can't be brought back: {}",
+                release.id,
+                missing.join(", ")
+            ));
+        };
+        let reference = format!("{}/{}@{}", registry.trim_end_matches('/'), self.name, digest);
+        image.pull(&reference).await?;
+        image.tag(&reference, &release.image).await?;
+        Ok(release.image.clone())
+    }
+
+    async fn is_local(&self, image: &Image<'_>, deployment: &Deployment) -> bool {
+        match &deployment.image_id {
+            Some(image_id) => image.exists(image_id).await,
+            None => image.exists(&deployment.image).await,
+        }
+    }
+}
+
+/// How the smoke checks of a release went, e.g. `1 of 3 smoke checks failed`.
+pub fn health(deployment: &Deployment) -> String {
+    let failed = deployment.smoke.iter().filter(|result| !result.passed).count();
+    match (deployment.smoke.len(), failed) {
+        (0, _) => "no smoke checks".to_string(),
+        (total, 0) => format!("{} smoke checks passed", total),
+        (total, failed) => format!("{} of {} smoke checks failed", failed, total),
+    }
+}
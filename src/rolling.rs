@@ -0,0 +1,128 @@
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::container::{is_managed, Container};
+use crate::logger::Logger;
+use crate::smoke::SmokeResult;
+use crate::strategy::Strategy;
+
+/// Creates the new container while the old one still runs, then swaps them: the old one is stopped and
+/// kept as `<app>-previous` until the new one is healthy and passed the smoke checks, and started again
+/// when it doesn't.
+pub struct Rolling<'a> {
+    log: &'a Logger,
+    stable: &'a Container<'a>,
+    next: Container<'a>,
+    previous: Container<'a>,
+    health_timeout: Duration,
+    /// Whether the previous container was running before the swap, so a rollback starts it again.
+    previous_running: Mutex<bool>,
+    /// Whether the new container was created ahead of the swap, by `ruku stage`.
+    prepared: bool,
+}
+
+impl<'a> Rolling<'a> {
+    pub fn new(log: &'a Logger, stable: &'a Container<'a>, health_timeout: Duration) -> Rolling<'a> {
+        Rolling {
+            log,
+            stable,
+            next: stable.staged("next"),
+            previous: stable.staged("previous"),
+            health_timeout,
+            previous_running: Mutex::new(false),
+            prepared: false,
+        }
+    }
+
+    /// Swap in the container `<prefix><app>-<suffix>` created ahead of time instead of creating one.
+    pub fn with_prepared(mut self, suffix: &str) -> Rolling<'a> {
+        self.next = self.stable.staged(suffix);
+        self.prepared = true;
+        self
+    }
+
+    /// Create the new container without running the `pre_start` command, when it already ran.
+    pub fn with_skip_pre_start(mut self, skip_pre_start: bool) -> Rolling<'a> {
+        self.next = self.next.with_skip_pre_start(skip_pre_start);
+        self
+    }
+}
+
+impl Strategy for Rolling<'_> {
+    fn plan(&self) -> Vec<String> {
+        let (stable, next) = (self.stable.container_name(), self.next.container_name());
+        vec![
+            format!("Creating {} while {} keeps running", next, stable),
+            format!(
+                "Swapping them, {} is kept as {} until the new version is healthy",
+                stable,
+                self.previous.container_name()
+            ),
+        ]
+    }
+
+    async fn execute(&self) -> Result<Vec<SmokeResult>, String> {
+        let image_name = self.stable.image_name();
+        // Left behind by an interrupted rollout
+        if !self.prepared {
+            self.next.discard().await?;
+        }
+        match self.stable.get().await? {
+            Some(summary) if is_managed(&summary) => {
+                self.previous.discard().await?;
+                if !self.prepared {
+                    self.next.prepare(image_name).await?;
+                }
+                let running = !matches!(summary.state.as_deref(), Some("created" | "exited" | "dead"));
+                if running {
+                    self.stable.suspend().await?;
+                }
+                *self.previous_running.lock().unwrap() = running;
+                self.stable.rename_to(&self.previous).await?;
+                self.next.rename_to(self.stable).await?;
+                self.stable.resume().await?;
+            }
+            None if self.prepared => {
+                self.next.rename_to(self.stable).await?;
+                self.stable.resume().await?;
+            }
+            // Nothing to swap with, or a container ruku did not create, which goes through the takeover
+            _ => {
+                if self.prepared {
+                    self.next.discard().await?;
+                }
+                self.stable.run_image(image_name).await?
+            }
+        }
+        self.stable.wait_healthy(self.health_timeout).await?;
+        let smoke = self.stable.smoke_test().await?;
+        self.previous.discard().await?;
+        Ok(smoke)
+    }
+
+    /// The new container, under its own name until the swap and the stable name after it, which is
+    /// when the previous container exists.
+    async fn failed(&self) -> Vec<String> {
+        let mut failed = vec![self.next.container_name().to_string()];
+        if matches!(self.previous.get().await, Ok(Some(_))) {
+            failed.push(self.stable.container_name().to_string());
+        }
+        failed
+    }
+
+    async fn rollback(&self) -> Result<(), String> {
+        self.next.discard().await?;
+        if self.previous.get().await?.is_none() {
+            self.log.step("There is no previous container to return to");
+            return Ok(());
+        }
+        self.log
+            .step(&format!("Returning to {}", self.previous.container_name()));
+        self.stable.discard().await?;
+        self.previous.rename_to(self.stable).await?;
+        if *self.previous_running.lock().unwrap() {
+            self.stable.resume().await?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,241 @@
+use std::fs;
+use std::path::Path;
+
+use bollard::models::SystemInfo;
+use bollard::Docker;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::connection::local_docker_host;
+use crate::container::is_port_free;
+use crate::logger::Logger;
+use crate::model::{publish_address, RukuConfig};
+use crate::spec::PortSpec;
+use crate::store::{self, Document};
+
+/// Where the kernel keeps the lowest port processes without privileges may bind.
+const PORT_START_SYSCTL: &str = "/proc/sys/net/ipv4/ip_unprivileged_port_start";
+/// Ports below this need privileges unless the sysctl says otherwise.
+const DEFAULT_PORT_START: u16 = 1024;
+/// Added to a low port to get the one `low_port_redirect` publishes on, so 80 becomes 10080. Clear of
+/// 8000-8999, where apps commonly listen.
+pub const REDIRECT_OFFSET: u16 = 10000;
+
+/// Whether the daemon runs without root, Docker and Podman both list `name=rootless` in its security
+/// options.
+pub fn is_rootless(info: &SystemInfo) -> bool {
+    info.security_options
+        .iter()
+        .flatten()
+        .any(|option| option.split(',').any(|part| part == "name=rootless"))
+}
+
+/// The lowest port a rootless daemon on this host may publish, none when the daemon is on another host
+/// and the sysctl there can't be read.
+pub fn unprivileged_port_start() -> Option<u16> {
+    if !local_docker_host().starts_with("unix://") {
+        return None;
+    }
+    Some(parse_port_start(fs::read_to_string(PORT_START_SYSCTL).ok().as_deref()))
+}
+
+/// The port start in the content of the sysctl, the default when it can't be read.
+fn parse_port_start(content: Option<&str>) -> u16 {
+    content
+        .and_then(|start| start.trim().parse().ok())
+        .unwrap_or(DEFAULT_PORT_START)
+}
+
+/// The port `port` is published on instead, an error when `is_free` tells another process holds it.
+fn redirect_port(port: u16, is_free: impl Fn(u16) -> bool) -> Result<u16, String> {
+    let published_on = port.saturating_add(REDIRECT_OFFSET);
+    if !is_free(published_on) {
+        return Err(format!(
+            "The daemon runs rootless and port {} would be published on {} instead, which is taken. Free it, \
+             or lower net.ipv4.ip_unprivileged_port_start so the daemon can publish port {} itself",
+            port, published_on, port
+        ));
+    }
+    Ok(published_on)
+}
+
+/// A low port of an app published on a high one because the daemon is rootless, kept so `status` can tell
+/// and `destroy` clears it.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LowPortRedirect {
+    /// The port the config asks for.
+    pub port: u16,
+    /// The port the app is published on instead.
+    pub published_on: u16,
+    pub since: DateTime<Utc>,
+}
+
+impl LowPortRedirect {
+    pub const FILE_NAME: &'static str = "low-port.json";
+
+    pub fn read(log: &Logger, state_dir: &Path) -> Result<Option<LowPortRedirect>, String> {
+        store::load(log, &state_dir.join(Self::FILE_NAME))
+    }
+
+    /// Forget the redirect, once the app is gone or publishes its port itself.
+    pub fn clear(state_dir: &Path) {
+        let _ = fs::remove_file(state_dir.join(Self::FILE_NAME));
+    }
+
+    /// How to forward the low port on the host, which ruku without root can't do itself.
+    pub fn hint(&self) -> String {
+        format!(
+            "forward it on the host with `sudo iptables -t nat -A PREROUTING -p tcp --dport {} -j REDIRECT --to-ports {}`, \
+             or let the daemon bind it with `sudo sysctl net.ipv4.ip_unprivileged_port_start={}`",
+            self.port, self.published_on, self.port
+        )
+    }
+}
+
+impl Document for LowPortRedirect {}
+
+/// Checks a host port below 1024 against a rootless daemon, which can't publish it.
+pub struct LowPorts<'a> {
+    log: &'a Logger,
+    docker: &'a Docker,
+    state_dir: &'a Path,
+}
+
+impl<'a> LowPorts<'a> {
+    pub fn new(log: &'a Logger, docker: &'a Docker, state_dir: &'a Path) -> LowPorts<'a> {
+        LowPorts { log, docker, state_dir }
+    }
+
+    /// Fail with the ways around it when the host port of `config` is privileged and the daemon rootless.
+    /// With `low_port_redirect` the app is published on the port plus [`REDIRECT_OFFSET`] instead.
+    pub async fn check(&self, config: &mut RukuConfig) -> Result<(), String> {
+        // The loaded config has the port of an earlier redirect already
+        let previous = LowPortRedirect::read(self.log, self.state_dir)?;
+        let port = match &previous {
+            Some(redirect) if redirect.published_on == config.port.host_port => redirect.port,
+            _ => config.port.host_port,
+        };
+        config.port.host_port = port;
+        let rootless = match self.docker.info().await {
+            Ok(info) => is_rootless(&info),
+            Err(_) => false,
+        };
+        let start = unprivileged_port_start();
+        if port == 0 || !rootless || start.is_some_and(|start| port >= start) {
+            LowPortRedirect::clear(self.state_dir);
+            return Ok(());
+        }
+        if !config.low_port_redirect {
+            match start {
+                Some(start) => {
+                    return Err(format!(
+                        "The daemon runs rootless and can't publish port {}, ports below {} need privileges. \
+                         Lower the limit with `sudo sysctl net.ipv4.ip_unprivileged_port_start={}` (keep it in \
+                         /etc/sysctl.d), give rootlesskit the capability with \
+                         `sudo setcap cap_net_bind_service=ep $(which rootlesskit)` and restart the daemon, \
+                         or set low_port_redirect: true to publish on port {}",
+                        port,
+                        start,
+                        port,
+                        port.saturating_add(REDIRECT_OFFSET)
+                    ));
+                }
+                // The limit of a remote host is not known, the daemon refuses the port if it is too low
+                None => {
+                    self.log.warn(&format!(
+                        "The daemon runs rootless, port {} only works when net.ipv4.ip_unprivileged_port_start \
+                         on its host is {} or lower",
+                        port, port
+                    ));
+                    return Ok(());
+                }
+            }
+        }
+        // A port on another host can't be checked from here
+        let is_free = |published_on: u16| {
+            start.is_none()
+                || config.port.protocols.iter().all(|protocol| {
+                    is_port_free(&PortSpec {
+                        container_port: config.port.number,
+                        protocol: protocol.to_string(),
+                        host_ip: publish_address(config.port.host_ip.or(config.bind_ip)),
+                        host_port: published_on,
+                    })
+                })
+        };
+        // An earlier redirect keeps its port, the app's own container holds it and the forwards set up on
+        // the host point at it
+        let published_on = match previous.as_ref().filter(|redirect| redirect.port == port) {
+            Some(redirect) => redirect.published_on,
+            None => redirect_port(port, is_free)?,
+        };
+        let redirect = LowPortRedirect {
+            port,
+            published_on,
+            since: Utc::now(),
+        };
+        self.log.warn(&format!(
+            "The daemon runs rootless, publishing port {} on {} instead, {}",
+            port,
+            redirect.published_on,
+            redirect.hint()
+        ));
+        if let Err(e) = store::save(&self.state_dir.join(LowPortRedirect::FILE_NAME), &redirect) {
+            self.log.warn(&format!("Could not record the port redirect: {}", e));
+        }
+        config.port.host_port = redirect.published_on;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(security_options: &[&str]) -> SystemInfo {
+        SystemInfo {
+            security_options: Some(security_options.iter().map(|option| option.to_string()).collect()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn rootless_is_read_from_the_security_options() {
+        assert!(is_rootless(&info(&[
+            "name=seccomp,profile=builtin",
+            "name=rootless",
+            "name=cgroupns"
+        ])));
+        assert!(is_rootless(&info(&["name=rootless,foo=bar"])));
+        assert!(!is_rootless(&info(&["name=seccomp,profile=builtin", "name=apparmor"])));
+        assert!(!is_rootless(&info(&["name=rootlesskit"])));
+        assert!(!is_rootless(&SystemInfo::default()));
+    }
+
+    #[test]
+    fn the_port_start_falls_back_to_1024() {
+        assert_eq!(parse_port_start(Some("0\n")), 0);
+        assert_eq!(parse_port_start(Some(" 80 \n")), 80);
+        assert_eq!(parse_port_start(Some("")), 1024);
+        assert_eq!(parse_port_start(Some("not a port")), 1024);
+        assert_eq!(parse_port_start(Some("70000")), 1024);
+        assert_eq!(parse_port_start(None), 1024);
+    }
+
+    #[test]
+    fn low_ports_are_redirected_clear_of_the_common_app_ports() {
+        assert_eq!(redirect_port(80, |_| true), Ok(10080));
+        assert_eq!(redirect_port(443, |_| true), Ok(10443));
+        assert_eq!(redirect_port(1023, |_| true), Ok(11023));
+    }
+
+    #[test]
+    fn a_taken_redirect_port_is_refused() {
+        let error = redirect_port(80, |port| port != 10080).unwrap_err();
+        assert!(
+            error.contains("published on 10080 instead, which is taken"),
+            "{}",
+            error
+        );
+    }
+}
@@ -0,0 +1,43 @@
+use std::collections::BTreeMap;
+use std::sync::LazyLock;
+
+use regex::Regex;
+
+use crate::model::RukuConfig;
+
+/// Labels Traefik reads its routers and services from.
+pub const ROUTING_PREFIX: &str = "traefik.";
+
+/// The label that tells Traefik the port a service listens on.
+static SERVICE_PORT: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^traefik\.http\.services\.([^.]+)\.loadbalancer\.server\.port$").unwrap());
+
+/// The proxy labels among `labels`.
+pub fn routing_labels(labels: &BTreeMap<String, String>) -> BTreeMap<String, String> {
+    labels
+        .iter()
+        .filter(|(key, _)| key.starts_with(ROUTING_PREFIX))
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect()
+}
+
+/// The services whose port label is not the port the app listens on, which Traefik would send traffic
+/// to in vain. Nothing to compare against when the port comes from the image.
+pub fn port_mismatches(labels: &BTreeMap<String, String>, config: &RukuConfig) -> Vec<String> {
+    if config.port.is_from_image() {
+        return vec![];
+    }
+    let port = config.port.number.to_string();
+    labels
+        .iter()
+        .filter_map(|(key, value)| {
+            let service = SERVICE_PORT.captures(key)?.get(1)?.as_str();
+            (*value != port).then(|| {
+                format!(
+                    "Service {} is routed to port {} but the app listens on {}, use ${{port}} in {}",
+                    service, value, port, key
+                )
+            })
+        })
+        .collect()
+}
@@ -0,0 +1,236 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use bollard::Docker;
+use chrono::{DateTime, Utc};
+use cmd_lib::run_fun;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::logger::Logger;
+use crate::releases::is_secret_key;
+use crate::store::{self, Document};
+use crate::templates::SECRET_MASK;
+
+/// Labels of the OCI image spec, which name the base image, source and revision when the build set them.
+const OCI_LABEL_PREFIX: &str = "org.opencontainers.image.";
+
+/// What is in the image of one deploy, for answering what ran in production after an incident.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Sbom {
+    pub deployment_id: String,
+    pub image: String,
+    pub image_id: Option<String>,
+    pub created_at: DateTime<Utc>,
+    /// `syft` when it listed the packages, `docker` when only what the daemon knows of the image is kept.
+    pub generator: String,
+    /// Digests of the filesystem layers, those of the base image first.
+    pub layers: Vec<String>,
+    /// The `org.opencontainers.image.` labels of the image.
+    pub labels: BTreeMap<String, String>,
+    /// Build args the image history records for its RUN steps, secrets masked.
+    pub build_args: BTreeMap<String, String>,
+    /// The SPDX document of syft, none without it.
+    pub spdx: Option<Value>,
+    /// The deployment this SBOM was first recorded for, when the image was deployed before.
+    #[serde(default)]
+    pub reused_from: Option<String>,
+}
+
+/// The image id of each deployment with an SBOM, so a redeploy of the same image finds its SBOM.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct SbomIndex {
+    images: BTreeMap<String, String>,
+}
+
+impl Document for SbomIndex {}
+
+/// SBOMs of the deploys of an app, zstd compressed JSON in the app state directory keyed by deployment id.
+pub struct Sboms<'a> {
+    log: &'a Logger,
+    dir: PathBuf,
+}
+
+impl<'a> Sboms<'a> {
+    pub const DIR_NAME: &'static str = "sboms";
+    const INDEX_FILE: &'static str = "index.json";
+
+    pub fn new(log: &'a Logger, state_dir: &Path) -> Sboms<'a> {
+        Sboms {
+            log,
+            dir: state_dir.join(Self::DIR_NAME),
+        }
+    }
+
+    /// Record the SBOM of `image` for deployment `id` and prune all but the newest `retention` ones. An
+    /// image deployed before gets a copy of its SBOM, failures only warn.
+    pub async fn record(&self, docker: &Docker, id: &str, image: &str, image_id: Option<&str>, retention: usize) {
+        let mut index: SbomIndex = match store::load(self.log, &self.dir.join(Self::INDEX_FILE)) {
+            Ok(index) => index.unwrap_or_default(),
+            Err(e) => {
+                self.log.warn(&e);
+                return;
+            }
+        };
+        let earlier = image_id.and_then(|image_id| {
+            index
+                .images
+                .iter()
+                .rev()
+                .filter(|(_, indexed)| indexed.as_str() == image_id)
+                .find_map(|(earlier, _)| self.load(earlier).ok())
+        });
+        let sbom = match earlier {
+            Some(earlier) => {
+                self.log.step(&format!(
+                    "Reusing the SBOM of deployment {}, which deployed the same image",
+                    earlier.deployment_id
+                ));
+                Sbom {
+                    reused_from: Some(earlier.reused_from.clone().unwrap_or(earlier.deployment_id.clone())),
+                    deployment_id: id.to_string(),
+                    image: image.to_string(),
+                    created_at: Utc::now(),
+                    ..earlier
+                }
+            }
+            None => match generate(self.log, docker, id, image).await {
+                Ok(sbom) => sbom,
+                Err(e) => {
+                    self.log.warn(&format!("Could not record an SBOM of {}: {}", image, e));
+                    return;
+                }
+            },
+        };
+        if let Err(e) = self.save(&sbom) {
+            self.log.warn(&format!("Could not store the SBOM of {}: {}", image, e));
+            return;
+        }
+        if let Some(image_id) = &sbom.image_id {
+            index.images.insert(id.to_string(), image_id.clone());
+        }
+
+        let ids = self.ids();
+        for old in ids.iter().take(ids.len().saturating_sub(retention)) {
+            let _ = fs::remove_file(self.path(old));
+            index.images.remove(old);
+        }
+        if let Err(e) = store::save(&self.dir.join(Self::INDEX_FILE), &index) {
+            self.log.warn(&format!("Could not update the SBOM index: {}", e));
+        }
+        self.log
+            .step(&format!("Recorded the SBOM of {} with {}", image, sbom.generator));
+    }
+
+    /// Ids of the deployments with an SBOM, oldest first.
+    pub fn ids(&self) -> Vec<String> {
+        let mut ids: Vec<String> = fs::read_dir(&self.dir)
+            .into_iter()
+            .flatten()
+            .flatten()
+            .filter_map(|entry| {
+                let name = entry.file_name().to_string_lossy().to_string();
+                name.strip_suffix(".json.zst").map(str::to_string)
+            })
+            .collect();
+        // Ids are timestamps, so name order is age order
+        ids.sort();
+        ids
+    }
+
+    /// The SBOM of deployment `id`.
+    pub fn load(&self, id: &str) -> Result<Sbom, String> {
+        // Ids come from the command line, anything but a plain name can't be one
+        if id.is_empty() || !id.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return Err(format!("{} is not a deployment id", id));
+        }
+        let compressed = fs::read(self.path(id)).map_err(|_| format!("No SBOM recorded for deployment {}", id))?;
+        let content = zstd::decode_all(compressed.as_slice()).map_err(|e| e.to_string())?;
+        serde_json::from_slice(&content).map_err(|e| format!("The SBOM of deployment {} is damaged: {}", id, e))
+    }
+
+    /// Where the SBOM of deployment `id` is kept.
+    pub fn path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}.json.zst", id))
+    }
+
+    fn save(&self, sbom: &Sbom) -> Result<(), String> {
+        fs::create_dir_all(&self.dir).map_err(|e| e.to_string())?;
+        let content = serde_json::to_vec(sbom).map_err(|e| e.to_string())?;
+        let compressed = zstd::encode_all(content.as_slice(), 0).map_err(|e| e.to_string())?;
+        store::write_atomic(&self.path(&sbom.deployment_id), &compressed).map_err(|e| e.to_string())
+    }
+}
+
+/// An SBOM of `image` from what the daemon knows of it, with the packages syft lists when it is on PATH.
+async fn generate(log: &Logger, docker: &Docker, id: &str, image: &str) -> Result<Sbom, String> {
+    let inspect = docker.inspect_image(image).await.map_err(|e| e.to_string())?;
+    let labels = inspect
+        .config
+        .as_ref()
+        .and_then(|config| config.labels.clone())
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|(key, _)| key.starts_with(OCI_LABEL_PREFIX))
+        .collect();
+    let history = docker.image_history(image).await.unwrap_or_default();
+    let build_args = history
+        .iter()
+        .flat_map(|step| parse_build_args(&step.created_by))
+        .map(|(key, value)| match is_secret_key(&key) {
+            true => (key, SECRET_MASK.to_string()),
+            false => (key, value),
+        })
+        .collect();
+    // syft reads the image from the same daemon through DOCKER_HOST
+    let source = format!("docker:{}", image);
+    let spdx = match run_fun!(syft version) {
+        Ok(_) => {
+            let spdx = run_fun!(syft --quiet --output spdx-json $source)
+                .map_err(|e| format!("syft failed: {}", e))
+                .and_then(|output| {
+                    serde_json::from_str(&output).map_err(|e| format!("syft wrote something else than JSON: {}", e))
+                });
+            match spdx {
+                Ok(spdx) => Some(spdx),
+                Err(e) => {
+                    log.warn(&format!("{}, the SBOM only lists the layers, labels and build args", e));
+                    None
+                }
+            }
+        }
+        Err(_) => {
+            log.warn("syft is not installed, the SBOM only lists the layers, labels and build args of the image");
+            None
+        }
+    };
+    Ok(Sbom {
+        deployment_id: id.to_string(),
+        image: image.to_string(),
+        image_id: inspect.id,
+        created_at: Utc::now(),
+        generator: if spdx.is_some() { "syft" } else { "docker" }.to_string(),
+        layers: inspect.root_fs.and_then(|root_fs| root_fs.layers).unwrap_or_default(),
+        labels,
+        build_args,
+        spdx,
+        reused_from: None,
+    })
+}
+
+/// The build args of a RUN step in the image history, which records them as `|2 KEY=value OTHER=value`
+/// before the command.
+fn parse_build_args(created_by: &str) -> Vec<(String, String)> {
+    let step = created_by.trim_start_matches("RUN ").trim_start();
+    let Some(rest) = step.strip_prefix('|') else {
+        return vec![];
+    };
+    let mut words = rest.split_whitespace();
+    let count: usize = words.next().and_then(|count| count.parse().ok()).unwrap_or(0);
+    words
+        .take(count)
+        .filter_map(|word| word.split_once('='))
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect()
+}
@@ -0,0 +1,184 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use cmd_lib::run_fun;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::logger::Logger;
+use crate::model::{ScanConfig, Scanner, Severity};
+
+/// Vulnerability counts of an image by severity, kept with the deployment.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScanSummary {
+    pub scanner: Scanner,
+    pub counts: BTreeMap<Severity, usize>,
+}
+
+impl ScanSummary {
+    /// Findings at `fail_on` or worse.
+    pub fn exceeding(&self, fail_on: Severity) -> usize {
+        self.counts
+            .iter()
+            .filter(|(severity, _)| **severity >= fail_on)
+            .map(|(_, count)| count)
+            .sum()
+    }
+
+    /// One line summary, most serious first, e.g. `2 critical, 5 high`.
+    pub fn describe(&self) -> String {
+        if self.counts.is_empty() {
+            return "no vulnerabilities".to_string();
+        }
+        self.counts
+            .iter()
+            .rev()
+            .map(|(severity, count)| format!("{} {}", count, severity))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+/// Count the findings of `trivy image --format json`.
+pub fn parse_trivy(output: &str) -> Result<BTreeMap<Severity, usize>, String> {
+    let report: Value = serde_json::from_str(output).map_err(|e| format!("invalid trivy output: {}", e))?;
+    let severities = report["Results"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .flat_map(|result| result["Vulnerabilities"].as_array().into_iter().flatten())
+        .map(|vulnerability| vulnerability["Severity"].as_str().unwrap_or("unknown"));
+    Ok(count(severities))
+}
+
+/// Count the findings of `docker scout cves --format gitlab`.
+pub fn parse_scout(output: &str) -> Result<BTreeMap<Severity, usize>, String> {
+    let report: Value = serde_json::from_str(output).map_err(|e| format!("invalid docker scout output: {}", e))?;
+    let severities = report["vulnerabilities"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .map(|vulnerability| vulnerability["severity"].as_str().unwrap_or("unknown"));
+    Ok(count(severities))
+}
+
+/// Severities a scanner reports that ruku doesn't know, e.g. `info`, count as unknown.
+fn count<'a>(severities: impl Iterator<Item = &'a str>) -> BTreeMap<Severity, usize> {
+    let mut counts = BTreeMap::new();
+    for severity in severities {
+        *counts.entry(severity.parse().unwrap_or(Severity::Unknown)).or_insert(0) += 1;
+    }
+    counts
+}
+
+/// Scans an image before it is deployed.
+pub struct Scan<'a> {
+    log: &'a Logger,
+    config: &'a ScanConfig,
+}
+
+impl<'a> Scan<'a> {
+    pub fn new(log: &'a Logger, config: &'a ScanConfig) -> Scan<'a> {
+        Scan { log, config }
+    }
+
+    /// Scan `image_name` and print the summary, failing when findings reach the `fail_on` severity.
+    pub fn run(&self, image_name: &str) -> Result<ScanSummary, String> {
+        self.log.step(&format!("Scanning image {}", image_name));
+        let counts = match self.config.scanner {
+            Scanner::Trivy => self.trivy(&[image_name]),
+            Scanner::Scout => self.scout(image_name),
+        };
+        self.check(image_name, counts)
+    }
+
+    /// Scan the OCI archive a multi-platform build of `image_name` wrote, before it is pushed.
+    pub fn run_archive(&self, image_name: &str, archive: &Path) -> Result<ScanSummary, String> {
+        self.log
+            .step(&format!("Scanning image {} in {}", image_name, archive.display()));
+        let counts = match self.config.scanner {
+            Scanner::Trivy => self.trivy(&["--input", &archive.display().to_string()]),
+            Scanner::Scout => self.scout(&format!("oci-archive://{}", archive.display())),
+        };
+        self.check(image_name, counts)
+    }
+
+    fn check(
+        &self,
+        image_name: &str,
+        counts: Result<BTreeMap<Severity, usize>, String>,
+    ) -> Result<ScanSummary, String> {
+        let counts = counts.map_err(|e| format!("Error scanning image {}: {}", image_name, e))?;
+
+        let summary = ScanSummary {
+            scanner: self.config.scanner,
+            counts,
+        };
+        let exceeding = summary.exceeding(self.config.fail_on);
+        if exceeding > 0 {
+            return Err(format!(
+                "Found {}, {} at {} or above, pass --skip-scan to deploy anyway",
+                summary.describe(),
+                exceeding,
+                self.config.fail_on
+            ));
+        }
+        self.log.step(&format!("Scan found {}", summary.describe()));
+        Ok(summary)
+    }
+
+    /// `image` is the image name, or `--input` and the archive.
+    fn trivy(&self, image: &[&str]) -> Result<BTreeMap<Severity, usize>, String> {
+        if run_fun!(trivy version).is_err() {
+            return Err(
+                "trivy is not installed, see https://aquasecurity.github.io/trivy/latest/getting-started/installation/ or set scan.scanner to scout"
+                    .to_string(),
+            );
+        }
+        let output = run_fun!(trivy image --quiet --format json $[image]).map_err(|e| e.to_string())?;
+        parse_trivy(&output)
+    }
+
+    fn scout(&self, image_name: &str) -> Result<BTreeMap<Severity, usize>, String> {
+        if run_fun!(docker scout version).is_err() {
+            return Err(
+                "docker scout is not installed, see https://docs.docker.com/scout/install/ or set scan.scanner to trivy"
+                    .to_string(),
+            );
+        }
+        let output = run_fun!(docker scout cves --format gitlab $image_name).map_err(|e| e.to_string())?;
+        parse_scout(&output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn severities_ruku_doesnt_know_count_as_unknown() {
+        let output = r#"{"vulnerabilities": [
+            {"severity": "Critical"}, {"severity": "Info"}, {"severity": "informational"}, {}
+        ]}"#;
+        let counts = parse_scout(output).unwrap();
+        assert_eq!(counts[&Severity::Critical], 1);
+        assert_eq!(counts[&Severity::Unknown], 3);
+        assert_eq!(counts.len(), 2);
+    }
+
+    #[test]
+    fn trivy_findings_are_counted_across_results() {
+        let output = r#"{"Results": [
+            {"Vulnerabilities": [{"Severity": "HIGH"}, {"Severity": "LOW"}]},
+            {"Target": "no findings"},
+            {"Vulnerabilities": [{"Severity": "HIGH"}, {"Severity": "NEGLIGIBLE"}]}
+        ]}"#;
+        let summary = ScanSummary {
+            scanner: Scanner::Trivy,
+            counts: parse_trivy(output).unwrap(),
+        };
+        assert_eq!(summary.describe(), "2 high, 2 low");
+        assert_eq!(summary.exceeding(Severity::High), 2);
+        assert!(parse_trivy("not json").is_err());
+    }
+}
@@ -0,0 +1,134 @@
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::io::Write;
+use std::sync::{LazyLock, Mutex};
+
+use cmd_lib::run_fun;
+use serde_json::Value;
+
+/// Values resolved by this process, by reference, so a deploy asks each backend once.
+static RESOLVED: LazyLock<Mutex<HashMap<String, String>>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// A secret in a backend, `<scheme>:<path>#<key>` in ruku.yml, e.g. `vault:kv/data/myapp#DB_PASSWORD`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SecretRef {
+    pub scheme: String,
+    pub path: String,
+    pub key: String,
+}
+
+impl SecretRef {
+    pub fn parse(reference: &str) -> Result<SecretRef, String> {
+        let invalid = || {
+            format!(
+                "invalid secret reference '{}', use <scheme>:<path>#<key> with scheme {}",
+                reference,
+                SCHEMES.join(" or ")
+            )
+        };
+        let (scheme, rest) = reference.split_once(':').ok_or_else(invalid)?;
+        let (path, key) = rest.rsplit_once('#').ok_or_else(invalid)?;
+        if !SCHEMES.contains(&scheme) || path.is_empty() || key.is_empty() {
+            return Err(invalid());
+        }
+        Ok(SecretRef {
+            scheme: scheme.to_string(),
+            path: path.to_string(),
+            key: key.to_string(),
+        })
+    }
+}
+
+/// The schemes of the backends ruku can read secrets from.
+pub const SCHEMES: [&str; 2] = [VaultResolver::SCHEME, SopsResolver::SCHEME];
+
+/// A backend secrets are read from. Errors name what failed but never hold a value.
+pub trait SecretResolver {
+    fn resolve(&self, secret: &SecretRef) -> Result<String, String>;
+}
+
+/// Reads the KV engine of HashiCorp Vault at `VAULT_ADDR` with `VAULT_TOKEN`. The path is that of the API
+/// without `/v1`, so `kv/data/myapp` for version 2 of the engine mounted at `kv`.
+pub struct VaultResolver;
+
+impl VaultResolver {
+    pub const SCHEME: &'static str = "vault";
+}
+
+impl SecretResolver for VaultResolver {
+    fn resolve(&self, secret: &SecretRef) -> Result<String, String> {
+        let addr = env::var("VAULT_ADDR").map_err(|_| "VAULT_ADDR is not set")?;
+        let token = env::var("VAULT_TOKEN").map_err(|_| "VAULT_TOKEN is not set")?;
+        // The token goes in a curl config file, so it doesn't show up in the process list
+        let mut config = tempfile::NamedTempFile::new().map_err(|e| e.to_string())?;
+        writeln!(config, "header = \"X-Vault-Token: {}\"", token.replace('"', "")).map_err(|e| e.to_string())?;
+        let config_path = config.path();
+        let url = format!(
+            "{}/v1/{}",
+            addr.trim_end_matches('/'),
+            secret.path.trim_start_matches('/')
+        );
+        let output = run_fun!(curl --silent --fail --max-time 10 --config $config_path $url)
+            .map_err(|_| format!("Vault at {} refused or could not be reached", addr))?;
+        let response: Value =
+            serde_json::from_str(&output).map_err(|_| "Vault answered with something else than JSON")?;
+        // Version 2 of the KV engine nests the secret in another `data`
+        let data = match response.pointer("/data/data") {
+            Some(data) if data.is_object() => data,
+            _ => &response["data"],
+        };
+        match &data[&secret.key] {
+            Value::String(value) => Ok(value.clone()),
+            Value::Null => Err(format!("{} has no key {}", secret.path, secret.key)),
+            value => Ok(value.to_string()),
+        }
+    }
+}
+
+/// Decrypts a file encrypted with SOPS through the `sops` binary, which finds the keys the way it always
+/// does. The key is a dotted path into the file, e.g. `db.password`.
+pub struct SopsResolver;
+
+impl SopsResolver {
+    pub const SCHEME: &'static str = "sops";
+}
+
+impl SecretResolver for SopsResolver {
+    fn resolve(&self, secret: &SecretRef) -> Result<String, String> {
+        let path = &secret.path;
+        let extract: String = secret.key.split('.').map(|part| format!("[\"{}\"]", part)).collect();
+        run_fun!(sops --decrypt --extract $extract $path)
+            .map_err(|_| format!("sops could not decrypt {} from {}", secret.key, secret.path))
+    }
+}
+
+/// The value of every secret of `secrets`, env var names to references, by name. Each reference is
+/// resolved once per process, the error names the reference when one can't be.
+pub fn resolve(secrets: &BTreeMap<String, String>) -> Result<BTreeMap<String, String>, String> {
+    let mut resolved = RESOLVED.lock().unwrap();
+    secrets
+        .iter()
+        .map(|(name, reference)| {
+            if let Some(value) = resolved.get(reference) {
+                return Ok((name.clone(), value.clone()));
+            }
+            let value = SecretRef::parse(reference)
+                .and_then(|secret| match resolver(&secret.scheme) {
+                    Some(resolver) => resolver.resolve(&secret),
+                    None => Err(format!("no backend for {}", secret.scheme)),
+                })
+                .map_err(|e| format!("Could not resolve secret {} ({}): {}", name, reference, e))?;
+            resolved.insert(reference.clone(), value.clone());
+            Ok((name.clone(), value))
+        })
+        .collect()
+}
+
+/// The backend of `scheme`, one of [`SCHEMES`].
+fn resolver(scheme: &str) -> Option<Box<dyn SecretResolver>> {
+    match scheme {
+        VaultResolver::SCHEME => Some(Box::new(VaultResolver)),
+        SopsResolver::SCHEME => Some(Box::new(SopsResolver)),
+        _ => None,
+    }
+}
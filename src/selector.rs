@@ -0,0 +1,134 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::process::Command;
+
+use bollard::models::ContainerSummary;
+
+use crate::auxiliary::is_aux;
+use crate::container::APP_LABEL;
+use crate::executor::Executor;
+use crate::logger::Logger;
+
+/// Labels every container of an app has to carry for `--selector` to pick the app, e.g.
+/// `team=payments,tier=web`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Selector {
+    labels: Vec<(String, String)>,
+}
+
+impl Selector {
+    pub fn parse(selector: &str) -> Result<Selector, String> {
+        let labels = selector
+            .split(',')
+            .map(|pair| match pair.split_once('=') {
+                Some((key, value)) if !key.trim().is_empty() => Ok((key.trim().to_string(), value.trim().to_string())),
+                _ => Err(format!(
+                    "invalid selector '{}', use key=value pairs separated by commas, e.g. team=payments",
+                    selector
+                )),
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Selector { labels })
+    }
+
+    /// Whether `labels` has every label of the selector.
+    pub fn matches(&self, labels: &HashMap<String, String>) -> bool {
+        self.labels
+            .iter()
+            .all(|(key, value)| labels.get(key).is_some_and(|label| label == value))
+    }
+
+    /// Whether the selector picks the container of `summary`, never a one-off container.
+    pub fn picks(&self, summary: &ContainerSummary) -> bool {
+        !is_aux(summary) && summary.labels.as_ref().is_some_and(|labels| self.matches(labels))
+    }
+
+    /// The apps whose containers the selector picks, ascending.
+    pub fn apps(&self, summaries: &[ContainerSummary]) -> Vec<String> {
+        let mut apps: Vec<String> = summaries
+            .iter()
+            .filter(|summary| self.picks(summary))
+            .filter_map(|summary| summary.labels.as_ref()?.get(APP_LABEL).cloned())
+            .collect();
+        apps.sort();
+        apps.dedup();
+        apps
+    }
+}
+
+impl fmt::Display for Selector {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let pairs: Vec<String> = self
+            .labels
+            .iter()
+            .map(|(key, value)| format!("{}={}", key, value))
+            .collect();
+        write!(f, "{}", pairs.join(","))
+    }
+}
+
+/// Runs a command of this ruku once for each app a selector picked, a few at a time. The output of each
+/// app is printed when it is done, prefixed with the app.
+pub struct AppGroup<'a> {
+    log: &'a Logger,
+    apps: Vec<String>,
+    concurrency: usize,
+}
+
+impl<'a> AppGroup<'a> {
+    pub fn new(log: &'a Logger, apps: Vec<String>, concurrency: usize) -> AppGroup<'a> {
+        AppGroup { log, apps, concurrency }
+    }
+
+    /// Run `args`, this process's command line without the program and `--selector`, with `--app` set to
+    /// each app, failing when one of them fails.
+    pub async fn run(self, action: &str, args: &[String]) -> Result<(), String> {
+        let exe = std::env::current_exe().map_err(|e| format!("Could not find the ruku binary: {}", e))?;
+        let total = self.apps.len();
+        let failures = Executor::new(self.log, self.concurrency)
+            .run(action, self.apps, |app| {
+                let mut command = Command::new(&exe);
+                command.args(args).arg("--app").arg(&app);
+                async move {
+                    let output = tokio::task::spawn_blocking(move || command.output())
+                        .await
+                        .map_err(|e| e.to_string())?
+                        .map_err(|e| format!("could not run ruku: {}", e))?;
+                    // One write per app, so the output of apps finishing together doesn't interleave
+                    let mut prefixed = String::new();
+                    for line in String::from_utf8_lossy(&output.stdout)
+                        .lines()
+                        .chain(String::from_utf8_lossy(&output.stderr).lines())
+                    {
+                        prefixed.push_str(&format!("[{}] {}\n", app, line));
+                    }
+                    eprint!("{}", prefixed);
+                    match output.status.code() {
+                        Some(0) => Ok(()),
+                        Some(code) => Err(format!("exited with {}", code)),
+                        None => Err("killed".to_string()),
+                    }
+                }
+            })
+            .await;
+        if !failures.is_empty() {
+            return Err(format!("Failed for {} of {} apps", failures.len(), total));
+        }
+        self.log.step(&format!("Done for {} apps", total));
+        Ok(())
+    }
+}
+
+/// `args` without `--selector` and its value.
+pub fn strip_selector(args: &[String]) -> Vec<String> {
+    let mut stripped = vec![];
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "--selector" {
+            args.next();
+        } else if !arg.starts_with("--selector=") {
+            stripped.push(arg.clone());
+        }
+    }
+    stripped
+}
@@ -1,24 +1,284 @@
-use std::path::PathBuf;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::auxiliary::DEFAULT_AUX_MAX_AGE;
+use crate::connection::check_context;
+use crate::freeze::FreezeWindow;
+use crate::policy::PolicyConfig;
+use crate::units;
+
+pub use crate::host_config::{HostConfig, Reservation};
+
+/// Settings shared by every app on the host, read from `~/.ruku/config.yml` when it exists.
+#[derive(Deserialize)]
+struct GlobalConfig {
+    /// How many deploys may build or pull at the same time, unlimited when not set.
+    max_concurrent_deploys: Option<usize>,
+    /// Seconds a deploy waits for a slot before giving up. Like the other times, also takes a duration like
+    /// `10m`.
+    #[serde(default = "default_deploy_slot_timeout", deserialize_with = "units::seconds")]
+    deploy_slot_timeout: u64,
+    /// How many config snapshots to keep per app.
+    #[serde(default = "default_release_retention")]
+    release_retention: usize,
+    /// How many replaced releases per app keep their logs for `ruku logs --release`, 0 keeps none.
+    #[serde(default = "default_release_log_retention")]
+    release_log_retention: usize,
+    /// Seconds the backup of a container before ruku removes it may take.
+    #[serde(default = "default_backup_timeout", deserialize_with = "units::seconds")]
+    backup_timeout: u64,
+    /// Seconds a failed deploy may spend keeping the logs of its containers before rolling back.
+    #[serde(default = "default_capture_timeout", deserialize_with = "units::seconds")]
+    capture_timeout: u64,
+    /// Seconds a one-off container such as a probe may exist before it is taken for a leftover.
+    #[serde(default = "default_aux_max_age", deserialize_with = "units::seconds")]
+    aux_max_age: u64,
+    /// OTLP/HTTP collector deploy traces are sent to, `OTEL_EXPORTER_OTLP_ENDPOINT` takes precedence.
+    otel_endpoint: Option<String>,
+    /// Endpoint told with a JSON notice when the Docker event stream is lost for good, e.g. a chat webhook.
+    events_webhook: Option<String>,
+    /// Daemons ruku can deploy to by name, picked with `--context` or the `context` of an app.
+    #[serde(default)]
+    contexts: BTreeMap<String, ContextConfig>,
+    /// Refuse every command that changes Docker or ruku state, for accounts that only look.
+    #[serde(default)]
+    read_only: bool,
+    /// The HTTP endpoints of `ruku server`.
+    #[serde(default)]
+    server: ServeConfig,
+    /// Where `ruku version --check` looks for newer releases.
+    #[serde(default)]
+    update_check: UpdateCheckConfig,
+    /// Times commands that change state are refused in for every app, like the `freeze` of ruku.yml.
+    #[serde(default)]
+    freeze: Vec<FreezeWindow>,
+    /// Rules the image of every app has to follow, the `policy` of ruku.yml can add to them.
+    policy: Option<PolicyConfig>,
+}
+
+/// The release endpoint `ruku version --check` asks, nothing else ever does.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct UpdateCheckConfig {
+    /// Set to false on air-gapped hosts, the check then makes no request at all.
+    #[serde(default = "default_update_check_enabled")]
+    pub enabled: bool,
+    /// An endpoint answering like the GitHub API for the latest release, with a `tag_name`.
+    #[serde(default = "default_update_check_url")]
+    pub url: String,
+}
+
+impl Default for UpdateCheckConfig {
+    fn default() -> Self {
+        UpdateCheckConfig {
+            enabled: default_update_check_enabled(),
+            url: default_update_check_url(),
+        }
+    }
+}
+
+fn default_update_check_enabled() -> bool {
+    true
+}
+
+fn default_update_check_url() -> String {
+    "https://api.github.com/repos/Joker666/ruku/releases/latest".to_string()
+}
+
+/// Where `ruku server` listens and who it answers.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct ServeConfig {
+    /// Address and port to listen on.
+    #[serde(default = "default_listen")]
+    pub listen: String,
+    /// Token requests must send as `Authorization: Bearer <token>`.
+    pub token: Option<String>,
+    /// Answer `/healthz` and `/apps/healthz` without the token, e.g. for a load balancer.
+    #[serde(default)]
+    pub healthz_public: bool,
+}
+
+impl Default for ServeConfig {
+    fn default() -> Self {
+        ServeConfig {
+            listen: default_listen(),
+            token: None,
+            healthz_public: false,
+        }
+    }
+}
+
+fn default_listen() -> String {
+    "127.0.0.1:7467".to_string()
+}
+
+/// A Docker daemon ruku deploys to, on this host or another one.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct ContextConfig {
+    /// The daemon address, a `unix://` socket, a `tcp://` address or `ssh://[user@]host[:port]`.
+    pub host: String,
+    /// The certificates to reach a `tcp://` daemon run with `--tlsverify` with.
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
+    /// Apps deployed to this daemon when neither `--context` nor their config picks one.
+    #[serde(default)]
+    pub apps: Vec<String>,
+    /// Only look at this daemon, commands that change it are refused.
+    #[serde(default)]
+    pub read_only: bool,
+}
+
+/// Paths of the PEM files of a TLS connection to a daemon, like the `--tlscacert`, `--tlscert` and
+/// `--tlskey` options of the docker CLI.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// The CA the certificate of the daemon is checked against.
+    pub ca: PathBuf,
+    /// The client certificate and its key, signed by a CA the daemon trusts.
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+impl Default for GlobalConfig {
+    fn default() -> Self {
+        GlobalConfig {
+            max_concurrent_deploys: None,
+            deploy_slot_timeout: default_deploy_slot_timeout(),
+            release_retention: default_release_retention(),
+            release_log_retention: default_release_log_retention(),
+            backup_timeout: default_backup_timeout(),
+            capture_timeout: default_capture_timeout(),
+            aux_max_age: DEFAULT_AUX_MAX_AGE,
+            otel_endpoint: None,
+            events_webhook: None,
+            contexts: BTreeMap::new(),
+            read_only: false,
+            server: ServeConfig::default(),
+            update_check: UpdateCheckConfig::default(),
+            freeze: vec![],
+            policy: None,
+        }
+    }
+}
+
+fn default_deploy_slot_timeout() -> u64 {
+    1800
+}
+
+fn default_release_retention() -> usize {
+    50
+}
+
+fn default_release_log_retention() -> usize {
+    10
+}
+
+fn default_backup_timeout() -> u64 {
+    600
+}
+
+fn default_capture_timeout() -> u64 {
+    30
+}
+
+fn default_aux_max_age() -> u64 {
+    DEFAULT_AUX_MAX_AGE
+}
 
 pub struct ServerConfig {
     pub ruku_root: PathBuf,
     pub ruku_binary: PathBuf,
     pub data_root: PathBuf,
+    pub state_root: PathBuf,
     pub git_root: PathBuf,
     pub apps_root: PathBuf,
+    pub max_concurrent_deploys: Option<usize>,
+    pub deploy_slot_timeout: u64,
+    pub release_retention: usize,
+    pub release_log_retention: usize,
+    pub backup_timeout: u64,
+    pub capture_timeout: u64,
+    pub aux_max_age: u64,
+    pub otel_endpoint: Option<String>,
+    pub events_webhook: Option<String>,
+    pub contexts: BTreeMap<String, ContextConfig>,
+    pub read_only: bool,
+    pub server: ServeConfig,
+    pub update_check: UpdateCheckConfig,
+    pub freeze: Vec<FreezeWindow>,
+    pub policy: Option<PolicyConfig>,
+    /// Port ranges reserved on the host, from `/etc/ruku/host.yml` or `~/.config/ruku/host.yml`.
+    pub host: HostConfig,
 }
 
 impl ServerConfig {
+    pub const FILE_NAME: &'static str = "config.yml";
+
     pub fn new() -> Result<Self, Box<dyn std::error::Error>> {
         let home_dir = home::home_dir().ok_or("Could not determine home directory")?;
+        Self::at_home(&home_dir)
+    }
+
+    /// The config of ruku run by the user with `home_dir`.
+    pub fn at_home(home_dir: &Path) -> Result<Self, Box<dyn std::error::Error>> {
         let ruku_root = home_dir.join(".ruku");
 
+        let config_path = ruku_root.join(Self::FILE_NAME);
+        let global: GlobalConfig = match fs::read_to_string(&config_path) {
+            Ok(content) => serde_yaml::from_str(&content).map_err(|e| format!("{}: {}", config_path.display(), e))?,
+            Err(_) => GlobalConfig::default(),
+        };
+        if global.max_concurrent_deploys == Some(0) {
+            return Err(format!("{}: max_concurrent_deploys must be at least 1", config_path.display()).into());
+        }
+
+        for (name, context) in &global.contexts {
+            check_context(context).map_err(|e| format!("{}: context {}: {}", config_path.display(), name, e))?;
+        }
+        let mut defaults: BTreeMap<&str, &str> = BTreeMap::new();
+        for (name, context) in &global.contexts {
+            for app in &context.apps {
+                if let Some(other) = defaults.insert(app, name) {
+                    return Err(format!(
+                        "{}: app {} is listed under both context {} and {}",
+                        config_path.display(),
+                        app,
+                        other,
+                        name
+                    )
+                    .into());
+                }
+            }
+        }
+
+        let host = HostConfig::load(&HostConfig::search_paths(Some(home_dir)))?;
+
         Ok(ServerConfig {
             ruku_root: home_dir.join(".ruku"),
             ruku_binary: PathBuf::from("/usr/bin/ruku"),
             data_root: ruku_root.join("data"),
+            state_root: ruku_root.join("state"),
             git_root: ruku_root.join("repos"),
             apps_root: home_dir.join("apps"),
+            max_concurrent_deploys: global.max_concurrent_deploys,
+            deploy_slot_timeout: global.deploy_slot_timeout,
+            release_retention: global.release_retention,
+            release_log_retention: global.release_log_retention,
+            backup_timeout: global.backup_timeout,
+            capture_timeout: global.capture_timeout,
+            aux_max_age: global.aux_max_age,
+            otel_endpoint: global.otel_endpoint,
+            events_webhook: global.events_webhook,
+            contexts: global.contexts,
+            read_only: global.read_only,
+            server: global.server,
+            update_check: global.update_check,
+            freeze: global.freeze,
+            policy: global.policy,
+            host,
         })
     }
 }
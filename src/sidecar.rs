@@ -0,0 +1,293 @@
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+use bollard::container::{CreateContainerOptions, RemoveContainerOptions, StartContainerOptions};
+use bollard::models::{ContainerStateStatusEnum, HealthStatusEnum};
+use bollard::Docker;
+
+use crate::container::{test_labels, Container, Role, APP_LABEL, ROLE_LABEL, SCHEMA_LABEL, SCHEMA_VERSION};
+use crate::daemon_error;
+use crate::diff::Change;
+use crate::image::Image;
+use crate::logger::Logger;
+use crate::model::{publish_address, NetworkMode, RukuConfig, SidecarConfig};
+use crate::network::Networks;
+use crate::spec::{ContainerSpec, Inherited, PortSpec};
+use crate::volume::{HostPaths, Volumes};
+
+/// Label holding the sidecar name of a sidecar container.
+pub const SIDECAR_LABEL: &str = "ruku.sidecar";
+
+/// How long a started sidecar has to become healthy, or stay up when its image has no healthcheck.
+const READY_TIMEOUT: Duration = Duration::from_secs(60);
+const READY_POLL_INTERVAL: Duration = Duration::from_millis(500);
+const SETTLE_TIME: Duration = Duration::from_secs(3);
+
+/// The sidecars of an app, started on its network before the app container.
+pub struct Sidecars<'a> {
+    log: &'a Logger,
+    name: &'a str,
+    docker: &'a Docker,
+    config: &'a RukuConfig,
+    container: &'a Container<'a>,
+}
+
+impl<'a> Sidecars<'a> {
+    pub fn new(
+        log: &'a Logger,
+        name: &'a str,
+        docker: &'a Docker,
+        config: &'a RukuConfig,
+        container: &'a Container<'a>,
+    ) -> Sidecars<'a> {
+        Sidecars {
+            log,
+            name,
+            docker,
+            config,
+            container,
+        }
+    }
+
+    /// The configured sidecar by `name`, an error when the app has none by that name.
+    pub fn find(&self, name: &str) -> Result<&'a SidecarConfig, String> {
+        self.config
+            .sidecars
+            .iter()
+            .find(|sidecar| sidecar.name == name)
+            .ok_or_else(|| {
+                let names: Vec<&str> = self.config.sidecars.iter().map(|s| s.name.as_str()).collect();
+                format!(
+                    "{} has no sidecar {}, it has: {}",
+                    self.name,
+                    name,
+                    if names.is_empty() {
+                        "none".to_string()
+                    } else {
+                        names.join(", ")
+                    }
+                )
+            })
+    }
+
+    pub fn container_name(&self, sidecar: &SidecarConfig) -> String {
+        format!("{}-{}", self.container.container_name(), sidecar.name)
+    }
+
+    /// Bring every sidecar in line with the config before the app container is replaced. A sidecar that
+    /// matches its spec and runs is left alone, any other is recreated. Fails when a sidecar does not
+    /// come up.
+    pub async fn ensure(&self) -> Result<(), String> {
+        if self.config.sidecars.is_empty() {
+            return Ok(());
+        }
+        Volumes::new(self.log, self.name, self.docker)
+            .ensure(&self.config.all_volume_specs())
+            .await?;
+        let networks = Networks::new(self.log, self.docker);
+        match &self.config.network_mode {
+            NetworkMode::Bridge => networks.ensure(self.name, self.config.internal).await?,
+            NetworkMode::Custom(name) => networks.require(name).await?,
+            NetworkMode::Host | NetworkMode::None => {}
+        }
+        let image = Image::new(self.log, self.docker).with_mirror(self.config.registry_mirror.as_deref());
+        for sidecar in &self.config.sidecars {
+            let container_name = self.container_name(sidecar);
+            let drift = self.drift(sidecar).await;
+            match drift {
+                Some(drift) if drift.is_empty() && self.is_running(&container_name).await => {
+                    self.log.step(&format!("Sidecar {} is up to date", container_name));
+                    continue;
+                }
+                Some(drift) => {
+                    if !drift.is_empty() {
+                        let fields: Vec<&str> = drift.iter().map(|change| change.path.as_str()).collect();
+                        self.log
+                            .step(&format!("Sidecar {} changed: {}", container_name, fields.join(", ")));
+                    }
+                    self.remove(&container_name).await?;
+                }
+                None => {}
+            }
+            if !image.exists(&sidecar.image).await {
+                image.pull(&sidecar.image).await?;
+            }
+            if self.config.create_host_paths.enabled {
+                let host_paths = HostPaths::new(self.log, &self.config.create_host_paths);
+                let image_user = image.defaults(&sidecar.image).await.and_then(|defaults| defaults.user);
+                host_paths.ensure(&sidecar.volume_specs(), host_paths.owner(image_user.as_deref()))?;
+            }
+            let options = CreateContainerOptions {
+                name: container_name.as_str(),
+                platform: None,
+            };
+            self.docker
+                .create_container(Some(options), self.spec(sidecar).to_create_config())
+                .await
+                .map_err(|e| {
+                    let explained = daemon_error::report(self.log, &e, self.name, self.config);
+                    format!("Failed to create sidecar {}: {}", container_name, explained)
+                })?;
+            self.docker
+                .start_container(&container_name, None::<StartContainerOptions<String>>)
+                .await
+                .map_err(|e| {
+                    let explained = daemon_error::report(self.log, &e, self.name, self.config);
+                    format!("Failed to start sidecar {}: {}", container_name, explained)
+                })?;
+            self.wait_ready(sidecar).await?;
+            self.log.step(&format!("Started sidecar {}", container_name));
+        }
+        Ok(())
+    }
+
+    /// How the live sidecar differs from its spec, none when it does not exist.
+    pub async fn drift(&self, sidecar: &SidecarConfig) -> Option<Vec<Change>> {
+        let live = self
+            .docker
+            .inspect_container(&self.container_name(sidecar), None)
+            .await
+            .ok()?;
+        let image = match live.image.as_deref() {
+            Some(image_id) => self.docker.inspect_image(image_id).await.ok(),
+            None => None,
+        };
+        Some(
+            self.spec(sidecar)
+                .diff(&ContainerSpec::from_inspect(&live, image.as_ref())),
+        )
+    }
+
+    /// What the sidecar container should look like according to the app config.
+    pub fn spec(&self, sidecar: &SidecarConfig) -> ContainerSpec {
+        let network = self.container.network();
+        let publishes = self.config.network_mode.publishes_ports();
+        let ports = sidecar
+            .ports
+            .iter()
+            .filter(|_| publishes)
+            .flat_map(|port| {
+                let host_ip = publish_address(port.host_ip.or(self.config.bind_ip));
+                port.protocols.iter().map(move |protocol| PortSpec {
+                    container_port: port.number,
+                    protocol: protocol.to_string(),
+                    host_ip: host_ip.clone(),
+                    host_port: port.host_port,
+                })
+            })
+            .collect();
+        ContainerSpec {
+            image: sidecar.image.clone(),
+            ports,
+            env: sidecar.env.clone(),
+            labels: BTreeMap::from_iter(
+                [
+                    (APP_LABEL.to_string(), self.name.to_string()),
+                    (ROLE_LABEL.to_string(), Role::Sidecar.as_str().to_string()),
+                    (SIDECAR_LABEL.to_string(), sidecar.name.clone()),
+                    (SCHEMA_LABEL.to_string(), SCHEMA_VERSION.to_string()),
+                ]
+                .into_iter()
+                .chain(test_labels()),
+            ),
+            restart_policy: None,
+            binds: sidecar
+                .volume_specs()
+                .iter()
+                .map(|volume| volume.to_bind(self.name))
+                .collect(),
+            networks: network.iter().cloned().collect(),
+            network_mode: network.clone().unwrap_or(self.config.network_mode.to_string()),
+            publish_all: false,
+            aliases: match network {
+                Some(_) => vec![sidecar.name.clone()],
+                None => vec![],
+            },
+            pids_limit: None,
+            oom_score_adj: None,
+            oom_kill_disable: false,
+            cpuset_cpus: None,
+            cpuset_mems: None,
+            tty: false,
+            open_stdin: false,
+            ipc_mode: None,
+            pid_mode: None,
+            uts_mode: None,
+            inherited: Inherited::default(),
+        }
+        .with_config_hash()
+    }
+
+    async fn is_running(&self, container_name: &str) -> bool {
+        self.docker
+            .inspect_container(container_name, None)
+            .await
+            .ok()
+            .and_then(|inspect| inspect.state)
+            .and_then(|state| state.running)
+            .unwrap_or(false)
+    }
+
+    async fn remove(&self, container_name: &str) -> Result<(), String> {
+        let options = RemoveContainerOptions {
+            force: true,
+            ..Default::default()
+        };
+        self.docker
+            .remove_container(container_name, Some(options))
+            .await
+            .map_err(|e| format!("Failed to remove sidecar {}: {}", container_name, e))?;
+        self.log.step(&format!("Removed sidecar {}", container_name));
+        Ok(())
+    }
+
+    /// Wait until the sidecar passes its healthcheck, or has stayed up for a few seconds without one.
+    async fn wait_ready(&self, sidecar: &SidecarConfig) -> Result<(), String> {
+        let container_name = &self.container_name(sidecar);
+        let deadline = Instant::now() + READY_TIMEOUT;
+        let mut running_since: Option<Instant> = None;
+        loop {
+            let state = self
+                .docker
+                .inspect_container(container_name, None)
+                .await
+                .map_err(|e| format!("Failed to inspect sidecar {}: {}", container_name, e))?
+                .state
+                .unwrap_or_default();
+            let health = state.health.and_then(|health| health.status);
+            match health {
+                Some(HealthStatusEnum::HEALTHY) => return Ok(()),
+                Some(HealthStatusEnum::UNHEALTHY) => {
+                    return Err(format!("Sidecar {} is unhealthy", container_name));
+                }
+                _ => {}
+            }
+            if state.running.unwrap_or(false) {
+                let since = *running_since.get_or_insert_with(Instant::now);
+                let has_healthcheck = health.is_some_and(|status| status != HealthStatusEnum::NONE);
+                if !has_healthcheck && since.elapsed() >= SETTLE_TIME {
+                    return Ok(());
+                }
+            } else if matches!(
+                state.status,
+                Some(ContainerStateStatusEnum::EXITED | ContainerStateStatusEnum::DEAD)
+            ) {
+                return Err(format!(
+                    "Sidecar {} exited with code {}, see `ruku logs {} --sidecar {}`",
+                    container_name,
+                    state.exit_code.unwrap_or_default(),
+                    self.name,
+                    sidecar.name
+                ));
+            }
+            if Instant::now() >= deadline {
+                return Err(format!(
+                    "Sidecar {} was not ready after {}s",
+                    container_name,
+                    READY_TIMEOUT.as_secs()
+                ));
+            }
+            tokio::time::sleep(READY_POLL_INTERVAL).await;
+        }
+    }
+}
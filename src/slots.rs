@@ -0,0 +1,119 @@
+use std::fs::{self, File, OpenOptions};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::logger::Logger;
+use crate::server_config::ServerConfig;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// How often a queued deploy reports its position.
+const REPORT_INTERVAL: Duration = Duration::from_secs(10);
+const QUEUE_DIR: &str = "queue";
+
+/// A held deploy slot. The lock belongs to the open file, so it is released when the slot is dropped or
+/// the process ends in any way, including a panic, `exit` or a kill.
+pub struct DeploySlot {
+    _file: File,
+}
+
+/// A counted lock shared by the deploys of every app on the host, taken around the phases that load the
+/// Docker daemon such as builds and pulls.
+pub struct DeploySlots<'a> {
+    log: &'a Logger,
+    dir: PathBuf,
+    limit: Option<usize>,
+    timeout: Duration,
+}
+
+impl<'a> DeploySlots<'a> {
+    pub const DIR_NAME: &'static str = "slots";
+
+    pub fn new(log: &'a Logger, server_config: &ServerConfig) -> DeploySlots<'a> {
+        DeploySlots {
+            log,
+            dir: server_config.ruku_root.join(Self::DIR_NAME),
+            limit: server_config.max_concurrent_deploys,
+            timeout: Duration::from_secs(server_config.deploy_slot_timeout),
+        }
+    }
+
+    /// Wait for a free slot, none is taken when `max_concurrent_deploys` is not set. Deploys are served
+    /// in the order they started waiting, failing once the timeout passes.
+    pub async fn acquire(&self) -> Result<Option<DeploySlot>, String> {
+        let Some(limit) = self.limit else {
+            return Ok(None);
+        };
+        let queue_dir = self.dir.join(QUEUE_DIR);
+        fs::create_dir_all(&queue_dir).map_err(|e| format!("Error creating directory: {}", e))?;
+
+        // A locked ticket marks this deploy as waiting, tickets nobody holds are left by dead processes
+        let started_at = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let ticket_name = format!("{:020}-{}", started_at.as_nanos(), std::process::id());
+        let ticket_path = queue_dir.join(&ticket_name);
+        let ticket = self.lock(&ticket_path).ok_or("Error creating deploy queue ticket")?;
+
+        let started = Instant::now();
+        let mut last_report: Option<Instant> = None;
+        loop {
+            let position = self.position(&queue_dir, &ticket_name);
+            if position == 1 {
+                if let Some(slot) = (0..limit).find_map(|i| self.lock(&self.dir.join(format!("slot-{}.lock", i)))) {
+                    drop(ticket);
+                    let _ = fs::remove_file(&ticket_path);
+                    if last_report.is_some() {
+                        self.log
+                            .step(&format!("Got a deploy slot after {}s", started.elapsed().as_secs()));
+                    }
+                    return Ok(Some(DeploySlot { _file: slot }));
+                }
+            }
+
+            if started.elapsed() >= self.timeout {
+                drop(ticket);
+                let _ = fs::remove_file(&ticket_path);
+                return Err(format!(
+                    "Timed out after {}s waiting for a deploy slot, {} deploys may run at once",
+                    self.timeout.as_secs(),
+                    limit
+                ));
+            }
+            if last_report.is_none_or(|at| at.elapsed() >= REPORT_INTERVAL) {
+                self.log
+                    .step(&format!("Waiting for a deploy slot (position {})", position));
+                last_report = Some(Instant::now());
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    }
+
+    /// Place of `ticket_name` among the live tickets, 1 when no one queued before it.
+    fn position(&self, queue_dir: &Path, ticket_name: &str) -> usize {
+        let mut earlier = 0;
+        for entry in fs::read_dir(queue_dir).into_iter().flatten().flatten() {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if name.as_str() >= ticket_name {
+                continue;
+            }
+            match self.lock(&entry.path()) {
+                Some(stale) => {
+                    drop(stale);
+                    let _ = fs::remove_file(entry.path());
+                }
+                None => earlier += 1,
+            }
+        }
+        earlier + 1
+    }
+
+    /// Open `path` and take its lock without waiting, none when another process holds it.
+    fn lock(&self, path: &Path) -> Option<File> {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path)
+            .ok()?;
+        file.try_lock().ok()?;
+        Some(file)
+    }
+}
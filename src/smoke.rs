@@ -0,0 +1,204 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use bollard::Docker;
+use serde::{Deserialize, Serialize};
+
+use crate::logger::Logger;
+use crate::model::{SmokeCheck, SmokeConfig};
+use crate::probe::{exchange, exec_script, quote, run_on_network, socket_address, ProbeTarget};
+
+/// How a smoke check went, kept in the deployment history.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SmokeResult {
+    pub method: String,
+    pub path: String,
+    pub passed: bool,
+    /// Status of the last response, none when nothing answered.
+    pub status: Option<u16>,
+    pub attempts: u32,
+    /// Why the last attempt failed.
+    pub error: Option<String>,
+}
+
+/// Check a raw HTTP response against what the check expects, returning its status either way when
+/// there is one.
+pub fn check_response(response: &str, check: &SmokeCheck) -> (Option<u16>, Result<(), String>) {
+    let (head, body) = response
+        .split_once("\r\n\r\n")
+        .or_else(|| response.split_once("\n\n"))
+        .unwrap_or((response, ""));
+    let mut lines = head
+        .lines()
+        .map(str::trim)
+        .skip_while(|line| !line.starts_with("HTTP/"));
+    let status = lines
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|code| code.parse::<u16>().ok());
+    let Some(status) = status else {
+        return (None, Err("no HTTP response".to_string()));
+    };
+
+    let expected = match check.status {
+        Some(expected) => expected == status,
+        None => (200..400).contains(&status),
+    };
+    if !expected {
+        let wanted = check
+            .status
+            .map_or("2xx or 3xx".to_string(), |status| status.to_string());
+        return (Some(status), Err(format!("expected status {}, got {}", wanted, status)));
+    }
+    let headers: Vec<(String, &str)> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_ascii_lowercase(), value.trim()))
+        .collect();
+    for (name, value) in &check.headers {
+        let found = headers
+            .iter()
+            .find(|(header, _)| *header == name.to_ascii_lowercase())
+            .map(|(_, value)| *value);
+        match found {
+            Some(found) if found.contains(value.as_str()) => {}
+            Some(found) => {
+                return (
+                    Some(status),
+                    Err(format!(
+                        "header {} is '{}', expected it to contain '{}'",
+                        name, found, value
+                    )),
+                )
+            }
+            None => return (Some(status), Err(format!("header {} is missing", name))),
+        }
+    }
+    if let Some(text) = &check.body {
+        if !body.contains(text.as_str()) {
+            return (Some(status), Err(format!("body does not contain '{}'", text)));
+        }
+    }
+    (Some(status), Ok(()))
+}
+
+/// Runs the smoke checks of an app against a freshly started container.
+pub struct SmokeTests<'a> {
+    log: &'a Logger,
+    docker: &'a Docker,
+    config: &'a SmokeConfig,
+}
+
+impl<'a> SmokeTests<'a> {
+    pub fn new(log: &'a Logger, docker: &'a Docker, config: &'a SmokeConfig) -> SmokeTests<'a> {
+        SmokeTests { log, docker, config }
+    }
+
+    /// Run every check, trying failing ones again up to the configured retries, and print the results.
+    /// Checks go through the published host port when there is one and to the container directly
+    /// otherwise.
+    pub async fn run(&self, target: &ProbeTarget) -> Vec<SmokeResult> {
+        self.log.step(&format!(
+            "Running {} smoke checks against {}",
+            self.config.checks.len(),
+            target.container_name
+        ));
+        let mut results = vec![];
+        for check in &self.config.checks {
+            let mut result = SmokeResult {
+                method: check.method.clone(),
+                path: check.path.clone(),
+                passed: false,
+                status: None,
+                attempts: 0,
+                error: None,
+            };
+            while result.attempts <= self.config.retries {
+                if result.attempts > 0 {
+                    tokio::time::sleep(Duration::from_secs(self.config.interval)).await;
+                }
+                result.attempts += 1;
+                let (status, outcome) = match self.request(check, target).await {
+                    Ok(response) => check_response(&response, check),
+                    Err(e) => (None, Err(e)),
+                };
+                result.status = status;
+                result.passed = outcome.is_ok();
+                result.error = outcome.err();
+                if result.passed {
+                    break;
+                }
+            }
+            results.push(result);
+        }
+        self.print(&results);
+        results
+    }
+
+    fn print(&self, results: &[SmokeResult]) {
+        let width = results
+            .iter()
+            .map(|result| result.method.len() + result.path.len() + 1)
+            .max()
+            .unwrap_or_default();
+        for result in results {
+            let request = format!("{} {}", result.method, result.path);
+            let status = result.status.map_or("-".to_string(), |status| status.to_string());
+            let line = format!(
+                "{} {:<width$} {:<3} {} attempt{}{}",
+                if result.passed { "PASS" } else { "FAIL" },
+                request,
+                status,
+                result.attempts,
+                if result.attempts == 1 { "" } else { "s" },
+                result.error.as_ref().map(|e| format!(", {}", e)).unwrap_or_default(),
+            );
+            if result.passed {
+                self.log.step(&line);
+            } else {
+                self.log.warn(&line);
+            }
+        }
+    }
+
+    /// The raw response to the check's request.
+    async fn request(&self, check: &SmokeCheck, target: &ProbeTarget) -> Result<String, String> {
+        if let Some((ip, port)) = &target.host {
+            let address = socket_address(ip, *port)?;
+            let request = build_request(check, &host_header(&address));
+            return exchange(address, Some(&request), Duration::from_secs(self.config.timeout)).await;
+        }
+        let host = if target.network == "none" {
+            "127.0.0.1"
+        } else {
+            target.container_name.as_str()
+        };
+        let request = quote(&build_request(check, host).replace("\r\n", "\\r\\n"));
+        let (timeout, port) = (self.config.timeout, target.container_port);
+        let script = format!(
+            "if command -v nc >/dev/null 2>&1; then printf '%b' {request} | nc -w {timeout} {host} {port}; \
+             else bash -c 'exec 3<>/dev/tcp/{host}/{port} && printf \"%b\" \"$0\" >&3 && cat <&3' {request}; fi"
+        );
+        // Nothing outside the container can reach it without a network
+        let (output, _) = if target.network == "none" {
+            exec_script(self.docker, &target.container_name, script).await?
+        } else {
+            run_on_network(self.log, self.docker, &self.config.image, target, script).await?
+        };
+        Ok(output)
+    }
+}
+
+/// The host of `address` as a `Host` header takes it, an IPv6 address in brackets.
+pub fn host_header(address: &SocketAddr) -> String {
+    match address {
+        SocketAddr::V4(address) => address.ip().to_string(),
+        SocketAddr::V6(address) => format!("[{}]", address.ip()),
+    }
+}
+
+fn build_request(check: &SmokeCheck, host: &str) -> String {
+    format!(
+        "{} {} HTTP/1.0\r\nHost: {}\r\nConnection: close\r\nContent-Length: 0\r\n\r\n",
+        check.method, check.path, host
+    )
+}
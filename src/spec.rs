@@ -0,0 +1,679 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+use bollard::container::NetworkingConfig;
+use bollard::models::{ContainerInspectResponse, EndpointSettings, HostConfig, ImageInspect, PortBinding, PortMap};
+
+use crate::container::{CONFIG_HASH_LABEL, DEPLOY_MESSAGE_LABEL, SCHEMA_LABEL};
+use crate::cpuset;
+use crate::diff::{self, parse_env, Change};
+use crate::sha256::sha256_hex;
+
+/// Version of the canonical form the config hash is computed over. It only changes when the form has to,
+/// and containers hashed with another version are recreated once after the upgrade.
+pub const CONFIG_HASH_VERSION: u32 = 1;
+
+/// A published port of a container.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PortSpec {
+    pub container_port: u16,
+    pub protocol: String,
+    pub host_ip: Option<String>,
+    pub host_port: u16,
+}
+
+impl fmt::Display for PortSpec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.host_ip {
+            Some(ip) if ip.contains(':') => write!(
+                f,
+                "[{}]:{}->{}/{}",
+                ip, self.host_port, self.container_port, self.protocol
+            ),
+            Some(ip) => write!(
+                f,
+                "{}:{}->{}/{}",
+                ip, self.host_port, self.container_port, self.protocol
+            ),
+            None => write!(f, "{}->{}/{}", self.host_port, self.container_port, self.protocol),
+        }
+    }
+}
+
+/// The env and labels a container inherits from its image, Docker merges them into its config.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Inherited {
+    pub env: BTreeMap<String, String>,
+    pub labels: BTreeMap<String, String>,
+}
+
+impl Inherited {
+    pub fn from_image(image: Option<&ImageInspect>) -> Inherited {
+        let image_config = image.and_then(|image| image.config.clone()).unwrap_or_default();
+        Inherited {
+            env: parse_env(image_config.env.as_deref().unwrap_or_default()),
+            labels: image_config.labels.unwrap_or_default().into_iter().collect(),
+        }
+    }
+
+    /// `env` and `labels` without the entries that only repeat what the image sets.
+    fn strip(
+        &self,
+        env: BTreeMap<String, String>,
+        labels: BTreeMap<String, String>,
+    ) -> (BTreeMap<String, String>, BTreeMap<String, String>) {
+        let env = env.into_iter().filter(|(k, v)| self.env.get(k) != Some(v)).collect();
+        let labels = labels
+            .into_iter()
+            .filter(|(k, v)| self.labels.get(k) != Some(v))
+            .collect();
+        (env, labels)
+    }
+}
+
+/// Everything ruku manages on a container, derived from the app config.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ContainerSpec {
+    pub image: String,
+    pub ports: Vec<PortSpec>,
+    pub env: BTreeMap<String, String>,
+    pub labels: BTreeMap<String, String>,
+    pub restart_policy: Option<String>,
+    /// Volume and bind mounts in Docker's `source:target[:ro]` form.
+    pub binds: Vec<String>,
+    /// Networks the container is attached to, the first is the one it is created on.
+    pub networks: Vec<String>,
+    /// Docker's network mode, the first network or `host` or `none`.
+    pub network_mode: String,
+    /// Publish every port the image exposes on a random host port, ruku keeps this off.
+    pub publish_all: bool,
+    /// Names the container is reachable by on its own network.
+    pub aliases: Vec<String>,
+    pub pids_limit: Option<i64>,
+    pub oom_score_adj: Option<i64>,
+    pub oom_kill_disable: bool,
+    /// CPUs and memory nodes in Docker's list form, e.g. `0-2,4`.
+    pub cpuset_cpus: Option<String>,
+    pub cpuset_mems: Option<String>,
+    pub tty: bool,
+    pub open_stdin: bool,
+    /// Namespace modes in Docker's form, e.g. `host` or `container:<name>`, none for Docker's default.
+    pub ipc_mode: Option<String>,
+    pub pid_mode: Option<String>,
+    pub uts_mode: Option<String>,
+    /// What the image of a live container set, stripped from its env and labels. Empty for a spec from
+    /// the config, it is not part of the hash or the diff.
+    pub inherited: Inherited,
+}
+
+impl ContainerSpec {
+    /// The spec as `field=value` lines in a fixed order, with lists and maps sorted, ports in their full
+    /// `ip:host->container/protocol` form and unset values spelled out. It is written out field by field
+    /// so the layout of the structs and serde defaults never change it.
+    pub fn canonical(&self) -> String {
+        let mut lines = vec![format!("image={}", self.image)];
+        let mut ports: Vec<String> = self
+            .ports
+            .iter()
+            .map(|port| {
+                format!(
+                    "{}:{}->{}/{}",
+                    port.host_ip.as_deref().unwrap_or("0.0.0.0"),
+                    port.host_port,
+                    port.container_port,
+                    port.protocol
+                )
+            })
+            .collect();
+        ports.sort();
+        lines.extend(ports.into_iter().map(|port| format!("port={}", port)));
+        lines.extend(self.env.iter().map(|(key, value)| format!("env.{}={}", key, value)));
+        lines.extend(
+            self.labels
+                .iter()
+                .filter(|(key, _)| *key != CONFIG_HASH_LABEL)
+                .map(|(key, value)| format!("label.{}={}", key, value)),
+        );
+        lines.push(format!(
+            "restart_policy={}",
+            self.restart_policy.as_deref().unwrap_or("no")
+        ));
+        let mut binds = self.binds.clone();
+        binds.sort();
+        lines.extend(binds.into_iter().map(|bind| format!("bind={}", bind)));
+        let mut networks = self.networks.clone();
+        networks.sort();
+        lines.extend(networks.into_iter().map(|network| format!("network={}", network)));
+        lines.push(format!("network_mode={}", self.network_mode));
+        lines.push(format!("publish_all={}", self.publish_all));
+        let mut aliases = self.aliases.clone();
+        aliases.sort();
+        lines.extend(aliases.into_iter().map(|alias| format!("alias={}", alias)));
+        let describe = |value: Option<i64>| value.map(|v| v.to_string()).unwrap_or("unset".to_string());
+        lines.push(format!("pids_limit={}", describe(self.pids_limit)));
+        lines.push(format!(
+            "oom_score_adj={}",
+            describe(self.oom_score_adj.filter(|adj| *adj != 0))
+        ));
+        lines.push(format!("oom_kill_disable={}", self.oom_kill_disable));
+        // Only written when set, so containers from before these fields keep their hash
+        if self.tty {
+            lines.push("tty=true".to_string());
+        }
+        if self.open_stdin {
+            lines.push("open_stdin=true".to_string());
+        }
+        for (field, list) in [("cpuset_cpus", &self.cpuset_cpus), ("cpuset_mems", &self.cpuset_mems)] {
+            if let Some(list) = list {
+                lines.push(format!("{}={}", field, cpuset::normalize(list)));
+            }
+        }
+        for (field, mode) in [
+            ("ipc", &self.ipc_mode),
+            ("pid", &self.pid_mode),
+            ("uts", &self.uts_mode),
+        ] {
+            if let Some(mode) = mode {
+                lines.push(format!("{}={}", field, mode));
+            }
+        }
+        lines.join("\n")
+    }
+
+    /// The hash of the canonical form, prefixed with its version, e.g. `v1:3f2a…`.
+    pub fn config_hash(&self) -> String {
+        format!("v{}:{}", CONFIG_HASH_VERSION, sha256_hex(self.canonical().as_bytes()))
+    }
+
+    /// The spec with its config hash in the labels, as containers are created with it.
+    pub fn with_config_hash(mut self) -> ContainerSpec {
+        let hash = self.config_hash();
+        self.labels.insert(CONFIG_HASH_LABEL.to_string(), hash);
+        self
+    }
+
+    /// The config to create a container matching this spec with.
+    pub fn to_create_config(&self) -> bollard::container::Config<String> {
+        let mut port_bindings = PortMap::new();
+        let mut exposed_ports: HashMap<String, HashMap<(), ()>> = HashMap::new();
+        for port in &self.ports {
+            let key = format!("{}/{}", port.container_port, port.protocol);
+            port_bindings
+                .entry(key.clone())
+                .or_insert_with(|| Some(vec![]))
+                .get_or_insert_with(Vec::new)
+                .push(PortBinding {
+                    host_ip: port.host_ip.clone(),
+                    host_port: Some(port.host_port.to_string()),
+                });
+            exposed_ports.insert(key, HashMap::new());
+        }
+
+        let host_config = HostConfig {
+            port_bindings: Some(port_bindings),
+            binds: (!self.binds.is_empty()).then(|| self.binds.clone()),
+            restart_policy: self.restart_policy.as_ref().map(|name| bollard::models::RestartPolicy {
+                name: name.parse().ok(),
+                maximum_retry_count: None,
+            }),
+            network_mode: Some(self.network_mode.clone()),
+            publish_all_ports: Some(self.publish_all),
+            pids_limit: self.pids_limit,
+            oom_score_adj: self.oom_score_adj,
+            oom_kill_disable: self.oom_kill_disable.then_some(true),
+            cpuset_cpus: self.cpuset_cpus.clone(),
+            cpuset_mems: self.cpuset_mems.clone(),
+            ipc_mode: self.ipc_mode.clone(),
+            pid_mode: self.pid_mode.clone(),
+            uts_mode: self.uts_mode.clone(),
+            ..Default::default()
+        };
+        let networking_config = self.networks.first().map(|network| NetworkingConfig {
+            endpoints_config: HashMap::from([(
+                network.clone(),
+                EndpointSettings {
+                    aliases: (!self.aliases.is_empty()).then(|| self.aliases.clone()),
+                    ..Default::default()
+                },
+            )]),
+        });
+
+        let env: Vec<String> = self.env.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+        bollard::container::Config {
+            image: Some(self.image.clone()),
+            env: (!env.is_empty()).then_some(env),
+            host_config: Some(host_config),
+            exposed_ports: Some(exposed_ports),
+            labels: Some(self.labels.clone().into_iter().collect()),
+            networking_config,
+            tty: Some(self.tty),
+            open_stdin: Some(self.open_stdin),
+            ..Default::default()
+        }
+    }
+
+    /// The spec of a live container. Docker merges the image defaults into env and labels, those
+    /// are removed again so only what ruku set remains.
+    pub fn from_inspect(container: &ContainerInspectResponse, image: Option<&ImageInspect>) -> ContainerSpec {
+        let config = container.config.clone().unwrap_or_default();
+        let inherited = Inherited::from_image(image);
+        let (env, labels) = inherited.strip(
+            parse_env(config.env.as_deref().unwrap_or_default()),
+            config.labels.unwrap_or_default().into_iter().collect(),
+        );
+
+        let host_config = container.host_config.clone().unwrap_or_default();
+        let mut ports = vec![];
+        for (key, bindings) in host_config.port_bindings.unwrap_or_default() {
+            let (container_port, protocol) = key.split_once('/').unwrap_or((&key, "tcp"));
+            let Ok(container_port) = container_port.parse() else {
+                continue;
+            };
+            for binding in bindings.unwrap_or_default() {
+                ports.push(PortSpec {
+                    container_port,
+                    protocol: protocol.to_string(),
+                    host_ip: binding.host_ip.filter(|ip| !ip.is_empty() && ip != "0.0.0.0"),
+                    host_port: binding.host_port.and_then(|p| p.parse().ok()).unwrap_or_default(),
+                });
+            }
+        }
+        ports.sort();
+
+        let restart_policy = host_config
+            .restart_policy
+            .and_then(|policy| policy.name)
+            .map(|name| name.to_string())
+            .filter(|name| !name.is_empty() && name != "no");
+
+        // Docker reports no limit as 0 or -1 depending on the version
+        let pids_limit = host_config.pids_limit.filter(|limit| *limit > 0);
+        let oom_score_adj = host_config.oom_score_adj.filter(|adj| *adj != 0);
+        let oom_kill_disable = host_config.oom_kill_disable.unwrap_or(false);
+        let mode = |mode: Option<String>| mode.filter(|mode| !mode.is_empty());
+
+        let mut binds = host_config.binds.unwrap_or_default();
+        binds.sort();
+
+        // The host and none modes show up as networks of their own, they are covered by the network mode
+        let networks: BTreeMap<String, EndpointSettings> = container
+            .network_settings
+            .clone()
+            .and_then(|settings| settings.networks)
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|(name, _)| name != "host" && name != "none")
+            .collect();
+
+        ContainerSpec {
+            image: config.image.unwrap_or_default(),
+            ports,
+            env,
+            labels,
+            restart_policy,
+            binds,
+            aliases: vec![],
+            networks: networks.into_keys().collect(),
+            network_mode: host_config.network_mode.unwrap_or_default(),
+            publish_all: host_config.publish_all_ports.unwrap_or(false),
+            pids_limit,
+            oom_score_adj,
+            oom_kill_disable,
+            cpuset_cpus: mode(host_config.cpuset_cpus),
+            cpuset_mems: mode(host_config.cpuset_mems),
+            tty: config.tty.unwrap_or(false),
+            open_stdin: config.open_stdin.unwrap_or(false),
+            ipc_mode: mode(host_config.ipc_mode),
+            pid_mode: mode(host_config.pid_mode),
+            uts_mode: mode(host_config.uts_mode),
+            inherited,
+        }
+    }
+
+    /// What redeploying from this spec changes in `live`, from the live value to the desired one.
+    pub fn diff(&self, live: &ContainerSpec) -> Vec<Change> {
+        // What the image sets is stripped from the live spec, a ruku.yml value repeating it is no change
+        let (env, labels) = live.inherited.strip(self.env.clone(), self.labels.clone());
+        let normalized = ContainerSpec {
+            env,
+            labels,
+            ..self.clone()
+        };
+        let mut desired = normalized.fields();
+        let mut live_fields = live.fields();
+        // Containers from before the schema label would otherwise be recreated just to gain it
+        if !live.labels.contains_key(SCHEMA_LABEL) {
+            desired.remove(&format!("labels.{}", SCHEMA_LABEL));
+        }
+        // The hash sums up the other fields, see `hash_change` for when it alone differs
+        desired.remove(&format!("labels.{}", CONFIG_HASH_LABEL));
+        live_fields.remove(&format!("labels.{}", CONFIG_HASH_LABEL));
+        // The note of the deploy that created the container is not part of the config
+        live_fields.remove(&format!("labels.{}", DEPLOY_MESSAGE_LABEL));
+        if self.oom_score_adj == Some(0) {
+            desired.remove("resources.oom_score_adj");
+        }
+        // The daemon fills in its default IPC mode, private or shareable, when none was asked for
+        if self.ipc_mode.is_none() && matches!(live.ipc_mode.as_deref(), Some("private" | "shareable")) {
+            live_fields.remove("ipc");
+        }
+        diff::compare(&live_fields, &desired)
+    }
+
+    /// The spec as normalized fields by path, e.g. `env.DATABASE_URL` or `ports[1].host_port`, unset ones
+    /// left out.
+    fn fields(&self) -> BTreeMap<String, String> {
+        let mut fields = BTreeMap::from([
+            ("image".to_string(), self.image.clone()),
+            (
+                "restart_policy".to_string(),
+                self.restart_policy.clone().unwrap_or("no".to_string()),
+            ),
+            ("network_mode".to_string(), self.network_mode.clone()),
+            ("publish_all".to_string(), self.publish_all.to_string()),
+            (
+                "resources.oom_kill_disable".to_string(),
+                self.oom_kill_disable.to_string(),
+            ),
+            ("tty".to_string(), self.tty.to_string()),
+            ("stdin_open".to_string(), self.open_stdin.to_string()),
+        ]);
+        fields.extend(diff::port_fields(&self.ports));
+        fields.extend(diff::map_fields("env", &self.env));
+        fields.extend(diff::map_fields("labels", &self.labels));
+        fields.extend(diff::volume_fields(&self.binds));
+        let mut networks = self.networks.clone();
+        networks.sort();
+        if !networks.is_empty() {
+            fields.insert("networks".to_string(), networks.join(", "));
+        }
+        let numbers = [
+            ("resources.pids_limit", self.pids_limit),
+            ("resources.oom_score_adj", self.oom_score_adj),
+        ];
+        for (path, value) in numbers {
+            if let Some(value) = value {
+                fields.insert(path.to_string(), value.to_string());
+            }
+        }
+        for (path, list) in [
+            ("resources.cpuset_cpus", &self.cpuset_cpus),
+            ("resources.cpuset_mems", &self.cpuset_mems),
+        ] {
+            if let Some(list) = list {
+                fields.insert(path.to_string(), cpuset::normalize(list));
+            }
+        }
+        for (path, mode) in [
+            ("ipc", &self.ipc_mode),
+            ("pid", &self.pid_mode),
+            ("uts", &self.uts_mode),
+        ] {
+            if let Some(mode) = mode {
+                fields.insert(path.to_string(), mode.clone());
+            }
+        }
+        fields
+    }
+}
+
+/// How the config hash of a live container relates to the desired one.
+#[derive(Debug, Clone, PartialEq)]
+pub enum HashChange {
+    /// The container was hashed by a ruku with another canonical form, the config may be the same.
+    Version { live: Option<u32> },
+    /// Same canonical form, different settings.
+    Config,
+}
+
+impl ContainerSpec {
+    /// Whether the live container carries a different config hash than this spec, none when they match
+    /// or the container predates the hash label.
+    pub fn hash_change(&self, live: &ContainerSpec) -> Option<HashChange> {
+        let live_hash = live.labels.get(CONFIG_HASH_LABEL)?;
+        if Some(live_hash) == self.labels.get(CONFIG_HASH_LABEL) {
+            return None;
+        }
+        let live_version = hash_version(live_hash);
+        if live_version != Some(CONFIG_HASH_VERSION) {
+            return Some(HashChange::Version { live: live_version });
+        }
+        Some(HashChange::Config)
+    }
+}
+
+/// The version prefix of a config hash, none when it has none.
+pub fn hash_version(hash: &str) -> Option<u32> {
+    hash.strip_prefix('v')?.split_once(':')?.0.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bollard::models::ContainerConfig;
+
+    /// A spec as ruku builds it from the config, with `env` and `labels`.
+    fn desired(env: &[(&str, &str)], labels: &[(&str, &str)]) -> ContainerSpec {
+        let map = |pairs: &[(&str, &str)]| {
+            pairs
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect::<BTreeMap<_, _>>()
+        };
+        ContainerSpec {
+            image: "shop:1.0".to_string(),
+            ports: vec![],
+            env: map(env),
+            labels: map(labels),
+            restart_policy: None,
+            binds: vec![],
+            networks: vec![],
+            network_mode: "default".to_string(),
+            publish_all: false,
+            aliases: vec![],
+            pids_limit: None,
+            oom_score_adj: None,
+            oom_kill_disable: false,
+            cpuset_cpus: None,
+            cpuset_mems: None,
+            tty: false,
+            open_stdin: false,
+            ipc_mode: None,
+            pid_mode: None,
+            uts_mode: None,
+            inherited: Inherited::default(),
+        }
+    }
+
+    /// The live container created from `spec` on an image that sets `image_env` and `image_labels`.
+    fn live(spec: &ContainerSpec, image_env: &[&str], image_labels: &[(&str, &str)]) -> ContainerSpec {
+        let mut env: Vec<String> = image_env.iter().map(|var| var.to_string()).collect();
+        env.extend(spec.env.iter().map(|(k, v)| format!("{}={}", k, v)));
+        let mut labels: HashMap<String, String> = image_labels
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        labels.extend(spec.labels.clone());
+        let container = ContainerInspectResponse {
+            config: Some(ContainerConfig {
+                image: Some(spec.image.clone()),
+                env: Some(env),
+                labels: Some(labels),
+                ..Default::default()
+            }),
+            host_config: Some(HostConfig {
+                network_mode: Some(spec.network_mode.clone()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let image = ImageInspect {
+            config: Some(ContainerConfig {
+                env: Some(image_env.iter().map(|var| var.to_string()).collect()),
+                labels: Some(
+                    image_labels
+                        .iter()
+                        .map(|(k, v)| (k.to_string(), v.to_string()))
+                        .collect(),
+                ),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        ContainerSpec::from_inspect(&container, Some(&image))
+    }
+
+    #[test]
+    fn values_repeating_the_image_defaults_are_no_change() {
+        let spec = desired(
+            &[("PATH", "/usr/bin"), ("RUST_LOG", "info")],
+            &[("org.opencontainers.image.vendor", "acme")],
+        );
+        let live = live(
+            &spec,
+            &["PATH=/usr/bin", "HOME=/root"],
+            &[("org.opencontainers.image.vendor", "acme")],
+        );
+        assert_eq!(live.env.keys().collect::<Vec<_>>(), ["RUST_LOG"]);
+        assert!(live.labels.is_empty());
+        assert_eq!(spec.diff(&live), vec![]);
+    }
+
+    #[test]
+    fn values_overriding_the_image_defaults_are_kept() {
+        let spec = desired(&[("PATH", "/app/bin")], &[("org.opencontainers.image.vendor", "shop")]);
+        let live = live(
+            &spec,
+            &["PATH=/usr/bin"],
+            &[("org.opencontainers.image.vendor", "acme")],
+        );
+        assert_eq!(spec.diff(&live), vec![]);
+
+        // Taking the override out of ruku.yml leaves the image default in place
+        let changes = desired(&[], &[]).diff(&live);
+        let paths: Vec<_> = changes.iter().map(|change| change.path.as_str()).collect();
+        assert_eq!(paths, ["env.PATH", "labels.org.opencontainers.image.vendor"]);
+    }
+
+    #[test]
+    fn cpusets_compare_in_their_shortest_form() {
+        let mut spec = desired(&[], &[]);
+        spec.cpuset_cpus = Some("0,1,2".to_string());
+        let mut live = spec.clone();
+        live.cpuset_cpus = Some("0-2".to_string());
+        assert_eq!(spec.diff(&live), vec![]);
+        assert_eq!(spec.config_hash(), live.config_hash());
+
+        // Changed with docker update
+        live.cpuset_cpus = Some("0-3".to_string());
+        let paths: Vec<_> = spec.diff(&live).into_iter().map(|change| change.path).collect();
+        assert_eq!(paths, ["resources.cpuset_cpus"]);
+
+        let invalid: crate::model::RukuConfig =
+            serde_yaml::from_str("version: '1.0'\nresources:\n  cpuset_mems: 0-a").unwrap();
+        assert!(validator::Validate::validate(&invalid).is_err());
+    }
+
+    fn port(container_port: u16, host_port: u16) -> PortSpec {
+        PortSpec {
+            container_port,
+            protocol: "tcp".to_string(),
+            host_ip: None,
+            host_port,
+        }
+    }
+
+    /// A spec setting a bit of everything, with its lists in the given order.
+    fn golden(ports: Vec<PortSpec>, binds: &[&str], networks: &[&str]) -> ContainerSpec {
+        let mut spec = desired(
+            &[("RUST_LOG", "info"), ("DATABASE_URL", "postgres://db/shop")],
+            &[("ruku.app", "shop"), ("ruku.role", "stable")],
+        );
+        spec.ports = ports;
+        spec.binds = binds.iter().map(|bind| bind.to_string()).collect();
+        spec.networks = networks.iter().map(|network| network.to_string()).collect();
+        spec.restart_policy = Some("unless-stopped".to_string());
+        spec.pids_limit = Some(256);
+        spec.cpuset_cpus = Some("0-1".to_string());
+        spec
+    }
+
+    /// Changing this hash recreates every container ruku runs on the next deploy. When the canonical form
+    /// has to change, bump `CONFIG_HASH_VERSION` along with the expected values here.
+    #[test]
+    fn config_hash_is_golden() {
+        let spec = golden(
+            vec![port(8080, 8000), port(9090, 9000)],
+            &["ruku-shop-data:/data", "/srv/shop/uploads:/app/uploads:ro"],
+            &["ruku-shop", "ruku-db"],
+        );
+        assert_eq!(
+            spec.canonical(),
+            "image=shop:1.0
+port=0.0.0.0:8000->8080/tcp
+port=0.0.0.0:9000->9090/tcp
+env.DATABASE_URL=postgres://db/shop
+env.RUST_LOG=info
+label.ruku.app=shop
+label.ruku.role=stable
+restart_policy=unless-stopped
+bind=/srv/shop/uploads:/app/uploads:ro
+bind=ruku-shop-data:/data
+network=ruku-db
+network=ruku-shop
+network_mode=default
+publish_all=false
+pids_limit=256
+oom_score_adj=unset
+oom_kill_disable=false
+cpuset_cpus=0-1"
+        );
+        assert_eq!(
+            spec.config_hash(),
+            "v1:5359a7073b1c3ff768f8d284616269b86d67f575730360f5d2f43a38a8e54d49"
+        );
+        assert_eq!(hash_version(&spec.config_hash()), Some(CONFIG_HASH_VERSION));
+    }
+
+    #[test]
+    fn config_hash_ignores_the_order_of_lists_and_maps() {
+        let spec = golden(
+            vec![port(8080, 8000), port(9090, 9000)],
+            &["ruku-shop-data:/data", "/srv/shop/uploads:/app/uploads:ro"],
+            &["ruku-shop", "ruku-db"],
+        );
+        let mut reordered = golden(
+            vec![port(9090, 9000), port(8080, 8000)],
+            &["/srv/shop/uploads:/app/uploads:ro", "ruku-shop-data:/data"],
+            &["ruku-db", "ruku-shop"],
+        );
+        reordered.env = spec.env.clone().into_iter().rev().collect();
+        assert_eq!(spec.config_hash(), reordered.config_hash());
+
+        reordered.binds.pop();
+        assert_ne!(spec.config_hash(), reordered.config_hash());
+    }
+
+    #[test]
+    fn config_hash_spells_out_defaults() {
+        let spec = desired(&[], &[]);
+        let mut explicit = desired(&[], &[]);
+        explicit.restart_policy = Some("no".to_string());
+        explicit.oom_score_adj = Some(0);
+        explicit
+            .labels
+            .insert(CONFIG_HASH_LABEL.to_string(), "v1:earlier".to_string());
+        // What the image set is not part of the hash
+        explicit
+            .inherited
+            .env
+            .insert("PATH".to_string(), "/usr/bin".to_string());
+        assert_eq!(spec.config_hash(), explicit.config_hash());
+
+        // Fields added later only count once set, containers from before them keep their hash
+        let mut tty = desired(&[], &[]);
+        tty.tty = true;
+        assert_ne!(spec.config_hash(), tty.config_hash());
+        assert!(!spec.canonical().contains("tty"));
+    }
+}
@@ -0,0 +1,175 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::container::Container;
+use crate::deploy::DeployReport;
+use crate::failures::Failures;
+use crate::history::{deployment_id, Deployment};
+use crate::logger::Logger;
+use crate::model::DeployStrategy;
+use crate::rolling::Rolling;
+use crate::scan::ScanSummary;
+use crate::store::{self, Document};
+use crate::strategy;
+use crate::templates::{Rendered, Templates};
+
+/// A deploy `ruku stage` built, checked and created without starting, which `ruku promote` puts in place.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StagedDeploy {
+    /// The deployment id it is recorded under once promoted.
+    pub id: String,
+    pub container: String,
+    pub image: String,
+    pub version: Option<String>,
+    /// The config hash of the staged container, the config has to still match it when promoting.
+    pub config_hash: String,
+    pub staged_at: DateTime<Utc>,
+    /// Registry digest of the image when it was pushed while staging.
+    #[serde(default)]
+    pub digest: Option<String>,
+    /// Vulnerability counts of the image when it was scanned while staging.
+    #[serde(default)]
+    pub scan: Option<ScanSummary>,
+    /// The note the deploy was staged with.
+    pub message: Option<String>,
+}
+
+impl Document for StagedDeploy {}
+
+/// The one staged deploy of an app, a container named `<prefix><app>-staged` and its record in the app
+/// state directory.
+pub struct Staging<'a> {
+    log: &'a Logger,
+    stable: &'a Container<'a>,
+    staged: Container<'a>,
+    state_dir: PathBuf,
+}
+
+impl<'a> Staging<'a> {
+    pub const FILE_NAME: &'static str = "staged.json";
+    /// What the name of the staged container ends in.
+    pub const SUFFIX: &'static str = "staged";
+
+    pub fn new(log: &'a Logger, stable: &'a Container<'a>, state_dir: &Path) -> Staging<'a> {
+        Staging {
+            log,
+            stable,
+            staged: stable.staged(Self::SUFFIX),
+            state_dir: state_dir.to_path_buf(),
+        }
+    }
+
+    /// The staged deploy of the app in `state_dir`, none when nothing is staged.
+    pub fn read(log: &Logger, state_dir: &Path) -> Result<Option<StagedDeploy>, String> {
+        store::load(log, &state_dir.join(Self::FILE_NAME))
+    }
+
+    /// Create the container of the configured version under the staging name without starting it, and
+    /// record it with what the build in `report` produced as the deployment started at `started_at`. A
+    /// deploy staged before is replaced, an app has one at a time.
+    pub async fn stage(
+        &self,
+        started_at: DateTime<Utc>,
+        version: &Option<String>,
+        report: &DeployReport,
+        message: Option<String>,
+    ) -> Result<StagedDeploy, String> {
+        if let Some(earlier) = Self::read(self.log, &self.state_dir)? {
+            self.log.step(&format!(
+                "Replacing the deploy staged at {}",
+                earlier.staged_at.format("%Y-%m-%d %H:%M:%S UTC")
+            ));
+        }
+        self.staged.discard().await?;
+        let image = self.stable.image_name();
+        self.staged.prepare(image.clone()).await?;
+        let staged = StagedDeploy {
+            id: deployment_id(started_at),
+            container: self.staged.container_name().to_string(),
+            config_hash: self.staged.spec(image.clone())?.config_hash(),
+            image,
+            version: version.clone(),
+            staged_at: started_at,
+            digest: report.digest.clone(),
+            scan: report.scan.clone(),
+            message,
+        };
+        store::save(&self.state_dir.join(Self::FILE_NAME), &staged)
+            .map_err(|e| format!("Error writing the staged deploy: {}", e))?;
+        Ok(staged)
+    }
+
+    /// Write the `rendered` templates, swap the container of `staged` in for the running one and gate on
+    /// its health, rolling back and failing when it fails.
+    pub async fn promote(
+        &self,
+        staged: StagedDeploy,
+        health_timeout: Duration,
+        rendered: &[Rendered],
+        failures: &Failures<'_>,
+    ) -> Result<Deployment, String> {
+        // The swap removes the staged container when it fails, there is nothing left to promote then
+        self.clear()?;
+        // Written only now, the running container sees the files change in place
+        Templates::new(self.log, &self.state_dir).write(rendered)?;
+        self.stable.own_templates(&staged.image).await?;
+        let rolling = Rolling::new(self.log, self.stable, health_timeout).with_prepared(Self::SUFFIX);
+        let smoke = strategy::run(self.log, &rolling, Some(failures), None).await?;
+        let mut deployment = Deployment::new(&staged.version, &staged.image, staged.staged_at);
+        deployment.digest = staged.digest;
+        deployment.scan = staged.scan;
+        deployment.smoke = smoke;
+        deployment.strategy = DeployStrategy::Rolling;
+        deployment.message = staged.message;
+        Ok(deployment)
+    }
+
+    /// Remove the staged container and forget the staged deploy.
+    pub async fn abandon(&self) -> Result<(), String> {
+        let Some(staged) = Self::read(self.log, &self.state_dir)? else {
+            return Err("No deploy is staged".to_string());
+        };
+        self.staged.discard().await?;
+        self.clear()?;
+        self.log.step(&format!(
+            "Abandoned the deploy of {} staged at {}",
+            staged.image,
+            staged.staged_at.format("%Y-%m-%d %H:%M:%S UTC")
+        ));
+        Ok(())
+    }
+
+    /// The staged deploy, failing when there is none or the config no longer matches it.
+    pub async fn check(&self) -> Result<StagedDeploy, String> {
+        let Some(staged) = Self::read(self.log, &self.state_dir)? else {
+            return Err("No deploy is staged, run `ruku stage` first".to_string());
+        };
+        if self.staged.get().await?.is_none() {
+            return Err(format!(
+                "The staged container {} is gone, run `ruku stage` again",
+                staged.container
+            ));
+        }
+        // What the image declares goes into the spec, as it did for the staged container
+        self.stable.use_image(&staged.image).await?;
+        if self.stable.spec(staged.image.clone())?.config_hash() != staged.config_hash {
+            return Err(format!(
+                "The config changed since the deploy was staged at {}, run `ruku stage` again",
+                staged.staged_at.format("%Y-%m-%d %H:%M:%S UTC")
+            ));
+        }
+        Ok(staged)
+    }
+
+    fn clear(&self) -> Result<(), String> {
+        let path = self.state_dir.join(Self::FILE_NAME);
+        if path.exists() {
+            fs::remove_file(&path).map_err(|e| format!("Error removing the staged deploy: {}", e))?;
+        }
+        Ok(())
+    }
+}
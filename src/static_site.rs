@@ -0,0 +1,94 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::model::{AppType, RukuConfig, StaticConfig};
+
+/// The web server image static apps are served by, pinned so a deploy doesn't change the server under
+/// the site.
+pub const STATIC_IMAGE: &str = "nginx:1.27-alpine";
+/// Where the web server image serves files from.
+pub const STATIC_ROOT: &str = "/usr/share/nginx/html";
+/// Label with the type of the app, so switching between an app and a static site recreates the container.
+pub const TYPE_LABEL: &str = "ruku.type";
+/// Port the web server listens on when the app config leaves it to the image, the one nginx exposes.
+const IMAGE_PORT: u16 = 80;
+
+/// The site of `config` when it is a static app.
+pub fn static_site(config: &RukuConfig) -> Option<&StaticConfig> {
+    config
+        .static_site
+        .as_ref()
+        .filter(|_| config.app_type == AppType::Static)
+}
+
+/// The directory mounted as the site of a static app that isn't `immutable`, none otherwise.
+pub fn static_root(app_path: &Path, config: &RukuConfig) -> Option<PathBuf> {
+    static_site(config)
+        .filter(|site| !site.immutable)
+        .map(|site| app_path.join(&site.dir))
+}
+
+/// The nginx server config for `site` listening on `port`.
+pub fn server_config(site: &StaticConfig, port: u16) -> String {
+    let port = if port == 0 { IMAGE_PORT } else { port };
+    let fallback = if site.spa { "/index.html" } else { "=404" };
+    let headers: String = site
+        .headers
+        .iter()
+        .map(|(name, value)| format!("    add_header {} \"{}\" always;\n", name, value.replace('"', "\\\"")))
+        .collect();
+    format!(
+        "server {{\n    listen {port};\n    listen [::]:{port};\n    root {root};\n    index index.html;\n\n{headers}\n    location / {{\n        try_files $uri $uri/ {fallback};\n    }}\n}}\n",
+        port = port,
+        root = STATIC_ROOT,
+        headers = headers,
+        fallback = fallback,
+    )
+}
+
+/// Write the build context of a static app to `state_path/static`: a Dockerfile on top of
+/// [`STATIC_IMAGE`] with the generated server config and, for an `immutable` site, a copy of the files.
+/// Returns the directory of the context.
+pub fn write_context(app_path: &Path, state_path: &Path, config: &RukuConfig) -> io::Result<PathBuf> {
+    let site = static_site(config).expect("write_context is only called for static apps");
+    let context = state_path.join("static");
+    if context.exists() {
+        fs::remove_dir_all(&context)?;
+    }
+    fs::create_dir_all(&context)?;
+
+    fs::write(context.join("default.conf"), server_config(site, config.port.number))?;
+    let mut dockerfile = format!(
+        "FROM {}\nCOPY default.conf /etc/nginx/conf.d/default.conf\n",
+        STATIC_IMAGE
+    );
+    if site.immutable {
+        let dir = app_path.join(&site.dir);
+        if !dir.is_dir() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("static.dir {} is not a directory", dir.display()),
+            ));
+        }
+        copy_dir(&dir, &context.join("site"))?;
+        dockerfile.push_str(&format!("COPY site {}\n", STATIC_ROOT));
+    }
+    fs::write(context.join("Dockerfile"), dockerfile)?;
+    Ok(context)
+}
+
+/// Copy the directory `from` to `to` with everything in it, symlinks are followed.
+fn copy_dir(from: &Path, to: &Path) -> io::Result<()> {
+    fs::create_dir_all(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let target = to.join(entry.file_name());
+        if entry.path().is_dir() {
+            copy_dir(&entry.path(), &target)?;
+        } else {
+            fs::copy(entry.path(), target)?;
+        }
+    }
+    Ok(())
+}
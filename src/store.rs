@@ -0,0 +1,357 @@
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use chrono::Utc;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::logger::Logger;
+
+/// Tells apart the temporary files of writes from several threads of one process.
+static WRITES: AtomicUsize = AtomicUsize::new(0);
+
+/// A JSON document in the ruku state, written atomically with its schema version and moved aside when
+/// it can't be read.
+pub trait Document: Serialize + DeserializeOwned {
+    /// The schema this version of ruku writes.
+    const SCHEMA: u32 = 1;
+
+    /// Bring a document in schema `from` to schema `from + 1`. Documents from before the schema versions
+    /// are schema 0 and hold what schema 1 holds.
+    fn migrate(from: u32, value: Value) -> Result<Value, String> {
+        let _ = from;
+        Ok(value)
+    }
+}
+
+#[derive(Serialize)]
+struct Envelope<'a, T> {
+    schema: u32,
+    data: &'a T,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct StoredEnvelope {
+    schema: u32,
+    data: Value,
+}
+
+/// Write `content` to `path` so a reader sees either the old or the new content, never part of it. It
+/// goes to a temporary file next to `path` that is synced and renamed over it, then on unix the directory
+/// is synced so the rename survives a crash.
+pub fn write_atomic(path: &Path, content: &[u8]) -> io::Result<()> {
+    write_atomic_with(path, |file| file.write_all(content))
+}
+
+/// [`write_atomic`] with the content written by `write`, `path` is left as it was when it fails.
+fn write_atomic_with(path: &Path, write: impl FnOnce(&mut dyn Write) -> io::Result<()>) -> io::Result<()> {
+    let dir = path
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let name = path
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "the path has no file name"))?;
+    fs::create_dir_all(dir)?;
+    let temp = dir.join(format!(
+        ".{}.{}-{}.tmp",
+        name.to_string_lossy(),
+        std::process::id(),
+        WRITES.fetch_add(1, Ordering::Relaxed)
+    ));
+    let written = File::create(&temp)
+        .and_then(|mut file| {
+            write(&mut file)?;
+            file.sync_all()
+        })
+        .and_then(|_| fs::rename(&temp, path));
+    if written.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    written?;
+    sync_dir(dir)
+}
+
+/// Sync the entry of a renamed file in `dir`. Windows can't open a directory as a file and keeps the
+/// rename without it.
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// Write `document` to `path` with its schema version, atomically.
+pub fn save<T: Document>(path: &Path, document: &T) -> io::Result<()> {
+    let envelope = Envelope {
+        schema: T::SCHEMA,
+        data: document,
+    };
+    write_atomic_with(path, |file| {
+        serde_json::to_writer_pretty(file, &envelope).map_err(io::Error::from)
+    })
+}
+
+/// The document at `path` in the current schema, none when there is none. One whose content can't be
+/// read is moved aside to `<name>.corrupt-<time>` with a warning and taken for none. One written by a
+/// newer ruku, or a file that can't be read at all, e.g. for its permissions or a disk error, is an error
+/// rather than being skipped and later overwritten.
+pub fn load<T: Document>(log: &Logger, path: &Path) -> Result<Option<T>, String> {
+    try_load(log, path).map_err(|e| format!("Error reading {}: {}", path.display(), e))
+}
+
+/// [`load`] with a file that can't be read left to the caller, only content that does not deserialize
+/// is moved aside.
+pub fn try_load<T: Document>(log: &Logger, path: &Path) -> io::Result<Option<T>> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        // Not UTF-8, the content is broken rather than the file
+        Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+            quarantine(log, path, &e.to_string());
+            return Ok(None);
+        }
+        Err(e) => return Err(e),
+    };
+    match parse::<T>(&content) {
+        Ok(document) => Ok(Some(document)),
+        Err(Unreadable::Newer(schema)) => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!(
+                "it has schema {}, this ruku only knows up to {}, update ruku",
+                schema,
+                T::SCHEMA
+            ),
+        )),
+        Err(Unreadable::Invalid(e)) => {
+            quarantine(log, path, &e);
+            Ok(None)
+        }
+    }
+}
+
+enum Unreadable {
+    Newer(u32),
+    Invalid(String),
+}
+
+fn parse<T: Document>(content: &str) -> Result<T, Unreadable> {
+    let value: Value = serde_json::from_str(content).map_err(|e| Unreadable::Invalid(e.to_string()))?;
+    let (schema, mut data) = match serde_json::from_value::<StoredEnvelope>(value.clone()) {
+        Ok(envelope) => (envelope.schema, envelope.data),
+        Err(_) => (0, value),
+    };
+    if schema > T::SCHEMA {
+        return Err(Unreadable::Newer(schema));
+    }
+    for from in schema..T::SCHEMA {
+        data = T::migrate(from, data)
+            .map_err(|e| Unreadable::Invalid(format!("migrating from schema {}: {}", from, e)))?;
+    }
+    serde_json::from_value(data).map_err(|e| Unreadable::Invalid(e.to_string()))
+}
+
+/// Move the unreadable file at `path` aside, so it can be looked at and the next write starts fresh.
+fn quarantine(log: &Logger, path: &Path, reason: &str) {
+    let aside = path.with_file_name(format!(
+        "{}.corrupt-{}",
+        path.file_name().unwrap_or_default().to_string_lossy(),
+        Utc::now().format("%Y%m%dT%H%M%S")
+    ));
+    match fs::rename(path, &aside) {
+        Ok(()) => log.warn(&format!(
+            "{} is unreadable ({}), moved it to {}",
+            path.display(),
+            reason,
+            aside.display()
+        )),
+        Err(e) => log.warn(&format!(
+            "{} is unreadable ({}) and could not be moved aside: {}",
+            path.display(),
+            reason,
+            e
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::ser::{Error as _, SerializeStruct, Serializer};
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Note {
+        text: String,
+        count: u64,
+        tags: Vec<String>,
+    }
+
+    impl Document for Note {}
+
+    /// A document that fails to serialize after writing its first field, like a writer that errors.
+    struct Failing;
+
+    impl Serialize for Failing {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            let mut state = serializer.serialize_struct("Failing", 2)?;
+            state.serialize_field("text", "partial")?;
+            Err(S::Error::custom("the disk is full"))
+        }
+    }
+
+    impl<'de> Deserialize<'de> for Failing {
+        fn deserialize<D: serde::Deserializer<'de>>(_: D) -> Result<Failing, D::Error> {
+            Ok(Failing)
+        }
+    }
+
+    impl Document for Failing {}
+
+    /// Names of the files in `dir`, sorted.
+    fn files(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        names.sort();
+        names
+    }
+
+    /// Deterministic pseudo random numbers, xorshift64.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn text(&mut self) -> String {
+            let len = self.next() % 12;
+            (0..len)
+                .map(|_| ['a', 'Z', '0', ' ', '"', '\\', '\n', 'é', '{', '🦀'][(self.next() % 10) as usize])
+                .collect()
+        }
+
+        fn note(&mut self) -> Note {
+            Note {
+                text: self.text(),
+                count: self.next(),
+                tags: (0..self.next() % 4).map(|_| self.text()).collect(),
+            }
+        }
+    }
+
+    #[test]
+    fn failed_write_keeps_the_old_document() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("note.json");
+        let note = Note {
+            text: "kept".to_string(),
+            count: 1,
+            tags: vec![],
+        };
+        save(&path, &note).unwrap();
+
+        let error = save(&path, &Failing).unwrap_err();
+        assert!(error.to_string().contains("the disk is full"), "{}", error);
+        assert_eq!(try_load::<Note>(&Logger::new(), &path).unwrap(), Some(note));
+        // The temporary file went with the failed write
+        assert_eq!(files(dir.path()), ["note.json"]);
+    }
+
+    #[test]
+    fn unreadable_file_is_an_error_not_quarantined() {
+        let dir = tempfile::tempdir().unwrap();
+        // Reading a directory fails like a permission or disk error does
+        let path = dir.path().join("note.json");
+        fs::create_dir(&path).unwrap();
+
+        assert!(try_load::<Note>(&Logger::new(), &path).is_err());
+        assert_eq!(files(dir.path()), ["note.json"]);
+        assert!(path.is_dir());
+    }
+
+    #[test]
+    fn newer_schema_is_an_error_not_quarantined() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("note.json");
+        fs::write(&path, r#"{"schema": 9, "data": {}}"#).unwrap();
+
+        let error = try_load::<Note>(&Logger::new(), &path).unwrap_err();
+        assert!(error.to_string().contains("schema 9"), "{}", error);
+        assert_eq!(files(dir.path()), ["note.json"]);
+    }
+
+    #[test]
+    fn saved_documents_are_wrapped_in_their_schema() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state").join("note.json");
+        let note = Note {
+            text: "hello".to_string(),
+            count: 2,
+            tags: vec!["a".to_string()],
+        };
+        save(&path, &note).unwrap();
+
+        let stored: Value = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(
+            stored,
+            serde_json::json!({"schema": 1, "data": {"text": "hello", "count": 2, "tags": ["a"]}})
+        );
+        assert_eq!(try_load::<Note>(&Logger::new(), &path).unwrap(), Some(note));
+        assert_eq!(files(&dir.path().join("state")), ["note.json"]);
+    }
+
+    #[test]
+    fn missing_file_is_none() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(
+            try_load::<Note>(&Logger::new(), &dir.path().join("note.json")).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn documents_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("note.json");
+        let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+        for _ in 0..200 {
+            let note = rng.note();
+            save(&path, &note).unwrap();
+            assert_eq!(try_load::<Note>(&Logger::new(), &path).unwrap(), Some(note));
+        }
+        assert_eq!(files(dir.path()), ["note.json"]);
+    }
+
+    #[test]
+    fn broken_content_is_quarantined_at_any_cut() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("note.json");
+        let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+        for _ in 0..20 {
+            let note = rng.note();
+            save(&path, &note).unwrap();
+            let content = fs::read(&path).unwrap();
+            for cut in 0..content.len() {
+                fs::write(&path, &content[..cut]).unwrap();
+                // Only the content is broken, never an error
+                assert_eq!(try_load::<Note>(&Logger::new(), &path).unwrap(), None, "cut at {}", cut);
+                assert!(!path.exists());
+                for name in files(dir.path()) {
+                    fs::remove_file(dir.path().join(name)).unwrap();
+                }
+            }
+        }
+    }
+}
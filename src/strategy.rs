@@ -0,0 +1,210 @@
+use std::future::Future;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::blue_green::BlueGreen;
+use crate::canary::Canary;
+use crate::container::Container;
+use crate::deadline::Deadlines;
+use crate::failures::Failures;
+use crate::logger::Logger;
+use crate::model::{DeployStrategy, RukuConfig};
+use crate::recreate::Recreate;
+use crate::rolling::Rolling;
+use crate::smoke::SmokeResult;
+
+/// A way of replacing the running version of an app with a new one.
+pub trait Strategy {
+    /// The steps the strategy is about to take, logged before anything changes.
+    fn plan(&self) -> Vec<String>;
+
+    /// Put the new version in place, returning the smoke checks it passed.
+    fn execute(&self) -> impl Future<Output = Result<Vec<SmokeResult>, String>>;
+
+    /// Undo what a failed [`Strategy::execute`] changed, as far as the strategy can.
+    fn rollback(&self) -> impl Future<Output = Result<(), String>>;
+
+    /// Names of the containers running the new version after a failed [`Strategy::execute`], whose logs
+    /// are kept before the rollback removes them.
+    fn failed(&self) -> impl Future<Output = Vec<String>>;
+}
+
+/// Log the plan of `strategy` and execute it as the `start` stage, failing once it is rolled back when it
+/// fails or runs out of time.
+pub async fn run(
+    log: &Logger,
+    strategy: &impl Strategy,
+    failures: Option<&Failures<'_>>,
+    deadlines: Option<&Deadlines>,
+) -> Result<Vec<SmokeResult>, String> {
+    for step in strategy.plan() {
+        log.step(&step);
+    }
+    let executed = match deadlines {
+        Some(deadlines) => deadlines.run("start", strategy.execute()).await,
+        None => Ok(strategy.execute().await),
+    };
+    match executed {
+        Ok(Ok(smoke)) => Ok(smoke),
+        Ok(Err(e)) => {
+            log.warn(&e);
+            roll_back(strategy, failures).await?;
+            Err("The deploy failed and was rolled back".to_string())
+        }
+        Err(timed_out) => {
+            roll_back(strategy, failures).await?;
+            Err(format!("{}, the new version was rolled back", timed_out))
+        }
+    }
+}
+
+/// Keep the logs of the containers of the new version and undo what `strategy` changed.
+pub async fn roll_back(strategy: &impl Strategy, failures: Option<&Failures<'_>>) -> Result<(), String> {
+    if let Some(failures) = failures {
+        failures.capture(&strategy.failed().await).await;
+    }
+    strategy.rollback().await
+}
+
+/// Replace `container` with the configured version the way the app's strategy does. Strategies that
+/// gate on health wait up to `health_timeout` for the new container. The logs of the containers a
+/// failed deploy removes are kept in `failures`, and it is rolled back once it runs over `deadlines`.
+pub async fn deploy(
+    log: &Logger,
+    config: &RukuConfig,
+    container: &Container<'_>,
+    state_dir: &Path,
+    health_timeout: Duration,
+    failures: Option<&Failures<'_>>,
+    deadlines: Option<&Deadlines>,
+) -> Result<Vec<SmokeResult>, String> {
+    log.step(&format!("Deploying with the {} strategy", config.strategy));
+    match (config.strategy, &config.canary) {
+        (DeployStrategy::Rolling, _) => {
+            run(log, &Rolling::new(log, container, health_timeout), failures, deadlines).await
+        }
+        (DeployStrategy::BlueGreen, _) => {
+            run(
+                log,
+                &BlueGreen::new(log, container, health_timeout),
+                failures,
+                deadlines,
+            )
+            .await
+        }
+        (DeployStrategy::Canary, Some(canary)) => {
+            run(
+                log,
+                &Canary::new(log, container, state_dir)
+                    .with_rollout(canary)
+                    .with_health_timeout(health_timeout),
+                failures,
+                deadlines,
+            )
+            .await
+        }
+        _ => run(log, &Recreate::new(container), failures, deadlines).await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use bollard::Docker;
+
+    use super::*;
+
+    /// Records what is asked of it, `execute` succeeds with one smoke check or fails as told.
+    struct Recorded {
+        fail: bool,
+        calls: Mutex<Vec<&'static str>>,
+    }
+
+    impl Strategy for Recorded {
+        fn plan(&self) -> Vec<String> {
+            self.calls.lock().unwrap().push("plan");
+            vec!["step".to_string()]
+        }
+
+        async fn execute(&self) -> Result<Vec<SmokeResult>, String> {
+            self.calls.lock().unwrap().push("execute");
+            match self.fail {
+                true => Err("unhealthy".to_string()),
+                false => Ok(vec![SmokeResult {
+                    method: "GET".to_string(),
+                    path: "/".to_string(),
+                    passed: true,
+                    status: Some(200),
+                    attempts: 1,
+                    error: None,
+                }]),
+            }
+        }
+
+        async fn rollback(&self) -> Result<(), String> {
+            self.calls.lock().unwrap().push("rollback");
+            Ok(())
+        }
+
+        async fn failed(&self) -> Vec<String> {
+            self.calls.lock().unwrap().push("failed");
+            vec![]
+        }
+    }
+
+    fn recorded(fail: bool) -> Recorded {
+        Recorded {
+            fail,
+            calls: Mutex::new(vec![]),
+        }
+    }
+
+    #[tokio::test]
+    async fn a_successful_execute_is_not_rolled_back() {
+        let log = Logger::new();
+        let strategy = recorded(false);
+        let smoke = run(&log, &strategy, None, None).await.unwrap();
+        assert_eq!(smoke.len(), 1);
+        assert_eq!(*strategy.calls.lock().unwrap(), ["plan", "execute"]);
+    }
+
+    #[tokio::test]
+    async fn the_failed_containers_are_kept_before_the_rollback() {
+        let log = Logger::new();
+        let state_dir = tempfile::tempdir().unwrap();
+        let failures = Failures::new(&log, state_dir.path());
+        let strategy = recorded(true);
+        roll_back(&strategy, Some(&failures)).await.unwrap();
+        assert_eq!(*strategy.calls.lock().unwrap(), ["failed", "rollback"]);
+
+        let strategy = recorded(true);
+        roll_back(&strategy, None).await.unwrap();
+        assert_eq!(*strategy.calls.lock().unwrap(), ["rollback"]);
+    }
+
+    #[test]
+    fn plans_name_the_containers_they_use() {
+        let log = Logger::new();
+        let docker = Docker::connect_with_http("http://127.0.0.1:1", 1, bollard::API_DEFAULT_VERSION).unwrap();
+        let config: RukuConfig = serde_yaml::from_str("version: '1.0'\nstrategy: blue_green").unwrap();
+        assert_eq!(config.strategy, DeployStrategy::BlueGreen);
+        let container = Container::new(&log, "shop", &docker, &config);
+        let timeout = Duration::from_secs(30);
+
+        assert_eq!(
+            Recreate::new(&container).plan(),
+            ["Replacing ruku-shop with the new version in place"]
+        );
+        let rolling = [
+            "Creating ruku-shop-next while ruku-shop keeps running",
+            "Swapping them, ruku-shop is kept as ruku-shop-previous until the new version is healthy",
+        ];
+        assert_eq!(Rolling::new(&log, &container, timeout).plan(), rolling);
+        let mut blue_green = vec!["Checking the new version in ruku-shop-green while ruku-shop keeps serving"];
+        blue_green.extend(rolling);
+        assert_eq!(BlueGreen::new(&log, &container, timeout).plan(), blue_green);
+        assert!(DeployStrategy::BlueGreen.gates_health());
+        assert!(!DeployStrategy::Recreate.gates_health());
+    }
+}
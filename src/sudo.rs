@@ -0,0 +1,291 @@
+use std::env;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::connection::local_docker_host;
+use crate::logger::Logger;
+
+/// Environment variable that makes ruku go through `sudo -n` when the docker socket is not accessible.
+pub const SUDO_ENV: &str = "RUKU_DOCKER_SUDO";
+
+/// Set on the re-executed process so it never tries sudo again.
+const REEXEC_ENV: &str = "RUKU_SUDO_REEXEC";
+
+/// Variables sudo would drop that the re-executed command still needs.
+const FORWARDED_ENV: [&str; 20] = [
+    "DOCKER_HOST",
+    "RUKU_APP",
+    "RUKU_CONTEXT",
+    "RUKU_FLEET_MEMBER",
+    "RUKU_READ_ONLY",
+    "RUKU_ROOT",
+    "RUKU_ASSUME_YES",
+    "RUKU_DEBUG",
+    "RUKU_JSON_EVENTS",
+    "RUKU_TRACE_DOCKER",
+    "RUKU_DEPLOY_MESSAGE",
+    "RUKU_OVERRIDE_FREEZE",
+    "RUKU_CONFIG_TOKEN",
+    "RUKU_TEST",
+    "RUKU_REGISTRY_USERNAME",
+    "RUKU_REGISTRY_PASSWORD",
+    "OTEL_EXPORTER_OTLP_ENDPOINT",
+    "OTEL_SERVICE_NAME",
+    "VAULT_ADDR",
+    "VAULT_TOKEN",
+];
+
+/// The unix socket the daemon listens on, none when it is reached some other way.
+pub fn socket_path(docker_host: &str) -> Option<PathBuf> {
+    docker_host.strip_prefix("unix://").map(PathBuf::from)
+}
+
+/// Whether connecting to the socket fails for lack of permission, as opposed to the daemon being down.
+pub fn is_permission_denied(socket: &Path) -> bool {
+    matches!(UnixStream::connect(socket), Err(e) if e.kind() == io::ErrorKind::PermissionDenied)
+}
+
+/// Whether the docker socket of this host refuses the current user.
+pub fn docker_permission_denied() -> bool {
+    socket_path(&local_docker_host()).is_some_and(|socket| is_permission_denied(&socket))
+}
+
+/// Whether `--sudo` or `RUKU_DOCKER_SUDO` asks for the sudo fallback.
+pub fn sudo_requested(flag: bool) -> bool {
+    flag || env::var(SUDO_ENV).is_ok_and(|value| !matches!(value.trim(), "" | "0" | "false"))
+}
+
+pub fn is_reexec() -> bool {
+    env::var_os(REEXEC_ENV).is_some()
+}
+
+/// The error shown when the socket refuses the user and sudo was not asked for.
+pub fn permission_hint(user: &str) -> String {
+    format!(
+        "Permission denied on the docker socket, add {} to the docker group (sudo usermod -aG docker {}) or set {}=1",
+        user, user, SUDO_ENV
+    )
+}
+
+/// The command line for running `args` again as root, with the invoking user's home so ruku finds the
+/// same state. The `forwarded` variables are only named, sudo keeps their values from the environment,
+/// on the command line they would show in `ps` to every user of the host.
+pub fn sudo_command(exe: &Path, args: &[String], home: &Path, forwarded: &[&str]) -> Vec<String> {
+    let mut command = vec!["sudo".to_string(), "-n".to_string()];
+    if !forwarded.is_empty() {
+        command.push(format!("--preserve-env={}", forwarded.join(",")));
+    }
+    command.push("env".to_string());
+    command.push(format!("HOME={}", home.display()));
+    command.push(format!("{}=1", REEXEC_ENV));
+    command.push(exe.display().to_string());
+    command.extend(args.iter().cloned());
+    command
+}
+
+/// The `find` arguments that give what root owns under `path` to `owner`, `uid:gid`, leaving out the
+/// `excluded` directories and everything in them.
+pub fn chown_command(path: &Path, owner: &str, excluded: &[&Path]) -> Vec<String> {
+    let mut command = vec!["find".to_string(), path.display().to_string()];
+    for dir in excluded {
+        command.extend([
+            "-path".to_string(),
+            dir.display().to_string(),
+            "-prune".to_string(),
+            "-o".to_string(),
+        ]);
+    }
+    command.extend(
+        ["-user", "root", "-exec", "chown", "-h", owner, "{}", "+"]
+            .iter()
+            .map(|arg| arg.to_string()),
+    );
+    command
+}
+
+/// Run the current command again through `sudo -n`, returning the exit code it ended with. Whatever the root process
+/// created under `owned` is handed back to the invoking user afterwards, so the next run without sudo is
+/// not tripped up by root owned state. The `excluded` directories hold app data, which belongs to
+/// whoever the containers run as and keeps its owner.
+pub fn reexec(log: &Logger, owned: &[&Path], excluded: &[&Path]) -> Result<i32, String> {
+    let exe = env::current_exe().map_err(|e| format!("Error locating the ruku binary: {}", e))?;
+    let home = home::home_dir().ok_or("Could not determine home directory")?;
+    let args: Vec<String> = env::args().skip(1).collect();
+    let forwarded: Vec<&str> = FORWARDED_ENV
+        .into_iter()
+        .filter(|key| env::var_os(key).is_some())
+        .collect();
+
+    if !sudo_allowed() {
+        return Err("sudo needs a password, allow ruku to run as root without one in sudoers".to_string());
+    }
+    let command = sudo_command(&exe, &args, &home, &forwarded);
+    log.step("Docker socket is not accessible, running through sudo");
+    let status = Command::new(&command[0])
+        .args(&command[1..])
+        .status()
+        .map_err(|e| format!("Error running sudo: {}", e))?;
+
+    // The home directory tells whose files these are, it is the one directory root never creates
+    let owner = home.metadata().map(|m| format!("{}:{}", m.uid(), m.gid()));
+    if let Ok(owner) = owner {
+        for path in owned.iter().filter(|path| path.exists()) {
+            // Only files root created, bind mounted app data owned by container users is left alone
+            let chown = Command::new("sudo")
+                .arg("-n")
+                .args(chown_command(path, &owner, excluded))
+                .status();
+            if !chown.is_ok_and(|status| status.success()) {
+                log.warn(&format!("Failed to restore ownership of {}", path.display()));
+            }
+        }
+    }
+
+    Ok(status.code().unwrap_or(1))
+}
+
+/// Whether sudo runs without asking for a password.
+fn sudo_allowed() -> bool {
+    Command::new("sudo")
+        .args(["-n", "true"])
+        .status()
+        .is_ok_and(|status| status.success())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+    use std::fs;
+
+    use regex::Regex;
+
+    use super::*;
+
+    /// The RUKU_ variables that stay behind on purpose: the sudo switch and its marker, which the
+    /// re-executed process must not act on again, and the ones read at build time.
+    const NOT_FORWARDED: [&str; 4] = [
+        "RUKU_DOCKER_SUDO",
+        "RUKU_SUDO_REEXEC",
+        "RUKU_GIT_SHA",
+        "RUKU_BUILD_DATE",
+    ];
+
+    #[test]
+    fn every_ruku_variable_is_forwarded_or_left_out_on_purpose() {
+        let name = Regex::new(r#""(RUKU_[A-Z_]+)""#).unwrap();
+        let mut read = BTreeSet::new();
+        let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+        for dir in ["src", "build.rs"] {
+            let path = root.join(dir);
+            let files = match path.is_dir() {
+                true => fs::read_dir(&path)
+                    .unwrap()
+                    .map(|entry| entry.unwrap().path())
+                    .collect(),
+                false => vec![path],
+            };
+            for file in files.into_iter().filter(|file| file.is_file()) {
+                let source = fs::read_to_string(&file).unwrap();
+                read.extend(name.captures_iter(&source).map(|captures| captures[1].to_string()));
+            }
+        }
+        let missing: Vec<_> = read
+            .iter()
+            .filter(|var| !FORWARDED_ENV.contains(&var.as_str()) && !NOT_FORWARDED.contains(&var.as_str()))
+            .collect();
+        assert!(missing.is_empty(), "not forwarded through sudo: {:?}", missing);
+        assert!(read.contains("RUKU_APP"));
+    }
+
+    #[test]
+    fn forwarded_values_stay_off_the_command_line() {
+        let command = sudo_command(
+            Path::new("/usr/bin/ruku"),
+            &["run".to_string(), "shop".to_string()],
+            Path::new("/home/deploy"),
+            &["RUKU_REGISTRY_PASSWORD", "VAULT_TOKEN"],
+        );
+        assert_eq!(
+            command,
+            [
+                "sudo",
+                "-n",
+                "--preserve-env=RUKU_REGISTRY_PASSWORD,VAULT_TOKEN",
+                "env",
+                "HOME=/home/deploy",
+                "RUKU_SUDO_REEXEC=1",
+                "/usr/bin/ruku",
+                "run",
+                "shop"
+            ]
+        );
+        assert!(command.iter().all(|arg| !arg.contains("RUKU_REGISTRY_PASSWORD=")));
+    }
+
+    #[test]
+    fn nothing_forwarded_preserves_nothing() {
+        let command = sudo_command(Path::new("/usr/bin/ruku"), &[], Path::new("/home/deploy"), &[]);
+        assert_eq!(
+            command,
+            [
+                "sudo",
+                "-n",
+                "env",
+                "HOME=/home/deploy",
+                "RUKU_SUDO_REEXEC=1",
+                "/usr/bin/ruku"
+            ]
+        );
+    }
+
+    #[test]
+    fn chown_prunes_the_data_directories() {
+        let command = chown_command(
+            Path::new("/home/deploy/.ruku"),
+            "1000:1000",
+            &[Path::new("/home/deploy/.ruku/data")],
+        );
+        assert_eq!(
+            command,
+            [
+                "find",
+                "/home/deploy/.ruku",
+                "-path",
+                "/home/deploy/.ruku/data",
+                "-prune",
+                "-o",
+                "-user",
+                "root",
+                "-exec",
+                "chown",
+                "-h",
+                "1000:1000",
+                "{}",
+                "+"
+            ]
+        );
+    }
+
+    #[test]
+    fn chown_leaves_the_excluded_directories_alone() {
+        // `-print` in place of the `-user root` test and the chown lists every file the chown looks at
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        std::fs::create_dir_all(root.join("data/shop")).unwrap();
+        std::fs::create_dir_all(root.join("state/shop")).unwrap();
+        std::fs::write(root.join("data/shop/db"), "").unwrap();
+        std::fs::write(root.join("state/shop/history.json"), "").unwrap();
+
+        let mut command = chown_command(root, "0:0", &[&root.join("data")]);
+        let user = command.iter().position(|arg| arg == "-user").unwrap();
+        command.truncate(user);
+        command.push("-print".to_string());
+        let output = Command::new(&command[0]).args(&command[1..]).output().unwrap();
+        let listed = String::from_utf8(output.stdout).unwrap();
+        assert!(listed.contains("state/shop/history.json"), "{}", listed);
+        assert!(!listed.contains("data/shop"), "{}", listed);
+    }
+}
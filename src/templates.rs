@@ -0,0 +1,471 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Write;
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use serde_yaml::Value;
+
+use crate::links::Link;
+use crate::logger::Logger;
+use crate::model::{FileSource, RukuConfig};
+use crate::provenance::flatten;
+use crate::secrets;
+use crate::sha256::sha256_hex;
+
+/// Shown instead of secret values when rendered output is printed.
+pub const SECRET_MASK: &str = "******";
+
+/// Prefix of the variables taken from the environment ruku runs in, e.g. `{{ env.DATABASE_URL }}`.
+const ENV_PREFIX: &str = "env.";
+/// Prefix of the variables resolved from `secrets` of ruku.yml, e.g. `{{ secret.DB_PASSWORD }}`.
+const SECRET_PREFIX: &str = "secret.";
+
+/// A value a template can refer to.
+#[derive(Debug, Clone)]
+pub struct Variable {
+    pub value: String,
+    /// Environment values and secrets may hold credentials, they are masked and make the file private.
+    pub secret: bool,
+}
+
+/// Suffix of the copy of a file holding secrets with them masked, which dry runs diff against.
+const MASKED_SUFFIX: &str = ".masked";
+
+/// A template or file rendered for a deploy.
+pub struct Rendered {
+    pub source: String,
+    pub target: String,
+    pub content: String,
+    /// The content with secret values replaced by the mask.
+    pub masked: String,
+    pub secret: bool,
+    /// Permission bits set in `files`, otherwise private for secrets and readable by all for the rest.
+    pub mode: Option<u32>,
+}
+
+impl Rendered {
+    fn mode(&self) -> u32 {
+        self.mode.unwrap_or(if self.secret { 0o600 } else { 0o644 })
+    }
+}
+
+/// `app` and every config key by its dotted path, none of them secret.
+pub fn config_variables(app: &str, config: &RukuConfig) -> BTreeMap<String, Variable> {
+    let public = |value: String| Variable { value, secret: false };
+    let mut variables = BTreeMap::new();
+
+    let value = serde_yaml::to_value(config).unwrap_or(Value::Null);
+    for (key, value) in flatten(&value).into_iter().filter(|(key, _)| {
+        !key.starts_with("templates")
+            && !key.starts_with("files")
+            && !key.starts_with("labels")
+            && !key.starts_with("secrets")
+    }) {
+        let value = match value {
+            Value::Null | Value::Mapping(_) => continue,
+            Value::String(value) => value.clone(),
+            value => serde_json::to_string(value).unwrap_or_default(),
+        };
+        variables.insert(key, public(value));
+    }
+    variables.insert("app".to_string(), public(app.to_string()));
+    // `port` is the number the app listens on, the full port spec is rarely what a config file wants. A
+    // port from the image is only known once the image is built, after the templates are rendered.
+    if config.port.is_from_image() {
+        variables.remove("port");
+    } else {
+        variables.insert("port".to_string(), public(config.port.number.to_string()));
+        variables.insert("port.number".to_string(), public(config.port.number.to_string()));
+        variables.insert("port.host_port".to_string(), public(config.port.host_port.to_string()));
+    }
+    if let Some(branch) = &config.preview_branch {
+        variables.insert("preview.branch".to_string(), public(branch.clone()));
+    }
+    variables
+}
+
+/// Everything templates can refer to: the config variables, the host and port variables of linked apps
+/// `env.<NAME>` for the environment of ruku itself and `secret.<NAME>` for the secrets of the app.
+pub fn variables(app: &str, config: &RukuConfig, links: &[Link]) -> Result<BTreeMap<String, Variable>, String> {
+    let public = |value: String| Variable { value, secret: false };
+    let mut variables = config_variables(app, config);
+    for (key, value) in links.iter().flat_map(Link::env) {
+        variables.insert(key, public(value));
+    }
+    for (key, value) in std::env::vars() {
+        variables.insert(format!("{}{}", ENV_PREFIX, key), Variable { value, secret: true });
+    }
+    for (key, value) in secrets::resolve(&config.secrets)? {
+        variables.insert(format!("{}{}", SECRET_PREFIX, key), Variable { value, secret: true });
+    }
+    Ok(variables)
+}
+
+/// Replace every `{{ name }}` in `template`, an unknown name or an unclosed placeholder is an error.
+pub fn render(template: &str, variables: &BTreeMap<String, Variable>) -> Result<(String, String, bool), String> {
+    let mut content = String::new();
+    let mut masked = String::new();
+    let mut secret = false;
+    let mut rest = template;
+
+    while let Some(start) = rest.find("{{") {
+        let line = template[..template.len() - rest.len() + start].matches('\n').count() + 1;
+        let Some(end) = rest[start..].find("}}") else {
+            return Err(format!("unclosed {{{{ on line {}", line));
+        };
+        let name = rest[start + 2..start + end].trim();
+        let variable = variables
+            .get(name)
+            .ok_or_else(|| format!("unknown variable '{}' on line {}", name, line))?;
+
+        content.push_str(&rest[..start]);
+        masked.push_str(&rest[..start]);
+        content.push_str(&variable.value);
+        masked.push_str(if variable.secret { SECRET_MASK } else { &variable.value });
+        secret |= variable.secret;
+        rest = &rest[start + end + 2..];
+    }
+    content.push_str(rest);
+    masked.push_str(rest);
+    Ok((content, masked, secret))
+}
+
+/// Replace every `${name}` in a config value such as a label.
+pub fn interpolate(value: &str, variables: &BTreeMap<String, Variable>) -> Result<String, String> {
+    interpolate_masked(value, variables).map(|(result, _, _)| result)
+}
+
+/// [`interpolate`] with the masked result too and whether a secret went into it.
+pub fn interpolate_masked(
+    value: &str,
+    variables: &BTreeMap<String, Variable>,
+) -> Result<(String, String, bool), String> {
+    let mut result = String::new();
+    let mut masked = String::new();
+    let mut secret = false;
+    let mut rest = value;
+    while let Some(start) = rest.find("${") {
+        let Some(end) = rest[start..].find('}') else {
+            return Err(format!("unclosed ${{ in '{}'", value));
+        };
+        let name = rest[start + 2..start + end].trim();
+        let variable = variables
+            .get(name)
+            .ok_or_else(|| format!("unknown variable '{}'", name))?;
+        result.push_str(&rest[..start]);
+        masked.push_str(&rest[..start]);
+        result.push_str(&variable.value);
+        masked.push_str(if variable.secret { SECRET_MASK } else { &variable.value });
+        secret |= variable.secret;
+        rest = &rest[start + end + 1..];
+    }
+    result.push_str(rest);
+    masked.push_str(rest);
+    Ok((result, masked, secret))
+}
+
+/// The `files` of the app with their content, inline content interpolated and local files read as they
+/// are. The error names the file.
+pub fn render_files(config: &RukuConfig, variables: &BTreeMap<String, Variable>) -> Result<Vec<Rendered>, String> {
+    config
+        .files
+        .iter()
+        .map(|(target, file)| {
+            let (source, (content, masked, secret)) = match &file.source {
+                FileSource::Content(content) => (
+                    "inline content".to_string(),
+                    interpolate_masked(content, variables).map_err(|e| format!("file {}: {}", target, e))?,
+                ),
+                FileSource::Path(path) => {
+                    let content =
+                        fs::read_to_string(path).map_err(|e| format!("file {} from {}: {}", target, path, e))?;
+                    (path.clone(), (content.clone(), content, false))
+                }
+            };
+            Ok(Rendered {
+                source,
+                target: target.clone(),
+                content,
+                masked,
+                secret,
+                mode: file.mode,
+            })
+        })
+        .collect()
+}
+
+/// Digest of the content and modes of `files`, none without files. It goes in a label so a change of
+/// content alone changes the config hash.
+pub fn files_digest(files: &[Rendered]) -> Option<String> {
+    if files.is_empty() {
+        return None;
+    }
+    let canonical: String = files
+        .iter()
+        .map(|file| format!("{}\0{:o}\0{}\0", file.target, file.mode(), file.content))
+        .collect();
+    Some(sha256_hex(canonical.as_bytes()))
+}
+
+/// A unified diff of `old` to `new` with three lines of context, empty when they are the same.
+pub fn unified_diff(old: &str, new: &str, old_name: &str, new_name: &str) -> String {
+    const CONTEXT: usize = 3;
+    let old: Vec<&str> = old.lines().collect();
+    let new: Vec<&str> = new.lines().collect();
+
+    // Longest common subsequence, the files are small config files
+    let mut lengths = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lengths[i][j] = if old[i] == new[j] {
+                lengths[i + 1][j + 1] + 1
+            } else {
+                lengths[i + 1][j].max(lengths[i][j + 1])
+            };
+        }
+    }
+    // Every line as (old line, new line, marker), a kept line has both
+    let mut edits: Vec<(usize, usize, char)> = vec![];
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            edits.push((i, j, ' '));
+            i += 1;
+            j += 1;
+        } else if i < old.len() && (j == new.len() || lengths[i + 1][j] >= lengths[i][j + 1]) {
+            edits.push((i, j, '-'));
+            i += 1;
+        } else {
+            edits.push((i, j, '+'));
+            j += 1;
+        }
+    }
+    if edits.iter().all(|(_, _, marker)| *marker == ' ') {
+        return String::new();
+    }
+
+    let mut diff = format!("--- {}\n+++ {}\n", old_name, new_name);
+    let mut index = 0;
+    while let Some(first_change) = edits[index..].iter().position(|(_, _, marker)| *marker != ' ') {
+        let start = (index + first_change).saturating_sub(CONTEXT);
+        // A hunk goes on while the next change is within twice the context
+        let mut end = index + first_change;
+        while end < edits.len() {
+            let next_change = edits[end..].iter().position(|(_, _, marker)| *marker != ' ');
+            match next_change {
+                Some(0) => end += 1,
+                Some(gap) if gap <= 2 * CONTEXT => end += gap,
+                _ => break,
+            }
+        }
+        let end = (end + CONTEXT).min(edits.len());
+        let hunk = &edits[start..end];
+        let old_count = hunk.iter().filter(|(_, _, marker)| *marker != '+').count();
+        let new_count = hunk.iter().filter(|(_, _, marker)| *marker != '-').count();
+        let (old_start, new_start) = (hunk[0].0, hunk[0].1);
+        diff.push_str(&format!(
+            "@@ -{},{} +{},{} @@\n",
+            old_start + usize::from(old_count > 0),
+            old_count,
+            new_start + usize::from(new_count > 0),
+            new_count
+        ));
+        for (i, j, marker) in hunk {
+            let line = if *marker == '+' { new[*j] } else { old[*i] };
+            diff.push_str(&format!("{}{}\n", marker, line));
+        }
+        index = end;
+    }
+    diff
+}
+
+/// Where the rendered file for a container path is kept, `/etc/app/app.conf` becomes `etc_app_app.conf`.
+pub fn get_template_path(template_dir: &Path, target: &str) -> PathBuf {
+    template_dir.join(target.trim_start_matches('/').replace('/', "_"))
+}
+
+/// Renders the templates of an app into its state directory.
+pub struct Templates<'a> {
+    log: &'a Logger,
+    dir: PathBuf,
+}
+
+impl<'a> Templates<'a> {
+    pub const DIR_NAME: &'static str = "templates";
+
+    pub fn new(log: &'a Logger, state_dir: &Path) -> Templates<'a> {
+        Templates {
+            log,
+            dir: state_dir.join(Self::DIR_NAME),
+        }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Render every configured template and file, failing on the first one that can't be.
+    pub fn render_all(
+        &self,
+        config: &RukuConfig,
+        variables: &BTreeMap<String, Variable>,
+    ) -> Result<Vec<Rendered>, String> {
+        let mut rendered = self.render_templates(config, variables)?;
+        rendered.extend(render_files(config, variables).map_err(|e| format!("Error rendering {}", e))?);
+        Ok(rendered)
+    }
+
+    fn render_templates(
+        &self,
+        config: &RukuConfig,
+        variables: &BTreeMap<String, Variable>,
+    ) -> Result<Vec<Rendered>, String> {
+        config
+            .templates
+            .iter()
+            .map(|(source, target)| {
+                let template =
+                    fs::read_to_string(source).map_err(|e| format!("Error reading template {}: {}", source, e))?;
+                let (content, masked, secret) =
+                    render(&template, variables).map_err(|e| format!("Error rendering template {}: {}", source, e))?;
+                Ok(Rendered {
+                    source: source.clone(),
+                    target: target.clone(),
+                    content,
+                    masked,
+                    secret,
+                    mode: None,
+                })
+            })
+            .collect()
+    }
+
+    /// The content a dry run compares `file` with: what the last deploy wrote, with its secrets masked.
+    pub fn written(&self, file: &Rendered) -> Option<String> {
+        let path = get_template_path(&self.dir, &file.target);
+        let masked = PathBuf::from(format!("{}{}", path.display(), MASKED_SUFFIX));
+        fs::read_to_string(masked).or_else(|_| fs::read_to_string(path)).ok()
+    }
+
+    /// Write the rendered files. Each one replaces the previous file in one rename, the running container
+    /// keeps the version it was started with. Files holding secrets are only readable by the owner,
+    /// [`Container::own_templates`](crate::container::Container::own_templates) hands them to the app.
+    pub fn write(&self, rendered: &[Rendered]) -> Result<(), String> {
+        fs::create_dir_all(&self.dir).map_err(|e| format!("Error creating directory: {}", e))?;
+        for file in rendered {
+            let path = get_template_path(&self.dir, &file.target);
+            self.write_file(&path, &file.content, file.mode())
+                .and_then(|_| self.write_masked(&path, file))
+                .map_err(|e| format!("Error writing {}: {}", path.display(), e))?;
+            self.log.step(&format!("Rendered {} for {}", file.source, file.target));
+        }
+        Ok(())
+    }
+
+    /// Write the rendered files over the ones a running container has mounted. Its bind mounts hold on
+    /// to the files they were started with, which [`Templates::write`] replaces rather than changes.
+    pub fn rewrite(&self, rendered: &[Rendered]) -> Result<(), String> {
+        for file in rendered {
+            let path = get_template_path(&self.dir, &file.target);
+            let mode = file.mode();
+            let written = fs::OpenOptions::new()
+                .write(true)
+                .truncate(true)
+                .create(true)
+                .open(&path)
+                .and_then(|mut handle| {
+                    #[cfg(unix)]
+                    handle.set_permissions(fs::Permissions::from_mode(mode))?;
+                    handle.write_all(file.content.as_bytes())
+                })
+                .and_then(|_| self.write_masked(&path, file));
+            written.map_err(|e| format!("Error writing {}: {}", path.display(), e))?;
+            self.log.step(&format!("Rendered {} for {}", file.source, file.target));
+        }
+        Ok(())
+    }
+
+    /// Keep the masked copy of a file holding secrets, or remove the one an earlier version left.
+    fn write_masked(&self, path: &Path, file: &Rendered) -> std::io::Result<()> {
+        let masked = PathBuf::from(format!("{}{}", path.display(), MASKED_SUFFIX));
+        if file.secret {
+            return self.write_file(&masked, &file.masked, 0o644);
+        }
+        match fs::remove_file(masked) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    fn write_file(&self, path: &Path, content: &str, mode: u32) -> std::io::Result<()> {
+        let mut file = tempfile::NamedTempFile::new_in(&self.dir)?;
+        #[cfg(unix)]
+        file.as_file().set_permissions(fs::Permissions::from_mode(mode))?;
+        file.write_all(content.as_bytes())?;
+        file.persist(path).map_err(|e| e.error)?;
+        Ok(())
+    }
+}
+
+/// The files rendered for `targets` into `dir` that hold secrets, the ones with a masked copy.
+pub fn secret_files<'t>(dir: &Path, targets: impl IntoIterator<Item = &'t String>) -> Vec<PathBuf> {
+    targets
+        .into_iter()
+        .map(|target| get_template_path(dir, target))
+        .filter(|path| PathBuf::from(format!("{}{}", path.display(), MASKED_SUFFIX)).exists())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rendered(target: &str, secret: bool) -> Rendered {
+        Rendered {
+            source: format!("{}.tmpl", target),
+            target: target.to_string(),
+            content: "password: hunter2".to_string(),
+            masked: format!("password: {}", SECRET_MASK),
+            secret,
+            mode: None,
+        }
+    }
+
+    #[test]
+    fn only_files_holding_secrets_are_private() {
+        let state = tempfile::tempdir().unwrap();
+        let log = Logger::new();
+        let templates = Templates::new(&log, state.path());
+        let targets = ["/etc/app/secret.yml".to_string(), "/etc/app/public.yml".to_string()];
+        templates
+            .write(&[rendered(&targets[0], true), rendered(&targets[1], false)])
+            .unwrap();
+
+        let secret = get_template_path(templates.dir(), &targets[0]);
+        assert_eq!(secret_files(templates.dir(), &targets), vec![secret.clone()]);
+        #[cfg(unix)]
+        {
+            let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+            assert_eq!(mode(&secret), 0o600);
+            assert_eq!(mode(&get_template_path(templates.dir(), &targets[1])), 0o644);
+        }
+
+        // The masked copy goes once the file holds no secret anymore
+        templates.write(&[rendered(&targets[0], false)]).unwrap();
+        assert!(secret_files(templates.dir(), &targets).is_empty());
+    }
+
+    #[test]
+    fn config_variables_are_the_scalar_leaves() {
+        let config: RukuConfig = serde_yaml::from_str("version: '1.2'\nlabels:\n  team: web\n").unwrap();
+        let variables = config_variables("shop", &config);
+        assert_eq!(variables["version"].value, "1.2");
+        assert_eq!(variables["app"].value, "shop");
+        assert!(!variables.keys().any(|key| key.starts_with("labels")));
+        assert!(variables
+            .values()
+            .all(|variable| !variable.value.is_empty() && !variable.secret));
+    }
+}
@@ -0,0 +1,108 @@
+use std::io::{self, IsTerminal, Write};
+use std::time::Duration;
+
+use bollard::container::TopOptions;
+use bollard::models::ContainerTopResponse;
+use bollard::Docker;
+
+use crate::dashboard::terminal_size;
+
+/// How often `--watch` asks the daemon again.
+pub const WATCH_INTERVAL: Duration = Duration::from_secs(2);
+/// The `ps` columns asked for, the daemon runs `ps` on its host and keeps the container's processes.
+const PS_ARGS: &str = "-eo pid,user,pcpu,args";
+
+/// The processes running in a container, the way `docker top` shows them.
+pub struct Top<'a> {
+    docker: &'a Docker,
+    container_name: &'a str,
+}
+
+impl<'a> Top<'a> {
+    pub fn new(docker: &'a Docker, container_name: &'a str) -> Top<'a> {
+        Top { docker, container_name }
+    }
+
+    /// The process table as the daemon returns it, an error when the container is not running.
+    pub async fn processes(&self) -> Result<ContainerTopResponse, String> {
+        let running = self
+            .docker
+            .inspect_container(self.container_name, None)
+            .await
+            .ok()
+            .and_then(|container| container.state)
+            .and_then(|state| state.running)
+            .unwrap_or(false);
+        if !running {
+            return Err(format!("{} is not running", self.container_name));
+        }
+        let options = TopOptions { ps_args: PS_ARGS };
+        self.docker
+            .top_processes(self.container_name, Some(options))
+            .await
+            .map_err(|e| format!("Error listing the processes of {}: {}", self.container_name, e))
+    }
+
+    /// Print the processes, refreshing every [`WATCH_INTERVAL`] with `watch` until interrupted.
+    pub async fn print(&self, watch: bool, json: bool) -> Result<(), String> {
+        loop {
+            let top = self.processes().await?;
+            if json {
+                println!("{}", serde_json::to_string(&top).unwrap());
+            } else {
+                if watch {
+                    // Home and clear, the table replaces the previous one
+                    print!("\x1b[H\x1b[2J");
+                }
+                let width = io::stdout()
+                    .is_terminal()
+                    .then(terminal_size)
+                    .flatten()
+                    .map(|(columns, _)| columns);
+                for line in format_table(&top, width) {
+                    println!("{}", line);
+                }
+            }
+            let _ = io::stdout().flush();
+            if !watch {
+                return Ok(());
+            }
+            tokio::time::sleep(WATCH_INTERVAL).await;
+        }
+    }
+}
+
+/// The rows of `top` under its titles, every column as wide as its widest value but the last, the
+/// command, which is cut to what is left of `width`.
+fn format_table(top: &ContainerTopResponse, width: Option<usize>) -> Vec<String> {
+    let titles = top.titles.clone().unwrap_or_default();
+    let mut rows = vec![titles];
+    rows.extend(top.processes.clone().unwrap_or_default());
+    let columns = rows.iter().map(Vec::len).max().unwrap_or_default();
+    let widths: Vec<usize> = (0..columns)
+        .map(|i| {
+            rows.iter()
+                .filter_map(|row| row.get(i))
+                .map(|value| value.chars().count())
+                .max()
+                .unwrap_or_default()
+        })
+        .collect();
+
+    rows.iter()
+        .map(|row| {
+            let mut line = String::new();
+            for (i, value) in row.iter().enumerate() {
+                if i + 1 < row.len() {
+                    line.push_str(&format!("{:<width$}  ", value, width = widths[i]));
+                } else {
+                    line.push_str(value);
+                }
+            }
+            match width {
+                Some(width) => line.chars().take(width).collect(),
+                None => line,
+            }
+        })
+        .collect()
+}
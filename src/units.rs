@@ -0,0 +1,252 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use serde::{Deserialize, Deserializer};
+
+const DECIMAL_UNITS: [&str; 4] = ["KB", "MB", "GB", "TB"];
+const BINARY_UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+
+/// Parse a size like `512K`, `10MB` or `1.5GiB` into bytes, a bare number is bytes. A single letter and
+/// the `iB` units are powers of 1024 as Docker reads them, `KB`, `MB`, `GB` and `TB` powers of 1000.
+pub fn parse_size(size: &str) -> Result<u64, String> {
+    let size = size.trim();
+    let invalid = || {
+        format!(
+            "invalid size '{}', use a number with an optional unit like K, MiB or GB",
+            size
+        )
+    };
+    let split = size
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(size.len());
+    let (number, unit) = size.split_at(split);
+    let multiplier: u64 = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kib" => 1 << 10,
+        "m" | "mib" => 1 << 20,
+        "g" | "gib" => 1 << 30,
+        "t" | "tib" => 1 << 40,
+        "kb" => 1_000,
+        "mb" => 1_000_000,
+        "gb" => 1_000_000_000,
+        "tb" => 1_000_000_000_000,
+        _ => return Err(invalid()),
+    };
+    // Whole numbers are kept exact, only fractions go through a float
+    if let Ok(number) = number.parse::<u64>() {
+        return number.checked_mul(multiplier).ok_or_else(invalid);
+    }
+    let number: f64 = number.parse().map_err(|_| invalid())?;
+    Ok((number * multiplier as f64).round() as u64)
+}
+
+/// A size in bytes for people in powers of 1000, e.g. `12.4 MB`.
+pub fn format_size(bytes: u64) -> String {
+    scale(bytes, 1000.0, &DECIMAL_UNITS)
+}
+
+/// A size in bytes for people in powers of 1024, e.g. `1.4 GiB`, for what the kernel counts that way
+/// like memory.
+pub fn format_size_binary(bytes: u64) -> String {
+    scale(bytes, 1024.0, &BINARY_UNITS)
+}
+
+/// A throughput in bytes per second, e.g. `3.2 MB/s`.
+pub fn format_rate(bytes_per_second: f64) -> String {
+    format!("{}/s", format_size(bytes_per_second.max(0.0) as u64))
+}
+
+fn scale(bytes: u64, base: f64, units: &[&str]) -> String {
+    if (bytes as f64) < base {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64;
+    let mut unit = "B";
+    for next in units {
+        if size < base {
+            break;
+        }
+        size /= base;
+        unit = next;
+    }
+    format!("{:.1} {}", size, unit)
+}
+
+/// Parse a duration like `90s`, `5m`, `1h30m` or `2d`. A bare number is in `bare_unit`, one of `s`,
+/// `m`, `h` and `d`.
+pub fn parse_duration(duration: &str, bare_unit: char) -> Result<Duration, String> {
+    let duration = duration.trim();
+    let invalid = || format!("invalid duration '{}', use e.g. 90s, 5m or 1h30m", duration);
+    if !duration.is_empty() && duration.chars().all(|c| c.is_ascii_digit()) {
+        let number: u64 = duration.parse().map_err(|_| invalid())?;
+        let unit = unit_seconds(bare_unit).ok_or_else(invalid)?;
+        return number.checked_mul(unit).map(Duration::from_secs).ok_or_else(invalid);
+    }
+    let mut seconds: u64 = 0;
+    let mut number = String::new();
+    for c in duration.chars().filter(|c| !c.is_whitespace()) {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        let unit = unit_seconds(c).ok_or_else(invalid)?;
+        let value: u64 = number.parse().map_err(|_| invalid())?;
+        seconds = value
+            .checked_mul(unit)
+            .and_then(|value| seconds.checked_add(value))
+            .ok_or_else(invalid)?;
+        number.clear();
+    }
+    if !number.is_empty() || duration.is_empty() {
+        return Err(invalid());
+    }
+    Ok(Duration::from_secs(seconds))
+}
+
+fn unit_seconds(unit: char) -> Option<u64> {
+    match unit {
+        's' => Some(1),
+        'm' => Some(60),
+        'h' => Some(3600),
+        'd' => Some(86400),
+        _ => None,
+    }
+}
+
+/// A duration for people in its two largest units, e.g. `45s`, `3m`, `2h5m` or `3d4h`.
+pub fn format_duration(duration: Duration) -> String {
+    match duration.as_secs() {
+        seconds if seconds < 60 => format!("{}s", seconds),
+        seconds if seconds < 3600 => format!("{}m", seconds / 60),
+        seconds if seconds < 86400 => format!("{}h{}m", seconds / 3600, seconds % 3600 / 60),
+        seconds => format!("{}d{}h", seconds / 86400, seconds % 86400 / 3600),
+    }
+}
+
+/// Seconds in a config file, as a number or a duration like `90s` or `1h30m`.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Seconds {
+    Number(u64),
+    Text(String),
+}
+
+impl Seconds {
+    fn seconds<E: serde::de::Error>(self) -> Result<u64, E> {
+        match self {
+            Seconds::Number(seconds) => Ok(seconds),
+            Seconds::Text(text) => parse_duration(&text, 's')
+                .map(|duration| duration.as_secs())
+                .map_err(E::custom),
+        }
+    }
+}
+
+/// Deserialize seconds given as a number or a duration like `90s` or `1h30m`.
+pub fn seconds<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    Seconds::deserialize(deserializer)?.seconds()
+}
+
+/// [`seconds`] for a setting that may be left out.
+pub fn optional_seconds<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u64>, D::Error> {
+    Option::<Seconds>::deserialize(deserializer)?
+        .map(Seconds::seconds)
+        .transpose()
+}
+
+/// [`seconds`] for each value of a map.
+pub fn seconds_map<'de, D: Deserializer<'de>>(deserializer: D) -> Result<BTreeMap<String, u64>, D::Error> {
+    BTreeMap::<String, Seconds>::deserialize(deserializer)?
+        .into_iter()
+        .map(|(key, value)| Ok((key, value.seconds()?)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_sizes_in_powers_of_1000() {
+        assert_eq!(format_size(0), "0 B");
+        assert_eq!(format_size(999), "999 B");
+        assert_eq!(format_size(1_000), "1.0 KB");
+        assert_eq!(format_size(12_400_000), "12.4 MB");
+        assert_eq!(format_size(1_500_000_000_000), "1.5 TB");
+        // Past the largest unit the number grows
+        assert_eq!(format_size(2_000_000_000_000_000), "2000.0 TB");
+    }
+
+    #[test]
+    fn formats_sizes_in_powers_of_1024() {
+        assert_eq!(format_size_binary(1023), "1023 B");
+        assert_eq!(format_size_binary(1024), "1.0 KiB");
+        assert_eq!(format_size_binary(1536 << 20), "1.5 GiB");
+    }
+
+    #[test]
+    fn formats_rates() {
+        assert_eq!(format_rate(3_200_000.0), "3.2 MB/s");
+        assert_eq!(format_rate(-1.0), "0 B/s");
+    }
+
+    #[test]
+    fn parses_sizes() {
+        assert_eq!(parse_size("512"), Ok(512));
+        assert_eq!(parse_size("512b"), Ok(512));
+        assert_eq!(parse_size("512K"), Ok(512 << 10));
+        assert_eq!(parse_size("10MB"), Ok(10_000_000));
+        assert_eq!(parse_size("10 mib"), Ok(10 << 20));
+        assert_eq!(parse_size("1.5GiB"), Ok(1536 << 20));
+        assert_eq!(parse_size(" 2T "), Ok(2 << 40));
+    }
+
+    #[test]
+    fn rejects_invalid_sizes() {
+        for size in ["", "MB", "10XB", "1.2.3G", "-1K"] {
+            assert!(parse_size(size).is_err(), "{}", size);
+        }
+        assert!(parse_size(&format!("{}T", u64::MAX)).is_err());
+    }
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("90", 's'), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("5", 'm'), Ok(Duration::from_secs(300)));
+        assert_eq!(parse_duration("90s", 'm'), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("1h30m", 's'), Ok(Duration::from_secs(5400)));
+        assert_eq!(parse_duration("2d 4h", 's'), Ok(Duration::from_secs(187_200)));
+    }
+
+    #[test]
+    fn rejects_invalid_durations() {
+        for duration in ["", "m", "10", "5x", "1h30"] {
+            let bare_unit = if duration == "10" { 'x' } else { 's' };
+            assert!(parse_duration(duration, bare_unit).is_err(), "{}", duration);
+        }
+    }
+
+    #[test]
+    fn formats_durations_in_two_units() {
+        assert_eq!(format_duration(Duration::from_secs(45)), "45s");
+        assert_eq!(format_duration(Duration::from_secs(180)), "3m");
+        assert_eq!(format_duration(Duration::from_secs(7500)), "2h5m");
+        assert_eq!(format_duration(Duration::from_secs(273_600)), "3d4h");
+    }
+
+    #[test]
+    fn reads_seconds_as_numbers_or_durations() {
+        #[derive(Deserialize)]
+        struct Config {
+            #[serde(deserialize_with = "seconds")]
+            timeout: u64,
+            #[serde(default, deserialize_with = "optional_seconds")]
+            pause: Option<u64>,
+        }
+        let config: Config = serde_yaml::from_str("timeout: 1m30s\npause: 20").unwrap();
+        assert_eq!((config.timeout, config.pause), (90, Some(20)));
+        let config: Config = serde_yaml::from_str("timeout: 5").unwrap();
+        assert_eq!((config.timeout, config.pause), (5, None));
+        assert!(serde_yaml::from_str::<Config>("timeout: soon").is_err());
+    }
+}
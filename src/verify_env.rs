@@ -0,0 +1,209 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use bollard::exec::{CreateExecOptions, StartExecResults};
+use bollard::Docker;
+use futures_util::StreamExt;
+use serde_json::Value;
+
+use crate::logger::Logger;
+use crate::model::VerifyEnvConfig;
+use crate::probe::{exchange, socket_address, ProbeTarget};
+use crate::releases::is_secret_key;
+use crate::sha256::sha256_hex;
+
+/// Seconds the env endpoint may take to answer.
+const ENDPOINT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Exit codes of an exec whose command could not be run at all.
+const CANT_RUN: [i64; 2] = [126, 127];
+
+/// How a variable the app sees compares with what ruku set.
+#[derive(Debug, PartialEq)]
+pub enum EnvMismatch {
+    Unset,
+    Differs { seen: String, intended: String },
+}
+
+/// The variables of `intended` the app sees otherwise. Values are compared by their hash, a secret one
+/// is only ever described by a short hash.
+pub fn compare(
+    intended: &BTreeMap<String, String>,
+    seen: &BTreeMap<String, Option<String>>,
+) -> Vec<(String, EnvMismatch)> {
+    intended
+        .iter()
+        .filter_map(|(name, value)| {
+            let mismatch = match seen.get(name).cloned().flatten() {
+                None => EnvMismatch::Unset,
+                Some(seen) if sha256_hex(seen.as_bytes()) == sha256_hex(value.as_bytes()) => return None,
+                Some(seen) if is_secret_key(name) => EnvMismatch::Differs {
+                    seen: format!("sha256 {}", &sha256_hex(seen.as_bytes())[..12]),
+                    intended: format!("sha256 {}", &sha256_hex(value.as_bytes())[..12]),
+                },
+                Some(seen) => EnvMismatch::Differs {
+                    seen: format!("'{}'", seen),
+                    intended: format!("'{}'", value),
+                },
+            };
+            Some((name.clone(), mismatch))
+        })
+        .collect()
+}
+
+/// Reads the configured env vars back from a new container, through `printenv` in it or the app's own
+/// env endpoint.
+pub struct EnvCheck<'a> {
+    log: &'a Logger,
+    docker: &'a Docker,
+    config: &'a VerifyEnvConfig,
+}
+
+impl<'a> EnvCheck<'a> {
+    pub fn new(log: &'a Logger, docker: &'a Docker, config: &'a VerifyEnvConfig) -> EnvCheck<'a> {
+        EnvCheck { log, docker, config }
+    }
+
+    /// Fail when the container sees other values than `env`, the env ruku created it with. Variables
+    /// ruku doesn't set are left out, and a container that can't be asked is let through with a warning.
+    pub async fn run(&self, target: &ProbeTarget, env: &BTreeMap<String, String>) -> Result<(), String> {
+        let mut intended = BTreeMap::new();
+        for name in &self.config.vars {
+            match env.get(name) {
+                Some(value) => {
+                    intended.insert(name.clone(), value.clone());
+                }
+                None => self.log.warn(&format!(
+                    "verify_env: ruku doesn't set {}, there is nothing to compare",
+                    name
+                )),
+            }
+        }
+        if intended.is_empty() {
+            return Ok(());
+        }
+
+        let seen = match &self.config.endpoint {
+            Some(endpoint) => self.ask_endpoint(target, endpoint).await,
+            None => self.printenv(&target.container_name, intended.keys()).await,
+        };
+        let seen = match seen {
+            Ok(seen) => seen,
+            Err(reason) => {
+                self.log.warn(&format!(
+                    "Skipping the env check of {}, {}",
+                    target.container_name, reason
+                ));
+                return Ok(());
+            }
+        };
+
+        let mismatches = compare(&intended, &seen);
+        if mismatches.is_empty() {
+            self.log.step(&format!(
+                "{} sees the env ruku set for {}",
+                target.container_name,
+                intended.keys().cloned().collect::<Vec<_>>().join(", ")
+            ));
+            return Ok(());
+        }
+        for (name, mismatch) in &mismatches {
+            match mismatch {
+                EnvMismatch::Unset => self.log.warn(&format!("{} is not set in the container", name)),
+                EnvMismatch::Differs { seen, intended } => self
+                    .log
+                    .warn(&format!("{} is {} in the container, ruku set {}", name, seen, intended)),
+            }
+        }
+        Err(format!(
+            "{} of {} env vars differ in {}, does the entrypoint override them?",
+            mismatches.len(),
+            intended.len(),
+            target.container_name
+        ))
+    }
+
+    /// Each variable through `printenv NAME`, which needs no shell but has to be in the image.
+    async fn printenv(
+        &self,
+        container_name: &str,
+        names: impl Iterator<Item = &String>,
+    ) -> Result<BTreeMap<String, Option<String>>, String> {
+        let mut seen = BTreeMap::new();
+        for name in names {
+            let options = CreateExecOptions {
+                cmd: Some(vec!["printenv".to_string(), name.clone()]),
+                attach_stdout: Some(true),
+                attach_stderr: Some(true),
+                ..Default::default()
+            };
+            let cant_exec = |e: String| format!("printenv can't be run in it ({}), distroless images have none", e);
+            let exec = self
+                .docker
+                .create_exec(container_name, options)
+                .await
+                .map_err(|e| cant_exec(e.to_string()))?;
+            let mut output = String::new();
+            if let StartExecResults::Attached { output: mut stream, .. } = self
+                .docker
+                .start_exec(&exec.id, None)
+                .await
+                .map_err(|e| cant_exec(e.to_string()))?
+            {
+                while let Some(Ok(chunk)) = stream.next().await {
+                    output.push_str(&String::from_utf8_lossy(chunk.as_ref()));
+                }
+            }
+            let exit_code = self
+                .docker
+                .inspect_exec(&exec.id)
+                .await
+                .ok()
+                .and_then(|inspect| inspect.exit_code)
+                .unwrap_or_default();
+            if CANT_RUN.contains(&exit_code) {
+                return Err(cant_exec(format!("exit code {}", exit_code)));
+            }
+            // printenv exits 1 for a variable that is not set
+            let value = (exit_code == 0).then(|| output.strip_suffix('\n').unwrap_or(&output).to_string());
+            seen.insert(name.clone(), value);
+        }
+        Ok(seen)
+    }
+
+    /// The JSON object the app answers `endpoint` with, asked through the published port.
+    async fn ask_endpoint(
+        &self,
+        target: &ProbeTarget,
+        endpoint: &str,
+    ) -> Result<BTreeMap<String, Option<String>>, String> {
+        let (ip, port) = target
+            .host
+            .as_ref()
+            .ok_or("its env endpoint is only asked through a published port")?;
+        let address = socket_address(ip, *port)?;
+        let request = format!("GET {} HTTP/1.0\r\nHost: {}\r\nConnection: close\r\n\r\n", endpoint, ip);
+        let response = exchange(address, Some(&request), ENDPOINT_TIMEOUT)
+            .await
+            .map_err(|e| format!("its env endpoint {} did not answer: {}", endpoint, e))?;
+        let body = response
+            .split_once("\r\n\r\n")
+            .map_or(response.as_str(), |(_, body)| body);
+        let Ok(Value::Object(values)) = serde_json::from_str::<Value>(body) else {
+            return Err(format!(
+                "its env endpoint {} did not answer with a JSON object",
+                endpoint
+            ));
+        };
+        Ok(values
+            .into_iter()
+            .map(|(name, value)| {
+                let value = match value {
+                    Value::Null => None,
+                    Value::String(value) => Some(value),
+                    value => Some(value.to_string()),
+                };
+                (name, value)
+            })
+            .collect())
+    }
+}
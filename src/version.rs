@@ -0,0 +1,120 @@
+use std::path::Path;
+
+use chrono::{DateTime, Duration, Utc};
+use cmd_lib::run_fun;
+use serde::{Deserialize, Serialize};
+
+use crate::logger::Logger;
+use crate::server_config::UpdateCheckConfig;
+use crate::store::{self, Document};
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+/// The commit ruku was built from, `unknown` outside a git checkout.
+pub const GIT_SHA: &str = env!("RUKU_GIT_SHA");
+pub const BUILD_DATE: &str = env!("RUKU_BUILD_DATE");
+/// What `--version` prints after the name, e.g. `0.1.0 (3f2a9c1d0b7e 2026-10-14)`.
+pub const LONG_VERSION: &str = concat!(
+    env!("CARGO_PKG_VERSION"),
+    " (",
+    env!("RUKU_GIT_SHA"),
+    " ",
+    env!("RUKU_BUILD_DATE"),
+    ")"
+);
+/// The answer of the release endpoint is reused for this long.
+const CHECK_CACHE_TTL: Duration = Duration::hours(24);
+/// Seconds the release endpoint has to answer.
+const CHECK_TIMEOUT: u64 = 5;
+/// The cached answer, in the state root.
+const CHECK_CACHE_FILE: &str = "version-check.json";
+
+/// The build of ruku, as bug reports and records carry it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BuildInfo {
+    pub version: String,
+    pub git_sha: String,
+    pub build_date: String,
+}
+
+/// The build of this ruku.
+pub fn build_info() -> BuildInfo {
+    BuildInfo {
+        version: VERSION.to_string(),
+        git_sha: GIT_SHA.to_string(),
+        build_date: BUILD_DATE.to_string(),
+    }
+}
+
+/// The latest release the release endpoint named, cached so checks don't hit it every time.
+#[derive(Debug, Serialize, Deserialize)]
+struct CheckedRelease {
+    url: String,
+    latest: String,
+    checked_at: DateTime<Utc>,
+}
+
+impl Document for CheckedRelease {}
+
+/// Ask the release endpoint of `config` for the latest version, or take the answer cached in
+/// `state_root` within the last day. Returns it with whether it is newer than this ruku.
+pub fn check(log: &Logger, config: &UpdateCheckConfig, state_root: &Path) -> Result<(String, bool), String> {
+    if !config.enabled {
+        return Err("the update check is turned off by update_check.enabled in ~/.ruku/config.yml".to_string());
+    }
+    let cache_path = state_root.join(CHECK_CACHE_FILE);
+    let cached = store::load::<CheckedRelease>(log, &cache_path)?
+        .filter(|cached| cached.url == config.url && Utc::now() - cached.checked_at < CHECK_CACHE_TTL);
+    let latest = match cached {
+        Some(cached) => {
+            log.debug(&format!(
+                "Using the answer of {} from {}",
+                cached.url, cached.checked_at
+            ));
+            cached.latest
+        }
+        None => {
+            let latest = fetch_latest(&config.url)?;
+            let checked = CheckedRelease {
+                url: config.url.clone(),
+                latest: latest.clone(),
+                checked_at: Utc::now(),
+            };
+            // A missing cache only costs another request next time
+            if let Err(e) = store::save(&cache_path, &checked) {
+                log.debug(&format!("Could not cache the update check: {}", e));
+            }
+            latest
+        }
+    };
+    let newer = is_newer(&latest, VERSION);
+    Ok((latest, newer))
+}
+
+/// The version of the latest release at `url`, a GitHub style endpoint answering with a `tag_name`.
+fn fetch_latest(url: &str) -> Result<String, String> {
+    let user_agent = format!("ruku/{}", VERSION);
+    let output = run_fun!(
+        curl --silent --fail --location --max-time $CHECK_TIMEOUT --user-agent $user_agent
+            --header "Accept: application/vnd.github+json" $url
+    )
+    .map_err(|_| format!("{} could not be reached", url))?;
+    let release: serde_json::Value =
+        serde_json::from_str(&output).map_err(|e| format!("{} answered with something else than JSON: {}", url, e))?;
+    release["tag_name"]
+        .as_str()
+        .map(|tag| tag.trim_start_matches('v').to_string())
+        .ok_or(format!("{} named no tag_name", url))
+}
+
+/// Whether the dotted version `latest` comes after `current`, anything after a `-` is left out.
+fn is_newer(latest: &str, current: &str) -> bool {
+    let parts = |version: &str| {
+        let mut parts = [0u64; 3];
+        let release = version.split('-').next().unwrap_or_default();
+        for (part, value) in parts.iter_mut().zip(release.split('.')) {
+            *part = value.parse().unwrap_or(0);
+        }
+        parts
+    };
+    parts(latest) > parts(current)
+}
@@ -0,0 +1,707 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use bollard::container::{Config, CreateContainerOptions, DownloadFromContainerOptions, UploadToContainerOptions};
+use bollard::models::{HostConfig, Volume};
+use bollard::volume::{CreateVolumeOptions, ListVolumesOptions};
+use bollard::Docker;
+use futures_util::StreamExt;
+use tar::EntryType;
+
+use crate::auxiliary::{aux_labels, remove, AuxGuard, AuxKind};
+use crate::container::{test_labels, APP_LABEL};
+use crate::image::Image;
+use crate::logger::Logger;
+use crate::model::{HostPathsConfig, Owner};
+use crate::units;
+
+/// Label holding the config key of a named volume.
+pub const VOLUME_LABEL: &str = "ruku.volume";
+/// Bytes of an image directory read to tell whether it is empty.
+const ARCHIVE_PEEK: usize = 64 * 1024;
+/// Image of the container that copies a volume in or out, only its filesystem is used.
+const VOLUME_COPY_IMAGE: &str = "busybox:stable";
+/// Where the copy container mounts the volume, the entries of a volume archive are under it.
+const VOLUME_COPY_MOUNT: &str = "/volume";
+/// Bytes of a volume archive sent to Docker at a time.
+const UPLOAD_CHUNK: usize = 1024 * 1024;
+
+/// Where the data of a volume entry lives on the host.
+#[derive(Debug, Clone, PartialEq)]
+pub enum VolumeSource {
+    /// A Docker volume managed by ruku, named `ruku-<app>-<key>`.
+    Named(String),
+    /// A directory on the host, bind mounted into the container.
+    Host(PathBuf),
+}
+
+/// A parsed `volumes` entry, e.g. `data:/var/lib/app` or `./uploads:/app/uploads:ro`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VolumeSpec {
+    pub source: VolumeSource,
+    pub target: String,
+    pub read_only: bool,
+}
+
+impl VolumeSpec {
+    pub fn parse(spec: &str) -> Result<VolumeSpec, String> {
+        let invalid = || format!("invalid volume '{}', use SOURCE:TARGET[:ro]", spec);
+        let (source, rest) = split_source(spec).ok_or_else(invalid)?;
+        let (target, read_only) = match rest.split(':').collect::<Vec<_>>().as_slice() {
+            [target] => (*target, false),
+            [target, "ro"] => (*target, true),
+            [target, "rw"] => (*target, false),
+            _ => return Err(invalid()),
+        };
+
+        if !target.starts_with('/') {
+            return Err(format!("volume target '{}' must be an absolute path", target));
+        }
+
+        let source = if is_host_path(source) {
+            VolumeSource::Host(PathBuf::from(source))
+        } else if !source.is_empty()
+            && source
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '-')
+        {
+            VolumeSource::Named(source.to_string())
+        } else {
+            return Err(format!("invalid volume name '{}'", source));
+        };
+
+        Ok(VolumeSpec {
+            source,
+            target: target.to_string(),
+            read_only,
+        })
+    }
+
+    /// The bind string Docker expects in `HostConfig.binds`.
+    pub fn to_bind(&self, app: &str) -> String {
+        let source = match &self.source {
+            VolumeSource::Named(key) => get_volume_name(app, key),
+            VolumeSource::Host(path) => to_daemon_path(&path.display().to_string()),
+        };
+        if self.read_only {
+            format!("{}:{}:ro", source, self.target)
+        } else {
+            format!("{}:{}", source, self.target)
+        }
+    }
+}
+
+pub fn is_host_path(source: &str) -> bool {
+    source.starts_with('/') || source.starts_with('.') || source.starts_with('~') || has_drive(source)
+}
+
+/// Whether the path starts with a Windows drive, e.g. `C:\data` or `C:/data`.
+fn has_drive(path: &str) -> bool {
+    let bytes = path.as_bytes();
+    bytes.len() >= 3 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':' && matches!(bytes[2], b'\\' | b'/')
+}
+
+/// Split a volume entry into its source and the rest, the colon of a Windows drive is part of the source.
+pub fn split_source(spec: &str) -> Option<(&str, &str)> {
+    let skip = if has_drive(spec) { 2 } else { 0 };
+    let colon = spec[skip..].find(':')? + skip;
+    Some((&spec[..colon], &spec[colon + 1..]))
+}
+
+/// A host path in the form the daemon expects, Docker Desktop takes `C:\data` as `/c/data`.
+pub fn to_daemon_path(path: &str) -> String {
+    if !has_drive(path) {
+        return path.to_string();
+    }
+    let drive = path[..1].to_ascii_lowercase();
+    format!("/{}/{}", drive, path[3..].replace('\\', "/"))
+}
+
+/// Make a relative or home based host path absolute.
+pub fn resolve_host_path(source: &str, base: &Path) -> PathBuf {
+    if let Some(rest) = source.strip_prefix("~/") {
+        if let Some(home) = home::home_dir() {
+            return home.join(rest);
+        }
+    }
+    let path = Path::new(source);
+    if path.is_absolute() || has_drive(source) {
+        return path.to_path_buf();
+    }
+    base.join(path.strip_prefix("./").unwrap_or(path))
+}
+
+pub fn get_volume_name(app: &str, key: &str) -> String {
+    format!("ruku-{}-{}", app, key)
+}
+
+/// The named Docker volumes of an app.
+pub struct Volumes<'a> {
+    log: &'a Logger,
+    name: &'a str,
+    docker: &'a Docker,
+}
+
+impl<'a> Volumes<'a> {
+    pub fn new(log: &'a Logger, name: &'a str, docker: &'a Docker) -> Volumes<'a> {
+        Volumes { log, name, docker }
+    }
+
+    /// Create the configured named volumes that don't exist yet, labeled with the app.
+    pub async fn ensure(&self, specs: &[VolumeSpec]) -> Result<(), String> {
+        let existing = self.list().await?;
+        let existing_keys: Vec<String> = existing.iter().filter_map(get_volume_key).collect();
+        let configured_keys: Vec<&str> = specs
+            .iter()
+            .filter_map(|spec| match &spec.source {
+                VolumeSource::Named(key) => Some(key.as_str()),
+                VolumeSource::Host(_) => None,
+            })
+            .collect();
+
+        let new_keys: Vec<&str> = configured_keys
+            .iter()
+            .filter(|key| !existing_keys.iter().any(|k| k == *key))
+            .copied()
+            .collect();
+        let orphaned: Vec<&String> = existing_keys
+            .iter()
+            .filter(|key| !configured_keys.contains(&key.as_str()))
+            .collect();
+        if !new_keys.is_empty() && !orphaned.is_empty() {
+            for key in &orphaned {
+                self.log.warn(&format!(
+                    "Volume {} is no longer in the config, its data is kept but not mounted",
+                    get_volume_name(self.name, key)
+                ));
+            }
+            for key in &new_keys {
+                self.log.warn(&format!(
+                    "Volume {} will be created empty, if you renamed a volume key its data is still in the old volume",
+                    get_volume_name(self.name, key)
+                ));
+            }
+        }
+
+        for key in new_keys {
+            let volume_name = get_volume_name(self.name, key);
+            let options = CreateVolumeOptions {
+                name: volume_name.clone(),
+                labels: HashMap::from_iter(
+                    [
+                        (APP_LABEL.to_string(), self.name.to_string()),
+                        (VOLUME_LABEL.to_string(), key.to_string()),
+                    ]
+                    .into_iter()
+                    .chain(test_labels()),
+                ),
+                ..Default::default()
+            };
+            self.docker
+                .create_volume(options)
+                .await
+                .map_err(|e| format!("Failed to create volume {}: {}", volume_name, e))?;
+            self.log.step(&format!("Created volume {}", volume_name));
+        }
+        Ok(())
+    }
+
+    /// Write the content of the volume `key` to `out` as a tar archive.
+    pub async fn export(&self, key: &str, out: &mut impl Write) -> Result<(), String> {
+        let (name, guard) = self.copy_container(key).await?;
+        let options = DownloadFromContainerOptions {
+            path: VOLUME_COPY_MOUNT,
+        };
+        let mut stream = self.docker.download_from_container(&name, Some(options));
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk.map_err(|e| e.to_string())?;
+            out.write_all(&chunk).map_err(|e| e.to_string())?;
+        }
+        guard.remove().await;
+        Ok(())
+    }
+
+    /// Copy the content of a tar archive [`Volumes::export`] wrote into the existing volume `key`. Files
+    /// by the same name are overwritten.
+    pub async fn import(&self, key: &str, archive: &Path) -> Result<(), String> {
+        let mut file = File::open(archive).map_err(|e| e.to_string())?;
+        let (name, guard) = self.copy_container(key).await?;
+        // Read as Docker takes it, a volume may hold more than fits in memory
+        let chunks = futures_util::stream::unfold((), move |_| {
+            let mut chunk = vec![0; UPLOAD_CHUNK];
+            let read = file.read(&mut chunk);
+            async move {
+                match read {
+                    Ok(0) | Err(_) => None,
+                    Ok(read) => {
+                        chunk.truncate(read);
+                        Some((bytes::Bytes::from(chunk), ()))
+                    }
+                }
+            }
+        });
+        let options = UploadToContainerOptions {
+            path: "/",
+            ..Default::default()
+        };
+        self.docker
+            .upload_to_container_streaming(&name, Some(options), chunks)
+            .await
+            .map_err(|e| e.to_string())?;
+        guard.remove().await;
+        Ok(())
+    }
+
+    /// A container that is never started with the volume `key` mounted, its name and the guard removing it.
+    async fn copy_container(&self, key: &str) -> Result<(String, AuxGuard), String> {
+        let image = Image::new(self.log, self.docker);
+        if !image.exists(VOLUME_COPY_IMAGE).await {
+            image.pull(VOLUME_COPY_IMAGE).await?;
+        }
+        let volume_name = get_volume_name(self.name, key);
+        let name = format!("{}-copy", volume_name);
+        let config = Config {
+            image: Some(VOLUME_COPY_IMAGE.to_string()),
+            cmd: Some(vec!["true".to_string()]),
+            labels: Some(aux_labels(self.name, AuxKind::VolumeCopy)),
+            host_config: Some(HostConfig {
+                binds: Some(vec![format!("{}:{}", volume_name, VOLUME_COPY_MOUNT)]),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let options = CreateContainerOptions {
+            name: name.as_str(),
+            platform: None,
+        };
+        remove(self.docker, &name).await;
+        self.docker
+            .create_container(Some(options), config)
+            .await
+            .map_err(|e| format!("creating {}: {}", name, e))?;
+        let guard = AuxGuard::new(self.docker, &name);
+        Ok((name, guard))
+    }
+
+    /// The keys of the named volumes of `specs` that exist.
+    pub async fn existing_keys(&self, specs: &[VolumeSpec]) -> Result<Vec<String>, String> {
+        let existing: Vec<String> = self.list().await?.iter().filter_map(get_volume_key).collect();
+        let mut keys: Vec<String> = specs
+            .iter()
+            .filter_map(|spec| match &spec.source {
+                VolumeSource::Named(key) if existing.contains(key) => Some(key.clone()),
+                _ => None,
+            })
+            .collect();
+        keys.sort();
+        keys.dedup();
+        Ok(keys)
+    }
+
+    /// The volumes labeled with this app.
+    pub async fn list(&self) -> Result<Vec<Volume>, String> {
+        let app_filter = format!("{}={}", APP_LABEL, self.name);
+        let options = ListVolumesOptions {
+            filters: HashMap::from([("label".to_string(), vec![app_filter])]),
+        };
+        Ok(self
+            .docker
+            .list_volumes(Some(options))
+            .await
+            .map_err(|e| format!("Failed to list volumes: {}", e))?
+            .volumes
+            .unwrap_or_default())
+    }
+
+    /// Print the volumes of the app with their size and where they are mounted.
+    pub async fn print(&self, specs: &[VolumeSpec]) -> Result<(), String> {
+        let volumes = self.list().await?;
+        if volumes.is_empty() {
+            self.log.step(&format!("{} has no volumes", self.name));
+            return Ok(());
+        }
+
+        let sizes = self.sizes().await;
+
+        for volume in volumes {
+            let key = get_volume_key(&volume);
+            let target = specs
+                .iter()
+                .find(|spec| {
+                    key.as_deref()
+                        .is_some_and(|k| spec.source == VolumeSource::Named(k.to_string()))
+                })
+                .map(|spec| spec.target.as_str())
+                .unwrap_or("not mounted");
+            println!(
+                "{:<32} {:<24} {}",
+                volume.name,
+                target,
+                format_size(sizes.get(&volume.name))
+            );
+        }
+        Ok(())
+    }
+
+    /// One line per volume of the app with its size, for confirmation prompts.
+    pub async fn describe(&self) -> Result<Vec<String>, String> {
+        Ok(self
+            .sized()
+            .await?
+            .iter()
+            .map(|(name, size)| format!("volume {} ({})", name, format_size(size.as_ref())))
+            .collect())
+    }
+
+    /// The names of the volumes of this app with their sizes where the daemon knows them.
+    pub async fn sized(&self) -> Result<Vec<(String, Option<i64>)>, String> {
+        let sizes = self.sizes().await;
+        Ok(self
+            .list()
+            .await?
+            .into_iter()
+            .map(|volume| {
+                let size = sizes.get(&volume.name).copied();
+                (volume.name, size)
+            })
+            .collect())
+    }
+
+    /// Volume sizes by name, only the disk usage endpoint reports them.
+    async fn sizes(&self) -> HashMap<String, i64> {
+        self.docker
+            .df()
+            .await
+            .ok()
+            .and_then(|df| df.volumes)
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|v| v.usage_data.map(|usage| (v.name, usage.size)))
+            .collect()
+    }
+
+    /// Remove every volume labeled with this app.
+    pub async fn remove_all(&self) -> Result<(), String> {
+        for volume in self.list().await? {
+            self.docker
+                .remove_volume(&volume.name, None)
+                .await
+                .map_err(|e| format!("Failed to remove volume {}: {}", volume.name, e))?;
+            self.log.step(&format!("Removed volume {}", volume.name));
+        }
+        Ok(())
+    }
+}
+
+fn format_size(size: Option<&i64>) -> String {
+    match size {
+        Some(size) if *size >= 0 => units::format_size(*size as u64),
+        _ => "unknown size".to_string(),
+    }
+}
+
+fn get_volume_key(volume: &Volume) -> Option<String> {
+    volume.labels.get(VOLUME_LABEL).cloned()
+}
+
+/// Looks for bind mounts of an empty host directory over a path the image ships content in, which hides
+/// that content from the app, e.g. an empty `./public` over the built assets in `/app/public`.
+pub struct Shadowing<'a> {
+    log: &'a Logger,
+    name: &'a str,
+    docker: &'a Docker,
+}
+
+impl<'a> Shadowing<'a> {
+    pub fn new(log: &'a Logger, name: &'a str, docker: &'a Docker) -> Shadowing<'a> {
+        Shadowing { log, name, docker }
+    }
+
+    /// The bind mounts of `specs` whose empty host directory hides content of `image`, with their target.
+    pub async fn find(&self, image: &str, specs: &[VolumeSpec], container_name: &str) -> Vec<(PathBuf, String)> {
+        let candidates: Vec<(PathBuf, String)> = specs
+            .iter()
+            .filter_map(|spec| match &spec.source {
+                VolumeSource::Host(path) if is_empty_dir(path) => Some((path.clone(), spec.target.clone())),
+                _ => None,
+            })
+            .collect();
+        if candidates.is_empty() {
+            return vec![];
+        }
+
+        // Never started, the container only gives access to the image filesystem
+        let name = format!("{}-mount-check", container_name);
+        let config = Config {
+            image: Some(image.to_string()),
+            cmd: Some(vec!["true".to_string()]),
+            labels: Some(aux_labels(self.name, AuxKind::MountCheck)),
+            ..Default::default()
+        };
+        let options = CreateContainerOptions {
+            name: name.as_str(),
+            platform: None,
+        };
+        remove(self.docker, &name).await;
+        if let Err(e) = self.docker.create_container(Some(options), config).await {
+            self.log.warn(&format!(
+                "Could not check what the bind mounts hide in {}: {}",
+                image, e
+            ));
+            return vec![];
+        }
+        let guard = AuxGuard::new(self.docker, &name);
+        let mut shadowed = vec![];
+        for (path, target) in candidates {
+            if self.ships_content(&name, &target).await {
+                shadowed.push((path, target));
+            }
+        }
+        guard.remove().await;
+        shadowed
+    }
+
+    /// Warn about each bind mount hiding content of `image`, or fail when `strict`.
+    pub async fn check(
+        &self,
+        image: &str,
+        specs: &[VolumeSpec],
+        container_name: &str,
+        strict: bool,
+    ) -> Result<(), String> {
+        let shadowed = self.find(image, specs, container_name).await;
+        for (path, target) in &shadowed {
+            let message = format!(
+                "Bind mount {} is empty and hides what {} ships in {}, the app sees an empty directory there. \
+                 Use a named volume like `{}:{}` instead, Docker fills a new named volume with the image \
+                 content when it is first mounted",
+                path.display(),
+                image,
+                target,
+                target
+                    .trim_matches('/')
+                    .rsplit('/')
+                    .next()
+                    .filter(|key| !key.is_empty())
+                    .unwrap_or("data"),
+                target
+            );
+            match strict {
+                true => self.log.error(&message),
+                false => self.log.warn(&message),
+            }
+        }
+        if strict && !shadowed.is_empty() {
+            return Err("Not deploying with strict_mounts, fill or change the bind mounts above".to_string());
+        }
+        Ok(())
+    }
+
+    /// Whether the image has a directory at `target` that is not empty.
+    async fn ships_content(&self, container_name: &str, target: &str) -> bool {
+        let options = DownloadFromContainerOptions { path: target };
+        let mut stream = self.docker.download_from_container(container_name, Some(options));
+        let mut archive = vec![];
+        // The first entries tell an empty directory apart, the rest of the archive is not needed
+        while archive.len() < ARCHIVE_PEEK {
+            match stream.next().await {
+                Some(Ok(chunk)) => archive.extend_from_slice(&chunk),
+                // Not in the image, so there is nothing to hide
+                Some(Err(_)) => return false,
+                None => break,
+            }
+        }
+        let mut archive = tar::Archive::new(archive.as_slice());
+        let Ok(entries) = archive.entries() else {
+            return false;
+        };
+        let mut entries = entries.map_while(Result::ok).filter(|entry| {
+            !matches!(
+                entry.header().entry_type(),
+                EntryType::XHeader | EntryType::XGlobalHeader
+            )
+        });
+        match entries.next() {
+            Some(root) if root.header().entry_type().is_dir() => entries.next().is_some(),
+            _ => false,
+        }
+    }
+}
+
+/// Whether `path` is an empty directory or missing, Docker creates a missing bind source empty.
+fn is_empty_dir(path: &Path) -> bool {
+    match std::fs::read_dir(path) {
+        Ok(mut entries) => entries.next().is_none(),
+        Err(e) => e.kind() == std::io::ErrorKind::NotFound,
+    }
+}
+
+/// What preparing a bind mounted host directory does.
+#[derive(Debug, Clone, PartialEq)]
+pub enum HostPathAction {
+    /// The directory is there and writable.
+    Keep,
+    /// The directory is created with this owner, or as the user ruku runs as without one.
+    Create { owner: Option<Owner>, mode: u32 },
+}
+
+/// Creates the missing host directories of bind mounts, which Docker would otherwise create owned by
+/// root so an app running as another user can't write to them.
+pub struct HostPaths<'a> {
+    log: &'a Logger,
+    config: &'a HostPathsConfig,
+}
+
+impl<'a> HostPaths<'a> {
+    pub fn new(log: &'a Logger, config: &'a HostPathsConfig) -> HostPaths<'a> {
+        HostPaths { log, config }
+    }
+
+    /// The configured owner, or the image user when it is numeric.
+    pub fn owner(&self, image_user: Option<&str>) -> Option<Owner> {
+        if self.config.owner.is_some() {
+            return self.config.owner;
+        }
+        let user = image_user?;
+        let owner = user.parse().ok();
+        if owner.is_none() {
+            self.log.warn(&format!(
+                "The image runs as user {}, set create_host_paths.owner to its uid for the host directories",
+                user
+            ));
+        }
+        owner
+    }
+
+    /// What each host directory of `specs` needs. Fails when a path is not a directory, or a writable
+    /// mount can't be written to by `owner`.
+    pub fn plan(&self, specs: &[VolumeSpec], owner: Option<Owner>) -> Result<Vec<(PathBuf, HostPathAction)>, String> {
+        let mut plan = vec![];
+        for spec in specs {
+            let VolumeSource::Host(path) = &spec.source else {
+                continue;
+            };
+            if plan.iter().any(|(planned, _)| planned == path) {
+                continue;
+            }
+            let action = match path.metadata() {
+                Ok(metadata) if !metadata.is_dir() => {
+                    return Err(format!(
+                        "{} is mounted at {} but is not a directory, create_host_paths only handles directories",
+                        path.display(),
+                        spec.target
+                    ));
+                }
+                Ok(metadata) => {
+                    if !spec.read_only && !is_writable(path, &metadata, owner) {
+                        let by = owner
+                            .map(|owner| format!("user {}", owner))
+                            .unwrap_or("ruku".to_string());
+                        return Err(format!(
+                            "{} is mounted writable at {} but {} can't write to it",
+                            path.display(),
+                            spec.target,
+                            by
+                        ));
+                    }
+                    HostPathAction::Keep
+                }
+                Err(_) => HostPathAction::Create {
+                    owner,
+                    mode: self.config.mode,
+                },
+            };
+            plan.push((path.clone(), action));
+        }
+        Ok(plan)
+    }
+
+    /// Create the missing host directories of `specs`, failing when one can't be used or created.
+    pub fn ensure(&self, specs: &[VolumeSpec], owner: Option<Owner>) -> Result<(), String> {
+        let plan = self.plan(specs, owner)?;
+        for (path, action) in plan {
+            let HostPathAction::Create { owner, mode } = action else {
+                continue;
+            };
+            create_dir(&path, owner, mode).map_err(|e| format!("Failed to create {}: {}", path.display(), e))?;
+            self.log
+                .step(&format!("Created {}", describe_host_path(&path, owner, mode)));
+        }
+        Ok(())
+    }
+}
+
+/// A host directory with the owner and mode it is created with, for the log and the dry run.
+pub fn describe_host_path(path: &Path, owner: Option<Owner>, mode: u32) -> String {
+    let owner = owner
+        .map(|owner| format!("owned by {}", owner))
+        .unwrap_or("owned by ruku's user".to_string());
+    format!("{} {} with mode {:04o}", path.display(), owner, mode)
+}
+
+fn create_dir(path: &Path, owner: Option<Owner>, mode: u32) -> std::io::Result<()> {
+    std::fs::create_dir_all(path)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+        if let Some(owner) = owner {
+            std::os::unix::fs::chown(path, Some(owner.uid), owner.gid)?;
+        }
+    }
+    Ok(())
+}
+
+/// Whether `owner` may write to the directory going by its permission bits, without an owner whether
+/// ruku itself can create a file in it.
+fn is_writable(path: &Path, metadata: &std::fs::Metadata, owner: Option<Owner>) -> bool {
+    match owner {
+        #[cfg(unix)]
+        Some(owner) => {
+            use std::os::unix::fs::MetadataExt;
+            let mode = metadata.mode();
+            owner.uid == 0
+                || (metadata.uid() == owner.uid && mode & 0o200 != 0)
+                || (owner.gid == Some(metadata.gid()) && mode & 0o020 != 0)
+                || mode & 0o002 != 0
+        }
+        _ => tempfile::tempfile_in(path).is_ok(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn windows_drive_letters_stay_in_the_source() {
+        assert_eq!(split_source("C:\\data:/data"), Some(("C:\\data", "/data")));
+        assert_eq!(split_source("d:/cache:/cache:ro"), Some(("d:/cache", "/cache:ro")));
+        assert_eq!(split_source("./data:/data"), Some(("./data", "/data")));
+        assert_eq!(split_source("uploads:/srv/uploads"), Some(("uploads", "/srv/uploads")));
+        assert_eq!(split_source("/data"), None);
+        // A letter and a colon before a slash is taken for a drive, not for a volume named `c`
+        assert_eq!(split_source("c:/data"), None);
+    }
+
+    #[test]
+    fn drive_paths_are_given_to_the_daemon_the_docker_desktop_way() {
+        assert_eq!(to_daemon_path("C:\\Users\\dev\\data"), "/c/Users/dev/data");
+        assert_eq!(to_daemon_path("D:/cache"), "/d/cache");
+        assert_eq!(to_daemon_path("/srv/data"), "/srv/data");
+        assert_eq!(to_daemon_path("./data"), "./data");
+        assert_eq!(to_daemon_path("C:"), "C:");
+    }
+
+    #[test]
+    fn host_paths_are_told_from_volume_names() {
+        for source in ["/srv", "./data", "../data", "~/data", "C:\\data", "c:/data"] {
+            assert!(is_host_path(source), "{}", source);
+        }
+        for source in ["data", "c", "C:data"] {
+            assert!(!is_host_path(source), "{}", source);
+        }
+    }
+}
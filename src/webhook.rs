@@ -0,0 +1,146 @@
+//! Notices posted to an endpoint of the operator's when something needs a person, e.g. a chat or paging
+//! webhook. A notice is a JSON object tagged by `event`, tried again while the endpoint can't take it.
+
+use std::io::Write;
+use std::time::Duration;
+
+use cmd_lib::run_fun;
+use serde_json::Value;
+
+/// Seconds one delivery may take.
+const DELIVERY_TIMEOUT: u64 = 10;
+/// Deliveries tried before a notice is given up on.
+const ATTEMPTS: u32 = 4;
+/// The wait before the first retry, doubled for every one after it.
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Posts notices to `url`.
+#[derive(Debug, Clone)]
+pub struct Webhook {
+    url: String,
+    attempts: u32,
+    retry_delay: Duration,
+}
+
+impl Webhook {
+    pub fn new(url: &str) -> Webhook {
+        Webhook {
+            url: url.to_string(),
+            attempts: ATTEMPTS,
+            retry_delay: RETRY_DELAY,
+        }
+    }
+
+    /// Post `notice`, trying again after a growing wait while the endpoint is unreachable or answers with
+    /// an error status.
+    pub async fn send(&self, notice: &Value) -> Result<(), String> {
+        let body = notice.to_string();
+        let mut delay = self.retry_delay;
+        for attempt in 1..=self.attempts {
+            let (url, body) = (self.url.clone(), body.clone());
+            let delivered = tokio::task::spawn_blocking(move || deliver(&url, &body))
+                .await
+                .map_err(|e| e.to_string())?;
+            if delivered.is_ok() {
+                return Ok(());
+            }
+            if attempt < self.attempts {
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+            }
+        }
+        Err(format!(
+            "{} could not be reached or refused the notice {} times",
+            self.url, self.attempts
+        ))
+    }
+}
+
+/// Post `body` to `url` once.
+fn deliver(url: &str, body: &str) -> Result<(), String> {
+    let mut file = tempfile::NamedTempFile::new().map_err(|e| e.to_string())?;
+    file.write_all(body.as_bytes()).map_err(|e| e.to_string())?;
+    let data = format!("@{}", file.path().display());
+    let timeout = DELIVERY_TIMEOUT.to_string();
+    run_fun!(
+        curl --silent --fail --max-time $timeout
+            --header "Content-Type: application/json" --data-binary $data $url
+    )
+    .map(|_| ())
+    .map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::io::{BufRead, BufReader, Read};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    use serde_json::json;
+
+    use super::*;
+
+    /// An endpoint answering with `statuses` in turn and 200 after them, and the bodies it was posted.
+    pub fn endpoint(statuses: &[u16]) -> (String, Arc<Mutex<Vec<Value>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hooks/ruku", listener.local_addr().unwrap());
+        let bodies = Arc::new(Mutex::new(vec![]));
+        let received = bodies.clone();
+        let statuses = statuses.to_vec();
+        thread::spawn(move || {
+            let mut statuses = statuses.into_iter();
+            for stream in listener.incoming().flatten() {
+                let mut reader = BufReader::new(&stream);
+                let mut length = 0;
+                let mut line = String::new();
+                while reader.read_line(&mut line).is_ok_and(|read| read > 2) {
+                    if let Some((name, value)) = line.split_once(':') {
+                        if name.eq_ignore_ascii_case("content-length") {
+                            length = value.trim().parse().unwrap_or(0);
+                        }
+                    }
+                    line.clear();
+                }
+                let mut body = vec![0; length];
+                let _ = reader.read_exact(&mut body);
+                received
+                    .lock()
+                    .unwrap()
+                    .push(serde_json::from_slice(&body).unwrap_or_default());
+                let status = statuses.next().unwrap_or(200);
+                let _ = (&stream).write_all(
+                    format!(
+                        "HTTP/1.1 {} Status\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                        status
+                    )
+                    .as_bytes(),
+                );
+            }
+        });
+        (url, bodies)
+    }
+
+    fn webhook(url: &str) -> Webhook {
+        Webhook {
+            retry_delay: Duration::from_millis(10),
+            ..Webhook::new(url)
+        }
+    }
+
+    #[tokio::test]
+    async fn notices_are_retried_until_the_endpoint_takes_them() {
+        let (url, bodies) = endpoint(&[503, 500]);
+        let notice = json!({ "event": "docker_events_lost", "message": "gone" });
+        webhook(&url).send(&notice).await.unwrap();
+        assert_eq!(*bodies.lock().unwrap(), [notice.clone(), notice.clone(), notice]);
+    }
+
+    #[tokio::test]
+    async fn a_notice_is_given_up_on_after_the_attempts() {
+        let (url, bodies) = endpoint(&[500; 8]);
+        let error = webhook(&url).send(&json!({ "event": "test" })).await.unwrap_err();
+        assert!(error.contains("4 times"), "{}", error);
+        assert_eq!(bodies.lock().unwrap().len(), 4);
+    }
+}
@@ -0,0 +1,280 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use serde::de::{self, DeserializeSeed, IgnoredAny, MapAccess, SeqAccess, Visitor};
+use serde_yaml::Value;
+
+/// Where a mapping key of a YAML document was written.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeySpan {
+    /// 1-based line of the key.
+    pub line: usize,
+    /// The value of the key when it is a string.
+    pub scalar: Option<String>,
+}
+
+/// The message of the error [`Seek`] stops the parse with at the key it looks for, serde_yaml puts the
+/// location of the key on it.
+const FOUND: &str = "ruku: key found";
+
+/// The span of every mapping key of a YAML document by its dotted path, e.g. `canary.pause`. The keys
+/// within sequences are left out, a sequence is a single value. A document that doesn't parse has none,
+/// serde_yaml reports the error when it loads the same document.
+///
+/// serde_yaml keeps no locations in its values, but it puts the location of the event it was at on an
+/// error. Each key is found by parsing the document again with a [`Seek`] for it, which fails at the key.
+pub fn key_spans(content: &str) -> BTreeMap<String, KeySpan> {
+    let mut spans = BTreeMap::new();
+    let Ok(document) = serde_yaml::from_str::<Value>(content) else {
+        return spans;
+    };
+    let mut keys = vec![];
+    collect_keys(&mut vec![], &document, &mut keys);
+    for (path, value) in keys {
+        let seek = Seek { path: &path };
+        let Err(error) = seek.deserialize(serde_yaml::Deserializer::from_str(content)) else {
+            continue;
+        };
+        if let (true, Some(location)) = (error.to_string().contains(FOUND), error.location()) {
+            let scalar = match value {
+                Value::String(string) => Some(string.clone()),
+                _ => None,
+            };
+            spans.insert(
+                path.join("."),
+                KeySpan {
+                    line: location.line(),
+                    scalar,
+                },
+            );
+        }
+    }
+    spans
+}
+
+/// The path and value of every mapping key under `value`, outside of sequences.
+fn collect_keys<'v>(prefix: &mut Vec<String>, value: &'v Value, keys: &mut Vec<(Vec<String>, &'v Value)>) {
+    if let Value::Mapping(mapping) = value {
+        for (key, value) in mapping {
+            let Some(key) = key_string(key) else {
+                continue;
+            };
+            prefix.push(key);
+            keys.push((prefix.clone(), value));
+            collect_keys(prefix, value, keys);
+            prefix.pop();
+        }
+    }
+}
+
+/// A scalar key as it is matched by [`KeySeed`], none for a mapping or sequence used as a key.
+fn key_string(key: &Value) -> Option<String> {
+    match key {
+        Value::String(string) => Some(string.clone()),
+        Value::Number(number) => Some(number.to_string()),
+        Value::Bool(bool) => Some(bool.to_string()),
+        Value::Null => Some("null".to_string()),
+        _ => None,
+    }
+}
+
+/// Walks a document down `path` and fails with [`FOUND`] at its last key.
+struct Seek<'p> {
+    path: &'p [String],
+}
+
+impl<'de> DeserializeSeed<'de> for Seek<'_> {
+    type Value = ();
+
+    fn deserialize<D: de::Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_any(self)
+    }
+}
+
+impl<'de> Visitor<'de> for Seek<'_> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "any YAML value")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        let Some((target, rest)) = self.path.split_first() else {
+            return Ok(());
+        };
+        let seed = || KeySeed {
+            target,
+            last: rest.is_empty(),
+        };
+        while let Some(found) = map.next_key_seed(seed())? {
+            match found {
+                true => map.next_value_seed(Seek { path: rest })?,
+                false => map.next_value::<IgnoredAny>().map(|_| ())?,
+            }
+        }
+        Ok(())
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+        while seq.next_element::<IgnoredAny>()?.is_some() {}
+        Ok(())
+    }
+
+    fn visit_enum<A: de::EnumAccess<'de>>(self, data: A) -> Result<(), A::Error> {
+        // A tagged value, e.g. `!include`, is left out like a sequence
+        let (IgnoredAny, variant) = data.variant::<IgnoredAny>()?;
+        de::VariantAccess::newtype_variant::<IgnoredAny>(variant).map(|_| ())
+    }
+
+    fn visit_bool<E>(self, _: bool) -> Result<(), E> {
+        Ok(())
+    }
+
+    fn visit_i64<E>(self, _: i64) -> Result<(), E> {
+        Ok(())
+    }
+
+    fn visit_u64<E>(self, _: u64) -> Result<(), E> {
+        Ok(())
+    }
+
+    fn visit_f64<E>(self, _: f64) -> Result<(), E> {
+        Ok(())
+    }
+
+    fn visit_str<E>(self, _: &str) -> Result<(), E> {
+        Ok(())
+    }
+
+    fn visit_unit<E>(self) -> Result<(), E> {
+        Ok(())
+    }
+}
+
+/// Reads a mapping key and tells whether it is `target`, failing with [`FOUND`] when it is the last key
+/// of the path.
+struct KeySeed<'p> {
+    target: &'p str,
+    last: bool,
+}
+
+impl KeySeed<'_> {
+    fn matches<E: de::Error>(&self, key: &str) -> Result<bool, E> {
+        match key == self.target {
+            true if self.last => Err(E::custom(FOUND)),
+            matches => Ok(matches),
+        }
+    }
+}
+
+impl<'de> DeserializeSeed<'de> for KeySeed<'_> {
+    type Value = bool;
+
+    fn deserialize<D: de::Deserializer<'de>>(self, deserializer: D) -> Result<bool, D::Error> {
+        deserializer.deserialize_any(self)
+    }
+}
+
+impl<'de> Visitor<'de> for KeySeed<'_> {
+    type Value = bool;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a mapping key")
+    }
+
+    fn visit_str<E: de::Error>(self, key: &str) -> Result<bool, E> {
+        self.matches(key)
+    }
+
+    fn visit_bool<E: de::Error>(self, key: bool) -> Result<bool, E> {
+        self.matches(&key.to_string())
+    }
+
+    fn visit_i64<E: de::Error>(self, key: i64) -> Result<bool, E> {
+        self.matches(&key.to_string())
+    }
+
+    fn visit_u64<E: de::Error>(self, key: u64) -> Result<bool, E> {
+        self.matches(&key.to_string())
+    }
+
+    fn visit_f64<E: de::Error>(self, key: f64) -> Result<bool, E> {
+        self.matches(&serde_yaml::Number::from(key).to_string())
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<bool, E> {
+        self.matches("null")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<bool, A::Error> {
+        while map.next_entry::<IgnoredAny, IgnoredAny>()?.is_some() {}
+        Ok(false)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<bool, A::Error> {
+        while seq.next_element::<IgnoredAny>()?.is_some() {}
+        Ok(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(content: &str) -> Vec<(String, usize)> {
+        key_spans(content)
+            .into_iter()
+            .map(|(key, span)| (key, span.line))
+            .collect()
+    }
+
+    #[test]
+    fn keys_are_found_in_any_style() {
+        let content = "\
+# comment
+port: 3000
+canary: {port: 3001,
+  steps: [10, 50]}
+env:
+  \"QUOTED\": 'a: b'
+  MULTI: |
+    line: one
+sidecars:
+  - name: db
+    image: postgres
+version: \"1.0\"
+";
+        let expected = [
+            ("canary", 3),
+            ("canary.port", 3),
+            ("canary.steps", 4),
+            ("env", 5),
+            ("env.MULTI", 7),
+            ("env.QUOTED", 6),
+            ("port", 2),
+            ("sidecars", 9),
+            ("version", 12),
+        ];
+        assert_eq!(lines(content), expected.map(|(key, line)| (key.to_string(), line)));
+        assert_eq!(key_spans(content)["env.QUOTED"].scalar.as_deref(), Some("a: b"));
+        assert_eq!(key_spans(content)["canary"].scalar, None);
+    }
+
+    #[test]
+    fn keys_that_are_not_strings_and_repeated_names_are_told_apart() {
+        let content = "ports:\n  8080: web\nweb:\n  port: 1\nport: 2\n";
+        let expected = [
+            ("port", 5),
+            ("ports", 1),
+            ("ports.8080", 2),
+            ("web", 3),
+            ("web.port", 4),
+        ];
+        assert_eq!(lines(content), expected.map(|(key, line)| (key.to_string(), line)));
+    }
+
+    #[test]
+    fn a_document_that_does_not_parse_has_no_spans() {
+        assert!(key_spans("port: 3000\nenv: [\n").is_empty());
+    }
+}
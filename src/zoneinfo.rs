@@ -0,0 +1,477 @@
+//! Named timezones like `Europe/Berlin`, read from the compiled tz database the host has in
+//! `/usr/share/zoneinfo`, or in `TZDIR` like the C library reads it.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use chrono::{Datelike, NaiveDate, Weekday};
+
+const ZONEINFO_DIR: &str = "/usr/share/zoneinfo";
+const TZDIR_ENV: &str = "TZDIR";
+
+/// The offsets from UTC of a named timezone over time, with its daylight saving changes.
+#[derive(Debug, Clone)]
+pub struct NamedZone {
+    pub name: String,
+    /// The UTC seconds of each change of offset and the offset from then on, in order.
+    transitions: Vec<(i64, i32)>,
+    /// The offset before the first change.
+    initial: i32,
+    /// How the offset changes after the last listed change, from the footer of the file.
+    rule: Option<Rule>,
+}
+
+impl PartialEq for NamedZone {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+    }
+}
+
+impl NamedZone {
+    /// The directory the tz database is read from.
+    pub fn dir() -> PathBuf {
+        std::env::var_os(TZDIR_ENV).map_or(PathBuf::from(ZONEINFO_DIR), PathBuf::from)
+    }
+
+    /// The zone `name` from the tz database in `dir`.
+    pub fn load(dir: &Path, name: &str) -> Result<NamedZone, String> {
+        let valid = !name.is_empty()
+            && name
+                .split('/')
+                .all(|part| !part.is_empty() && part != "." && part != "..")
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || "/_+-".contains(c));
+        if !valid {
+            return Err(format!("'{}' is not a timezone name", name));
+        }
+        let data = fs::read(dir.join(name)).map_err(|_| format!("no timezone {} in {}", name, dir.display()))?;
+        NamedZone::parse(name, &data)
+    }
+
+    /// A zone from the TZif data of a compiled tz database file.
+    pub fn parse(name: &str, data: &[u8]) -> Result<NamedZone, String> {
+        let invalid = || format!("timezone {} is not a valid TZif file", name);
+        let mut reader = Reader { data, position: 0 };
+        let header = Header::read(&mut reader).ok_or_else(invalid)?;
+        // The 64-bit data of version 2 and later follows the 32-bit data, which is left for older readers
+        let (header, time_size) = match header.version {
+            0 => (header, 4),
+            _ => {
+                reader.skip(header.data_size(4)).ok_or_else(invalid)?;
+                (Header::read(&mut reader).ok_or_else(invalid)?, 8)
+            }
+        };
+
+        let times = (0..header.transitions)
+            .map(|_| reader.int(time_size))
+            .collect::<Option<Vec<i64>>>()
+            .ok_or_else(invalid)?;
+        let indices = reader.bytes(header.transitions).ok_or_else(invalid)?.to_vec();
+        let offsets = (0..header.types)
+            .map(|_| {
+                let offset = reader.int(4)? as i32;
+                reader.skip(2)?;
+                Some(offset)
+            })
+            .collect::<Option<Vec<i32>>>()
+            .ok_or_else(invalid)?;
+        let transitions = times
+            .into_iter()
+            .zip(indices)
+            .map(|(time, index)| offsets.get(usize::from(index)).map(|offset| (time, *offset)))
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(invalid)?;
+        let initial = *offsets.first().ok_or_else(invalid)?;
+
+        let rule = match header.version {
+            0 => None,
+            _ => {
+                let rest = header.data_size(time_size) - header.transitions * (time_size + 1) - header.types * 6;
+                reader.skip(rest).ok_or_else(invalid)?;
+                let footer = String::from_utf8_lossy(reader.rest());
+                let footer = footer.trim_matches('\n');
+                match footer.is_empty() {
+                    true => None,
+                    false => {
+                        Some(Rule::parse(footer).ok_or(format!("timezone {} has an invalid rule '{}'", name, footer))?)
+                    }
+                }
+            }
+        };
+        Ok(NamedZone {
+            name: name.to_string(),
+            transitions,
+            initial,
+            rule,
+        })
+    }
+
+    /// The offset from UTC in seconds at the unix time `utc`.
+    pub fn offset_at(&self, utc: i64) -> i32 {
+        let after = self.transitions.partition_point(|(time, _)| *time <= utc);
+        match &self.rule {
+            Some(rule) if after == self.transitions.len() => rule.offset_at(utc),
+            _ if after == 0 => self.initial,
+            _ => self.transitions[after - 1].1,
+        }
+    }
+
+    /// The unix times the wall time `local`, in seconds as if it were UTC, is at: none in the gap of a
+    /// change forward, two in the hour a change back repeats.
+    pub fn instants(&self, local: i64) -> Vec<i64> {
+        // The offsets in effect within a day of it, a zone changes offset less often than that
+        let mut offsets = vec![
+            self.offset_at(local - 86_400),
+            self.offset_at(local),
+            self.offset_at(local + 86_400),
+        ];
+        offsets.dedup();
+        let mut instants: Vec<i64> = offsets
+            .into_iter()
+            .map(|offset| local - i64::from(offset))
+            .filter(|utc| i64::from(self.offset_at(*utc)) == local - utc)
+            .collect();
+        instants.sort();
+        instants.dedup();
+        instants
+    }
+}
+
+/// A POSIX TZ rule like `CET-1CEST,M3.5.0,M10.5.0/3`.
+#[derive(Debug, Clone, PartialEq)]
+struct Rule {
+    /// The offset east of UTC outside daylight saving.
+    standard: i32,
+    /// The daylight saving offset and when it starts and ends, in the local time before each change.
+    dst: Option<(i32, Change, Change)>,
+}
+
+impl Rule {
+    fn parse(text: &str) -> Option<Rule> {
+        let mut parser = Parser(text);
+        parser.name()?;
+        // POSIX offsets are west of UTC
+        let standard = -parser.duration()?;
+        if parser.0.is_empty() {
+            return Some(Rule { standard, dst: None });
+        }
+        parser.name()?;
+        let daylight = match parser.0.starts_with(',') {
+            true => standard + 3600,
+            false => -parser.duration()?,
+        };
+        let start = parser.change()?;
+        let end = parser.change()?;
+        parser.0.is_empty().then_some(Rule {
+            standard,
+            dst: Some((daylight, start, end)),
+        })
+    }
+
+    fn offset_at(&self, utc: i64) -> i32 {
+        let Some((daylight, start, end)) = &self.dst else {
+            return self.standard;
+        };
+        let year = chrono::DateTime::from_timestamp(utc + i64::from(self.standard), 0).map_or(1970, |time| time.year());
+        // Each change is at a local time in the offset before it
+        let starts = start.local(year) - i64::from(self.standard);
+        let ends = end.local(year) - i64::from(*daylight);
+        let in_dst = match starts < ends {
+            true => starts <= utc && utc < ends,
+            // Southern hemisphere, daylight saving spans the new year
+            false => !(ends <= utc && utc < starts),
+        };
+        match in_dst {
+            true => *daylight,
+            false => self.standard,
+        }
+    }
+}
+
+/// The day and local time a change of a [`Rule`] happens on each year.
+#[derive(Debug, Clone, PartialEq)]
+struct Change {
+    day: Day,
+    /// Seconds after the start of the day, may be negative or past a day.
+    time: i64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Day {
+    /// `Jn`, 1 to 365, February 29 is never counted.
+    Julian(u16),
+    /// `n`, 0 to 365, February 29 is counted in leap years.
+    Ordinal(u16),
+    /// `Mm.w.d`, weekday `d` of week `w` of month `m`, week 5 being the last.
+    Weekday { month: u32, week: u8, weekday: Weekday },
+}
+
+impl Change {
+    /// The local time of the change in `year`, in seconds as if it were UTC.
+    fn local(&self, year: i32) -> i64 {
+        let date = match self.day {
+            Day::Julian(day) => {
+                let leap = NaiveDate::from_ymd_opt(year, 2, 29).is_some();
+                let day = u32::from(day) + u32::from(leap && day >= 60);
+                NaiveDate::from_yo_opt(year, day)
+            }
+            Day::Ordinal(day) => NaiveDate::from_yo_opt(year, u32::from(day) + 1),
+            Day::Weekday { month, week, weekday } => (1..=week)
+                .rev()
+                .find_map(|week| NaiveDate::from_weekday_of_month_opt(year, month, weekday, week)),
+        };
+        let midnight = date.map_or(0, |date| date.and_time(chrono::NaiveTime::MIN).and_utc().timestamp());
+        midnight + self.time
+    }
+}
+
+/// Reads a [`Rule`] from the front of the text.
+struct Parser<'a>(&'a str);
+
+impl Parser<'_> {
+    /// A zone abbreviation, `CET` or quoted like `<+0330>`.
+    fn name(&mut self) -> Option<()> {
+        let end = match self.0.strip_prefix('<') {
+            Some(quoted) => quoted.find('>')? + 2,
+            None => self.0.find(|c: char| !c.is_ascii_alphabetic()).unwrap_or(self.0.len()),
+        };
+        (end >= 3).then(|| self.0 = &self.0[end..])
+    }
+
+    /// `[+-]hh[:mm[:ss]]` in seconds.
+    fn duration(&mut self) -> Option<i32> {
+        let sign = match self.0.chars().next()? {
+            '-' => -1,
+            _ => 1,
+        };
+        self.0 = self.0.strip_prefix(['+', '-']).unwrap_or(self.0);
+        let end = self
+            .0
+            .find(|c: char| !c.is_ascii_digit() && c != ':')
+            .unwrap_or(self.0.len());
+        let (text, rest) = self.0.split_at(end);
+        self.0 = rest;
+        let mut parts = text.split(':').map(|part| part.parse::<i32>().ok());
+        let hours = parts.next()??;
+        let minutes = parts.next().unwrap_or(Some(0))?;
+        let seconds = parts.next().unwrap_or(Some(0))?;
+        Some(sign * (hours * 3600 + minutes * 60 + seconds))
+    }
+
+    /// `,date[/time]`, at 02:00 when no time is given.
+    fn change(&mut self) -> Option<Change> {
+        self.0 = self.0.strip_prefix(',')?;
+        let end = self.0.find([',', '/']).unwrap_or(self.0.len());
+        let (day, rest) = self.0.split_at(end);
+        self.0 = rest;
+        let day = if let Some(day) = day.strip_prefix('J') {
+            Day::Julian(day.parse().ok().filter(|day| (1..=365).contains(day))?)
+        } else if let Some(day) = day.strip_prefix('M') {
+            let mut parts = day.split('.').map(|part| part.parse::<u8>().ok());
+            let (month, week, weekday) = (parts.next()??, parts.next()??, parts.next()??);
+            if !(1..=12).contains(&month) || !(1..=5).contains(&week) || weekday > 6 || parts.next().is_some() {
+                return None;
+            }
+            Day::Weekday {
+                month: u32::from(month),
+                week,
+                weekday: Weekday::try_from(weekday).ok()?.pred(),
+            }
+        } else {
+            Day::Ordinal(day.parse().ok().filter(|day| *day <= 365)?)
+        };
+        let time = match self.0.strip_prefix('/') {
+            Some(rest) => {
+                self.0 = rest;
+                self.duration()?
+            }
+            None => 7200,
+        };
+        Some(Change {
+            day,
+            time: i64::from(time),
+        })
+    }
+}
+
+/// The counts in a TZif header.
+struct Header {
+    version: u8,
+    is_ut: usize,
+    is_std: usize,
+    leaps: usize,
+    transitions: usize,
+    types: usize,
+    chars: usize,
+}
+
+impl Header {
+    fn read(reader: &mut Reader) -> Option<Header> {
+        if reader.bytes(4)? != b"TZif" {
+            return None;
+        }
+        let version = match reader.bytes(1)?[0] {
+            0 => 0,
+            version => version - b'0',
+        };
+        reader.skip(15)?;
+        let mut count = || reader.int(4).map(|count| count as usize);
+        Some(Header {
+            version,
+            is_ut: count()?,
+            is_std: count()?,
+            leaps: count()?,
+            transitions: count()?,
+            types: count()?,
+            chars: count()?,
+        })
+    }
+
+    /// The size of the data after the header, with times of `time_size` bytes.
+    fn data_size(&self, time_size: usize) -> usize {
+        self.transitions * (time_size + 1)
+            + self.types * 6
+            + self.chars
+            + self.leaps * (time_size + 4)
+            + self.is_std
+            + self.is_ut
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, count: usize) -> Option<&'a [u8]> {
+        let bytes = self.data.get(self.position..self.position.checked_add(count)?)?;
+        self.position += count;
+        Some(bytes)
+    }
+
+    fn skip(&mut self, count: usize) -> Option<()> {
+        self.bytes(count).map(|_| ())
+    }
+
+    /// A big-endian signed integer of `size` bytes.
+    fn int(&mut self, size: usize) -> Option<i64> {
+        let bytes = self.bytes(size)?;
+        Some(match size {
+            4 => i64::from(i32::from_be_bytes(bytes.try_into().ok()?)),
+            _ => i64::from_be_bytes(bytes.try_into().ok()?),
+        })
+    }
+
+    fn rest(&self) -> &'a [u8] {
+        &self.data[self.position.min(self.data.len())..]
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use chrono::DateTime;
+
+    use super::*;
+
+    /// The TZif data of a version 2 file with the 64-bit `transitions` to the offsets of `types`.
+    pub fn tzif(transitions: &[(i64, u8)], types: &[i32], footer: &str) -> Vec<u8> {
+        let header = |transitions: usize, types: usize, chars: usize| {
+            let mut header = b"TZif2".to_vec();
+            header.extend([0; 15]);
+            for count in [0, 0, 0, transitions, types, chars] {
+                header.extend((count as u32).to_be_bytes());
+            }
+            header
+        };
+        // No 32-bit data, readers of version 2 skip it
+        let mut data = header(0, 0, 0);
+        data.extend(header(transitions.len(), types.len(), 1));
+        for (time, _) in transitions {
+            data.extend(time.to_be_bytes());
+        }
+        data.extend(transitions.iter().map(|(_, index)| index));
+        for offset in types {
+            data.extend(offset.to_be_bytes());
+            data.extend([0, 0]);
+        }
+        data.push(0);
+        data.extend(format!("\n{}\n", footer).into_bytes());
+        data
+    }
+
+    fn unix(text: &str) -> i64 {
+        DateTime::parse_from_rfc3339(text).unwrap().timestamp()
+    }
+
+    fn zone(footer: &str) -> NamedZone {
+        NamedZone::parse("Test/Zone", &tzif(&[], &[0], footer)).unwrap()
+    }
+
+    #[test]
+    fn rules_change_the_offset_at_local_times() {
+        let berlin = zone("CET-1CEST,M3.5.0,M10.5.0/3");
+        // Forward on the last Sunday of March at 02:00 CET, back on the last of October at 03:00 CEST
+        assert_eq!(berlin.offset_at(unix("2026-03-29T00:59:59Z")), 3600);
+        assert_eq!(berlin.offset_at(unix("2026-03-29T01:00:00Z")), 7200);
+        assert_eq!(berlin.offset_at(unix("2026-10-25T00:59:59Z")), 7200);
+        assert_eq!(berlin.offset_at(unix("2026-10-25T01:00:00Z")), 3600);
+        // 02:30 is skipped in March and repeated in October
+        assert!(berlin.instants(unix("2026-03-29T02:30:00Z")).is_empty());
+        assert_eq!(
+            berlin.instants(unix("2026-10-25T02:30:00Z")),
+            [unix("2026-10-25T00:30:00Z"), unix("2026-10-25T01:30:00Z")]
+        );
+        assert_eq!(
+            berlin.instants(unix("2026-07-01T12:00:00Z")),
+            [unix("2026-07-01T10:00:00Z")]
+        );
+
+        // Daylight saving over the new year, an hour ahead when no offset is given for it
+        let sydney = zone("AEST-10AEDT,M10.1.0,M4.1.0/3");
+        assert_eq!(sydney.offset_at(unix("2026-01-15T00:00:00Z")), 39600);
+        assert_eq!(sydney.offset_at(unix("2026-07-15T00:00:00Z")), 36000);
+        let new_york = zone("EST5EDT,M3.2.0,M11.1.0");
+        assert_eq!(new_york.offset_at(unix("2026-07-15T00:00:00Z")), -14400);
+        assert_eq!(new_york.offset_at(unix("2026-12-15T00:00:00Z")), -18000);
+
+        assert_eq!(zone("<+0530>-5:30").offset_at(0), 19800);
+
+        // J60 is March 1 every year, 59 counted from 0 is February 29 in a leap year
+        let julian = zone("AAA0BBB,J60/0,J300/0");
+        assert_eq!(julian.offset_at(unix("2024-02-29T12:00:00Z")), 0);
+        assert_eq!(julian.offset_at(unix("2024-03-01T12:00:00Z")), 3600);
+        let ordinal = zone("AAA0BBB,59/0,300/0");
+        assert_eq!(ordinal.offset_at(unix("2024-02-29T12:00:00Z")), 3600);
+    }
+
+    #[test]
+    fn listed_changes_come_before_the_rule() {
+        let change = unix("2026-04-01T00:00:00Z");
+        let data = tzif(&[(change, 1)], &[0, 3600], "");
+        let zone = NamedZone::parse("Test/Zone", &data).unwrap();
+        assert_eq!(zone.offset_at(change - 1), 0);
+        assert_eq!(zone.offset_at(change), 3600);
+        assert_eq!(zone.offset_at(change + 86_400 * 365 * 10), 3600);
+
+        let data = tzif(&[(change, 1)], &[0, 3600], "XXX-2");
+        let zone = NamedZone::parse("Test/Zone", &data).unwrap();
+        assert_eq!(zone.offset_at(change - 1), 0);
+        assert_eq!(zone.offset_at(change), 7200);
+    }
+
+    #[test]
+    fn invalid_zones_are_refused() {
+        assert!(NamedZone::parse("Test/Zone", b"not a zone")
+            .unwrap_err()
+            .contains("not a valid TZif"));
+        let error = NamedZone::parse("Test/Zone", &tzif(&[], &[0], "CET-1CEST,M13.5.0,M10.5.0")).unwrap_err();
+        assert!(error.contains("invalid rule"));
+        let dir = tempfile::tempdir().unwrap();
+        assert!(NamedZone::load(dir.path(), "../etc/passwd")
+            .unwrap_err()
+            .contains("not a timezone name"));
+        assert!(NamedZone::load(dir.path(), "Mars/Olympus")
+            .unwrap_err()
+            .contains("no timezone Mars/Olympus"));
+    }
+}
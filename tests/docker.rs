@@ -0,0 +1,254 @@
+//! Deploys to the local Docker daemon through the `ruku` binary, run with `cargo docker-test`. Every test
+//! has an app of its own under a random name and a home of its own, so runs in parallel don't collide, and
+//! removes what it created when it ends, also when it fails.
+
+use std::collections::hash_map::RandomState;
+use std::fs;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use tempfile::TempDir;
+
+/// The label ruku puts on what it creates when run with [`TEST_ENV`].
+const TEST_LABEL: &str = "ruku.test";
+const TEST_ENV: &str = "RUKU_TEST";
+const APP_LABEL: &str = "ruku.app";
+/// Longest the app may stop answering while the rolling strategy swaps its containers.
+const MAX_ROLLING_GAP: Duration = Duration::from_secs(5);
+
+/// A test app in a home of its own. Dropping it sweeps the containers, networks, volumes and images that
+/// carry the test label and the app's name, nothing of other apps or runs.
+struct TestApp {
+    name: String,
+    home: TempDir,
+}
+
+impl TestApp {
+    fn new() -> TestApp {
+        let suffix = RandomState::new().build_hasher().finish();
+        let app = TestApp {
+            name: format!("test-{:012x}", suffix & 0xffff_ffff_ffff),
+            home: tempfile::tempdir().unwrap(),
+        };
+        fs::create_dir_all(app.dir()).unwrap();
+        app
+    }
+
+    fn dir(&self) -> PathBuf {
+        self.home.path().join("apps").join(&self.name)
+    }
+
+    /// Write a version of the app: busybox httpd answering with the version on an assigned host port.
+    fn write_version(&self, version: &str, strategy: &str) {
+        let config = format!(
+            "version: \"{}\"\nport: auto:8000\nstrategy: {}\nbuild:\n  builder: dockerfile\n",
+            version, strategy
+        );
+        let dockerfile = format!(
+            "FROM busybox:1.36\nRUN mkdir /www && echo {} > /www/index.html\n\
+             CMD echo started {}; exec httpd -f -p 8000 -h /www\n",
+            version, version
+        );
+        write(&self.dir().join("ruku.yml"), &config);
+        write(&self.dir().join("Dockerfile"), &dockerfile);
+    }
+
+    /// Run ruku with `args` and the app's name after them.
+    fn ruku(&self, args: &[&str]) -> Output {
+        let output = Command::new(env!("CARGO_BIN_EXE_ruku"))
+            .args(args)
+            .arg(&self.name)
+            .env("HOME", self.home.path())
+            .env(TEST_ENV, "1")
+            .env("RUKU_ASSUME_YES", "1")
+            .output()
+            .unwrap();
+        eprintln!(
+            "ruku {} {}:\n{}{}",
+            args.join(" "),
+            self.name,
+            String::from_utf8_lossy(&output.stdout),
+            String::from_utf8_lossy(&output.stderr)
+        );
+        output
+    }
+
+    /// The output of a ruku command that has to succeed, stdout and stderr together.
+    fn ruku_ok(&self, args: &[&str]) -> String {
+        let output = self.ruku(args);
+        assert!(output.status.success(), "ruku {} failed", args.join(" "));
+        [output.stdout, output.stderr]
+            .map(|bytes| String::from_utf8_lossy(&bytes).into_owned())
+            .concat()
+    }
+
+    /// The filter options for the objects of this app that ruku created for a test.
+    fn filters(&self) -> Vec<String> {
+        vec![
+            "--filter".to_string(),
+            format!("label={}=true", TEST_LABEL),
+            "--filter".to_string(),
+            format!("label={}={}", APP_LABEL, self.name),
+        ]
+    }
+
+    /// Ids of the app's objects listed by `docker <list>`, e.g. `ps -a`.
+    fn list(&self, list: &[&str]) -> Vec<String> {
+        let output = Command::new("docker")
+            .args(list)
+            .arg("-q")
+            .args(self.filters())
+            .output()
+            .unwrap();
+        String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .map(str::to_string)
+            .collect()
+    }
+
+    /// A label of the app's running stable container.
+    fn stable_label(&self, label: &str) -> String {
+        let ids = Command::new("docker")
+            .args(["ps", "-q", "--filter", "label=ruku.role=stable"])
+            .args(self.filters())
+            .output()
+            .unwrap();
+        let id = String::from_utf8_lossy(&ids.stdout).trim().to_string();
+        assert!(!id.is_empty(), "{} has no running container", self.name);
+        docker(&[
+            "inspect",
+            "-f",
+            &format!("{{{{index .Config.Labels \"{}\"}}}}", label),
+            &id,
+        ])
+    }
+
+    fn address(&self) -> SocketAddr {
+        let port: u16 = self.stable_label("ruku.port").parse().unwrap();
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+}
+
+impl Drop for TestApp {
+    fn drop(&mut self) {
+        let sweeps: [(&[&str], &[&str]); 4] = [
+            (&["ps", "-a"], &["rm", "-f", "-v"]),
+            (&["network", "ls"], &["network", "rm"]),
+            (&["volume", "ls"], &["volume", "rm", "-f"]),
+            (&["image", "ls"], &["image", "rm", "-f"]),
+        ];
+        for (list, remove) in sweeps {
+            let ids = self.list(list);
+            if !ids.is_empty() {
+                let _ = Command::new("docker").args(remove).args(&ids).output();
+            }
+        }
+    }
+}
+
+fn write(path: &Path, content: &str) {
+    fs::write(path, content).unwrap();
+}
+
+fn docker(args: &[&str]) -> String {
+    let output = Command::new("docker").args(args).output().unwrap();
+    assert!(output.status.success(), "docker {} failed", args.join(" "));
+    String::from_utf8_lossy(&output.stdout).trim().to_string()
+}
+
+/// The body the app answers with, none while it doesn't answer.
+fn get(address: SocketAddr) -> Option<String> {
+    let mut stream = TcpStream::connect_timeout(&address, Duration::from_secs(1)).ok()?;
+    stream.set_read_timeout(Some(Duration::from_secs(1))).ok()?;
+    stream.write_all(b"GET / HTTP/1.0\r\n\r\n").ok()?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response).ok()?;
+    let (head, body) = response.split_once("\r\n\r\n")?;
+    let status = head.split_whitespace().nth(1);
+    (status == Some("200")).then(|| body.trim().to_string())
+}
+
+/// Wait for the app to answer and return the body.
+fn wait_for(address: SocketAddr) -> String {
+    let deadline = Instant::now() + Duration::from_secs(30);
+    loop {
+        if let Some(body) = get(address) {
+            return body;
+        }
+        assert!(Instant::now() < deadline, "{} didn't answer", address);
+        thread::sleep(Duration::from_millis(200));
+    }
+}
+
+/// Poll the app until stopped and return the longest time it didn't answer.
+fn watch(address: SocketAddr, stop: Arc<AtomicBool>) -> thread::JoinHandle<Duration> {
+    thread::spawn(move || {
+        let mut longest = Duration::ZERO;
+        let mut down_since: Option<Instant> = None;
+        while !stop.load(Ordering::Relaxed) {
+            match get(address) {
+                Some(_) => down_since = None,
+                None => {
+                    let since = *down_since.get_or_insert_with(Instant::now);
+                    longest = longest.max(since.elapsed());
+                }
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        longest
+    })
+}
+
+#[test]
+fn deploy_publishes_the_port_and_labels_the_container() {
+    let app = TestApp::new();
+    app.write_version("1.0", "recreate");
+    app.ruku_ok(&["run"]);
+
+    assert_eq!(app.stable_label(APP_LABEL), app.name);
+    assert_eq!(app.stable_label("ruku.version"), "1.0");
+    assert_eq!(app.stable_label(TEST_LABEL), "true");
+    assert_eq!(wait_for(app.address()), "1.0");
+    assert!(app.ruku_ok(&["logs", "--tail", "20"]).contains("started 1.0"));
+
+    app.ruku_ok(&["stop", "--keep"]);
+    assert!(get(app.address()).is_none());
+    app.ruku_ok(&["destroy", "--no-backup"]);
+    assert!(app.list(&["ps", "-a"]).is_empty());
+}
+
+#[test]
+fn rolling_redeploy_keeps_the_app_answering_and_rolls_back() {
+    let app = TestApp::new();
+    app.write_version("1.0", "rolling");
+    app.ruku_ok(&["run"]);
+    let address = app.address();
+    assert_eq!(wait_for(address), "1.0");
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let watcher = watch(address, stop.clone());
+    app.write_version("2.0", "rolling");
+    app.ruku_ok(&["run"]);
+    assert_eq!(wait_for(address), "2.0");
+    stop.store(true, Ordering::Relaxed);
+    let gap = watcher.join().unwrap();
+    assert!(
+        gap <= MAX_ROLLING_GAP,
+        "the app didn't answer for {:?} during the rollout",
+        gap
+    );
+
+    app.ruku_ok(&["rollback"]);
+    assert_eq!(app.stable_label("ruku.version"), "1.0");
+    assert_eq!(wait_for(app.address()), "1.0");
+
+    app.ruku_ok(&["destroy", "--no-backup", "--volumes"]);
+    assert!(app.list(&["ps", "-a"]).is_empty());
+}
@@ -0,0 +1,165 @@
+//! Runs the destructive commands of the `ruku` binary with `--dry-run` against a stand-in for the Docker
+//! daemon, which records what it is asked. A dry run may look at anything but change nothing.
+
+use std::fs;
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::path::Path;
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use tempfile::TempDir;
+
+const APP: &str = "shop";
+
+/// What the stand-in daemon answers `method path` with, enough for the commands to make their plans.
+fn respond(method: &str, path: &str) -> (u16, String) {
+    match (method, path) {
+        ("GET", "/_ping") => (200, "OK".to_string()),
+        ("GET", "/version") => (200, r#"{"Version": "27.3.1", "ApiVersion": "1.47"}"#.to_string()),
+        ("GET", "/info") => (200, "{}".to_string()),
+        ("GET", "/containers/json") => (
+            200,
+            format!(
+                r#"[{{"Id": "0123456789abcdef", "Names": ["/ruku-{app}"], "Image": "{app}:1.0",
+                     "State": "running", "Labels": {{"ruku.app": "{app}", "ruku.role": "stable"}}}}]"#,
+                app = APP
+            ),
+        ),
+        ("GET", "/volumes") => (
+            200,
+            format!(
+                r#"{{"Volumes": [{{"Name": "{app}-data", "Driver": "local", "Mountpoint": "/var/lib/docker/volumes/{app}-data",
+                                  "Scope": "local", "Options": {{}}, "Labels": {{"ruku.app": "{app}"}}}}]}}"#,
+                app = APP
+            ),
+        ),
+        ("GET", "/images/json") => (200, "[]".to_string()),
+        ("GET", path) if path.starts_with("/images/") => {
+            (200, r#"{"Id": "sha256:ffffeeeeddddcccc", "Size": 3000000}"#.to_string())
+        }
+        ("GET", path) if path.starts_with("/containers/") => (
+            200,
+            r#"{"Id": "0123456789abcdef", "State": {"Status": "running", "Running": true}}"#.to_string(),
+        ),
+        _ => (404, r#"{"message": "not found"}"#.to_string()),
+    }
+}
+
+/// A daemon on a local port answering with [`respond`], and the `METHOD /path` of every request it got.
+fn fake_daemon() -> (String, Arc<Mutex<Vec<String>>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let requests = Arc::new(Mutex::new(vec![]));
+    let recorded = requests.clone();
+    thread::spawn(move || {
+        for mut stream in listener.incoming().flatten() {
+            let mut request = vec![];
+            let mut buffer = [0; 4096];
+            while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+                match stream.read(&mut buffer) {
+                    Ok(0) | Err(_) => break,
+                    Ok(read) => request.extend_from_slice(&buffer[..read]),
+                }
+            }
+            let request = String::from_utf8_lossy(&request).to_string();
+            let line = request.lines().next().unwrap_or_default().to_string();
+            let mut parts = line.split(' ');
+            let method = parts.next().unwrap_or_default();
+            let path = parts.next().unwrap_or_default().split('?').next().unwrap_or_default();
+            // Without the API version, e.g. `/v1.47`
+            let path = match path.strip_prefix("/v").and_then(|rest| rest.split_once('/')) {
+                Some((version, rest)) if version.contains('.') => format!("/{}", rest),
+                _ => path.to_string(),
+            };
+            let (status, body) = respond(method, &path);
+            recorded.lock().unwrap().push(format!("{} {}", method, path));
+            let response = format!(
+                "HTTP/1.1 {} OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status,
+                body.len(),
+                body
+            );
+            let _ = stream.write_all(response.as_bytes());
+        }
+    });
+    (format!("tcp://{}", address), requests)
+}
+
+/// A home with the app, a backup of it to undo to and the state of a canary that is gone to repair.
+fn home() -> TempDir {
+    let home = tempfile::tempdir().unwrap();
+    let app_dir = home.path().join("apps").join(APP);
+    fs::create_dir_all(&app_dir).unwrap();
+    fs::write(app_dir.join("ruku.yml"), "version: \"1.0\"\nport: 8000\n").unwrap();
+    let state_dir = home.path().join(".ruku").join("state").join(APP);
+    fs::create_dir_all(&state_dir).unwrap();
+    let backups = format!(
+        r#"[{{"image": "ruku-backup/{app}:20261014120000", "container_name": "ruku-{app}",
+              "source_image": "{app}:1.0", "reason": "destroy", "created_at": "2026-10-14T12:00:00Z"}}]"#,
+        app = APP
+    );
+    fs::write(state_dir.join("backups.json"), backups).unwrap();
+    let canary = r#"{"container": "ruku-shop-canary", "weight": 10, "started_at": "2026-10-14T12:00:00Z"}"#;
+    fs::write(state_dir.join("canary.json"), canary).unwrap();
+    home
+}
+
+fn dry_run(home: &Path, docker_host: &str, args: &[&str]) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_ruku"))
+        .args(args)
+        .arg(APP)
+        .arg("--dry-run")
+        .env("HOME", home)
+        .env("DOCKER_HOST", docker_host)
+        .env_remove("RUKU_CONTEXT")
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout).to_string();
+    assert!(
+        output.status.success(),
+        "ruku {} --dry-run failed:\n{}{}",
+        args.join(" "),
+        stdout,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    stdout
+}
+
+#[test]
+fn dry_runs_send_the_daemon_no_changes() {
+    let home = home();
+    let (docker_host, requests) = fake_daemon();
+    let commands: [(&[&str], &str); 5] = [
+        (&["stop", "--purge"], "remove image shop:1.0"),
+        (&["destroy", "--volumes"], "remove volume shop-data"),
+        (&["repair"], "canary state without a canary container"),
+        (&["undo"], "replace container ruku-shop"),
+        (&["destroy", "--json"], r#""action": "backup""#),
+    ];
+    for (args, step) in commands {
+        let plan = dry_run(home.path(), &docker_host, args);
+        assert!(plan.contains(step), "ruku {} planned:\n{}", args.join(" "), plan);
+    }
+
+    let requests = requests.lock().unwrap();
+    assert!(requests.iter().any(|request| request == "GET /containers/json"));
+    let changes: Vec<&String> = requests.iter().filter(|request| !request.starts_with("GET ")).collect();
+    assert!(changes.is_empty(), "dry runs sent {:?}", changes);
+    // Nothing was written to the state either, not even the audit log
+    let state_root = home.path().join(".ruku").join("state");
+    assert_eq!(entries(&state_root), [APP]);
+    assert_eq!(entries(&state_root.join(APP)), ["backups.json", "canary.json"]);
+}
+
+/// The names in `dir`, sorted.
+fn entries(dir: &Path) -> Vec<String> {
+    let mut names: Vec<String> = fs::read_dir(dir)
+        .unwrap()
+        .flatten()
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .collect();
+    names.sort();
+    names
+}